@@ -0,0 +1,20 @@
+//! Records the git commit that bcl2fastr was built from, so that it can be written
+//! into the provenance of each demux. Builds from a source tarball have no hash
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output();
+
+    if let Ok(output) = output {
+        if output.status.success() {
+            let hash = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=BCL2FASTR_GIT_HASH={}", hash.trim());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+/*
+ * C API for bcl2fastr. Link against libbcl2fastr_ffi (built with
+ * `cargo build --release -p bcl2fastr-ffi`).
+ *
+ * Functions that can fail return NULL or -1; bcl2fastr_last_error() then gives
+ * the reason, for the calling thread.
+ */
+
+#ifndef BCL2FASTR_H
+#define BCL2FASTR_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Bcl2fastrRun bcl2fastr_run;
+typedef struct Bcl2fastrStats bcl2fastr_stats;
+
+/* called with the tiles done, the total tiles and user_data, from any thread */
+typedef void (*bcl2fastr_progress_fn)(uint64_t tiles, uint64_t total, void *user_data);
+
+typedef struct {
+    /* the most mismatches allowed in each index */
+    uint32_t mismatch;
+    /* gzip level for the fastq files, or -1 to write them uncompressed */
+    int compression;
+    /* the number of tiles to read at once */
+    uint32_t read_chunks;
+    /* write the HTML reports as well as Stats.json, if non-zero */
+    int reports;
+    bcl2fastr_progress_fn progress;
+    void *user_data;
+} bcl2fastr_demux_options;
+
+typedef struct {
+    uint32_t lane;
+    uint64_t clusters_raw;
+    uint64_t clusters_pf;
+    uint64_t yield_bases;
+    uint64_t undetermined_reads;
+    size_t sample_count;
+} bcl2fastr_lane_stats;
+
+typedef struct {
+    /* valid for as long as the stats */
+    const char *sample_id;
+    uint64_t reads;
+    uint64_t yield_bases;
+} bcl2fastr_sample_stats;
+
+const char *bcl2fastr_version(void);
+const char *bcl2fastr_last_error(void);
+
+/* load a run folder, or NULL on failure */
+bcl2fastr_run *bcl2fastr_run_load(const char *run_path);
+void bcl2fastr_run_free(bcl2fastr_run *run);
+const char *bcl2fastr_run_id(const bcl2fastr_run *run);
+const char *bcl2fastr_run_read_structure(const bcl2fastr_run *run);
+/* tiles in a lane, or in every lane if lane is 0 */
+size_t bcl2fastr_run_tile_count(const bcl2fastr_run *run, uint32_t lane);
+
+/* always call this before setting any options */
+void bcl2fastr_demux_options_init(bcl2fastr_demux_options *options);
+
+/* demultiplex a run into output_path, which must exist; options may be NULL */
+bcl2fastr_stats *bcl2fastr_demux(const char *run_path,
+                                 const char *samplesheet,
+                                 const char *output_path,
+                                 const bcl2fastr_demux_options *options);
+void bcl2fastr_stats_free(bcl2fastr_stats *stats);
+size_t bcl2fastr_stats_lane_count(const bcl2fastr_stats *stats);
+int bcl2fastr_stats_lane(const bcl2fastr_stats *stats, size_t lane_i, bcl2fastr_lane_stats *out);
+int bcl2fastr_stats_sample(const bcl2fastr_stats *stats,
+                           size_t lane_i,
+                           size_t sample_i,
+                           bcl2fastr_sample_stats *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BCL2FASTR_H */
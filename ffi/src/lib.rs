@@ -0,0 +1,522 @@
+//! A C API for bcl2fastr, so that C and C++ pipelines can load runs, demultiplex
+//! them and read the stats without shelling out. The declarations are in
+//! `include/bcl2fastr.h`, which must be kept in step with this file.
+//!
+//! Functions that can fail return NULL or a negative number, and the reason is
+//! available from `bcl2fastr_last_error` on the same thread. Panics inside
+//! bcl2fastr (e.g. a bad samplesheet) are caught and reported the same way.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    ptr,
+};
+
+use common::demux::{DemuxBuilder, DEFAULT_READ_CHUNKS};
+use common::novaseq_run::NovaSeqRun;
+use common::progress::{Progress, ProgressMode};
+use common::stats::LaneStats;
+use common::write_fastq::OutputOptions;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Run `f`, turning an error or a panic into the last error and `None`
+fn guard<T, F>(f: F) -> Option<T>
+where
+    F: FnOnce() -> std::io::Result<T>,
+{
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            None
+        }
+        Err(panic) => {
+            let message = match panic.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => match panic.downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "bcl2fastr panicked".to_string(),
+                },
+            };
+            set_last_error(message);
+            None
+        }
+    }
+}
+
+/// Convert a C string argument to a path, failing if it is NULL or not UTF-8
+unsafe fn path_arg(s: *const c_char, name: &str) -> std::io::Result<PathBuf> {
+    if s.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is NULL", name),
+        ));
+    }
+
+    CStr::from_ptr(s)
+        .to_str()
+        .map(PathBuf::from)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+/// The version of bcl2fastr, as a static string
+#[no_mangle]
+pub extern "C" fn bcl2fastr_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// The message for the last error on this thread, or NULL if there hasn't been
+/// one. The string is valid until the next failing call on this thread
+#[no_mangle]
+pub extern "C" fn bcl2fastr_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| match &*e.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// A loaded run folder
+pub struct Bcl2fastrRun {
+    novaseq_run: NovaSeqRun,
+    run_id: CString,
+    read_structure: CString,
+}
+
+/// Load the run in `run_path`, including all of its CBCL headers. Returns NULL on
+/// failure. Free the run with `bcl2fastr_run_free`
+///
+/// # Safety
+///
+/// `run_path` must be NULL or a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_run_load(run_path: *const c_char) -> *mut Bcl2fastrRun {
+    let run = guard(|| {
+        let novaseq_run = NovaSeqRun::read_path(path_arg(run_path, "run_path")?, false)?;
+        Ok(Bcl2fastrRun {
+            run_id: CString::new(novaseq_run.run_info.id.clone()).unwrap(),
+            read_structure: CString::new(novaseq_run.read_structure.to_string()).unwrap(),
+            novaseq_run,
+        })
+    });
+
+    match run {
+        Some(run) => Box::into_raw(Box::new(run)),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Free a run from `bcl2fastr_run_load`
+///
+/// # Safety
+///
+/// `run` must be NULL or a run from `bcl2fastr_run_load` that hasn't been freed
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_run_free(run: *mut Bcl2fastrRun) {
+    if !run.is_null() {
+        drop(Box::from_raw(run));
+    }
+}
+
+/// The run ID from RunInfo.xml, valid for as long as the run
+///
+/// # Safety
+///
+/// `run` must be a live run from `bcl2fastr_run_load`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_run_id(run: *const Bcl2fastrRun) -> *const c_char {
+    (*run).run_id.as_ptr()
+}
+
+/// The reads in the run, e.g. `Y151;I8;I8;Y151`, valid for as long as the run
+///
+/// # Safety
+///
+/// `run` must be a live run from `bcl2fastr_run_load`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_run_read_structure(run: *const Bcl2fastrRun) -> *const c_char {
+    (*run).read_structure.as_ptr()
+}
+
+/// The number of tiles in `lane`, or in every lane if `lane` is 0
+///
+/// # Safety
+///
+/// `run` must be a live run from `bcl2fastr_run_load`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_run_tile_count(run: *const Bcl2fastrRun, lane: u32) -> usize {
+    (*run).novaseq_run.tile_count(lane as usize)
+}
+
+/// Called with the number of tiles done, the total number of tiles and the
+/// `user_data` from the options. May be called from any thread
+pub type Bcl2fastrProgressFn = Option<extern "C" fn(u64, u64, *mut c_void)>;
+
+/// Options for `bcl2fastr_demux`. Always fill this in with
+/// `bcl2fastr_demux_options_init` first, so that new options get their defaults
+#[repr(C)]
+pub struct Bcl2fastrDemuxOptions {
+    /// the most mismatches allowed in each index
+    pub mismatch: u32,
+    /// gzip level for the fastq files, or -1 to write them uncompressed
+    pub compression: c_int,
+    /// the number of tiles to read at once
+    pub read_chunks: u32,
+    /// write the HTML reports as well as Stats.json, if non-zero
+    pub reports: c_int,
+    pub progress: Bcl2fastrProgressFn,
+    pub user_data: *mut c_void,
+}
+
+/// Fill in `options` with the same defaults as the `demux` subcommand
+///
+/// # Safety
+///
+/// `options` must point to a `bcl2fastr_demux_options`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_demux_options_init(options: *mut Bcl2fastrDemuxOptions) {
+    ptr::write(
+        options,
+        Bcl2fastrDemuxOptions {
+            mismatch: 1,
+            compression: OutputOptions::default()
+                .compression
+                .map_or(-1, |c| c as c_int),
+            read_chunks: DEFAULT_READ_CHUNKS as u32,
+            reports: 1,
+            progress: None,
+            user_data: ptr::null_mut(),
+        },
+    );
+}
+
+/// The user's pointer, passed back to their callback from the worker threads
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// The results of a demux
+pub struct Bcl2fastrStats {
+    lane_stats: Vec<LaneStats>,
+    sample_ids: Vec<Vec<CString>>,
+}
+
+/// Demultiplex the run in `run_path` with `samplesheet`, writing to `output_path`,
+/// which must already exist. `options` can be NULL for the defaults. Returns NULL on
+/// failure. Free the stats with `bcl2fastr_stats_free`
+///
+/// # Safety
+///
+/// The paths must be NULL or valid C strings, and `options` must be NULL or
+/// initialized with `bcl2fastr_demux_options_init`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_demux(
+    run_path: *const c_char,
+    samplesheet: *const c_char,
+    output_path: *const c_char,
+    options: *const Bcl2fastrDemuxOptions,
+) -> *mut Bcl2fastrStats {
+    let stats = guard(|| {
+        let mut builder = DemuxBuilder::new(
+            path_arg(run_path, "run_path")?,
+            path_arg(samplesheet, "samplesheet")?,
+            path_arg(output_path, "output_path")?,
+        );
+
+        let mut callback = None;
+        if let Some(options) = options.as_ref() {
+            builder = builder
+                .mismatch(options.mismatch as usize)
+                .read_chunks(options.read_chunks as usize)
+                .output_options(OutputOptions {
+                    compression: if options.compression < 0 {
+                        None
+                    } else {
+                        Some(options.compression as u32)
+                    },
+                    ..Default::default()
+                })
+                .reports(options.reports != 0);
+            callback = options
+                .progress
+                .map(|progress| (progress, UserData(options.user_data)));
+        }
+
+        let demux = builder.build()?;
+
+        let mut progress = Progress::new(ProgressMode::Hidden, demux.total_tiles() as u64);
+        if let Some((callback, user_data)) = callback {
+            progress = progress.with_callback(Box::new(move |tiles, total| {
+                callback(tiles, total, user_data.0)
+            }));
+        }
+
+        let lane_stats = demux.run_with_progress(&progress)?;
+        let sample_ids = lane_stats
+            .iter()
+            .map(|ls| {
+                ls.demux_results
+                    .iter()
+                    .map(|ss| CString::new(ss.sample_id.clone()).unwrap())
+                    .collect()
+            })
+            .collect();
+
+        Ok(Bcl2fastrStats {
+            lane_stats,
+            sample_ids,
+        })
+    });
+
+    match stats {
+        Some(stats) => Box::into_raw(Box::new(stats)),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Free stats from `bcl2fastr_demux`
+///
+/// # Safety
+///
+/// `stats` must be NULL or stats from `bcl2fastr_demux` that haven't been freed
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_stats_free(stats: *mut Bcl2fastrStats) {
+    if !stats.is_null() {
+        drop(Box::from_raw(stats));
+    }
+}
+
+/// The totals for one lane of a demux
+#[repr(C)]
+pub struct Bcl2fastrLaneStats {
+    pub lane: u32,
+    pub clusters_raw: u64,
+    pub clusters_pf: u64,
+    pub yield_bases: u64,
+    pub undetermined_reads: u64,
+    pub sample_count: usize,
+}
+
+/// The totals for one sample in a lane. `sample_id` is valid for as long as the
+/// stats
+#[repr(C)]
+pub struct Bcl2fastrSampleStats {
+    pub sample_id: *const c_char,
+    pub reads: u64,
+    pub yield_bases: u64,
+}
+
+/// The number of lanes in the stats
+///
+/// # Safety
+///
+/// `stats` must be live stats from `bcl2fastr_demux`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_stats_lane_count(stats: *const Bcl2fastrStats) -> usize {
+    (*stats).lane_stats.len()
+}
+
+/// Fill in `out` with the totals for the `lane_i`th lane. Returns 0, or -1 if
+/// there is no such lane
+///
+/// # Safety
+///
+/// `stats` must be live stats from `bcl2fastr_demux` and `out` must point to a
+/// `bcl2fastr_lane_stats`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_stats_lane(
+    stats: *const Bcl2fastrStats,
+    lane_i: usize,
+    out: *mut Bcl2fastrLaneStats,
+) -> c_int {
+    let stats = &*stats;
+    let lane_stats = match stats.lane_stats.get(lane_i) {
+        Some(lane_stats) => lane_stats,
+        None => {
+            set_last_error(format!("no lane {} in stats", lane_i));
+            return -1;
+        }
+    };
+
+    ptr::write(
+        out,
+        Bcl2fastrLaneStats {
+            lane: lane_stats.lane_number as u32,
+            clusters_raw: lane_stats.total_clusters_raw,
+            clusters_pf: lane_stats.total_clusters_pf,
+            yield_bases: lane_stats.yield_bases,
+            undetermined_reads: lane_stats.undetermined.number_reads,
+            sample_count: lane_stats.demux_results.len(),
+        },
+    );
+
+    0
+}
+
+/// Fill in `out` with the totals for the `sample_i`th sample in the `lane_i`th
+/// lane. Returns 0, or -1 if there is no such sample
+///
+/// # Safety
+///
+/// `stats` must be live stats from `bcl2fastr_demux` and `out` must point to a
+/// `bcl2fastr_sample_stats`
+#[no_mangle]
+pub unsafe extern "C" fn bcl2fastr_stats_sample(
+    stats: *const Bcl2fastrStats,
+    lane_i: usize,
+    sample_i: usize,
+    out: *mut Bcl2fastrSampleStats,
+) -> c_int {
+    let stats = &*stats;
+    let sample = stats
+        .lane_stats
+        .get(lane_i)
+        .and_then(|ls| ls.demux_results.get(sample_i));
+
+    match sample {
+        Some(sample_stats) => {
+            ptr::write(
+                out,
+                Bcl2fastrSampleStats {
+                    sample_id: stats.sample_ids[lane_i][sample_i].as_ptr(),
+                    reads: sample_stats.number_reads,
+                    yield_bases: sample_stats.yield_bases,
+                },
+            );
+            0
+        }
+        None => {
+            set_last_error(format!("no sample {} in lane {}", sample_i, lane_i));
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::MaybeUninit;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const ISEQ_RUN: &str = "../test_data/210618_FS10000171_0042_BPA73113-1417\0";
+    const ISEQ_SAMPLESHEET: &str =
+        "../test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv\0";
+
+    fn c_str(s: &str) -> *const c_char {
+        s.as_ptr() as *const c_char
+    }
+
+    #[test]
+    fn run_load() {
+        unsafe {
+            let run = bcl2fastr_run_load(c_str(ISEQ_RUN));
+            assert!(!run.is_null());
+
+            assert_eq!(
+                CStr::from_ptr(bcl2fastr_run_id(run)).to_str().unwrap(),
+                "210618_FS10000171_0042_BPA73113-1417"
+            );
+            assert_eq!(
+                CStr::from_ptr(bcl2fastr_run_read_structure(run))
+                    .to_str()
+                    .unwrap(),
+                "Y6;I8;I8;Y6"
+            );
+            assert_eq!(bcl2fastr_run_tile_count(run, 0), 2);
+
+            bcl2fastr_run_free(run);
+        }
+    }
+
+    #[test]
+    fn run_load_error() {
+        unsafe {
+            let run = bcl2fastr_run_load(c_str("../test_data/no_such_run\0"));
+            assert!(run.is_null());
+            assert!(!bcl2fastr_last_error().is_null());
+
+            assert!(bcl2fastr_run_load(ptr::null()).is_null());
+            assert_eq!(
+                CStr::from_ptr(bcl2fastr_last_error()).to_str().unwrap(),
+                "run_path is NULL"
+            );
+        }
+    }
+
+    extern "C" fn count_progress(_tiles: u64, _total: u64, user_data: *mut c_void) {
+        let calls = unsafe { &*(user_data as *const AtomicU64) };
+        calls.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn demux() {
+        let output_path = "../test_data/test_output/ffi_demux";
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+        let output_c = CString::new(output_path).unwrap();
+
+        let calls = AtomicU64::new(0);
+
+        unsafe {
+            let mut options = MaybeUninit::uninit();
+            bcl2fastr_demux_options_init(options.as_mut_ptr());
+            let mut options = options.assume_init();
+            options.mismatch = 0;
+            options.compression = -1;
+            options.reports = 0;
+            options.progress = Some(count_progress);
+            options.user_data = &calls as *const AtomicU64 as *mut c_void;
+
+            let stats = bcl2fastr_demux(
+                c_str(ISEQ_RUN),
+                c_str(ISEQ_SAMPLESHEET),
+                output_c.as_ptr(),
+                &options,
+            );
+            assert!(!stats.is_null());
+            assert_eq!(bcl2fastr_stats_lane_count(stats), 1);
+
+            let mut lane_stats = MaybeUninit::uninit();
+            assert_eq!(bcl2fastr_stats_lane(stats, 0, lane_stats.as_mut_ptr()), 0);
+            let lane_stats = lane_stats.assume_init();
+            assert_eq!(lane_stats.lane, 1);
+            assert_eq!(lane_stats.clusters_pf, 44);
+            assert_eq!(lane_stats.sample_count, 3);
+
+            let mut sample_stats = MaybeUninit::uninit();
+            assert_eq!(
+                bcl2fastr_stats_sample(stats, 0, 0, sample_stats.as_mut_ptr()),
+                0
+            );
+            let sample_stats = sample_stats.assume_init();
+            assert_eq!(
+                CStr::from_ptr(sample_stats.sample_id).to_str().unwrap(),
+                "iseq_1"
+            );
+
+            let mut missing = MaybeUninit::uninit();
+            assert_eq!(
+                bcl2fastr_stats_sample(stats, 0, 3, missing.as_mut_ptr()),
+                -1
+            );
+
+            bcl2fastr_stats_free(stats);
+        }
+
+        assert!(calls.load(Ordering::SeqCst) > 0);
+        assert!(std::path::Path::new(output_path)
+            .join("iseq_project/iseq_1_L001_R1.fastq")
+            .is_file());
+    }
+}
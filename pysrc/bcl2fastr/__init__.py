@@ -13,6 +13,12 @@ from dataclasses import dataclass
 
 import numpy as np
 
+try:
+    # the Rust extension, if this was installed with maturin
+    from bcl2fastr._bcl2fastr import Run, TileReads, demux, read_samplesheet
+except ImportError:
+    pass
+
 
 @dataclass(eq=False, frozen=True)
 class CBCLHeader:
@@ -0,0 +1,189 @@
+#!/usr/bin/env python
+
+"""Write a small synthetic iSeq 100 run folder for testing. Unlike the NovaSeq test
+run, there is no s.locs file (each tile has its own locs file in
+Data/Intensities/L001), the CBCL files only contain clusters that pass filter,
+and i5 is read as the reverse complement of the samplesheet sequence.
+"""
+
+import gzip
+import pathlib
+import random
+import struct
+
+import click
+
+RUN_ID = "210618_FS10000171_0042_BPA73113-1417"
+
+# (sample, index, index2), with index2 in forward orientation
+SAMPLES = [
+    ("iseq_1", "ACTGCGAA", "GATTGTCC"),
+    ("iseq_2", "TAGTCTCG", "AGTGGCAA"),
+    ("iseq_3", "TGAGCTGT", "CCAACTTC"),
+]
+
+TILES = [1101, 1102]
+N_CLUSTERS = 24
+READ_LENGTH = 6
+INDEX_LENGTH = 8
+
+BASES = "ACGT"
+# qscore bins as (key, value) pairs, the same as NovaSeq
+BINS = [(0, 0), (1, 11), (2, 25), (3, 37)]
+
+RUN_INFO = f"""<?xml version="1.0"?>
+<RunInfo Version="5">
+	<Run Id="{RUN_ID}" Number="42">
+		<Flowcell>BPA73113-1417</Flowcell>
+		<Instrument>FS10000171</Instrument>
+		<Date>6/18/2021 10:12:33 AM</Date>
+		<Reads>
+			<Read Number="1" NumCycles="{READ_LENGTH}" IsIndexedRead="N" />
+			<Read Number="2" NumCycles="{INDEX_LENGTH}" IsIndexedRead="Y" />
+			<Read Number="3" NumCycles="{INDEX_LENGTH}" IsIndexedRead="Y" />
+			<Read Number="4" NumCycles="{READ_LENGTH}" IsIndexedRead="N" />
+		</Reads>
+		<FlowcellLayout LaneCount="1" SurfaceCount="1" SwathCount="1" TileCount="{len(TILES)}">
+			<TileSet TileNamingConvention="FourDigit">
+				<Tiles>
+{"".join(f"					<Tile>1_{tile}</Tile>{chr(10)}" for tile in TILES)}				</Tiles>
+			</TileSet>
+		</FlowcellLayout>
+		<ImageDimensions Width="3272" Height="2848" />
+		<ImageChannels>
+			<Name>Green</Name>
+			<Name>Blue</Name>
+		</ImageChannels>
+	</Run>
+</RunInfo>
+"""
+
+RUN_PARAMETERS = f"""<?xml version="1.0"?>
+<RunParameters xmlns:xsd="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+	<RunParametersVersion>iSeq_1</RunParametersVersion>
+	<ApplicationName>iSeq Control Software</ApplicationName>
+	<ApplicationVersion>2.0.0.10</ApplicationVersion>
+	<RTAVersion>2.9.2</RTAVersion>
+	<RunId>{RUN_ID}</RunId>
+	<ExperimentName>test</ExperimentName>
+</RunParameters>
+"""
+
+
+def reverse_complement(seq: str) -> str:
+    return seq[::-1].translate(str.maketrans("ACGT", "TGCA"))
+
+
+def make_tile(rng: random.Random):
+    """Pick the filter, locations and sequences for the clusters in one tile"""
+    pf = [rng.random() > 0.2 for _ in range(N_CLUSTERS)]
+    locs = [(rng.uniform(0, 300), rng.uniform(0, 300)) for _ in range(N_CLUSTERS)]
+
+    clusters = []
+    for _ in range(N_CLUSTERS):
+        r1 = "".join(rng.choice(BASES) for _ in range(READ_LENGTH))
+        r2 = "".join(rng.choice(BASES) for _ in range(READ_LENGTH))
+
+        if rng.random() < 0.8:
+            _, index, index2 = rng.choice(SAMPLES)
+        else:
+            index = "".join(rng.choice(BASES) for _ in range(INDEX_LENGTH))
+            index2 = "".join(rng.choice(BASES) for _ in range(INDEX_LENGTH))
+
+        qscores = [rng.choice([2, 3, 3, 3]) for _ in range(len(r1) * 2 + 16)]
+        seq = r1 + index + reverse_complement(index2) + r2
+        clusters.append(list(zip(seq, qscores)))
+
+    return pf, locs, clusters
+
+
+def write_filter(path: pathlib.Path, pf):
+    with path.open("wb") as out:
+        out.write(struct.pack("<III", 0, 3, len(pf)))
+        out.write(bytes(int(p) for p in pf))
+
+
+def write_locs(path: pathlib.Path, locs):
+    with path.open("wb") as out:
+        out.write(struct.pack("<IfI", 1, 1.0, len(locs)))
+        for x, y in locs:
+            out.write(struct.pack("<ff", x, y))
+
+
+def pack_cycle(calls):
+    """Two clusters per byte, the first in the low nibble: two bits for the base
+    and two for the qscore bin"""
+    nibbles = [(q << 2) | BASES.index(b) for b, q in calls]
+    if len(nibbles) % 2 == 1:
+        nibbles.append(0)
+
+    return bytes(lo | (hi << 4) for lo, hi in zip(nibbles[::2], nibbles[1::2]))
+
+
+def write_cbcl(path: pathlib.Path, tile_blocks):
+    header_size = 17 + 8 * len(BINS) + 16 * len(tile_blocks)
+
+    with path.open("wb") as out:
+        out.write(struct.pack("<HIBBI", 1, header_size, 2, 2, len(BINS)))
+        for key, value in BINS:
+            out.write(struct.pack("<II", key, value))
+
+        out.write(struct.pack("<I", len(tile_blocks)))
+        for tile, n_clusters, block, compressed in tile_blocks:
+            out.write(struct.pack("<IIII", tile, n_clusters, len(block), len(compressed)))
+
+        # only clusters that pass filter are in the file
+        out.write(struct.pack("B", 1))
+
+        for _, _, _, compressed in tile_blocks:
+            out.write(compressed)
+
+
+@click.command()
+@click.option(
+    "-o",
+    "--output_path",
+    required=True,
+    type=click.Path(exists=True, file_okay=False, dir_okay=True),
+)
+@click.option("--seed", default=1417, type=int, help="Seed for the random reads")
+def main(output_path: str, seed: int):
+    rng = random.Random(seed)
+
+    run_path = pathlib.Path(output_path) / RUN_ID
+    basecalls = run_path / "Data" / "Intensities" / "BaseCalls" / "L001"
+    locs_path = run_path / "Data" / "Intensities" / "L001"
+    basecalls.mkdir(parents=True, exist_ok=True)
+    locs_path.mkdir(parents=True, exist_ok=True)
+
+    (run_path / "RunInfo.xml").write_text(RUN_INFO)
+    (run_path / "RunParameters.xml").write_text(RUN_PARAMETERS)
+
+    with (run_path / "SampleSheet.csv").open("w") as out:
+        out.write("[Data],,,,,\n")
+        out.write("Sample_ID,Sample_Name,Sample_Project,Index,Index2,Lane\n")
+        for sample, index, index2 in SAMPLES:
+            out.write(f"{sample},{sample},iseq_project,{index},{index2},1\n")
+
+    tiles = {}
+    for tile in TILES:
+        pf, locs, clusters = make_tile(rng)
+        write_filter(basecalls / f"s_1_{tile}.filter", pf)
+        write_locs(locs_path / f"s_1_{tile}.locs", locs)
+        tiles[tile] = (pf, clusters)
+
+    n_cycles = 2 * READ_LENGTH + 2 * INDEX_LENGTH
+    for cycle in range(n_cycles):
+        tile_blocks = []
+        for tile, (pf, clusters) in tiles.items():
+            calls = [c[cycle] for c, p in zip(clusters, pf) if p]
+            block = pack_cycle(calls)
+            tile_blocks.append((tile, len(calls), block, gzip.compress(block, mtime=0)))
+
+        cycle_path = basecalls / f"C{cycle + 1}.1"
+        cycle_path.mkdir(exist_ok=True)
+        write_cbcl(cycle_path / "L001_1.cbcl", tile_blocks)
+
+
+if __name__ == "__main__":
+    main()
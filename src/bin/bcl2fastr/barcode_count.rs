@@ -0,0 +1,63 @@
+//! `bcl2fastr barcode-count`: count the most common index sequences in a run,
+//! reading only the index cycles
+
+use clap::{App, Arg, SubCommand};
+use std::path::PathBuf;
+
+use common::index_count::index_count;
+use common::run_summary::RunStatus;
+
+use log::{error, info};
+
+use crate::load::{check_run_path, load_run, run_load_args, run_path_arg};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("barcode-count")
+        .about("count the most common index sequences in a run")
+        .arg(run_path_arg())
+        .args(&run_load_args())
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("output path for index count file")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("top-n")
+                .long("top-n")
+                .help("return the top N index counts")
+                .default_value("384")
+                .takes_value(true),
+        )
+}
+
+/// Load the index cycles of the run and write out the top index counts
+pub fn run(options: &Options) -> RunStatus {
+    let top_n = options.value::<usize>("top-n").unwrap();
+
+    let output_path = PathBuf::from(options.value_of("output").unwrap());
+    match output_path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() || parent.is_dir() => (),
+        _ => {
+            error!("Could not find output path {}", output_path.display());
+            return RunStatus::OutputError;
+        }
+    }
+
+    let novaseq_run =
+        match check_run_path(options).and_then(|run_path| load_run(run_path, true, options)) {
+            Ok(novaseq_run) => novaseq_run,
+            Err(e) => return e.fail(),
+        };
+
+    info!("Counting indexes");
+    if let Err(e) = index_count(&novaseq_run, output_path, top_n) {
+        error!("Error writing index counts: {}", e);
+        return RunStatus::OutputError;
+    }
+    info!("Done");
+
+    RunStatus::Success
+}
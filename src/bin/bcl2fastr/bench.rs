@@ -0,0 +1,92 @@
+//! `bcl2fastr bench`: time each stage of the demux on a few tiles, and project how
+//! long the full run would take with the same settings
+
+use clap::{App, Arg, SubCommand};
+
+use common::bench::bench_tiles;
+use common::run_summary::RunStatus;
+use common::sample_data::ConflictPolicy;
+
+use log::{error, warn};
+
+use crate::load::{
+    build_stage_pools_for, check_run_path, check_samplesheet, i5_orientation, i5_orientation_arg,
+    load_run, load_run_parameters, load_samplesheet, matcher_args, mismatch_arg, output_args,
+    output_options, run_load_args, run_path_arg, samplesheet_arg, stage_thread_args,
+};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("bench")
+        .about(
+            "measure the speed of each stage of the demux on a few tiles, without writing output",
+        )
+        .arg(run_path_arg())
+        .args(&run_load_args())
+        .arg(samplesheet_arg())
+        .arg(mismatch_arg())
+        .args(&matcher_args())
+        .arg(i5_orientation_arg())
+        .arg(
+            Arg::with_name("tiles")
+                .long("tiles")
+                .help("number of tiles to benchmark")
+                .default_value("4")
+                .takes_value(true),
+        )
+        .args(&output_args())
+        .args(&stage_thread_args())
+}
+
+/// Load the run and samplesheet, run the benchmark and print the results
+pub fn run(options: &Options) -> RunStatus {
+    let mismatch = options.value::<usize>("mismatch").unwrap();
+    let n_tiles = options.value::<usize>("tiles").unwrap();
+
+    let run_path = match check_run_path(options) {
+        Ok(run_path) => run_path,
+        Err(e) => return e.fail(),
+    };
+
+    let run_parameters = match load_run_parameters(&run_path) {
+        Ok(run_parameters) => run_parameters,
+        Err(e) => return e.fail(),
+    };
+    let i5_orientation = i5_orientation(options, &run_parameters);
+    let output_options = output_options(options, &run_parameters);
+
+    let sample_data = match check_samplesheet(options).and_then(|samplesheets| {
+        load_samplesheet(
+            &samplesheets,
+            mismatch,
+            i5_orientation,
+            ConflictPolicy::Reduce,
+        )
+    }) {
+        Ok((sample_data, conflicts)) => {
+            for conflict in conflicts {
+                warn!("{}", conflict);
+            }
+            sample_data
+        }
+        Err(e) => return e.fail(),
+    };
+
+    let novaseq_run = match load_run(run_path, false, options) {
+        Ok(novaseq_run) => novaseq_run,
+        Err(e) => return e.fail(),
+    };
+
+    build_stage_pools_for(options);
+
+    match bench_tiles(&novaseq_run, &sample_data, n_tiles, &output_options) {
+        Ok(result) => {
+            println!("{}", result);
+            RunStatus::Success
+        }
+        Err(e) => {
+            error!("Error reading tiles: {}", e);
+            RunStatus::BasecallError
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! `bcl2fastr compare`: check our fastq files against another demultiplexer's, e.g.
+//! when moving a facility over from bcl2fastq
+
+use clap::{App, Arg, SubCommand};
+use std::path::{Path, PathBuf};
+
+use common::compare::{compare_dirs, DEFAULT_PARALLEL_FILES};
+use common::run_summary::RunStatus;
+
+use log::error;
+
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("compare")
+        .about("compare the reads in two sets of fastq files, e.g. ours and bcl2fastq's, by sample and read name")
+        .arg(
+            Arg::with_name("ours")
+                .long("ours")
+                .help("output path of the bcl2fastr demux")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("theirs")
+                .long("theirs")
+                .help("output path of the demux to compare with")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("parallel-files")
+                .long("parallel-files")
+                .help("the number of file pairs to compare at once [default: 4]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("also write the comparison to this file as JSON")
+                .takes_value(true),
+        )
+}
+
+/// Compare the files and print a line for each, exiting with `Discordant` if any
+/// of them differ
+pub fn run(options: &Options) -> RunStatus {
+    let ours = PathBuf::from(options.value_of("ours").unwrap());
+    let theirs = PathBuf::from(options.value_of("theirs").unwrap());
+
+    for path in [&ours, &theirs] {
+        if !path.is_dir() {
+            error!("Could not find output path {}", path.display());
+            return RunStatus::OutputError;
+        }
+    }
+
+    let parallel_files = options
+        .value::<usize>("parallel-files")
+        .unwrap_or(DEFAULT_PARALLEL_FILES);
+
+    let comparison = match compare_dirs(&ours, &theirs, parallel_files) {
+        Ok(comparison) => comparison,
+        Err(e) => {
+            error!("Error comparing fastq files: {}", e);
+            return RunStatus::OutputError;
+        }
+    };
+
+    println!("{}", comparison);
+
+    if let Some(json_path) = options.value_of("json") {
+        if let Err(e) = comparison.write(Path::new(&json_path)) {
+            error!("Error writing {}: {}", json_path, e);
+            return RunStatus::OutputError;
+        }
+    }
+
+    if comparison.is_concordant() {
+        RunStatus::Success
+    } else {
+        RunStatus::Discordant
+    }
+}
@@ -0,0 +1,1819 @@
+//! `bcl2fastr demux`: demultiplex a run into fastq files, and write out the stats
+//! and reports
+
+use clap::{App, Arg, SubCommand};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::delivery::{delivery_manifests, write_delivery_manifests};
+use common::demux::{demux_lanes, demux_lanes_numa};
+use common::file_limit::{FileLimit, LimitAction, RESERVED_FILES};
+use common::index_count::count_indexes;
+use common::loading::lane_loading;
+use common::logging::set_context;
+use common::metrics::{serve_metrics, DEFAULT_METRICS_BIND};
+use common::novaseq_run::{parse_lanes, NovaSeqRun, Shard};
+use common::numa::{numa_nodes, parse_cpulist, NumaNode};
+use common::output_sink::{LruFileSink, ScatterSink, SinkHandle, SinkKind, DEFAULT_MAX_OPEN_FILES};
+use common::overlap::OverlapOptions;
+use common::plan::{
+    auto_tune, available_memory, fit_memory, n_output_files, parse_memory, plan_demux, Tuning,
+};
+use common::progress::{Progress, ProgressMode};
+use common::provenance::{Provenance, RunInfoSummary};
+use common::read_structure::parse_read_names;
+use common::reports::write_reports;
+use common::run_summary::{RunStatus, RunSummary};
+use common::sample_data::{
+    merge_lanes, sample_numbers, split_lane_zero, ConflictPolicy, DualIndexMode, SampleData,
+};
+use common::screen::Screen;
+use common::shutdown::{install_handler, shutdown_requested, ResumeManifest};
+use common::stats::{
+    merge_lane_stats, write_combined_stats_json, write_run_stats_json, write_shard_stats_json,
+    write_stats_json, LaneStats,
+};
+use common::storage::{is_remote, output_storage, OutputHandle};
+use common::umi::{parse_umi_read, UmiOptions};
+use common::watch::{cache_index_cycles, wait_for_completion, wait_for_index_cycles, WatchOptions};
+use common::webhook::Webhooks;
+use common::write_fastq::{
+    check_ascii_offset, is_undetermined_limit, parse_phix_index, write_zstd_dictionary,
+    DimerOutput, NonPfOutput, PhixControl, ReadFilter, UndeterminedLimit,
+};
+
+use log::{error, info, warn};
+
+use crate::load::{
+    build_stage_pools_for, check_run_paths, check_samplesheet, check_sheet_reads, configure_run,
+    dual_index_args, i5_orientation, i5_orientation_arg, load_folder_check, load_incomplete_run,
+    load_lanes, load_lanes_isolated, load_run, load_run_parameters, load_samplesheet, matcher_args,
+    mismatch_arg, missing_files_error, on_conflict_arg, open_run, output_args, output_options,
+    run_load_args, run_path_arg, samplesheet_arg, set_dual_index_mode, stage_thread_args,
+    LoadError,
+};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("demux")
+        .about("demultiplex a run into fastq files for each sample")
+        .arg(run_path_arg().required_unless("runfolder"))
+        .arg(
+            Arg::with_name("runfolder")
+                .long("runfolder")
+                .help("a run folder to demultiplex, instead of --run-path. Give it once for each run of the same libraries (e.g. a top-up run), and the reads from all of them go in the same fastq files")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&[
+                    "run-path",
+                    "shard",
+                    "watch",
+                    "allow-incomplete",
+                    "dry-run",
+                    "index-cache",
+                ]),
+        )
+        .args(&run_load_args())
+        .arg(samplesheet_arg())
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("output path for fastq files")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("upload")
+                .long("upload")
+                .help("upload the fastq files to this s3:// or gs:// URL as they are written, instead of writing them to --output. The stats and reports are still written to --output, then uploaded at the end")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sink")
+                .long("sink")
+                .help("where the fastq files are written: file for files in --output, fifo for named pipes that are already at their paths, e.g. made with mkfifo, or null to throw the reads away after formatting and compressing them, to measure throughput without the storage [default: file]")
+                .possible_values(&["file", "fifo", "null"])
+                .conflicts_with("upload")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-chunks")
+                .long("read-chunks")
+                .help("number of tiles to process at once while reading")
+                .default_value("39")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("two-phase")
+                .long("two-phase")
+                .help("assign every cluster from the index cycles first, then read only the assigned clusters' reads. Faster for poorly matched runs, but the Undetermined yield and quality aren't computed, unless --two-phase-undetermined"),
+        )
+        .arg(
+            Arg::with_name("two-phase-undetermined")
+                .long("two-phase-undetermined")
+                .help("with --two-phase, read the undetermined clusters' reads in the second pass too, for their yield and quality, --screen-undetermined and --phix-output")
+                .requires("two-phase"),
+        )
+        .arg(
+            Arg::with_name("compression-workers")
+                .long("compression-workers")
+                .help("compress the output files on a worker thread for each compress thread, each with its share of the files and a bounded queue of reads for each file, so that a sample with a huge file doesn't hold up the rest"),
+        )
+        .arg(
+            Arg::with_name("queue-depth")
+                .long("queue-depth")
+                .help("the most blocks of reads waiting for each compression worker [default: 4]")
+                .requires("compression-workers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("write-buffer")
+                .long("write-buffer")
+                .help("size of the buffer for writing each output file, e.g. 64K [default: 8K]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auto-tune")
+                .long("auto-tune")
+                .help("choose --read-chunks, --write-buffer and --queue-depth for the number of samples and the memory, which is --memory-limit or half of what is available. The options that are given are kept"),
+        )
+        .arg(
+            Arg::with_name("many-samples")
+                .long("many-samples")
+                .help("for plates with thousands of samples: compress with --compression-workers, and keep at most --max-open-files output files open between chunks"),
+        )
+        .arg(
+            Arg::with_name("max-open-files")
+                .long("max-open-files")
+                .help("the most output files kept open with --many-samples [default: 512]")
+                .requires("many-samples")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scatter")
+                .long("scatter")
+                .help("with --many-samples, write the compressed chunks to a few spill files in the output folder as the tiles are demultiplexed, then gather each sample's files from them at the end. Only a handful of files are open at once, but everything is written twice")
+                .requires("many-samples")
+                .conflicts_with_all(&["sink", "upload"]),
+        )
+        .arg(
+            Arg::with_name("gpu")
+                .long("gpu")
+                .help("experimental: unpack the bases and match the barcodes on the GPU, if bcl2fastr was built with the gpu feature. Falls back to the CPU if there isn't a GPU"),
+        )
+        .arg(
+            Arg::with_name("numa")
+                .long("numa")
+                .help("split the lanes between the NUMA nodes, or the tiles of each lane if there are fewer lanes than nodes, each with a thread for every CPU on the node (instead of --threads) and its own buffers"),
+        )
+        .arg(
+            Arg::with_name("numa-nodes")
+                .long("numa-nodes")
+                .help("only use these NUMA nodes with --numa, e.g. 0-1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory-limit")
+                .long("memory-limit")
+                .help("keep the read and write buffers under this size, e.g. 32G, by reading fewer tiles at once")
+                .takes_value(true),
+        )
+        .arg(mismatch_arg())
+        .arg(on_conflict_arg())
+        .args(&matcher_args())
+        .args(&dual_index_args())
+        .arg(i5_orientation_arg())
+        .arg(
+            Arg::with_name("lanes")
+                .long("lanes")
+                .help("only process these lanes, e.g. 1,3-4")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-lane-splitting")
+                .long("no-lane-splitting")
+                .help("write each sample's reads from every lane to one set of fastq files, without the lane in their names. Every lane in the samplesheet has to have the same samples"),
+        )
+        .arg(
+            Arg::with_name("shard")
+                .long("shard")
+                .help("process one share of the tiles, e.g. 2/4, so that a run can be split across machines. Combine the stats afterwards with merge-stats")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("first-tile-only")
+                .long("first-tile-only")
+                .help("only process the first tile of each lane, to check the samplesheet and index orientation in a few minutes before demultiplexing the whole run"),
+        )
+        .arg(
+            Arg::with_name("abort-if-undetermined-above")
+                .long("abort-if-undetermined-above")
+                .help("stop with an error if more than this fraction of a lane's reads are undetermined, e.g. 0.9, once --check-after-tiles tiles are done")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("check-after-tiles")
+                .long("check-after-tiles")
+                .help("the number of tiles of each lane to demultiplex before checking --abort-if-undetermined-above")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("index-cache")
+                .long("index-cache")
+                .help("keep the index reads of each tile in this directory. A rerun with the same directory (e.g. with a corrected samplesheet) uses them instead of decoding the index cycles again")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("only-reads")
+                .long("only-reads")
+                .help("only write these reads, e.g. R1 for counting applications. The other reads aren't decoded at all")
+                .takes_value(true)
+                .conflicts_with("skip-reads"),
+        )
+        .arg(
+            Arg::with_name("skip-reads")
+                .long("skip-reads")
+                .help("don't write these reads, e.g. R2")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("delivery-manifests")
+                .long("delivery-manifests")
+                .help("write delivery_manifest.json in each project's directory, with the size, CRC32, SHA-256 and read count of each fastq file. Every file is read again to checksum it"),
+        )
+        .arg(
+            Arg::with_name("min-mean-quality")
+                .long("min-mean-quality")
+                .help("filter out clusters with a read whose mean quality score is below this, e.g. 20")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-n-fraction")
+                .long("max-n-fraction")
+                .help("filter out clusters with a read that has more than this fraction of Ns, e.g. 0.1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keep-filtered")
+                .long("keep-filtered")
+                .help("write the clusters filtered out by --min-mean-quality or --max-n-fraction to Filtered_R1.fastq.gz etc, instead of dropping them"),
+        )
+        .arg(
+            Arg::with_name("include-non-pf")
+                .long("include-non-pf")
+                .help("also write the clusters that failed filter, if the CBCL files have them: inline with a Y in the read name's filter field, or separate in <sample>_R1_nonPF.fastq.gz etc")
+                .takes_value(true)
+                .possible_values(&["inline", "separate"]),
+        )
+        .arg(
+            Arg::with_name("phix")
+                .long("phix")
+                .help("count the undetermined reads that look like the PhiX spike-in (no signal in the first index) on their own in the stats, instead of as Undetermined"),
+        )
+        .arg(
+            Arg::with_name("phix-index")
+                .long("phix-index")
+                .help("the index of an indexed PhiX, e.g. ACGTACGT or ACGTACGT+TTGGCCAA, to count as PhiX as well. Implies --phix")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("screen-undetermined")
+                .long("screen-undetermined")
+                .help("check this many undetermined reads in each lane for PhiX, adapter dimers and low-complexity sequence, and write the fractions to Reports/Undetermined_Screen.csv")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("screen-reference")
+                .long("screen-reference")
+                .help("a FASTA file of the PhiX genome, so that --screen-undetermined also counts reads with its 16-mers as PhiX")
+                .takes_value(true)
+                .requires("screen-undetermined"),
+        )
+        .arg(
+            Arg::with_name("phix-output")
+                .long("phix-output")
+                .help("write the PhiX reads to PhiX_R1.fastq.gz etc. Implies --phix"),
+        )
+        .arg(
+            Arg::with_name("correct-overlap")
+                .long("correct-overlap")
+                .help("find where R1 and R2 overlap, for inserts shorter than the two reads, and where they disagree take the call with the higher quality score for both. The corrected positions are listed in the read name as XC:Z:12,40"),
+        )
+        .arg(
+            Arg::with_name("trim-overlap")
+                .long("trim-overlap")
+                .help("find where R1 and R2 overlap, and when the insert is shorter than a read, trim the bases past it, which read through into the adapter"),
+        )
+        .arg(
+            Arg::with_name("min-overlap")
+                .long("min-overlap")
+                .help("the fewest bases that R1 and R2 have to overlap by for --correct-overlap and --trim-overlap")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("adapter-dimers")
+                .long("adapter-dimers")
+                .help("find the reads that start with an adapter, so have no insert, and count them for each sample in Reports/Adapter_Dimers.csv. With separate, they are written to <sample>_R1_dimer.fastq.gz etc instead of the sample's files")
+                .takes_value(true)
+                .possible_values(&["count", "separate"]),
+        )
+        .arg(
+            Arg::with_name("umi-read")
+                .long("umi-read")
+                .help("the index read with the UMIs, e.g. I2 when the i5 read is a UMI. It isn't matched to the samples, which have the other index, and the UMI quality is written to Reports/UMI_Metrics.csv")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("umi-fastq")
+                .long("umi-fastq")
+                .help("write the UMIs of each sample to <sample>_UMI.fastq.gz etc, next to its reads")
+                .requires("umi-read"),
+        )
+        .arg(
+            Arg::with_name("umi-min-quality")
+                .long("umi-min-quality")
+                .help("a UMI with a base below this quality score, or with an N, is counted as invalid")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mask-invalid-umis")
+                .long("mask-invalid-umis")
+                .help("replace every base of an invalid UMI with an N, in the read names and the UMI files")
+                .requires("umi-read"),
+        )
+        .arg(
+            Arg::with_name("single-file")
+                .long("single-file")
+                .help("write the reads of every sample to All_L001_R1.fastq.gz etc, with the sample in each read name as SM:Z:<sample>, for tools that split them themselves")
+                .conflicts_with("delivery-manifests"),
+        )
+        .arg(
+            Arg::with_name("sample-numbers")
+                .long("sample-numbers")
+                .help("name the fastq files as bcl2fastq does, with each sample's number from the order of the samplesheet: sample_1_S1_L001_R1.fastq.gz etc"),
+        )
+        .arg(
+            Arg::with_name("no-index-truncation")
+                .long("no-index-truncation")
+                .help("match every index cycle, even when the samplesheet's indices are shorter. By default only as many bases as the indices have are matched, e.g. the first 8 of 10 index cycles"),
+        )
+        .args(&output_args())
+        .args(&stage_thread_args())
+        .arg(
+            Arg::with_name("allow-incomplete")
+                .long("allow-incomplete")
+                .help("demultiplex a run that is still sequencing, using only the cycles that are complete"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .help("wait for a run that is still sequencing: check and decode the indices as soon as they are written, and demultiplex the rest when the run is complete"),
+        )
+        .arg(
+            Arg::with_name("watch-interval")
+                .long("watch-interval")
+                .help("seconds between checks of the run folder in watch mode")
+                .default_value("60")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-timeout")
+                .long("watch-timeout")
+                .help("in watch mode, give up on a run that has written no new cycle for this many seconds")
+                .default_value("7200")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("load the run and samplesheet and print the plan for the demux, without writing any fastq files"),
+        )
+        .arg(
+            Arg::with_name("provenance-headers")
+                .long("provenance-headers")
+                .help("add the bcl2fastr version and SHA-256 of the samplesheet from provenance.json to every read header"),
+        )
+        .arg(
+            Arg::with_name("json-progress")
+                .long("json-progress")
+                .help("print progress to stdout as lines of JSON, instead of a progress bar"),
+        )
+        .arg(
+            Arg::with_name("metrics-port")
+                .long("metrics-port")
+                .help("serve Prometheus metrics over HTTP on this port while running")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-bind")
+                .long("metrics-bind")
+                .help("the address to serve the metrics on, e.g. 0.0.0.0 for every interface [default: 127.0.0.1, this host only]")
+                .requires("metrics-port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-complete-webhook")
+                .long("on-complete-webhook")
+                .help("POST the run summary to this URL when the run finishes (or fails, if there is no failure webhook)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-failure-webhook")
+                .long("on-failure-webhook")
+                .help("POST the run summary to this URL if the run fails")
+                .takes_value(true),
+        )
+}
+
+/// Log a fatal error, write out the run summary if we have somewhere to put it, and
+/// exit with the code for `status`
+fn exit_with_error(
+    summary: &mut RunSummary,
+    webhooks: &Webhooks,
+    output_path: Option<&Path>,
+    status: RunStatus,
+    message: String,
+) -> ! {
+    summary.error(message);
+
+    if let Some(output_path) = output_path {
+        if let Err(e) = summary.finish(status, output_path) {
+            error!("Error writing run_summary.json: {}", e);
+        }
+    }
+
+    webhooks.notify(summary, status);
+
+    process::exit(status.exit_code());
+}
+
+/// The read filter from `--min-mean-quality` and `--max-n-fraction`, if either is set
+fn read_filter(options: &Options) -> Option<ReadFilter> {
+    let min_mean_quality = options.value::<f64>("min-mean-quality");
+    let max_n_fraction = options.value::<f64>("max-n-fraction");
+
+    if let Some(q) = min_mean_quality.filter(|q| q.is_nan() || *q < 0.) {
+        clap::Error {
+            message: format!(
+                "invalid value for 'min-mean-quality': expected a quality score of 0 or more, got {}",
+                q
+            ),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit()
+    }
+    if let Some(f) = max_n_fraction.filter(|f| !(0.0..=1.0).contains(f)) {
+        clap::Error {
+            message: format!(
+                "invalid value for 'max-n-fraction': expected a fraction from 0 to 1, got {}",
+                f
+            ),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit()
+    }
+
+    if min_mean_quality.is_none() && max_n_fraction.is_none() {
+        if options.is_present("keep-filtered") {
+            warn!("--keep-filtered does nothing without --min-mean-quality or --max-n-fraction");
+        }
+        return None;
+    }
+
+    Some(ReadFilter {
+        min_mean_quality,
+        max_n_fraction,
+        keep_filtered: options.is_present("keep-filtered"),
+    })
+}
+
+/// How PhiX is told apart from the undetermined reads, from `--phix`,
+/// `--phix-index` and `--phix-output`, if it is
+/// The UMI options, if the run has UMIs in an index read
+fn umi_options(options: &Options) -> Option<UmiOptions> {
+    let index_read = parse_umi_read(&options.value_of("umi-read")?).unwrap_or_else(|e| {
+        clap::Error {
+            message: format!("invalid value for 'umi-read': {}", e),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit()
+    });
+
+    Some(UmiOptions {
+        index_read,
+        write: options.is_present("umi-fastq"),
+        min_quality: options.value::<u8>("umi-min-quality").unwrap(),
+        mask: options.is_present("mask-invalid-umis"),
+    })
+}
+
+fn phix_control(options: &Options) -> Option<PhixControl> {
+    if !["phix", "phix-index", "phix-output"]
+        .iter()
+        .any(|&arg| options.is_present(arg))
+    {
+        return None;
+    }
+
+    let indices = options
+        .values_of("phix-index")
+        .iter()
+        .map(|index| {
+            parse_phix_index(index).unwrap_or_else(|e| {
+                clap::Error {
+                    message: format!("invalid value for 'phix-index': {}", e),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }
+                .exit()
+            })
+        })
+        .collect();
+
+    Some(PhixControl {
+        indices,
+        write: options.is_present("phix-output"),
+    })
+}
+
+/// The template reads to skip, from `--only-reads` or `--skip-reads`, for a run with
+/// `n_templates` of them
+fn skip_reads(options: &Options, n_templates: usize) -> BTreeSet<usize> {
+    let invalid = |name: &str, message: String| -> ! {
+        clap::Error {
+            message: format!("invalid value for '{}': {}", name, message),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit()
+    };
+
+    let (name, reads) = match (
+        options.value_of("only-reads"),
+        options.value_of("skip-reads"),
+    ) {
+        (Some(reads), _) => ("only-reads", reads),
+        (None, Some(reads)) => ("skip-reads", reads),
+        (None, None) => return BTreeSet::new(),
+    };
+    let reads = parse_read_names(&reads).unwrap_or_else(|e| invalid(name, e));
+
+    if let Some(read_num) = reads.iter().find(|&&r| r > n_templates) {
+        invalid(
+            name,
+            format!("the run has no R{}, only {} reads", read_num, n_templates),
+        );
+    }
+
+    let skip_reads: BTreeSet<_> = if name == "only-reads" {
+        (1..=n_templates).filter(|r| !reads.contains(r)).collect()
+    } else {
+        reads
+    };
+    if skip_reads.len() == n_templates {
+        invalid(name, "there would be no reads left to write".to_string());
+    }
+
+    skip_reads
+}
+
+/// The NUMA nodes to use with `--numa`: all of them, or the ones in `node_ids`.
+/// Returns None, to run as usual, if the nodes can't be found
+fn select_numa_nodes(node_ids: Option<&str>) -> Option<Vec<NumaNode>> {
+    let node_ids = node_ids.map(|node_ids| {
+        parse_cpulist(node_ids).unwrap_or_else(|e| {
+            clap::Error {
+                message: format!("invalid value for 'numa-nodes': {}", e),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        })
+    });
+
+    let mut nodes = match numa_nodes() {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            warn!(
+                "couldn't find the NUMA nodes, running without --numa: {}",
+                e
+            );
+            return None;
+        }
+    };
+
+    if let Some(node_ids) = node_ids {
+        nodes.retain(|n| node_ids.contains(&n.id));
+    }
+    if nodes.is_empty() {
+        clap::Error {
+            message: "invalid value for 'numa-nodes': no NUMA nodes with CPUs".to_string(),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit()
+    }
+
+    info!(
+        "using NUMA nodes {:?}",
+        nodes.iter().map(|n| n.id).collect::<Vec<_>>()
+    );
+    Some(nodes)
+}
+
+/// The number of index sequences to count when checking the indices early
+const EARLY_INDEX_COUNTS: usize = 384;
+
+/// The index cache in the output folder for a watched run, removed after the demux
+const WATCH_INDEX_CACHE: &str = ".watch_index_cache";
+
+/// Check that runs demultiplexed together are of the same libraries: the same reads,
+/// and read names that can't be mistaken for each other. Returns the problem if not
+fn check_same_libraries(novaseq_runs: &[NovaSeqRun]) -> Option<String> {
+    let first = &novaseq_runs[0];
+    for (i, novaseq_run) in novaseq_runs.iter().enumerate().skip(1) {
+        if novaseq_run.read_structure.to_string() != first.read_structure.to_string() {
+            return Some(format!(
+                "{} has reads {}, but {} has {}",
+                novaseq_run.run_info.id,
+                novaseq_run.read_structure,
+                first.run_info.id,
+                first.read_structure
+            ));
+        }
+
+        // read names start with the instrument, run number and flowcell
+        if let Some(other) = novaseq_runs[..i]
+            .iter()
+            .find(|other| other.run_id == novaseq_run.run_id)
+        {
+            return Some(format!(
+                "{} and {} would have the same read names ({})",
+                other.run_info.id, novaseq_run.run_info.id, novaseq_run.run_id
+            ));
+        }
+    }
+
+    None
+}
+
+/// Write the stats of each of several runs demultiplexed together, then combine
+/// them into Stats.json. Returns the combined stats
+fn write_runs_stats(
+    output_path: &Path,
+    novaseq_runs: &[NovaSeqRun],
+    run_lane_stats: Vec<Vec<LaneStats>>,
+) -> std::io::Result<Vec<LaneStats>> {
+    for (novaseq_run, lane_stats) in novaseq_runs.iter().zip(&run_lane_stats) {
+        write_run_stats_json(output_path, novaseq_run, lane_stats)?;
+    }
+
+    let demuxed_runs = &novaseq_runs[..run_lane_stats.len()];
+    let lane_stats = merge_lane_stats(run_lane_stats)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    write_combined_stats_json(output_path, demuxed_runs, &lane_stats)?;
+
+    Ok(lane_stats)
+}
+
+/// Count the most common index sequences in an index-only run and check that most
+/// of them match a sample. Returns a warning if they don't, since that probably
+/// means there's a problem with the samplesheet
+fn check_early_indexes(index_run: &NovaSeqRun, sample_data: &SampleData) -> Option<String> {
+    let counts = count_indexes(index_run, EARLY_INDEX_COUNTS);
+
+    let total = counts.iter().map(|(_, n)| n).sum::<usize>();
+    let matched = counts
+        .iter()
+        .filter(|(index, _)| {
+            let indices: Vec<_> = index.split(|&b| b == b'+').map(|i| i.to_vec()).collect();
+            sample_data
+                .values()
+                .any(|samples| samples.is_any_sample(&indices))
+        })
+        .map(|(_, n)| n)
+        .sum::<usize>();
+
+    let percent_matched = 100. * matched as f64 / total.max(1) as f64;
+    info!(
+        "{:.1}% of the most common index sequences match a sample",
+        percent_matched
+    );
+
+    if percent_matched < 50. {
+        Some(format!(
+            "only {:.1}% of the most common index sequences match a sample: check the samplesheet",
+            percent_matched
+        ))
+    } else {
+        None
+    }
+}
+
+/// Demultiplex the run and write the stats, reports, and run summary
+pub fn run(options: &Options) -> RunStatus {
+    let mut summary = RunSummary::new();
+    let webhooks = Webhooks {
+        on_complete: options.value_of("on-complete-webhook"),
+        on_failure: options.value_of("on-failure-webhook"),
+    };
+
+    let output_path = PathBuf::from(options.value_of("output").unwrap());
+    if !output_path.exists() {
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            None,
+            RunStatus::OutputError,
+            format!("Could not find output path {}", output_path.display()),
+        );
+    }
+    if !output_path.is_dir() {
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            None,
+            RunStatus::OutputError,
+            format!("Output path {} is not a directory", output_path.display()),
+        );
+    }
+
+    let shard = options.value::<Shard>("shard");
+    if let Some(shard) = &shard {
+        summary.set_shard(shard);
+    }
+
+    let mut load_error = |e: LoadError| -> ! {
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            Some(&output_path),
+            e.status,
+            e.message,
+        )
+    };
+
+    let run_paths = check_run_paths(options).unwrap_or_else(|e| load_error(e));
+    // the first run sets the i5 orientation and output options for all of them
+    let run_path = run_paths[0].clone();
+    let samplesheets = check_samplesheet(options).unwrap_or_else(|e| load_error(e));
+    let run_parameters = load_run_parameters(&run_path).unwrap_or_else(|e| load_error(e));
+
+    let mut r_chunks = options.value::<usize>("read-chunks").unwrap();
+    let mismatch = options.value::<usize>("mismatch").unwrap();
+    let on_conflict = options.value::<ConflictPolicy>("on-conflict").unwrap();
+    let i5_orientation = i5_orientation(options, &run_parameters);
+    let mut output_options = output_options(options, &run_parameters);
+    output_options.shard = shard;
+    output_options.two_phase = options.is_present("two-phase");
+    output_options.two_phase_undetermined = options.is_present("two-phase-undetermined");
+    // these need the undetermined reads, which --two-phase skips
+    if output_options.two_phase && !output_options.two_phase_undetermined {
+        for name in ["screen-undetermined", "phix-output"] {
+            if options.is_present(name) {
+                clap::Error {
+                    message: format!(
+                        "invalid value for '{}': the undetermined reads aren't read with --two-phase, unless with --two-phase-undetermined",
+                        name
+                    ),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }
+                .exit()
+            }
+        }
+    }
+    output_options.compression_workers = options.is_present("compression-workers");
+    // a worker for each compress thread, each with its own group of the files
+    let compress_threads = options
+        .value::<usize>("compress-threads")
+        .or_else(|| options.value::<usize>("threads"))
+        .unwrap();
+    if output_options.compression_workers {
+        output_options.writer_groups = Some(compress_threads);
+    }
+    if let Some(queue_depth) = options.value::<usize>("queue-depth") {
+        if queue_depth == 0 {
+            clap::Error {
+                message: "invalid value for 'queue-depth': it has to be at least 1".to_string(),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        }
+        output_options.queue_depth = queue_depth;
+    }
+    if let Some(write_buffer) = options.value_of("write-buffer") {
+        output_options.write_buffer = parse_memory(&write_buffer).unwrap_or_else(|e| {
+            clap::Error {
+                message: format!("invalid value for 'write-buffer': {}", e),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        }) as usize;
+    }
+    output_options.gpu = options.is_present("gpu");
+    if output_options.gpu && !cfg!(feature = "gpu") {
+        warn!("bcl2fastr was built without the gpu feature, using the CPU");
+    }
+    output_options.undetermined_limit = options
+        .value::<f64>("abort-if-undetermined-above")
+        .map(|max_fraction| {
+            if !(0.0..=1.0).contains(&max_fraction) {
+                clap::Error {
+                    message: format!(
+                        "invalid value for 'abort-if-undetermined-above': expected a fraction from 0 to 1, got {}",
+                        max_fraction
+                    ),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }
+                .exit()
+            }
+
+            UndeterminedLimit {
+                max_fraction,
+                after_tiles: options.value::<usize>("check-after-tiles").unwrap(),
+            }
+        });
+    output_options.read_filter = read_filter(options);
+    output_options.non_pf = options.value("include-non-pf");
+    output_options.phix = phix_control(options);
+    output_options.adapter_dimers = options.value("adapter-dimers");
+    output_options.umi = umi_options(options);
+    output_options.single_file = options.is_present("single-file");
+    output_options.sample_numbers = options.is_present("sample-numbers");
+    output_options.truncate_indices = !options.is_present("no-index-truncation");
+    if output_options.single_file {
+        // the samples' own files would only have some of their reads
+        for (name, separate) in [
+            (
+                "include-non-pf",
+                output_options.non_pf == Some(NonPfOutput::Separate),
+            ),
+            (
+                "adapter-dimers",
+                output_options.adapter_dimers == Some(DimerOutput::Separate),
+            ),
+        ] {
+            if separate {
+                clap::Error {
+                    message: format!(
+                        "invalid value for '{}': separate files for each sample can't be used with --single-file",
+                        name
+                    ),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }
+                .exit()
+            }
+        }
+    }
+    if options.is_present("correct-overlap") || options.is_present("trim-overlap") {
+        output_options.overlap = Some(OverlapOptions {
+            min_overlap: options.value::<usize>("min-overlap").unwrap(),
+            correct: options.is_present("correct-overlap"),
+            trim: options.is_present("trim-overlap"),
+        });
+    }
+    output_options.screen = options
+        .value::<u64>("screen-undetermined")
+        .map(|max_reads| {
+            let mut screen = Screen::new(max_reads);
+            if let Some(reference) = options.value_of("screen-reference") {
+                if let Err(e) = screen.add_reference(Path::new(&reference)) {
+                    clap::Error {
+                        message: format!("invalid value for 'screen-reference': {}", e),
+                        kind: clap::ErrorKind::InvalidValue,
+                        info: None,
+                    }
+                    .exit()
+                }
+            }
+            screen
+        });
+    if let Some(index_cache) = options.value_of("index-cache") {
+        let index_cache = PathBuf::from(index_cache);
+        if let Err(e) = std::fs::create_dir_all(&index_cache) {
+            load_error(LoadError {
+                status: RunStatus::OutputError,
+                message: format!(
+                    "Could not create index cache {}: {}",
+                    index_cache.display(),
+                    e
+                ),
+            })
+        }
+        output_options.index_cache = Some(index_cache);
+    }
+    if let Some(url) = options.value_of("upload") {
+        let upload = output_storage(&url, &output_path).unwrap_or_else(|e| {
+            load_error(LoadError {
+                status: RunStatus::OutputError,
+                message: e.to_string(),
+            })
+        });
+        output_options.upload = Some(OutputHandle(upload));
+    }
+    output_options.sink = options.value::<SinkKind>("sink").and_then(SinkKind::sink);
+    if options.is_present("many-samples") {
+        output_options.compression_workers = true;
+        output_options.writer_groups = Some(compress_threads);
+
+        if options.is_present("scatter") {
+            // the spill files of each shard are kept apart
+            let spill_path = output_path.join(format!(".spill_{}", process::id()));
+            // and a spill file for each writer group
+            output_options.sink = Some(SinkHandle(Arc::new(ScatterSink::new(
+                spill_path,
+                output_options.writer_groups.unwrap(),
+            ))));
+        } else if output_options.sink.is_none() && output_options.upload.is_none() {
+            let max_open = options
+                .value::<usize>("max-open-files")
+                .unwrap_or(DEFAULT_MAX_OPEN_FILES);
+            output_options.sink = Some(SinkHandle(Arc::new(LruFileSink::new(max_open))));
+        }
+    }
+    let provenance_headers = options.is_present("provenance-headers");
+
+    let memory_limit = options.value_of("memory-limit").map(|memory_limit| {
+        parse_memory(&memory_limit).unwrap_or_else(|e| {
+            clap::Error {
+                message: format!("invalid value for 'memory-limit': {}", e),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        })
+    });
+
+    let lanes = options.value_of("lanes").map(|lanes| {
+        parse_lanes(&lanes).unwrap_or_else(|e| {
+            clap::Error {
+                message: format!("invalid value for 'lanes': {}", e),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        })
+    });
+
+    let numa = if options.is_present("numa") {
+        select_numa_nodes(options.value_of("numa-nodes").as_deref())
+    } else {
+        None
+    };
+
+    info!("reading Index2 in {} orientation", i5_orientation);
+    let (mut sample_data, conflicts) =
+        load_samplesheet(&samplesheets, mismatch, i5_orientation, on_conflict)
+            .unwrap_or_else(|e| load_error(e));
+    let dual_index_mode =
+        set_dual_index_mode(options, &mut sample_data).unwrap_or_else(|e| load_error(e));
+    if dual_index_mode != DualIndexMode::Combinatorial {
+        info!("matching indices as {}", dual_index_mode);
+    }
+
+    if let Some(lanes) = &lanes {
+        // lane 0 means the samplesheet has no lanes, so the samples are in every lane
+        sample_data.retain(|lane, _| *lane == 0 || lanes.contains(lane));
+        if sample_data.is_empty() {
+            load_error(LoadError {
+                status: RunStatus::SamplesheetError,
+                message: "No samples in the selected lanes".to_string(),
+            });
+        }
+    }
+    // what was changed in the samplesheet goes in the run summary
+    let mut warnings = conflicts;
+    // the unassigned clusters are dropped before their reads are decoded
+    if output_options.two_phase && !output_options.two_phase_undetermined {
+        warnings.push(
+            "--two-phase doesn't decode the undetermined reads, so they aren't written and their yield and quality aren't in the stats. Add --two-phase-undetermined to decode them"
+                .to_string(),
+        );
+    }
+
+    // a cache for the indices decoded while the run is sequencing, if there isn't one
+    let mut watch_index_cache = None;
+    if options.is_present("watch") {
+        let watch = WatchOptions {
+            interval: Duration::from_secs(options.value::<u64>("watch-interval").unwrap()),
+            timeout: Duration::from_secs(options.value::<u64>("watch-timeout").unwrap()),
+        };
+        let watch_error = |e: std::io::Error| LoadError {
+            status: RunStatus::BasecallError,
+            message: format!("Error watching the run: {}", e),
+        };
+
+        wait_for_index_cycles(&run_path, watch).unwrap_or_else(|e| load_error(watch_error(e)));
+
+        let mut index_run =
+            load_run(run_path.clone(), true, options).unwrap_or_else(|e| load_error(e));
+        if let Some(lanes) = &lanes {
+            index_run.retain_lanes(lanes);
+        }
+        warnings.extend(check_early_indexes(&index_run, &sample_data));
+
+        let cache_path = output_options
+            .index_cache
+            .get_or_insert_with(|| {
+                let cache_path = output_path.join(WATCH_INDEX_CACHE);
+                watch_index_cache = Some(cache_path.clone());
+                cache_path
+            })
+            .clone();
+        std::fs::create_dir_all(&cache_path)
+            .and_then(|_| {
+                cache_index_cycles(&index_run, &cache_path, output_options.truncate_indices)
+            })
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Error caching the index cycles, they will be decoded after the run: {}",
+                    e
+                );
+                0
+            });
+
+        wait_for_completion(&run_path, watch).unwrap_or_else(|e| load_error(watch_error(e)));
+    }
+
+    // with one run split by lane, a lane that can't be read fails on its own and the
+    // others are still demultiplexed
+    let isolate_lanes = run_paths.len() == 1 && !options.is_present("no-lane-splitting");
+    let mut failed_lanes: BTreeMap<usize, String> = BTreeMap::new();
+
+    // an incomplete run is missing cycles by design, so only check a finished one.
+    // Listing a run in object storage is slow, so its missing files are found as
+    // they load. An archive is checked against its index
+    if !options.is_present("allow-incomplete") {
+        for run_path in run_paths.iter().filter(|run_path| !is_remote(run_path)) {
+            let folder_check =
+                load_folder_check(run_path, lanes.as_ref()).unwrap_or_else(|e| load_error(e));
+            match folder_check.missing_by_lane() {
+                _ if folder_check.is_complete() => (),
+                Some(missing) if isolate_lanes => {
+                    for (lane, files) in missing {
+                        failed_lanes.insert(
+                            lane,
+                            format!(
+                                "run folder is missing {} files, e.g. {}",
+                                files.len(),
+                                files[0].display()
+                            ),
+                        );
+                    }
+                }
+                _ => load_error(missing_files_error(&folder_check)),
+            }
+            warnings.extend(
+                folder_check
+                    .unexpected
+                    .iter()
+                    .map(|p| format!("Unexpected file in run folder: {}", p.display())),
+            );
+        }
+    }
+
+    let mut novaseq_runs = Vec::new();
+    for run_path in run_paths {
+        let novaseq_run = if options.is_present("allow-incomplete") {
+            load_incomplete_run(run_path).map(|(novaseq_run, run_cycles)| {
+                let n_cycles = novaseq_run.run_info.total_cycles();
+                if n_cycles < run_cycles {
+                    warnings.push(format!(
+                        "run is incomplete: demultiplexed {} of {} cycles",
+                        n_cycles, run_cycles
+                    ));
+                }
+                novaseq_run
+            })
+        } else if isolate_lanes {
+            open_run(run_path, false).and_then(|mut novaseq_run| {
+                configure_run(options, &mut novaseq_run);
+                let lane_count = novaseq_run.run_info.flowcell_layout.lane_count;
+                let load: BTreeSet<_> = match &lanes {
+                    Some(lanes) => lanes.clone(),
+                    None => (1..=lane_count).collect(),
+                }
+                .into_iter()
+                .filter(|lane| !failed_lanes.contains_key(lane))
+                .collect();
+                if load.is_empty() {
+                    return Err(LoadError {
+                        status: RunStatus::BasecallError,
+                        message: format!(
+                            "Every lane failed to load: {}",
+                            failed_lanes
+                                .values()
+                                .cloned()
+                                .collect::<Vec<_>>()
+                                .join("; ")
+                        ),
+                    });
+                }
+
+                failed_lanes.extend(load_lanes_isolated(&mut novaseq_run, &load)?);
+                Ok(novaseq_run)
+            })
+        } else if let Some(lanes) = &lanes {
+            // only read the headers and filters for the lanes we need
+            open_run(run_path, false).and_then(|mut novaseq_run| {
+                configure_run(options, &mut novaseq_run);
+                load_lanes(&mut novaseq_run, Some(lanes)).map(|_| novaseq_run)
+            })
+        } else {
+            load_run(run_path, false, options)
+        }
+        .unwrap_or_else(|e| load_error(e));
+
+        let sheet_problems =
+            check_sheet_reads(&samplesheets, &novaseq_run).unwrap_or_else(|e| load_error(e));
+        if !sheet_problems.is_empty() {
+            load_error(LoadError {
+                status: RunStatus::SamplesheetError,
+                message: format!(
+                    "The samplesheet was written for a different run: {}",
+                    sheet_problems.join("; ")
+                ),
+            });
+        }
+
+        if let Err(e) = check_ascii_offset(&novaseq_run, output_options.ascii_offset) {
+            clap::Error {
+                message: format!("invalid value for 'ascii-offset': {}", e),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        }
+
+        novaseq_runs.push(novaseq_run);
+    }
+    if let Some(problem) = check_same_libraries(&novaseq_runs) {
+        load_error(LoadError {
+            status: RunStatus::BasecallError,
+            message: format!("Can't demultiplex these runs together: {}", problem),
+        });
+    }
+
+    if let Some(lanes) = &lanes {
+        for novaseq_run in novaseq_runs.iter_mut() {
+            novaseq_run.retain_lanes(lanes);
+        }
+    }
+
+    if options.is_present("no-lane-splitting") {
+        match merge_lanes(&mut sample_data) {
+            Ok(Some(sheet_lanes)) => {
+                for novaseq_run in novaseq_runs.iter_mut() {
+                    novaseq_run.retain_lanes(&sheet_lanes);
+                }
+            }
+            Ok(None) => (),
+            Err(e) => load_error(LoadError {
+                status: RunStatus::SamplesheetError,
+                message: format!("Can't use --no-lane-splitting: {}", e),
+            }),
+        }
+    } else {
+        // a samplesheet without lanes has the same samples in every lane of the run
+        let mut run_lanes: Vec<_> = novaseq_runs
+            .iter()
+            .flat_map(|novaseq_run| {
+                (1..=novaseq_run.run_info.flowcell_layout.lane_count)
+                    .filter(move |&lane| novaseq_run.tile_count(lane) > 0)
+            })
+            .collect();
+        run_lanes.sort_unstable();
+        run_lanes.dedup();
+        split_lane_zero(&mut sample_data, &run_lanes);
+        sample_data.retain(|lane, _| !failed_lanes.contains_key(lane));
+    }
+
+    // --shard can't be used with more than one run
+    if let Some(shard) = &shard {
+        let novaseq_run = &mut novaseq_runs[0];
+        novaseq_run.retain_shard(shard);
+        if novaseq_run.tile_count(0) == 0 {
+            load_error(LoadError {
+                status: RunStatus::BasecallError,
+                message: format!("No tiles in shard {}", shard),
+            });
+        }
+    }
+
+    output_options.skip_reads = skip_reads(options, novaseq_runs[0].read_structure.n_templates());
+    let n_written = (1..=novaseq_runs[0].read_structure.n_templates())
+        .filter(|&read_num| output_options.writes_read(read_num))
+        .count();
+    if output_options.overlap.is_some() && n_written != 2 {
+        load_error(LoadError {
+            status: RunStatus::BasecallError,
+            message: format!(
+                "Can't overlap the reads: {} template reads are written, not a pair",
+                n_written
+            ),
+        })
+    }
+
+    if let Some(umi) = &output_options.umi {
+        let n_indices = novaseq_runs[0].read_structure.n_indices();
+        if umi.index_read > n_indices {
+            load_error(LoadError {
+                status: RunStatus::BasecallError,
+                message: format!(
+                    "Can't read the UMIs from I{}: the run has {} index reads",
+                    umi.index_read, n_indices
+                ),
+            })
+        }
+        // the samples only have the indices that aren't UMIs
+        if let Some(samples) = sample_data
+            .values()
+            .find(|samples| samples.n_indices() != n_indices - 1)
+        {
+            load_error(LoadError {
+                status: RunStatus::SamplesheetError,
+                message: format!(
+                    "The samples have {} indices, but with the UMIs in I{} there are {} index reads to match",
+                    samples.n_indices(),
+                    umi.index_read,
+                    n_indices - 1
+                ),
+            })
+        }
+    }
+
+    for novaseq_run in novaseq_runs.iter_mut() {
+        if output_options.non_pf.is_some() {
+            if let Err(e) = novaseq_run.include_non_pf() {
+                load_error(LoadError {
+                    status: RunStatus::BasecallError,
+                    message: format!("Can't use --include-non-pf: {}", e),
+                })
+            }
+        }
+
+        if options.is_present("first-tile-only") {
+            novaseq_run.retain_first_tiles();
+            info!(
+                "Spot check: only demultiplexing {} tiles, the first of each lane",
+                novaseq_run.tile_count(0)
+            );
+        }
+    }
+
+    let n_threads = options.value::<usize>("threads").unwrap();
+
+    // check the open file limit now, instead of running out partway through
+    if let Some(limit) = FileLimit::current() {
+        let lane_files = novaseq_runs
+            .iter()
+            .map(|novaseq_run| n_output_files(novaseq_run, &sample_data, &output_options))
+            .max()
+            .unwrap_or(0);
+        // compression workers are put in groups if the files have to be pooled
+        let pooled_writers = if output_options.compression_workers {
+            output_options.writer_groups.unwrap_or(n_threads)
+        } else {
+            options
+                .value::<usize>("compress-threads")
+                .unwrap_or(n_threads)
+        };
+        let n_writers = if output_options.compression_workers {
+            output_options
+                .writer_groups
+                .unwrap_or(n_threads)
+                .min(lane_files)
+        } else {
+            pooled_writers
+        };
+        let output_files = output_options
+            .sink()
+            .open_files(lane_files * sample_data.len(), n_writers);
+
+        let action = limit
+            .action(output_files as u64, pooled_writers)
+            .unwrap_or_else(|e| {
+                load_error(LoadError {
+                    status: RunStatus::OutputError,
+                    message: e,
+                })
+            });
+        match action {
+            LimitAction::Enough => {}
+            LimitAction::Raise(soft) => match limit.raise(soft) {
+                Ok(()) => info!("raised the open file limit from {} to {}", limit.soft, soft),
+                Err(e) => warn!("couldn't raise the open file limit to {}: {}", soft, e),
+            },
+            LimitAction::Pool(max_open) => {
+                // only files in the output folder can be closed and opened again
+                if output_options.upload.is_some()
+                    || output_options
+                        .sink
+                        .as_ref()
+                        .is_some_and(|sink| !sink.in_output_folder())
+                {
+                    load_error(LoadError {
+                        status: RunStatus::OutputError,
+                        message: format!(
+                            "this demux needs {} open files, but the limit is {}. Raise it with `ulimit -n`",
+                            output_files + RESERVED_FILES as usize,
+                            limit.hard
+                        ),
+                    })
+                }
+                if let Err(e) = limit.raise(limit.hard) {
+                    warn!(
+                        "couldn't raise the open file limit to {}: {}",
+                        limit.hard, e
+                    );
+                }
+
+                let max_open = (max_open - pooled_writers).max(1);
+                warn!(
+                    "this demux needs {} open files, but the limit is {}, so only {} output files are kept open at once",
+                    output_files + RESERVED_FILES as usize,
+                    limit.hard,
+                    max_open
+                );
+                output_options.sink = Some(SinkHandle(Arc::new(LruFileSink::new(max_open))));
+                if output_options.compression_workers {
+                    output_options.writer_groups = Some(pooled_writers);
+                }
+            }
+        }
+    }
+
+    if options.is_present("auto-tune") {
+        let mut memory = memory_limit.or_else(available_memory).unwrap_or_else(|| {
+            clap::Error {
+                message: "invalid value for 'auto-tune': the available memory isn't known, give it with --memory-limit".to_string(),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        });
+        let mut n_writers = options
+            .value::<usize>("compress-threads")
+            .unwrap_or(n_threads);
+        if let Some(nodes) = &numa {
+            // with fewer lanes than nodes, the tiles of each lane are shared out, so
+            // every node is demultiplexing at once
+            memory /= nodes.len() as u64;
+            n_writers = nodes.iter().map(|n| n.cpus.len()).max().unwrap();
+        }
+
+        let mut tuned: Option<Tuning> = None;
+        for novaseq_run in novaseq_runs.iter() {
+            let n_files = n_output_files(novaseq_run, &sample_data, &output_options);
+            let tuning = auto_tune(
+                novaseq_run,
+                memory,
+                n_files,
+                n_writers,
+                &output_options,
+                options
+                    .is_given("write-buffer")
+                    .then_some(output_options.write_buffer),
+                options
+                    .is_given("queue-depth")
+                    .then_some(output_options.queue_depth),
+            )
+            .unwrap_or_else(|e| {
+                clap::Error {
+                    message: format!("invalid value for 'auto-tune': {}", e),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }
+                .exit()
+            });
+
+            // the runs are demultiplexed one at a time, but share the options
+            tuned = Some(match tuned {
+                Some(t) => Tuning {
+                    read_chunks: t.read_chunks.min(tuning.read_chunks),
+                    write_buffer: t.write_buffer.min(tuning.write_buffer),
+                    queue_depth: t.queue_depth.min(tuning.queue_depth),
+                },
+                None => tuning,
+            });
+        }
+
+        if let Some(tuning) = tuned {
+            if !options.is_given("read-chunks") {
+                r_chunks = tuning.read_chunks;
+            }
+            // the buffer sizes that were given are kept by auto_tune
+            output_options.write_buffer = tuning.write_buffer;
+            output_options.queue_depth = tuning.queue_depth;
+            info!(
+                "auto-tuned for {} bytes of memory: reading {} tiles at a time, with a {} byte write buffer{}",
+                memory,
+                r_chunks,
+                output_options.write_buffer,
+                if output_options.compression_workers {
+                    format!(" and {} blocks queued for each file", output_options.queue_depth)
+                } else {
+                    String::new()
+                }
+            );
+        }
+    } else if let Some(mut memory_limit) = memory_limit {
+        let mut n_writers = options
+            .value::<usize>("compress-threads")
+            .unwrap_or(n_threads);
+        if let Some(nodes) = &numa {
+            // every node is demultiplexing a lane at once, so they share the limit
+            let n_groups = nodes.len().min(sample_data.len());
+            memory_limit /= n_groups as u64;
+            n_writers = nodes.iter().map(|n| n.cpus.len()).max().unwrap();
+        }
+        // the runs are demultiplexed one at a time, so each has the whole limit
+        let mut write_buffer = usize::MAX;
+        for novaseq_run in novaseq_runs.iter() {
+            let budget = fit_memory(novaseq_run, memory_limit, n_writers, &output_options)
+                .unwrap_or_else(|e| {
+                    clap::Error {
+                        message: format!("invalid value for 'memory-limit': {}", e),
+                        kind: clap::ErrorKind::InvalidValue,
+                        info: None,
+                    }
+                    .exit()
+                });
+
+            if budget.read_chunks < r_chunks {
+                info!(
+                    "reading {} tiles at a time to stay within the memory limit",
+                    budget.read_chunks
+                );
+                r_chunks = budget.read_chunks;
+            }
+            write_buffer = write_buffer.min(budget.write_buffer);
+        }
+        if !options.is_given("write-buffer") {
+            output_options.write_buffer = write_buffer;
+        }
+    }
+
+    let run_ids: Vec<_> = novaseq_runs
+        .iter()
+        .map(|novaseq_run| novaseq_run.run_info.id.as_str())
+        .collect();
+    let run_id = run_ids.join("+");
+    set_context("run", &run_id);
+    summary.set_run_id(&run_id);
+    summary.set_sample_numbers(sample_numbers(&sample_data));
+    let mut loading = Vec::new();
+    for novaseq_run in novaseq_runs.iter() {
+        let run_loading = lane_loading(novaseq_run);
+        for lane in run_loading.iter() {
+            info!(
+                "{}lane {}: {:.1}% of clusters passed filter{}",
+                if novaseq_runs.len() > 1 {
+                    format!("{} ", novaseq_run.run_info.id)
+                } else {
+                    String::new()
+                },
+                lane.lane,
+                100.0 * lane.pf_fraction,
+                match lane.occupancy {
+                    Some(occupancy) => format!(", {:.1}% occupied", 100.0 * occupancy),
+                    None => String::new(),
+                }
+            );
+        }
+        loading.extend(run_loading);
+    }
+    summary.set_loading(loading);
+
+    for warning in warnings {
+        summary.warn(warning);
+    }
+
+    // the first run stands for all of them in the plan, provenance and reports
+    let novaseq_run = &novaseq_runs[0];
+
+    summary.end_stage("load_run");
+
+    if options.is_present("dry-run") {
+        let plan = plan_demux(novaseq_run, &sample_data, r_chunks, &output_options);
+        println!("{}", plan);
+
+        if let Err(e) = plan.write(&output_path) {
+            error!("Error writing demux_plan.json: {}", e);
+            return RunStatus::OutputError;
+        }
+
+        return RunStatus::Success;
+    }
+
+    if let Err(e) = write_zstd_dictionary(&output_path, &sample_data, &output_options) {
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            Some(&output_path),
+            RunStatus::OutputError,
+            format!("Error writing the zstd dictionary: {}", e),
+        );
+    }
+
+    let progress_mode = if options.is_present("json-progress") {
+        ProgressMode::Json
+    } else if options.is_present("quiet") {
+        ProgressMode::Hidden
+    } else {
+        ProgressMode::Bar
+    };
+
+    let total_tiles = novaseq_runs
+        .iter()
+        .flat_map(|novaseq_run| {
+            sample_data
+                .keys()
+                .map(move |&lane| novaseq_run.tile_count(lane))
+        })
+        .sum::<usize>();
+    let progress = Arc::new(Progress::new(progress_mode, total_tiles as u64));
+
+    if let Some(metrics_port) = options.value::<u16>("metrics-port") {
+        let bind = options
+            .value::<IpAddr>("metrics-bind")
+            .unwrap_or(DEFAULT_METRICS_BIND);
+        serve_metrics(Arc::clone(&progress), bind, metrics_port)
+            .unwrap_or_else(|e| panic!("Error starting metrics server: {}", e));
+    }
+
+    if numa.is_none() {
+        build_stage_pools_for(options);
+    }
+
+    // all of the options have been read by now, so record them with the output
+    let shard_suffix = shard.as_ref().map(|shard| shard.suffix());
+    let config_name = match &shard_suffix {
+        Some(suffix) => format!("config.{}.toml", suffix),
+        None => "config.toml".to_string(),
+    };
+    if let Err(e) = options.write_effective(&output_path.join(config_name)) {
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            Some(&output_path),
+            RunStatus::OutputError,
+            format!("Error writing config.toml: {}", e),
+        );
+    }
+
+    let provenance = Provenance::new(novaseq_run, &samplesheets, options.effective_config())
+        .and_then(|mut provenance| {
+            provenance.other_runs = novaseq_runs[1..].iter().map(RunInfoSummary::new).collect();
+            provenance.write(&output_path, shard_suffix.as_deref())?;
+            Ok(provenance)
+        })
+        .unwrap_or_else(|e| {
+            exit_with_error(
+                &mut summary,
+                &webhooks,
+                Some(&output_path),
+                RunStatus::OutputError,
+                format!("Error writing provenance.json: {}", e),
+            )
+        });
+    if provenance_headers {
+        output_options.header_comment = Some(provenance.header_comment());
+    }
+
+    install_handler().unwrap_or_else(|e| panic!("Error setting signal handler: {}", e));
+
+    let mut run_lane_stats = Vec::new();
+    for (run_i, novaseq_run) in novaseq_runs.iter().enumerate() {
+        // the runs after the first add their reads to the files of the first
+        output_options.append = run_i > 0;
+
+        let lane_results = match &numa {
+            Some(nodes) => demux_lanes_numa(
+                novaseq_run,
+                &sample_data,
+                &output_path,
+                r_chunks,
+                &output_options,
+                &progress,
+                nodes,
+            ),
+            None => demux_lanes(
+                novaseq_run,
+                &sample_data,
+                &output_path,
+                r_chunks,
+                &output_options,
+                &progress,
+            ),
+        }
+        .unwrap_or_else(|e| {
+            progress.finish();
+            output_options.sink().abort();
+            // over the undetermined limit, the samplesheet is most likely wrong for the run
+            let status = if is_undetermined_limit(&e) {
+                RunStatus::SamplesheetError
+            } else {
+                RunStatus::OutputError
+            };
+            exit_with_error(
+                &mut summary,
+                &webhooks,
+                Some(&output_path),
+                status,
+                format!("Stopped demultiplexing: {}", e),
+            )
+        });
+        failed_lanes.extend(lane_results.failed);
+        run_lane_stats.push(lane_results.lane_stats);
+
+        if shutdown_requested() {
+            break;
+        }
+    }
+
+    progress.finish();
+
+    if run_lane_stats.iter().all(Vec::is_empty) && !failed_lanes.is_empty() {
+        output_options.sink().abort();
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            Some(&output_path),
+            RunStatus::OutputError,
+            format!(
+                "Stopped demultiplexing: every lane failed: {}",
+                failed_lanes
+                    .iter()
+                    .map(|(lane, e)| format!("lane {}: {}", lane, e))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        );
+    }
+    for ls in run_lane_stats.iter().flatten() {
+        if ls.lane_number > 0 && !failed_lanes.contains_key(&ls.lane_number) {
+            summary.lane_succeeded(ls.lane_number);
+        }
+    }
+    for (lane, e) in failed_lanes {
+        summary.lane_failed(lane, e);
+    }
+
+    if let Err(e) = output_options.sink().finish() {
+        output_options.sink().abort();
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            Some(&output_path),
+            RunStatus::OutputError,
+            format!("Error finishing fastq files: {}", e),
+        );
+    }
+    summary.end_stage("demux");
+
+    if let Some(cache_path) = watch_index_cache {
+        if let Err(e) = std::fs::remove_dir_all(&cache_path) {
+            warn!("Error removing {}: {}", cache_path.display(), e);
+        }
+    }
+
+    // the tiles left in the run that was stopped, and in the runs after it
+    let resume = shutdown_requested().then(|| {
+        let run_i = run_lane_stats.len() - 1;
+        let not_started: usize = novaseq_runs[run_i + 1..]
+            .iter()
+            .map(|novaseq_run| novaseq_run.tile_count(0))
+            .sum();
+        (
+            ResumeManifest::new(&novaseq_runs[run_i], &run_lane_stats[run_i]),
+            not_started,
+        )
+    });
+
+    let stats_result = if run_lane_stats.len() > 1 {
+        write_runs_stats(&output_path, &novaseq_runs, run_lane_stats)
+    } else {
+        let lane_stats = run_lane_stats.pop().unwrap();
+        match &shard {
+            Some(shard) => write_shard_stats_json(&output_path, shard, novaseq_run, &lane_stats),
+            None => write_stats_json(&output_path, novaseq_run, &lane_stats),
+        }
+        .map(|_| lane_stats)
+    };
+    let lane_stats = stats_result.unwrap_or_else(|e| {
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            Some(&output_path),
+            RunStatus::OutputError,
+            format!("Error writing Stats.json: {}", e),
+        )
+    });
+
+    if let Some((manifest, not_started)) = resume {
+        if let Err(e) = manifest.write(&output_path) {
+            error!("Error writing resume.json: {}", e);
+        }
+
+        summary.error(format!(
+            "stopped by a signal with {} tiles left to demultiplex",
+            manifest
+                .remaining
+                .iter()
+                .map(|lt| lt.tiles.len())
+                .sum::<usize>()
+                + not_started
+        ));
+        summary
+            .finish(RunStatus::Interrupted, &output_path)
+            .unwrap_or_else(|e| panic!("Error writing run_summary.json: {}", e));
+        webhooks.notify(&summary, RunStatus::Interrupted);
+
+        return RunStatus::Interrupted;
+    }
+
+    // each shard only has part of the run, so the reports are written by merge-stats
+    if shard.is_some() {
+        info!("skipping reports for a single shard");
+    } else if let Err(e) = write_reports(&output_path, novaseq_run, &lane_stats) {
+        exit_with_error(
+            &mut summary,
+            &webhooks,
+            Some(&output_path),
+            RunStatus::OutputError,
+            format!("Error writing reports: {}", e),
+        );
+    }
+
+    if options.is_present("delivery-manifests") {
+        if shard.is_some() || !output_options.in_output_folder() {
+            warn!(
+                "skipping delivery manifests: the fastq files aren't all in the output directory"
+            );
+        } else if let Err(e) = delivery_manifests(
+            &output_path,
+            novaseq_run,
+            &sample_data,
+            &lane_stats,
+            &output_options,
+        )
+        .and_then(|manifests| write_delivery_manifests(&output_path, &manifests))
+        {
+            exit_with_error(
+                &mut summary,
+                &webhooks,
+                Some(&output_path),
+                RunStatus::OutputError,
+                format!("Error writing delivery manifests: {}", e),
+            );
+        }
+    }
+
+    summary.end_stage("write_reports");
+
+    let mut status = RunStatus::Success;
+    for ls in lane_stats.iter().filter(|ls| ls.failed_tile_cycles > 0) {
+        summary.warn(format!(
+            "lane {}: {} tile cycles could not be read and were written as N",
+            ls.lane_number, ls.failed_tile_cycles
+        ));
+        status = RunStatus::PartialSuccess;
+    }
+    if summary.has_failed_lanes() {
+        status = RunStatus::PartialSuccess;
+    }
+
+    summary
+        .finish(status, &output_path)
+        .unwrap_or_else(|e| panic!("Error writing run_summary.json: {}", e));
+
+    if let Some(upload) = &output_options.upload {
+        if let Err(e) = upload.put_folder(&output_path) {
+            error!("Error uploading {}: {}", output_path.display(), e);
+            status = RunStatus::OutputError;
+        }
+    }
+
+    webhooks.notify(&summary, status);
+
+    status
+}
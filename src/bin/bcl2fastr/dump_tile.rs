@@ -0,0 +1,80 @@
+//! `bcl2fastr dump-tile`: write all the pass-filter reads in a single tile as
+//! fastq, without demultiplexing. Useful for looking at the raw data when
+//! something has gone wrong
+
+use clap::{App, Arg, SubCommand};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use common::run_summary::RunStatus;
+use common::write_fastq::dump_tile;
+
+use log::{error, info};
+
+use crate::load::{check_run_path, load_run, run_load_args, run_path_arg};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("dump-tile")
+        .about("write every read from a single tile as fastq")
+        .arg(run_path_arg())
+        .args(&run_load_args())
+        .arg(
+            Arg::with_name("lane")
+                .long("lane")
+                .help("lane of the tile")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("tile")
+                .long("tile")
+                .help("tile number, e.g. 1101")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("output fastq file (default: stdout)")
+                .takes_value(true),
+        )
+}
+
+/// Load the run and write out the tile
+pub fn run(options: &Options) -> RunStatus {
+    let lane = options.value::<usize>("lane").unwrap();
+    let tile = options.value::<u32>("tile").unwrap();
+
+    let novaseq_run =
+        match check_run_path(options).and_then(|run_path| load_run(run_path, false, options)) {
+            Ok(novaseq_run) => novaseq_run,
+            Err(e) => return e.fail(),
+        };
+
+    let mut writer: Box<dyn Write> = match options.value_of("output") {
+        Some(output) => match File::create(&output) {
+            Ok(out_file) => Box::new(BufWriter::new(out_file)),
+            Err(e) => {
+                error!("Error creating {}: {}", output, e);
+                return RunStatus::OutputError;
+            }
+        },
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    match dump_tile(&novaseq_run, lane, tile, &mut writer) {
+        Ok(n_pf) => {
+            info!("wrote {} clusters from lane {} tile {}", n_pf, lane, tile);
+            RunStatus::Success
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            error!("{}", e);
+            RunStatus::BasecallError
+        }
+        Err(e) => {
+            error!("Error writing tile: {}", e);
+            RunStatus::OutputError
+        }
+    }
+}
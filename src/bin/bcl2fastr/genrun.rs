@@ -0,0 +1,159 @@
+//! `bcl2fastr genrun`: generate a small synthetic run folder from a samplesheet, to
+//! reproduce a bug or build a test case without real sequencing data
+
+use clap::{App, Arg, SubCommand};
+use std::path::PathBuf;
+
+use common::genrun::{generate_run, parse_bins, GenRunOptions};
+use common::run_summary::RunStatus;
+use common::sample_data::{ConflictPolicy, I5Orientation};
+
+use log::{error, info};
+
+use crate::load::{check_samplesheet, load_samplesheet, samplesheet_arg};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("genrun")
+        .about("generate a synthetic NovaSeq run folder with random reads for the samples in a samplesheet")
+        .arg(samplesheet_arg())
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("path for the new run folder")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .help("seed for the random reads: the same seed and samplesheet give the same run")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lanes")
+                .long("lanes")
+                .help("number of lanes, if the samplesheet has no Lane column")
+                .default_value("1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tiles")
+                .long("tiles")
+                .help("number of tiles in each lane")
+                .default_value("3")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("clusters")
+                .long("clusters")
+                .help("number of clusters in each tile")
+                .default_value("100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-length")
+                .long("read-length")
+                .help("number of cycles in each of the two reads")
+                .default_value("8")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pf-fraction")
+                .long("pf-fraction")
+                .help("fraction of clusters that pass filter")
+                .default_value("0.9")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("undetermined-fraction")
+                .long("undetermined-fraction")
+                .help("fraction of clusters whose indices match no sample")
+                .default_value("0.1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("poly-g-fraction")
+                .long("poly-g-fraction")
+                .help("fraction of reads that end in a run of G from halfway through, as on two-color instruments")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bins")
+                .long("bins")
+                .help("lower bounds of the quality score bins in the CBCL headers")
+                .default_value("0,11,25,37")
+                .takes_value(true),
+        )
+}
+
+/// Generate the run and print the reads we expect for each sample
+pub fn run(options: &Options) -> RunStatus {
+    let bins = parse_bins(&options.value_of("bins").unwrap()).unwrap_or_else(|e| {
+        clap::Error {
+            message: format!("invalid value for 'bins': {}", e),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit()
+    });
+
+    let genrun_options = GenRunOptions {
+        seed: options.value("seed").unwrap(),
+        lanes: options.value("lanes").unwrap(),
+        tiles: options.value("tiles").unwrap(),
+        clusters: options.value("clusters").unwrap(),
+        read_length: options.value("read-length").unwrap(),
+        pf_fraction: options.value("pf-fraction").unwrap(),
+        undetermined_fraction: options.value("undetermined-fraction").unwrap(),
+        poly_g_fraction: options.value("poly-g-fraction").unwrap(),
+        bins,
+    };
+    let run_path = PathBuf::from(options.value_of("output").unwrap());
+
+    let samplesheets = match check_samplesheet(options) {
+        Ok(samplesheets) => samplesheets,
+        Err(e) => return e.fail(),
+    };
+    // the run gets a copy of the samplesheet, so there can only be one
+    let samplesheet = match samplesheets.as_slice() {
+        [samplesheet] => samplesheet,
+        _ => clap::Error {
+            message: "invalid value for 'samplesheet': genrun takes one samplesheet".to_string(),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit(),
+    };
+    // undetermined reads are at least two mismatches from every sample, so they
+    // stay undetermined with the default --mismatch
+    let sample_data = match load_samplesheet(
+        &samplesheets,
+        1,
+        I5Orientation::Forward,
+        ConflictPolicy::Reduce,
+    ) {
+        Ok((sample_data, _)) => sample_data,
+        Err(e) => return e.fail(),
+    };
+
+    match generate_run(samplesheet, &sample_data, &run_path, &genrun_options) {
+        Ok(lanes) => {
+            info!("wrote run to {}", run_path.display());
+            for lane in lanes {
+                println!("{}", lane);
+            }
+            RunStatus::Success
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            error!("Can't generate a run for this samplesheet: {}", e);
+            RunStatus::SamplesheetError
+        }
+        Err(e) => {
+            error!("Error generating run: {}", e);
+            RunStatus::OutputError
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! `bcl2fastr inspect-cbcl`: print the header of a CBCL file and check that its
+//! tile blocks fit the file. Useful when a run fails to load and the question is
+//! whether a CBCL is truncated or corrupt
+
+use clap::{App, Arg, SubCommand};
+use std::path::Path;
+
+use common::cbcl_header_decoder::CBCLHeader;
+use common::run_summary::RunStatus;
+
+use log::error;
+
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("inspect-cbcl")
+        .about("print the header of a CBCL file and check its tile offsets")
+        .arg(
+            Arg::with_name("cbcl")
+                .help("path to the CBCL file")
+                .required(true),
+        )
+}
+
+/// Print the header, then any problems with its offsets
+pub fn run(options: &Options) -> RunStatus {
+    let cbcl_path = options.value_of("cbcl").unwrap();
+    let cbcl_path = Path::new(&cbcl_path);
+
+    let file_len = match std::fs::metadata(cbcl_path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            error!("Error reading {}: {}", cbcl_path.display(), e);
+            return RunStatus::BasecallError;
+        }
+    };
+    let header = match CBCLHeader::from_path(cbcl_path) {
+        Ok(header) => header,
+        Err(e) => {
+            error!(
+                "Error decoding the header of {}: {}",
+                cbcl_path.display(),
+                e
+            );
+            return RunStatus::BasecallError;
+        }
+    };
+
+    println!("{}", cbcl_path.display());
+    println!("version: {}", header.version);
+    println!("header size: {} bytes", header.header_size);
+    println!("file size: {} bytes", file_len);
+    println!(
+        "bits per basecall / qscore: {} / {}",
+        header.bits_per_basecall, header.bits_per_qscore
+    );
+    let qscores: Vec<_> = header
+        .bin_qscores()
+        .iter()
+        .map(|q| format!("Q{}", q))
+        .collect();
+    println!("bins: {}", qscores.join(" "));
+    println!(
+        "non-PF clusters excluded: {}",
+        if header.non_pf_clusters_excluded {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+    println!();
+    println!("tile\tclusters\toffset\tcompressed\tuncompressed");
+    for i in 0..header.tiles.len() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            header.tiles[i],
+            header.n_clusters[i],
+            header.start_pos[i],
+            header.compressed_size[i],
+            header.uncompressed_size[i]
+        );
+    }
+
+    let problems = header.offset_problems(file_len);
+    if problems.is_empty() {
+        return RunStatus::Success;
+    }
+    for problem in problems {
+        error!("{}: {}", cbcl_path.display(), problem);
+    }
+    RunStatus::BasecallError
+}
@@ -0,0 +1,635 @@
+//! Arguments and loading code for the run folder and samplesheet, shared by all
+//! of the subcommands
+
+use clap::Arg;
+use std::collections::{BTreeMap, BTreeSet};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use common::barcode_matcher::{
+    ExactMatcher, LevenshteinMatcher, MatcherHandle, QualityMatcher, DEFAULT_MAX_MISMATCH_QUALITY,
+};
+use common::demux::panic_message;
+use common::novaseq_run::{parse_excluded_tiles, NovaSeqRun};
+use common::run_folder::{check_run_folder, FolderCheck};
+use common::run_parameters_parser::{read_run_parameters, RunParameters};
+use common::run_summary::RunStatus;
+use common::sample_data::{
+    read_samplesheets_on_conflict, ConflictPolicy, DualIndexMode, I5Orientation, SampleData,
+};
+use common::sheet_reads::read_sheet_reads;
+use common::storage::is_remote;
+use common::thread_pools::build_stage_pools;
+use common::write_fastq::{
+    OutputOptions, DEFAULT_POLY_G_LENGTH, DEFAULT_QUEUE_DEPTH, DEFAULT_WRITE_BUFFER,
+};
+use common::zstd_dictionary::ZstdDictionary;
+
+use log::{error, info};
+
+use crate::options::Options;
+
+/// An error loading the inputs, along with the status that we should exit with
+pub struct LoadError {
+    pub status: RunStatus,
+    pub message: String,
+}
+
+impl LoadError {
+    fn new(status: RunStatus, message: String) -> LoadError {
+        LoadError { status, message }
+    }
+
+    /// Log the error and return the status to exit with
+    pub fn fail(self) -> RunStatus {
+        error!("{}", self.message);
+        self.status
+    }
+}
+
+pub fn run_path_arg() -> Arg<'static, 'static> {
+    Arg::with_name("run-path")
+        .long("run-path")
+        .help("specify path to the sequencing run folder, or an s3:// or gs:// URL if built with the object-store feature")
+        .takes_value(true)
+        .required(true)
+}
+
+pub fn run_load_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("metadata-cache")
+            .long("metadata-cache")
+            .help("keep the CBCL headers and filters in this directory (e.g. one in the run folder), so that later commands on the run don't read them again")
+            .takes_value(true),
+        Arg::with_name("exclude-tiles")
+            .long("exclude-tiles")
+            .help("file listing tiles to skip, e.g. 1_2104 or s_1_2104, separated by commas or new lines. Tiles that RunInfo.xml doesn't list are always skipped")
+            .takes_value(true),
+    ]
+}
+
+/// Set up a run with the options from `run_load_args`, before its lanes are loaded
+pub fn configure_run(options: &Options, novaseq_run: &mut NovaSeqRun) {
+    if let Some(metadata_cache) = options.value_of("metadata-cache") {
+        novaseq_run.set_metadata_cache(PathBuf::from(metadata_cache));
+    }
+
+    if let Some(exclude_tiles) = options.value_of("exclude-tiles") {
+        let tiles = std::fs::read_to_string(&exclude_tiles)
+            .map_err(|e| e.to_string())
+            .and_then(|s| parse_excluded_tiles(&s))
+            .unwrap_or_else(|e| {
+                clap::Error {
+                    message: format!("invalid value for 'exclude-tiles': {}", e),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }
+                .exit()
+            });
+        novaseq_run.exclude_tiles(tiles);
+    }
+}
+
+pub fn samplesheet_arg() -> Arg<'static, 'static> {
+    Arg::with_name("samplesheet")
+        .long("samplesheet")
+        .help("path to samplesheet.csv. Give it more than once to merge the samplesheets of groups sharing the flowcell")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required(true)
+}
+
+pub fn mismatch_arg() -> Arg<'static, 'static> {
+    Arg::with_name("mismatch")
+        .long("mismatch")
+        .help("maximum hamming distance to allow for indexes")
+        .default_value("1")
+        .takes_value(true)
+}
+
+pub fn on_conflict_arg() -> Arg<'static, 'static> {
+    Arg::with_name("on-conflict")
+        .long("on-conflict")
+        .help("what to do when two samples in a lane are within --mismatch of each other: fail, use fewer mismatches in the lane, or drop one of the samples")
+        .possible_values(&["error", "reduce", "drop-sample"])
+        .default_value("reduce")
+        .takes_value(true)
+}
+
+pub fn i5_orientation_arg() -> Arg<'static, 'static> {
+    Arg::with_name("i5-orientation")
+        .long("i5-orientation")
+        .help("orientation of the Index2 sequences in the samplesheet relative to how the instrument reads them [default: from RunParameters.xml, or forward]")
+        .possible_values(&["forward", "reverse-complement"])
+        .takes_value(true)
+}
+
+pub fn matcher_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("matcher")
+            .long("matcher")
+            .help("how to match the indices to samples: within --mismatch substitutions (hamming), only exact matches, within --mismatch substitutions, insertions and deletions (levenshtein), or with mismatches weighted by their quality scores (quality)")
+            .possible_values(&["hamming", "exact", "levenshtein", "quality"])
+            .default_value("hamming")
+            .takes_value(true),
+        Arg::with_name("max-mismatch-quality")
+            .long("max-mismatch-quality")
+            .help("with --matcher quality, the most that the quality scores of the mismatched index bases can add up to [default: 20]")
+            .takes_value(true),
+    ]
+}
+
+/// Options for how two indices have to match a sample
+pub fn dual_index_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("strict-udi")
+            .long("strict-udi")
+            .help("require unique dual indices: every sample has its own Index and Index2, and a read's mismatches over both are limited by --max-total-mismatches. By default any pairing of the indices in the samplesheet is a sample"),
+        Arg::with_name("max-total-mismatches")
+            .long("max-total-mismatches")
+            .help("with --strict-udi, the most mismatches to allow across both indices [default: 1]")
+            .requires("strict-udi")
+            .takes_value(true),
+    ]
+}
+
+/// Options for the format of the output files
+pub fn output_args() -> [Arg<'static, 'static>; 8] {
+    [
+        Arg::with_name("compression")
+            .long("compression")
+            .help("compression level for gzipped output, or for zstd with --zstd")
+            .default_value("1")
+            .takes_value(true),
+        Arg::with_name("no-compression")
+            .long("no-compression")
+            .help("write uncompressed output files"),
+        Arg::with_name("bgzf")
+            .long("bgzf")
+            .help("compress the output files as BGZF blocks, which tools like samtools and tabix can index, instead of gzip")
+            .conflicts_with("no-compression"),
+        Arg::with_name("zstd")
+            .long("zstd")
+            .help("compress the output files with zstd instead of gzip, as .fastq.zst, if bcl2fastr was built with the zstd feature")
+            .conflicts_with_all(&["no-compression", "bgzf"]),
+        Arg::with_name("zstd-dictionary")
+            .long("zstd-dictionary")
+            .help("prime every zstd frame with this dictionary, e.g. from train-zstd-dictionary, to compress small chunks better. A copy is kept in the output folder, as it is needed to decompress the files")
+            .requires("zstd")
+            .takes_value(true),
+        Arg::with_name("seq-only")
+            .long("seq-only")
+            .help("only write read sequences, one per line, without headers or qscores"),
+        Arg::with_name("trim-poly-g")
+            .long("trim-poly-g")
+            .help("trim a run of at least this many Gs from the end of each read, e.g. 10 for two-color chemistry, where G means no signal. Reads keep at least one base [default: 0, no trimming]")
+            .takes_value(true),
+        Arg::with_name("ascii-offset")
+            .long("ascii-offset")
+            .help("ASCII code for a quality score of 0: 33 for phred+33, or 64 for older tools that expect phred+64")
+            .default_value("33")
+            .takes_value(true),
+    ]
+}
+
+/// Options for the number of threads in each stage of the demux
+pub fn stage_thread_args() -> [Arg<'static, 'static>; 3] {
+    [
+        Arg::with_name("io-threads")
+            .long("io-threads")
+            .help("threads for reading CBCL files [default: --threads]")
+            .takes_value(true),
+        Arg::with_name("demux-threads")
+            .long("demux-threads")
+            .help("threads for matching indices to samples [default: --threads]")
+            .takes_value(true),
+        Arg::with_name("compress-threads")
+            .long("compress-threads")
+            .help("threads for compressing and writing output files [default: --threads]")
+            .takes_value(true),
+    ]
+}
+
+/// The output format from the options in `output_args`, with defaults that depend
+/// on the chemistry in `run_parameters`
+pub fn output_options(options: &Options, run_parameters: &Option<RunParameters>) -> OutputOptions {
+    let compression = options.value::<u32>("compression").unwrap();
+    if options.is_present("zstd") && !cfg!(feature = "zstd") {
+        clap::Error {
+            message: "bcl2fastr was built without the zstd feature, so it can't write zstd"
+                .to_string(),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        }
+        .exit()
+    }
+    let trim_poly_g = options.value::<usize>("trim-poly-g").unwrap_or(0);
+    if trim_poly_g == 0 && run_parameters.as_ref().is_some_and(|rp| rp.is_two_color()) {
+        info!(
+            "two-color chemistry, where G means no signal: --trim-poly-g {} trims runs of Gs from the reads",
+            DEFAULT_POLY_G_LENGTH
+        );
+    }
+
+    OutputOptions {
+        compression: if options.is_present("no-compression") {
+            None
+        } else {
+            Some(compression)
+        },
+        seq_only: options.is_present("seq-only"),
+        shard: None,
+        write_buffer: DEFAULT_WRITE_BUFFER,
+        trim_poly_g,
+        header_comment: None,
+        upload: None,
+        sink: None,
+        bgzf: options.is_present("bgzf"),
+        zstd: options.is_present("zstd"),
+        zstd_dictionary: options.value_of("zstd-dictionary").map(|path| {
+            let dictionary =
+                ZstdDictionary::read(Path::new(&path), compression).unwrap_or_else(|e| {
+                    clap::Error {
+                        message: format!("invalid value for 'zstd-dictionary': {}", e),
+                        kind: clap::ErrorKind::InvalidValue,
+                        info: None,
+                    }
+                    .exit()
+                });
+            Arc::new(dictionary)
+        }),
+        two_phase: false,
+        two_phase_undetermined: false,
+        compression_workers: false,
+        queue_depth: DEFAULT_QUEUE_DEPTH,
+        writer_groups: None,
+        gpu: false,
+        undetermined_limit: None,
+        index_cache: None,
+        skip_reads: Default::default(),
+        matcher: matcher(options),
+        ascii_offset: options.value("ascii-offset").unwrap(),
+        read_filter: None,
+        non_pf: None,
+        append: false,
+        phix: None,
+        screen: None,
+        adapter_dimers: None,
+        overlap: None,
+        umi: None,
+        single_file: false,
+        sample_numbers: false,
+        truncate_indices: true,
+    }
+}
+
+/// The barcode matcher from `--matcher`, or `None` for the default hamming sets
+pub fn matcher(options: &Options) -> Option<MatcherHandle> {
+    match options.value_of("matcher").as_deref() {
+        Some("exact") => Some(MatcherHandle(Arc::new(ExactMatcher))),
+        Some("levenshtein") => Some(MatcherHandle(Arc::new(LevenshteinMatcher {
+            max_distance: options.value("mismatch").unwrap(),
+        }))),
+        Some("quality") => Some(MatcherHandle(Arc::new(QualityMatcher {
+            max_score: options
+                .value("max-mismatch-quality")
+                .unwrap_or(DEFAULT_MAX_MISMATCH_QUALITY),
+            max_mismatches: options.value("mismatch").unwrap(),
+        }))),
+        _ => None,
+    }
+}
+
+/// The orientation of i5 from `--i5-orientation`, or from the chemistry in
+/// `run_parameters` if it isn't set
+pub fn i5_orientation(options: &Options, run_parameters: &Option<RunParameters>) -> I5Orientation {
+    options
+        .value::<I5Orientation>("i5-orientation")
+        .unwrap_or_else(|| {
+            run_parameters
+                .as_ref()
+                .map_or(I5Orientation::Forward, |rp| rp.i5_orientation())
+        })
+}
+
+/// Use strict UDI matching for every lane if `--strict-udi` is set, failing if the
+/// samplesheet doesn't have unique dual indices
+pub fn set_dual_index_mode(
+    options: &Options,
+    sample_data: &mut SampleData,
+) -> Result<DualIndexMode, LoadError> {
+    if !options.is_present("strict-udi") {
+        return Ok(DualIndexMode::Combinatorial);
+    }
+
+    let dual_index_mode = DualIndexMode::StrictUdi {
+        max_mismatches: options.value("max-total-mismatches").unwrap_or(1),
+    };
+    for (lane, samples) in sample_data.iter_mut() {
+        samples.set_dual_index_mode(dual_index_mode).map_err(|e| {
+            // lane 0 means the samplesheet has no lanes
+            let message = match lane {
+                0 => format!("Can't use --strict-udi: {}", e),
+                lane => format!("Can't use --strict-udi for lane {}: {}", lane, e),
+            };
+            LoadError::new(RunStatus::SamplesheetError, message)
+        })?;
+    }
+
+    Ok(dual_index_mode)
+}
+
+/// Give each stage that has its own number of threads its own thread pool. The
+/// others share the global pool of `--threads`
+pub fn build_stage_pools_for(options: &Options) {
+    let [io, demux, compress] = ["io-threads", "demux-threads", "compress-threads"]
+        .map(|name| options.value::<usize>(name));
+
+    if io.is_some() || demux.is_some() || compress.is_some() {
+        build_stage_pools(io, demux, compress)
+            .unwrap_or_else(|e| panic!("Error configuring stage threadpools: {}", e));
+    }
+}
+
+/// Check that the run path exists. URLs for object storage are checked when the
+/// run is loaded
+pub fn check_run_path(options: &Options) -> Result<PathBuf, LoadError> {
+    let run_path = PathBuf::from(options.value_of("run-path").unwrap());
+    if run_path.exists() || is_remote(&run_path) {
+        Ok(run_path)
+    } else {
+        Err(LoadError::new(
+            RunStatus::BasecallError,
+            format!("Could not find run path {}", run_path.display()),
+        ))
+    }
+}
+
+/// The run folders given with `--runfolder`, or else the one `--run-path`
+pub fn check_run_paths(options: &Options) -> Result<Vec<PathBuf>, LoadError> {
+    let run_paths: Vec<_> = options
+        .values_of("runfolder")
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    if run_paths.is_empty() {
+        return check_run_path(options).map(|run_path| vec![run_path]);
+    }
+
+    match run_paths
+        .iter()
+        .find(|run_path| !run_path.exists() && !is_remote(run_path))
+    {
+        Some(run_path) => Err(LoadError::new(
+            RunStatus::BasecallError,
+            format!("Could not find run path {}", run_path.display()),
+        )),
+        None => Ok(run_paths),
+    }
+}
+
+/// The most missing files that we list in an error message
+const MAX_MISSING_LISTED: usize = 10;
+
+/// Compare the run folder with RunInfo.xml, listing every missing and unexpected file
+pub fn load_folder_check(
+    run_path: &Path,
+    lanes: Option<&BTreeSet<usize>>,
+) -> Result<FolderCheck, LoadError> {
+    check_run_folder(run_path, lanes).map_err(|e| {
+        LoadError::new(
+            RunStatus::BasecallError,
+            format!("Error checking run folder: {}", e),
+        )
+    })
+}
+
+/// The error for a run folder that is missing files, listing the first few
+pub fn missing_files_error(folder_check: &FolderCheck) -> LoadError {
+    let mut listed: Vec<_> = folder_check
+        .missing
+        .iter()
+        .take(MAX_MISSING_LISTED)
+        .map(|p| p.display().to_string())
+        .collect();
+    if folder_check.missing.len() > MAX_MISSING_LISTED {
+        listed.push(format!(
+            "and {} more",
+            folder_check.missing.len() - MAX_MISSING_LISTED
+        ));
+    }
+
+    LoadError::new(
+        RunStatus::BasecallError,
+        format!(
+            "Run folder is missing {} files: {}",
+            folder_check.missing.len(),
+            listed.join(", ")
+        ),
+    )
+}
+
+/// Load the run, set up with the options from `run_load_args`. If `index_only` is
+/// true, only the index cycles are loaded
+pub fn load_run(
+    run_path: PathBuf,
+    index_only: bool,
+    options: &Options,
+) -> Result<NovaSeqRun, LoadError> {
+    let mut novaseq_run = open_run(run_path, index_only)?;
+    configure_run(options, &mut novaseq_run);
+    load_lanes(&mut novaseq_run, None)?;
+
+    Ok(novaseq_run)
+}
+
+/// Read the metadata of a run without loading any lanes, for commands that only
+/// need RunInfo.xml or a few of the lanes
+pub fn open_run(run_path: PathBuf, index_only: bool) -> Result<NovaSeqRun, LoadError> {
+    NovaSeqRun::open_path(run_path, index_only).map_err(|e| {
+        LoadError::new(
+            RunStatus::BasecallError,
+            format!("Error reading NovaSeq run: {}", e),
+        )
+    })
+}
+
+/// Load the headers and filters for `lanes` of a run opened with `open_run`, or for
+/// every lane
+pub fn load_lanes(
+    novaseq_run: &mut NovaSeqRun,
+    lanes: Option<&BTreeSet<usize>>,
+) -> Result<(), LoadError> {
+    // missing or corrupt CBCL headers cause a panic while loading
+    let load = || match lanes {
+        Some(lanes) => novaseq_run.load_lanes(lanes),
+        None => novaseq_run.load_all(),
+    };
+    match panic::catch_unwind(panic::AssertUnwindSafe(load)) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(LoadError::new(
+            RunStatus::BasecallError,
+            format!("Error reading NovaSeq run: {}", e),
+        )),
+        Err(panic) => Err(LoadError::new(
+            RunStatus::BasecallError,
+            format!("Error reading NovaSeq run: {}", panic_message(&*panic)),
+        )),
+    }
+}
+
+/// Load `lanes` like `load_lanes`, but if they can't all be read, load each on its
+/// own so that a lane with a corrupt or missing file doesn't stop the others. The
+/// lanes that fail are taken out of the run and returned with their errors. It's
+/// still an error if none of them can be loaded
+pub fn load_lanes_isolated(
+    novaseq_run: &mut NovaSeqRun,
+    lanes: &BTreeSet<usize>,
+) -> Result<BTreeMap<usize, String>, LoadError> {
+    if load_lanes(novaseq_run, Some(lanes)).is_ok() {
+        return Ok(BTreeMap::new());
+    }
+
+    // start again from nothing, as some surfaces may have loaded
+    novaseq_run.retain_lanes(&BTreeSet::new());
+    let mut failed = BTreeMap::new();
+    let mut loaded = BTreeSet::new();
+    let mut last_error = None;
+    for &lane in lanes {
+        match load_lanes(novaseq_run, Some(&std::iter::once(lane).collect())) {
+            Ok(()) => {
+                loaded.insert(lane);
+            }
+            Err(e) => {
+                failed.insert(lane, e.message.clone());
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match (loaded.is_empty(), last_error) {
+        (true, Some(e)) => Err(e),
+        _ => {
+            novaseq_run.retain_lanes(&loaded);
+            Ok(failed)
+        }
+    }
+}
+
+/// Load a run that is still sequencing, using only the cycles that are complete,
+/// with the number of cycles in RunInfo.xml
+pub fn load_incomplete_run(run_path: PathBuf) -> Result<(NovaSeqRun, usize), LoadError> {
+    let load = || -> std::io::Result<_> {
+        let novaseq_run = NovaSeqRun::read_path_incomplete(run_path)?;
+        let run_cycles = novaseq_run.run_info_cycles()?;
+        Ok((novaseq_run, run_cycles))
+    };
+
+    match panic::catch_unwind(load) {
+        Ok(Ok(loaded)) => Ok(loaded),
+        Ok(Err(e)) => Err(LoadError::new(
+            RunStatus::BasecallError,
+            format!("Error reading NovaSeq run: {}", e),
+        )),
+        Err(_) => Err(LoadError::new(
+            RunStatus::BasecallError,
+            "Error reading NovaSeq run".to_string(),
+        )),
+    }
+}
+
+/// Read RunParameters.xml from the run folder, if there is one
+pub fn load_run_parameters(run_path: &Path) -> Result<Option<RunParameters>, LoadError> {
+    match panic::catch_unwind(|| read_run_parameters(run_path)) {
+        Ok(Ok(run_parameters)) => Ok(run_parameters),
+        Ok(Err(e)) => Err(LoadError::new(
+            RunStatus::BasecallError,
+            format!("Error reading RunParameters.xml: {}", e),
+        )),
+        Err(_) => Err(LoadError::new(
+            RunStatus::BasecallError,
+            "Error reading RunParameters.xml".to_string(),
+        )),
+    }
+}
+
+/// Check that the samplesheets exist
+pub fn check_samplesheet(options: &Options) -> Result<Vec<PathBuf>, LoadError> {
+    let samplesheets: Vec<_> = options
+        .values_of("samplesheet")
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    match samplesheets
+        .iter()
+        .find(|samplesheet| !samplesheet.exists())
+    {
+        Some(samplesheet) => Err(LoadError::new(
+            RunStatus::SamplesheetError,
+            format!("Could not find samplesheet {}", samplesheet.display()),
+        )),
+        None => Ok(samplesheets),
+    }
+}
+
+/// Compare each samplesheet's [Reads] section and OverrideCycles with the run, and
+/// return a problem for each read that doesn't match
+pub fn check_sheet_reads(
+    samplesheets: &[PathBuf],
+    novaseq_run: &NovaSeqRun,
+) -> Result<Vec<String>, LoadError> {
+    let mut problems = Vec::new();
+    for samplesheet in samplesheets {
+        let sheet_reads = read_sheet_reads(samplesheet).map_err(|e| {
+            LoadError::new(
+                RunStatus::SamplesheetError,
+                format!("Error reading samplesheet: {}", e),
+            )
+        })?;
+
+        let sheet_problems = sheet_reads.problems(&novaseq_run.read_structure);
+        if samplesheets.len() > 1 {
+            problems.extend(
+                sheet_problems
+                    .into_iter()
+                    .map(|p| format!("{}: {}", samplesheet.display(), p)),
+            );
+        } else {
+            problems.extend(sheet_problems);
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Read the samplesheets, allowing up to `mismatch` errors in the indices. Also
+/// returns a message for everything that was changed because of conflicting samples
+pub fn load_samplesheet(
+    samplesheets: &[PathBuf],
+    mismatch: usize,
+    i5_orientation: I5Orientation,
+    on_conflict: ConflictPolicy,
+) -> Result<(SampleData, Vec<String>), LoadError> {
+    // the samplesheet parser panics on some invalid sheets, so catch those too
+    match panic::catch_unwind(|| {
+        read_samplesheets_on_conflict(samplesheets, mismatch, i5_orientation, on_conflict)
+    }) {
+        Ok(Ok((sample_data, reports))) => {
+            let messages = reports
+                .iter()
+                .flat_map(|(&lane, report)| report.messages(lane))
+                .collect();
+            Ok((sample_data, messages))
+        }
+        Ok(Err(e)) => Err(LoadError::new(
+            RunStatus::SamplesheetError,
+            format!("Error reading samplesheet: {}", e),
+        )),
+        Err(_) => Err(LoadError::new(
+            RunStatus::SamplesheetError,
+            "Error reading samplesheet".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,181 @@
+//! bcl2fastr is a program for efficient multi-threaded demultiplexing of large
+//! sequencing runs (specifically from the NovaSeq instrument).
+//!
+//! The work is split into subcommands, which all share the same logging and
+//! thread pool setup and load the run folder in the same way.
+
+use clap::{App, AppSettings, Arg};
+use std::process;
+use std::str::FromStr;
+
+use common::logging::{parse_module_level, LogFormat, Logger, Timestamp};
+
+use rayon::ThreadPoolBuilder;
+
+use crate::options::{arg_names, Options};
+
+mod barcode_count;
+mod bench;
+mod compare;
+mod demux;
+mod dump_tile;
+mod genrun;
+mod inspect_cbcl;
+mod load;
+mod merge;
+mod merge_stats;
+mod options;
+mod stats;
+mod train_zstd_dictionary;
+mod validate;
+mod verify_output;
+
+/// Set up the global logger from the (global) logging arguments
+fn init_logging(options: &Options) {
+    let verbose = options.occurrences_of("verbosity") as usize;
+    let quiet = options.is_present("quiet");
+    let ts = options
+        .value_of("timestamp")
+        .map(|v| {
+            Timestamp::from_str(&v).unwrap_or_else(|_| {
+                clap::Error {
+                    message: "invalid value for 'timestamp'".into(),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }
+                .exit()
+            })
+        })
+        .unwrap_or(Timestamp::Off);
+    let log_format = options.value::<LogFormat>("log-format").unwrap();
+
+    let mut logger = Logger::new()
+        .module(module_path!())
+        .module("common")
+        .verbosity(verbose)
+        .timestamp(ts)
+        .format(log_format);
+
+    for module_level in options.values_of("log-level") {
+        let (module, level) = parse_module_level(&module_level).unwrap_or_else(|e| {
+            clap::Error {
+                message: format!("invalid value for 'log-level': {}", e),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        });
+        logger = logger.module_level(&module, level);
+    }
+
+    logger.quiet(quiet).init().unwrap();
+}
+
+/// Parses command line arguments and runs the chosen subcommand
+fn main() {
+    let app = App::new("bcl2fastr")
+        .version(clap::crate_version!())
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("TOML file of option values, which are overridden by the command line")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .help("number of threads used for demultiplexing")
+                .default_value("4")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbosity")
+                .short("v")
+                .multiple(true)
+                .help("Increase message verbosity")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .help("Silence all output")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("timestamp")
+                .short("t")
+                .help("prepend log lines with a timestamp")
+                .takes_value(true)
+                .possible_values(&["none", "sec", "ms", "ns"])
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .help("write log lines as plain text or as JSON")
+                .takes_value(true)
+                .default_value("text")
+                .possible_values(&["text", "json"])
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .help("set the log level for a module, e.g. common::write_fastq=debug")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .subcommand(demux::subcommand())
+        .subcommand(validate::subcommand())
+        .subcommand(stats::subcommand())
+        .subcommand(dump_tile::subcommand())
+        .subcommand(barcode_count::subcommand())
+        .subcommand(merge_stats::subcommand())
+        .subcommand(merge::subcommand())
+        .subcommand(bench::subcommand())
+        .subcommand(compare::subcommand())
+        .subcommand(genrun::subcommand())
+        .subcommand(inspect_cbcl::subcommand())
+        .subcommand(verify_output::subcommand())
+        .subcommand(train_zstd_dictionary::subcommand());
+    let matches = app.clone().get_matches();
+
+    let (name, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.unwrap();
+
+    let options = Options::new(sub_matches, &arg_names(&app, name));
+
+    init_logging(&options);
+
+    let n_threads = options.value::<usize>("threads").unwrap();
+
+    ThreadPoolBuilder::new()
+        .num_threads(n_threads)
+        .build_global()
+        .unwrap_or_else(|e| panic!("Error configuring global threadpool: {}", e));
+
+    let status = match name {
+        "demux" => demux::run(&options),
+        "validate" => validate::run(&options),
+        "stats" => stats::run(&options),
+        "dump-tile" => dump_tile::run(&options),
+        "barcode-count" => barcode_count::run(&options),
+        "merge-stats" => merge_stats::run(&options),
+        "merge" => merge::run(&options),
+        "bench" => bench::run(&options),
+        "compare" => compare::run(&options),
+        "genrun" => genrun::run(&options),
+        "inspect-cbcl" => inspect_cbcl::run(&options),
+        "verify-output" => verify_output::run(&options),
+        "train-zstd-dictionary" => train_zstd_dictionary::run(&options),
+        _ => unreachable!("clap only accepts the subcommands above"),
+    };
+
+    process::exit(status.exit_code());
+}
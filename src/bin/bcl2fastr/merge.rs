@@ -0,0 +1,104 @@
+//! `bcl2fastr merge`: join the fastq files written by each shard of a run, and
+//! optionally by each lane of a sample, then merge the shard stats and write the
+//! checksums of the merged files
+
+use clap::{App, Arg, SubCommand};
+use std::path::PathBuf;
+
+use common::merge::{find_chunks, merge_chunks, write_checksums, CHECKSUMS_NAME};
+use common::reports::write_merged_reports;
+use common::run_summary::RunStatus;
+use common::stats::merge_stats_json;
+
+use log::{error, info};
+
+use crate::merge_stats::find_shard_stats;
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("merge")
+        .about("join the fastq files of each shard (and lane, with --lanes) of a demux")
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("output path of the demux, where the merged files are written")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("lanes")
+                .long("lanes")
+                .help("also join the files for each lane of a sample, e.g. s1_L001_R1 and s1_L002_R1 into s1_R1"),
+        )
+        .arg(
+            Arg::with_name("remove-chunks")
+                .long("remove-chunks")
+                .help("delete the files that were joined once the merged file is written"),
+        )
+}
+
+/// Merge the fastq files, then the stats of the shards if there are any
+pub fn run(options: &Options) -> RunStatus {
+    let output_path = PathBuf::from(options.value_of("output").unwrap());
+    if !output_path.is_dir() {
+        error!("Could not find output path {}", output_path.display());
+        return RunStatus::OutputError;
+    }
+
+    let merged = match find_chunks(&output_path, options.is_present("lanes")) {
+        Ok(merged) => merged,
+        Err(e) => {
+            error!("Error listing {}: {}", output_path.display(), e);
+            return RunStatus::OutputError;
+        }
+    };
+    if merged.is_empty() {
+        error!("No fastq files to merge in {}", output_path.display());
+        return RunStatus::OutputError;
+    }
+
+    info!(
+        "merging {} files into {}",
+        merged.iter().map(|m| m.chunks.len()).sum::<usize>(),
+        merged.len()
+    );
+    let checksums = match merge_chunks(&merged, options.is_present("remove-chunks")) {
+        Ok(checksums) => checksums,
+        Err(e) => {
+            error!("Error merging fastq files: {}", e);
+            return RunStatus::OutputError;
+        }
+    };
+    if let Err(e) = write_checksums(&output_path, &checksums) {
+        error!("Error writing {}: {}", CHECKSUMS_NAME, e);
+        return RunStatus::OutputError;
+    }
+
+    // the stats of each lane are already in Stats.json, only shards need merging
+    let stats_paths = find_shard_stats(&output_path).unwrap_or_default();
+    if !stats_paths.is_empty() {
+        info!("merging {} stats files", stats_paths.len());
+        let lane_stats = match merge_stats_json(&stats_paths, &output_path) {
+            Ok(lane_stats) => lane_stats,
+            Err(e) => {
+                error!("Error merging stats: {}", e);
+                return RunStatus::OutputError;
+            }
+        };
+
+        if let Err(e) = write_merged_reports(&output_path, &lane_stats) {
+            error!("Error writing reports: {}", e);
+            return RunStatus::OutputError;
+        }
+    }
+
+    for merged_file in merged.iter() {
+        println!(
+            "{}: {} files",
+            merged_file.path.display(),
+            merged_file.chunks.len()
+        );
+    }
+
+    RunStatus::Success
+}
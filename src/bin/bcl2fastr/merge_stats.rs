@@ -0,0 +1,94 @@
+//! `bcl2fastr merge-stats`: combine the stats written by each shard of a run into
+//! a single Stats.json and set of reports
+
+use clap::{App, Arg, SubCommand};
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+use common::reports::write_merged_reports;
+use common::run_summary::RunStatus;
+use common::stats::merge_stats_json;
+
+use log::{error, info};
+
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("merge-stats")
+        .about("combine the stats from each shard of a run demultiplexed with --shard")
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("output path of the shards, where the merged stats and reports are written")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .help("Stats.json files to merge [default: Stats/Stats.shard*.json in the output path]")
+                .multiple(true),
+        )
+}
+
+/// Find the per-shard stats files in the `Stats` directory of the output
+pub fn find_shard_stats(output_path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut stats_paths: Vec<_> = read_dir(output_path.join("Stats"))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("Stats.shard") && name.ends_with(".json"))
+        })
+        .collect();
+    stats_paths.sort();
+
+    Ok(stats_paths)
+}
+
+/// Merge the stats and write out the combined Stats.json and reports
+pub fn run(options: &Options) -> RunStatus {
+    let output_path = PathBuf::from(options.value_of("output").unwrap());
+    if !output_path.is_dir() {
+        error!("Could not find output path {}", output_path.display());
+        return RunStatus::OutputError;
+    }
+
+    let mut stats_paths: Vec<_> = options
+        .values_of("stats")
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    if stats_paths.is_empty() {
+        stats_paths = find_shard_stats(&output_path).unwrap_or_default();
+    }
+    if stats_paths.is_empty() {
+        error!(
+            "No shard stats found in {}",
+            output_path.join("Stats").display()
+        );
+        return RunStatus::OutputError;
+    }
+
+    info!("merging {} stats files", stats_paths.len());
+    let lane_stats = match merge_stats_json(&stats_paths, &output_path) {
+        Ok(lane_stats) => lane_stats,
+        Err(e) => {
+            error!("Error merging stats: {}", e);
+            return RunStatus::OutputError;
+        }
+    };
+
+    if let Err(e) = write_merged_reports(&output_path, &lane_stats) {
+        error!("Error writing reports: {}", e);
+        return RunStatus::OutputError;
+    }
+
+    for ls in lane_stats.iter() {
+        println!(
+            "lane {}: {} clusters passing filter, {} undetermined",
+            ls.lane_number, ls.total_clusters_pf, ls.undetermined.number_reads
+        );
+    }
+
+    RunStatus::Success
+}
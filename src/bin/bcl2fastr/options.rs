@@ -0,0 +1,202 @@
+//! Looks up option values from the command line, falling back to a `--config` file
+//! and then to the defaults. Every value that is used is recorded, so that the
+//! effective configuration can be written out with the results
+
+use clap::{value_t, App, ArgMatches};
+use std::cell::RefCell;
+use std::path::Path;
+use std::str::FromStr;
+
+use common::config::Config;
+
+use toml::Value;
+
+pub struct Options<'a> {
+    matches: &'a ArgMatches<'a>,
+    config: Config,
+    effective: RefCell<Config>,
+}
+
+/// store numbers as numbers in the effective config, and anything else as a string
+fn toml_value(value: &str) -> Value {
+    match value.parse::<i64>() {
+        Ok(i) => Value::Integer(i),
+        Err(_) => Value::String(value.to_string()),
+    }
+}
+
+/// The names of the options that `subcommand` takes, with the global ones. clap 2
+/// has no public way to list the arguments of an `App`, so this reads its parser
+pub fn arg_names<'a>(app: &App<'a, '_>, subcommand: &str) -> Vec<&'a str> {
+    let mut names: Vec<_> = app.p.global_args.iter().map(|arg| arg.b.name).collect();
+    if let Some(sub) = app
+        .p
+        .subcommands
+        .iter()
+        .find(|sub| sub.p.meta.name == subcommand)
+    {
+        names.extend(sub.p.flags.iter().map(|flag| flag.b.name));
+        names.extend(sub.p.opts.iter().map(|opt| opt.b.name));
+        names.extend(sub.p.positionals.values().map(|pos| pos.b.name));
+    }
+
+    names
+}
+
+impl<'a> Options<'a> {
+    /// Read the config file given with `--config`, if any. Exits with an error
+    /// message if the file can't be read or sets an option that isn't in `known`
+    pub fn new(matches: &'a ArgMatches<'a>, known: &[&str]) -> Options<'a> {
+        let config_error = |message: String| -> ! {
+            clap::Error {
+                message: format!("invalid value for 'config': {}", message),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        };
+
+        let config = match matches.value_of("config") {
+            Some(config_path) => {
+                let config = Config::read_path(Path::new(config_path))
+                    .unwrap_or_else(|e| config_error(e.to_string()));
+                let unknown = config.unknown_keys(known);
+                if !unknown.is_empty() {
+                    config_error(format!(
+                        "{}: unknown option(s) {}",
+                        config_path,
+                        unknown.join(", ")
+                    ));
+                }
+                config
+            }
+            None => Config::default(),
+        };
+
+        Options {
+            matches,
+            config,
+            effective: RefCell::new(Config::default()),
+        }
+    }
+
+    /// true if the option was given on the command line, rather than defaulted
+    fn on_command_line(&self, name: &str) -> bool {
+        self.matches.occurrences_of(name) > 0
+    }
+
+    fn record(&self, name: &str, value: Value) {
+        self.effective.borrow_mut().set(name, value);
+    }
+
+    /// The value of an option as a string
+    pub fn value_of(&self, name: &str) -> Option<String> {
+        let value = if self.on_command_line(name) {
+            self.matches.value_of(name).map(String::from)
+        } else {
+            self.config
+                .get(name)
+                .or_else(|| self.matches.value_of(name).map(String::from))
+        };
+
+        if let Some(value) = &value {
+            self.record(name, toml_value(value));
+        }
+
+        value
+    }
+
+    /// The value of an option parsed as `T`, exiting with an error if it's invalid
+    pub fn value<T: FromStr>(&self, name: &str) -> Option<T> {
+        if self.on_command_line(name) || self.config.get(name).is_none() {
+            let raw_value = self.matches.value_of(name)?;
+            let matches = self.matches;
+            let value = value_t!(matches, name, T).unwrap_or_else(|e| e.exit());
+            self.record(name, toml_value(raw_value));
+            return Some(value);
+        }
+
+        let config_value = self.config.get(name).unwrap();
+        let value = T::from_str(&config_value).unwrap_or_else(|_| {
+            clap::Error {
+                message: format!(
+                    "invalid value for '{}' in config file: '{}'",
+                    name, config_value
+                ),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        });
+        self.record(name, toml_value(&config_value));
+
+        Some(value)
+    }
+
+    /// true if an option was given on the command line or in the config, rather
+    /// than left to its default
+    pub fn is_given(&self, name: &str) -> bool {
+        self.on_command_line(name) || self.config.get(name).is_some()
+    }
+
+    /// true if a flag was given on the command line or set to true in the config
+    pub fn is_present(&self, name: &str) -> bool {
+        let present = self.matches.is_present(name) || self.config.get_flag(name);
+        if present {
+            self.record(name, Value::Boolean(true));
+        }
+        present
+    }
+
+    /// the number of times a flag was given (e.g. `-vv`), or its value in the config
+    pub fn occurrences_of(&self, name: &str) -> u64 {
+        let occurrences = if self.on_command_line(name) {
+            self.matches.occurrences_of(name)
+        } else if self.config.get_flag(name) {
+            1
+        } else {
+            self.config
+                .get(name)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0)
+        };
+
+        if occurrences > 0 {
+            self.record(name, Value::Integer(occurrences as i64));
+        }
+        occurrences
+    }
+
+    /// all the values of an option that can be given more than once
+    pub fn values_of(&self, name: &str) -> Vec<String> {
+        let values: Vec<_> = if self.on_command_line(name) {
+            self.matches
+                .values_of(name)
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect()
+        } else {
+            self.config.get_all(name)
+        };
+
+        if !values.is_empty() {
+            self.record(
+                name,
+                Value::Array(values.iter().cloned().map(Value::String).collect()),
+            );
+        }
+        values
+    }
+
+    /// Every option value that has been used so far
+    pub fn effective_config(&self) -> Config {
+        self.effective.borrow().clone()
+    }
+
+    /// Write every option value that has been used so far as a TOML file, which can
+    /// be passed back in with `--config`
+    pub fn write_effective(&self, path: &Path) -> std::io::Result<()> {
+        self.effective.borrow().write(path)
+    }
+}
@@ -0,0 +1,59 @@
+//! `bcl2fastr stats`: print a summary of a run folder (read structure and cluster
+//! counts per lane) without reading any basecalls
+
+use clap::{App, SubCommand};
+
+use common::run_summary::RunStatus;
+
+use crate::load::{check_run_path, load_run, run_load_args, run_path_arg};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("stats")
+        .about("print the read structure and cluster counts for a run")
+        .arg(run_path_arg())
+        .args(&run_load_args())
+}
+
+/// Load the index cycles of the run and print a tab-separated summary to stdout
+pub fn run(options: &Options) -> RunStatus {
+    let novaseq_run =
+        match check_run_path(options).and_then(|run_path| load_run(run_path, true, options)) {
+            Ok(novaseq_run) => novaseq_run,
+            Err(e) => return e.fail(),
+        };
+
+    let run_info = &novaseq_run.run_info;
+
+    println!("run_id\t{}", run_info.id);
+    println!("instrument\t{}", run_info.instrument);
+    println!("flowcell\t{}", run_info.flowcell);
+    println!("date\t{}", run_info.date);
+    println!("read_structure\t{}", novaseq_run.read_structure);
+    println!();
+    println!("lane\ttiles\tclusters_raw\tclusters_pf\tpercent_pf");
+
+    for lane in 1..=run_info.flowcell_layout.lane_count {
+        let n_tiles = novaseq_run.tile_count(lane);
+        let clusters_raw = novaseq_run.clusters_raw(lane);
+        let clusters_pf = novaseq_run
+            .n_pfs
+            .iter()
+            .filter(|([l, _], _)| *l == lane)
+            .flat_map(|(_, n_pfs)| n_pfs.iter())
+            .sum::<usize>() as u64;
+
+        let percent_pf = if clusters_raw > 0 {
+            100. * clusters_pf as f64 / clusters_raw as f64
+        } else {
+            0.
+        };
+
+        println!(
+            "{}\t{}\t{}\t{}\t{:.2}",
+            lane, n_tiles, clusters_raw, clusters_pf, percent_pf
+        );
+    }
+
+    RunStatus::Success
+}
@@ -0,0 +1,66 @@
+//! `bcl2fastr train-zstd-dictionary`: train a zstd dictionary on the fastq files of
+//! an earlier demux, for `demux --zstd --zstd-dictionary`
+
+use clap::{App, Arg, SubCommand};
+use std::path::Path;
+
+use common::run_summary::RunStatus;
+use common::zstd_dictionary::{train_from_files, DEFAULT_DICTIONARY_SIZE};
+
+use log::{error, info};
+
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("train-zstd-dictionary")
+        .about("train a zstd dictionary on fastq files, for demux --zstd-dictionary")
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("path of the dictionary file to write")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("max-size")
+                .long("max-size")
+                .help("the largest dictionary to train, in bytes [default: 112640]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fastq")
+                .help("fastq files to train on, which can be gzipped")
+                .multiple(true)
+                .required(true),
+        )
+}
+
+/// Train the dictionary and write it out
+pub fn run(options: &Options) -> RunStatus {
+    let fastq_paths = options.values_of("fastq");
+    let fastq_paths: Vec<&Path> = fastq_paths.iter().map(Path::new).collect();
+    let max_size = options
+        .value::<usize>("max-size")
+        .unwrap_or(DEFAULT_DICTIONARY_SIZE);
+    let output_path = options.value_of("output").unwrap();
+
+    info!("training a dictionary on {} fastq files", fastq_paths.len());
+    let dictionary = match train_from_files(&fastq_paths, max_size) {
+        Ok(dictionary) => dictionary,
+        Err(e) => {
+            error!("Error training the dictionary: {}", e);
+            return RunStatus::OutputError;
+        }
+    };
+    if let Err(e) = std::fs::write(&output_path, &dictionary) {
+        error!("Error writing {}: {}", output_path, e);
+        return RunStatus::OutputError;
+    }
+
+    println!(
+        "wrote a {} byte dictionary to {}",
+        dictionary.len(),
+        output_path
+    );
+    RunStatus::Success
+}
@@ -0,0 +1,152 @@
+//! `bcl2fastr validate`: check a samplesheet against a run folder before
+//! demultiplexing, and print any problems that would cause reads to go undetermined
+
+use clap::{App, SubCommand};
+
+use common::novaseq_run::NovaSeqRun;
+use common::run_summary::RunStatus;
+use common::sample_data::{ConflictPolicy, SampleData};
+
+use crate::load::{
+    check_run_path, check_samplesheet, check_sheet_reads, dual_index_args, i5_orientation,
+    i5_orientation_arg, load_folder_check, load_run_parameters, load_samplesheet, mismatch_arg,
+    on_conflict_arg, open_run, run_path_arg, samplesheet_arg, set_dual_index_mode,
+};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("validate")
+        .about("check that a samplesheet matches a run folder, without demultiplexing")
+        .arg(run_path_arg())
+        .arg(samplesheet_arg())
+        .arg(mismatch_arg())
+        .arg(on_conflict_arg())
+        .args(&dual_index_args())
+        .arg(i5_orientation_arg())
+}
+
+/// Compare the lanes and index lengths in the samplesheet with the run. Indices
+/// shorter than their index reads aren't a problem, as only their bases are matched
+fn find_problems(novaseq_run: &NovaSeqRun, sample_data: &SampleData) -> Vec<String> {
+    let lane_count = novaseq_run.run_info.flowcell_layout.lane_count;
+    let index_cycles: Vec<_> = novaseq_run
+        .read_structure
+        .indices()
+        .map(|s| s.num_cycles())
+        .collect();
+
+    let mut lanes: Vec<_> = sample_data.keys().cloned().collect();
+    lanes.sort_unstable();
+
+    let mut problems = Vec::new();
+
+    for lane in lanes {
+        if lane > lane_count {
+            problems.push(format!(
+                "lane {} is in the samplesheet but the run only has {} lanes",
+                lane, lane_count
+            ));
+            continue;
+        }
+
+        let samples = &sample_data[&lane];
+        for (i, sample_name) in samples.sample_names.iter().enumerate() {
+            let index_string = samples.index_string(i);
+            let index_lengths: Vec<_> = index_string.split('+').map(str::len).collect();
+
+            if index_lengths.len() != index_cycles.len() {
+                problems.push(format!(
+                    "lane {} sample {}: {} indices in the samplesheet but {} index reads in the run",
+                    lane,
+                    sample_name,
+                    index_lengths.len(),
+                    index_cycles.len()
+                ));
+                continue;
+            }
+
+            for (k, (length, cycles)) in index_lengths.iter().zip(&index_cycles).enumerate() {
+                if length > cycles {
+                    problems.push(format!(
+                        "lane {} sample {}: index {} is {} bases but the run has {} cycles",
+                        lane,
+                        sample_name,
+                        k + 1,
+                        length,
+                        cycles
+                    ));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+/// Load the run and samplesheet and print any problems to stdout
+pub fn run(options: &Options) -> RunStatus {
+    let mismatch = options.value::<usize>("mismatch").unwrap();
+    let on_conflict = options.value::<ConflictPolicy>("on-conflict").unwrap();
+
+    let run_path = match check_run_path(options) {
+        Ok(run_path) => run_path,
+        Err(e) => return e.fail(),
+    };
+
+    let run_parameters = match load_run_parameters(&run_path) {
+        Ok(run_parameters) => run_parameters,
+        Err(e) => return e.fail(),
+    };
+    let i5_orientation = i5_orientation(options, &run_parameters);
+
+    let samplesheets = match check_samplesheet(options) {
+        Ok(samplesheets) => samplesheets,
+        Err(e) => return e.fail(),
+    };
+    let sample_data = match load_samplesheet(&samplesheets, mismatch, i5_orientation, on_conflict)
+        .and_then(|(mut sample_data, conflicts)| {
+            set_dual_index_mode(options, &mut sample_data).map(|_| (sample_data, conflicts))
+        }) {
+        Ok((sample_data, conflicts)) => {
+            // these don't stop the demux, but the user should know about them
+            for conflict in conflicts {
+                println!("{}", conflict);
+            }
+            sample_data
+        }
+        Err(e) => return e.fail(),
+    };
+
+    let folder_check = match load_folder_check(&run_path, None) {
+        Ok(folder_check) => folder_check,
+        Err(e) => return e.fail(),
+    };
+    for problem in folder_check.problems() {
+        println!("{}", problem);
+    }
+    if !folder_check.is_complete() {
+        return RunStatus::BasecallError;
+    }
+
+    // the samplesheet is only checked against RunInfo.xml, so no lanes are loaded
+    let novaseq_run = match open_run(run_path, true) {
+        Ok(novaseq_run) => novaseq_run,
+        Err(e) => return e.fail(),
+    };
+
+    let mut problems = match check_sheet_reads(&samplesheets, &novaseq_run) {
+        Ok(problems) => problems,
+        Err(e) => return e.fail(),
+    };
+    problems.extend(find_problems(&novaseq_run, &sample_data));
+
+    if problems.is_empty() {
+        println!("samplesheet is valid for run {}", novaseq_run.run_info.id);
+        RunStatus::Success
+    } else {
+        for problem in problems {
+            println!("{}", problem);
+        }
+        RunStatus::SamplesheetError
+    }
+}
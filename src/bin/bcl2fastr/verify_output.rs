@@ -0,0 +1,81 @@
+//! `bcl2fastr verify-output`: count the reads in the fastq files of a demux again
+//! and check them against its Stats.json, and against the run's filter files with
+//! `--run-path`. Meant as a last check before the files are delivered
+
+use clap::{App, Arg, SubCommand};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use common::run_summary::RunStatus;
+use common::verify::verify_output;
+
+use log::error;
+
+use crate::load::{check_run_path, load_run, run_load_args, run_path_arg};
+use crate::options::Options;
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("verify-output")
+        .about("count the reads in the fastq files of a demux and check them against Stats.json")
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("output path of the demux")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            run_path_arg()
+                .required(false)
+                .help("also check the clusters of each lane against this run's filter files"),
+        )
+        .args(&run_load_args())
+}
+
+/// Verify the output and print the counts, exiting with `Discordant` if anything
+/// disagrees
+pub fn run(options: &Options) -> RunStatus {
+    let output_path = PathBuf::from(options.value_of("output").unwrap());
+    if !output_path.is_dir() {
+        error!("Could not find output path {}", output_path.display());
+        return RunStatus::OutputError;
+    }
+
+    let run_clusters = if options.is_present("run-path") {
+        let novaseq_run =
+            match check_run_path(options).and_then(|run_path| load_run(run_path, true, options)) {
+                Ok(novaseq_run) => novaseq_run,
+                Err(e) => return e.fail(),
+            };
+
+        let mut run_clusters = BTreeMap::new();
+        for (&[lane, _], n_pfs) in novaseq_run.n_pfs.iter() {
+            let clusters = run_clusters
+                .entry(lane)
+                .or_insert((novaseq_run.clusters_raw(lane), 0));
+            clusters.1 += n_pfs.iter().sum::<usize>() as u64;
+        }
+        Some(run_clusters)
+    } else {
+        None
+    };
+
+    let verification = match verify_output(&output_path, run_clusters.as_ref()) {
+        Ok(verification) => verification,
+        Err(e) => {
+            error!("Error verifying {}: {}", output_path.display(), e);
+            return RunStatus::OutputError;
+        }
+    };
+
+    println!("{}", verification);
+    for discrepancy in verification.discrepancies.iter() {
+        error!("{}", discrepancy);
+    }
+
+    if verification.is_concordant() {
+        RunStatus::Success
+    } else {
+        RunStatus::Discordant
+    }
+}
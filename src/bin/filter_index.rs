@@ -92,8 +92,8 @@ fn main() {
         Err(e) => panic!("Error creating file: {}", e),
     };
 
-    for row in rdr.records().filter_map(|r| match r {
-        Ok(r) => Some(r),
+    for row in rdr.records().map(|r| match r {
+        Ok(r) => r,
         Err(e) => panic!("{}", e),
     }) {
         let indices = text_to_vecs(row.get(0).unwrap());
@@ -6,11 +6,11 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 use common::index_count::index_count;
+use common::logging::{parse_module_level, LogFormat, Logger, Timestamp};
 use common::novaseq_run::NovaSeqRun;
 
 use log::info;
 use rayon::ThreadPoolBuilder;
-use stderrlog;
 
 /// Parses command line arguments and runs demux
 fn main() {
@@ -62,6 +62,22 @@ fn main() {
                 .takes_value(true)
                 .possible_values(&["none", "sec", "ms", "ns"]),
         )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .help("write log lines as plain text or as JSON")
+                .takes_value(true)
+                .default_value("text")
+                .possible_values(&["text", "json"]),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .help("set the log level for a module, e.g. common::write_fastq=debug")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .get_matches();
 
     let verbose = matches.occurrences_of("verbosity") as usize;
@@ -69,7 +85,7 @@ fn main() {
     let ts = matches
         .value_of("timestamp")
         .map(|v| {
-            stderrlog::Timestamp::from_str(v).unwrap_or_else(|_| {
+            Timestamp::from_str(v).unwrap_or_else(|_| {
                 clap::Error {
                     message: "invalid value for 'timestamp'".into(),
                     kind: clap::ErrorKind::InvalidValue,
@@ -78,16 +94,29 @@ fn main() {
                 .exit()
             })
         })
-        .unwrap_or(stderrlog::Timestamp::Off);
+        .unwrap_or(Timestamp::Off);
+    let log_format = value_t!(matches, "log-format", LogFormat).unwrap_or_else(|e| e.exit());
 
-    stderrlog::new()
+    let mut logger = Logger::new()
         .module(module_path!())
         .module("common")
-        .quiet(quiet)
         .verbosity(verbose)
         .timestamp(ts)
-        .init()
-        .unwrap();
+        .format(log_format);
+
+    for module_level in matches.values_of("log-level").into_iter().flatten() {
+        let (module, level) = parse_module_level(module_level).unwrap_or_else(|e| {
+            clap::Error {
+                message: format!("invalid value for 'log-level': {}", e),
+                kind: clap::ErrorKind::InvalidValue,
+                info: None,
+            }
+            .exit()
+        });
+        logger = logger.module_level(&module, level);
+    }
+
+    logger.quiet(quiet).init().unwrap();
 
     let run_path = PathBuf::from(matches.value_of("run-path").unwrap());
     if !run_path.exists() {
@@ -118,6 +147,8 @@ fn main() {
 
     info!("Finished loading novaseq run");
     info!("Counting indexes");
-    index_count(&novaseq_run, output_path, top_n).unwrap();
+    if let Err(e) = index_count(&novaseq_run, output_path, top_n) {
+        panic!("Error writing index counts: {}", e);
+    }
     info!("Done");
 }
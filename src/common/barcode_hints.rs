@@ -0,0 +1,268 @@
+//! Looks for common causes of undetermined reads, by comparing unknown barcodes
+//! against the sample indices in the samplesheet and some widely-used kit indices
+
+use std::fmt;
+
+use crate::stats::SampleStats;
+
+/// TruSeq single-index adapters (i7)
+const TRUSEQ_I7: [(&str, &str); 12] = [
+    ("AD001", "ATCACG"),
+    ("AD002", "CGATGT"),
+    ("AD003", "TTAGGC"),
+    ("AD004", "TGACCA"),
+    ("AD005", "ACAGTG"),
+    ("AD006", "GCCAAT"),
+    ("AD007", "CAGATC"),
+    ("AD008", "ACTTGA"),
+    ("AD009", "GATCAG"),
+    ("AD010", "TAGCTT"),
+    ("AD011", "GGCTAC"),
+    ("AD012", "CTTGTA"),
+];
+
+/// Nextera i7 indices
+const NEXTERA_I7: [(&str, &str); 12] = [
+    ("N701", "TAAGGCGA"),
+    ("N702", "CGTACTAG"),
+    ("N703", "AGGCAGAA"),
+    ("N704", "TCCTGAGC"),
+    ("N705", "GGACTCCT"),
+    ("N706", "TAGGCATG"),
+    ("N707", "CTCTCTAC"),
+    ("N708", "CAGAGAGG"),
+    ("N709", "GCTACGCT"),
+    ("N710", "CGAGGCTG"),
+    ("N711", "AAGAGGCA"),
+    ("N712", "GTAGAGGA"),
+];
+
+/// Nextera i5 indices
+const NEXTERA_I5: [(&str, &str); 8] = [
+    ("N501", "TAGATCGC"),
+    ("N502", "CTCTCTAT"),
+    ("N503", "TATCCTCT"),
+    ("N504", "AGAGTAGA"),
+    ("N505", "GTAAGGAG"),
+    ("N506", "ACTGCATA"),
+    ("N507", "AAGGAGTA"),
+    ("N508", "CTAAGCCT"),
+];
+
+/// A likely explanation for why a barcode didn't match any sample
+#[derive(Debug, Clone, PartialEq)]
+pub enum BarcodeHint {
+    /// index2 is the reverse complement of this sample's index2
+    ReverseComplementIndex2(String),
+    /// index and index2 are swapped relative to this sample
+    SwappedIndices(String),
+    /// the index is shifted by one cycle relative to this sample
+    CycleShift(String),
+    /// the index matches a kit index that isn't in the samplesheet
+    KitIndex(&'static str),
+    /// the index is all G, which on two-color chemistry means no signal
+    NoSignal,
+}
+
+impl fmt::Display for BarcodeHint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BarcodeHint::ReverseComplementIndex2(sample) => write!(
+                f,
+                "index2 is the reverse complement of {}: check the index2 orientation \
+                 for this instrument",
+                sample
+            ),
+            BarcodeHint::SwappedIndices(sample) => write!(
+                f,
+                "index and index2 of {} are swapped: check the index columns in the samplesheet",
+                sample
+            ),
+            BarcodeHint::CycleShift(sample) => write!(
+                f,
+                "index matches {} shifted by one cycle: check the index lengths and read structure",
+                sample
+            ),
+            BarcodeHint::KitIndex(name) => write!(
+                f,
+                "matches kit index {}, which is not in the samplesheet: a sample may be missing",
+                name
+            ),
+            BarcodeHint::NoSignal => write!(
+                f,
+                "poly-G index: no signal, likely a missing library or a failed index read"
+            ),
+        }
+    }
+}
+
+/// reverse complement of a DNA sequence, leaving anything other than ACGT alone
+pub(crate) fn reverse_complement(seq: &str) -> String {
+    seq.chars()
+        .rev()
+        .map(|c| match c {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            c => c,
+        })
+        .collect()
+}
+
+/// true if the sequences are the same length and differ at no more than one position
+fn within_one(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).filter(|(x, y)| x != y).count() <= 1
+}
+
+/// true if the sequences match when one is shifted by a single cycle
+fn shifted_by_one(a: &str, b: &str) -> bool {
+    if a.len() != b.len() || a.len() < 2 {
+        return false;
+    }
+
+    let n = a.len();
+    a[1..] == b[..n - 1] || a[..n - 1] == b[1..]
+}
+
+/// true if the index starts with the kit sequence. Some kit indices are only six
+/// bases, so we don't allow any mismatches here
+fn matches_kit(index: &str, kit_seq: &str) -> bool {
+    index.starts_with(kit_seq)
+}
+
+/// Look for explanations for an unknown barcode (one or two indices joined by '+')
+/// given the samples that were expected in the lane
+pub fn find_hints(barcode: &str, samples: &[SampleStats]) -> Vec<BarcodeHint> {
+    let mut split = barcode.split('+');
+    let index = split.next().unwrap_or_default();
+    let index2 = split.next();
+
+    if !index.is_empty() && index.bytes().all(|b| b == b'G') {
+        return vec![BarcodeHint::NoSignal];
+    }
+
+    let mut hints = Vec::new();
+
+    for sample in samples {
+        let mut sample_split = sample.index_metrics[0].index_sequence.split('+');
+        let s_index = sample_split.next().unwrap_or_default();
+        let s_index2 = sample_split.next();
+
+        match (index2, s_index2) {
+            (Some(index2), Some(s_index2)) => {
+                if within_one(index, s_index)
+                    && !within_one(index2, s_index2)
+                    && within_one(index2, &reverse_complement(s_index2))
+                {
+                    hints.push(BarcodeHint::ReverseComplementIndex2(
+                        sample.sample_id.clone(),
+                    ));
+                } else if within_one(index, s_index2) && within_one(index2, s_index) {
+                    hints.push(BarcodeHint::SwappedIndices(sample.sample_id.clone()));
+                } else if shifted_by_one(index, s_index) && shifted_by_one(index2, s_index2) {
+                    hints.push(BarcodeHint::CycleShift(sample.sample_id.clone()));
+                }
+            }
+            _ => {
+                if shifted_by_one(index, s_index) {
+                    hints.push(BarcodeHint::CycleShift(sample.sample_id.clone()));
+                }
+            }
+        }
+    }
+
+    if !hints.is_empty() {
+        return hints;
+    }
+
+    // only suggest kit indices that aren't already used by a sample in the sheet
+    let in_sheet = |kit_seq: &str, i: usize| {
+        samples.iter().any(|s| {
+            s.index_metrics[0]
+                .index_sequence
+                .split('+')
+                .nth(i)
+                .is_some_and(|s_index| matches_kit(s_index, kit_seq))
+        })
+    };
+
+    for (name, kit_seq) in TRUSEQ_I7.iter().chain(NEXTERA_I7.iter()) {
+        if matches_kit(index, kit_seq) && !in_sheet(kit_seq, 0) {
+            hints.push(BarcodeHint::KitIndex(name));
+        }
+    }
+
+    if let Some(index2) = index2 {
+        for (name, kit_seq) in NEXTERA_I5.iter() {
+            let matched =
+                matches_kit(index2, kit_seq) || matches_kit(index2, &reverse_complement(kit_seq));
+            if matched && !in_sheet(kit_seq, 1) {
+                hints.push(BarcodeHint::KitIndex(name));
+            }
+        }
+    }
+
+    hints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::IndexMetrics;
+
+    fn sample(sample_id: &str, index_sequence: &str) -> SampleStats {
+        SampleStats {
+            sample_id: sample_id.to_string(),
+            sample_name: sample_id.to_string(),
+            sample_number: 0,
+            sample_project: None,
+            genome: None,
+            index_metrics: vec![IndexMetrics {
+                index_sequence: index_sequence.to_string(),
+                mismatch_counts: Default::default(),
+            }],
+            index_mismatch_histogram: Vec::new(),
+            number_reads: 0,
+            number_reads_filtered: 0,
+            number_reads_adapter_dimer: None,
+            yield_bases: 0,
+            read_metrics: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reverse_complement() {
+        assert_eq!(super::reverse_complement("AACGTN"), "NACGTT");
+    }
+
+    #[test]
+    fn find_hints() {
+        let samples = vec![
+            sample("s1", "ACTGCGAA+GATTGTCC"),
+            sample("s2", "TAGTCTCG+AGTGGCAA"),
+        ];
+
+        assert_eq!(
+            super::find_hints("ACTGCGAA+GGACAATC", &samples),
+            vec![BarcodeHint::ReverseComplementIndex2("s1".to_string())]
+        );
+        assert_eq!(
+            super::find_hints("AGTGGCAA+TAGTCTCG", &samples),
+            vec![BarcodeHint::SwappedIndices("s2".to_string())]
+        );
+        assert_eq!(
+            super::find_hints("CTGCGAAT+ATTGTCCA", &samples),
+            vec![BarcodeHint::CycleShift("s1".to_string())]
+        );
+        assert_eq!(
+            super::find_hints("TAAGGCGA+CCCCCCCC", &samples),
+            vec![BarcodeHint::KitIndex("N701")]
+        );
+        assert_eq!(
+            super::find_hints("GGGGGGGG+AGTGGCAA", &samples),
+            vec![BarcodeHint::NoSignal]
+        );
+        assert!(super::find_hints("CCCCCCCC+CCCCCCCC", &samples).is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+//! How a cluster's index reads are matched to a sample. The demux uses the hamming
+//! sets built from the samplesheet by default, but any `BarcodeMatcher` can be put
+//! in the output options instead, e.g. to assign reads using their quality scores
+
+use std::{fmt, ops::Deref, sync::Arc};
+
+use ndarray::ArrayView1;
+
+use crate::sample_data::Samples;
+
+/// Finds the sample for the indices of one cluster. `indices` has the bases of each
+/// index read, and `qscores` their quality scores (phred+33), in the same order
+pub trait BarcodeMatcher: Send + Sync {
+    /// The sample that the indices belong to and the number of differences from
+    /// its indices, or `None` if the read is undetermined
+    fn find_sample(
+        &self,
+        samples: &Samples,
+        indices: &[ArrayView1<u8>],
+        qscores: &[ArrayView1<u8>],
+    ) -> Option<(usize, usize)>;
+}
+
+/// Matches each index to a sample's within the hamming distance that the
+/// samplesheet was loaded with
+pub struct HammingMatcher;
+
+impl BarcodeMatcher for HammingMatcher {
+    fn find_sample(
+        &self,
+        samples: &Samples,
+        indices: &[ArrayView1<u8>],
+        _qscores: &[ArrayView1<u8>],
+    ) -> Option<(usize, usize)> {
+        samples
+            .find_sample(indices)
+            .map(|sample_i| (sample_i, samples.mismatches(sample_i, indices)))
+    }
+}
+
+/// Only matches indices that are exactly the same as a sample's
+pub struct ExactMatcher;
+
+impl BarcodeMatcher for ExactMatcher {
+    fn find_sample(
+        &self,
+        samples: &Samples,
+        indices: &[ArrayView1<u8>],
+        _qscores: &[ArrayView1<u8>],
+    ) -> Option<(usize, usize)> {
+        (0..samples.sample_names.len())
+            .find(|&i| samples.is_exact(i, indices))
+            .map(|sample_i| (sample_i, 0))
+    }
+}
+
+/// Matches each index within an edit distance of a sample's, so that a base
+/// inserted or deleted in the index read can be corrected. The distances aren't
+/// checked for conflicts in advance like the hamming sets, so a read that is as
+/// close to two samples is undetermined
+pub struct LevenshteinMatcher {
+    pub max_distance: usize,
+}
+
+impl BarcodeMatcher for LevenshteinMatcher {
+    fn find_sample(
+        &self,
+        samples: &Samples,
+        indices: &[ArrayView1<u8>],
+        _qscores: &[ArrayView1<u8>],
+    ) -> Option<(usize, usize)> {
+        let max_total = samples.max_total_mismatches().unwrap_or(usize::MAX);
+        let mut best: Option<(usize, usize)> = None;
+        let mut tied = false;
+
+        for sample_i in 0..samples.sample_names.len() {
+            let distances: Vec<_> = indices
+                .iter()
+                .enumerate()
+                .map(|(index_i, index)| {
+                    edit_distance(samples.sample_index(index_i, sample_i), index)
+                })
+                .collect();
+            if distances.iter().any(|&d| d > self.max_distance) {
+                continue;
+            }
+
+            let total = distances.iter().sum();
+            if total > max_total {
+                continue;
+            }
+            match best {
+                Some((_, best_total)) if total > best_total => (),
+                Some((_, best_total)) if total == best_total => tied = true,
+                _ => {
+                    best = Some((sample_i, total));
+                    tied = false;
+                }
+            }
+        }
+
+        best.filter(|_| !tied)
+    }
+}
+
+/// The default limit on the summed quality of the mismatched bases for
+/// `QualityMatcher`: one mismatch at Q8 is corrected, but not one at Q37
+pub const DEFAULT_MAX_MISMATCH_QUALITY: usize = 20;
+
+/// Weighs each mismatch by the quality score of the base, so that a read can be
+/// corrected for a base the instrument wasn't sure of but not for one it called
+/// confidently. The read goes to the sample with the lowest summed quality of the
+/// mismatched bases if that is at most `max_score`, and is undetermined if two
+/// samples are as close. Like the hamming sets, no index can have more than
+/// `max_mismatches` mismatches, however low their quality
+pub struct QualityMatcher {
+    pub max_score: usize,
+    pub max_mismatches: usize,
+}
+
+impl BarcodeMatcher for QualityMatcher {
+    fn find_sample(
+        &self,
+        samples: &Samples,
+        indices: &[ArrayView1<u8>],
+        qscores: &[ArrayView1<u8>],
+    ) -> Option<(usize, usize)> {
+        let max_total = samples.max_total_mismatches().unwrap_or(usize::MAX);
+        let mut best: Option<(usize, usize, usize)> = None;
+        let mut tied = false;
+
+        'samples: for sample_i in 0..samples.sample_names.len() {
+            let mut score = 0;
+            let mut mismatches = 0;
+            for (index_i, (index, qscore)) in indices.iter().zip(qscores).enumerate() {
+                let sample_index = samples.sample_index(index_i, sample_i);
+                if sample_index.len() != index.len() {
+                    continue 'samples;
+                }
+
+                let mut index_mismatches = 0;
+                for ((a, b), q) in sample_index.iter().zip(index).zip(qscore) {
+                    if a != b {
+                        score += q.saturating_sub(33) as usize;
+                        index_mismatches += 1;
+                    }
+                }
+                if index_mismatches > self.max_mismatches {
+                    continue 'samples;
+                }
+                mismatches += index_mismatches;
+            }
+
+            if score > self.max_score || mismatches > max_total {
+                continue;
+            }
+            match best {
+                Some((_, best_score, _)) if score > best_score => (),
+                Some((_, best_score, _)) if score == best_score => tied = true,
+                _ => {
+                    best = Some((sample_i, score, mismatches));
+                    tied = false;
+                }
+            }
+        }
+
+        best.filter(|_| !tied)
+            .map(|(sample_i, _, mismatches)| (sample_i, mismatches))
+    }
+}
+
+/// The number of substitutions, insertions and deletions to turn `a` into `b`
+fn edit_distance(a: &[u8], b: &ArrayView1<u8>) -> usize {
+    let mut row: Vec<_> = (0..=b.len()).collect();
+    for (i, &ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The matcher to use for a demux, kept in the output options. Handles are equal if
+/// they are the same matcher
+#[derive(Clone)]
+pub struct MatcherHandle(pub Arc<dyn BarcodeMatcher>);
+
+impl Deref for MatcherHandle {
+    type Target = dyn BarcodeMatcher;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl PartialEq for MatcherHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for MatcherHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MatcherHandle")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use ndarray::array;
+
+    use crate::sample_data::read_samplesheet;
+
+    #[test]
+    fn edit_distance() {
+        let b = array![65, 67, 71, 84];
+        assert_eq!(super::edit_distance(b"ACGT", &b.view()), 0);
+        assert_eq!(super::edit_distance(b"ACTT", &b.view()), 1);
+        // a base was skipped, so everything after it is shifted
+        assert_eq!(super::edit_distance(b"CGTA", &b.view()), 2);
+        assert_eq!(super::edit_distance(b"AGTA", &b.view()), 2);
+        assert_eq!(super::edit_distance(b"", &b.view()), 4);
+    }
+
+    #[test]
+    fn matchers() {
+        let samplesheet = PathBuf::from("test_data/sample_data/no_conflict_w_index2.csv");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        let samples = &sampledata[&0];
+
+        let exact = [array![71, 71, 71, 71, 71], array![65, 65, 65, 65, 65]];
+        let one_off = [array![71, 84, 71, 71, 71], array![65, 65, 65, 65, 65]];
+        // two mismatches from GGGGG
+        let far = [array![67, 71, 84, 71, 71], array![65, 65, 65, 65, 65]];
+        let qscores = [array![70, 70, 70, 70, 70], array![70, 70, 70, 70, 70]];
+
+        let find = |matcher: &dyn BarcodeMatcher, indices: &[ndarray::Array1<u8>; 2]| {
+            let indices: Vec<_> = indices.iter().map(|i| i.view()).collect();
+            let qscores: Vec<_> = qscores.iter().map(|q| q.view()).collect();
+            matcher.find_sample(samples, &indices, &qscores)
+        };
+
+        assert_eq!(find(&HammingMatcher, &exact), Some((0, 0)));
+        assert_eq!(find(&HammingMatcher, &one_off), Some((0, 1)));
+        assert_eq!(find(&HammingMatcher, &far), None);
+
+        assert_eq!(find(&ExactMatcher, &exact), Some((0, 0)));
+        assert_eq!(find(&ExactMatcher, &one_off), None);
+
+        let levenshtein = LevenshteinMatcher { max_distance: 1 };
+        assert_eq!(find(&levenshtein, &exact), Some((0, 0)));
+        assert_eq!(find(&levenshtein, &one_off), Some((0, 1)));
+        assert_eq!(find(&levenshtein, &far), None);
+        assert_eq!(
+            find(&LevenshteinMatcher { max_distance: 2 }, &far),
+            Some((0, 2))
+        );
+    }
+
+    #[test]
+    fn quality_matcher() {
+        let samplesheet = PathBuf::from("test_data/sample_data/no_conflict_w_index2.csv");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        let samples = &sampledata[&0];
+
+        // GTGGG+AAAAA, with the T at Q8 or Q37
+        let indices = [array![71, 84, 71, 71, 71], array![65, 65, 65, 65, 65]];
+        let find = |q: u8, matcher: &QualityMatcher| {
+            let qscores = [array![70, q + 33, 70, 70, 70], array![70, 70, 70, 70, 70]];
+            let indices: Vec<_> = indices.iter().map(|i| i.view()).collect();
+            let qscores: Vec<_> = qscores.iter().map(|q| q.view()).collect();
+            matcher.find_sample(samples, &indices, &qscores)
+        };
+
+        let quality = |max_score| QualityMatcher {
+            max_score,
+            max_mismatches: 1,
+        };
+        let matcher = quality(DEFAULT_MAX_MISMATCH_QUALITY);
+        assert_eq!(find(8, &matcher), Some((0, 1)));
+        assert_eq!(find(37, &matcher), None);
+        assert_eq!(find(37, &quality(40)), Some((0, 1)));
+        assert_eq!(find(8, &quality(0)), None);
+
+        // two low quality mismatches add up to less than the limit, but are more
+        // than one in the same index
+        let indices = [array![67, 84, 71, 71, 71], array![65, 65, 65, 65, 65]];
+        let qscores = [array![35, 35, 70, 70, 70], array![70, 70, 70, 70, 70]];
+        let indices: Vec<_> = indices.iter().map(|i| i.view()).collect();
+        let qscores: Vec<_> = qscores.iter().map(|q| q.view()).collect();
+        assert_eq!(matcher.find_sample(samples, &indices, &qscores), None);
+        let matcher = QualityMatcher {
+            max_score: DEFAULT_MAX_MISMATCH_QUALITY,
+            max_mismatches: 2,
+        };
+        assert_eq!(
+            matcher.find_sample(samples, &indices, &qscores),
+            Some((0, 2))
+        );
+    }
+}
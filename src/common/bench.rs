@@ -0,0 +1,291 @@
+//! Times each stage of the demux on a few tiles: reading the CBCLs, matching
+//! indices to samples, and formatting and compressing the output. The output is
+//! compressed but not written, so disk speed only affects the reading stage. The
+//! times are scaled up to project how long the whole run would take, to help pick
+//! thread and compression settings before starting a real demux
+
+use std::{
+    fmt,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::barcode_matcher::{BarcodeMatcher, HammingMatcher};
+use crate::novaseq_run::NovaSeqRun;
+use crate::sample_data::SampleData;
+use crate::thread_pools::{in_stage, Stage};
+use crate::write_fastq::{
+    assign_reads, check_ascii_offset, extract_tile, index_buffer, index_slices, output_writer,
+    pf_locs, write_records, OutputOptions,
+};
+
+/// Counts the bytes written to it and throws them away
+#[derive(Default)]
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How long one stage took, and how much it processed
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StageTime {
+    pub stage: String,
+    pub seconds: f64,
+    pub clusters: u64,
+    /// uncompressed CBCL bytes for reading, or output bytes for compression
+    pub bytes: u64,
+}
+
+impl StageTime {
+    fn new(stage: &str) -> StageTime {
+        StageTime {
+            stage: stage.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, elapsed: Duration, clusters: u64, bytes: u64) {
+        self.seconds += elapsed.as_secs_f64();
+        self.clusters += clusters;
+        self.bytes += bytes;
+    }
+
+    /// clusters per second
+    pub fn cluster_rate(&self) -> f64 {
+        self.clusters as f64 / self.seconds.max(1e-9)
+    }
+
+    /// megabytes per second
+    pub fn byte_rate(&self) -> f64 {
+        self.bytes as f64 / 1e6 / self.seconds.max(1e-9)
+    }
+}
+
+/// The stage times for a sample of tiles, and the projected time for all of them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BenchResult {
+    pub run_id: String,
+    pub tiles: usize,
+    pub total_tiles: usize,
+    pub stages: Vec<StageTime>,
+    pub projected_seconds: f64,
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "run: {}", self.run_id)?;
+        writeln!(
+            f,
+            "benchmarked {} of {} tiles",
+            self.tiles, self.total_tiles
+        )?;
+        for stage in self.stages.iter() {
+            write!(
+                f,
+                "{}: {:.2}s, {:.0} clusters/s",
+                stage.stage,
+                stage.seconds,
+                stage.cluster_rate()
+            )?;
+            if stage.bytes > 0 {
+                write!(f, ", {:.1} MB/s", stage.byte_rate())?;
+            }
+            writeln!(f)?;
+        }
+        write!(
+            f,
+            "projected time for the full run: {:.0}s",
+            self.projected_seconds
+        )
+    }
+}
+
+/// Run each stage of the demux on the first `n_tiles` tiles of the lanes in
+/// `sample_data`
+pub fn bench_tiles(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    n_tiles: usize,
+    output_options: &OutputOptions,
+) -> std::io::Result<BenchResult> {
+    check_ascii_offset(novaseq_run, output_options.ascii_offset)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    let mut keys: Vec<_> = novaseq_run
+        .tile_ids
+        .keys()
+        .filter(|[lane, _]| sample_data.contains_key(lane) || sample_data.contains_key(&0))
+        .cloned()
+        .collect();
+    keys.sort_unstable();
+
+    let total_tiles = keys.iter().map(|k| novaseq_run.tile_ids[k].len()).sum();
+
+    let idx_slices = index_slices(novaseq_run);
+    let matcher = output_options
+        .matcher
+        .as_deref()
+        .unwrap_or(&HammingMatcher as &dyn BarcodeMatcher);
+
+    let mut extract = StageTime::new("extract");
+    let mut matching = StageTime::new("match");
+    let mut compress = StageTime::new("compress");
+
+    let tiles = keys
+        .iter()
+        .flat_map(|key| (0..novaseq_run.tile_ids[key].len()).map(move |tile_i| (*key, tile_i)))
+        .take(n_tiles);
+
+    let mut n_benched = 0;
+    for ([lane, surface], tile_i) in tiles {
+        let samples = sample_data
+            .get(&lane)
+            .or_else(|| sample_data.get(&0))
+            .unwrap();
+
+        let tile = novaseq_run.tile_ids[&[lane, surface]][tile_i];
+        let filter = &novaseq_run.filters[&[lane, surface]][tile_i];
+        let pf_filter = &novaseq_run.pf_filters[&[lane, surface]][tile_i];
+        let n_pf = novaseq_run.n_pfs[&[lane, surface]][tile_i];
+        let read_headers = &novaseq_run.read_headers[&[lane, surface]];
+        let idx_headers = &novaseq_run.index_headers[&[lane, surface]];
+
+        // 1. read and decompress every cycle of the tile
+        let start = Instant::now();
+        let (index_arrays, read_arrays, locs_vec) = in_stage(Stage::Io, || {
+            let index_arrays = idx_headers
+                .iter()
+                .map(|h| extract_tile(h, filter, pf_filter, n_pf, tile_i))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            let read_arrays = read_headers
+                .iter()
+                .map(|h| extract_tile(h, filter, pf_filter, n_pf, tile_i))
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            let mut locs_vec = Vec::with_capacity(n_pf);
+            pf_locs(
+                novaseq_run.locs_for([lane, surface], tile_i),
+                filter,
+                &mut locs_vec,
+            );
+
+            Ok::<_, std::io::Error>((index_arrays, read_arrays, locs_vec))
+        })?;
+        let n_bytes: u64 = read_headers
+            .iter()
+            .chain(idx_headers.iter())
+            .flatten()
+            .map(|h| h.uncompressed_size[tile_i])
+            .sum();
+        extract.add(start.elapsed(), n_pf as u64, n_bytes);
+
+        let mut index_array = index_buffer(novaseq_run, n_pf);
+        for (ix_array, &[i0, i1]) in index_arrays.iter().zip(idx_slices.iter()) {
+            index_array
+                .slice_mut(ndarray::s![i0..i1, .., ..])
+                .assign(ix_array);
+        }
+
+        // 2. assign each cluster to a sample
+        let start = Instant::now();
+        let assignments = in_stage(Stage::Demux, || {
+            assign_reads(samples, matcher, n_pf, &index_array.view(), &idx_slices)
+        });
+        matching.add(start.elapsed(), n_pf as u64, 0);
+
+        // 3. format and compress the reads for every sample
+        let start = Instant::now();
+        let n_out_bytes: u64 = in_stage(Stage::Compress, || {
+            read_arrays
+                .iter()
+                .enumerate()
+                .map(|(k, read_array)| {
+                    (0..samples.sample_names.len())
+                        .into_par_iter()
+                        .map(|sample_i| {
+                            let mut counter = ByteCounter::default();
+                            {
+                                let mut writer = output_writer(&mut counter, output_options);
+                                write_records(
+                                    &mut writer,
+                                    novaseq_run,
+                                    sample_i,
+                                    &read_array.view(),
+                                    &index_array.view(),
+                                    &assignments,
+                                    &locs_vec,
+                                    None,
+                                    None,
+                                    None,
+                                    tile,
+                                    lane,
+                                    k + 1,
+                                    output_options,
+                                )
+                                .unwrap();
+                                writer.flush().unwrap();
+                            }
+                            counter.0
+                        })
+                        .sum::<u64>()
+                })
+                .sum()
+        });
+        compress.add(start.elapsed(), n_pf as u64, n_out_bytes);
+
+        n_benched += 1;
+    }
+
+    let total_seconds = extract.seconds + matching.seconds + compress.seconds;
+    let projected_seconds = total_seconds * total_tiles as f64 / n_benched.max(1) as f64;
+
+    Ok(BenchResult {
+        run_id: novaseq_run.run_info.id.clone(),
+        tiles: n_benched,
+        total_tiles,
+        stages: vec![extract, matching, compress],
+        projected_seconds,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::sample_data::read_samplesheet;
+
+    #[test]
+    fn bench_tiles() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sample_data = read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+
+        let result = super::bench_tiles(
+            &novaseq_run,
+            &sample_data,
+            2,
+            &OutputOptions {
+                compression: None,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(result.tiles, 2);
+        assert_eq!(result.total_tiles, 3);
+        assert_eq!(result.stages[0].clusters, result.stages[1].clusters);
+        assert!(result.stages[2].bytes > 0);
+    }
+}
@@ -0,0 +1,285 @@
+//! A pool of byte buffers shared by the stages of the demux. Decompressing a tile
+//! and writing out a sample's reads each need a large buffer for a moment: taking
+//! them from the pool means they are allocated once per thread, rather than once
+//! per tile and per output file
+
+use std::{
+    cell::Cell,
+    io::{self, Write},
+    mem,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// The most buffers to keep for reuse. This is more than the threads in any stage,
+/// so a buffer is only freed if there is a burst of them in use at once
+const MAX_POOLED_BUFFERS: usize = 256;
+
+/// The most memory to keep in buffers for reuse. Tile buffers can be hundreds of
+/// megabytes, so a few hundred of them can't be kept after a burst
+const MAX_POOLED_BYTES: usize = 4 << 30;
+
+/// Buffers that are free to reuse
+pub struct BufferPool {
+    free: Mutex<FreeBuffers>,
+    max_buffers: usize,
+    max_bytes: usize,
+}
+
+/// The buffers in a pool, and their total capacity
+struct FreeBuffers {
+    buffers: Vec<Vec<u8>>,
+    bytes: usize,
+}
+
+/// The pool shared by the whole demux
+static BUFFER_POOL: BufferPool = BufferPool::new(MAX_POOLED_BUFFERS, MAX_POOLED_BYTES);
+
+thread_local! {
+    /// The pool for this thread, if it has its own (see `set_thread_pool`)
+    static THREAD_POOL: Cell<Option<&'static BufferPool>> = const { Cell::new(None) };
+}
+
+/// The pool for the current thread: the one from `set_thread_pool`, or else the
+/// pool shared by the whole demux
+pub fn buffer_pool() -> &'static BufferPool {
+    THREAD_POOL.with(|p| p.get()).unwrap_or(&BUFFER_POOL)
+}
+
+/// Use `pool` for the buffers taken on this thread, e.g. so that the threads on a
+/// NUMA node only reuse buffers in that node's memory
+pub fn set_thread_pool(pool: &'static BufferPool) {
+    THREAD_POOL.with(|p| p.set(Some(pool)));
+}
+
+impl BufferPool {
+    /// A pool that keeps at most `max_buffers` buffers, with at most `max_bytes`
+    /// between them. Buffers that don't fit are freed when they are dropped
+    pub const fn new(max_buffers: usize, max_bytes: usize) -> BufferPool {
+        BufferPool {
+            free: Mutex::new(FreeBuffers {
+                buffers: Vec::new(),
+                bytes: 0,
+            }),
+            max_buffers,
+            max_bytes,
+        }
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes. It goes back in
+    /// the pool when it's dropped
+    pub fn get(&self, capacity: usize) -> PooledBuffer<'_> {
+        let mut buffer = {
+            let mut free = self.free.lock().unwrap();
+            // prefer a buffer that is big enough already, so it isn't reallocated
+            let buffer = match free.buffers.iter().position(|b| b.capacity() >= capacity) {
+                Some(i) => free.buffers.swap_remove(i),
+                None => free.buffers.pop().unwrap_or_default(),
+            };
+            free.bytes -= buffer.capacity();
+            buffer
+        };
+
+        buffer.clear();
+        buffer.reserve(capacity);
+
+        PooledBuffer { buffer, pool: self }
+    }
+
+    /// The number of buffers waiting to be reused
+    pub fn len(&self) -> usize {
+        self.free.lock().unwrap().buffers.len()
+    }
+
+    /// The total capacity of the buffers waiting to be reused
+    pub fn bytes(&self) -> usize {
+        self.free.lock().unwrap().bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn put(&self, buffer: Vec<u8>) {
+        let mut free = self.free.lock().unwrap();
+        if free.buffers.len() < self.max_buffers && free.bytes + buffer.capacity() <= self.max_bytes
+        {
+            free.bytes += buffer.capacity();
+            free.buffers.push(buffer);
+        }
+    }
+}
+
+/// A buffer borrowed from a `BufferPool`
+pub struct PooledBuffer<'a> {
+    buffer: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(mem::take(&mut self.buffer));
+    }
+}
+
+impl Write for PooledBuffer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Like `BufWriter`, but with its buffer from the pool. Writes are collected until
+/// there are `capacity` bytes, then passed on to `inner` in one go
+pub struct PooledWriter<'a, W: Write> {
+    inner: W,
+    buffer: PooledBuffer<'a>,
+    capacity: usize,
+}
+
+impl<W: Write> PooledWriter<'static, W> {
+    /// A writer with a buffer from the shared pool
+    pub fn new(inner: W, capacity: usize) -> PooledWriter<'static, W> {
+        PooledWriter::with_pool(inner, capacity, buffer_pool())
+    }
+}
+
+impl<'a, W: Write> PooledWriter<'a, W> {
+    pub fn with_pool(inner: W, capacity: usize, pool: &'a BufferPool) -> PooledWriter<'a, W> {
+        PooledWriter {
+            inner,
+            buffer: pool.get(capacity),
+            capacity,
+        }
+    }
+
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for PooledWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.capacity {
+            self.flush_buffer()?;
+        }
+
+        if buf.len() >= self.capacity {
+            self.inner.write(buf)
+        } else {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buffer()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for PooledWriter<'_, W> {
+    fn drop(&mut self) {
+        // errors are ignored here, the same as BufWriter: flush first to see them
+        let _ = self.flush_buffer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(2, usize::MAX);
+
+        let mut buffer = pool.get(100);
+        buffer.extend_from_slice(b"ACGT");
+        let ptr = buffer.as_ptr();
+        drop(buffer);
+        assert_eq!(pool.len(), 1);
+
+        // the same allocation comes back, emptied
+        let buffer = pool.get(50);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert!(pool.is_empty());
+
+        let others: Vec<_> = (0..3).map(|_| pool.get(10)).collect();
+        drop(buffer);
+        drop(others);
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn max_bytes() {
+        let pool = BufferPool::new(4, 1000);
+
+        let small = pool.get(100);
+        let small_capacity = small.capacity();
+        let big = pool.get(2000);
+        drop(big);
+        drop(small);
+
+        // the big buffer doesn't fit in the pool, so only the small one is kept
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.bytes(), small_capacity);
+
+        drop(pool.get(10));
+        assert_eq!(pool.bytes(), small_capacity);
+    }
+
+    #[test]
+    fn pooled_writer() {
+        let pool = BufferPool::new(4, usize::MAX);
+        let mut output = Vec::new();
+
+        {
+            let mut writer = PooledWriter::with_pool(&mut output, 8, &pool);
+            writer.write_all(b"@read\n").unwrap();
+            writer.write_all(b"ACGTACGTACGT\n").unwrap();
+            writer.write_all(b"+\n").unwrap();
+        }
+
+        assert_eq!(output, b"@read\nACGTACGTACGT\n+\n");
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn thread_pool() {
+        static POOL: BufferPool = BufferPool::new(4, usize::MAX);
+
+        std::thread::spawn(|| {
+            set_thread_pool(&POOL);
+            drop(buffer_pool().get(10));
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(POOL.len(), 1);
+        assert!(!std::ptr::eq(buffer_pool(), &POOL));
+    }
+}
@@ -1,16 +1,64 @@
 //! Read the header from CBCL file and decode into a struct of useful information about
-//! the file, to allow efficient tile extraction later.
+//! the file, to allow efficient tile extraction later. This is the only place that
+//! knows the layout of the header: loading a run, the metadata cache, `inspect-cbcl`
+//! and the synthetic runs from `genrun` all go through it.
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::File,
+    io::{self, Cursor, Read, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-#[derive(Debug, PartialEq)]
+use crate::storage::{local_storage, RunStorage, StorageHandle};
+
+/// Keep the elements of `values` where the matching element of `keep` is true
+pub(crate) fn retain_by<T>(values: &mut Vec<T>, keep: &[bool]) {
+    let mut keep_iter = keep.iter();
+    values.retain(|_| *keep_iter.next().unwrap());
+}
+
+/// The highest phred score that fits in a printable character as phred+33 (`~`)
+const MAX_PHRED: u32 = 93;
+
+/// The version of the CBCL format written by the NovaSeq, the only one so far
+const CBCL_V1: u16 = 1;
+
+fn invalid_data(cbcl_path: &Path, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", cbcl_path.display(), message),
+    )
+}
+
+/// Fail unless a header of `header_size` bytes has room for the first `needed` bytes,
+/// so that a corrupt count can't make us allocate or read far more than the header
+fn check_room(cbcl_path: &Path, header_size: u32, needed: u64, what: &str) -> io::Result<()> {
+    if needed > u64::from(header_size) {
+        return Err(invalid_data(
+            cbcl_path,
+            format!(
+                "the header is {} bytes, too short for {} ({} bytes)",
+                header_size, what, needed
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn default_storage() -> StorageHandle {
+    StorageHandle(local_storage())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 /// Represents the header information from a CBCL file
 pub struct CBCLHeader {
     pub cbcl_path: PathBuf,
+    /// where the file is read from. This isn't serialized: whoever deserializes a
+    /// header sets it to the storage of the run
+    #[serde(skip, default = "default_storage")]
+    pub storage: StorageHandle,
     pub version: u16,
     pub header_size: u32,
     pub bits_per_basecall: u8,
@@ -19,9 +67,14 @@ pub struct CBCLHeader {
     pub bins: Vec<u8>,
     pub num_tile_records: u32,
     pub tiles: Vec<u32>,
+    /// the number of clusters in each tile's block. Missing from metadata caches
+    /// written before it was kept
+    #[serde(default)]
+    pub n_clusters: Vec<u32>,
     pub non_pf_clusters_excluded: bool,
     pub start_pos: Vec<u64>,
     pub uncompressed_size: Vec<u64>,
+    pub compressed_size: Vec<u64>,
 }
 
 impl CBCLHeader {
@@ -42,30 +95,104 @@ impl CBCLHeader {
     ///     3. Uncompressed block size
     ///     4. Compressed block size
     ///     
-    ///     Note: we only store the tile number, and compute chunked uncompressed block sizes
+    ///     Note: we store the tile number, and compute the start of each block from
+    ///     the compressed sizes
     ///  9. `u8` flag for whether this file is only reads that pass quality filtering
+    ///
+    /// This is the layout of version 1, and other versions are an error rather than
+    /// being read as if they were the same
     pub fn from_path(cbcl_path: &Path) -> std::io::Result<Self> {
-        let mut rdr = File::open(cbcl_path)?;
+        CBCLHeader::read(local_storage(), cbcl_path)
+    }
+
+    /// Read the header of a CBCL file in `storage`. Only the header is read, which
+    /// matters when the file is in object storage
+    pub fn read(storage: Arc<dyn RunStorage>, cbcl_path: &Path) -> std::io::Result<Self> {
+        // the version and the header size, then the rest of the header
+        let mut start = [0u8; 6];
+        storage
+            .open_range(cbcl_path, 0, 6)?
+            .read_exact(&mut start)?;
+        let mut rdr = Cursor::new(start);
 
         let version = rdr.read_u16::<LittleEndian>()?;
         let header_size = rdr.read_u32::<LittleEndian>()?;
+
+        let rdr = storage.open_range(cbcl_path, 6, (header_size as u64).saturating_sub(6))?;
+        let header = match version {
+            CBCL_V1 => CBCLHeader::read_v1(storage.clone(), cbcl_path, header_size, rdr)?,
+            _ => {
+                return Err(invalid_data(
+                    cbcl_path,
+                    format!("unsupported CBCL version {}", version),
+                ))
+            }
+        };
+
+        // otherwise a truncated file only fails when the tile is read
+        let file_len = storage.file_len(cbcl_path)?;
+        if let Some(problem) = header.blocks_past_end(file_len).next() {
+            return Err(invalid_data(cbcl_path, problem));
+        }
+
+        Ok(header)
+    }
+
+    /// Read the rest of a version 1 header, after the version and header size
+    fn read_v1(
+        storage: Arc<dyn RunStorage>,
+        cbcl_path: &Path,
+        header_size: u32,
+        mut rdr: Box<dyn Read + '_>,
+    ) -> std::io::Result<Self> {
         let bits_per_basecall = rdr.read_u8()?;
         let bits_per_qscore = rdr.read_u8()?;
 
-        assert_eq!(bits_per_basecall, 2);
-        assert_eq!(bits_per_qscore, 2);
+        // the bases and scores are unpacked two bits at a time
+        if (bits_per_basecall, bits_per_qscore) != (2, 2) {
+            return Err(invalid_data(
+                cbcl_path,
+                format!(
+                    "{} bits per basecall and {} per quality score, only 2 and 2 can be read",
+                    bits_per_basecall, bits_per_qscore
+                ),
+            ));
+        }
 
         let number_of_bins = rdr.read_u32::<LittleEndian>()?;
-        let mut bin_buffer = vec![0u32; (2 * number_of_bins) as usize];
+        let bins_end = 12 + 8 * u64::from(number_of_bins);
+        check_room(
+            cbcl_path,
+            header_size,
+            bins_end,
+            &format!("{} quality bins", number_of_bins),
+        )?;
+        let mut bin_buffer = vec![0u32; 2 * number_of_bins as usize];
         rdr.read_u32_into::<LittleEndian>(&mut bin_buffer)?;
 
+        // scores are kept as phred+33, which only has room up to Q93
+        if let Some(bc) = bin_buffer.chunks_exact(2).find(|bc| bc[1] > MAX_PHRED) {
+            return Err(invalid_data(
+                cbcl_path,
+                format!(
+                    "quality bin {} is Q{}, above the highest score that can be written (Q{})",
+                    bc[0], bc[1], MAX_PHRED
+                ),
+            ));
+        }
         let bins = bin_buffer
             .chunks_exact(2)
             .map(|bc| bc[1].max(2) as u8 + 33)
             .collect();
 
         let num_tile_records = rdr.read_u32::<LittleEndian>()?;
-        let mut tile_buffer = vec![0u32; (4 * num_tile_records) as usize];
+        check_room(
+            cbcl_path,
+            header_size,
+            bins_end + 4 + 16 * u64::from(num_tile_records) + 1,
+            &format!("{} tile records", num_tile_records),
+        )?;
+        let mut tile_buffer = vec![0u32; 4 * num_tile_records as usize];
         rdr.read_u32_into::<LittleEndian>(&mut tile_buffer)?;
 
         let tile_offsets: Vec<[u32; 4]> = tile_buffer
@@ -76,21 +203,31 @@ impl CBCLHeader {
         let non_pf_clusters_excluded = rdr.read_u8()? != 0;
 
         let tiles = tile_offsets.iter().map(|t| t[0]).collect();
+        let n_clusters = tile_offsets.iter().map(|t| t[1]).collect();
 
-        let start_pos = tile_offsets
-            .iter()
-            .scan(header_size, |pos, &t| {
-                *pos += t[3];
-                Some(*pos - t[3])
-            })
-            .map(|v| v as u64)
-            .collect();
+        let uncompressed_size: Vec<_> = tile_offsets.iter().map(|c| u64::from(c[2])).collect();
+        let compressed_size: Vec<_> = tile_offsets.iter().map(|c| u64::from(c[3])).collect();
 
-        let uncompressed_size: Vec<_> = tile_offsets.iter().map(|c| c[2] as u64).collect();
+        // each block starts where the one before it ends
+        let mut start_pos = Vec::with_capacity(compressed_size.len());
+        let mut pos = u64::from(header_size);
+        for (i, &size) in compressed_size.iter().enumerate() {
+            start_pos.push(pos);
+            pos = pos.checked_add(size).ok_or_else(|| {
+                invalid_data(
+                    cbcl_path,
+                    format!(
+                        "tile record {} (tile {}) ends past the largest possible offset",
+                        i, tile_offsets[i][0]
+                    ),
+                )
+            })?;
+        }
 
         Ok(CBCLHeader {
             cbcl_path: cbcl_path.to_path_buf(),
-            version,
+            storage: StorageHandle(storage),
+            version: CBCL_V1,
             header_size,
             bits_per_basecall,
             bits_per_qscore,
@@ -98,11 +235,94 @@ impl CBCLHeader {
             bins,
             num_tile_records,
             tiles,
+            n_clusters,
             non_pf_clusters_excluded,
             start_pos,
             uncompressed_size,
+            compressed_size,
         })
     }
+
+    /// Drop the records for tiles where `keep` is false, keeping the tile numbers
+    /// and offsets in step so that the remaining tiles can be read as before
+    pub(crate) fn retain_tiles(&mut self, keep: &[bool]) {
+        retain_by(&mut self.tiles, keep);
+        retain_by(&mut self.n_clusters, keep);
+        retain_by(&mut self.start_pos, keep);
+        retain_by(&mut self.uncompressed_size, keep);
+        retain_by(&mut self.compressed_size, keep);
+
+        self.num_tile_records = self.tiles.len() as u32;
+    }
+
+    /// The quality score of each bin, e.g. Q2, Q11, Q25, Q37 on the NovaSeq
+    pub fn bin_qscores(&self) -> Vec<u8> {
+        self.bins.iter().map(|b| b - 33).collect()
+    }
+
+    /// The tile records whose blocks run past the end of a file that is `file_len`
+    /// bytes long
+    fn blocks_past_end(&self, file_len: u64) -> impl Iterator<Item = String> + '_ {
+        self.tiles
+            .iter()
+            .zip(&self.start_pos)
+            .zip(&self.compressed_size)
+            .enumerate()
+            .filter_map(move |(i, ((tile, start_pos), compressed_size))| {
+                let end = start_pos + compressed_size;
+                (end > file_len).then(|| {
+                    format!(
+                        "tile record {} (tile {}) ends at byte {}, past the end of the file ({} bytes)",
+                        i, tile, end, file_len
+                    )
+                })
+            })
+    }
+
+    /// Everything wrong with the tile blocks of a file that is `file_len` bytes
+    /// long: blocks that run past the end of the file, or bytes after the last one
+    pub fn offset_problems(&self, file_len: u64) -> Vec<String> {
+        let mut problems: Vec<_> = self.blocks_past_end(file_len).collect();
+
+        let end = self.header_size as u64 + self.compressed_size.iter().sum::<u64>();
+        if end < file_len {
+            problems.push(format!(
+                "{} bytes after the last tile's block",
+                file_len - end
+            ));
+        }
+
+        problems
+    }
+}
+
+/// Write a version 1 CBCL header, as [`CBCLHeader::read`] reads it. Each of the
+/// `tile_records` is the tile number, the number of clusters, and the uncompressed
+/// and compressed size of the tile's block, and the blocks follow in that order
+pub(crate) fn write_header<W: Write>(
+    out: &mut W,
+    bins: &[u32],
+    tile_records: &[[u32; 4]],
+    non_pf_clusters_excluded: bool,
+) -> io::Result<()> {
+    let header_size = 2 + 4 + 1 + 1 + 4 + 8 * bins.len() + 4 + 16 * tile_records.len() + 1;
+
+    out.write_u16::<LittleEndian>(CBCL_V1)?;
+    out.write_u32::<LittleEndian>(header_size as u32)?;
+    out.write_u8(2)?;
+    out.write_u8(2)?;
+    out.write_u32::<LittleEndian>(bins.len() as u32)?;
+    for (key, &bin) in bins.iter().enumerate() {
+        out.write_u32::<LittleEndian>(key as u32)?;
+        out.write_u32::<LittleEndian>(bin)?;
+    }
+    out.write_u32::<LittleEndian>(tile_records.len() as u32)?;
+    for record in tile_records {
+        for &value in record {
+            out.write_u32::<LittleEndian>(value)?;
+        }
+    }
+    out.write_u8(non_pf_clusters_excluded as u8)
 }
 
 #[cfg(test)]
@@ -116,6 +336,7 @@ mod tests {
         let actual_cbclheader = CBCLHeader::from_path(&cbcl_path).unwrap();
         let expected_cbclheader = CBCLHeader {
             cbcl_path: cbcl_path.to_path_buf(),
+            storage: StorageHandle(local_storage()),
             version: 1,
             header_size: 97,
             bits_per_basecall: 2,
@@ -124,13 +345,154 @@ mod tests {
             bins: vec![35, 44, 58, 70],
             num_tile_records: 3,
             tiles: vec![1101, 1102, 1103],
+            n_clusters: vec![100, 100, 100],
             non_pf_clusters_excluded: false,
             start_pos: vec![97, 170, 243],
             uncompressed_size: vec![50, 50, 50],
+            compressed_size: vec![73, 73, 73],
         };
         assert_eq!(actual_cbclheader, expected_cbclheader)
     }
 
+    #[test]
+    fn offset_problems() {
+        let cbcl_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX")
+            .join("Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl");
+        let header = CBCLHeader::from_path(&cbcl_path).unwrap();
+        assert_eq!(header.bin_qscores(), [2, 11, 25, 37]);
+
+        assert!(header.offset_problems(316).is_empty());
+        assert_eq!(
+            header.offset_problems(300),
+            ["tile record 2 (tile 1103) ends at byte 316, past the end of the file (300 bytes)"]
+        );
+        assert_eq!(
+            header.offset_problems(320),
+            ["4 bytes after the last tile's block"]
+        );
+    }
+
+    #[test]
+    fn retain_tiles() {
+        let cbcl_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX")
+            .join("Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl");
+        let mut header = CBCLHeader::from_path(&cbcl_path).unwrap();
+        header.retain_tiles(&[true, false, true]);
+
+        assert_eq!(header.num_tile_records, 2);
+        assert_eq!(header.tiles, vec![1101, 1103]);
+        assert_eq!(header.start_pos, vec![97, 243]);
+        assert_eq!(header.uncompressed_size, vec![50, 50]);
+        assert_eq!(header.compressed_size, vec![73, 73]);
+    }
+
+    #[test]
+    fn bin_out_of_range() {
+        let output_path = Path::new("test_data/test_output/cbcl_header_bins");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // set the score of the last of the four bins to Q100
+        let mut cbcl_bytes = std::fs::read(
+            "test_data/190414_A00111_0296_AHJCWWDSXX/Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl",
+        )
+        .unwrap();
+        cbcl_bytes[40..44].copy_from_slice(&100u32.to_le_bytes());
+        let cbcl_path = output_path.join("L001_1.cbcl");
+        std::fs::write(&cbcl_path, cbcl_bytes).unwrap();
+
+        let e = CBCLHeader::from_path(&cbcl_path).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("quality bin 3 is Q100, above the highest score"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn write_and_read() {
+        let output_path = Path::new("test_data/test_output/cbcl_header_write");
+        std::fs::create_dir_all(output_path).unwrap();
+        let cbcl_path = output_path.join("L001_1.cbcl");
+
+        let mut bytes = Vec::new();
+        write_header(
+            &mut bytes,
+            &[0, 11, 25, 37],
+            &[[1101, 10, 5, 30], [1102, 8, 4, 20]],
+            true,
+        )
+        .unwrap();
+        let header_size = bytes.len() as u64;
+        bytes.resize(bytes.len() + 50, 0);
+        std::fs::write(&cbcl_path, &bytes).unwrap();
+
+        let header = CBCLHeader::from_path(&cbcl_path).unwrap();
+        assert_eq!(header.header_size as u64, header_size);
+        assert_eq!(header.bin_qscores(), [2, 11, 25, 37]);
+        assert_eq!(header.tiles, [1101, 1102]);
+        assert_eq!(header.n_clusters, [10, 8]);
+        assert_eq!(header.start_pos, [header_size, header_size + 30]);
+        assert!(header.non_pf_clusters_excluded);
+        assert!(header.offset_problems(bytes.len() as u64).is_empty());
+
+        // four bits per quality score isn't something the decoder can unpack
+        bytes[7] = 4;
+        std::fs::write(&cbcl_path, &bytes).unwrap();
+        let e = CBCLHeader::from_path(&cbcl_path).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("2 bits per basecall and 4 per quality score"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn corrupt_offsets() {
+        let output_path = Path::new("test_data/test_output/cbcl_header_offsets");
+        std::fs::create_dir_all(output_path).unwrap();
+        let cbcl_path = output_path.join("L001_1.cbcl");
+        let cbcl_bytes = std::fs::read(
+            "test_data/190414_A00111_0296_AHJCWWDSXX/Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl",
+        )
+        .unwrap();
+        let read_error = |bytes: &[u8]| {
+            std::fs::write(&cbcl_path, bytes).unwrap();
+            CBCLHeader::from_path(&cbcl_path).unwrap_err().to_string()
+        };
+
+        // a file cut short in the last tile's block
+        let e = read_error(&cbcl_bytes[..300]);
+        assert!(
+            e.contains(
+                "tile record 2 (tile 1103) ends at byte 316, past the end of the file (300 bytes)"
+            ),
+            "{}",
+            e
+        );
+
+        // a compressed size near u32::MAX
+        let mut bytes = cbcl_bytes.clone();
+        bytes[60..64].copy_from_slice(&u32::MAX.to_le_bytes());
+        let e = read_error(&bytes);
+        assert!(
+            e.contains("tile record 0 (tile 1101) ends at byte"),
+            "{}",
+            e
+        );
+
+        // far more tile records than the header has room for
+        let mut bytes = cbcl_bytes;
+        bytes[44..48].copy_from_slice(&u32::MAX.to_le_bytes());
+        let e = read_error(&bytes);
+        assert!(
+            e.contains("the header is 97 bytes, too short for 4294967295 tile records"),
+            "{}",
+            e
+        );
+    }
+
     #[test]
     #[should_panic(expected = r#"No such file or directory"#)]
     fn no_file() {
@@ -139,7 +501,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = r#"failed to fill whole buffer"#)]
+    #[should_panic(expected = r#"unsupported CBCL version 0"#)]
     fn bad_file() {
         let cbcl_path = Path::new("test_data/bad_data_8.bin");
         CBCLHeader::from_path(cbcl_path).unwrap();
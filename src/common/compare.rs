@@ -0,0 +1,484 @@
+//! Compare our fastq files with another demultiplexer's output, e.g. bcl2fastq, read
+//! by read. The files are paired up by sample, lane and read (ignoring the `_S1`
+//! sample numbers and `_001` suffixes that bcl2fastq adds), then the reads in each
+//! pair are matched by name and their sequences and qualities compared
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use flate2::read::MultiGzDecoder;
+use rayon::prelude::*;
+use serde::Serialize;
+
+/// The number of differing read names to keep for each file, as examples
+const MAX_EXAMPLES: usize = 5;
+
+/// The number of file pairs compared at once by default. Each pair holds the reads
+/// that one file has reached and the other hasn't, which for files in a different
+/// order can be most of a file
+pub const DEFAULT_PARALLEL_FILES: usize = 4;
+
+/// How the reads in one pair of files compare
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct FileComparison {
+    /// the file name without the extension, sample number or `_001`
+    pub name: String,
+    pub ours: Option<PathBuf>,
+    pub theirs: Option<PathBuf>,
+    pub reads_ours: u64,
+    pub reads_theirs: u64,
+    /// reads that are in both files, with the same sequence and qualities
+    pub identical: u64,
+    pub only_ours: u64,
+    pub only_theirs: u64,
+    pub sequence_differs: u64,
+    /// reads with the same sequence, but different qualities
+    pub quality_differs: u64,
+    /// names of some of the reads that differ or are missing from one side
+    pub examples: Vec<String>,
+}
+
+impl FileComparison {
+    /// True if both files have exactly the same reads
+    pub fn is_concordant(&self) -> bool {
+        self.ours.is_some()
+            && self.theirs.is_some()
+            && self.only_ours == 0
+            && self.only_theirs == 0
+            && self.sequence_differs == 0
+            && self.quality_differs == 0
+    }
+
+    fn add_example(&mut self, read_name: &str) {
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(read_name.to_string());
+        }
+    }
+}
+
+/// The comparison of every fastq file in the two directories
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub files: Vec<FileComparison>,
+}
+
+impl Comparison {
+    pub fn is_concordant(&self) -> bool {
+        self.files.iter().all(|f| f.is_concordant())
+    }
+
+    /// Write the comparison to `path` as JSON
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self).map_err(io::Error::other)
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}",
+            "file",
+            "ours",
+            "theirs",
+            "identical",
+            "only_ours",
+            "only_theirs",
+            "seq_diff",
+            "qual_diff"
+        )?;
+        for fc in &self.files {
+            writeln!(
+                f,
+                "{:<40} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}",
+                fc.name,
+                fc.reads_ours,
+                fc.reads_theirs,
+                fc.identical,
+                fc.only_ours,
+                fc.only_theirs,
+                fc.sequence_differs,
+                fc.quality_differs
+            )?;
+        }
+
+        let n_discordant = self.files.iter().filter(|f| !f.is_concordant()).count();
+        if n_discordant == 0 {
+            write!(f, "all {} files are identical", self.files.len())
+        } else {
+            write!(f, "{} of {} files differ", n_discordant, self.files.len())
+        }
+    }
+}
+
+/// The name a fastq file is paired up by: the file name without the extension,
+/// the `_S<n>` sample number, or the `_001` chunk number, so that bcl2fastq's
+/// `sample_S1_L001_R1_001.fastq.gz` matches our `sample_L001_R1.fastq.gz`. Returns
+/// None if the file isn't a fastq file
+pub fn fastq_key(file_name: &str) -> Option<String> {
+    let stem = file_name
+        .strip_suffix(".fastq.gz")
+        .or_else(|| file_name.strip_suffix(".fastq"))?;
+
+    let mut parts: Vec<_> = stem.split('_').collect();
+    let is_read = |p: &str| {
+        p.len() > 1
+            && (p.starts_with('R') || p.starts_with('I'))
+            && p[1..].bytes().all(|b| b.is_ascii_digit())
+    };
+    let is_lane =
+        |p: &str| p.len() == 4 && p.starts_with('L') && p[1..].bytes().all(|b| b.is_ascii_digit());
+    let is_sample_number =
+        |p: &str| p.len() > 1 && p.starts_with('S') && p[1..].bytes().all(|b| b.is_ascii_digit());
+
+    if parts.len() > 2 && parts[parts.len() - 1] == "001" && is_read(parts[parts.len() - 2]) {
+        parts.pop();
+    }
+    // the sample number comes just before the lane, or the read if there's no lane
+    if let Some(i) = parts.iter().position(|&p| is_lane(p) || is_read(p)) {
+        if i > 1 && is_sample_number(parts[i - 1]) {
+            parts.remove(i - 1);
+        }
+    }
+
+    Some(parts.join("_"))
+}
+
+/// Every fastq file under `path`, by its key
+fn find_fastqs(path: &Path) -> io::Result<HashMap<String, PathBuf>> {
+    let mut fastqs = HashMap::new();
+    let mut dirs = vec![path.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry_path = entry?.path();
+            if entry_path.is_dir() {
+                dirs.push(entry_path);
+            } else if let Some(key) = entry_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(fastq_key)
+            {
+                if let Some(other) = fastqs.insert(key, entry_path.clone()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} and {} have the same sample, lane and read",
+                            other.display(),
+                            entry_path.display()
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(fastqs)
+}
+
+/// A fastq record: the read name (the header up to the first space), sequence, and
+/// qualities
+type Record = (String, Vec<u8>, Vec<u8>);
+
+/// The records of a fastq file, gzipped or not, read one at a time
+struct FastqRecords {
+    path: PathBuf,
+    lines: io::Lines<BufReader<Box<dyn Read + Send>>>,
+}
+
+impl FastqRecords {
+    fn open(path: &Path) -> io::Result<FastqRecords> {
+        let file = File::open(path)?;
+        let reader: Box<dyn Read + Send> = if path.extension().is_some_and(|e| e == "gz") {
+            Box::new(MultiGzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        Ok(FastqRecords {
+            path: path.to_path_buf(),
+            lines: BufReader::new(reader).lines(),
+        })
+    }
+
+    fn invalid(&self, message: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", self.path.display(), message),
+        )
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let header = match self.lines.next() {
+            Some(header) => header?,
+            None => return Ok(None),
+        };
+        let name = header
+            .strip_prefix('@')
+            .ok_or_else(|| self.invalid("expected a header starting with '@'"))?
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        let mut next_line = || match self.lines.next() {
+            Some(line) => line,
+            None => Err(self.invalid("file ends in the middle of a record")),
+        };
+        let seq = next_line()?;
+        next_line()?;
+        let qual = next_line()?;
+
+        Ok(Some((name, seq.into_bytes(), qual.into_bytes())))
+    }
+}
+
+/// Count the records in a fastq file
+fn count_records(path: &Path) -> io::Result<u64> {
+    let mut records = FastqRecords::open(path)?;
+    let mut n = 0;
+    while records.next_record()?.is_some() {
+        n += 1;
+    }
+
+    Ok(n)
+}
+
+/// Compare the reads in two fastq files. The files are read side by side, and
+/// a read is only held until the other file reaches it, so two files in much the
+/// same order are compared in little memory
+pub fn compare_files(ours: &Path, theirs: &Path) -> io::Result<FileComparison> {
+    let mut fc = FileComparison {
+        ours: Some(ours.to_path_buf()),
+        theirs: Some(theirs.to_path_buf()),
+        ..Default::default()
+    };
+
+    let mut our_records = FastqRecords::open(ours)?;
+    let mut their_records = FastqRecords::open(theirs)?;
+    // the reads of each side that the other hasn't reached yet
+    let mut our_pending: HashMap<String, (Vec<u8>, Vec<u8>)> = HashMap::new();
+    let mut their_pending: HashMap<String, (Vec<u8>, Vec<u8>)> = HashMap::new();
+
+    let compare = |fc: &mut FileComparison,
+                   name: &str,
+                   ours: (Vec<u8>, Vec<u8>),
+                   theirs: (Vec<u8>, Vec<u8>)| {
+        if ours.0 != theirs.0 {
+            fc.sequence_differs += 1;
+            fc.add_example(name);
+        } else if ours.1 != theirs.1 {
+            fc.quality_differs += 1;
+            fc.add_example(name);
+        } else {
+            fc.identical += 1;
+        }
+    };
+
+    loop {
+        let ours = our_records.next_record()?;
+        let theirs = their_records.next_record()?;
+        if ours.is_none() && theirs.is_none() {
+            break;
+        }
+
+        if let Some((name, seq, qual)) = ours {
+            fc.reads_ours += 1;
+            match their_pending.remove(&name) {
+                Some(their_read) => compare(&mut fc, &name, (seq, qual), their_read),
+                None => {
+                    our_pending.insert(name, (seq, qual));
+                }
+            }
+        }
+        if let Some((name, seq, qual)) = theirs {
+            fc.reads_theirs += 1;
+            match our_pending.remove(&name) {
+                Some(our_read) => compare(&mut fc, &name, our_read, (seq, qual)),
+                None => {
+                    their_pending.insert(name, (seq, qual));
+                }
+            }
+        }
+    }
+
+    fc.only_ours = our_pending.len() as u64;
+    fc.only_theirs = their_pending.len() as u64;
+    let mut only_ours: Vec<_> = our_pending.into_keys().collect();
+    only_ours.sort_unstable();
+    let mut only_theirs: Vec<_> = their_pending.into_keys().collect();
+    only_theirs.sort_unstable();
+    for name in only_ours.iter().chain(&only_theirs) {
+        fc.add_example(name);
+    }
+
+    Ok(fc)
+}
+
+/// Compare every fastq file under `ours` with the one for the same sample, lane and
+/// read under `theirs`, `parallel_files` pairs at a time. Files that are only on
+/// one side are included, with their reads counted
+pub fn compare_dirs(ours: &Path, theirs: &Path, parallel_files: usize) -> io::Result<Comparison> {
+    let our_fastqs = find_fastqs(ours)?;
+    let mut their_fastqs = find_fastqs(theirs)?;
+
+    let mut pairs: Vec<_> = our_fastqs
+        .into_iter()
+        .map(|(key, path)| {
+            let their_path = their_fastqs.remove(&key);
+            (key, Some(path), their_path)
+        })
+        .collect();
+    pairs.extend(
+        their_fastqs
+            .into_iter()
+            .map(|(key, path)| (key, None, Some(path))),
+    );
+    pairs.sort();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(parallel_files.max(1))
+        .build()
+        .map_err(io::Error::other)?;
+    let files = pool.install(|| {
+        pairs
+            .into_par_iter()
+            .map(|(name, our_path, their_path)| {
+                let fc = match (&our_path, &their_path) {
+                    (Some(o), Some(t)) => compare_files(o, t)?,
+                    (Some(o), None) => FileComparison {
+                        ours: our_path.clone(),
+                        reads_ours: count_records(o)?,
+                        ..Default::default()
+                    },
+                    (None, Some(t)) => FileComparison {
+                        theirs: their_path.clone(),
+                        reads_theirs: count_records(t)?,
+                        ..Default::default()
+                    },
+                    (None, None) => unreachable!(),
+                };
+
+                Ok(FileComparison { name, ..fc })
+            })
+            .collect::<io::Result<Vec<_>>>()
+    })?;
+
+    Ok(Comparison { files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            fastq_key("sample_1_S3_L001_R1_001.fastq.gz").unwrap(),
+            "sample_1_L001_R1"
+        );
+        assert_eq!(
+            fastq_key("sample_1_L001_R1.fastq.gz").unwrap(),
+            "sample_1_L001_R1"
+        );
+        assert_eq!(fastq_key("sample_S12_R2_001.fastq").unwrap(), "sample_R2");
+        assert_eq!(fastq_key("S1_L002_I1.fastq").unwrap(), "S1_L002_I1");
+        assert_eq!(fastq_key("Stats.json"), None);
+    }
+
+    #[test]
+    fn compare() {
+        let root = PathBuf::from("test_data/test_output/compare");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("ours/project")).unwrap();
+        fs::create_dir_all(root.join("theirs/project")).unwrap();
+
+        let record = |name: &str, seq: &str, qual: &str| {
+            format!("@{} 1:N:0:ACGT\n{}\n+\n{}\n", name, seq, qual)
+        };
+        fs::write(
+            root.join("ours/project/s1_L001_R1.fastq"),
+            [
+                record("r1", "ACGT", "FFFF"),
+                record("r2", "ACGT", "FFFF"),
+                record("r3", "ACGT", "FFFF"),
+                record("r4", "ACGT", "FFFF"),
+            ]
+            .concat(),
+        )
+        .unwrap();
+
+        let mut gz = GzEncoder::new(
+            File::create(root.join("theirs/project/s1_S1_L001_R1_001.fastq.gz")).unwrap(),
+            Compression::default(),
+        );
+        gz.write_all(
+            [
+                record("r2", "ACGT", "FFFF"),
+                record("r1", "ACGT", "FFFF"),
+                record("r3", "ACGA", "FFFF"),
+                record("r4", "ACGT", "FF:F"),
+                record("r5", "ACGT", "FFFF"),
+            ]
+            .concat()
+            .as_bytes(),
+        )
+        .unwrap();
+        gz.finish().unwrap();
+
+        fs::write(
+            root.join("theirs/project/s1_S1_L001_I1_001.fastq"),
+            record("r1", "ACGT", "FFFF"),
+        )
+        .unwrap();
+
+        let comparison = compare_dirs(&root.join("ours"), &root.join("theirs"), 2).unwrap();
+        assert!(!comparison.is_concordant());
+        assert_eq!(comparison.files.len(), 2);
+
+        let index = &comparison.files[0];
+        assert_eq!(index.name, "s1_L001_I1");
+        assert_eq!(index.ours, None);
+        assert_eq!(index.reads_theirs, 1);
+
+        let r1 = &comparison.files[1];
+        assert_eq!(r1.name, "s1_L001_R1");
+        assert_eq!(r1.reads_ours, 4);
+        assert_eq!(r1.reads_theirs, 5);
+        assert_eq!(r1.identical, 2);
+        assert_eq!(r1.sequence_differs, 1);
+        assert_eq!(r1.quality_differs, 1);
+        assert_eq!(r1.only_ours, 0);
+        assert_eq!(r1.only_theirs, 1);
+        assert_eq!(r1.examples, vec!["r3", "r4", "r5"]);
+    }
+
+    #[test]
+    fn different_lengths() {
+        let root = PathBuf::from("test_data/test_output/compare_lengths");
+        fs::create_dir_all(&root).unwrap();
+        let fastq = |names: &[&str]| -> String {
+            names
+                .iter()
+                .map(|name| format!("@{}\nACGT\n+\nFFFF\n", name))
+                .collect()
+        };
+        fs::write(root.join("ours.fastq"), fastq(&["r1", "r2", "r3", "r4"])).unwrap();
+        fs::write(root.join("theirs.fastq"), fastq(&["r2", "r1"])).unwrap();
+
+        let fc = compare_files(&root.join("ours.fastq"), &root.join("theirs.fastq")).unwrap();
+        assert_eq!((fc.reads_ours, fc.reads_theirs), (4, 2));
+        assert_eq!(fc.identical, 2);
+        assert_eq!((fc.only_ours, fc.only_theirs), (2, 0));
+        assert_eq!(fc.examples, vec!["r3", "r4"]);
+    }
+}
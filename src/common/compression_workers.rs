@@ -0,0 +1,248 @@
+//! A bounded pool of compression workers, each with its share of the output files.
+//! The demux threads format the reads for a sample into blocks and put them on the
+//! queue of the file's worker, which compresses each block and writes it out. The
+//! queues are bounded, so a sample with a huge file slows down only the threads
+//! that are writing to its worker's files, instead of holding a demux thread while
+//! it compresses
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+use crate::buffer_pool::{buffer_pool, PooledBuffer};
+use crate::write_fastq::OutputOptions;
+
+/// The size of the blocks of formatted reads that are sent to the workers. Each
+/// block is compressed as one gzip member
+pub(crate) const BLOCK_SIZE: usize = 256 * 1024;
+
+type Block = PooledBuffer<'static>;
+
+/// The workers for every output file of a demux
+pub(crate) struct CompressionWorkers {
+    /// the queue of each file's group and its index in the group, by read and
+    /// then sample, like the sample files
+    queues: Vec<Vec<(usize, usize)>>,
+    senders: Vec<SyncSender<(usize, Block)>>,
+    workers: Vec<(String, JoinHandle<io::Result<()>>)>,
+}
+
+/// Compress each block from `blocks` and add it to the end of its file, one of
+/// `paths`. A file with no blocks still gets an empty gzip member (or zstd frame),
+/// so that it's a valid compressed file
+fn compress_files(
+    paths: &[PathBuf],
+    blocks: Receiver<(usize, Block)>,
+    output_options: &OutputOptions,
+) -> io::Result<()> {
+    let sink = output_options.sink();
+    let write_block = |path: &Path, block: &[u8]| -> io::Result<()> {
+        // each block is a chunk of its own, so that it's a whole gzip member
+        let mut chunk = sink.chunk(path, output_options)?;
+        chunk.write_all(block)?;
+        chunk.finish()
+    };
+    let write_block = |path: &Path, block: &[u8]| {
+        write_block(path, block).map_err(|e| {
+            io::Error::new(e.kind(), format!("Error writing {}: {}", path.display(), e))
+        })
+    };
+
+    let mut written = vec![false; paths.len()];
+    for (file_i, block) in blocks {
+        write_block(&paths[file_i], &block)?;
+        written[file_i] = true;
+    }
+    for (path, _) in paths.iter().zip(written).filter(|(_, written)| !written) {
+        write_block(path, &[])?;
+    }
+
+    Ok(())
+}
+
+impl CompressionWorkers {
+    /// Start the workers for the files in `sample_files`:
+    /// `output_options.writer_groups` of them, or one for each thread of the rayon
+    /// pool, that each take every nth file
+    pub(crate) fn start(
+        sample_files: &[Vec<PathBuf>],
+        output_options: &OutputOptions,
+    ) -> io::Result<CompressionWorkers> {
+        let n_files = sample_files.iter().map(Vec::len).sum::<usize>();
+        let n_groups = output_options
+            .writer_groups
+            .unwrap_or_else(rayon::current_num_threads)
+            .min(n_files)
+            .max(1);
+
+        // the files are dealt out to the groups in turn
+        let mut group_paths = vec![Vec::new(); n_groups];
+        let mut queues = Vec::new();
+        let mut file_i = 0;
+        for read_files in sample_files {
+            let mut read_queues = Vec::new();
+            for path in read_files {
+                let group_i = file_i % n_groups;
+                read_queues.push((group_i, group_paths[group_i].len()));
+                group_paths[group_i].push(path.clone());
+                file_i += 1;
+            }
+            queues.push(read_queues);
+        }
+
+        let mut senders = Vec::new();
+        let mut workers = Vec::new();
+        for (group_i, paths) in group_paths.into_iter().enumerate() {
+            // as many blocks waiting for the group as for its files on their own
+            let (sender, blocks) = sync_channel(output_options.queue_depth * paths.len().max(1));
+            let name = match &paths[..] {
+                [path] => path.display().to_string(),
+                _ => format!("group {}", group_i),
+            };
+            let worker_options = output_options.clone();
+
+            let worker = thread::Builder::new()
+                .name(format!("compress {}", name))
+                .spawn(move || compress_files(&paths, blocks, &worker_options))?;
+
+            senders.push(sender);
+            workers.push((name, worker));
+        }
+
+        Ok(CompressionWorkers {
+            queues,
+            senders,
+            workers,
+        })
+    }
+
+    /// A writer for the file for `sample_i` in read `read_i` (from 0)
+    pub(crate) fn writer(&self, read_i: usize, sample_i: usize) -> QueueWriter<'_> {
+        let (group_i, file_i) = self.queues[read_i][sample_i];
+        QueueWriter {
+            queue: &self.senders[group_i],
+            file_i,
+            block: buffer_pool().get(BLOCK_SIZE),
+        }
+    }
+
+    /// Close the queues and wait for the workers to write everything out
+    pub(crate) fn finish(self) -> io::Result<()> {
+        drop(self.senders);
+
+        for (name, worker) in self.workers {
+            worker.join().map_err(|_| {
+                io::Error::other(format!("compression worker for {} panicked", name))
+            })??;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects formatted reads into blocks for a file's worker. Call `flush` to send
+/// the last block
+pub(crate) struct QueueWriter<'a> {
+    queue: &'a SyncSender<(usize, Block)>,
+    file_i: usize,
+    block: Block,
+}
+
+impl QueueWriter<'_> {
+    fn send_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let block = std::mem::replace(&mut self.block, buffer_pool().get(BLOCK_SIZE));
+        self.queue.send((self.file_i, block)).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the compression worker has stopped",
+            )
+        })
+    }
+}
+
+impl Write for QueueWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.block.extend_from_slice(buf);
+        if self.block.len() >= BLOCK_SIZE {
+            self.send_block()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_block()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_files() {
+        let output_path = PathBuf::from("test_data/test_output/compression_workers");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let sample_files = vec![
+            vec![
+                output_path.join("a_R1.fastq"),
+                output_path.join("b_R1.fastq"),
+            ],
+            vec![
+                output_path.join("a_R2.fastq"),
+                output_path.join("b_R2.fastq"),
+            ],
+        ];
+        // a worker for each thread, and two groups for the four files
+        for writer_groups in [None, Some(2)] {
+            let output_options = OutputOptions {
+                compression: None,
+                writer_groups,
+                ..Default::default()
+            };
+            let workers = CompressionWorkers::start(&sample_files, &output_options).unwrap();
+            assert_eq!(
+                workers.workers.len(),
+                writer_groups
+                    .unwrap_or_else(rayon::current_num_threads)
+                    .min(4)
+            );
+
+            // enough to fill the queue a few times over
+            let record = b"@read\nACGT\n+\nFFFF\n";
+            let n_records = output_options.queue_depth * 3 * BLOCK_SIZE / record.len();
+            let mut writer = workers.writer(1, 0);
+            for _ in 0..n_records {
+                writer.write_all(record).unwrap();
+            }
+            writer.flush().unwrap();
+
+            let mut writer = workers.writer(0, 1);
+            writer.write_all(record).unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+
+            workers.finish().unwrap();
+
+            assert_eq!(
+                std::fs::read(&sample_files[1][0]).unwrap(),
+                record.repeat(n_records)
+            );
+            assert_eq!(std::fs::read(&sample_files[0][1]).unwrap(), record);
+            assert!(std::fs::read(&sample_files[0][0]).unwrap().is_empty());
+
+            for path in sample_files.iter().flatten() {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+    }
+}
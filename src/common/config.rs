@@ -0,0 +1,149 @@
+//! Reads command line options from a TOML file. Keys are the long names of the
+//! options (either `read-chunks` or `read_chunks`), and options that are set on
+//! the command line take precedence over the file
+
+use std::{collections::BTreeMap, fs, path::Path, str::FromStr};
+
+use serde::Serialize;
+use toml::Value;
+
+/// Option values read from a config file, keyed by the option's long name
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Config {
+    values: BTreeMap<String, Value>,
+}
+
+/// Convert a single TOML value to the string we'd get on the command line
+fn value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    /// Parse a config from a TOML string. Only top-level keys are used
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let table = match s.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => return Err("expected a table of options".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let mut config = Config::default();
+        for (key, value) in table {
+            if let Value::Table(_) = value {
+                return Err(format!("unexpected table '{}'", key));
+            }
+            config.values.insert(key.replace('_', "-"), value);
+        }
+
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Read a config file
+    pub fn read_path(path: &Path) -> std::io::Result<Config> {
+        let contents = fs::read_to_string(path)?;
+        contents.parse::<Config>().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// The value for an option, as a string
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.values.get(name).and_then(value_string)
+    }
+
+    /// All the values for an option that can be given more than once
+    pub fn get_all(&self, name: &str) -> Vec<String> {
+        match self.values.get(name) {
+            Some(Value::Array(values)) => values.iter().filter_map(value_string).collect(),
+            Some(value) => value_string(value).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// True if a flag is set to `true`
+    pub fn get_flag(&self, name: &str) -> bool {
+        matches!(self.values.get(name), Some(Value::Boolean(true)))
+    }
+
+    /// Names of the options in this config
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+
+    /// Names of the options in this config that aren't in `known`, e.g. misspelled
+    pub fn unknown_keys(&self, known: &[&str]) -> Vec<&str> {
+        self.keys().filter(|key| !known.contains(key)).collect()
+    }
+
+    pub fn set(&mut self, name: &str, value: Value) {
+        self.values.insert(name.to_string(), value);
+    }
+
+    /// Write the config out as TOML
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let contents = toml::to_string(&self.values)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str() {
+        let config = Config::from_str(
+            r#"
+            threads = 8
+            read_chunks = 2
+            seq-only = true
+            log-level = ["common=debug", "common::stats=warn"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.get("threads"), Some("8".to_string()));
+        assert_eq!(config.get("read-chunks"), Some("2".to_string()));
+        assert!(config.get_flag("seq-only"));
+        assert!(!config.get_flag("no-compression"));
+        assert_eq!(
+            config.get_all("log-level"),
+            vec!["common=debug", "common::stats=warn"]
+        );
+        assert_eq!(config.keys().count(), 4);
+        assert_eq!(
+            config.unknown_keys(&["threads", "read-chunks", "log-level"]),
+            vec!["seq-only"]
+        );
+
+        assert!(Config::from_str("threads = ").is_err());
+        assert!(Config::from_str("[demux]\nthreads = 2").is_err());
+    }
+
+    #[test]
+    fn write() {
+        let mut config = Config::default();
+        config.set("threads", Value::Integer(2));
+        config.set("output", Value::String("test_data/test_output".to_string()));
+
+        let path = Path::new("test_data/test_output/config_test.toml");
+        config.write(path).unwrap();
+
+        assert_eq!(Config::read_path(path).unwrap(), config);
+    }
+}
@@ -0,0 +1,285 @@
+//! Delivery manifests: for each `Sample_Project`, the fastq files written for its
+//! samples with their sizes, checksums and read counts, in
+//! `delivery_manifest.json` next to the files, so they can be handed over with
+//! the project's data and checked on the other side
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::novaseq_run::NovaSeqRun;
+use crate::provenance::FileChecksum;
+use crate::sample_data::SampleData;
+use crate::stats::{project_stats, LaneStats, ProjectStats};
+use crate::write_fastq::{make_filename, umi_filename, DimerOutput, OutputOptions};
+use crate::zstd_dictionary::DICTIONARY_NAME;
+
+/// The name of the manifest in each project directory
+pub const MANIFEST_NAME: &str = "delivery_manifest.json";
+
+/// One fastq file in a delivery
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryFile {
+    /// the path from the project directory
+    pub path: String,
+    pub sample_id: String,
+    /// the sample's reference genome, if the samplesheet has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genome: Option<String>,
+    /// the lane the reads are from, or 0 if they are from every lane
+    pub lane: usize,
+    /// 0 for the UMIs
+    pub read_number: usize,
+    pub bytes: u64,
+    pub crc32: String,
+    pub sha256: String,
+    pub reads: u64,
+}
+
+/// The files and stats for one project
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryManifest {
+    pub run_id: String,
+    #[serde(flatten)]
+    pub stats: ProjectStats,
+    pub files: Vec<DeliveryFile>,
+    /// the copy of the zstd dictionary that the files need, in the project directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zstd_dictionary: Option<String>,
+}
+
+impl DeliveryManifest {
+    /// The project directory, where the manifest and the files are
+    fn project_path(&self, output_path: &Path) -> PathBuf {
+        match &self.stats.project {
+            Some(project) => output_path.join(project),
+            None => output_path.to_path_buf(),
+        }
+    }
+}
+
+/// Build the manifest for every project from the fastq files in `output_path`.
+/// `lane_stats` has to be from the demux of `sample_data` that wrote them. The
+/// files are read to checksum them, so this takes a while for a large run
+pub fn delivery_manifests(
+    output_path: &Path,
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    lane_stats: &[LaneStats],
+    output_options: &OutputOptions,
+) -> std::io::Result<Vec<DeliveryManifest>> {
+    let extension = output_options.extension();
+    let mut manifests: Vec<_> = project_stats(lane_stats)
+        .into_iter()
+        .map(|stats| DeliveryManifest {
+            run_id: novaseq_run.run_info.id.clone(),
+            stats,
+            files: Vec::new(),
+            zstd_dictionary: output_options
+                .zstd_dictionary
+                .as_ref()
+                .map(|_| DICTIONARY_NAME.to_string()),
+        })
+        .collect();
+
+    let mut lanes: Vec<_> = sample_data.keys().cloned().collect();
+    lanes.sort_unstable();
+    for lane in lanes {
+        let samples = &sample_data[&lane];
+        // without lane splitting, the stats for every lane go in the same files
+        let stats: Vec<_> = lane_stats
+            .iter()
+            .filter(|ls| lane == 0 || ls.lane_number == lane)
+            .collect();
+        if stats.is_empty() {
+            continue;
+        }
+
+        for (sample_i, (sample_name, sample_project)) in samples
+            .sample_names
+            .iter()
+            .zip(samples.project_names.iter())
+            .enumerate()
+        {
+            let reads: u64 = stats
+                .iter()
+                .map(|ls| {
+                    let sample_stats = &ls.demux_results[sample_i];
+                    let dimers = match output_options.adapter_dimers {
+                        Some(DimerOutput::Separate) => {
+                            sample_stats.number_reads_adapter_dimer.unwrap_or(0)
+                        }
+                        _ => 0,
+                    };
+                    sample_stats.number_reads - sample_stats.number_reads_filtered - dimers
+                })
+                .sum();
+            let manifest = manifests
+                .iter_mut()
+                .find(|m| &m.stats.project == sample_project)
+                .unwrap();
+
+            for read_number in 1..=novaseq_run.read_structure.n_templates() {
+                if !output_options.writes_read(read_number) {
+                    continue;
+                }
+
+                let file_path = make_filename(
+                    output_path,
+                    &output_options.sample_file_name(samples, sample_i),
+                    sample_project,
+                    lane,
+                    read_number,
+                    &extension,
+                )?;
+                manifest.files.push(DeliveryFile {
+                    path: file_path.file_name().unwrap().to_string_lossy().to_string(),
+                    sample_id: sample_name.clone(),
+                    genome: samples.genomes[sample_i].clone(),
+                    lane,
+                    read_number,
+                    bytes: 0,
+                    crc32: String::new(),
+                    sha256: String::new(),
+                    reads,
+                });
+            }
+
+            // the UMIs are named after the first written read's file
+            if output_options.umi.is_some_and(|umi| umi.write) {
+                let read_number = (1..=novaseq_run.read_structure.n_templates())
+                    .find(|&read_number| output_options.writes_read(read_number))
+                    .unwrap();
+                let file_path = make_filename(
+                    output_path,
+                    &output_options.sample_file_name(samples, sample_i),
+                    sample_project,
+                    lane,
+                    read_number,
+                    &extension,
+                )?;
+                manifest.files.push(DeliveryFile {
+                    path: umi_filename(&file_path, read_number)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string(),
+                    sample_id: sample_name.clone(),
+                    genome: samples.genomes[sample_i].clone(),
+                    lane,
+                    read_number: 0,
+                    bytes: 0,
+                    crc32: String::new(),
+                    sha256: String::new(),
+                    reads,
+                });
+            }
+        }
+    }
+
+    // the checksums take the longest, so the files are read in parallel
+    for manifest in manifests.iter_mut() {
+        let project_path = manifest.project_path(output_path);
+        manifest
+            .files
+            .par_iter_mut()
+            .try_for_each(|file| -> std::io::Result<()> {
+                let checksum = FileChecksum::read_path(&project_path.join(&file.path))?;
+                file.bytes = checksum.bytes;
+                file.crc32 = checksum.crc32;
+                file.sha256 = checksum.sha256;
+                Ok(())
+            })?;
+    }
+
+    Ok(manifests)
+}
+
+/// Write each project's manifest to its directory
+pub fn write_delivery_manifests(
+    output_path: &Path,
+    manifests: &[DeliveryManifest],
+) -> std::io::Result<()> {
+    for manifest in manifests {
+        let writer = BufWriter::new(File::create(
+            manifest.project_path(output_path).join(MANIFEST_NAME),
+        )?);
+        serde_json::to_writer_pretty(writer, manifest)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{Progress, ProgressMode};
+    use crate::sample_data::{read_oriented_samplesheet, I5Orientation};
+    use crate::write_fastq::demux_fastqs;
+
+    #[test]
+    fn delivery_manifests() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let output_path = PathBuf::from("test_data/test_output/delivery");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sample_data =
+            read_oriented_samplesheet(run_path.join("SampleSheet.csv"), 1, I5Orientation::Forward)
+                .unwrap();
+        let output_options = OutputOptions {
+            compression: None,
+            ..Default::default()
+        };
+        let lane_stats = demux_fastqs(
+            &novaseq_run,
+            1,
+            &sample_data[&1],
+            &output_path,
+            2,
+            &output_options,
+            &Progress::new(ProgressMode::Hidden, 3),
+        )
+        .unwrap();
+
+        let manifests = super::delivery_manifests(
+            &output_path,
+            &novaseq_run,
+            &sample_data,
+            &lane_stats,
+            &output_options,
+        )
+        .unwrap();
+        assert_eq!(manifests.len(), 1);
+        let manifest = &manifests[0];
+        assert_eq!(manifest.stats.project.as_deref(), Some("project_1"));
+        assert_eq!(manifest.stats.reads, 222);
+        assert_eq!(manifest.files.len(), 2 * manifest.stats.samples);
+        assert_eq!(manifest.zstd_dictionary, None);
+
+        let file = manifest
+            .files
+            .iter()
+            .find(|f| f.path == "8034211776_L001_R2.fastq")
+            .unwrap();
+        assert_eq!(file.reads, 10);
+        assert_eq!(file.genome, None);
+        assert_eq!((file.lane, file.read_number), (1, 2));
+        let checksum =
+            FileChecksum::read_path(&output_path.join("project_1/8034211776_L001_R2.fastq"))
+                .unwrap();
+        assert_eq!(file.bytes, checksum.bytes);
+        assert_eq!(file.crc32, checksum.crc32);
+        assert_eq!(file.sha256, checksum.sha256);
+        assert_eq!(file.sha256.len(), 64);
+
+        write_delivery_manifests(&output_path, &manifests).unwrap();
+        assert!(output_path.join("project_1").join(MANIFEST_NAME).is_file());
+    }
+}
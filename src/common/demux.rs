@@ -0,0 +1,946 @@
+//! The demux pipeline as a library: load a run and its samplesheet, write the
+//! fastq files for each sample, then the stats and reports. This is what the
+//! `demux` subcommand does, without the command line, so that other Rust programs
+//! can demultiplex a run without shelling out to bcl2fastr. A program that wants
+//! the reads themselves can iterate over them with `Demux::reads` instead
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{Error, ErrorKind, Read},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use rayon::ThreadPool;
+
+use crate::delivery::{delivery_manifests, write_delivery_manifests};
+use crate::demux_reads::DemuxReads;
+use crate::novaseq_run::NovaSeqRun;
+use crate::numa::{node_pool, split_lanes, NumaNode};
+use crate::progress::{Progress, ProgressMode};
+use crate::reports::write_reports;
+use crate::sample_data::{
+    merge_lanes, read_oriented_samplesheet, split_lane_zero, I5Orientation, SampleData,
+};
+use crate::shutdown::shutdown_requested;
+use crate::stats::{write_stats_json, LaneStats};
+use crate::write_fastq::{
+    check_ascii_offset, demux_fastqs, is_undetermined_limit, lane_filepaths, merge_reports,
+    OutputOptions,
+};
+
+/// The number of tiles to read at once, the same as the `demux` default
+pub const DEFAULT_READ_CHUNKS: usize = 39;
+
+/// The stats for the lanes that were demultiplexed, and the lanes that failed with
+/// the error that stopped each
+#[derive(Debug, Default)]
+pub struct LaneResults {
+    pub lane_stats: Vec<LaneStats>,
+    pub failed: BTreeMap<usize, String>,
+}
+
+impl LaneResults {
+    fn extend(&mut self, other: LaneResults) {
+        self.lane_stats.extend(other.lane_stats);
+        self.failed.extend(other.failed);
+    }
+
+    /// The stats, or an error listing the lanes that failed
+    pub fn into_result(self) -> std::io::Result<Vec<LaneStats>> {
+        if self.failed.is_empty() {
+            return Ok(self.lane_stats);
+        }
+
+        let failed: Vec<_> = self
+            .failed
+            .iter()
+            .map(|(lane, e)| format!("lane {}: {}", lane, e))
+            .collect();
+        Err(Error::other(failed.join("; ")))
+    }
+}
+
+/// The message of a caught panic
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "panicked".to_string(),
+    }
+}
+
+/// Demultiplex every lane in `sample_data`, stopping between lanes if a shutdown
+/// has been requested. Each lane is demultiplexed on its own, so an error or a
+/// panic in one (e.g. a corrupt CBCL) doesn't stop the others: it is listed with
+/// the failed lanes instead. The undetermined limit stopping a lane is still an
+/// error, as the samplesheet is most likely wrong for the whole run
+pub fn demux_lanes(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    output_path: &Path,
+    read_chunks: usize,
+    output_options: &OutputOptions,
+    progress: &Progress,
+) -> std::io::Result<LaneResults> {
+    let mut lanes: Vec<_> = sample_data.keys().cloned().collect();
+    lanes.sort_unstable();
+
+    demux_lane_group(
+        novaseq_run,
+        sample_data,
+        &lanes,
+        output_path,
+        read_chunks,
+        output_options,
+        progress,
+    )
+}
+
+/// Like `demux_lanes`, but with the lanes shared out between `nodes`. Each node
+/// demultiplexes its lanes on threads pinned to its CPUs, at the same time as the
+/// others. With fewer lanes than nodes (e.g. without lane splitting, which has the
+/// one entry for every lane), the tiles of each lane are shared out instead
+pub fn demux_lanes_numa(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    output_path: &Path,
+    read_chunks: usize,
+    output_options: &OutputOptions,
+    progress: &Progress,
+    nodes: &[NumaNode],
+) -> std::io::Result<LaneResults> {
+    let mut lanes: Vec<_> = sample_data.keys().cloned().collect();
+    lanes.sort_unstable();
+
+    if lanes.len() < nodes.len() {
+        let pools = nodes
+            .iter()
+            .map(node_pool)
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        return demux_lane_tiles(
+            novaseq_run,
+            sample_data,
+            &lanes,
+            output_path,
+            read_chunks,
+            output_options,
+            progress,
+            &pools,
+        );
+    }
+
+    let groups = split_lanes(&lanes, nodes.len());
+    let pools = nodes[..groups.len()]
+        .iter()
+        .map(node_pool)
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = groups
+            .iter()
+            .zip(&pools)
+            .map(|(group, pool)| {
+                scope.spawn(move || {
+                    pool.install(|| {
+                        demux_lane_group(
+                            novaseq_run,
+                            sample_data,
+                            group,
+                            output_path,
+                            read_chunks,
+                            output_options,
+                            progress,
+                        )
+                    })
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut lane_results = LaneResults::default();
+    for result in results {
+        lane_results.extend(result?);
+    }
+    lane_results.lane_stats.sort_by_key(|ls| ls.lane_number);
+
+    Ok(lane_results)
+}
+
+/// The size of the blocks that the files of a lane's parts are copied in
+const GATHER_BLOCK: usize = 16 * 1024 * 1024;
+
+/// Demux `lanes` one after another, with the tiles of each shared out between
+/// `pools`. Each pool writes its part of the lane to a folder of its own, and then
+/// the parts are added to the lane's files in order, so the reads are in the same
+/// order as when the lane is demultiplexed in one go
+#[allow(clippy::too_many_arguments)]
+fn demux_lane_tiles(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    lanes: &[usize],
+    output_path: &Path,
+    read_chunks: usize,
+    output_options: &OutputOptions,
+    progress: &Progress,
+    pools: &[ThreadPool],
+) -> std::io::Result<LaneResults> {
+    // the parts are written as local files, whatever the lane's files go to
+    let part_options = OutputOptions {
+        upload: None,
+        sink: None,
+        append: false,
+        ..output_options.clone()
+    };
+    let mut lane_results = LaneResults::default();
+
+    for &lane in lanes {
+        if shutdown_requested() {
+            break;
+        }
+
+        let parts = novaseq_run.split_lane_tiles(lane, pools.len());
+        let part_tiles: Vec<_> = parts.iter().map(|p| p.tile_count(lane)).collect();
+        info!(
+            "tiles of lane {} for each NUMA node: {:?}",
+            lane, part_tiles
+        );
+        let part_paths: Vec<_> = (1..=parts.len())
+            .map(|i| output_path.join(format!(".numa_L{:03}_{}", lane, i)))
+            .collect();
+
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = parts
+                .iter()
+                .zip(&part_paths)
+                .zip(pools)
+                .map(|((part_run, part_path), pool)| {
+                    let part_options = &part_options;
+                    scope.spawn(move || {
+                        let demux = || {
+                            std::fs::create_dir_all(part_path)?;
+                            pool.install(|| {
+                                demux_fastqs(
+                                    part_run,
+                                    lane,
+                                    &sample_data[&lane],
+                                    part_path,
+                                    read_chunks,
+                                    part_options,
+                                    progress,
+                                )
+                            })
+                        };
+                        catch_unwind(AssertUnwindSafe(demux))
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let mut lane_stats: Vec<LaneStats> = Vec::new();
+        let mut failed = None;
+        for result in results {
+            match result {
+                Ok(Ok(part_stats)) => {
+                    for stats in part_stats {
+                        match lane_stats
+                            .iter_mut()
+                            .find(|ls| ls.lane_number == stats.lane_number)
+                        {
+                            Some(ls) => ls.merge(&stats).map_err(Error::other)?,
+                            None => lane_stats.push(stats),
+                        }
+                    }
+                }
+                Ok(Err(e)) if is_undetermined_limit(&e) => {
+                    remove_part_paths(&part_paths);
+                    return Err(e);
+                }
+                Ok(Err(e)) => failed = Some(e.to_string()),
+                Err(panic) => failed = Some(panic_message(panic.as_ref())),
+            }
+        }
+
+        if failed.is_none() {
+            let gathered = gather_lane_parts(
+                &parts,
+                &part_paths,
+                sample_data,
+                lane,
+                novaseq_run,
+                output_path,
+                output_options,
+                &part_options,
+            );
+            failed = gathered.err().map(|e| e.to_string());
+        }
+        remove_part_paths(&part_paths);
+
+        match failed {
+            None => lane_results.lane_stats.extend(lane_stats),
+            Some(e) => {
+                lane_results.failed.insert(lane, e);
+                remove_lane_files(novaseq_run, sample_data, lane, output_path, output_options);
+            }
+        }
+    }
+    lane_results.lane_stats.sort_by_key(|ls| ls.lane_number);
+
+    Ok(lane_results)
+}
+
+/// Add the files of each part of a lane to the lane's files, in order, and add up
+/// their barcode reports into the lane's
+#[allow(clippy::too_many_arguments)]
+fn gather_lane_parts(
+    parts: &[NovaSeqRun],
+    part_paths: &[PathBuf],
+    sample_data: &SampleData,
+    lane: usize,
+    novaseq_run: &NovaSeqRun,
+    output_path: &Path,
+    output_options: &OutputOptions,
+    part_options: &OutputOptions,
+) -> std::io::Result<()> {
+    let sink = output_options.sink();
+    let samples = &sample_data[&lane];
+    let lane_files = lane_filepaths(novaseq_run, samples, lane, output_path, output_options)?;
+    if !output_options.append {
+        for path in lane_files.iter().flatten() {
+            sink.clear(path)?;
+        }
+    }
+
+    let mut block = vec![0; GATHER_BLOCK];
+    for (part_run, part_path) in parts.iter().zip(part_paths) {
+        let part_files = lane_filepaths(part_run, samples, lane, part_path, part_options)?;
+        for (part_file, lane_file) in part_files.iter().flatten().zip(lane_files.iter().flatten()) {
+            let mut part_file = match File::open(part_file) {
+                Ok(part_file) => part_file,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            // an empty part still creates the lane's file
+            sink.append_encoded(lane_file, &[])?;
+            loop {
+                let n = part_file.read(&mut block)?;
+                if n == 0 {
+                    break;
+                }
+                sink.append_encoded(lane_file, &block[..n])?;
+            }
+        }
+    }
+
+    merge_reports(part_paths, output_path, lane, output_options)
+}
+
+/// Remove the folders that the parts of a lane were written to
+fn remove_part_paths(part_paths: &[PathBuf]) {
+    for part_path in part_paths {
+        if let Err(e) = std::fs::remove_dir_all(part_path) {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("Error removing {}: {}", part_path.display(), e);
+            }
+        }
+    }
+}
+
+/// Remove the files of a lane that failed, so that its partial reads aren't taken
+/// for all of them. With `append`, the reads of the earlier runs go as well
+fn remove_lane_files(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    lane: usize,
+    output_path: &Path,
+    output_options: &OutputOptions,
+) {
+    let sink = output_options.sink();
+    let removed = lane_filepaths(
+        novaseq_run,
+        &sample_data[&lane],
+        lane,
+        output_path,
+        output_options,
+    )
+    .and_then(|sample_files| {
+        for path in sample_files.iter().flatten() {
+            sink.clear(path)?;
+        }
+        Ok(())
+    });
+
+    if let Err(e) = removed {
+        warn!("Error removing the files of lane {}: {}", lane, e);
+    }
+}
+
+/// Demux `lanes`, one after another
+fn demux_lane_group(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    lanes: &[usize],
+    output_path: &Path,
+    read_chunks: usize,
+    output_options: &OutputOptions,
+    progress: &Progress,
+) -> std::io::Result<LaneResults> {
+    let mut lane_results = LaneResults::default();
+
+    for &lane in lanes {
+        if shutdown_requested() {
+            break;
+        }
+
+        let demux = || {
+            demux_fastqs(
+                novaseq_run,
+                lane,
+                &sample_data[&lane],
+                output_path,
+                read_chunks,
+                output_options,
+                progress,
+            )
+        };
+        match catch_unwind(AssertUnwindSafe(demux)) {
+            Ok(Ok(lane_stats)) => lane_results.lane_stats.extend(lane_stats),
+            Ok(Err(e)) if is_undetermined_limit(&e) => return Err(e),
+            Ok(Err(e)) => {
+                lane_results.failed.insert(lane, e.to_string());
+            }
+            Err(panic) => {
+                lane_results
+                    .failed
+                    .insert(lane, panic_message(panic.as_ref()));
+            }
+        }
+
+        if lane_results.failed.contains_key(&lane) {
+            remove_lane_files(novaseq_run, sample_data, lane, output_path, output_options);
+        }
+    }
+
+    lane_results.lane_stats.sort_by_key(|ls| ls.lane_number);
+
+    Ok(lane_results)
+}
+
+/// Sets up a demux. Everything but the paths has the same default as the `demux`
+/// subcommand
+pub struct DemuxBuilder {
+    run_path: PathBuf,
+    samplesheet: PathBuf,
+    output_path: PathBuf,
+    mismatch: usize,
+    i5_orientation: Option<I5Orientation>,
+    lanes: Option<BTreeSet<usize>>,
+    lane_splitting: bool,
+    read_chunks: usize,
+    output_options: OutputOptions,
+    reports: bool,
+    delivery_manifests: bool,
+}
+
+impl DemuxBuilder {
+    /// Demultiplex the run in `run_path` using `samplesheet`, writing to
+    /// `output_path`, which must already exist
+    pub fn new<P, S, O>(run_path: P, samplesheet: S, output_path: O) -> DemuxBuilder
+    where
+        P: Into<PathBuf>,
+        S: Into<PathBuf>,
+        O: Into<PathBuf>,
+    {
+        DemuxBuilder {
+            run_path: run_path.into(),
+            samplesheet: samplesheet.into(),
+            output_path: output_path.into(),
+            mismatch: 1,
+            i5_orientation: None,
+            lanes: None,
+            lane_splitting: true,
+            read_chunks: DEFAULT_READ_CHUNKS,
+            output_options: OutputOptions::default(),
+            reports: true,
+            delivery_manifests: false,
+        }
+    }
+
+    /// Demultiplex the run in `run_path` using `samplesheet` without writing any
+    /// files, to iterate over the reads with `Demux::reads`
+    pub fn for_reads<P, S>(run_path: P, samplesheet: S) -> DemuxBuilder
+    where
+        P: Into<PathBuf>,
+        S: Into<PathBuf>,
+    {
+        DemuxBuilder::new(run_path, samplesheet, PathBuf::new())
+    }
+
+    /// Allow up to this many mismatches in each index
+    pub fn mismatch(mut self, mismatch: usize) -> DemuxBuilder {
+        self.mismatch = mismatch;
+        self
+    }
+
+    /// Set the orientation of i5, instead of working it out from RunParameters.xml
+    pub fn i5_orientation(mut self, i5_orientation: I5Orientation) -> DemuxBuilder {
+        self.i5_orientation = Some(i5_orientation);
+        self
+    }
+
+    /// Only demultiplex these lanes
+    pub fn lanes(mut self, lanes: BTreeSet<usize>) -> DemuxBuilder {
+        self.lanes = Some(lanes);
+        self
+    }
+
+    /// Write separate fastq files for each lane (the default), or put the reads
+    /// from every lane together. Without lane splitting, every lane in the
+    /// samplesheet has to have the same samples
+    pub fn lane_splitting(mut self, lane_splitting: bool) -> DemuxBuilder {
+        self.lane_splitting = lane_splitting;
+        self
+    }
+
+    /// The number of tiles to read at once. More tiles use more memory
+    pub fn read_chunks(mut self, read_chunks: usize) -> DemuxBuilder {
+        self.read_chunks = read_chunks;
+        self
+    }
+
+    /// How the fastq files are written, e.g. their compression
+    pub fn output_options(mut self, output_options: OutputOptions) -> DemuxBuilder {
+        self.output_options = output_options;
+        self
+    }
+
+    /// Write the HTML reports as well as Stats.json
+    pub fn reports(mut self, reports: bool) -> DemuxBuilder {
+        self.reports = reports;
+        self
+    }
+
+    /// Write a delivery manifest in each project's directory, with the size, CRC32
+    /// and read count of each fastq file. Not written when the fastq files go to an
+    /// upload or another sink instead of the output folder
+    pub fn delivery_manifests(mut self, delivery_manifests: bool) -> DemuxBuilder {
+        self.delivery_manifests = delivery_manifests;
+        self
+    }
+
+    fn has_output_path(&self) -> bool {
+        !self.output_path.as_os_str().is_empty()
+    }
+
+    /// Load the run and the samplesheet, ready to demultiplex
+    pub fn build(self) -> std::io::Result<Demux> {
+        if self.has_output_path() && !self.output_path.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Could not find output path {}", self.output_path.display()),
+            ));
+        }
+
+        let mut novaseq_run = NovaSeqRun::read_path(self.run_path, false)?;
+        check_ascii_offset(&novaseq_run, self.output_options.ascii_offset)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        if self.output_options.non_pf.is_some() {
+            novaseq_run.include_non_pf()?;
+        }
+
+        let i5_orientation = self.i5_orientation.unwrap_or_else(|| {
+            novaseq_run
+                .run_parameters
+                .as_ref()
+                .map_or(I5Orientation::Forward, |rp| rp.i5_orientation())
+        });
+        let mut sample_data =
+            read_oriented_samplesheet(self.samplesheet, self.mismatch, i5_orientation)?;
+
+        if let Some(lanes) = &self.lanes {
+            novaseq_run.retain_lanes(lanes);
+            // lane 0 means the samplesheet has no lanes, so the samples are in every lane
+            sample_data.retain(|lane, _| *lane == 0 || lanes.contains(lane));
+            if sample_data.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "No samples in the selected lanes",
+                ));
+            }
+        }
+
+        if self.lane_splitting {
+            // a samplesheet without lanes has the same samples in every lane
+            let run_lanes: Vec<_> = (1..=novaseq_run.run_info.flowcell_layout.lane_count)
+                .filter(|&lane| novaseq_run.tile_count(lane) > 0)
+                .collect();
+            split_lane_zero(&mut sample_data, &run_lanes);
+        } else if let Some(sheet_lanes) =
+            merge_lanes(&mut sample_data).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+        {
+            novaseq_run.retain_lanes(&sheet_lanes);
+        }
+
+        Ok(Demux {
+            novaseq_run,
+            sample_data,
+            output_path: self.output_path,
+            read_chunks: self.read_chunks,
+            output_options: self.output_options,
+            reports: self.reports,
+            delivery_manifests: self.delivery_manifests,
+        })
+    }
+}
+
+/// A run and samplesheet that are loaded and ready to demultiplex
+pub struct Demux {
+    pub novaseq_run: NovaSeqRun,
+    pub sample_data: SampleData,
+    output_path: PathBuf,
+    read_chunks: usize,
+    output_options: OutputOptions,
+    reports: bool,
+    delivery_manifests: bool,
+}
+
+impl Demux {
+    /// The number of tiles that will be demultiplexed
+    pub fn total_tiles(&self) -> usize {
+        self.sample_data
+            .keys()
+            .map(|&lane| self.novaseq_run.tile_count(lane))
+            .sum()
+    }
+
+    /// The reads assigned to each sample, decoded a tile at a time as they are
+    /// consumed. Nothing is written, so the output path isn't needed
+    pub fn reads(&self) -> DemuxReads<'_> {
+        DemuxReads::new(&self.novaseq_run, &self.sample_data, &self.output_options)
+    }
+
+    /// Write the fastq files, Stats.json and the reports, and return the stats. If
+    /// the output options have an upload, everything ends up there
+    pub fn run(&self) -> std::io::Result<Vec<LaneStats>> {
+        let progress = Progress::new(ProgressMode::Hidden, self.total_tiles() as u64);
+        self.run_with_progress(&progress)
+    }
+
+    /// Like `run`, but reports each tile to `progress` as it is done
+    pub fn run_with_progress(&self, progress: &Progress) -> std::io::Result<Vec<LaneStats>> {
+        if self.output_path.as_os_str().is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "This demux was set up for reads only, with no output path",
+            ));
+        }
+
+        let lane_stats = demux_lanes(
+            &self.novaseq_run,
+            &self.sample_data,
+            &self.output_path,
+            self.read_chunks,
+            &self.output_options,
+            progress,
+        )?
+        .into_result();
+        progress.finish();
+        let lane_stats = lane_stats?;
+
+        write_stats_json(&self.output_path, &self.novaseq_run, &lane_stats)?;
+        if self.reports {
+            write_reports(&self.output_path, &self.novaseq_run, &lane_stats)?;
+        }
+        // the manifests need the fastq files in the output folder, to checksum them
+        self.output_options.sink().finish()?;
+        if self.delivery_manifests && self.output_options.in_output_folder() {
+            let manifests = delivery_manifests(
+                &self.output_path,
+                &self.novaseq_run,
+                &self.sample_data,
+                &lane_stats,
+                &self.output_options,
+            )?;
+            write_delivery_manifests(&self.output_path, &manifests)?;
+        }
+
+        if let Some(upload) = &self.output_options.upload {
+            upload.put_folder(&self.output_path)?;
+        }
+
+        Ok(lane_stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{OutputHandle, OutputStorage};
+    use std::sync::{Arc, Mutex};
+
+    /// The files that were appended to and not discarded. Appending to the files
+    /// of `failing` fails with `kind`
+    struct FailingStorage {
+        failing: &'static str,
+        kind: ErrorKind,
+        files: Mutex<BTreeSet<PathBuf>>,
+    }
+
+    impl OutputStorage for FailingStorage {
+        fn append(&self, path: &Path, _block: &[u8]) -> std::io::Result<()> {
+            if path.to_string_lossy().contains(self.failing) {
+                return Err(Error::new(self.kind, "no space left"));
+            }
+            self.files.lock().unwrap().insert(path.to_path_buf());
+            Ok(())
+        }
+
+        fn put(&self, _path: &Path, _contents: Vec<u8>) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn finish(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn discard(&self, path: &Path) -> std::io::Result<bool> {
+            Ok(self.files.lock().unwrap().remove(path))
+        }
+    }
+
+    #[test]
+    fn failed_lane_files() {
+        let output_path = PathBuf::from("test_data/test_output/failed_lane_files");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let storage = Arc::new(FailingStorage {
+            failing: "iseq_2",
+            kind: ErrorKind::Other,
+            files: Mutex::new(BTreeSet::new()),
+        });
+        let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let result = DemuxBuilder::new(&run_path, run_path.join("SampleSheet.csv"), &output_path)
+            .output_options(OutputOptions {
+                upload: Some(OutputHandle(storage.clone())),
+                ..Default::default()
+            })
+            .reports(false)
+            .build()
+            .unwrap()
+            .run();
+
+        let e = result.unwrap_err();
+        assert!(e.to_string().contains("no space left"), "{}", e);
+        // the other samples' reads were written, then removed with the lane
+        assert!(storage.files.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_data_fails_the_lane() {
+        let output_path = PathBuf::from("test_data/test_output/invalid_data_lane");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let demux = DemuxBuilder::new(&run_path, run_path.join("SampleSheet.csv"), &output_path)
+            .output_options(OutputOptions {
+                upload: Some(OutputHandle(Arc::new(FailingStorage {
+                    failing: "iseq_2",
+                    kind: ErrorKind::InvalidData,
+                    files: Mutex::new(BTreeSet::new()),
+                }))),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        // only the undetermined limit stops every lane, other errors fail their own
+        let lane_results = demux_lanes(
+            &demux.novaseq_run,
+            &demux.sample_data,
+            &output_path,
+            DEFAULT_READ_CHUNKS,
+            &demux.output_options,
+            &Progress::new(ProgressMode::Hidden, 2),
+        )
+        .unwrap();
+        assert!(lane_results.lane_stats.is_empty());
+        assert!(lane_results.failed[&1].contains("no space left"));
+    }
+
+    #[test]
+    fn demux() {
+        let output_path = PathBuf::from("test_data/test_output/library_demux");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let demux = DemuxBuilder::new(&run_path, run_path.join("SampleSheet.csv"), &output_path)
+            .mismatch(0)
+            .output_options(OutputOptions {
+                compression: None,
+                ..Default::default()
+            })
+            .reports(false)
+            .build()
+            .unwrap();
+
+        assert_eq!(demux.total_tiles(), 2);
+
+        let lane_stats = demux.run().unwrap();
+        assert_eq!(lane_stats.len(), 1);
+        assert_eq!(lane_stats[0].total_clusters_pf, 44);
+        assert!(output_path.join("Stats/Stats.json").is_file());
+        assert!(output_path
+            .join("iseq_project/iseq_1_L001_R1.fastq")
+            .is_file());
+        assert!(!output_path.join("Reports").exists());
+    }
+
+    #[test]
+    fn numa() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sample_data =
+            read_oriented_samplesheet(run_path.join("SampleSheet.csv"), 1, I5Orientation::Forward)
+                .unwrap();
+        let output_options = OutputOptions {
+            compression: None,
+            ..Default::default()
+        };
+        let progress = Progress::new(ProgressMode::Hidden, 3);
+
+        let demux = |output_path: &Path, nodes: Option<&[NumaNode]>| {
+            let _ = std::fs::remove_dir_all(output_path);
+            std::fs::create_dir_all(output_path).unwrap();
+
+            match nodes {
+                Some(nodes) => demux_lanes_numa(
+                    &novaseq_run,
+                    &sample_data,
+                    output_path,
+                    2,
+                    &output_options,
+                    &progress,
+                    nodes,
+                ),
+                None => demux_lanes(
+                    &novaseq_run,
+                    &sample_data,
+                    output_path,
+                    2,
+                    &output_options,
+                    &progress,
+                ),
+            }
+            .unwrap()
+            .into_result()
+            .unwrap()
+        };
+
+        // every machine has a CPU 0, so two nodes with just that one will do
+        let nodes: Vec<_> = (0..2).map(|id| NumaNode { id, cpus: vec![0] }).collect();
+        let numa_path = PathBuf::from("test_data/test_output/numa");
+        let plain_path = PathBuf::from("test_data/test_output/no_numa");
+        let numa_stats = demux(&numa_path, Some(&nodes));
+        let plain_stats = demux(&plain_path, None);
+
+        assert_eq!(numa_stats.len(), 1);
+        assert_eq!(
+            numa_stats[0].total_clusters_pf,
+            plain_stats[0].total_clusters_pf
+        );
+        assert_eq!(
+            numa_stats[0].undetermined.number_reads,
+            plain_stats[0].undetermined.number_reads
+        );
+        assert_eq!(numa_stats[0].tile_stats, plain_stats[0].tile_stats);
+        // the one lane's tiles were split between the nodes, then put back together
+        for file in [
+            "project_1/8034211010_L001_R1.fastq",
+            "project_1/8034211010_L001_R2.fastq",
+            "barcode_L001_report.txt",
+        ] {
+            assert_eq!(
+                std::fs::read(numa_path.join(file)).unwrap(),
+                std::fs::read(plain_path.join(file)).unwrap()
+            );
+        }
+        assert!(!numa_path.join(".numa_L001_1").exists());
+    }
+
+    #[test]
+    fn reads() {
+        let output_path = PathBuf::from("test_data/test_output/library_reads");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let lane_stats =
+            DemuxBuilder::new(&run_path, run_path.join("SampleSheet.csv"), &output_path)
+                .reports(false)
+                .build()
+                .unwrap()
+                .run()
+                .unwrap();
+
+        let demux = DemuxBuilder::for_reads(&run_path, run_path.join("SampleSheet.csv"))
+            .build()
+            .unwrap();
+        let reads = demux.reads().collect::<std::io::Result<Vec<_>>>().unwrap();
+
+        // the same reads as the fastq files
+        for sample_stats in lane_stats[0].demux_results.iter() {
+            assert_eq!(
+                reads
+                    .iter()
+                    .filter(|r| r.sample_id == sample_stats.sample_id)
+                    .count() as u64,
+                sample_stats.number_reads
+            );
+        }
+        assert_eq!(
+            reads.len() as u64,
+            lane_stats[0].number_reads() - lane_stats[0].undetermined.number_reads
+        );
+
+        assert_eq!(demux.run().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn bad_samplesheet() {
+        let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let result = DemuxBuilder::new(
+            &run_path,
+            "test_data/sample_data/sample_collision.csv",
+            "test_data",
+        )
+        .build();
+
+        let e = result.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.to_string().contains("using the same indices"), "{}", e);
+    }
+
+    #[test]
+    fn no_samples_in_lanes() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let result = DemuxBuilder::new(&run_path, run_path.join("SampleSheet.csv"), "test_data")
+            .lanes([2].iter().cloned().collect())
+            .build();
+
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "No samples in the selected lanes"
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Demultiplexed reads as an iterator, for programs that want to use the reads
+//! themselves (e.g. to feed an aligner) instead of reading them back from fastq
+//! files. The tiles are decoded one at a time as the reads are consumed, so only
+//! one tile's reads are held in memory
+
+use std::{collections::VecDeque, io};
+
+use ndarray::Axis;
+
+use crate::barcode_matcher::{BarcodeMatcher, HammingMatcher};
+use crate::novaseq_run::NovaSeqRun;
+use crate::sample_data::SampleData;
+use crate::write_fastq::{
+    assign_reads, extract_tile, filter_flags, index_buffer, index_slices, pf_locs,
+    poly_g_trimmed_len, truncate_index_slices, OutputOptions,
+};
+
+/// One template read of a cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadSegment {
+    /// the template read, numbered from 1 as in R1
+    pub read_number: usize,
+    pub seq: Vec<u8>,
+    /// quality scores as phred+33
+    pub qual: Vec<u8>,
+}
+
+/// A cluster that was assigned to a sample, with the template reads that the
+/// output options write
+#[derive(Debug, Clone, PartialEq)]
+pub struct DemuxRead {
+    pub sample_id: String,
+    pub lane: usize,
+    pub tile: u32,
+    /// the x and y position of the cluster, as in the read name
+    pub loc: [u32; 2],
+    /// the bases of each index read
+    pub indices: Vec<Vec<u8>>,
+    /// the number of mismatches between the indices and the sample's
+    pub mismatches: usize,
+    pub segments: Vec<ReadSegment>,
+}
+
+/// Yields the assigned reads of every lane in the samplesheet, a tile at a time.
+/// Undetermined reads are skipped, as are reads that fail the read filter. A tile
+/// that can't be read gives an error, and the next call goes on to the next tile
+pub struct DemuxReads<'a> {
+    novaseq_run: &'a NovaSeqRun,
+    sample_data: &'a SampleData,
+    output_options: &'a OutputOptions,
+    /// the lane surface and position of each tile still to read
+    tiles: VecDeque<([usize; 2], usize)>,
+    reads: std::vec::IntoIter<DemuxRead>,
+}
+
+impl<'a> DemuxReads<'a> {
+    /// Read the tiles of the lanes in `sample_data`, in lane, surface and tile order.
+    /// Lane 0 (a samplesheet without lanes) has the samples for every lane
+    pub fn new(
+        novaseq_run: &'a NovaSeqRun,
+        sample_data: &'a SampleData,
+        output_options: &'a OutputOptions,
+    ) -> DemuxReads<'a> {
+        let mut keys: Vec<_> = novaseq_run
+            .tile_ids
+            .keys()
+            .filter(|[lane, _]| sample_data.contains_key(lane) || sample_data.contains_key(&0))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+
+        let tiles = keys
+            .into_iter()
+            .flat_map(|key| (0..novaseq_run.tile_ids[&key].len()).map(move |tile_i| (key, tile_i)))
+            .collect();
+
+        DemuxReads {
+            novaseq_run,
+            sample_data,
+            output_options,
+            tiles,
+            reads: Vec::new().into_iter(),
+        }
+    }
+
+    /// The number of tiles that haven't been read yet
+    pub fn tiles_left(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Decode one tile and keep its assigned reads
+    fn read_tile(&self, [lane, surface]: [usize; 2], tile_i: usize) -> io::Result<Vec<DemuxRead>> {
+        let novaseq_run = self.novaseq_run;
+        let output_options = self.output_options;
+        let samples = self
+            .sample_data
+            .get(&lane)
+            .or_else(|| self.sample_data.get(&0))
+            .unwrap();
+        let matcher = output_options
+            .matcher
+            .as_deref()
+            .unwrap_or(&HammingMatcher as &dyn BarcodeMatcher);
+
+        let tile = novaseq_run.tile_ids[&[lane, surface]][tile_i];
+        let filter = &novaseq_run.filters[&[lane, surface]][tile_i];
+        let pf_filter = &novaseq_run.pf_filters[&[lane, surface]][tile_i];
+        let n_pf = novaseq_run.n_pfs[&[lane, surface]][tile_i];
+        // the clusters that failed filter aren't yielded, even if they were decoded
+        let pf_flags = novaseq_run
+            .pf_flags
+            .get(&[lane, surface])
+            .map(|filters| filter_flags(&filters[tile_i], n_pf));
+
+        let idx_slices = index_slices(novaseq_run);
+        let mut index_array = index_buffer(novaseq_run, n_pf);
+        for (idx_h, &[i0, i1]) in novaseq_run.index_headers[&[lane, surface]]
+            .iter()
+            .zip(idx_slices.iter())
+        {
+            index_array
+                .slice_mut(ndarray::s![i0..i1, .., ..])
+                .assign(&extract_tile(idx_h, filter, pf_filter, n_pf, tile_i)?);
+        }
+        // the index reads are decoded whole, but only matched as far as the samples'
+        let mut match_slices = idx_slices.clone();
+        if output_options.truncate_indices {
+            truncate_index_slices(&mut match_slices, samples);
+        }
+        let assignments = assign_reads(samples, matcher, n_pf, &index_array.view(), &match_slices);
+
+        // skipped reads aren't decoded
+        let read_arrays = novaseq_run.read_headers[&[lane, surface]]
+            .iter()
+            .enumerate()
+            .filter(|(k, _)| output_options.writes_read(k + 1))
+            .map(|(k, read_h)| {
+                Ok((
+                    k + 1,
+                    extract_tile(read_h, filter, pf_filter, n_pf, tile_i)?,
+                ))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut locs_vec = Vec::with_capacity(n_pf);
+        pf_locs(
+            novaseq_run.locs_for([lane, surface], tile_i),
+            filter,
+            &mut locs_vec,
+        );
+
+        let mut reads = Vec::new();
+        for (j, (assignment, loc)) in assignments.iter().zip(&locs_vec).enumerate() {
+            let (sample_i, mismatches) = match assignment {
+                Some(assignment) => *assignment,
+                None => continue,
+            };
+            if pf_flags.as_ref().is_some_and(|pf| !pf[j]) {
+                continue;
+            }
+
+            let mut segments = Vec::with_capacity(read_arrays.len());
+            for (read_number, read_array) in read_arrays.iter() {
+                let bq_row = read_array.index_axis(Axis(1), j);
+                segments.push(ReadSegment {
+                    read_number: *read_number,
+                    seq: bq_row.slice(ndarray::s![.., 0]).to_vec(),
+                    qual: bq_row.slice(ndarray::s![.., 1]).to_vec(),
+                });
+            }
+
+            // the filter sees the reads before they are trimmed, as in the fastq files
+            if let Some(read_filter) = &output_options.read_filter {
+                if !segments.iter().all(|s| read_filter.passes(&s.seq, &s.qual)) {
+                    continue;
+                }
+            }
+            for segment in segments.iter_mut() {
+                let read_len = poly_g_trimmed_len(&segment.seq, output_options.trim_poly_g);
+                segment.seq.truncate(read_len);
+                segment.qual.truncate(read_len);
+            }
+
+            reads.push(DemuxRead {
+                sample_id: samples.sample_names[sample_i].clone(),
+                lane,
+                tile,
+                loc: *loc,
+                indices: idx_slices
+                    .iter()
+                    .map(|&[i0, i1]| index_array.slice(ndarray::s![i0..i1, j, 0]).to_vec())
+                    .collect(),
+                mismatches,
+                segments,
+            });
+        }
+
+        Ok(reads)
+    }
+}
+
+impl Iterator for DemuxReads<'_> {
+    type Item = io::Result<DemuxRead>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(read) = self.reads.next() {
+                return Some(Ok(read));
+            }
+
+            let (key, tile_i) = self.tiles.pop_front()?;
+            match self.read_tile(key, tile_i) {
+                Ok(reads) => self.reads = reads.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::sample_data::{read_oriented_samplesheet, I5Orientation};
+
+    #[test]
+    fn demux_reads() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sample_data =
+            read_oriented_samplesheet(run_path.join("SampleSheet.csv"), 1, I5Orientation::Forward)
+                .unwrap();
+        let output_options = OutputOptions::default();
+
+        let reads = DemuxReads::new(&novaseq_run, &sample_data, &output_options);
+        assert_eq!(reads.tiles_left(), 3);
+        let reads: Vec<_> = reads.collect::<io::Result<_>>().unwrap();
+
+        // the same reads as demux_fastqs writes
+        assert_eq!(reads.len(), 222);
+        let sample_reads: Vec<_> = reads
+            .iter()
+            .filter(|r| r.sample_id == "8034211776")
+            .collect();
+        assert_eq!(sample_reads.len(), 10);
+
+        let read = sample_reads[0];
+        assert_eq!(read.lane, 1);
+        assert_eq!(read.indices.len(), 2);
+        assert!(read.indices.iter().all(|index| index.len() == 8));
+        assert_eq!(
+            read.segments
+                .iter()
+                .map(|s| (s.read_number, s.seq.len(), s.qual.len()))
+                .collect::<Vec<_>>(),
+            [(1, 4, 4), (2, 4, 4)]
+        );
+
+        // only R1, and without the clusters with a low quality read
+        let output_options = OutputOptions {
+            skip_reads: [2].iter().cloned().collect(),
+            read_filter: Some(crate::write_fastq::ReadFilter {
+                min_mean_quality: Some(30.),
+                max_n_fraction: None,
+                keep_filtered: false,
+            }),
+            ..Default::default()
+        };
+        let filtered: Vec<_> = DemuxReads::new(&novaseq_run, &sample_data, &output_options)
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert!(filtered.len() < reads.len());
+        assert!(filtered.iter().all(|r| r.segments.len() == 1));
+    }
+}
@@ -1,81 +1,248 @@
 //! Extract and decompress a set of tiles from a vector of cbcl files.
 
-use std::{fs::File, io::prelude::*, io::SeekFrom, ptr::write};
+use std::{io::prelude::*, ptr::write};
 
 use flate2::read::MultiGzDecoder;
-use ndarray::{ArrayViewMut2, Axis};
+use ndarray::{ArrayView3, ArrayViewMut2, ArrayViewMut3, Axis};
+use rayon::prelude::*;
 
 use crate::base_decoder::{B_MAP_01, B_MAP_10, Q_MAP_01, Q_MAP_10};
+use crate::buffer_pool::{buffer_pool, PooledBuffer};
 use crate::cbcl_header_decoder::CBCLHeader;
+use crate::thread_pools::largest_first;
 
-/// extract multiple tiles from a CBCL file and write them into the array
-fn extract_tiles(
+/// read and decompress a tile's block of a CBCL file, still packed two clusters to
+/// a byte
+pub(crate) fn read_tile_bytes(
     header: &CBCLHeader,
     tile_i: usize,
-    bq_cycle: &mut ArrayViewMut2<u8>,
-    filter: &[u8],
-) -> std::io::Result<()> {
+) -> std::io::Result<PooledBuffer<'static>> {
     let start_pos = header.start_pos[tile_i];
     let uncompressed_size = header.uncompressed_size[tile_i];
+    let compressed_size = header.compressed_size[tile_i];
+
+    // open just the tile's block of the file
+    let cbcl = header
+        .storage
+        .open_range(&header.cbcl_path, start_pos, compressed_size)?;
 
-    // open file and seek to start position
-    let mut cbcl = File::open(&header.cbcl_path)?;
-    cbcl.seek(SeekFrom::Start(start_pos))?;
-
-    let n_cycles = bq_cycle.strides()[0] as usize;
-    let mut read_slice = bq_cycle.slice_mut(ndarray::s![.., 0]).as_mut_ptr();
-    let mut qscore_slice = bq_cycle.slice_mut(ndarray::s![.., 1]).as_mut_ptr();
-
-    // use MultiGzDecoder to decompress
-    let gz = MultiGzDecoder::new(&cbcl).take(uncompressed_size);
-    for (byte, f) in gz.bytes().zip(filter) {
-        let c = match byte {
-            Ok(c) => c as usize,
-            Err(e) => return Err(e),
-        };
-        match f {
-            3 => unsafe {
-                write(read_slice, B_MAP_10[c]);
-                read_slice = read_slice.add(n_cycles);
-                write(read_slice, B_MAP_01[c]);
-                read_slice = read_slice.add(n_cycles);
-                write(qscore_slice, Q_MAP_10[c]);
-                qscore_slice = qscore_slice.add(n_cycles);
-                write(qscore_slice, Q_MAP_01[c]);
-                qscore_slice = qscore_slice.add(n_cycles);
-            },
-            1 => unsafe {
-                write(read_slice, B_MAP_01[c]);
-                read_slice = read_slice.add(n_cycles);
-                write(qscore_slice, Q_MAP_01[c]);
-                qscore_slice = qscore_slice.add(n_cycles);
-            },
-            2 => unsafe {
-                write(read_slice, B_MAP_10[c]);
-                read_slice = read_slice.add(n_cycles);
-                write(qscore_slice, Q_MAP_10[c]);
-                qscore_slice = qscore_slice.add(n_cycles);
-            },
-            _ => (),
+    // use MultiGzDecoder to decompress the whole tile block at once
+    let mut gz = MultiGzDecoder::new(cbcl).take(uncompressed_size);
+    let mut tile_bytes = buffer_pool().get(uncompressed_size as usize);
+    gz.read_to_end(&mut tile_bytes)?;
+
+    Ok(tile_bytes)
+}
+
+/// The base and qscore of every packed byte, for the cluster in its high bits
+/// (`B_MAP_10`, `Q_MAP_10`) and then for the one in its low bits, so that either
+/// cluster of a byte is unpacked with one lookup
+static BQ_MAP: [[u8; 2]; 512] = bq_map();
+
+const fn bq_map() -> [[u8; 2]; 512] {
+    let mut map = [[0; 2]; 512];
+    let mut c = 0;
+    while c < 256 {
+        map[c] = [B_MAP_10[c], Q_MAP_10[c]];
+        map[256 + c] = [B_MAP_01[c], Q_MAP_01[c]];
+        c += 1;
+    }
+    map
+}
+
+/// Where each cluster that passes a tile's filter is in the tile's packed bytes,
+/// in output order: the byte times two, plus one for the cluster in the low bits.
+/// The filter is the same for every cycle of the tile, so this is worked out once,
+/// and each cycle is then unpacked without a branch on the filter
+pub(crate) struct Compaction {
+    sources: Vec<u32>,
+}
+
+impl Compaction {
+    pub(crate) fn new(filter: &[u8]) -> Compaction {
+        let mut sources = Vec::with_capacity(filter.len() * 2);
+        for (i, &f) in filter.iter().enumerate() {
+            let i = i as u32;
+            // the first cluster of a byte is in its high bits, and its filter bit
+            if f & 0b10 != 0 {
+                sources.push(i << 1);
+            }
+            if f & 0b01 != 0 {
+                sources.push((i << 1) | 1);
+            }
         }
+
+        Compaction { sources }
     }
+}
+
+/// The compactions of a tile, for the cbcls with every cluster and for the ones
+/// with only the clusters that passed filter
+pub(crate) struct TileCompaction {
+    filter: Compaction,
+    pf_filter: Compaction,
+}
+
+impl TileCompaction {
+    pub(crate) fn new(filter: &[u8], pf_filter: &[u8]) -> TileCompaction {
+        TileCompaction {
+            filter: Compaction::new(filter),
+            pf_filter: Compaction::new(pf_filter),
+        }
+    }
+
+    /// The compaction for the tile's block of the cbcl with `header`
+    pub(crate) fn for_header(&self, header: &CBCLHeader) -> &Compaction {
+        if header.non_pf_clusters_excluded {
+            &self.pf_filter
+        } else {
+            &self.filter
+        }
+    }
+}
+
+/// unpack the clusters of a tile's packed bytes that are in `compaction` into a
+/// cycle, in order
+fn compact(tile_bytes: &[u8], compaction: &Compaction, bq_cycle: &mut ArrayViewMut2<u8>) {
+    let stride = bq_cycle.strides()[0] as usize;
+    // a short block fills as many clusters as it has
+    let n_clusters = compaction
+        .sources
+        .partition_point(|&source| ((source >> 1) as usize) < tile_bytes.len())
+        .min(bq_cycle.dim().0);
+    let read_slice = bq_cycle.slice_mut(ndarray::s![.., 0]).as_mut_ptr();
+    let qscore_slice = bq_cycle.slice_mut(ndarray::s![.., 1]).as_mut_ptr();
+
+    for (o, &source) in compaction.sources[..n_clusters].iter().enumerate() {
+        let source = source as usize;
+        // the sources are within the bytes, and the outputs within the cycle
+        unsafe {
+            let c = *tile_bytes.get_unchecked(source >> 1) as usize;
+            let [base, qscore] = *BQ_MAP.get_unchecked(((source & 1) << 8) | c);
+            write(read_slice.add(o * stride), base);
+            write(qscore_slice.add(o * stride), qscore);
+        }
+    }
+}
+
+/// extract multiple tiles from a CBCL file and write them into the array
+fn extract_tiles(
+    header: &CBCLHeader,
+    tile_i: usize,
+    bq_cycle: &mut ArrayViewMut2<u8>,
+    compaction: &Compaction,
+) -> std::io::Result<()> {
+    let tile_bytes = read_tile_bytes(header, tile_i)?;
+    compact(&tile_bytes, compaction, bq_cycle);
 
     Ok(())
 }
 
-/// just read a lot of data into one cycle
+/// just read a lot of data into one cycle, unpacking the clusters in `compaction`.
+/// If the tile can't be read, the cycle is filled with N and the error is returned
+/// so the caller can report it
 pub fn extract_cbcl(
     header: &CBCLHeader,
-    filter: &[u8],
+    compaction: &Compaction,
     bq_cycle: &mut ArrayViewMut2<u8>,
     tile_i: usize,
-) {
-    if let Ok(_) = extract_tiles(header, tile_i, bq_cycle, filter) {
-        return;
-    } else {
-        bq_cycle.index_axis_mut(Axis(1), 0).fill(b'N');
-        bq_cycle.index_axis_mut(Axis(1), 1).fill(b'#');
+) -> std::io::Result<()> {
+    let result = extract_tiles(header, tile_i, bq_cycle, compaction);
+    if result.is_err() {
+        fill_missing(bq_cycle);
     }
+    result
+}
+
+/// fill a cycle that couldn't be read with N, at the lowest quality
+pub(crate) fn fill_missing(bq_cycle: &mut ArrayViewMut2<u8>) {
+    bq_cycle.index_axis_mut(Axis(1), 0).fill(b'N');
+    bq_cycle.index_axis_mut(Axis(1), 1).fill(b'#');
+}
+
+/// The side of the square blocks for `transpose_blocked`. A block of 64 cycles by 64
+/// clusters, for both the bases and the qscores, fits in L1 with room to spare
+const TRANSPOSE_BLOCK: usize = 64;
+
+/// Extract every cycle of a tile into `b_array`, which is cluster-major: the cycles
+/// of each cluster are next to each other, so the reads can be written out in order.
+/// Each cycle is decoded into a cycle-major buffer first, so that decoding writes
+/// to memory in order instead of a byte in every cluster's column, then the buffer
+/// is transposed in blocks. Returns the result for each cycle, as for `extract_cbcl`.
+/// With `gpu`, the cycles are unpacked on the GPU if there is one
+pub fn extract_cbcls(
+    headers: &[CBCLHeader],
+    filter: &[u8],
+    pf_filter: &[u8],
+    b_array: &mut ArrayViewMut3<u8>,
+    tile_i: usize,
+    gpu: bool,
+) -> Vec<std::io::Result<()>> {
+    let (n_cycles, n_pf, _) = b_array.dim();
+    let compaction = TileCompaction::new(filter, pf_filter);
+
+    let mut cycle_bytes = buffer_pool().get(n_cycles * n_pf * 2);
+    cycle_bytes.resize(n_cycles * n_pf * 2, 0);
+    let mut cycle_array = ArrayViewMut3::from_shape((n_cycles, n_pf, 2), &mut cycle_bytes[..])
+        .expect("buffer is the size of the tile");
+
+    #[cfg(feature = "gpu")]
+    let gpu_tile = match gpu {
+        true => crate::gpu::gpu().and_then(|gpu| gpu.tile(filter, pf_filter)),
+        false => None,
+    };
+    #[cfg(not(feature = "gpu"))]
+    let _ = gpu;
+
+    let jobs: Vec<_> = cycle_array.axis_iter_mut(Axis(0)).zip(headers).collect();
+    let results = largest_first(
+        jobs,
+        |(_, header)| header.compressed_size[tile_i],
+        |(mut bq_cycle, header)| {
+            #[cfg(feature = "gpu")]
+            if let Some(gpu_tile) = &gpu_tile {
+                if let Some(result) = gpu_tile.extract_cbcl(header, &mut bq_cycle, tile_i) {
+                    return result;
+                }
+            }
+
+            extract_cbcl(header, compaction.for_header(header), &mut bq_cycle, tile_i)
+        },
+    );
+
+    transpose_blocked(&cycle_array.view(), b_array);
+
+    results
+}
+
+/// Copy a cycle-major array into a cluster-major one of the same shape, a block at
+/// a time so that neither side is read or written with a stride bigger than the
+/// block
+fn transpose_blocked(cycle_array: &ArrayView3<u8>, b_array: &mut ArrayViewMut3<u8>) {
+    assert_eq!(cycle_array.dim(), b_array.dim());
+    let n_cycles = cycle_array.dim().0;
+
+    b_array
+        .axis_chunks_iter_mut(Axis(1), TRANSPOSE_BLOCK)
+        .into_par_iter()
+        .zip(cycle_array.axis_chunks_iter(Axis(1), TRANSPOSE_BLOCK))
+        .for_each(|(mut dst, src)| {
+            let n_clusters = src.dim().1;
+
+            for b in 0..2 {
+                for y0 in (0..n_cycles).step_by(TRANSPOSE_BLOCK) {
+                    let y1 = (y0 + TRANSPOSE_BLOCK).min(n_cycles);
+                    for c in 0..n_clusters {
+                        for y in y0..y1 {
+                            // the indices are all within the shared shape
+                            unsafe {
+                                *dst.uget_mut([y, c, b]) = *src.uget([y, c, b]);
+                            }
+                        }
+                    }
+                }
+            }
+        });
 }
 
 #[cfg(test)]
@@ -83,6 +250,8 @@ mod tests {
     use ndarray::{Array2, Array3, Axis, ShapeBuilder};
     use std::path::PathBuf;
 
+    use super::{compact, Compaction, TileCompaction};
+    use crate::base_decoder::{B_MAP_01, B_MAP_10, Q_MAP_01, Q_MAP_10};
     use crate::cbcl_header_decoder::CBCLHeader;
     use crate::filter_decoder::filter_decoder;
     use crate::novaseq_run::NovaSeqRun;
@@ -94,7 +263,7 @@ mod tests {
         let cbcl_header = CBCLHeader::from_path(&cbcl_path).unwrap();
         let filter_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX")
             .join("Data/Intensities/BaseCalls/L001/s_1_1101.filter");
-        let cbcl_filter = filter_decoder(&filter_path).unwrap();
+        let cbcl_filter = filter_decoder(std::fs::File::open(&filter_path).unwrap()).unwrap();
         let n_pf = cbcl_filter
             .iter()
             .map(|&b| [0, 1, 1, 2][b as usize])
@@ -113,7 +282,13 @@ mod tests {
 
         let mut read_array: Array2<u8> = Array2::zeros((n_pf, 2));
 
-        super::extract_tiles(&cbcl_header, 0, &mut read_array.view_mut(), &cbcl_filter).unwrap();
+        super::extract_tiles(
+            &cbcl_header,
+            0,
+            &mut read_array.view_mut(),
+            &Compaction::new(&cbcl_filter),
+        )
+        .unwrap();
 
         assert_eq!(read_array.into_raw_vec(), expected_bytes);
     }
@@ -144,24 +319,92 @@ mod tests {
 
         let n_pf = filter.iter().map(|&b| [0, 1, 1, 2][b as usize]).sum();
         let mut bq_array = Array3::zeros((headers.len(), n_pf, 2).f());
+        let compaction = TileCompaction::new(filter, pf_filter);
 
         for ((mut byte_array, read_h), exp_bq) in bq_array
             .axis_iter_mut(Axis(0))
             .zip(headers)
             .zip(expected_bq_pairs)
         {
-            super::extract_cbcl(
-                read_h,
-                if read_h.non_pf_clusters_excluded {
-                    &pf_filter
-                } else {
-                    &filter
-                },
-                &mut byte_array,
-                0,
-            );
+            super::extract_cbcl(read_h, compaction.for_header(read_h), &mut byte_array, 0).unwrap();
             let bq_pairs: Vec<_> = byte_array.iter().cloned().take(16).collect();
             assert_eq!(bq_pairs, exp_bq);
         }
     }
+
+    #[test]
+    fn extract_transposed() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+
+        let headers = &novaseq_run.read_headers.get(&[1, 1]).unwrap()[0];
+        let filter = &novaseq_run.filters.get(&[1, 1]).unwrap()[0];
+        let pf_filter = &novaseq_run.pf_filters.get(&[1, 1]).unwrap()[0];
+        let n_pf = filter.iter().map(|&b| [0, 1, 1, 2][b as usize]).sum();
+
+        let mut expected = Array3::zeros((headers.len(), n_pf, 2).f());
+        let compaction = TileCompaction::new(filter, pf_filter);
+        for (mut byte_array, read_h) in expected.axis_iter_mut(Axis(0)).zip(headers) {
+            super::extract_cbcl(read_h, compaction.for_header(read_h), &mut byte_array, 0).unwrap();
+        }
+
+        // with room for more cycles, like the demux buffer
+        let mut bq_array = Array3::zeros((headers.len() + 3, n_pf, 2).f());
+        let results = super::extract_cbcls(
+            headers,
+            filter,
+            pf_filter,
+            &mut bq_array.slice_mut(ndarray::s![..headers.len(), .., ..]),
+            0,
+            false,
+        );
+
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(
+            bq_array.slice(ndarray::s![..headers.len(), .., ..]),
+            expected
+        );
+    }
+
+    #[test]
+    fn compaction() {
+        let compaction = Compaction::new(&[0b11, 0b00, 0b01, 0b10, 0b11]);
+        assert_eq!(compaction.sources, [0, 1, 5, 6, 8, 9]);
+
+        // the same as unpacking each byte by its filter
+        let tile_bytes: Vec<u8> = (0..5).map(|i| 0x1b + 37 * i).collect();
+        let mut expected = Vec::new();
+        for (&c, f) in tile_bytes.iter().zip([0b11, 0b00, 0b01, 0b10, 0b11]) {
+            let c = c as usize;
+            if f & 0b10 != 0 {
+                expected.extend([B_MAP_10[c], Q_MAP_10[c]]);
+            }
+            if f & 0b01 != 0 {
+                expected.extend([B_MAP_01[c], Q_MAP_01[c]]);
+            }
+        }
+        let mut bq_cycle = Array2::zeros((6, 2));
+        compact(&tile_bytes, &compaction, &mut bq_cycle.view_mut());
+        assert_eq!(bq_cycle.into_raw_vec(), expected);
+
+        // a short block fills the clusters it has, and leaves the rest
+        let mut bq_cycle = Array2::zeros((6, 2));
+        compact(&tile_bytes[..3], &compaction, &mut bq_cycle.view_mut());
+        assert_eq!(&bq_cycle.as_slice().unwrap()[..6], &expected[..6]);
+        assert!(bq_cycle.as_slice().unwrap()[6..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn transpose() {
+        // bigger than a block in both directions, and not a multiple of one
+        let (n_cycles, n_clusters) = (151, 200);
+        let cycle_array = Array3::from_shape_fn((n_cycles, n_clusters, 2), |(y, c, b)| {
+            (y * 7 + c * 3 + b) as u8
+        });
+        let mut b_array = Array3::zeros((n_cycles, n_clusters, 2).f());
+
+        super::transpose_blocked(&cycle_array.view(), &mut b_array.view_mut());
+
+        assert_eq!(b_array, cycle_array);
+    }
 }
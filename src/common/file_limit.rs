@@ -0,0 +1,133 @@
+//! The limit on open files (`RLIMIT_NOFILE`). With compression workers or named
+//! pipes a demux can have a file open for every sample and read at once, which
+//! for plates with thousands of samples is past the usual soft limit of 1024.
+//! Running out partway through fails the run with EMFILE, so the limit is checked
+//! before it starts: raised if the hard limit allows it, or else the output files
+//! are pooled so that only as many are open as the limit allows
+
+use std::io;
+
+/// The files kept for the run folder, the stats, logs and sockets on top of the
+/// output files
+pub const RESERVED_FILES: u64 = 64;
+
+/// The soft and hard limits on the number of open files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileLimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+/// What to do about the limit before a demux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitAction {
+    /// the soft limit is high enough already
+    Enough,
+    /// raise the soft limit to this
+    Raise(u64),
+    /// even the hard limit is too low: raise the soft limit to the hard limit,
+    /// and keep at most this many output files open
+    Pool(usize),
+}
+
+impl FileLimit {
+    /// The limits of this process, or `None` where they aren't known
+    #[cfg(target_os = "linux")]
+    // rlim_t is only u64 on some targets
+    #[allow(clippy::unnecessary_cast)]
+    pub fn current() -> Option<FileLimit> {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return None;
+        }
+
+        Some(FileLimit {
+            soft: limit.rlim_cur as u64,
+            hard: limit.rlim_max as u64,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn current() -> Option<FileLimit> {
+        None
+    }
+
+    /// Raise the soft limit to `soft`, which has to be within the hard limit
+    #[cfg(target_os = "linux")]
+    pub fn raise(&self, soft: u64) -> io::Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: soft.min(self.hard) as libc::rlim_t,
+            rlim_max: self.hard as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn raise(&self, _soft: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "changing the open file limit is only supported on Linux",
+        ))
+    }
+
+    /// What to do for a demux that has up to `output_files` output files open at
+    /// once, plus `RESERVED_FILES`. Fails if even the hard limit is too low to keep
+    /// one output file open for each of the `n_writers` writing at once
+    pub fn action(&self, output_files: u64, n_writers: usize) -> Result<LimitAction, String> {
+        let required = output_files + RESERVED_FILES;
+        if required <= self.soft {
+            return Ok(LimitAction::Enough);
+        }
+        if required <= self.hard {
+            return Ok(LimitAction::Raise(required));
+        }
+
+        let available = self.hard.saturating_sub(RESERVED_FILES);
+        if available < n_writers.max(1) as u64 {
+            return Err(format!(
+                "the open file limit of {} leaves too few files for {} writers, raise it with `ulimit -n`",
+                self.hard, n_writers
+            ));
+        }
+
+        Ok(LimitAction::Pool(available as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_action() {
+        let limit = FileLimit {
+            soft: 1024,
+            hard: 4096,
+        };
+        assert_eq!(limit.action(500, 8), Ok(LimitAction::Enough));
+        assert_eq!(limit.action(3072, 8), Ok(LimitAction::Raise(3136)));
+        assert_eq!(
+            limit.action(2 * 1537 * 4, 8),
+            Ok(LimitAction::Pool(4096 - RESERVED_FILES as usize))
+        );
+
+        let limit = FileLimit { soft: 64, hard: 70 };
+        assert!(limit.action(100, 8).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn current_limit() {
+        let limit = FileLimit::current().unwrap();
+        assert!(limit.soft <= limit.hard);
+        // raising it to where it is already always works
+        limit.raise(limit.soft).unwrap();
+    }
+}
@@ -2,21 +2,23 @@
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
-use std::{fs::File, io::Read, path::Path};
+use std::io::Read;
+
+use crate::storage::gunzip_if_compressed;
 
 /// A filter is a vector of bytes representing pairs of booleans,
 /// e.g. (false, false) = 0, (true, false) = 2, etc
 pub type Filter = Vec<u8>;
 
-/// Decode a `.filter` file into a `Filter` struct or panic
+/// Decode a `.filter` file, from a local file or object storage, into a `Filter` struct or panic.
+/// The file can be gzipped
 ///
 /// Format of a `.filter` file:
 ///  1. Two `u32` containing header info (ignored)
 ///  2. `u32` representing the number of clusters
 ///  3. `[u8; num_clusters]` of true/false (1 or 0) values
-pub fn filter_decoder(filter_path: &Path) -> std::io::Result<Filter> {
-    let mut rdr = File::open(filter_path)?;
-
+pub fn filter_decoder<R: Read>(rdr: R) -> std::io::Result<Filter> {
+    let mut rdr = gunzip_if_compressed(rdr)?;
     let _ = rdr.read_u64::<LittleEndian>()?;
 
     let num_clusters = rdr.read_u32::<LittleEndian>()? as usize;
@@ -36,12 +38,26 @@ pub fn filter_decoder(filter_path: &Path) -> std::io::Result<Filter> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{fs::File, path::Path};
+
+    fn decode_path(path: &Path) -> std::io::Result<Filter> {
+        filter_decoder(File::open(path)?)
+    }
+
+    /// the bytes of a file, gzipped as in an archived run
+    fn gzip_file(path: &Path) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&std::fs::read(path).unwrap()).unwrap();
+        gz.finish().unwrap()
+    }
 
     #[test]
     fn decode() {
         let test_file = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX")
             .join("Data/Intensities/BaseCalls/L001/s_1_1101.filter");
-        let actual_filter = filter_decoder(&test_file).unwrap();
+        let actual_filter = decode_path(&test_file).unwrap();
         let expected_filter = vec![
             0, 1, 3, 3, 0, 2, 3, 3, 3, 3, 2, 3, 3, 3, 3, 3, 3, 3, 3, 3, 1, 3, 3, 3, 3, 1, 3, 3, 3,
             2, 3, 3, 1, 3, 3, 2, 3, 2, 1, 3, 2, 3, 3, 3, 2, 3, 0, 3, 2, 0,
@@ -49,31 +65,42 @@ mod tests {
         assert_eq!(actual_filter, expected_filter);
     }
 
+    #[test]
+    fn decode_gzipped() {
+        let test_file = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX")
+            .join("Data/Intensities/BaseCalls/L001/s_1_1101.filter");
+        let gzipped = gzip_file(&test_file);
+        assert_eq!(
+            filter_decoder(&gzipped[..]).unwrap(),
+            decode_path(&test_file).unwrap()
+        );
+    }
+
     #[test]
     #[should_panic(expected = r#"No such file or directory"#)]
     fn no_file() {
         let test_file = Path::new("test_data/no_file.filter");
-        filter_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 
     #[test]
     #[should_panic(expected = r#"failed to fill whole buffer"#)]
     fn empty_file() {
         let test_file = Path::new("test_data/empty_file");
-        filter_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 
     #[test]
     #[should_panic(expected = r#"failed to fill whole buffer"#)]
     fn bad_8_bytes() {
         let test_file = Path::new("test_data/bad_data_8.bin");
-        filter_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 
     #[test]
     #[should_panic(expected = r#"failed to fill whole buffer"#)]
     fn bad_12_bytes() {
         let test_file = Path::new("test_data/bad_data_12.bin");
-        filter_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 }
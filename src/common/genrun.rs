@@ -0,0 +1,511 @@
+//! Generate a small synthetic NovaSeq run folder from a samplesheet: RunInfo.xml,
+//! CBCL files, filters and locs, with reads for each sample (and some that match
+//! none of them) at random. The same samplesheet and seed always give the same run,
+//! so a bug can be reproduced from a run that has no real sequencing data in it
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::cbcl_header_decoder::write_header;
+use crate::sample_data::{read_samplesheet_rows, SampleData, Samples};
+
+/// The lower bounds of the quality score bins on the NovaSeq
+pub const NOVASEQ_BINS: [u32; 4] = [0, 11, 25, 37];
+
+/// The shape of the generated run
+#[derive(Debug, Clone)]
+pub struct GenRunOptions {
+    /// seed for the random reads
+    pub seed: u64,
+    /// number of lanes, if the samplesheet doesn't have a Lane column
+    pub lanes: usize,
+    /// tiles in each lane, all on the first surface
+    pub tiles: usize,
+    /// clusters in each tile
+    pub clusters: usize,
+    /// cycles in each of the two (non-index) reads
+    pub read_length: usize,
+    /// fraction of clusters that pass filter
+    pub pf_fraction: f64,
+    /// fraction of clusters whose indices don't match any sample
+    pub undetermined_fraction: f64,
+    /// fraction of reads that run off the end of the insert halfway through, so
+    /// the rest of the read is G, as on two-color instruments
+    pub poly_g_fraction: f64,
+    /// lower bounds of the quality score bins, written to the CBCL headers. Bin 0
+    /// is a no-call, so there are at most four
+    pub bins: Vec<u32>,
+}
+
+impl Default for GenRunOptions {
+    fn default() -> Self {
+        GenRunOptions {
+            seed: 0,
+            lanes: 1,
+            tiles: 3,
+            clusters: 100,
+            read_length: 8,
+            pf_fraction: 0.9,
+            undetermined_fraction: 0.1,
+            poly_g_fraction: 0.,
+            bins: NOVASEQ_BINS.to_vec(),
+        }
+    }
+}
+
+/// Parse a list of quality bins, e.g. `0,11,25,37`
+pub fn parse_bins(s: &str) -> Result<Vec<u32>, String> {
+    let bins = s
+        .split(',')
+        .map(|b| b.trim().parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| format!("invalid quality bins '{}'", s))?;
+
+    if !(2..=4).contains(&bins.len()) {
+        return Err(format!("expected 2 to 4 quality bins, got {}", bins.len()));
+    }
+
+    Ok(bins)
+}
+
+/// The reads we expect in each lane of a generated run, counting only clusters
+/// that pass filter
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedLane {
+    pub lane: usize,
+    pub sample_names: Vec<String>,
+    pub sample_reads: Vec<u64>,
+    pub undetermined: u64,
+}
+
+/// A splitmix64 generator: we only need something small and reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn sequence(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| b"ACGT"[self.below(4)]).collect()
+    }
+}
+
+/// The length of each index in the samplesheet, which must be the same for every
+/// sample so that they fit in the index cycles
+fn index_lengths(sample_data: &SampleData) -> io::Result<Vec<usize>> {
+    let mut lengths: Option<Vec<usize>> = None;
+
+    for samples in sample_data.values() {
+        for sample_i in 0..samples.sample_names.len() {
+            let sample_lengths: Vec<_> = (0..samples.n_indices())
+                .map(|index_i| samples.sample_index(index_i, sample_i).len())
+                .collect();
+
+            match &lengths {
+                None => lengths = Some(sample_lengths),
+                Some(lengths) if *lengths == sample_lengths => (),
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "every sample must have the same index lengths to generate a run",
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(lengths.unwrap_or_default())
+}
+
+fn run_info_xml(
+    run_id: &str,
+    lanes: usize,
+    tiles: &[u32],
+    read_cycles: &[(usize, bool)],
+) -> String {
+    let reads: String = read_cycles
+        .iter()
+        .enumerate()
+        .map(|(i, (cycles, is_index))| {
+            format!(
+                "\t\t\t<Read IsIndexedRead=\"{}\" NumCycles=\"{}\" Number=\"{}\" />\n",
+                if *is_index { "Y" } else { "N" },
+                cycles,
+                i + 1
+            )
+        })
+        .collect();
+
+    let tile_names: String = (1..=lanes)
+        .flat_map(|lane| tiles.iter().map(move |tile| (lane, tile)))
+        .map(|(lane, tile)| format!("\t\t\t\t\t<Tile>{}_{}</Tile>\n", lane, tile))
+        .collect();
+
+    format!(
+        "<?xml version='1.0' encoding='utf-8'?>
+<RunInfo Version=\"5\">
+\t<Run Id=\"{}\" Number=\"1\">
+\t\t<Flowcell>SYNTHETIC</Flowcell>
+\t\t<Instrument>A00000</Instrument>
+\t\t<Date>1/1/2000 12:00:00 AM</Date>
+\t\t<Reads>
+{}\t\t</Reads>
+\t\t<FlowcellLayout FlowcellSide=\"1\" LaneCount=\"{}\" SurfaceCount=\"1\" SwathCount=\"1\" TileCount=\"{}\">
+\t\t\t<TileSet TileNamingConvention=\"FourDigit\">
+\t\t\t\t<Tiles>
+{}\t\t\t\t</Tiles>
+\t\t\t</TileSet>
+\t\t</FlowcellLayout>
+\t</Run>
+</RunInfo>
+",
+        run_id,
+        reads,
+        lanes,
+        tiles.len(),
+        tile_names
+    )
+}
+
+/// Write a CBCL file for one cycle of a lane. `tile_calls` has the packed base and
+/// quality bin of every cluster in each tile
+fn write_cbcl(path: &Path, tiles: &[u32], tile_calls: &[Vec<u8>], bins: &[u32]) -> io::Result<()> {
+    let blocks = tile_calls
+        .iter()
+        .map(|calls| {
+            // two clusters to a byte, the first in the low half
+            let packed: Vec<u8> = calls
+                .chunks(2)
+                .map(|pair| pair[0] | pair.get(1).map_or(0, |c| c << 4))
+                .collect();
+
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(&packed)?;
+            Ok((calls.len(), packed.len(), gz.finish()?))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let tile_records: Vec<_> = tiles
+        .iter()
+        .zip(&blocks)
+        .map(|(&tile, (n_clusters, uncompressed, compressed))| {
+            [
+                tile,
+                *n_clusters as u32,
+                *uncompressed as u32,
+                compressed.len() as u32,
+            ]
+        })
+        .collect();
+
+    let mut out = BufWriter::new(File::create(path)?);
+    // every cluster is in the file, passing filter or not
+    write_header(&mut out, bins, &tile_records, false)?;
+
+    for (_, _, compressed) in &blocks {
+        out.write_all(compressed)?;
+    }
+
+    out.flush()
+}
+
+fn write_filter(path: &Path, pf: &[bool]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_u32::<LittleEndian>(0)?;
+    out.write_u32::<LittleEndian>(3)?;
+    out.write_u32::<LittleEndian>(pf.len() as u32)?;
+    for &p in pf {
+        out.write_u8(p as u8)?;
+    }
+
+    out.flush()
+}
+
+fn write_locs(path: &Path, n_clusters: usize, rng: &mut Rng) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_u32::<LittleEndian>(1)?;
+    out.write_f32::<LittleEndian>(1.0)?;
+    out.write_u32::<LittleEndian>(n_clusters as u32)?;
+    for _ in 0..2 * n_clusters {
+        out.write_f32::<LittleEndian>(rng.below(30_000) as f32 / 10.0)?;
+    }
+
+    out.flush()
+}
+
+/// Random index sequences that don't match any of `samples`, even with mismatches
+fn undetermined_indices(
+    samples: Option<&Samples>,
+    lengths: &[usize],
+    rng: &mut Rng,
+) -> Vec<Vec<u8>> {
+    loop {
+        let indices: Vec<_> = lengths.iter().map(|&len| rng.sequence(len)).collect();
+        if !samples.is_some_and(|s| s.is_any_sample(&indices)) {
+            return indices;
+        }
+    }
+}
+
+/// Generate a run in `run_path` with reads for the samples in `sample_data`, and
+/// copy the samplesheet into it. Returns the reads we expect for each lane
+pub fn generate_run(
+    samplesheet: &Path,
+    sample_data: &SampleData,
+    run_path: &Path,
+    options: &GenRunOptions,
+) -> io::Result<Vec<GeneratedLane>> {
+    if !(2..=4).contains(&options.bins.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected 2 to 4 quality bins, got {}", options.bins.len()),
+        ));
+    }
+    if !(1..=99).contains(&options.tiles) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the number of tiles must be from 1 to 99",
+        ));
+    }
+
+    let mut rng = Rng(options.seed);
+
+    let index_lengths = index_lengths(sample_data)?;
+    // samples without a lane are in every lane
+    let n_lanes = match sample_data.keys().max() {
+        Some(0) | None => options.lanes,
+        Some(&lane) => lane,
+    };
+    let tiles: Vec<u32> = (1..=options.tiles as u32).map(|t| 1100 + t).collect();
+
+    let mut read_cycles = vec![(options.read_length, false)];
+    read_cycles.extend(index_lengths.iter().map(|&len| (len, true)));
+    read_cycles.push((options.read_length, false));
+    let n_cycles: usize = read_cycles.iter().map(|(cycles, _)| cycles).sum();
+
+    let run_id = run_path
+        .file_name()
+        .map_or("synthetic".into(), |name| name.to_string_lossy());
+
+    let basecalls_path = run_path.join("Data/Intensities/BaseCalls");
+    fs::create_dir_all(&basecalls_path)?;
+    fs::write(
+        run_path.join("RunInfo.xml"),
+        run_info_xml(&run_id, n_lanes, &tiles, &read_cycles),
+    )?;
+    // the samplesheet might be gzipped or a workbook, so write it out as CSV
+    let mut wtr = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_path(run_path.join("SampleSheet.csv"))?;
+    for row in read_samplesheet_rows(samplesheet)? {
+        wtr.write_record(&row)?;
+    }
+    wtr.flush()?;
+    write_locs(
+        &run_path.join("Data/Intensities/s.locs"),
+        options.clusters,
+        &mut rng,
+    )?;
+
+    let read = |rng: &mut Rng| {
+        let mut seq = rng.sequence(options.read_length);
+        // the same seed still gives the same run without poly-G
+        if options.poly_g_fraction > 0. && rng.chance(options.poly_g_fraction) {
+            seq[options.read_length / 2..].fill(b'G');
+        }
+        seq
+    };
+
+    let mut lanes = Vec::new();
+    for lane in 1..=n_lanes {
+        let samples = sample_data.get(&lane).or_else(|| sample_data.get(&0));
+        let n_samples = samples.map_or(0, |s| s.sample_names.len());
+
+        let mut generated = GeneratedLane {
+            lane,
+            sample_names: samples.map_or(Vec::new(), |s| s.sample_names.clone()),
+            sample_reads: vec![0; n_samples],
+            undetermined: 0,
+        };
+
+        let lane_path = basecalls_path.join(format!("L{:03}", lane));
+        fs::create_dir_all(&lane_path)?;
+
+        // the packed call for each cycle, tile and cluster
+        let mut calls = vec![vec![Vec::with_capacity(options.clusters); tiles.len()]; n_cycles];
+
+        for (tile_i, &tile) in tiles.iter().enumerate() {
+            let mut pf = Vec::with_capacity(options.clusters);
+
+            for _ in 0..options.clusters {
+                let passes = rng.chance(options.pf_fraction);
+                pf.push(passes);
+
+                let indices = match samples {
+                    Some(s) if n_samples > 0 && !rng.chance(options.undetermined_fraction) => {
+                        let sample_i = rng.below(n_samples);
+                        if passes {
+                            generated.sample_reads[sample_i] += 1;
+                        }
+                        (0..index_lengths.len())
+                            .map(|index_i| s.sample_index(index_i, sample_i).to_vec())
+                            .collect()
+                    }
+                    _ => {
+                        if passes {
+                            generated.undetermined += 1;
+                        }
+                        undetermined_indices(samples, &index_lengths, &mut rng)
+                    }
+                };
+
+                let mut bases = read(&mut rng);
+                bases.extend(indices.concat());
+                bases.extend(read(&mut rng));
+
+                for (cycle_calls, base) in calls.iter_mut().zip(bases) {
+                    let base = match base {
+                        b'A' => 0,
+                        b'C' => 1,
+                        b'G' => 2,
+                        _ => 3,
+                    };
+                    // mostly the top bin, like a real run
+                    let top_bin = options.bins.len() - 1;
+                    let bin = if rng.chance(0.8) {
+                        top_bin
+                    } else {
+                        1 + rng.below(top_bin)
+                    };
+                    cycle_calls[tile_i].push(base | (bin as u8) << 2);
+                }
+            }
+
+            write_filter(&lane_path.join(format!("s_{}_{}.filter", lane, tile)), &pf)?;
+        }
+
+        for (cycle_i, tile_calls) in calls.iter().enumerate() {
+            let cycle_path = lane_path.join(format!("C{}.1", cycle_i + 1));
+            fs::create_dir_all(&cycle_path)?;
+            write_cbcl(
+                &cycle_path.join(format!("L{:03}_1.cbcl", lane)),
+                &tiles,
+                tile_calls,
+                &options.bins,
+            )?;
+        }
+
+        lanes.push(generated);
+    }
+
+    Ok(lanes)
+}
+
+impl fmt::Display for GeneratedLane {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, reads) in self.sample_names.iter().zip(&self.sample_reads) {
+            writeln!(f, "lane {}\t{}\t{}", self.lane, name, reads)?;
+        }
+        write!(f, "lane {}\tUndetermined\t{}", self.lane, self.undetermined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap as Map;
+
+    use crate::demux::DemuxBuilder;
+    use crate::novaseq_run::NovaSeqRun;
+    use crate::sample_data::read_samplesheet;
+    use crate::write_fastq::OutputOptions;
+
+    static SAMPLESHEET: &str = "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv";
+
+    #[test]
+    fn bins() {
+        assert_eq!(parse_bins("0,11,25,37").unwrap(), NOVASEQ_BINS);
+        assert!(parse_bins("0").is_err());
+        assert!(parse_bins("0,1,2,3,4").is_err());
+        assert!(parse_bins("0,x").is_err());
+    }
+
+    #[test]
+    fn generate() {
+        let run_path = Path::new("test_data/test_output/genrun/run");
+        let output_path = Path::new("test_data/test_output/genrun/output");
+        let _ = fs::remove_dir_all("test_data/test_output/genrun");
+
+        let sample_data = read_samplesheet(SAMPLESHEET.into(), 1).unwrap();
+        let options = GenRunOptions {
+            seed: 7,
+            ..Default::default()
+        };
+        let lanes = generate_run(Path::new(SAMPLESHEET), &sample_data, run_path, &options).unwrap();
+
+        // the same seed gives the same run
+        let again_path = Path::new("test_data/test_output/genrun/again");
+        let again =
+            generate_run(Path::new(SAMPLESHEET), &sample_data, again_path, &options).unwrap();
+        assert_eq!(lanes, again);
+        assert_eq!(
+            fs::read(run_path.join("Data/Intensities/BaseCalls/L001/C20.1/L001_1.cbcl")).unwrap(),
+            fs::read(again_path.join("Data/Intensities/BaseCalls/L001/C20.1/L001_1.cbcl")).unwrap()
+        );
+
+        let novaseq_run = NovaSeqRun::read_path(run_path.to_path_buf(), false).unwrap();
+        assert_eq!(novaseq_run.run_info.total_cycles(), 32);
+        assert_eq!(novaseq_run.tile_count(1), 3);
+
+        fs::create_dir_all(output_path).unwrap();
+        let lane_stats = DemuxBuilder::new(run_path, run_path.join("SampleSheet.csv"), output_path)
+            .output_options(OutputOptions {
+                compression: None,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        let expected: Map<_, _> = lanes[0]
+            .sample_names
+            .iter()
+            .zip(&lanes[0].sample_reads)
+            .map(|(name, &reads)| (name.clone(), reads))
+            .collect();
+        let actual: Map<_, _> = lane_stats[0]
+            .demux_results
+            .iter()
+            .map(|s| (s.sample_name.clone(), s.number_reads))
+            .collect();
+
+        assert_eq!(actual, expected);
+        assert_eq!(
+            lane_stats[0].undetermined.number_reads,
+            lanes[0].undetermined
+        );
+        assert_eq!(
+            lane_stats[0].total_clusters_pf,
+            expected.values().sum::<u64>() + lanes[0].undetermined
+        );
+    }
+}
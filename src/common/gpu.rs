@@ -0,0 +1,498 @@
+//! Experimental GPU offload, with the `gpu` feature. The tile blocks are still read
+//! and decompressed on the CPU, then uploaded so the 2-bit unpacking, quality
+//! mapping, and barcode matching are done by CUDA kernels. If there is no GPU (or
+//! no CUDA driver) everything stays on the CPU, and a kernel that fails falls back
+//! to the CPU for that tile. With several GPUs, the demux threads are spread
+//! over them
+
+use std::{
+    io,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use cudarc::driver::{
+    CudaDevice, CudaFunction, CudaSlice, DeviceSlice, DriverError, LaunchAsync, LaunchConfig,
+};
+use cudarc::nvrtc::{compile_ptx, Ptx};
+use log::{info, warn};
+use ndarray::{ArrayView3, ArrayViewMut2, Axis};
+
+use crate::base_decoder::{B_MAP_01, B_MAP_10, Q_MAP_01, Q_MAP_10};
+use crate::cbcl_header_decoder::CBCLHeader;
+use crate::extract_reads::{fill_missing, read_tile_bytes};
+use crate::sample_data::Samples;
+use crate::write_fastq::Assignment;
+
+const MODULE: &str = "bcl2fastr";
+
+/// the position of a cluster that failed the filter
+const NO_POSITION: u32 = u32::MAX;
+
+const KERNELS: &str = r#"
+// one thread per byte of the tile, i.e. two clusters. `maps` is the four lookup
+// tables from base_decoder, one after another
+extern "C" __global__ void unpack(
+    const unsigned char *tile_bytes,
+    const unsigned int *positions,
+    unsigned int n_bytes,
+    const unsigned char *maps,
+    unsigned char *bq_cycle
+) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i >= n_bytes) {
+        return;
+    }
+
+    unsigned int c = tile_bytes[i];
+    unsigned int p = positions[2 * i];
+    if (p != 0xFFFFFFFF) {
+        bq_cycle[2 * p] = maps[c];
+        bq_cycle[2 * p + 1] = maps[512 + c];
+    }
+    p = positions[2 * i + 1];
+    if (p != 0xFFFFFFFF) {
+        bq_cycle[2 * p] = maps[256 + c];
+        bq_cycle[2 * p + 1] = maps[768 + c];
+    }
+}
+
+// one thread per cluster: the first sample with every index within
+// `max_mismatches`, like Samples::find_sample, which is dropped if its mismatches over
+// all the indices are more than `max_total_mismatches`. Samples with an index of a different
+// length from the read's can't match, so they are left out with `usable`
+extern "C" __global__ void match_barcodes(
+    const unsigned char *index_reads,
+    unsigned int n_clusters,
+    unsigned int n_cycles,
+    const unsigned char *sample_indices,
+    const unsigned char *usable,
+    unsigned int n_samples,
+    const unsigned int *slices,
+    unsigned int n_indices,
+    unsigned int max_mismatches,
+    unsigned int max_total_mismatches,
+    int *samples_out,
+    unsigned int *mismatches_out
+) {
+    unsigned int c = blockIdx.x * blockDim.x + threadIdx.x;
+    if (c >= n_clusters) {
+        return;
+    }
+
+    const unsigned char *read = index_reads + (size_t)c * n_cycles;
+    int found = -1;
+    unsigned int found_mismatches = 0;
+
+    for (unsigned int s = 0; s < n_samples && found < 0; s++) {
+        if (!usable[s]) {
+            continue;
+        }
+
+        const unsigned char *sample = sample_indices + (size_t)s * n_cycles;
+        unsigned int total = 0;
+        bool matched = true;
+        for (unsigned int k = 0; k < n_indices && matched; k++) {
+            unsigned int mismatches = 0;
+            for (unsigned int y = slices[2 * k]; y < slices[2 * k + 1]; y++) {
+                mismatches += sample[y] != read[y];
+            }
+            matched = mismatches <= max_mismatches;
+            total += mismatches;
+        }
+
+        if (matched) {
+            found = s;
+            found_mismatches = total;
+        }
+    }
+
+    if (found_mismatches > max_total_mismatches) {
+        found = -1;
+        found_mismatches = 0;
+    }
+
+    samples_out[c] = found;
+    mismatches_out[c] = found_mismatches;
+}
+"#;
+
+/// One GPU, with the kernels loaded
+pub(crate) struct Gpu {
+    device: Arc<CudaDevice>,
+    maps: CudaSlice<u8>,
+    unpack: CudaFunction,
+    match_barcodes: CudaFunction,
+    /// the threads using this GPU take turns with it
+    lock: Mutex<()>,
+}
+
+static GPUS: OnceLock<Vec<Gpu>> = OnceLock::new();
+
+/// Every GPU that we can use, which is none if there is no CUDA driver
+fn gpus() -> &'static [Gpu] {
+    GPUS.get_or_init(|| match Gpu::all() {
+        Ok(gpus) => {
+            info!(
+                "using {} GPU(s) to unpack bases and match barcodes",
+                gpus.len()
+            );
+            gpus
+        }
+        Err(e) => {
+            info!("can't use the GPU, falling back to the CPU: {}", e);
+            Vec::new()
+        }
+    })
+}
+
+/// The GPU for the current thread, or None if there isn't one that we can use.
+/// The threads of a pool are spread over the GPUs, so each GPU only makes its own
+/// threads wait
+pub(crate) fn gpu() -> Option<&'static Gpu> {
+    let gpus = gpus();
+    if gpus.is_empty() {
+        return None;
+    }
+
+    gpus.get(rayon::current_thread_index().unwrap_or(0) % gpus.len())
+}
+
+/// The position in the output of each cluster in a tile (two to a byte, the first
+/// cluster in the high bits), or `NO_POSITION` if it failed the filter
+fn cluster_positions(filter: &[u8]) -> Vec<u32> {
+    let mut positions = Vec::with_capacity(filter.len() * 2);
+    let mut next = 0;
+
+    for &f in filter {
+        for bit in [0b10, 0b01] {
+            if f & bit != 0 {
+                positions.push(next);
+                next += 1;
+            } else {
+                positions.push(NO_POSITION);
+            }
+        }
+    }
+
+    positions
+}
+
+/// The index sequences of every sample, each laid out at the cycles of the index
+/// reads, and whether every index is the same length as its cycles
+fn sample_table(
+    samples: &Samples,
+    n_cycles: usize,
+    index_slices: &[[usize; 2]],
+) -> (Vec<u8>, Vec<u8>) {
+    let n_samples = samples.sample_names.len();
+    let mut table = vec![0; n_samples * n_cycles];
+    let mut usable = vec![1; n_samples];
+
+    for sample_i in 0..n_samples {
+        for (index_i, &[i0, i1]) in index_slices.iter().enumerate() {
+            let index = samples.sample_index(index_i, sample_i);
+            if index.len() == i1 - i0 {
+                table[sample_i * n_cycles + i0..sample_i * n_cycles + i1].copy_from_slice(index);
+            } else {
+                usable[sample_i] = 0;
+            }
+        }
+    }
+
+    (table, usable)
+}
+
+impl Gpu {
+    /// Load the kernels on every GPU
+    fn all() -> Result<Vec<Gpu>, String> {
+        // cudarc panics if it can't find the driver library, so look for it first
+        unsafe { libloading::Library::new(libloading::library_filename("cuda")) }
+            .map_err(|e| format!("no CUDA driver: {}", e))?;
+
+        let count = std::panic::catch_unwind(CudaDevice::count)
+            .map_err(|_| "couldn't load CUDA".to_string())?
+            .map_err(|e| e.to_string())?;
+        if count < 1 {
+            return Err("no GPUs found".to_string());
+        }
+
+        let ptx = std::panic::catch_unwind(|| compile_ptx(KERNELS))
+            .map_err(|_| "couldn't load NVRTC".to_string())?
+            .map_err(|e| e.to_string())?;
+
+        (0..count as usize)
+            .map(|ordinal| Gpu::new(ordinal, ptx.clone()))
+            .collect()
+    }
+
+    fn new(ordinal: usize, ptx: Ptx) -> Result<Gpu, String> {
+        let device = std::panic::catch_unwind(|| CudaDevice::new(ordinal))
+            .map_err(|_| "couldn't load CUDA".to_string())?
+            .map_err(|e| e.to_string())?;
+        device
+            .load_ptx(ptx, MODULE, &["unpack", "match_barcodes"])
+            .map_err(|e| e.to_string())?;
+
+        let maps = device
+            .htod_sync_copy(&[B_MAP_10, B_MAP_01, Q_MAP_10, Q_MAP_01].concat())
+            .map_err(|e| e.to_string())?;
+
+        Ok(Gpu {
+            unpack: device.get_func(MODULE, "unpack").unwrap(),
+            match_barcodes: device.get_func(MODULE, "match_barcodes").unwrap(),
+            device,
+            maps,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Upload the cluster positions for a tile, which are the same for every cycle.
+    /// Returns None (and the tile is read on the CPU) if that fails
+    pub(crate) fn tile(&self, filter: &[u8], pf_filter: &[u8]) -> Option<GpuTile<'_>> {
+        let _lock = self.lock.lock().unwrap();
+
+        let upload = || -> Result<GpuTile<'_>, DriverError> {
+            Ok(GpuTile {
+                gpu: self,
+                positions: self.device.htod_sync_copy(&cluster_positions(filter))?,
+                pf_positions: self.device.htod_sync_copy(&cluster_positions(pf_filter))?,
+            })
+        };
+
+        upload()
+            .map_err(|e| warn!("error uploading a tile to the GPU: {}", e))
+            .ok()
+    }
+
+    /// Like `assign_reads`, but on the GPU. Returns None if the kernel fails
+    pub(crate) fn assign_reads(
+        &self,
+        samples: &Samples,
+        n_pf: usize,
+        index_array: &ArrayView3<u8>,
+        index_slices: &[[usize; 2]],
+    ) -> Option<Vec<Assignment>> {
+        let n_cycles = index_array.len_of(Axis(0));
+        // the bases of each cluster's index cycles, one cluster after another
+        let index_reads: Vec<u8> = index_array
+            .slice(ndarray::s![.., ..n_pf, 0])
+            .reversed_axes()
+            .iter()
+            .cloned()
+            .collect();
+        let (table, usable) = sample_table(samples, n_cycles, index_slices);
+        let slices: Vec<u32> = index_slices
+            .iter()
+            .flat_map(|&[i0, i1]| [i0 as u32, i1 as u32])
+            .collect();
+
+        let _lock = self.lock.lock().unwrap();
+        let run = || -> Result<(Vec<i32>, Vec<u32>), DriverError> {
+            let index_reads = self.device.htod_sync_copy(&index_reads)?;
+            let table = self.device.htod_sync_copy(&table)?;
+            let usable = self.device.htod_sync_copy(&usable)?;
+            let slices = self.device.htod_sync_copy(&slices)?;
+            let mut samples_out = self.device.alloc_zeros::<i32>(n_pf)?;
+            let mut mismatches_out = self.device.alloc_zeros::<u32>(n_pf)?;
+
+            unsafe {
+                self.match_barcodes.clone().launch(
+                    LaunchConfig::for_num_elems(n_pf as u32),
+                    (
+                        &index_reads,
+                        n_pf as u32,
+                        n_cycles as u32,
+                        &table,
+                        &usable,
+                        usable.len() as u32,
+                        &slices,
+                        index_slices.len() as u32,
+                        samples.distance() as u32,
+                        samples
+                            .max_total_mismatches()
+                            .map_or(u32::MAX, |m| m as u32),
+                        &mut samples_out,
+                        &mut mismatches_out,
+                    ),
+                )?;
+            }
+
+            Ok((
+                self.device.dtoh_sync_copy(&samples_out)?,
+                self.device.dtoh_sync_copy(&mismatches_out)?,
+            ))
+        };
+
+        match run() {
+            Ok((sample_is, mismatches)) => Some(
+                sample_is
+                    .into_iter()
+                    .zip(mismatches)
+                    .map(|(sample_i, mismatches)| {
+                        (sample_i >= 0).then_some((sample_i as usize, mismatches as usize))
+                    })
+                    .collect(),
+            ),
+            Err(e) => {
+                warn!("error matching barcodes on the GPU, using the CPU: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// A tile's cluster positions, on the GPU
+pub(crate) struct GpuTile<'a> {
+    gpu: &'a Gpu,
+    positions: CudaSlice<u32>,
+    pf_positions: CudaSlice<u32>,
+}
+
+impl GpuTile<'_> {
+    /// Like `extract_cbcl`, into a cycle of a cycle-major array, but unpacked on the
+    /// GPU. Returns None if the GPU fails, to extract the cycle on the CPU instead
+    pub(crate) fn extract_cbcl(
+        &self,
+        header: &CBCLHeader,
+        bq_cycle: &mut ArrayViewMut2<u8>,
+        tile_i: usize,
+    ) -> Option<io::Result<()>> {
+        let tile_bytes = match read_tile_bytes(header, tile_i) {
+            Ok(tile_bytes) => tile_bytes,
+            Err(e) => {
+                fill_missing(bq_cycle);
+                return Some(Err(e));
+            }
+        };
+        let positions = if header.non_pf_clusters_excluded {
+            &self.pf_positions
+        } else {
+            &self.positions
+        };
+        let n_bytes = tile_bytes.len().min(positions.len() / 2);
+        let output = bq_cycle.as_slice_mut()?;
+
+        let gpu = self.gpu;
+        let _lock = gpu.lock.lock().unwrap();
+        let mut run = || -> Result<(), DriverError> {
+            let tile_bytes = gpu.device.htod_sync_copy(&tile_bytes[..n_bytes])?;
+            let mut out = gpu.device.alloc_zeros::<u8>(output.len())?;
+
+            unsafe {
+                gpu.unpack.clone().launch(
+                    LaunchConfig::for_num_elems(n_bytes as u32),
+                    (&tile_bytes, positions, n_bytes as u32, &gpu.maps, &mut out),
+                )?;
+            }
+
+            gpu.device.dtoh_sync_copy_into(&out, output)
+        };
+
+        match run() {
+            Ok(()) => Some(Ok(())),
+            Err(e) => {
+                warn!("error unpacking a tile on the GPU, using the CPU: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use ndarray::{Array3, ShapeBuilder};
+
+    use crate::barcode_matcher::HammingMatcher;
+    use crate::extract_reads::extract_cbcls;
+    use crate::novaseq_run::NovaSeqRun;
+    use crate::sample_data::read_samplesheet;
+    use crate::write_fastq::{assign_reads, index_buffer, index_slices};
+
+    #[test]
+    fn positions() {
+        assert_eq!(
+            cluster_positions(&[0b11, 0b00, 0b01, 0b10]),
+            vec![
+                0,
+                1,
+                NO_POSITION,
+                NO_POSITION,
+                NO_POSITION,
+                2,
+                3,
+                NO_POSITION
+            ]
+        );
+    }
+
+    #[test]
+    fn samples() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let sample_data = read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+        let samples = &sample_data[&1];
+
+        // two 8bp indices, in 17 cycles
+        let (table, usable) = sample_table(samples, 17, &[[0, 8], [8, 16]]);
+        assert_eq!(&table[..16], b"ACTGCGAAGATTGTCC");
+        assert_eq!(table[16], 0);
+        assert!(usable.iter().all(|&u| u == 1));
+
+        let (_, usable) = sample_table(samples, 17, &[[0, 8], [8, 17]]);
+        assert!(usable.iter().all(|&u| u == 0));
+    }
+
+    /// the same as the CPU, if there's a GPU to run on
+    #[test]
+    fn gpu_matches_cpu() {
+        let gpu = match gpu() {
+            Some(gpu) => gpu,
+            None => return,
+        };
+
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sample_data = read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+        let headers = &novaseq_run.read_headers.get(&[1, 1]).unwrap()[0];
+        let filter = &novaseq_run.filters.get(&[1, 1]).unwrap()[0];
+        let pf_filter = &novaseq_run.pf_filters.get(&[1, 1]).unwrap()[0];
+        let n_pf = filter.iter().map(|&b| [0, 1, 1, 2][b as usize]).sum();
+
+        let extract = |use_gpu| {
+            let mut bq_array = Array3::zeros((headers.len(), n_pf, 2).f());
+            extract_cbcls(
+                headers,
+                filter,
+                pf_filter,
+                &mut bq_array.view_mut(),
+                0,
+                use_gpu,
+            );
+            bq_array
+        };
+        assert_eq!(extract(true), extract(false));
+
+        let idx_slices = index_slices(&novaseq_run);
+        let idx_headers = &novaseq_run.index_headers.get(&[1, 1]).unwrap();
+        let mut index_array = index_buffer(&novaseq_run, n_pf);
+        for (idx_vec, &[i0, i1]) in idx_headers.iter().zip(&idx_slices) {
+            let mut idx_array = index_array.slice_mut(ndarray::s![i0..i1, .., ..]);
+            extract_cbcls(idx_vec, filter, pf_filter, &mut idx_array, 0, false);
+        }
+
+        assert_eq!(
+            gpu.assign_reads(&sample_data[&1], n_pf, &index_array.view(), &idx_slices)
+                .unwrap(),
+            assign_reads(
+                &sample_data[&1],
+                &HammingMatcher,
+                n_pf,
+                &index_array.view(),
+                &idx_slices
+            )
+        );
+    }
+}
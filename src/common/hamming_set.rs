@@ -1,5 +1,7 @@
 //! Functions for building hamming sets (all strings within a given distance of a seed)
-//! and checking for conflicts between them
+//! and checking for conflicts between them. The strings near a seed can also be
+//! generated one at a time with [`neighbors`], over the ACGT or ACGTN alphabet or
+//! any other bases, e.g. for reads from a platform with its own no-call base
 
 use std::collections::HashSet;
 
@@ -7,43 +9,157 @@ use itertools::Itertools;
 use rayon::prelude::*;
 
 /// makes a single-element HashSet from a vector of bytes
+#[allow(clippy::ptr_arg)]
 pub fn singleton_set(index: &Vec<u8>) -> HashSet<Vec<u8>> {
     [index.clone()].iter().cloned().collect::<HashSet<_>>()
 }
 
-/// given a set of indices, return a new set of indices which include everything
-/// within hamming distance 1
-pub fn hamming_set(index_set: &HashSet<Vec<u8>>) -> HashSet<Vec<u8>> {
-    let nucleotides = [b'A', b'C', b'G', b'T', b'N'];
+/// The bases that an index base can be substituted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alphabet<'a> {
+    /// A, C, G and T
+    Acgt,
+    /// A, C, G, T and N, so an index also matches reads with an N in it, which is
+    /// what the demux uses
+    Acgtn,
+    /// the caller's bases. A base that is given twice is only substituted once
+    Custom(&'a [u8]),
+}
+
+impl<'a> Alphabet<'a> {
+    pub fn bases(self) -> &'a [u8] {
+        match self {
+            Alphabet::Acgt => b"ACGT",
+            Alphabet::Acgtn => b"ACGTN",
+            Alphabet::Custom(bases) => bases,
+        }
+    }
+}
+
+/// The number of positions where two sequences differ, or None if they aren't the
+/// same length
+pub fn hamming_distance<T: PartialEq>(a: &[T], b: &[T]) -> Option<usize> {
+    if a.len() != b.len() {
+        return None;
+    }
+
+    Some(a.iter().zip(b).filter(|(x, y)| x != y).count())
+}
+
+/// Every sequence within a hamming distance of an index, made from substitutions
+/// from an alphabet. Each is generated once, without a set to deduplicate them:
+/// the index itself, then every sequence at distance 1, then 2, and so on. Made by
+/// [`neighbors`]
+pub struct Neighbors {
+    index: Vec<u8>,
+    /// the bases that each position can be substituted with, all but its own
+    substitutes: Vec<Vec<u8>>,
+    max_distance: usize,
+    /// the positions that are substituted, in order
+    positions: Vec<usize>,
+    /// the substitute used at each of the positions
+    choices: Vec<usize>,
+    done: bool,
+}
 
-    let new_set: HashSet<_> = index_set
+/// Iterate over every sequence within `distance` substitutions of `index`, with
+/// the bases of `alphabet`
+pub fn neighbors(index: &[u8], distance: usize, alphabet: Alphabet) -> Neighbors {
+    let bases: Vec<u8> = alphabet.bases().iter().copied().unique().collect();
+    let substitutes = index
         .iter()
-        .cloned()
-        .map(|index| {
-            let mut this_set = HashSet::new();
-            for i in 0..index.len() {
-                for c in nucleotides.iter().cloned() {
-                    let mut new_index = index.clone();
-                    new_index[i] = c;
-                    this_set.insert(new_index);
-                }
-            }
-            this_set
-        })
-        .flatten()
+        .map(|b| bases.iter().copied().filter(|c| c != b).collect())
         .collect();
 
-    new_set
+    Neighbors {
+        index: index.to_vec(),
+        substitutes,
+        max_distance: distance.min(index.len()),
+        positions: Vec::new(),
+        choices: Vec::new(),
+        done: false,
+    }
+}
+
+impl Neighbors {
+    /// Move on to the next set of substitutes, then the next positions, then the
+    /// next distance
+    fn advance(&mut self) {
+        for i in (0..self.choices.len()).rev() {
+            self.choices[i] += 1;
+            if self.choices[i] < self.substitutes[self.positions[i]].len() {
+                return;
+            }
+            self.choices[i] = 0;
+        }
+
+        let (n, k) = (self.index.len(), self.positions.len());
+        if let Some(i) = (0..k).rev().find(|&i| self.positions[i] < n - k + i) {
+            self.positions[i] += 1;
+            for j in i + 1..k {
+                self.positions[j] = self.positions[j - 1] + 1;
+            }
+            return;
+        }
+
+        if k == self.max_distance {
+            self.done = true;
+        } else {
+            self.positions = (0..k + 1).collect();
+            self.choices = vec![0; k + 1];
+        }
+    }
+}
+
+impl Iterator for Neighbors {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        // a position with nothing to substitute can't be at any distance
+        while !self.done
+            && self
+                .positions
+                .iter()
+                .any(|&p| self.substitutes[p].is_empty())
+        {
+            self.advance();
+        }
+        if self.done {
+            return None;
+        }
+
+        let mut neighbor = self.index.clone();
+        for (&p, &c) in self.positions.iter().zip(&self.choices) {
+            neighbor[p] = self.substitutes[p][c];
+        }
+        self.advance();
+
+        Some(neighbor)
+    }
+}
+
+/// given a set of indices, return a new set of indices which include everything
+/// within `distance` substitutions from `alphabet`
+pub fn hamming_set(
+    index_set: &HashSet<Vec<u8>>,
+    distance: usize,
+    alphabet: Alphabet,
+) -> HashSet<Vec<u8>> {
+    index_set
+        .iter()
+        .flat_map(|index| neighbors(index, distance, alphabet))
+        .collect()
 }
 
 /// Function to check for overlaps between the sets of sample indices. If there are
 /// two indices, then an overlap between one is allowed as long as the second index
-/// is sufficient to distinguish them.
-pub fn check_conflict(
-    sample_names: &[String],
+/// is sufficient to distinguish them. Returns the pairs of samples that overlap,
+/// sorted by name
+pub fn conflicting_samples<'a>(
+    sample_names: &'a [String],
     index_sets: &[HashSet<Vec<u8>>],
     index2_sets: &[HashSet<Vec<u8>>],
-) -> bool {
+) -> Vec<(&'a String, &'a String)> {
     let sample_clash: HashSet<_> = sample_names
         .iter()
         .zip(index_sets.iter())
@@ -58,33 +174,28 @@ pub fn check_conflict(
         })
         .collect();
 
-    if index2_sets.len() == 0 {
-        if sample_clash.len() > 0 {
-            return true;
-        } else {
-            return false;
-        }
-    }
+    let mut clashes: Vec<_> = if index2_sets.is_empty() {
+        sample_clash.into_iter().collect()
+    } else {
+        let sample_clash2: HashSet<_> = sample_names
+            .iter()
+            .zip(index2_sets.iter())
+            .tuple_combinations()
+            .par_bridge()
+            .filter_map(|((s1, hset1), (s2, hset2))| {
+                if s1 != s2 && hset1.intersection(hset2).count() > 0 {
+                    Some((std::cmp::min(s1, s2), std::cmp::max(s1, s2)))
+                } else {
+                    None
+                }
+            })
+            .collect();
 
-    let sample_clash2: HashSet<_> = sample_names
-        .iter()
-        .zip(index2_sets.iter())
-        .tuple_combinations()
-        .par_bridge()
-        .filter_map(|((s1, hset1), (s2, hset2))| {
-            if s1 != s2 && hset1.intersection(hset2).count() > 0 {
-                Some((std::cmp::min(s1, s2), std::cmp::max(s1, s2)))
-            } else {
-                None
-            }
-        })
-        .collect();
+        sample_clash.intersection(&sample_clash2).cloned().collect()
+    };
+    clashes.sort_unstable();
 
-    if sample_clash.intersection(&sample_clash2).count() > 0 {
-        return true;
-    } else {
-        return false;
-    }
+    clashes
 }
 
 #[cfg(test)]
@@ -104,7 +215,7 @@ mod test {
     #[test]
     fn hamming_set_distance_1() {
         let initial_set = super::singleton_set(&b"ACTGCGAA".to_vec());
-        let actual_hammingset = hamming_set(&initial_set);
+        let actual_hammingset = hamming_set(&initial_set, 1, Alphabet::Acgtn);
 
         let test_contents = fs::read_to_string("test_data/hamming_distance_1_test.txt").unwrap();
 
@@ -119,7 +230,7 @@ mod test {
     #[test]
     fn hamming_set_distance_2() {
         let initial_set = super::singleton_set(&b"ACTGCGAA".to_vec());
-        let actual_hammingset = hamming_set(&hamming_set(&initial_set));
+        let actual_hammingset = hamming_set(&initial_set, 2, Alphabet::Acgtn);
 
         let test_contents = fs::read_to_string("test_data/hamming_distance_2_test.txt").unwrap();
 
@@ -131,6 +242,55 @@ mod test {
         assert_eq!(actual_hammingset, expected_hammingset);
     }
 
+    #[test]
+    fn generate_neighbors() {
+        // each sequence once, the nearest first
+        let generated: Vec<_> = neighbors(b"ACG", 3, Alphabet::Acgt).collect();
+        assert_eq!(generated.len(), 4 * 4 * 4);
+        assert_eq!(
+            generated.iter().collect::<HashSet<_>>().len(),
+            generated.len()
+        );
+        assert_eq!(generated[0], b"ACG");
+        assert_eq!(generated[1], b"CCG");
+        let distances: Vec<_> = generated
+            .iter()
+            .map(|n| hamming_distance(n, b"ACG").unwrap())
+            .collect();
+        assert!(distances.windows(2).all(|d| d[0] <= d[1]));
+
+        // 1 + 8 * 4 at distance 1, and C(8, 2) * 4 * 4 at distance 2
+        assert_eq!(neighbors(b"ACTGCGAA", 1, Alphabet::Acgtn).count(), 33);
+        assert_eq!(
+            neighbors(b"ACTGCGAA", 2, Alphabet::Acgtn).count(),
+            33 + 28 * 16
+        );
+        // without N, an N in the index can be any of the four bases
+        assert_eq!(neighbors(b"AN", 1, Alphabet::Acgt).count(), 1 + 3 + 4);
+        assert_eq!(neighbors(b"", 2, Alphabet::Acgt).count(), 1);
+
+        // the caller's own bases, given twice or not
+        let generated: Vec<_> = neighbors(b"AC", 1, Alphabet::Custom(b"ACA.")).collect();
+        assert_eq!(
+            generated,
+            vec![
+                b"AC".to_vec(),
+                b"CC".to_vec(),
+                b".C".to_vec(),
+                b"AA".to_vec(),
+                b"A.".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn distances() {
+        assert_eq!(hamming_distance(b"ACGT", b"ACGT"), Some(0));
+        assert_eq!(hamming_distance(b"ACGT", b"TCGA"), Some(2));
+        assert_eq!(hamming_distance(b"ACGT", b"ACG"), None);
+        assert_eq!(hamming_distance(&[1, 2, 3], &[1, 2, 4]), Some(1));
+    }
+
     #[test]
     fn hamming_conflicts() {
         let sample_names = vec!["sample_1".to_string(), "sample_2".to_string()];
@@ -139,12 +299,28 @@ mod test {
         let index2 = super::singleton_set(&b"ACTGCGAT".to_vec());
         let index3 = super::singleton_set(&b"ACTGCCTT".to_vec());
 
-        let hammingset1 = &[hamming_set(&index1), hamming_set(&index2)];
-        let hammingset2 = &[hamming_set(&index2), hamming_set(&index3)];
-        let hammingset3 = &[hamming_set(&index1), hamming_set(&index3)];
+        let hammingset1 = &[
+            hamming_set(&index1, 1, Alphabet::Acgtn),
+            hamming_set(&index2, 1, Alphabet::Acgtn),
+        ];
+        let hammingset2 = &[
+            hamming_set(&index2, 1, Alphabet::Acgtn),
+            hamming_set(&index3, 1, Alphabet::Acgtn),
+        ];
+        let hammingset3 = &[
+            hamming_set(&index1, 1, Alphabet::Acgtn),
+            hamming_set(&index3, 1, Alphabet::Acgtn),
+        ];
+
+        assert!(!conflicting_samples(&sample_names, hammingset1, &[]).is_empty());
+        assert!(!conflicting_samples(&sample_names, hammingset2, &[]).is_empty());
+        assert!(conflicting_samples(&sample_names, hammingset3, &[]).is_empty());
 
-        assert!(check_conflict(&sample_names, hammingset1, &[]));
-        assert!(check_conflict(&sample_names, hammingset2, &[]));
-        assert!(!check_conflict(&sample_names, hammingset3, &[]));
+        assert_eq!(
+            conflicting_samples(&sample_names, hammingset1, &[]),
+            [(&sample_names[0], &sample_names[1])]
+        );
+        // the second index tells them apart
+        assert!(conflicting_samples(&sample_names, hammingset1, hammingset3).is_empty());
     }
 }
@@ -0,0 +1,187 @@
+//! A cache of the index reads of each tile, so that a demux can be run again with a
+//! corrected samplesheet without decoding the index cycles a second time. Each tile
+//! is a gzipped file with the number of index rows and clusters, then the bases and
+//! quality scores of the index rows for each cluster in turn. The tiles are named
+//! with a key for the run, its read structure and the index truncation, so a cache
+//! shared by several runs, or kept across a change to the read structure, never
+//! gives a tile another run's indices
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use ndarray::{ArrayView3, ArrayViewMut3, Axis};
+use ring::digest;
+
+use crate::novaseq_run::NovaSeqRun;
+
+/// The key for the tiles of `novaseq_run` in the cache: the start of a SHA-256 of
+/// the run's id and read header prefix, its read structure, and whether the
+/// indices are truncated to the samplesheet's
+pub(crate) fn cache_key(novaseq_run: &NovaSeqRun, truncate_indices: bool) -> String {
+    let fingerprint = format!(
+        "{}\n{}\n{}\n{}",
+        novaseq_run.run_info.id, novaseq_run.run_id, novaseq_run.read_structure, truncate_indices
+    );
+
+    digest::digest(&digest::SHA256, fingerprint.as_bytes()).as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The cache file for a tile, for runs with the key `key`
+pub(crate) fn tile_path(
+    cache_path: &Path,
+    key: &str,
+    lane: usize,
+    surface: usize,
+    tile: u32,
+) -> PathBuf {
+    cache_path.join(format!("{}_L{:03}_{}_{}.idx.gz", key, lane, surface, tile))
+}
+
+/// Write the index rows for the clusters of a tile. The file is written under
+/// another name first, so a demux that stops part way never leaves half a tile
+pub(crate) fn write_tile(path: &Path, idx_array: &ArrayView3<u8>) -> io::Result<()> {
+    let (n_rows, n_clusters, _) = idx_array.dim();
+    let tmp_path = path.with_extension("tmp");
+
+    let mut gz = GzEncoder::new(
+        BufWriter::new(File::create(&tmp_path)?),
+        Compression::fast(),
+    );
+    gz.write_u32::<LittleEndian>(n_rows as u32)?;
+    gz.write_u32::<LittleEndian>(n_clusters as u32)?;
+
+    let mut cluster_bytes = Vec::with_capacity(2 * n_rows);
+    for cluster in idx_array.axis_iter(Axis(1)) {
+        cluster_bytes.clear();
+        cluster_bytes.extend(cluster.column(0));
+        cluster_bytes.extend(cluster.column(1));
+        gz.write_all(&cluster_bytes)?;
+    }
+    gz.finish()?.flush()?;
+
+    fs::rename(tmp_path, path)
+}
+
+/// Read a tile's index rows into `idx_array`. With `keep`, only the clusters where it
+/// is true are read, e.g. the ones that were assigned to a sample
+pub(crate) fn read_tile(
+    path: &Path,
+    idx_array: &mut ArrayViewMut3<u8>,
+    keep: Option<&[bool]>,
+) -> io::Result<()> {
+    let (n_rows, n_clusters, _) = idx_array.dim();
+    let invalid = |message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), message),
+        )
+    };
+
+    let mut gz = MultiGzDecoder::new(BufReader::new(File::open(path)?));
+    let cached_rows = gz.read_u32::<LittleEndian>()? as usize;
+    let cached_clusters = gz.read_u32::<LittleEndian>()? as usize;
+
+    if cached_rows != n_rows {
+        return Err(invalid(format!(
+            "cached {} index rows, but the run has {}",
+            cached_rows, n_rows
+        )));
+    }
+    let n_kept = keep.map_or(cached_clusters, |k| k.iter().filter(|&&k| k).count());
+    if keep.is_some_and(|k| k.len() != cached_clusters) || n_kept != n_clusters {
+        return Err(invalid(format!(
+            "cached {} clusters, which doesn't match the tile",
+            cached_clusters
+        )));
+    }
+
+    let mut cluster_bytes = vec![0; 2 * n_rows];
+    let mut clusters = idx_array.axis_iter_mut(Axis(1));
+    for cluster_i in 0..cached_clusters {
+        gz.read_exact(&mut cluster_bytes)?;
+        if keep.is_some_and(|k| !k[cluster_i]) {
+            continue;
+        }
+
+        let mut cluster = clusters.next().unwrap();
+        for (plane, bytes) in cluster_bytes.chunks_exact(n_rows).enumerate() {
+            cluster
+                .column_mut(plane)
+                .iter_mut()
+                .zip(bytes)
+                .for_each(|(b, &c)| *b = c);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_structure::ReadStructure;
+    use ndarray::{s, Array3, ShapeBuilder};
+
+    #[test]
+    fn round_trip() {
+        let cache_path = Path::new("test_data/test_output/index_cache");
+        fs::create_dir_all(cache_path).unwrap();
+        let path = tile_path(cache_path, "0123abcd", 1, 2, 2101);
+        assert_eq!(path, cache_path.join("0123abcd_L001_2_2101.idx.gz"));
+
+        let mut idx_array = Array3::zeros((3, 4, 2).f());
+        for (i, b) in idx_array.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        write_tile(&path, &idx_array.view()).unwrap();
+
+        let mut cached = Array3::zeros((3, 4, 2).f());
+        read_tile(&path, &mut cached.view_mut(), None).unwrap();
+        assert_eq!(cached, idx_array);
+
+        // only the second and fourth clusters
+        let mut kept = Array3::zeros((3, 2, 2).f());
+        read_tile(
+            &path,
+            &mut kept.view_mut(),
+            Some(&[false, true, false, true]),
+        )
+        .unwrap();
+        assert_eq!(kept.slice(s![.., 0, ..]), idx_array.slice(s![.., 1, ..]));
+        assert_eq!(kept.slice(s![.., 1, ..]), idx_array.slice(s![.., 3, ..]));
+
+        let mut too_few = Array3::zeros((3, 3, 2).f());
+        let e = read_tile(&path, &mut too_few.view_mut(), None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn cache_keys() {
+        let run_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let mut novaseq_run = NovaSeqRun::read_path(run_path.to_path_buf(), false).unwrap();
+
+        let key = cache_key(&novaseq_run, true);
+        assert_eq!(key.len(), 16);
+        assert_eq!(cache_key(&novaseq_run, true), key);
+        assert_ne!(cache_key(&novaseq_run, false), key);
+
+        // another read structure for the same run
+        let n_reads = novaseq_run.run_info.reads.len();
+        let read_structure = novaseq_run.read_structure.clone();
+        novaseq_run.read_structure = ReadStructure::new(&novaseq_run.run_info.reads[..n_reads - 1]);
+        assert_ne!(cache_key(&novaseq_run, true), key);
+
+        // or another run
+        novaseq_run.read_structure = read_structure;
+        novaseq_run.run_info.id.push('2');
+        assert_ne!(cache_key(&novaseq_run, true), key);
+    }
+}
@@ -8,10 +8,10 @@ use rayon::prelude::*;
 use ndarray::{Array3, Axis, ShapeBuilder};
 
 use crate::cbcl_header_decoder::CBCLHeader;
-use crate::extract_reads::extract_cbcl;
+use crate::extract_reads::{extract_cbcl, TileCompaction};
 use crate::novaseq_run::NovaSeqRun;
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
 fn count_tile_chunk(
     tile_i: usize,
@@ -30,6 +30,7 @@ fn count_tile_chunk(
             .fill(b'+');
     }
 
+    let compaction = TileCompaction::new(filter, pf_filter);
     let mut j = 0;
     for idx_vec in headers {
         let mut idx_array = index_array.slice_mut(ndarray::s![j..j + idx_vec.len(), ..n_pf, ..]);
@@ -38,14 +39,11 @@ fn count_tile_chunk(
         for (mut byte_array, idx_h) in idx_array.axis_iter_mut(Axis(0)).zip(idx_vec) {
             extract_cbcl(
                 idx_h,
-                if idx_h.non_pf_clusters_excluded {
-                    &pf_filter
-                } else {
-                    &filter
-                },
+                compaction.for_header(idx_h),
                 &mut byte_array.slice_mut(ndarray::s![..n_pf, ..]),
                 tile_i,
             )
+            .unwrap_or_else(|e| warn!("error reading {}: {}", idx_h.cbcl_path.display(), e));
         }
     }
 
@@ -63,25 +61,16 @@ fn count_tile_chunk(
         .collect()
 }
 
-/// Iterate through all lanes and surfaces and count indexes
-pub fn index_count(
-    novaseq_run: &NovaSeqRun,
-    output_path: PathBuf,
-    top_n_counts: usize,
-) -> Result<(), &'static str> {
-    info!("writing to {}", output_path.display());
-    let mut out_file = match File::create(output_path) {
-        Ok(out_file) => out_file,
-        Err(e) => panic!("Error creating file: {}", e),
-    };
-
+/// Iterate through all lanes and surfaces and count indexes, returning the
+/// `top_n_counts` most common index sequences (joined with '+') and their counts
+pub fn count_indexes(novaseq_run: &NovaSeqRun, top_n_counts: usize) -> Vec<(Vec<u8>, usize)> {
     let top_8n_counts = top_n_counts * 8;
     let mut counts: Counter<Vec<u8>> = Counter::new();
 
     info!("Counting indexes");
     for lane in 1..=novaseq_run.run_info.flowcell_layout.lane_count {
         debug!("Starting lane {}", lane);
-        for surface in novaseq_run.run_info.flowcell_layout.surface_range.clone() {
+        for surface in novaseq_run.run_info.flowcell_layout.surfaces(lane) {
             debug!("Starting surface {}", surface);
 
             let filters = novaseq_run.filters.get(&[lane, surface]).unwrap();
@@ -105,18 +94,40 @@ pub fn index_count(
         debug!("Lane {} complete", lane);
     }
 
+    counts
+        .most_common_ordered()
+        .into_iter()
+        .take(top_n_counts)
+        .collect()
+}
+
+/// Count the indexes in a run and write the top `top_n_counts` to a text file
+pub fn index_count(
+    novaseq_run: &NovaSeqRun,
+    output_path: PathBuf,
+    top_n_counts: usize,
+) -> std::io::Result<()> {
+    info!("writing to {}", output_path.display());
+    let mut out_file = File::create(&output_path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Error creating {}: {}", output_path.display(), e),
+        )
+    })?;
+
+    let counts = count_indexes(novaseq_run, top_n_counts);
+
     debug!("Writing counts");
-    for (elem, freq) in counts.most_common_ordered().iter().take(top_n_counts) {
+    for (elem, freq) in counts.iter() {
         writeln!(
             &mut out_file,
             "{}\t{}",
             unsafe { String::from_utf8_unchecked(elem.to_vec()) },
             freq
-        )
-        .unwrap();
+        )?;
     }
 
-    out_file.flush().unwrap();
+    out_file.flush()?;
 
     Ok(())
 }
@@ -136,12 +147,15 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = r#"No such file or directory"#)]
     fn index_count_bad_path() {
         let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
         let output_path = PathBuf::from("test_data/wrong_test_output/index_counts.txt");
         let novaseq_run = NovaSeqRun::read_path(run_path, true).unwrap();
 
-        super::index_count(&novaseq_run, output_path, 384).unwrap()
+        let e = super::index_count(&novaseq_run, output_path, 384).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert!(e
+            .to_string()
+            .contains("test_data/wrong_test_output/index_counts.txt"));
     }
 }
@@ -1,13 +1,91 @@
+//! Demultiplexes Illumina runs from CBCL files into fastq files for each sample.
+//!
+//! The `bcl2fastr` binary is a command line wrapper around this library. To
+//! demultiplex a run from another Rust program, set it up with a [`DemuxBuilder`]:
+//!
+//! ```no_run
+//! use common::DemuxBuilder;
+//!
+//! let demux = DemuxBuilder::new("/runs/my_run", "/runs/my_run/SampleSheet.csv", "/out")
+//!     .mismatch(1)
+//!     .build()?;
+//! let lane_stats = demux.run()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! To use the reads in-process instead of writing fastq files, build the demux
+//! with [`DemuxBuilder::for_reads`] and iterate over [`Demux::reads`], which
+//! decodes a tile at a time as the reads are consumed.
+//!
+//! The stages are also available separately: [`novaseq_run::NovaSeqRun`] loads the
+//! run, [`sample_data::read_oriented_samplesheet`] compiles the samplesheet into
+//! index lookups, [`write_fastq::dump_tile`] extracts a single tile, and
+//! [`demux::demux_lanes`] writes the fastq files for each lane. For other index
+//! tooling, [`hamming_set`] generates the sequences within a hamming distance of an
+//! index, over the ACGT or ACGTN alphabet.
+//!
+//! With the `python` feature, the same steps are available from Python as the
+//! `bcl2fastr` package (see `pyproject.toml`).
+
 mod base_decoder;
-mod cbcl_header_decoder;
+mod compression_workers;
 mod extract_reads;
 mod filter_decoder;
-mod hamming_set;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod index_cache;
 mod locs_decoder;
+mod metadata_cache;
+mod read_name;
 mod run_info_parser;
+mod sequencer;
 
+pub mod barcode_hints;
+pub mod barcode_matcher;
+pub mod bench;
+pub mod buffer_pool;
+pub mod cbcl_header_decoder;
+pub mod compare;
+pub mod config;
+pub mod delivery;
+pub mod demux;
+pub mod demux_reads;
+pub mod file_limit;
+pub mod genrun;
+pub mod hamming_set;
+pub mod loading;
+pub mod logging;
+pub mod merge;
+pub mod metrics;
 pub mod novaseq_run;
+pub mod numa;
+pub mod output_sink;
+pub mod overlap;
+pub mod plan;
+pub mod progress;
+pub mod provenance;
+#[cfg(feature = "python")]
+mod python;
+pub mod read_structure;
+pub mod reports;
+pub mod run_folder;
+pub mod run_parameters_parser;
+pub mod run_summary;
 pub mod sample_data;
+pub mod screen;
+pub mod sheet_reads;
+pub mod shutdown;
+pub mod stats;
+pub mod storage;
+pub mod thread_pools;
+pub mod umi;
+pub mod verify;
+pub mod watch;
+pub mod webhook;
+pub mod zstd_dictionary;
 
 pub mod index_count;
 pub mod write_fastq;
+
+pub use demux::{Demux, DemuxBuilder};
+pub use demux_reads::{DemuxRead, DemuxReads};
@@ -0,0 +1,206 @@
+//! How well each lane of the flowcell was loaded: the fraction of clusters that
+//! passed filter, from the filter files, and the fraction of wells that were
+//! occupied, from `InterOp/ExtendedTileMetricsOut.bin` if the run has it. Demux is
+//! often where a badly loaded flowcell is first noticed, so these go in the run
+//! summary
+
+use std::{collections::HashMap, io::Read, path::Path};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use log::warn;
+use serde::Serialize;
+
+use crate::novaseq_run::NovaSeqRun;
+
+/// The InterOp file with the number of occupied wells in each tile
+pub const EXTENDED_TILE_METRICS: &str = "InterOp/ExtendedTileMetricsOut.bin";
+
+/// The pass-filter rate and occupancy of one tile
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TileLoading {
+    pub tile: u32,
+    pub clusters_raw: u64,
+    pub clusters_pf: u64,
+    pub pf_fraction: f64,
+    /// the fraction of wells with a cluster, if the run has extended tile metrics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occupancy: Option<f64>,
+}
+
+/// The pass-filter rate and occupancy of a lane, and of each of its tiles
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LaneLoading {
+    pub lane: usize,
+    pub clusters_raw: u64,
+    pub clusters_pf: u64,
+    pub pf_fraction: f64,
+    /// the occupancy of the tiles that have extended tile metrics, if any do
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occupancy: Option<f64>,
+    pub tiles: Vec<TileLoading>,
+}
+
+fn fraction(n: f64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n / total as f64
+    }
+}
+
+/// Decode an `ExtendedTileMetricsOut.bin` (version 3) into the number of occupied
+/// wells in each (lane, tile)
+pub fn extended_tile_metrics<R: Read>(mut rdr: R) -> std::io::Result<HashMap<(usize, u32), f64>> {
+    let version = rdr.read_u8()?;
+    if version != 3 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unsupported extended tile metrics version {}", version),
+        ));
+    }
+    let record_size = rdr.read_u8()? as usize;
+    if record_size < 10 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("extended tile metrics records of {} bytes", record_size),
+        ));
+    }
+
+    let mut bytes = Vec::new();
+    rdr.read_to_end(&mut bytes)?;
+
+    // the later fields (the fiducial positions) aren't needed
+    let mut occupied = HashMap::new();
+    for mut record in bytes.chunks_exact(record_size) {
+        let lane = record.read_u16::<LittleEndian>()? as usize;
+        let tile = record.read_u32::<LittleEndian>()?;
+        let n_occupied = record.read_f32::<LittleEndian>()?;
+        occupied.insert((lane, tile), n_occupied as f64);
+    }
+
+    Ok(occupied)
+}
+
+/// The loading of each lane of the run that is loaded, in lane order. Occupancy is
+/// only filled in if the run has extended tile metrics that can be read
+pub fn lane_loading(novaseq_run: &NovaSeqRun) -> Vec<LaneLoading> {
+    let occupied = match novaseq_run.open_file(Path::new(EXTENDED_TILE_METRICS)) {
+        Ok(file) => match extended_tile_metrics(file) {
+            Ok(occupied) => occupied,
+            Err(e) => {
+                warn!("Couldn't read {}: {}", EXTENDED_TILE_METRICS, e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    };
+
+    let mut keys: Vec<_> = novaseq_run.tile_ids.keys().cloned().collect();
+    keys.sort_unstable();
+
+    let mut lanes: Vec<LaneLoading> = Vec::new();
+    for [lane, surface] in keys {
+        // with the non-PF clusters included, the real filters are kept aside
+        let filters = novaseq_run
+            .pf_flags
+            .get(&[lane, surface])
+            .unwrap_or(&novaseq_run.filters[&[lane, surface]]);
+
+        let tiles = novaseq_run.tile_ids[&[lane, surface]]
+            .iter()
+            .zip(filters)
+            .enumerate()
+            .map(|(tile_i, (&tile, filter))| {
+                let clusters_raw = novaseq_run.locs_for([lane, surface], tile_i).len() as u64;
+                let clusters_pf: u64 = filter.iter().map(|&b| [0, 1, 1, 2][b as usize]).sum();
+
+                TileLoading {
+                    tile,
+                    clusters_raw,
+                    clusters_pf,
+                    pf_fraction: fraction(clusters_pf as f64, clusters_raw),
+                    occupancy: occupied
+                        .get(&(lane, tile))
+                        .map(|&n| fraction(n, clusters_raw)),
+                }
+            });
+
+        match lanes.last_mut() {
+            Some(lane_loading) if lane_loading.lane == lane => lane_loading.tiles.extend(tiles),
+            _ => lanes.push(LaneLoading {
+                lane,
+                clusters_raw: 0,
+                clusters_pf: 0,
+                pf_fraction: 0.0,
+                occupancy: None,
+                tiles: tiles.collect(),
+            }),
+        }
+    }
+
+    for lane_loading in lanes.iter_mut() {
+        lane_loading.tiles.sort_unstable_by_key(|t| t.tile);
+        lane_loading.clusters_raw = lane_loading.tiles.iter().map(|t| t.clusters_raw).sum();
+        lane_loading.clusters_pf = lane_loading.tiles.iter().map(|t| t.clusters_pf).sum();
+        lane_loading.pf_fraction =
+            fraction(lane_loading.clusters_pf as f64, lane_loading.clusters_raw);
+
+        let with_metrics: Vec<_> = lane_loading
+            .tiles
+            .iter()
+            .filter(|t| t.occupancy.is_some())
+            .collect();
+        if !with_metrics.is_empty() {
+            let n_occupied: f64 = with_metrics
+                .iter()
+                .map(|t| t.occupancy.unwrap() * t.clusters_raw as f64)
+                .sum();
+            let clusters_raw = with_metrics.iter().map(|t| t.clusters_raw).sum();
+            lane_loading.occupancy = Some(fraction(n_occupied, clusters_raw));
+        }
+    }
+
+    lanes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::path::PathBuf;
+
+    #[test]
+    fn extended_tile_metrics() {
+        let mut bytes = vec![3, 10];
+        for (tile, n_occupied) in [(1101u32, 80.0f32), (1102, 90.0)].iter() {
+            bytes.write_u16::<LittleEndian>(1).unwrap();
+            bytes.write_u32::<LittleEndian>(*tile).unwrap();
+            bytes.write_f32::<LittleEndian>(*n_occupied).unwrap();
+        }
+
+        let occupied = super::extended_tile_metrics(&bytes[..]).unwrap();
+        assert_eq!(occupied[&(1, 1101)], 80.0);
+        assert_eq!(occupied[&(1, 1102)], 90.0);
+
+        assert!(super::extended_tile_metrics(&[2u8, 10][..]).is_err());
+    }
+
+    #[test]
+    fn lane_loading() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+
+        let lanes = super::lane_loading(&novaseq_run);
+        assert_eq!(lanes.len(), 1);
+        let lane = &lanes[0];
+        assert_eq!((lane.clusters_raw, lane.clusters_pf), (300, 245));
+        assert!((lane.pf_fraction - 245.0 / 300.0).abs() < 1e-9);
+        // the test run has no InterOp folder
+        assert_eq!(lane.occupancy, None);
+        assert_eq!(
+            lane.tiles.iter().map(|t| t.tile).collect::<Vec<_>>(),
+            [1101, 1102, 1103]
+        );
+        assert_eq!(lane.tiles.iter().map(|t| t.clusters_raw).sum::<u64>(), 300);
+    }
+}
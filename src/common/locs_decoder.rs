@@ -2,12 +2,15 @@
 //! scaled integer value that fastq headers have.
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::{fs::File, path::Path};
+use std::io::Read;
+
+use crate::storage::gunzip_if_compressed;
 
 /// Each element is an array of [x, y] locations, one for each cluster in a tile
 pub type Locs = Vec<[u32; 2]>;
 
-/// Decode a `.locs` file into a `Locs` struct or panic
+/// Decode a `.locs` file, from a local file or object storage, into a `Locs` struct or panic.
+/// The file can be gzipped
 ///
 /// Format of a `.locs` file:
 ///  1. Two `u32` containing header info (ignored)
@@ -16,9 +19,8 @@ pub type Locs = Vec<[u32; 2]>;
 ///
 /// To go from f32 to the integer coordinates bcl2fastq outputs, we use the conversion
 /// round((v as f64) * 10. + 1000.) as u32
-pub fn locs_decoder(locs_path: &Path) -> std::io::Result<Locs> {
-    let mut rdr = File::open(locs_path)?;
-
+pub fn locs_decoder<R: Read>(rdr: R) -> std::io::Result<Locs> {
+    let mut rdr = gunzip_if_compressed(rdr)?;
     let _ = rdr.read_u64::<LittleEndian>()?;
 
     let num_clusters = rdr.read_u32::<LittleEndian>()? as usize;
@@ -43,12 +45,26 @@ pub fn locs_decoder(locs_path: &Path) -> std::io::Result<Locs> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{fs::File, path::Path};
+
+    fn decode_path(path: &Path) -> std::io::Result<Locs> {
+        locs_decoder(File::open(path)?)
+    }
+
+    /// the bytes of a file, gzipped as in an archived run
+    fn gzip_file(path: &Path) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&std::fs::read(path).unwrap()).unwrap();
+        gz.finish().unwrap()
+    }
 
     #[test]
     fn decode() {
         let test_file =
             Path::new("test_data/190414_A00111_0296_AHJCWWDSXX/Data/Intensities/s.locs");
-        let actual_locs = locs_decoder(test_file).unwrap();
+        let actual_locs = decode_path(test_file).unwrap();
         #[rustfmt::skip]
         let expected_locs = vec![
             [1000, 1000], [1018, 1000], [1036, 1000], [1054, 1000], [1072, 1000],
@@ -75,31 +91,42 @@ mod tests {
         assert_eq!(actual_locs, expected_locs)
     }
 
+    #[test]
+    fn decode_gzipped() {
+        let test_file =
+            Path::new("test_data/190414_A00111_0296_AHJCWWDSXX/Data/Intensities/s.locs");
+        let gzipped = gzip_file(test_file);
+        assert_eq!(
+            locs_decoder(&gzipped[..]).unwrap(),
+            decode_path(test_file).unwrap()
+        );
+    }
+
     #[test]
     #[should_panic(expected = r#"No such file or directory"#)]
     fn no_file() {
         let test_file = Path::new("test_data/no_file.locs");
-        locs_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 
     #[test]
     #[should_panic(expected = r#"failed to fill whole buffer"#)]
     fn empty_file() {
         let test_file = Path::new("test_data/empty_file");
-        locs_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 
     #[test]
     #[should_panic(expected = r#"failed to fill whole buffer"#)]
     fn bad_8_bytes() {
         let test_file = Path::new("test_data/bad_data_8.bin");
-        locs_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 
     #[test]
     #[should_panic(expected = r#"failed to fill whole buffer"#)]
     fn bad_12_bytes() {
         let test_file = Path::new("test_data/bad_data_12.bin");
-        locs_decoder(test_file).unwrap();
+        decode_path(test_file).unwrap();
     }
 }
@@ -0,0 +1,387 @@
+//! A logger for all of the binaries, which writes to stderr either as plain text
+//! or as one JSON object per line. Log levels can be set per module, and JSON lines
+//! include context fields (e.g. the run and lane being processed) so that they can
+//! be filtered after ingestion
+
+use std::{cell::Cell, collections::BTreeMap, io::Write, str::FromStr, sync::Mutex};
+
+use chrono::{Local, SecondsFormat};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{json, Value};
+
+/// Fields that are added to JSON log lines, e.g. the current lane
+pub struct LogContext {
+    fields: Mutex<BTreeMap<&'static str, String>>,
+}
+
+impl LogContext {
+    pub const fn new() -> LogContext {
+        LogContext {
+            fields: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Default for LogContext {
+    fn default() -> Self {
+        LogContext::new()
+    }
+}
+
+/// The fields for the whole process, e.g. the run
+static CONTEXT: LogContext = LogContext::new();
+
+thread_local! {
+    /// The context for this thread, if it has its own (see `set_thread_context`)
+    static THREAD_CONTEXT: Cell<Option<&'static LogContext>> = const { Cell::new(None) };
+}
+
+/// The context that `set_context` changes on the current thread: the one from
+/// `set_thread_context`, or else the one for the whole process
+fn context() -> &'static LogContext {
+    THREAD_CONTEXT.with(|c| c.get()).unwrap_or(&CONTEXT)
+}
+
+/// Keep the context fields set on this thread apart from the rest of the process,
+/// e.g. so that the threads of a NUMA node tag their lines with the node's lane
+/// while the other nodes work on theirs. Lines from the thread still have the
+/// fields of the whole process, unless they are set again here
+pub fn set_thread_context(context: &'static LogContext) {
+    THREAD_CONTEXT.with(|c| c.set(Some(context)));
+}
+
+/// Set a context field for all subsequent log lines from this thread's context
+pub fn set_context<T: ToString>(key: &'static str, value: T) {
+    context()
+        .fields
+        .lock()
+        .unwrap()
+        .insert(key, value.to_string());
+}
+
+/// Remove a context field
+pub fn clear_context(key: &'static str) {
+    context().fields.lock().unwrap().remove(key);
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `LEVEL - message`, optionally with a timestamp
+    Text,
+    /// one JSON object per line, always with a timestamp
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("invalid log format '{}'", s)),
+        }
+    }
+}
+
+/// The precision of the timestamp on text log lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timestamp {
+    Off,
+    Second,
+    Millisecond,
+    Nanosecond,
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Timestamp::Off),
+            "sec" => Ok(Timestamp::Second),
+            "ms" => Ok(Timestamp::Millisecond),
+            "ns" => Ok(Timestamp::Nanosecond),
+            _ => Err(format!("invalid timestamp '{}'", s)),
+        }
+    }
+}
+
+/// Parse a per-module log level of the form `module=level`
+pub fn parse_module_level(s: &str) -> Result<(String, LevelFilter), String> {
+    let mut split = s.splitn(2, '=');
+    let module = split.next().unwrap_or_default();
+    let level = split
+        .next()
+        .ok_or_else(|| format!("expected MODULE=LEVEL, got '{}'", s))?;
+    let level =
+        LevelFilter::from_str(level).map_err(|_| format!("invalid log level '{}'", level))?;
+
+    Ok((module.to_string(), level))
+}
+
+/// Builds and installs the global logger. Modules that are not registered with
+/// `module` or `module_level` are not logged
+pub struct Logger {
+    format: LogFormat,
+    timestamp: Timestamp,
+    level: LevelFilter,
+    modules: Vec<String>,
+    module_levels: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger {
+            format: LogFormat::Text,
+            timestamp: Timestamp::Off,
+            level: LevelFilter::Error,
+            modules: Vec::new(),
+            module_levels: Vec::new(),
+        }
+    }
+}
+
+impl Logger {
+    /// A new logger that only logs errors, as plain text
+    pub fn new() -> Logger {
+        Logger::default()
+    }
+
+    /// Log messages from this module (and its submodules) at the default level
+    pub fn module(mut self, module: &str) -> Logger {
+        self.modules.push(module.to_string());
+        self
+    }
+
+    /// Override the level for a module and its submodules
+    pub fn module_level(mut self, module: &str, level: LevelFilter) -> Logger {
+        self.module_levels.push((module.to_string(), level));
+        self
+    }
+
+    /// Set the default level from a count of `-v` flags, starting from errors only
+    pub fn verbosity(mut self, verbosity: usize) -> Logger {
+        self.level = match verbosity {
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        self
+    }
+
+    /// Turn off all logging, regardless of the other settings
+    pub fn quiet(mut self, quiet: bool) -> Logger {
+        if quiet {
+            self.level = LevelFilter::Off;
+            self.module_levels.clear();
+        }
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Logger {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Logger {
+        self.format = format;
+        self
+    }
+
+    /// Install this as the global logger
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max_level = self
+            .module_levels
+            .iter()
+            .map(|(_, level)| *level)
+            .chain(std::iter::once(self.level))
+            .max()
+            .unwrap_or(LevelFilter::Off);
+
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+
+        Ok(())
+    }
+
+    /// find the level for a target, using the most specific module that matches
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let is_in = |module: &str| {
+            target == module
+                || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+        };
+
+        self.module_levels
+            .iter()
+            .filter(|(module, _)| is_in(module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .or_else(|| {
+                if self.modules.iter().any(|m| is_in(m)) {
+                    Some(self.level)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(LevelFilter::Off)
+    }
+
+    /// format a log line as JSON, including the context fields
+    fn json_line(&self, record: &Record) -> String {
+        let mut line = json!({
+            "timestamp": Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+
+        let thread_context = THREAD_CONTEXT.with(|c| c.get());
+        for context in std::iter::once(&CONTEXT).chain(thread_context) {
+            for (key, value) in context.fields.lock().unwrap().iter() {
+                line[*key] = Value::String(value.clone());
+            }
+        }
+
+        line.to_string()
+    }
+
+    /// format a log line as text, in the same style as stderrlog
+    fn text_line(&self, record: &Record) -> String {
+        let now = Local::now();
+        let timestamp = match self.timestamp {
+            Timestamp::Off => None,
+            Timestamp::Second => Some(now.to_rfc3339_opts(SecondsFormat::Secs, false)),
+            Timestamp::Millisecond => Some(now.to_rfc3339_opts(SecondsFormat::Millis, false)),
+            Timestamp::Nanosecond => Some(now.to_rfc3339_opts(SecondsFormat::Nanos, false)),
+        };
+
+        match timestamp {
+            Some(ts) => format!("{} - {} - {}", ts, record.level(), record.args()),
+            None => format!("{} - {}", record.level(), record.args()),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match self.format {
+            LogFormat::Json => self.json_line(record),
+            LogFormat::Text => self.text_line(record),
+        };
+
+        let stderr = std::io::stderr();
+        let mut handle = stderr.lock();
+        // there's nowhere to report a failure to write a log line
+        let _ = writeln!(handle, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn parse_module_level() {
+        assert_eq!(
+            super::parse_module_level("common::write_fastq=debug"),
+            Ok(("common::write_fastq".to_string(), LevelFilter::Debug))
+        );
+        assert!(super::parse_module_level("common").is_err());
+        assert!(super::parse_module_level("common=loud").is_err());
+    }
+
+    #[test]
+    fn level_for() {
+        let logger = Logger::new()
+            .module("common")
+            .verbosity(2)
+            .module_level("common::write_fastq", LevelFilter::Trace)
+            .module_level("common::write_fastq::inner", LevelFilter::Warn);
+
+        assert_eq!(logger.level_for("common"), LevelFilter::Info);
+        assert_eq!(logger.level_for("common::novaseq_run"), LevelFilter::Info);
+        assert_eq!(logger.level_for("common::write_fastq"), LevelFilter::Trace);
+        assert_eq!(
+            logger.level_for("common::write_fastq::inner"),
+            LevelFilter::Warn
+        );
+        assert_eq!(logger.level_for("commonplace"), LevelFilter::Off);
+        assert_eq!(logger.level_for("tiny_http"), LevelFilter::Off);
+    }
+
+    #[test]
+    fn json_line() {
+        // the demux tests set "lane" in the process context on other threads
+        set_thread_context(Box::leak(Box::default()));
+        set_context("lane", 3);
+
+        let logger = Logger::new().format(LogFormat::Json);
+        let line = logger.json_line(
+            &Record::builder()
+                .args(format_args!("hello"))
+                .level(Level::Info)
+                .target("common::test")
+                .build(),
+        );
+        let value: Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["message"], "hello");
+        assert_eq!(value["lane"], "3");
+
+        clear_context("lane");
+    }
+
+    #[test]
+    fn thread_context() {
+        let logger = Logger::new().format(LogFormat::Json);
+        let line = || {
+            let line = logger.json_line(
+                &Record::builder()
+                    .args(format_args!("hello"))
+                    .level(Level::Info)
+                    .target("common::test")
+                    .build(),
+            );
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+
+        // two threads with their own contexts, like two NUMA nodes
+        let contexts: Vec<&'static LogContext> =
+            (0..2).map(|_| &*Box::leak(Box::default())).collect();
+        std::thread::scope(|scope| {
+            for (node, &context) in contexts.iter().enumerate() {
+                let line = &line;
+                scope.spawn(move || {
+                    set_thread_context(context);
+                    set_context("thread_context_node", node);
+                    assert_eq!(line()["thread_context_node"], node.to_string());
+                });
+            }
+        });
+
+        // and none of it leaks into the rest of the process
+        assert_eq!(line()["thread_context_node"], Value::Null);
+        assert_eq!(
+            contexts[1].fields.lock().unwrap()["thread_context_node"],
+            "1"
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Merge the fastq chunks of a demux: the files written by each shard of a run,
+//! e.g. `s1_L001_R1.shard1of4.fastq.gz`, and optionally the files for each lane of
+//! a sample, e.g. `s1_L001_R1.fastq.gz`. Gzip and zstd files can be concatenated
+//! as they are, so the chunks are joined without decompressing them
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+
+use crate::provenance::FileChecksum;
+use crate::write_fastq::UMI_READ;
+
+/// The file with the size and checksum of every merged file, in the output directory
+pub const CHECKSUMS_NAME: &str = "fastq_checksums.json";
+
+/// The extensions of the files a demux writes, longest first
+const EXTENSIONS: [&str; 6] = ["fastq.zst", "fastq.gz", "seq.zst", "seq.gz", "fastq", "seq"];
+
+/// The lane and shard of a chunk (0 if it isn't from one), which put the chunks of
+/// a file in order
+type ChunkOrder = (usize, usize);
+
+/// One merged file and the chunks it is made from, in the order they are joined
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedFile {
+    pub path: PathBuf,
+    pub chunks: Vec<PathBuf>,
+}
+
+/// Split a file name into the name without its shard and extension, the shard
+/// number (or 0 if it isn't from a shard) and the extension
+pub(crate) fn split_shard(file_name: &str) -> Option<(&str, usize, &str)> {
+    let extension = EXTENSIONS
+        .iter()
+        .find(|ext| file_name.ends_with(&format!(".{}", ext)))?;
+    let stem = &file_name[..file_name.len() - extension.len() - 1];
+
+    if let Some(i) = stem.rfind(".shard") {
+        let (index, count) = stem[i + ".shard".len()..].split_once("of")?;
+        if let (Ok(index), Ok(_)) = (index.parse::<usize>(), count.parse::<usize>()) {
+            return Some((&stem[..i], index, extension));
+        }
+    }
+
+    Some((stem, 0, extension))
+}
+
+/// Take the lane out of a file name without its extension, e.g. `s1_L001_R1` is
+/// `s1_R1` from lane 1, and `s1_L001_UMI` is `s1_UMI`
+pub(crate) fn split_lane(stem: &str) -> Option<(String, usize)> {
+    stem.rmatch_indices("_L").find_map(|(i, _)| {
+        let lane = stem.get(i + 2..i + 5)?;
+        let read = &stem[i + 5..];
+        if lane.bytes().all(|b| b.is_ascii_digit())
+            && (read.starts_with("_R") || read.starts_with(UMI_READ))
+        {
+            Some((
+                format!("{}{}", &stem[..i], &stem[i + 5..]),
+                lane.parse().ok()?,
+            ))
+        } else {
+            None
+        }
+    })
+}
+
+/// List the files in `output_path` and in its project directories
+pub(crate) fn output_files(output_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = vec![output_path.to_path_buf()];
+    for entry in fs::read_dir(output_path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+
+    let mut files = Vec::new();
+    for dir in dirs {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Find the chunks in `output_path` and its project directories, and the files they
+/// merge into. Shards are always merged, and lanes are as well with `lanes`. A lane's
+/// file that is next to its shards was merged from them by an earlier merge that
+/// kept the shards, so it is left out, or its reads would be counted twice
+pub fn find_chunks(output_path: &Path, lanes: bool) -> io::Result<Vec<MergedFile>> {
+    let mut merged: BTreeMap<PathBuf, Vec<(ChunkOrder, PathBuf)>> = BTreeMap::new();
+    for path in output_files(output_path)? {
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name.to_string(),
+            None => continue,
+        };
+        let (stem, shard, extension) = match split_shard(&file_name) {
+            Some(split) => split,
+            None => continue,
+        };
+
+        let (stem, lane) = match split_lane(stem) {
+            Some((stem, lane)) if lanes => (stem, lane),
+            _ => (stem.to_string(), 0),
+        };
+        if (lane, shard) == (0, 0) {
+            continue;
+        }
+
+        merged
+            .entry(path.with_file_name(format!("{}.{}", stem, extension)))
+            .or_default()
+            .push(((lane, shard), path));
+    }
+
+    Ok(merged
+        .into_iter()
+        .map(|(path, mut chunks)| {
+            chunks.sort();
+            let sharded: Vec<usize> = chunks
+                .iter()
+                .filter(|((_, shard), _)| *shard > 0)
+                .map(|((lane, _), _)| *lane)
+                .collect();
+            chunks.retain(|((lane, shard), _)| *shard > 0 || !sharded.contains(lane));
+            MergedFile {
+                path,
+                chunks: chunks.into_iter().map(|(_, chunk)| chunk).collect(),
+            }
+        })
+        .collect())
+}
+
+/// Join the chunks of each file, and remove them afterwards with `remove_chunks`.
+/// Returns the size and checksum of each merged file
+pub fn merge_chunks(merged: &[MergedFile], remove_chunks: bool) -> io::Result<Vec<FileChecksum>> {
+    merged
+        .par_iter()
+        .map(|merged_file| {
+            // written under another name first, so a failed merge leaves no file
+            let tmp_path = merged_file.path.with_extension("tmp");
+            let mut out = BufWriter::new(File::create(&tmp_path)?);
+            for chunk in merged_file.chunks.iter() {
+                io::copy(&mut File::open(chunk)?, &mut out)?;
+            }
+            out.flush()?;
+            drop(out);
+            fs::rename(&tmp_path, &merged_file.path)?;
+
+            if remove_chunks {
+                for chunk in merged_file.chunks.iter() {
+                    fs::remove_file(chunk)?;
+                }
+            }
+
+            FileChecksum::read_path(&merged_file.path)
+        })
+        .collect()
+}
+
+/// Write the checksums of the merged files, with their paths from `output_path`
+pub fn write_checksums(output_path: &Path, checksums: &[FileChecksum]) -> io::Result<()> {
+    let checksums: Vec<_> = checksums
+        .iter()
+        .map(|checksum| FileChecksum {
+            path: Path::new(&checksum.path)
+                .strip_prefix(output_path)
+                .map_or_else(|_| checksum.path.clone(), |p| p.display().to_string()),
+            ..checksum.clone()
+        })
+        .collect();
+
+    let out_file = BufWriter::new(File::create(output_path.join(CHECKSUMS_NAME))?);
+    serde_json::to_writer_pretty(out_file, &checksums)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+    use std::io::Read;
+
+    #[test]
+    fn split_names() {
+        assert_eq!(
+            split_shard("s1_L001_R1.shard2of4.fastq.gz"),
+            Some(("s1_L001_R1", 2, "fastq.gz"))
+        );
+        assert_eq!(split_shard("s1_R2.fastq"), Some(("s1_R2", 0, "fastq")));
+        assert_eq!(split_shard("Stats.json"), None);
+
+        assert_eq!(split_lane("s1_L001_R1"), Some(("s1_R1".to_string(), 1)));
+        assert_eq!(
+            split_lane("s_L2_L003_R2_nonPF"),
+            Some(("s_L2_R2_nonPF".to_string(), 3))
+        );
+        assert_eq!(split_lane("s1_L002_UMI"), Some(("s1_UMI".to_string(), 2)));
+        assert_eq!(split_lane("s1_R1"), None);
+    }
+
+    #[test]
+    fn merge_shards_and_lanes() {
+        let output_path = Path::new("test_data/test_output/merge_chunks");
+        let _ = fs::remove_dir_all(output_path);
+        fs::create_dir_all(output_path.join("project_1")).unwrap();
+
+        let write_gz = |name: &str, contents: &str| {
+            let mut gz = GzEncoder::new(
+                File::create(output_path.join("project_1").join(name)).unwrap(),
+                Compression::default(),
+            );
+            gz.write_all(contents.as_bytes()).unwrap();
+            gz.finish().unwrap();
+        };
+        write_gz("s1_L001_R1.shard2of2.fastq.gz", "b\n");
+        write_gz("s1_L001_R1.shard1of2.fastq.gz", "a\n");
+        write_gz("s1_L002_R1.shard1of2.fastq.gz", "c\n");
+        fs::write(output_path.join("Undetermined_L001_R1.fastq"), "u\n").unwrap();
+
+        // only the shards, then the lanes too
+        let merged = find_chunks(output_path, false).unwrap();
+        assert_eq!(
+            merged.iter().map(|m| m.path.clone()).collect::<Vec<_>>(),
+            [
+                output_path.join("project_1/s1_L001_R1.fastq.gz"),
+                output_path.join("project_1/s1_L002_R1.fastq.gz")
+            ]
+        );
+
+        let merged = find_chunks(output_path, true).unwrap();
+        assert_eq!(merged.len(), 2);
+        let checksums = merge_chunks(&merged, true).unwrap();
+        assert_eq!(checksums.len(), 2);
+
+        let mut reads = String::new();
+        MultiGzDecoder::new(File::open(output_path.join("project_1/s1_R1.fastq.gz")).unwrap())
+            .read_to_string(&mut reads)
+            .unwrap();
+        assert_eq!(reads, "a\nb\nc\n");
+        assert_eq!(
+            fs::read_to_string(output_path.join("Undetermined_R1.fastq")).unwrap(),
+            "u\n"
+        );
+        assert!(!output_path
+            .join("project_1/s1_L001_R1.shard1of2.fastq.gz")
+            .exists());
+
+        write_checksums(output_path, &checksums).unwrap();
+        let written = fs::read_to_string(output_path.join(CHECKSUMS_NAME)).unwrap();
+        assert!(written.contains("\"path\": \"project_1/s1_R1.fastq.gz\""));
+    }
+
+    #[test]
+    fn merge_lanes_after_shards() {
+        let output_path = Path::new("test_data/test_output/merge_lanes_after_shards");
+        let _ = fs::remove_dir_all(output_path);
+        fs::create_dir_all(output_path).unwrap();
+
+        fs::write(output_path.join("s1_L001_R1.shard1of2.fastq"), "a\n").unwrap();
+        fs::write(output_path.join("s1_L001_R1.shard2of2.fastq"), "b\n").unwrap();
+        fs::write(output_path.join("s1_L002_R1.fastq"), "c\n").unwrap();
+
+        // the shards are merged and kept, then merged again with the lanes
+        merge_chunks(&find_chunks(output_path, false).unwrap(), false).unwrap();
+        assert_eq!(
+            fs::read_to_string(output_path.join("s1_L001_R1.fastq")).unwrap(),
+            "a\nb\n"
+        );
+
+        let merged = find_chunks(output_path, true).unwrap();
+        assert_eq!(
+            merged[0].chunks,
+            [
+                output_path.join("s1_L001_R1.shard1of2.fastq"),
+                output_path.join("s1_L001_R1.shard2of2.fastq"),
+                output_path.join("s1_L002_R1.fastq"),
+            ]
+        );
+        merge_chunks(&merged, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(output_path.join("s1_R1.fastq")).unwrap(),
+            "a\nb\nc\n"
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! A cache of the CBCL headers and filters of each lane surface, so that running
+//! bcl2fastr on the same run again doesn't open every CBCL file to read its header,
+//! or fetch every filter file again from an archive or object storage. Each lane
+//! surface has a gzipped JSON file of headers in cycle order and one of filters in
+//! tile order, with the size and modification time of each file when it was read,
+//! as the run's storage gives them (for a file in an archive, the archive's): if
+//! any file has changed, the whole lane surface is read again
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::cbcl_header_decoder::CBCLHeader;
+use crate::filter_decoder::Filter;
+use crate::storage::{gz_path, RunStorage, StorageHandle};
+
+/// A CBCL header and the size and modification time of its file when it was read.
+/// Headers are written from a reference, and read back as a `CBCLHeader`
+#[derive(Serialize, Deserialize)]
+struct CachedHeader<H> {
+    file_len: u64,
+    modified: (u64, u32),
+    #[serde(flatten)]
+    header: H,
+}
+
+/// The cache file for a lane surface. Runs loaded with only their index reads are
+/// kept apart, so switching between the two doesn't replace the cache each time
+pub(crate) fn lane_surface_path(
+    cache_path: &Path,
+    lane: usize,
+    surface: usize,
+    index_only: bool,
+) -> PathBuf {
+    let kind = if index_only {
+        "index_headers"
+    } else {
+        "headers"
+    };
+    cache_path.join(format!("L{:03}_{}.{}.json.gz", lane, surface, kind))
+}
+
+/// A tile's filter, with the number of clusters that passed it and the size and
+/// modification time of its file when it was read. The filter has a digit for each
+/// pair of clusters, which is much smaller than a JSON array once gzipped
+#[derive(Serialize, Deserialize)]
+struct CachedFilter {
+    filter_path: PathBuf,
+    file_len: u64,
+    modified: (u64, u32),
+    n_pf: usize,
+    filter: String,
+}
+
+/// The cache file for the filters of a lane surface, which are the same whichever
+/// reads are loaded
+pub(crate) fn filters_path(cache_path: &Path, lane: usize, surface: usize) -> PathBuf {
+    cache_path.join(format!("L{:03}_{}.filters.json.gz", lane, surface))
+}
+
+/// The size and modification time of a file in `storage`, or of its gzipped copy if
+/// there is only that, as it is read with `open_or_gz`
+fn fingerprint(storage: &dyn RunStorage, path: &Path) -> io::Result<(u64, (u64, u32))> {
+    match storage.len_modified(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            storage.len_modified(&gz_path(path)).map_err(|_| e)
+        }
+        result => result,
+    }
+}
+
+/// Write a cache file as gzipped JSON. Like the index cache, the file is written
+/// under another name first
+fn write_cache<T: Serialize>(path: &Path, cached: &T) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut gz = GzEncoder::new(
+        BufWriter::new(File::create(&tmp_path)?),
+        Compression::fast(),
+    );
+    serde_json::to_writer(&mut gz, cached)?;
+    gz.finish()?.flush()?;
+
+    fs::rename(tmp_path, path)
+}
+
+fn read_cache<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let gz = MultiGzDecoder::new(BufReader::new(File::open(path)?));
+    Ok(serde_json::from_reader(gz)?)
+}
+
+/// Write the headers for a lane surface, with the current size and modification time
+/// of each file
+pub(crate) fn write_headers(path: &Path, headers: &[CBCLHeader]) -> io::Result<()> {
+    let cached = headers
+        .iter()
+        .map(|h| {
+            let (file_len, modified) = fingerprint(h.storage.0.as_ref(), &h.cbcl_path)?;
+            Ok(CachedHeader {
+                file_len,
+                modified,
+                header: h,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    write_cache(path, &cached)
+}
+
+/// Read the cached headers for the CBCL files in `cbcl_paths`, which are read from
+/// `storage` later. Fails if the cache is for other files, or any of them has
+/// changed since it was written
+pub(crate) fn read_headers(
+    path: &Path,
+    storage: &Arc<dyn RunStorage>,
+    cbcl_paths: &[PathBuf],
+) -> io::Result<Vec<CBCLHeader>> {
+    let out_of_date = |message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), message),
+        )
+    };
+
+    let cached: Vec<CachedHeader<CBCLHeader>> = read_cache(path)?;
+
+    if cached.len() != cbcl_paths.len()
+        || cached
+            .iter()
+            .zip(cbcl_paths)
+            .any(|(h, p)| &h.header.cbcl_path != p)
+    {
+        return Err(out_of_date(
+            "cached headers are for other files".to_string(),
+        ));
+    }
+
+    cached
+        .into_iter()
+        .map(|h| {
+            if fingerprint(storage.as_ref(), &h.header.cbcl_path)? != (h.file_len, h.modified) {
+                return Err(out_of_date(format!(
+                    "{} has changed",
+                    h.header.cbcl_path.display()
+                )));
+            }
+
+            Ok(CBCLHeader {
+                storage: StorageHandle(storage.clone()),
+                ..h.header
+            })
+        })
+        .collect()
+}
+
+/// Write the filters for the tiles of a lane surface, read from `filter_paths` in
+/// `storage`, with the number of clusters passing each
+pub(crate) fn write_filters(
+    path: &Path,
+    storage: &dyn RunStorage,
+    filter_paths: &[PathBuf],
+    filters: &[Filter],
+    n_pfs: &[usize],
+) -> io::Result<()> {
+    let cached = filter_paths
+        .iter()
+        .zip(filters)
+        .zip(n_pfs)
+        .map(|((filter_path, filter), &n_pf)| {
+            let (file_len, modified) = fingerprint(storage, filter_path)?;
+            Ok(CachedFilter {
+                filter_path: filter_path.clone(),
+                file_len,
+                modified,
+                n_pf,
+                filter: filter.iter().map(|&b| (b'0' + b) as char).collect(),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    write_cache(path, &cached)
+}
+
+/// Read the cached filters for the tiles with `filter_paths`, and the number of
+/// clusters passing each. Fails like `read_headers` if any of them has changed
+pub(crate) fn read_filters(
+    path: &Path,
+    storage: &dyn RunStorage,
+    filter_paths: &[PathBuf],
+) -> io::Result<(Vec<Filter>, Vec<usize>)> {
+    let out_of_date = |message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), message),
+        )
+    };
+
+    let cached: Vec<CachedFilter> = read_cache(path)?;
+    if cached.len() != filter_paths.len()
+        || cached
+            .iter()
+            .zip(filter_paths)
+            .any(|(f, p)| &f.filter_path != p)
+    {
+        return Err(out_of_date(
+            "cached filters are for other files".to_string(),
+        ));
+    }
+
+    cached
+        .into_iter()
+        .map(|f| {
+            if fingerprint(storage, &f.filter_path)? != (f.file_len, f.modified) {
+                return Err(out_of_date(format!(
+                    "{} has changed",
+                    f.filter_path.display()
+                )));
+            }
+            let filter = f
+                .filter
+                .bytes()
+                .map(|b| match b {
+                    b'0'..=b'3' => Ok(b - b'0'),
+                    _ => Err(out_of_date("invalid filter".to_string())),
+                })
+                .collect::<io::Result<Filter>>()?;
+
+            Ok((filter, f.n_pf))
+        })
+        .collect::<io::Result<Vec<_>>>()
+        .map(|filters| filters.into_iter().unzip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter_decoder::filter_decoder;
+    use crate::storage::{local_storage, tar_storage::TarStorage};
+
+    #[test]
+    fn round_trip() {
+        let cache_path = Path::new("test_data/test_output/metadata_cache");
+        fs::create_dir_all(cache_path).unwrap();
+        let path = lane_surface_path(cache_path, 1, 2, false);
+        assert_eq!(path, cache_path.join("L001_2.headers.json.gz"));
+        assert_eq!(
+            lane_surface_path(cache_path, 1, 2, true),
+            cache_path.join("L001_2.index_headers.json.gz")
+        );
+
+        // a copy of a CBCL file, so that it can be changed
+        let cbcl_path = cache_path.join("L001_1.cbcl");
+        let cbcl_bytes = fs::read(
+            "test_data/190414_A00111_0296_AHJCWWDSXX/Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl",
+        )
+        .unwrap();
+        fs::write(&cbcl_path, &cbcl_bytes).unwrap();
+
+        let storage = local_storage();
+        let header = CBCLHeader::read(storage.clone(), &cbcl_path).unwrap();
+        write_headers(&path, std::slice::from_ref(&header)).unwrap();
+
+        let cached = read_headers(&path, &storage, std::slice::from_ref(&cbcl_path)).unwrap();
+        assert_eq!(cached, [header]);
+
+        let other_path = cache_path.join("L001_2.cbcl");
+        let e = read_headers(&path, &storage, &[other_path]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // the same bytes, but written again
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(&cbcl_path, &cbcl_bytes).unwrap();
+        let e = read_headers(&path, &storage, &[cbcl_path]).unwrap_err();
+        assert!(e.to_string().contains("has changed"), "{}", e);
+    }
+
+    #[test]
+    fn filters() {
+        let cache_path = Path::new("test_data/test_output/metadata_cache_filters");
+        fs::create_dir_all(cache_path).unwrap();
+        let path = filters_path(cache_path, 1, 1);
+        assert_eq!(path, cache_path.join("L001_1.filters.json.gz"));
+
+        // one plain filter, and a gzipped copy of another
+        let run_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let filter_path = cache_path.join("s_1_1101.filter");
+        let filter_bytes =
+            fs::read(run_path.join("Data/Intensities/BaseCalls/L001/s_1_1101.filter")).unwrap();
+        fs::write(&filter_path, &filter_bytes).unwrap();
+        let gz_filter_path = cache_path.join("s_1_1102.filter");
+        let _ = fs::remove_file(&gz_filter_path);
+        let mut gz = GzEncoder::new(
+            File::create(gz_path(&gz_filter_path)).unwrap(),
+            Compression::fast(),
+        );
+        gz.write_all(
+            &fs::read(run_path.join("Data/Intensities/BaseCalls/L001/s_1_1102.filter")).unwrap(),
+        )
+        .unwrap();
+        gz.finish().unwrap();
+
+        let storage = local_storage();
+        let filter_paths = [filter_path.clone(), gz_filter_path];
+        let filters: Vec<_> = filter_paths
+            .iter()
+            .map(|p| filter_decoder(storage.open_or_gz(p).unwrap()).unwrap())
+            .collect();
+        let n_pfs = [11, 12];
+        write_filters(&path, storage.as_ref(), &filter_paths, &filters, &n_pfs).unwrap();
+
+        let cached = read_filters(&path, storage.as_ref(), &filter_paths).unwrap();
+        assert_eq!(cached, (filters, n_pfs.to_vec()));
+
+        let e = read_filters(&path, storage.as_ref(), &filter_paths[..1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(&filter_path, &filter_bytes).unwrap();
+        let e = read_filters(&path, storage.as_ref(), &filter_paths).unwrap_err();
+        assert!(e.to_string().contains("has changed"), "{}", e);
+    }
+
+    #[test]
+    fn tar_archive() {
+        let cache_path = Path::new("test_data/test_output/metadata_cache_tar");
+        let _ = fs::remove_dir_all(cache_path);
+        fs::create_dir_all(cache_path).unwrap();
+        let path = lane_surface_path(cache_path, 1, 1, false);
+
+        let tar_path = cache_path.join("run.tar");
+        let status = std::process::Command::new("tar")
+            .arg("-cf")
+            .arg(&tar_path)
+            .args(["-C", "test_data/190414_A00111_0296_AHJCWWDSXX", "."])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // the files in the archive are fingerprinted with the archive
+        let storage: Arc<dyn RunStorage> = Arc::new(TarStorage::new(&tar_path).unwrap());
+        let cbcl_path = tar_path.join("Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl");
+        let header = CBCLHeader::read(storage.clone(), &cbcl_path).unwrap();
+        write_headers(&path, std::slice::from_ref(&header)).unwrap();
+        let cached = read_headers(&path, &storage, std::slice::from_ref(&cbcl_path)).unwrap();
+        assert_eq!(cached, [header]);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let tar_bytes = fs::read(&tar_path).unwrap();
+        fs::write(&tar_path, tar_bytes).unwrap();
+        let storage: Arc<dyn RunStorage> = Arc::new(TarStorage::new(&tar_path).unwrap());
+        let e = read_headers(&path, &storage, &[cbcl_path]).unwrap_err();
+        assert!(e.to_string().contains("has changed"), "{}", e);
+    }
+}
@@ -0,0 +1,160 @@
+//! Serves the progress of a run in the Prometheus/OpenMetrics text format, so that
+//! long-running jobs can be monitored while they demux
+
+use std::{
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    thread,
+};
+
+use log::{info, warn};
+use tiny_http::{Header, Response, Server};
+
+use crate::progress::Progress;
+
+/// format the current state of the run as OpenMetrics text
+fn render_metrics(progress: &Progress) -> String {
+    let mut text = String::new();
+
+    let counters = [
+        (
+            "bcl2fastr_tiles_processed",
+            "Tiles that have been demultiplexed",
+            progress.tiles(),
+        ),
+        (
+            "bcl2fastr_reads_written",
+            "Reads written to sample files",
+            progress.reads(),
+        ),
+        (
+            "bcl2fastr_basecall_bytes",
+            "Bytes of basecall data decoded",
+            progress.bytes(),
+        ),
+        (
+            "bcl2fastr_output_bytes",
+            "Bytes written to output files",
+            progress.bytes_written.load(Ordering::SeqCst),
+        ),
+    ];
+
+    for (name, help, value) in counters.iter() {
+        writeln!(text, "# HELP {} {}", name, help).unwrap();
+        writeln!(text, "# TYPE {} counter", name).unwrap();
+        writeln!(text, "{}_total {}", name, value).unwrap();
+    }
+
+    let gauges = [
+        (
+            "bcl2fastr_tiles",
+            "Total tiles to demultiplex",
+            progress.total_tiles(),
+        ),
+        (
+            "bcl2fastr_busy_workers",
+            "Threads currently writing output files",
+            progress.busy_workers.load(Ordering::SeqCst),
+        ),
+        (
+            "bcl2fastr_worker_threads",
+            "Size of the worker thread pool",
+            rayon::current_num_threads() as u64,
+        ),
+    ];
+
+    for (name, help, value) in gauges.iter() {
+        writeln!(text, "# HELP {} {}", name, help).unwrap();
+        writeln!(text, "# TYPE {} gauge", name).unwrap();
+        writeln!(text, "{} {}", name, value).unwrap();
+    }
+
+    writeln!(
+        text,
+        "# HELP bcl2fastr_sample_reads Reads assigned to each sample"
+    )
+    .unwrap();
+    writeln!(text, "# TYPE bcl2fastr_sample_reads counter").unwrap();
+    for ((lane, sample_id), n_reads) in progress.sample_reads() {
+        writeln!(
+            text,
+            "bcl2fastr_sample_reads_total{{lane=\"{}\",sample=\"{}\"}} {}",
+            lane,
+            sample_id.replace('\\', "\\\\").replace('"', "\\\""),
+            n_reads
+        )
+        .unwrap();
+    }
+
+    writeln!(text, "# EOF").unwrap();
+
+    text
+}
+
+/// The address the metrics are served on by default, which only this host can reach
+pub const DEFAULT_METRICS_BIND: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+/// Start a background thread that serves metrics on `port` of the `bind` address
+/// until the process exits. Returns the address it is listening on
+pub fn serve_metrics(
+    progress: Arc<Progress>,
+    bind: IpAddr,
+    port: u16,
+) -> std::io::Result<SocketAddr> {
+    let server = Server::http((bind, port)).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let addr = server.server_addr().to_ip().unwrap();
+
+    info!("serving metrics on {}", addr);
+
+    let content_type = Header::from_bytes(
+        &b"Content-Type"[..],
+        &b"application/openmetrics-text; version=1.0.0; charset=utf-8"[..],
+    )
+    .unwrap();
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response =
+                Response::from_string(render_metrics(&progress)).with_header(content_type.clone());
+            if let Err(e) = request.respond(response) {
+                warn!("error serving metrics: {}", e);
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::ProgressMode;
+
+    #[test]
+    fn render_metrics() {
+        let progress = Progress::new(ProgressMode::Hidden, 3);
+        progress.add_tiles(1, 10, 100);
+
+        let text = super::render_metrics(&progress);
+
+        assert!(text.contains("bcl2fastr_tiles_processed_total 1\n"));
+        assert!(text.contains("bcl2fastr_reads_written_total 10\n"));
+        assert!(text.contains("bcl2fastr_tiles 3\n"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let progress = Arc::new(Progress::new(ProgressMode::Hidden, 3));
+        let addr = super::serve_metrics(progress, DEFAULT_METRICS_BIND, 0).unwrap();
+        assert!(addr.ip().is_loopback());
+
+        let text = ureq::get(&format!("http://{}/metrics", addr))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        assert!(text.contains("bcl2fastr_tiles 3\n"));
+    }
+}
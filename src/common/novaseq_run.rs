@@ -1,16 +1,73 @@
 //! Represents a NovaSeq sequencing run as a struct
 //! that can be shared across threads
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use rayon::prelude::*;
 
-use crate::cbcl_header_decoder::CBCLHeader;
+use crate::cbcl_header_decoder::{retain_by, CBCLHeader};
 use crate::filter_decoder::{filter_decoder, Filter};
 use crate::locs_decoder::{locs_decoder, Locs};
-use crate::run_info_parser::{parse_run_info, RunInfo};
+use crate::metadata_cache;
+use crate::read_structure::ReadStructure;
+use crate::run_info_parser::{parse_run_info, read_run_info, RunInfo, Tile};
+use crate::run_parameters_parser::{read_run_parameters_from, RunParameters};
+use crate::storage::{local_storage, run_storage, RunStorage};
+
+/// The number of cycles, counting from the first, that have a CBCL file for every
+/// lane and surface
+pub(crate) fn completed_cycles(run_path: &Path, run_info: &RunInfo) -> usize {
+    let layout = &run_info.flowcell_layout;
+
+    let lane_surfaces = layout.lane_surfaces();
+
+    (1..=run_info.total_cycles())
+        .take_while(|cycle| {
+            lane_surfaces.iter().all(|[lane, surface]| {
+                run_path
+                    .join("Data/Intensities/BaseCalls")
+                    .join(format!(
+                        "L{:03}/C{}.1/L{:03}_{}.cbcl",
+                        lane, cycle, lane, surface
+                    ))
+                    .is_file()
+            })
+        })
+        .count()
+}
+
+/// The path of a tile's own locs file, relative to the run folder
+pub(crate) fn tile_locs_path(lane: usize, tile: u32) -> PathBuf {
+    PathBuf::from(format!(
+        "Data/Intensities/L{:03}/s_{}_{}.locs",
+        lane, lane, tile
+    ))
+}
+
+/// An error reading one of the run's files, naming the file
+fn read_error(what: &str, path: &Path, e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(
+        e.kind(),
+        format!("Error reading {} {} {}", what, path.display(), e),
+    )
+}
+
+/// Everything loaded for one [lane, surface], before it is added to the run
+struct LaneSurfaceData {
+    read_headers: Vec<Vec<CBCLHeader>>,
+    index_headers: Vec<Vec<CBCLHeader>>,
+    tile_ids: Vec<u32>,
+    filters: Vec<Filter>,
+    pf_filters: Vec<Filter>,
+    n_pfs: Vec<usize>,
+    tile_locs: Option<Vec<Locs>>,
+}
 
 /// Represents a sequencing run, including a bunch of metadata
 /// and the headers of all the CBCL files.
@@ -19,10 +76,18 @@ pub struct NovaSeqRun {
     pub run_path: PathBuf,
     /// RunInfo object, stores the contents of RunInfo.xml
     pub run_info: RunInfo,
+    /// the read segments (R1, I1, ...) for the cycles in `run_info`
+    pub read_structure: ReadStructure,
+    /// the contents of RunParameters.xml, if the run has one
+    pub run_parameters: Option<RunParameters>,
     /// a string with the run info formatted for read headers
     pub run_id: String,
-    /// a single universal locs array, same for every tile
+    /// a single universal locs array, same for every tile, or empty if each tile
+    /// has its own
     pub locs: Locs,
+    /// a map from [lane, surface] to vectors of locs for each tile, for runs without
+    /// a universal locs file (e.g. iSeq)
+    pub tile_locs: HashMap<[usize; 2], Vec<Locs>>,
     /// a map from [lane, surface] tuples to vectors of filters
     pub filters: HashMap<[usize; 2], Vec<Filter>>,
     /// a map from [lane, surface] tuples to vectors of post-filter "filters"
@@ -34,10 +99,23 @@ pub struct NovaSeqRun {
     /// a map from [lane, surface] to vectors of number of reads that pass filter,
     /// because we need this value a lot
     pub n_pfs: HashMap<[usize; 2], Vec<usize>>,
+    /// the tiles' own filters, for the [lane, surface]s whose clusters are all
+    /// decoded (see `include_non_pf`). Their `filters` let every cluster through
+    pub pf_flags: HashMap<[usize; 2], Vec<Filter>>,
     /// a map from [lane, surface] to vectors of CBCL headers for the reads
     pub read_headers: HashMap<[usize; 2], Vec<Vec<CBCLHeader>>>,
     /// a map from [lane, surface] to vectors of CBCL headers for the indices
     pub index_headers: HashMap<[usize; 2], Vec<Vec<CBCLHeader>>>,
+    /// where the run's files are read from, to load more lanes later
+    storage: Arc<dyn RunStorage>,
+    /// whether only the headers for the index reads are loaded
+    index_only: bool,
+    /// where the CBCL headers are cached between runs, if anywhere
+    metadata_cache: Option<PathBuf>,
+    /// (lane, tile number) of the tiles to skip in every lane that is loaded
+    excluded_tiles: BTreeSet<(usize, u32)>,
+    /// whether the clusters that failed filter are decoded in every lane loaded
+    non_pf_included: bool,
 }
 
 impl NovaSeqRun {
@@ -45,152 +123,791 @@ impl NovaSeqRun {
     /// in chunks of `tile_chunk` tiles each. If `index_only` is true, will only
     /// load in data for cycles that are in indexes, and adjusts `index_ix` attribute
     /// accordingly. Uses threads to load the data in parallel.
+    ///
+    /// `run_path` can also be an `s3://` or `gs://` URL, if bcl2fastr was built with
+    /// the `object-store` feature
     pub fn read_path(run_path: PathBuf, index_only: bool) -> std::io::Result<NovaSeqRun> {
-        let run_info = parse_run_info(&run_path.join("RunInfo.xml"))?;
+        NovaSeqRun::read_storage(run_storage(&run_path)?, run_path, index_only)
+    }
+
+    /// Like `read_path`, for a run folder in `storage`
+    pub fn read_storage(
+        storage: Arc<dyn RunStorage>,
+        run_path: PathBuf,
+        index_only: bool,
+    ) -> std::io::Result<NovaSeqRun> {
+        let mut novaseq_run = NovaSeqRun::open_storage(storage, run_path, index_only)?;
+        novaseq_run.load_all()?;
+
+        Ok(novaseq_run)
+    }
+
+    /// Loads the metadata for the run in `run_path`, but none of the headers and
+    /// filters for its lanes. They are loaded as needed with `load_lanes` or
+    /// `load_lane_surface`, so that looking at one lane of a big run, or only at its
+    /// metadata, doesn't wait for all of them
+    pub fn open_path(run_path: PathBuf, index_only: bool) -> std::io::Result<NovaSeqRun> {
+        NovaSeqRun::open_storage(run_storage(&run_path)?, run_path, index_only)
+    }
+
+    /// Like `open_path`, for a run folder in `storage`
+    pub fn open_storage(
+        storage: Arc<dyn RunStorage>,
+        run_path: PathBuf,
+        index_only: bool,
+    ) -> std::io::Result<NovaSeqRun> {
+        let run_info = read_run_info(storage.open(&run_path.join("RunInfo.xml"))?)?;
+
+        NovaSeqRun::open(storage, run_path, run_info, index_only)
+    }
+
+    /// Loads a run that is still sequencing, using only the cycles that are complete
+    /// for every lane and surface. The last read will be truncated or missing. The
+    /// index reads must be complete, or we couldn't assign reads to samples
+    pub fn read_path_incomplete(run_path: PathBuf) -> std::io::Result<NovaSeqRun> {
+        let mut run_info = parse_run_info(&run_path.join("RunInfo.xml"))?;
+        let n_cycles = completed_cycles(&run_path, &run_info);
+
+        let index_end = ReadStructure::new(&run_info.reads).index_end();
+
+        if n_cycles + 1 < index_end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "only {} cycles are complete, but the index reads end at cycle {}",
+                    n_cycles,
+                    index_end - 1
+                ),
+            ));
+        }
+
+        if n_cycles < run_info.total_cycles() {
+            warn!(
+                "run is incomplete: using {} of {} cycles",
+                n_cycles,
+                run_info.total_cycles()
+            );
+            run_info.truncate_cycles(n_cycles);
+        }
+
+        let mut novaseq_run = NovaSeqRun::open(local_storage(), run_path, run_info, false)?;
+        novaseq_run.load_all()?;
+
+        Ok(novaseq_run)
+    }
+
+    /// The number of cycles in the run's RunInfo.xml, which is more than `run_info`
+    /// has if the run was loaded with `read_path_incomplete` before it finished
+    pub fn run_info_cycles(&self) -> std::io::Result<usize> {
+        let run_info = read_run_info(self.storage.open(&self.run_path.join("RunInfo.xml"))?)?;
+
+        Ok(run_info.total_cycles())
+    }
+
+    /// Read the metadata for the reads in `run_info` from `storage`, without loading
+    /// any lanes
+    fn open(
+        storage: Arc<dyn RunStorage>,
+        run_path: PathBuf,
+        run_info: RunInfo,
+        index_only: bool,
+    ) -> std::io::Result<NovaSeqRun> {
+        let start = Instant::now();
         let run_id = format!(
             "@{}:{}:{}",
             run_info.instrument, run_info.number, run_info.flowcell,
         );
 
-        // need to repeat locs for each tile in tile_chunk
-        let locs = locs_decoder(&run_path.join("Data/Intensities/s.locs"))?;
-
-        let mut read_headers = HashMap::new();
-        let mut index_headers = HashMap::new();
-
-        let mut filters = HashMap::new();
-        let mut pf_filters = HashMap::new();
-        let mut tile_ids = HashMap::new();
-        let mut n_pfs = HashMap::new();
-
-        for lane in 1..=run_info.flowcell_layout.lane_count {
-            for surface in run_info.flowcell_layout.surface_range.clone() {
-                info!("lane {} - surface {}", lane, surface);
-
-                let mut lane_surface_read_headers = Vec::new();
-                let mut lane_surface_index_headers = Vec::new();
-
-                for read in run_info.reads.iter() {
-                    if index_only && !read.is_indexed_read {
-                        continue;
-                    }
-
-                    let these_headers: Vec<CBCLHeader> = (read.start..read.end)
-                        .into_par_iter()
-                        .map(|cycle| {
-                            let cbcl_path = run_path.join("Data/Intensities/BaseCalls").join(
-                                format!("L{:03}/C{}.1/L{:03}_{}.cbcl", lane, cycle, lane, surface),
-                            );
-
-                            match CBCLHeader::from_path(&cbcl_path) {
-                                Ok(header) => header,
-                                Err(e) => {
-                                    panic!("Error reading header {} {}", cbcl_path.display(), e)
-                                }
-                            }
-                        })
-                        .collect();
-
-                    if read.is_indexed_read {
-                        lane_surface_index_headers.push(these_headers);
-                    } else {
-                        lane_surface_read_headers.push(these_headers);
-                    }
-                }
+        let read_structure = ReadStructure::new(&run_info.reads);
+        let run_parameters = read_run_parameters_from(storage.as_ref(), &run_path)?;
+        if let Some(run_parameters) = &run_parameters {
+            info!("chemistry: {}", run_parameters.chemistry());
+        }
 
-                let mut lane_surface_filters = Vec::new();
-                let mut lane_surface_tile_ids = Vec::new();
+        // patterned flowcells have one locs file that is the same for every tile,
+        // but iSeq runs have a locs file for each tile instead
+        let locs = if storage.has_tile_locs(&run_path) {
+            info!("reading a locs file for each tile");
+            Vec::new()
+        } else {
+            locs_decoder(storage.open_or_gz(&run_path.join("Data/Intensities/s.locs"))?)?
+        };
 
-                // tile numbers are not stored by surface in RunInfo, so we are
-                // taking advantage of the headers having the right names.
-                // We will always have index_headers, but not always read_headers
-                lane_surface_index_headers[0][0]
-                    .tiles
-                    .par_iter()
-                    .map(|tile| {
-                        let filter_path = run_path.join(format!(
-                            "Data/Intensities/BaseCalls/L{:03}/s_{}_{}.filter",
-                            lane, lane, tile,
-                        ));
-                        let filter = match filter_decoder(&filter_path) {
-                            Ok(filter) => filter,
-                            Err(e) => {
-                                panic!("Error reading filter {} {}", filter_path.display(), e)
-                            }
-                        };
-
-                        (tile.clone(), filter)
-                    })
-                    .unzip_into_vecs(&mut lane_surface_tile_ids, &mut lane_surface_filters);
+        info!(
+            "read the run's metadata and locs in {:.2}s",
+            start.elapsed().as_secs_f64()
+        );
 
-                let mut lane_surface_n_pfs = Vec::new();
-                let mut lane_surface_pf_filters = Vec::new();
+        Ok(NovaSeqRun {
+            run_path,
+            run_info,
+            read_structure,
+            run_parameters,
+            run_id,
+            locs,
+            tile_locs: HashMap::new(),
+            filters: HashMap::new(),
+            pf_filters: HashMap::new(),
+            tile_ids: HashMap::new(),
+            n_pfs: HashMap::new(),
+            pf_flags: HashMap::new(),
+            read_headers: HashMap::new(),
+            index_headers: HashMap::new(),
+            storage,
+            index_only,
+            metadata_cache: None,
+            excluded_tiles: BTreeSet::new(),
+            non_pf_included: false,
+        })
+    }
 
-                lane_surface_filters
-                    .par_iter()
-                    .map(|filter| {
-                        let n_pf: usize = filter.iter().map(|&b| [0, 1, 1, 2][b as usize]).sum();
+    /// Keep the CBCL headers of the lanes loaded from now on in `cache_path`, and
+    /// use the ones that are there already if their files haven't changed
+    pub fn set_metadata_cache(&mut self, cache_path: PathBuf) {
+        self.metadata_cache = Some(cache_path);
+    }
+
+    /// Skip `tiles`, as (lane, tile number), in the lanes that are loaded already
+    /// and in any loaded later
+    pub fn exclude_tiles(&mut self, tiles: impl IntoIterator<Item = (usize, u32)>) {
+        self.excluded_tiles.extend(tiles);
+        self.retain_included_tiles();
+    }
 
-                        let mut pf_filter: Vec<_> = std::iter::repeat(3).take(n_pf / 2).collect();
-                        if n_pf % 2 == 1 {
-                            pf_filter.push(2)
-                        }
+    /// Decode the clusters that failed filter along with the rest, in the lanes that
+    /// are loaded already and in any loaded later. The tiles' filters are kept in
+    /// `pf_flags` so the clusters can still be told apart. Fails if a CBCL file
+    /// left them out, as the NovaSeq does unless it is set up to keep them
+    pub fn include_non_pf(&mut self) -> std::io::Result<()> {
+        self.non_pf_included = true;
 
-                        (n_pf, pf_filter)
-                    })
-                    .unzip_into_vecs(&mut lane_surface_n_pfs, &mut lane_surface_pf_filters);
+        let lane_surfaces: Vec<_> = self
+            .filters
+            .keys()
+            .filter(|lane_surface| !self.pf_flags.contains_key(*lane_surface))
+            .cloned()
+            .collect();
+        for lane_surface in lane_surfaces {
+            self.pass_every_cluster(lane_surface)?;
+        }
 
-                info!("loaded {} filters and ids", lane_surface_filters.len());
+        Ok(())
+    }
 
-                filters.insert([lane, surface], lane_surface_filters);
-                pf_filters.insert([lane, surface], lane_surface_pf_filters);
-                tile_ids.insert([lane, surface], lane_surface_tile_ids);
-                n_pfs.insert([lane, surface], lane_surface_n_pfs);
+    /// Replace the filters of a [lane, surface] with ones that every cluster passes,
+    /// and keep the real ones in `pf_flags`
+    fn pass_every_cluster(&mut self, lane_surface: [usize; 2]) -> std::io::Result<()> {
+        if let Some(header) = self.read_headers[&lane_surface]
+            .iter()
+            .chain(&self.index_headers[&lane_surface])
+            .flatten()
+            .find(|h| h.non_pf_clusters_excluded)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} only has the clusters that passed filter",
+                    header.cbcl_path.display()
+                ),
+            ));
+        }
 
-                read_headers.insert([lane, surface], lane_surface_read_headers);
-                index_headers.insert([lane, surface], lane_surface_index_headers);
+        let n_clusters: Vec<_> = (0..self.tile_ids[&lane_surface].len())
+            .map(|tile_i| self.locs_for(lane_surface, tile_i).len())
+            .collect();
+        let all_filters: Vec<Filter> = n_clusters
+            .iter()
+            .map(|&n| {
+                let mut filter = vec![3; n / 2];
+                if n % 2 == 1 {
+                    filter.push(2)
+                }
+                filter
+            })
+            .collect();
+
+        let filters = std::mem::replace(
+            self.filters.get_mut(&lane_surface).unwrap(),
+            all_filters.clone(),
+        );
+        self.pf_flags.insert(lane_surface, filters);
+        self.pf_filters.insert(lane_surface, all_filters);
+        self.n_pfs.insert(lane_surface, n_clusters);
+
+        Ok(())
+    }
+
+    /// Drop the tiles that were excluded, and the ones that RunInfo.xml doesn't list
+    /// if it lists any: the instrument leaves a tile out when it wasn't imaged
+    /// properly, even though the CBCL files still have a block for it
+    fn retain_included_tiles(&mut self) {
+        let listed: BTreeSet<_> = self
+            .run_info
+            .flowcell_layout
+            .tiles
+            .iter()
+            .map(|tile| (tile.lane, tile.number))
+            .collect();
+        let excluded = self.excluded_tiles.clone();
+
+        let mut skipped = Vec::new();
+        self.retain_tiles(|[lane, _], tile| {
+            let keep = !excluded.contains(&(lane, tile))
+                && (listed.is_empty() || listed.contains(&(lane, tile)));
+            if !keep {
+                skipped.push(format!("{}_{}", lane, tile));
             }
+            keep
+        });
+
+        if !skipped.is_empty() {
+            info!("skipping excluded tiles {}", skipped.join(", "));
         }
+    }
 
-        // check to make sure our "constant qscore map" assumption is correct
-        let mut qscore_maps: std::collections::HashSet<_> = index_headers
-            .values()
-            .flat_map(|hs| hs.iter().flat_map(|h| h.iter().map(|h| h.bins.clone())))
+    /// Every [lane, surface] of the flowcell, whether or not it has been loaded
+    pub fn lane_surfaces(&self) -> Vec<[usize; 2]> {
+        self.run_info.flowcell_layout.lane_surfaces()
+    }
+
+    /// Open a file in the run folder, from wherever the run is stored. `path` is
+    /// relative to the run folder
+    pub fn open_file(&self, path: &Path) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+        self.storage.open(&self.run_path.join(path))
+    }
+
+    /// Whether the headers and filters for a [lane, surface] have been loaded
+    pub fn is_loaded(&self, lane_surface: [usize; 2]) -> bool {
+        self.tile_ids.contains_key(&lane_surface)
+    }
+
+    /// Load every lane of the run
+    pub fn load_all(&mut self) -> std::io::Result<()> {
+        self.load_lane_surfaces(&self.lane_surfaces())
+    }
+
+    /// Load the surfaces of each lane in `lanes` that aren't loaded yet
+    pub fn load_lanes(&mut self, lanes: &BTreeSet<usize>) -> std::io::Result<()> {
+        let lane_count = self.run_info.flowcell_layout.lane_count;
+        if let Some(lane) = lanes.iter().find(|&&lane| lane == 0 || lane > lane_count) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("the run has no lane {}", lane),
+            ));
+        }
+
+        let lane_surfaces: Vec<_> = self
+            .lane_surfaces()
+            .into_iter()
+            .filter(|[lane, _]| lanes.contains(lane))
+            .collect();
+        self.load_lane_surfaces(&lane_surfaces)
+    }
+
+    /// Load the headers and filters for one [lane, surface], if they aren't loaded
+    /// already
+    pub fn load_lane_surface(&mut self, lane_surface: [usize; 2]) -> std::io::Result<()> {
+        self.load_lane_surfaces(&[lane_surface])
+    }
+
+    /// Load the [lane, surface]s that aren't loaded yet, all at once: on a network
+    /// filesystem most of the time goes to waiting for each file to open
+    fn load_lane_surfaces(&mut self, lane_surfaces: &[[usize; 2]]) -> std::io::Result<()> {
+        let start = Instant::now();
+
+        let lane_surfaces: Vec<_> = lane_surfaces
+            .iter()
+            .filter(|&&lane_surface| !self.is_loaded(lane_surface))
+            .cloned()
+            .collect();
+        if lane_surfaces.is_empty() {
+            return Ok(());
+        }
+
+        let loaded: Vec<_> = lane_surfaces
+            .par_iter()
+            .map(|&lane_surface| self.read_lane_surface(lane_surface))
             .collect();
 
-        qscore_maps.extend(
-            read_headers
-                .values()
-                .flat_map(|hs| hs.iter().flat_map(|h| h.iter().map(|h| h.bins.clone()))),
+        for (lane_surface, data) in lane_surfaces.iter().zip(loaded) {
+            let data = data?;
+            if let Some(lane_surface_locs) = data.tile_locs {
+                self.tile_locs.insert(*lane_surface, lane_surface_locs);
+            }
+            self.filters.insert(*lane_surface, data.filters);
+            self.pf_filters.insert(*lane_surface, data.pf_filters);
+            self.tile_ids.insert(*lane_surface, data.tile_ids);
+            self.n_pfs.insert(*lane_surface, data.n_pfs);
+            self.read_headers.insert(*lane_surface, data.read_headers);
+            self.index_headers.insert(*lane_surface, data.index_headers);
+        }
+        self.retain_included_tiles();
+        if self.non_pf_included {
+            for lane_surface in lane_surfaces.iter() {
+                self.pass_every_cluster(*lane_surface)?;
+            }
+        }
+
+        info!(
+            "loaded {} lane surfaces in {:.2}s",
+            lane_surfaces.len(),
+            start.elapsed().as_secs_f64()
         );
 
-        if qscore_maps.len() == 1 {
-            if !qscore_maps.contains(&vec![35, 44, 58, 70]) {
-                panic!("Got a new qscore map! {:?}", qscore_maps);
+        Ok(())
+    }
+
+    /// Read the headers and filters for one [lane, surface]. The headers for every
+    /// cycle are read in parallel, and then the filters (and locs) for every tile
+    fn read_lane_surface(&self, lane_surface: [usize; 2]) -> std::io::Result<LaneSurfaceData> {
+        let [lane, surface] = lane_surface;
+        let storage = &self.storage;
+        let run_path = &self.run_path;
+        let start = Instant::now();
+
+        let segments: Vec<_> = self
+            .read_structure
+            .segments
+            .iter()
+            .filter(|segment| !self.index_only || segment.is_index())
+            .collect();
+
+        let cbcl_paths: Vec<_> = segments
+            .iter()
+            .flat_map(|segment| segment.cycles.clone())
+            .map(|cycle| {
+                run_path.join("Data/Intensities/BaseCalls").join(format!(
+                    "L{:03}/C{}.1/L{:03}_{}.cbcl",
+                    lane, cycle, lane, surface
+                ))
+            })
+            .collect();
+
+        let cache_file = self.metadata_cache.as_ref().map(|cache_path| {
+            metadata_cache::lane_surface_path(cache_path, lane, surface, self.index_only)
+        });
+        let cached = cache_file.as_ref().and_then(|cache_file| {
+            metadata_cache::read_headers(cache_file, storage, &cbcl_paths)
+                .map_err(|e| debug!("not using cached headers: {}", e))
+                .ok()
+        });
+        let from_cache = cached.is_some();
+
+        let mut headers: Vec<CBCLHeader> = match cached {
+            Some(headers) => headers,
+            None => cbcl_paths
+                .par_iter()
+                .map(|cbcl_path| {
+                    CBCLHeader::read(storage.clone(), cbcl_path)
+                        .map_err(|e| read_error("header", cbcl_path, e))
+                })
+                .collect::<std::io::Result<_>>()?,
+        };
+
+        if let (Some(cache_file), false) = (&cache_file, from_cache) {
+            if let Err(e) = metadata_cache::write_headers(cache_file, &headers) {
+                warn!("Can't cache the headers in {}: {}", cache_file.display(), e);
             }
-        } else {
-            panic!("Got {} different qscore maps!", qscore_maps.len());
         }
 
-        debug!("qscore map: {:?}", qscore_maps);
+        // split the headers back up by read
+        let mut lane_surface_read_headers = Vec::new();
+        let mut lane_surface_index_headers = Vec::new();
+        for segment in segments.iter().rev() {
+            let these_headers = headers.split_off(headers.len() - segment.num_cycles());
+            if segment.is_index() {
+                lane_surface_index_headers.insert(0, these_headers);
+            } else {
+                lane_surface_read_headers.insert(0, these_headers);
+            }
+        }
+        let header_time = start.elapsed();
 
-        let novaseq_run = NovaSeqRun {
-            run_path,
-            run_info,
-            run_id,
-            locs,
-            filters,
-            pf_filters,
-            tile_ids,
-            n_pfs,
-            read_headers,
-            index_headers,
+        // the quality scores are decoded with a fixed map, so every file has to
+        // use the same bins. Name one that doesn't, so the run can be checked
+        if let Some(header) = lane_surface_index_headers
+            .iter()
+            .chain(&lane_surface_read_headers)
+            .flatten()
+            .find(|h| h.bins != [35, 44, 58, 70])
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} has quality bins {}, but only Q2, Q11, Q25 and Q37 can be decoded",
+                    header.cbcl_path.display(),
+                    header
+                        .bins
+                        .iter()
+                        .map(|b| format!("Q{}", b - 33))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            ));
+        }
+
+        // tile numbers are not stored by surface in RunInfo, so we are
+        // taking advantage of the headers having the right names. Index
+        // headers are always loaded if the run has indices, read headers
+        // are not
+        let first_headers = lane_surface_index_headers
+            .first()
+            .or_else(|| lane_surface_read_headers.first())
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "No reads to load")
+            })?;
+        let lane_surface_tile_ids = first_headers[0].tiles.clone();
+        let filter_paths: Vec<_> = lane_surface_tile_ids
+            .iter()
+            .map(|tile| {
+                run_path.join(format!(
+                    "Data/Intensities/BaseCalls/L{:03}/s_{}_{}.filter",
+                    lane, lane, tile,
+                ))
+            })
+            .collect();
+
+        let filters_cache_file = self
+            .metadata_cache
+            .as_ref()
+            .map(|cache_path| metadata_cache::filters_path(cache_path, lane, surface));
+        let cached_filters = filters_cache_file.as_ref().and_then(|cache_file| {
+            metadata_cache::read_filters(cache_file, storage.as_ref(), &filter_paths)
+                .map_err(|e| debug!("not using cached filters: {}", e))
+                .ok()
+        });
+        let filters_from_cache = cached_filters.is_some();
+
+        let (lane_surface_filters, lane_surface_n_pfs): (Vec<_>, Vec<_>) = match cached_filters {
+            Some(filters) => filters,
+            None => filter_paths
+                .par_iter()
+                .map(|filter_path| {
+                    let filter = storage
+                        .open_or_gz(filter_path)
+                        .and_then(filter_decoder)
+                        .map_err(|e| read_error("filter", filter_path, e))?;
+                    let n_pf: usize = filter.iter().map(|&b| [0, 1, 1, 2][b as usize]).sum();
+
+                    Ok((filter, n_pf))
+                })
+                .collect::<std::io::Result<Vec<_>>>()?
+                .into_iter()
+                .unzip(),
         };
 
-        Ok(novaseq_run)
+        if let (Some(cache_file), false) = (&filters_cache_file, filters_from_cache) {
+            if let Err(e) = metadata_cache::write_filters(
+                cache_file,
+                storage.as_ref(),
+                &filter_paths,
+                &lane_surface_filters,
+                &lane_surface_n_pfs,
+            ) {
+                warn!("Can't cache the filters in {}: {}", cache_file.display(), e);
+            }
+        }
+        let filter_time = start.elapsed() - header_time;
+
+        // the shared locs are read when the run is opened, so an empty array means
+        // each tile has its own
+        let tile_locs = if self.locs.is_empty() {
+            Some(
+                lane_surface_tile_ids
+                    .par_iter()
+                    .map(|tile| {
+                        let locs_path = run_path.join(tile_locs_path(lane, *tile));
+                        storage
+                            .open_or_gz(&locs_path)
+                            .and_then(locs_decoder)
+                            .map_err(|e| read_error("locs", &locs_path, e))
+                    })
+                    .collect::<std::io::Result<_>>()?,
+            )
+        } else {
+            None
+        };
+
+        let lane_surface_pf_filters = lane_surface_n_pfs
+            .iter()
+            .map(|&n_pf| {
+                let mut pf_filter = vec![3; n_pf / 2];
+                if n_pf % 2 == 1 {
+                    pf_filter.push(2)
+                }
+
+                pf_filter
+            })
+            .collect();
+
+        info!(
+            "lane {} - surface {}: loaded {} headers{} in {:.2}s, {} filters{} in {:.2}s, {:.2}s in total",
+            lane,
+            surface,
+            lane_surface_index_headers
+                .iter()
+                .chain(&lane_surface_read_headers)
+                .map(Vec::len)
+                .sum::<usize>(),
+            if from_cache { " from the cache" } else { "" },
+            header_time.as_secs_f64(),
+            lane_surface_filters.len(),
+            if filters_from_cache { " from the cache" } else { "" },
+            filter_time.as_secs_f64(),
+            start.elapsed().as_secs_f64()
+        );
+
+        Ok(LaneSurfaceData {
+            read_headers: lane_surface_read_headers,
+            index_headers: lane_surface_index_headers,
+            tile_ids: lane_surface_tile_ids,
+            filters: lane_surface_filters,
+            pf_filters: lane_surface_pf_filters,
+            n_pfs: lane_surface_n_pfs,
+            tile_locs,
+        })
+    }
+
+    /// The number of tiles with data in a lane, or in all lanes if `lane` is 0
+    pub fn tile_count(&self, lane: usize) -> usize {
+        self.tile_ids
+            .iter()
+            .filter(|([l, _], _)| lane == 0 || *l == lane)
+            .map(|(_, tiles)| tiles.len())
+            .sum()
+    }
+
+    /// The locations of the clusters in a tile, from the universal locs file or the
+    /// tile's own
+    pub fn locs_for(&self, lane_surface: [usize; 2], tile_i: usize) -> &[[u32; 2]] {
+        match self.tile_locs.get(&lane_surface) {
+            Some(tile_locs) => &tile_locs[tile_i],
+            None => &self.locs,
+        }
+    }
+
+    /// The number of clusters in the tiles of a lane, before filtering, or in all
+    /// lanes if `lane` is 0
+    pub fn clusters_raw(&self, lane: usize) -> u64 {
+        self.tile_ids
+            .iter()
+            .filter(|([l, _], _)| lane == 0 || *l == lane)
+            .flat_map(|(key, tiles)| (0..tiles.len()).map(move |tile_i| (*key, tile_i)))
+            .map(|(key, tile_i)| self.locs_for(key, tile_i).len() as u64)
+            .sum()
+    }
+
+    /// Drop the data for every lane that isn't in `lanes`, so that it won't be
+    /// processed
+    pub fn retain_lanes(&mut self, lanes: &BTreeSet<usize>) {
+        let keep = |[lane, _]: &[usize; 2]| lanes.contains(lane);
+
+        self.tile_locs.retain(|k, _| keep(k));
+        self.filters.retain(|k, _| keep(k));
+        self.pf_filters.retain(|k, _| keep(k));
+        self.tile_ids.retain(|k, _| keep(k));
+        self.n_pfs.retain(|k, _| keep(k));
+        self.pf_flags.retain(|k, _| keep(k));
+        self.read_headers.retain(|k, _| keep(k));
+        self.index_headers.retain(|k, _| keep(k));
+    }
+
+    /// Drop the data for every tile that isn't in `shard`. Tiles are dealt out to
+    /// the shards in turn, in order of lane, surface and tile number, so that every
+    /// invocation with the same run and shard count agrees on the split
+    pub fn retain_shard(&mut self, shard: &Shard) {
+        let mut tile_n = 0;
+        self.retain_tiles(|_, _| {
+            tile_n += 1;
+            (tile_n - 1) % shard.count == shard.index - 1
+        });
+    }
+
+    /// Split the tiles of `lane` (or every lane, for lane 0) between up to `n_parts`
+    /// runs of just that lane. Each part has the next tiles in order of lane, surface
+    /// and tile number, so the parts one after another have the tiles in the usual
+    /// order
+    pub fn split_lane_tiles(&self, lane: usize, n_parts: usize) -> Vec<NovaSeqRun> {
+        let n_tiles = self.tile_count(lane);
+        let n_parts = n_parts.clamp(1, n_tiles.max(1));
+
+        (0..n_parts)
+            .map(|part| {
+                let tiles = part * n_tiles / n_parts..(part + 1) * n_tiles / n_parts;
+                let mut part_run = self.lane_copy(lane);
+                let mut tile_n = 0;
+                part_run.retain_tiles(|_, _| {
+                    tile_n += 1;
+                    tiles.contains(&(tile_n - 1))
+                });
+
+                part_run
+            })
+            .collect()
+    }
+
+    /// A copy of the run with only the data for `lane`, or every lane for lane 0
+    fn lane_copy(&self, lane: usize) -> NovaSeqRun {
+        fn lane_entries<T: Clone>(
+            map: &HashMap<[usize; 2], T>,
+            lane: usize,
+        ) -> HashMap<[usize; 2], T> {
+            map.iter()
+                .filter(|([l, _], _)| lane == 0 || *l == lane)
+                .map(|(k, v)| (*k, v.clone()))
+                .collect()
+        }
+
+        NovaSeqRun {
+            run_path: self.run_path.clone(),
+            run_info: self.run_info.clone(),
+            read_structure: self.read_structure.clone(),
+            run_parameters: self.run_parameters.clone(),
+            run_id: self.run_id.clone(),
+            locs: self.locs.clone(),
+            tile_locs: lane_entries(&self.tile_locs, lane),
+            filters: lane_entries(&self.filters, lane),
+            pf_filters: lane_entries(&self.pf_filters, lane),
+            tile_ids: lane_entries(&self.tile_ids, lane),
+            n_pfs: lane_entries(&self.n_pfs, lane),
+            pf_flags: lane_entries(&self.pf_flags, lane),
+            read_headers: lane_entries(&self.read_headers, lane),
+            index_headers: lane_entries(&self.index_headers, lane),
+            storage: self.storage.clone(),
+            index_only: self.index_only,
+            metadata_cache: self.metadata_cache.clone(),
+            excluded_tiles: self.excluded_tiles.clone(),
+            non_pf_included: self.non_pf_included,
+        }
+    }
+
+    /// Drop every tile but the first of each lane, for a quick check of the
+    /// samplesheet before demultiplexing the whole run
+    pub fn retain_first_tiles(&mut self) {
+        let mut seen_lanes = BTreeSet::new();
+        self.retain_tiles(|[lane, _], _| seen_lanes.insert(lane));
+    }
+
+    /// Drop the data for the tiles where `keep` is false. It is called for each
+    /// tile in order of lane, surface and tile number, with the tile's [lane, surface]
+    /// and number
+    fn retain_tiles(&mut self, mut keep: impl FnMut([usize; 2], u32) -> bool) {
+        let mut keys: Vec<_> = self.tile_ids.keys().cloned().collect();
+        keys.sort_unstable();
+
+        for key in keys {
+            let keep: Vec<_> = self.tile_ids[&key]
+                .iter()
+                .map(|&tile| keep(key, tile))
+                .collect();
+            if keep.iter().all(|&k| k) {
+                continue;
+            }
+
+            retain_by(self.tile_ids.get_mut(&key).unwrap(), &keep);
+            retain_by(self.n_pfs.get_mut(&key).unwrap(), &keep);
+            retain_by(self.filters.get_mut(&key).unwrap(), &keep);
+            retain_by(self.pf_filters.get_mut(&key).unwrap(), &keep);
+            if let Some(pf_flags) = self.pf_flags.get_mut(&key) {
+                retain_by(pf_flags, &keep);
+            }
+            if let Some(tile_locs) = self.tile_locs.get_mut(&key) {
+                retain_by(tile_locs, &keep);
+            }
+
+            for headers in self.read_headers.get_mut(&key).unwrap() {
+                headers.iter_mut().for_each(|h| h.retain_tiles(&keep));
+            }
+            for headers in self.index_headers.get_mut(&key).unwrap() {
+                headers.iter_mut().for_each(|h| h.retain_tiles(&keep));
+            }
+        }
+    }
+}
+
+/// One of `count` invocations that split the tiles of a run between them, given
+/// on the command line as `index/count` and numbered from 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    /// added to the names of output files, so that shards can share a directory
+    pub fn suffix(&self) -> String {
+        format!("shard{}of{}", self.index, self.count)
     }
 }
 
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid shard '{}', expected e.g. 1/4", s);
+
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index = index.trim().parse::<usize>().map_err(|_| invalid())?;
+        let count = count.trim().parse::<usize>().map_err(|_| invalid())?;
+
+        if index == 0 || index > count {
+            return Err(invalid());
+        }
+
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Parse a list of lanes and ranges of lanes, e.g. `1,3-4`
+pub fn parse_lanes(s: &str) -> Result<BTreeSet<usize>, String> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("invalid lane '{}'", n))
+    };
+
+    let mut lanes = BTreeSet::new();
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("invalid lane range '{}'", part));
+                }
+                lanes.extend(start..=end);
+            }
+            None => {
+                lanes.insert(parse(part)?);
+            }
+        }
+    }
+
+    Ok(lanes)
+}
+
+/// Parse a list of tiles to exclude, as (lane, tile number). Tiles are named like
+/// in RunInfo.xml (`1_2104`) or in the instrument's file names (`s_1_2104`), and
+/// separated by commas, spaces or new lines. Anything after a `#` is a comment
+pub fn parse_excluded_tiles(s: &str) -> Result<BTreeSet<(usize, u32)>, String> {
+    s.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let tile = Tile::parse(name.strip_prefix("s_").unwrap_or(name), None)?;
+            Ok((tile.lane, tile.number))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -201,6 +918,167 @@ mod tests {
         let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
 
         assert_eq!(novaseq_run.run_id, "@A00111:296:HJCWWDSXX");
+        assert_eq!(novaseq_run.tile_count(0), 3);
+        assert_eq!(novaseq_run.tile_count(1), 3);
+        assert_eq!(novaseq_run.tile_count(2), 0);
+    }
+
+    #[test]
+    fn iseq_run() {
+        let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let mut novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+
+        assert_eq!(novaseq_run.run_id, "@FS10000171:42:BPA73113-1417");
+        assert_eq!(novaseq_run.tile_ids[&[1, 1]], vec![1101, 1102]);
+        assert!(novaseq_run.locs.is_empty());
+        assert_eq!(novaseq_run.locs_for([1, 1], 1).len(), 24);
+        assert_eq!(novaseq_run.clusters_raw(1), 48);
+        assert!(novaseq_run.read_headers[&[1, 1]][0][0].non_pf_clusters_excluded);
+
+        novaseq_run.retain_shard(&"2/2".parse().unwrap());
+        assert_eq!(novaseq_run.tile_locs[&[1, 1]].len(), 1);
+        assert_eq!(novaseq_run.clusters_raw(0), 24);
+    }
+
+    #[test]
+    fn retain_lanes() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let mut novaseq_run = NovaSeqRun::read_path(run_path, true).unwrap();
+
+        novaseq_run.retain_lanes(&[1].iter().cloned().collect());
+        assert_eq!(novaseq_run.tile_count(0), 3);
+
+        novaseq_run.retain_lanes(&[2].iter().cloned().collect());
+        assert_eq!(novaseq_run.tile_count(0), 0);
+        assert!(novaseq_run.index_headers.is_empty());
+    }
+
+    #[test]
+    fn lazy_loading() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let mut novaseq_run = NovaSeqRun::open_path(run_path, false).unwrap();
+
+        assert_eq!(novaseq_run.run_id, "@A00111:296:HJCWWDSXX");
+        assert_eq!(novaseq_run.lane_surfaces(), vec![[1, 1]]);
+        assert!(!novaseq_run.is_loaded([1, 1]));
+        assert_eq!(novaseq_run.tile_count(0), 0);
+
+        let e = novaseq_run
+            .load_lanes(&[2].iter().cloned().collect())
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+
+        novaseq_run
+            .load_lanes(&[1].iter().cloned().collect())
+            .unwrap();
+        assert!(novaseq_run.is_loaded([1, 1]));
+        assert_eq!(novaseq_run.tile_count(1), 3);
+        // the headers for every cycle are read together, then split up by read
+        let cycles =
+            |headers: &Vec<Vec<CBCLHeader>>| headers.iter().map(Vec::len).collect::<Vec<_>>();
+        assert_eq!(cycles(&novaseq_run.read_headers[&[1, 1]]), [4, 4]);
+        assert_eq!(cycles(&novaseq_run.index_headers[&[1, 1]]), [8, 8]);
+        assert!(novaseq_run.index_headers[&[1, 1]][1][0]
+            .cbcl_path
+            .ends_with("C13.1/L001_1.cbcl"));
+
+        // loading a lane again doesn't change it
+        novaseq_run.load_all().unwrap();
+        assert_eq!(novaseq_run.tile_count(0), 3);
+
+        let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let mut novaseq_run = NovaSeqRun::open_path(run_path, false).unwrap();
+        novaseq_run.load_lane_surface([1, 1]).unwrap();
+        assert_eq!(novaseq_run.locs_for([1, 1], 1).len(), 24);
+    }
+
+    #[test]
+    fn parse_lanes() {
+        assert_eq!(
+            super::parse_lanes("1,3-4").unwrap(),
+            [1, 3, 4].iter().cloned().collect()
+        );
+        assert_eq!(
+            super::parse_lanes("2").unwrap(),
+            [2].iter().cloned().collect()
+        );
+        assert!(super::parse_lanes("0").is_err());
+        assert!(super::parse_lanes("4-3").is_err());
+        assert!(super::parse_lanes("1,x").is_err());
+    }
+
+    #[test]
+    fn parse_excluded_tiles() {
+        let tiles =
+            super::parse_excluded_tiles("# bubbles\n1_1101, s_2_2204\n\n1_11203 # dim").unwrap();
+        assert_eq!(
+            tiles,
+            [(1, 1101), (1, 11203), (2, 2204)].iter().cloned().collect()
+        );
+        assert!(super::parse_excluded_tiles("1101").is_err());
+        assert!(super::parse_excluded_tiles("x_1101").is_err());
+    }
+
+    #[test]
+    fn exclude_tiles() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let mut novaseq_run = NovaSeqRun::open_path(run_path, false).unwrap();
+        novaseq_run.exclude_tiles([(1, 1102), (2, 1101)]);
+        novaseq_run.load_all().unwrap();
+
+        assert_eq!(novaseq_run.tile_ids[&[1, 1]], vec![1101, 1103]);
+        assert_eq!(novaseq_run.filters[&[1, 1]].len(), 2);
+        assert_eq!(
+            novaseq_run.read_headers[&[1, 1]][0][0].tiles,
+            vec![1101, 1103]
+        );
+
+        // tiles that RunInfo.xml doesn't list are skipped too
+        novaseq_run
+            .run_info
+            .flowcell_layout
+            .tiles
+            .retain(|tile| tile.number != 1103);
+        novaseq_run.exclude_tiles([]);
+        assert_eq!(novaseq_run.tile_ids[&[1, 1]], vec![1101]);
+        assert_eq!(novaseq_run.index_headers[&[1, 1]][0][0].start_pos.len(), 1);
+    }
+
+    #[test]
+    fn retain_shard() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let mut novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        let shard: Shard = "2/2".parse().unwrap();
+        novaseq_run.retain_shard(&shard);
+
+        assert_eq!(novaseq_run.tile_ids[&[1, 1]], vec![1102]);
+        assert_eq!(novaseq_run.n_pfs[&[1, 1]].len(), 1);
+        assert_eq!(novaseq_run.read_headers[&[1, 1]][0][0].tiles, vec![1102]);
+        assert_eq!(novaseq_run.index_headers[&[1, 1]][1][7].start_pos.len(), 1);
+    }
+
+    #[test]
+    fn retain_first_tiles() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let mut novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        novaseq_run.retain_first_tiles();
+
+        assert_eq!(novaseq_run.tile_ids[&[1, 1]], vec![1101]);
+        assert_eq!(novaseq_run.filters[&[1, 1]].len(), 1);
+        assert_eq!(novaseq_run.read_headers[&[1, 1]][0][0].tiles, vec![1101]);
+        assert_eq!(novaseq_run.clusters_raw(1), 100);
+    }
+
+    #[test]
+    fn parse_shard() {
+        assert_eq!(
+            "1/4".parse::<Shard>().unwrap(),
+            Shard { index: 1, count: 4 }
+        );
+        assert_eq!(Shard { index: 3, count: 4 }.suffix(), "shard3of4");
+        assert!("0/4".parse::<Shard>().is_err());
+        assert!("5/4".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
     }
 
     #[test]
@@ -211,6 +1089,94 @@ mod tests {
         assert_eq!(novaseq_run.run_id, "@A00111:296:HJCWWDSXX");
     }
 
+    /// copy a run folder, leaving out some of the cycles
+    fn copy_run(from: &Path, to: &Path, skip_cycles: &[&str]) {
+        std::fs::create_dir_all(to).unwrap();
+
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name();
+            if skip_cycles.iter().any(|c| name == *c) {
+                continue;
+            }
+
+            if entry.file_type().unwrap().is_dir() {
+                copy_run(&entry.path(), &to.join(&name), skip_cycles);
+            } else {
+                std::fs::copy(entry.path(), to.join(&name)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_run() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path_incomplete(run_path).unwrap();
+        assert_eq!(novaseq_run.run_info.total_cycles(), 24);
+        assert_eq!(novaseq_run.run_info_cycles().unwrap(), 24);
+
+        let partial_path = PathBuf::from("test_data/test_output/incomplete_run");
+        let _ = std::fs::remove_dir_all(&partial_path);
+        copy_run(
+            Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            &partial_path,
+            &["C23.1", "C24.1"],
+        );
+
+        let novaseq_run = NovaSeqRun::read_path_incomplete(partial_path.clone()).unwrap();
+        assert_eq!(novaseq_run.run_info.total_cycles(), 22);
+        assert_eq!(novaseq_run.run_info_cycles().unwrap(), 24);
+        assert_eq!(novaseq_run.read_headers[&[1, 1]][1].len(), 2);
+
+        let index_path = PathBuf::from("test_data/test_output/incomplete_index_run");
+        let _ = std::fs::remove_dir_all(&index_path);
+        copy_run(&partial_path, &index_path, &["C20.1"]);
+
+        assert!(NovaSeqRun::read_path_incomplete(index_path).is_err());
+    }
+
+    #[test]
+    fn split_lane_tiles() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        let tiles = |run: &NovaSeqRun| -> Vec<u32> {
+            let mut keys: Vec<_> = run.tile_ids.keys().cloned().collect();
+            keys.sort_unstable();
+            keys.iter().flat_map(|k| run.tile_ids[k].clone()).collect()
+        };
+
+        let parts = novaseq_run.split_lane_tiles(1, 2);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts.iter().map(|p| p.tile_count(1)).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            parts.iter().flat_map(tiles).collect::<Vec<_>>(),
+            tiles(&novaseq_run)
+        );
+        assert_eq!(parts[1].read_headers[&[1, 1]][0][0].tiles.len(), 2);
+
+        // no more parts than tiles
+        assert_eq!(novaseq_run.split_lane_tiles(1, 8).len(), 3);
+        assert_eq!(novaseq_run.split_lane_tiles(0, 8).len(), 3);
+    }
+
+    #[test]
+    fn missing_filter() {
+        let run_path = PathBuf::from("test_data/test_output/missing_filter");
+        let _ = std::fs::remove_dir_all(&run_path);
+        copy_run(
+            Path::new("test_data/210618_FS10000171_0042_BPA73113-1417"),
+            &run_path,
+            &["s_1_1102.filter"],
+        );
+
+        let e = NovaSeqRun::read_path(run_path, false).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert!(e.to_string().contains("Error reading filter"), "{}", e);
+    }
+
     #[test]
     #[should_panic(expected = r#"No such file or directory"#)]
     fn no_run() {
@@ -0,0 +1,190 @@
+//! NUMA-aware demux for nodes with more than one socket. Each NUMA node gets its
+//! own group of worker threads, pinned to the node's CPUs, and a share of the
+//! lanes, or of the tiles of each lane if there are fewer lanes than nodes. The
+//! buffers for a lane are allocated by the threads that demultiplex it, so with
+//! the kernel's first-touch policy they are in that node's memory, and the hot
+//! loops never reach across the interconnect
+
+use std::{fs, io, path::Path};
+
+use log::info;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::buffer_pool::{set_thread_pool, BufferPool};
+use crate::logging::{set_thread_context, LogContext};
+
+/// The most buffers to keep for reuse on each node
+const MAX_NODE_BUFFERS: usize = 128;
+
+/// The most memory to keep in buffers for reuse on each node
+const MAX_NODE_BYTES: usize = 2 << 30;
+
+/// The CPUs of one NUMA node
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaNode {
+    pub id: usize,
+    pub cpus: Vec<usize>,
+}
+
+/// Parse a list of CPUs in the format used by sysfs, e.g. `0-15,32-47`
+pub fn parse_cpulist(s: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("invalid CPU list '{}'", s);
+    let mut cpus = Vec::new();
+
+    for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.parse().map_err(|_| invalid())?;
+                let end: usize = end.parse().map_err(|_| invalid())?;
+                if end < start {
+                    return Err(invalid());
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+
+    Ok(cpus)
+}
+
+/// The NUMA nodes that have CPUs, from `/sys/devices/system/node`
+pub fn numa_nodes() -> io::Result<Vec<NumaNode>> {
+    read_numa_nodes(Path::new("/sys/devices/system/node"))
+}
+
+fn read_numa_nodes(node_path: &Path) -> io::Result<Vec<NumaNode>> {
+    let mut nodes = Vec::new();
+
+    for entry in fs::read_dir(node_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let id = match name.strip_prefix("node").and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+
+        let cpulist = fs::read_to_string(entry.path().join("cpulist"))?;
+        let cpus =
+            parse_cpulist(&cpulist).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // memory-only nodes have no CPUs to run on
+        if !cpus.is_empty() {
+            nodes.push(NumaNode { id, cpus });
+        }
+    }
+
+    nodes.sort_by_key(|n| n.id);
+    Ok(nodes)
+}
+
+/// Pin the current thread to `cpus`
+#[cfg(target_os = "linux")]
+pub fn pin_to_cpus(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_cpus(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning threads to CPUs is only supported on Linux",
+    ))
+}
+
+/// A thread pool with a thread for each CPU of `node`, pinned to the node. The
+/// threads share a buffer pool of their own, so the buffers they reuse stay in the
+/// node's memory, and a log context, so their lines have the node's lane
+pub fn node_pool(node: &NumaNode) -> io::Result<ThreadPool> {
+    let cpus = node.cpus.clone();
+    let id = node.id;
+    // there are only ever a few of these, so it's simplest to leak them
+    let buffers: &'static BufferPool =
+        Box::leak(Box::new(BufferPool::new(MAX_NODE_BUFFERS, MAX_NODE_BYTES)));
+    let log_context: &'static LogContext = Box::leak(Box::default());
+
+    ThreadPoolBuilder::new()
+        .num_threads(node.cpus.len())
+        .thread_name(move |i| format!("numa{}-{}", id, i))
+        .start_handler(move |_| {
+            set_thread_pool(buffers);
+            set_thread_context(log_context);
+            if let Err(e) = pin_to_cpus(&cpus) {
+                log::warn!("couldn't pin thread to NUMA node {}: {}", id, e);
+            }
+        })
+        .build()
+        .map_err(io::Error::other)
+}
+
+/// Share out `lanes` between `n_groups` groups, in turn, so that neighbouring lanes
+/// (which tend to be the same size) are on different nodes. Groups with no lanes
+/// are left out
+pub fn split_lanes(lanes: &[usize], n_groups: usize) -> Vec<Vec<usize>> {
+    let n_groups = n_groups.max(1);
+    let mut groups = vec![Vec::new(); n_groups];
+    for (i, &lane) in lanes.iter().enumerate() {
+        groups[i % n_groups].push(lane);
+    }
+    groups.retain(|g| !g.is_empty());
+
+    info!("lanes for each NUMA node: {:?}", groups);
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpulist() {
+        assert_eq!(
+            parse_cpulist("0-3,8,10-11\n").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpulist("\n").unwrap(), Vec::<usize>::new());
+        assert!(parse_cpulist("3-1").is_err());
+        assert!(parse_cpulist("a").is_err());
+    }
+
+    #[test]
+    fn read_nodes() {
+        let node_path = Path::new("test_data/test_output/numa_nodes");
+        let _ = fs::remove_dir_all(node_path);
+        for (node, cpulist) in [("node1", "4-7\n"), ("node0", "0-3\n"), ("node2", "\n")] {
+            fs::create_dir_all(node_path.join(node)).unwrap();
+            fs::write(node_path.join(node).join("cpulist"), cpulist).unwrap();
+        }
+        fs::write(node_path.join("possible"), "0-2\n").unwrap();
+
+        assert_eq!(
+            read_numa_nodes(node_path).unwrap(),
+            vec![
+                NumaNode {
+                    id: 0,
+                    cpus: vec![0, 1, 2, 3]
+                },
+                NumaNode {
+                    id: 1,
+                    cpus: vec![4, 5, 6, 7]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lanes() {
+        assert_eq!(split_lanes(&[1, 2, 3, 4], 2), vec![vec![1, 3], vec![2, 4]]);
+        assert_eq!(split_lanes(&[1], 2), vec![vec![1]]);
+    }
+}
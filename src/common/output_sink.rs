@@ -0,0 +1,1103 @@
+//! Where the fastq files are written. The demux formats each sample's reads a
+//! chunk of tiles at a time, and a sink adds each chunk to the end of the
+//! sample's file, encoded as the output options ask: plain text, gzip, BGZF, or
+//! zstd with the `zstd` feature. The output folder, an upload to object storage
+//! and named pipes are all sinks, so a new place to put the reads only needs a
+//! new sink.
+//!
+//! Each chunk is encoded on its own (a gzip member, BGZF blocks ending in the
+//! empty block, or a zstd frame), so the chunks of a file can simply be joined.
+//! This is also what lets plates with thousands of samples scatter their chunks
+//! to a few spill files, and gather each sample's file from them at the end
+
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        HashMap,
+    },
+    fmt,
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use flate2::{write::GzEncoder, Compress, Crc, FlushCompress, Status};
+use log::{info, warn};
+use rayon::prelude::*;
+
+use crate::buffer_pool::{buffer_pool, PooledBuffer, PooledWriter};
+use crate::storage::{OutputHandle, OutputStorage};
+use crate::write_fastq::OutputOptions;
+use crate::zstd_dictionary::ZstdDictionary;
+
+/// Writes the chunks of every output file
+pub trait OutputSink: Send + Sync {
+    /// Start a chunk at the end of the file at `path`, creating the file if this
+    /// is its first chunk
+    fn chunk<'a>(
+        &'a self,
+        path: &Path,
+        output_options: &OutputOptions,
+    ) -> io::Result<Box<dyn SinkChunk + 'a>>;
+
+    /// Add a chunk that was encoded ahead of time, as `chunk` would encode it, to
+    /// the end of the file at `path`
+    fn append_encoded(&self, path: &Path, encoded: &[u8]) -> io::Result<()>;
+
+    /// Write a whole file that isn't fastq, e.g. a copy of the zstd dictionary
+    fn put(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)
+    }
+
+    /// Finish every file, after their last chunks
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Throw away what the sink holds that isn't in the files yet, when the demux
+    /// stops with an error
+    fn abort(&self) {}
+
+    /// Remove the file at `path` left by an earlier demux, before the first chunk.
+    /// Returns true if there was one
+    fn clear(&self, path: &Path) -> io::Result<bool> {
+        if path.exists() {
+            fs::remove_file(path)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// True if the files end up in the output folder, once the sink is finished
+    fn in_output_folder(&self) -> bool {
+        false
+    }
+
+    /// The most files the sink has open at once, with `n_files` output files and
+    /// `n_writers` chunks being written at once. A file is only open while its
+    /// chunk is written, unless the sink keeps it
+    fn open_files(&self, _n_files: usize, n_writers: usize) -> usize {
+        n_writers
+    }
+}
+
+/// A chunk of reads for one file, encoded as it is written
+pub trait SinkChunk: Write {
+    /// End the chunk's encoding and add it to the file
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// A sink chosen for the output options. Handles are equal if they are the same sink
+#[derive(Clone)]
+pub struct SinkHandle(pub Arc<dyn OutputSink>);
+
+impl Deref for SinkHandle {
+    type Target = dyn OutputSink;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl PartialEq for SinkHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for SinkHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SinkHandle")
+    }
+}
+
+/// The sinks that can be chosen with `--sink`. Uploads are chosen with a URL instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkKind {
+    /// files in the output folder
+    File,
+    /// named pipes that are already at the paths of the output files
+    Fifo,
+    /// nowhere: the reads are formatted and encoded, then thrown away
+    Null,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(SinkKind::File),
+            "fifo" => Ok(SinkKind::Fifo),
+            "null" => Ok(SinkKind::Null),
+            _ => Err(format!("expected file, fifo or null, got '{}'", s)),
+        }
+    }
+}
+
+impl SinkKind {
+    /// The sink for the output options, or None for the output folder
+    pub fn sink(self) -> Option<SinkHandle> {
+        match self {
+            SinkKind::File => None,
+            SinkKind::Fifo => Some(SinkHandle(Arc::new(FifoSink::default()))),
+            SinkKind::Null => Some(SinkHandle(Arc::new(NullSink::default()))),
+        }
+    }
+}
+
+/// The most uncompressed bytes in a BGZF block, as htslib uses, so that the
+/// compressed block always fits in 64 KiB
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Room for a compressed block, which is never more than 64 KiB with its header
+const BGZF_MAX_COMPRESSED: usize = 0x10000;
+
+/// The empty block at the end of a BGZF file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compresses into BGZF blocks: gzip members of at most 64 KiB, with their size in
+/// a header field, which tools like `tabix` and `samtools` can seek between.
+///
+/// The blocks can't share a preset dictionary, as gzip has no field for one and
+/// readers would fail to inflate them, so each is compressed from scratch. The
+/// compressor and its buffers are reset and reused from block to block, though,
+/// instead of being allocated again for each one
+pub struct BgzfWriter<W: Write> {
+    inner: Option<W>,
+    deflate: Compress,
+    buffer: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W, level: u32) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner: Some(inner),
+            // raw deflate, the gzip header and trailer are written around it
+            deflate: Compress::new(flate2::Compression::new(level), false),
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+            compressed: Vec::with_capacity(BGZF_MAX_COMPRESSED),
+        }
+    }
+
+    /// Deflate the buffer into `compressed`
+    fn deflate_block(&mut self) -> io::Result<()> {
+        self.deflate.reset();
+        self.compressed.clear();
+        loop {
+            let consumed = self.deflate.total_in() as usize;
+            let status = self
+                .deflate
+                .compress_vec(
+                    &self.buffer[consumed..],
+                    &mut self.compressed,
+                    FlushCompress::Finish,
+                )
+                .map_err(io::Error::other)?;
+            if status == Status::StreamEnd {
+                return Ok(());
+            }
+            // only if it didn't fit, which a block of incompressible bytes can't
+            // quite do
+            self.compressed.reserve(BGZF_BLOCK_SIZE);
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.deflate_block()?;
+        let compressed = &self.compressed;
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+
+        // the block size, less one, goes in the BC field of the header
+        let block_size = (BGZF_EOF.len() - 2 + compressed.len() - 1) as u16;
+        let mut block = Vec::with_capacity(block_size as usize + 1);
+        block.extend_from_slice(&BGZF_EOF[..16]);
+        block.extend_from_slice(&block_size.to_le_bytes());
+        block.extend_from_slice(compressed);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(self.buffer.len() as u32).to_le_bytes());
+
+        self.inner.as_mut().unwrap().write_all(&block)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Write the last block and the empty block that ends the file
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        let mut inner = self.inner.take().unwrap();
+        inner.write_all(&BGZF_EOF)?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        // errors are ignored here, the same as GzEncoder: finish first to see them
+        if self.inner.is_some() {
+            let _ = self.write_block();
+            let _ = self.inner.as_mut().unwrap().write_all(&BGZF_EOF);
+        }
+    }
+}
+
+/// The encoding of the output files
+pub(crate) enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Bgzf(BgzfWriter<W>),
+    /// with the dictionary it refers to, if there is one, kept until it is done
+    #[cfg(feature = "zstd")]
+    Zstd(
+        zstd::stream::write::Encoder<'static, W>,
+        Option<Arc<ZstdDictionary>>,
+    ),
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(out: W, output_options: &OutputOptions) -> Encoder<W> {
+        match output_options.compression {
+            None => Encoder::Plain(out),
+            Some(level) if output_options.zstd => {
+                Encoder::zstd(out, level, output_options.zstd_dictionary.clone())
+            }
+            Some(level) if output_options.bgzf => Encoder::Bgzf(BgzfWriter::new(out, level)),
+            Some(level) => Encoder::Gzip(GzEncoder::new(out, flate2::Compression::new(level))),
+        }
+    }
+
+    /// A zstd frame, with a checksum like a gzip member's. A dictionary is prepared
+    /// for the compression level already
+    #[cfg(feature = "zstd")]
+    fn zstd(out: W, level: u32, dictionary: Option<Arc<ZstdDictionary>>) -> Encoder<W> {
+        let mut encoder = match &dictionary {
+            Some(dictionary) => {
+                zstd::stream::write::Encoder::with_prepared_dictionary(out, dictionary.prepared())
+            }
+            None => zstd::stream::write::Encoder::new(out, level as i32),
+        }
+        .unwrap_or_else(|e| panic!("Error starting zstd: {}", e));
+        encoder
+            .include_checksum(true)
+            .unwrap_or_else(|e| panic!("Error starting zstd: {}", e));
+        Encoder::Zstd(encoder, dictionary)
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn zstd(_out: W, _level: u32, _dictionary: Option<Arc<ZstdDictionary>>) -> Encoder<W> {
+        panic!("bcl2fastr was built without the zstd feature, so it can't write zstd")
+    }
+
+    /// End the encoding, and return the writer underneath
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(out) => Ok(out),
+            Encoder::Gzip(writer) => writer.finish(),
+            Encoder::Bgzf(writer) => writer.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(writer, _dictionary) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(out) => out.write(buf),
+            Encoder::Gzip(writer) => writer.write(buf),
+            Encoder::Bgzf(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(writer, _) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(out) => out.flush(),
+            Encoder::Gzip(writer) => writer.flush(),
+            Encoder::Bgzf(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(writer, _) => writer.flush(),
+        }
+    }
+}
+
+/// A chunk written through an encoder into a writer that doesn't need anything
+/// more than a flush at the end
+struct StreamChunk<W: Write>(Encoder<W>);
+
+impl<W: Write> Write for StreamChunk<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> SinkChunk for StreamChunk<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.0.finish()?.flush()
+    }
+}
+
+/// Files in the output folder
+pub struct FileSink;
+
+impl OutputSink for FileSink {
+    fn chunk<'a>(
+        &'a self,
+        path: &Path,
+        output_options: &OutputOptions,
+    ) -> io::Result<Box<dyn SinkChunk + 'a>> {
+        let out_file = OpenOptions::new().create(true).append(true).open(path)?;
+        let out_file = PooledWriter::new(out_file, output_options.write_buffer);
+
+        Ok(Box::new(StreamChunk(Encoder::new(
+            out_file,
+            output_options,
+        ))))
+    }
+
+    fn append_encoded(&self, path: &Path, encoded: &[u8]) -> io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(encoded)
+    }
+}
+
+/// A chunk for an upload, encoded in memory and then added to the file as one part
+struct UploadChunk<'a> {
+    upload: &'a OutputHandle,
+    path: PathBuf,
+    encoder: Encoder<PooledBuffer<'static>>,
+}
+
+impl Write for UploadChunk<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl SinkChunk for UploadChunk<'_> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let block = self.encoder.finish()?;
+        self.upload.append(&self.path, &block)
+    }
+}
+
+/// Files uploaded to object storage as they are written
+impl OutputSink for OutputHandle {
+    fn chunk<'a>(
+        &'a self,
+        path: &Path,
+        output_options: &OutputOptions,
+    ) -> io::Result<Box<dyn SinkChunk + 'a>> {
+        Ok(Box::new(UploadChunk {
+            upload: self,
+            path: path.to_path_buf(),
+            encoder: Encoder::new(
+                buffer_pool().get(output_options.write_buffer),
+                output_options,
+            ),
+        }))
+    }
+
+    fn append_encoded(&self, path: &Path, encoded: &[u8]) -> io::Result<()> {
+        OutputStorage::append(&**self, path, encoded)
+    }
+
+    fn put(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        OutputStorage::put(&**self, path, contents.to_vec())
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        OutputStorage::finish(&**self)
+    }
+
+    fn clear(&self, path: &Path) -> io::Result<bool> {
+        OutputStorage::discard(&**self, path)
+    }
+}
+
+/// A pipe that stays open for all of a file's chunks
+struct SharedFile(Arc<File>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+/// Named pipes that are already at the paths of the output files, e.g. made with
+/// `mkfifo`, for tools that read the reads as they are written. Each pipe is
+/// opened at its first chunk, which waits for a reader, and kept open until the
+/// demux finishes, so that the reader only sees the end of the file then
+#[derive(Default)]
+pub struct FifoSink {
+    pipes: Mutex<HashMap<PathBuf, Arc<File>>>,
+}
+
+impl FifoSink {
+    /// The pipe at `path`, opened at its first chunk
+    fn pipe(&self, path: &Path) -> io::Result<Arc<File>> {
+        // the lock isn't held while a pipe waits for its reader
+        let open = self.pipes.lock().unwrap().get(path).cloned();
+        if let Some(pipe) = open {
+            return Ok(pipe);
+        }
+
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no named pipe at {}", path.display()),
+            ));
+        }
+        let pipe = Arc::new(OpenOptions::new().write(true).open(path)?);
+        Ok(self
+            .pipes
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_insert(pipe)
+            .clone())
+    }
+}
+
+impl OutputSink for FifoSink {
+    fn chunk<'a>(
+        &'a self,
+        path: &Path,
+        output_options: &OutputOptions,
+    ) -> io::Result<Box<dyn SinkChunk + 'a>> {
+        let out = PooledWriter::new(SharedFile(self.pipe(path)?), output_options.write_buffer);
+        Ok(Box::new(StreamChunk(Encoder::new(out, output_options))))
+    }
+
+    fn append_encoded(&self, path: &Path, encoded: &[u8]) -> io::Result<()> {
+        (&*self.pipe(path)?).write_all(encoded)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        // closing the pipes ends the files for their readers
+        self.pipes.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear(&self, _path: &Path) -> io::Result<bool> {
+        // the pipes are made beforehand, so they're left where they are
+        Ok(false)
+    }
+
+    fn open_files(&self, n_files: usize, _n_writers: usize) -> usize {
+        n_files
+    }
+}
+
+/// The most output files kept open with `--many-samples`, by default
+pub const DEFAULT_MAX_OPEN_FILES: usize = 512;
+
+/// The output files that are open, and when each was last written
+#[derive(Default)]
+struct OpenFiles {
+    files: HashMap<PathBuf, (Arc<File>, u64)>,
+    n_chunks: u64,
+}
+
+/// Files in the output folder that are kept open between their chunks, up to
+/// `max_open` of them, closing the one that was written longest ago to open
+/// another. With thousands of samples, this saves opening every file again for
+/// each chunk of tiles without running out of file handles
+pub struct LruFileSink {
+    max_open: usize,
+    open: Mutex<OpenFiles>,
+}
+
+impl LruFileSink {
+    pub fn new(max_open: usize) -> LruFileSink {
+        LruFileSink {
+            max_open: max_open.max(1),
+            open: Mutex::new(OpenFiles::default()),
+        }
+    }
+
+    /// The number of files that are open
+    pub fn n_open(&self) -> usize {
+        self.open.lock().unwrap().files.len()
+    }
+
+    fn file(&self, path: &Path) -> io::Result<Arc<File>> {
+        let mut open = self.open.lock().unwrap();
+        open.n_chunks += 1;
+        let n_chunks = open.n_chunks;
+        if let Some((file, last_chunk)) = open.files.get_mut(path) {
+            *last_chunk = n_chunks;
+            return Ok(file.clone());
+        }
+
+        if open.files.len() >= self.max_open {
+            let oldest = open
+                .files
+                .iter()
+                .min_by_key(|(_, (_, last_chunk))| *last_chunk)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                open.files.remove(&oldest);
+            }
+        }
+
+        let file = Arc::new(OpenOptions::new().create(true).append(true).open(path)?);
+        open.files
+            .insert(path.to_path_buf(), (file.clone(), n_chunks));
+        Ok(file)
+    }
+}
+
+impl OutputSink for LruFileSink {
+    fn chunk<'a>(
+        &'a self,
+        path: &Path,
+        output_options: &OutputOptions,
+    ) -> io::Result<Box<dyn SinkChunk + 'a>> {
+        // a file that is closed while its chunk is written stays open until the end
+        let out = PooledWriter::new(SharedFile(self.file(path)?), output_options.write_buffer);
+        Ok(Box::new(StreamChunk(Encoder::new(out, output_options))))
+    }
+
+    fn append_encoded(&self, path: &Path, encoded: &[u8]) -> io::Result<()> {
+        (&*self.file(path)?).write_all(encoded)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.open.lock().unwrap().files.clear();
+        Ok(())
+    }
+
+    fn in_output_folder(&self) -> bool {
+        true
+    }
+
+    fn open_files(&self, n_files: usize, n_writers: usize) -> usize {
+        // and the files that were closed while their chunks were being written
+        self.max_open.min(n_files) + n_writers
+    }
+}
+
+/// Spill files are started again after this many bytes
+const SPILL_FILE_SIZE: u64 = 1 << 30;
+
+/// Where one chunk of a file was spilled
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    spill_i: usize,
+    offset: u64,
+    len: u64,
+}
+
+/// The spill files of one group of output files
+#[derive(Default)]
+struct Spills {
+    paths: Vec<PathBuf>,
+    current: Option<BufWriter<File>>,
+    current_len: u64,
+    /// the chunks of each output file, in order
+    segments: HashMap<PathBuf, Vec<Segment>>,
+}
+
+/// Writes each encoded chunk to the end of a spill file in `dir` as the tiles are
+/// demultiplexed, then gathers every output file from its chunks when the sink is
+/// finished. The output files are split into groups that each have their own
+/// spill file, so only one spill file for each group is open while
+/// demultiplexing and one output file for each thread while gathering, however
+/// many samples there are, at the cost of writing everything twice. The spill
+/// files are removed when the sink is finished, aborted or dropped
+pub struct ScatterSink {
+    dir: PathBuf,
+    groups: Vec<Mutex<Spills>>,
+}
+
+impl ScatterSink {
+    /// A sink spilling to `n_groups` files at once, e.g. one for each writer group
+    pub fn new(dir: PathBuf, n_groups: usize) -> ScatterSink {
+        ScatterSink {
+            dir,
+            groups: (0..n_groups.max(1)).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn spill(&self, path: &Path, block: &[u8]) -> io::Result<()> {
+        // every chunk of a file goes to the same group, so they stay in order
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let group_i = (hasher.finish() % self.groups.len() as u64) as usize;
+        let mut spills = self.groups[group_i].lock().unwrap();
+
+        if spills.current.is_none() || spills.current_len >= SPILL_FILE_SIZE {
+            if let Some(mut current) = spills.current.take() {
+                current.flush()?;
+            }
+            fs::create_dir_all(&self.dir)?;
+            let spill_path = self
+                .dir
+                .join(format!("spill_{}_{}", group_i, spills.paths.len()));
+            spills.current = Some(BufWriter::new(File::create(&spill_path)?));
+            spills.current_len = 0;
+            spills.paths.push(spill_path);
+        }
+
+        let segment = Segment {
+            spill_i: spills.paths.len() - 1,
+            offset: spills.current_len,
+            len: block.len() as u64,
+        };
+        spills.current.as_mut().unwrap().write_all(block)?;
+        spills.current_len += segment.len;
+        spills
+            .segments
+            .entry(path.to_path_buf())
+            .or_default()
+            .push(segment);
+
+        Ok(())
+    }
+
+    /// Gather every output file from the spill files
+    fn gather(&self) -> io::Result<()> {
+        let mut groups: Vec<_> = self
+            .groups
+            .iter()
+            .map(|spills| std::mem::take(&mut *spills.lock().unwrap()))
+            .collect();
+        for spills in groups.iter_mut() {
+            if let Some(mut current) = spills.current.take() {
+                current.flush()?;
+            }
+        }
+
+        groups
+            .par_iter()
+            .flat_map(|spills| {
+                spills
+                    .segments
+                    .par_iter()
+                    .map(move |(path, segments)| (path, segments, &spills.paths))
+            })
+            .try_for_each(|(path, segments, spill_paths)| {
+                gather_file(path, segments, spill_paths).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Error gathering {}: {}", path.display(), e),
+                    )
+                })
+            })?;
+        info!(
+            "gathered {} fastq files from {} spill files",
+            groups
+                .iter()
+                .map(|spills| spills.segments.len())
+                .sum::<usize>(),
+            groups
+                .iter()
+                .map(|spills| spills.paths.len())
+                .sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    /// Remove the spill files, and the files still open
+    fn remove_spills(&self) -> io::Result<()> {
+        for spills in self.groups.iter() {
+            if let Ok(mut spills) = spills.lock() {
+                *spills = Spills::default();
+            }
+        }
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScatterSink {
+    fn drop(&mut self) {
+        // e.g. a panic while demultiplexing
+        let _ = self.remove_spills();
+    }
+}
+
+/// Copy the chunks of the file at `path` out of the spill files
+fn gather_file(path: &Path, segments: &[Segment], spill_paths: &[PathBuf]) -> io::Result<()> {
+    let mut out = BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?);
+    let mut spill_files: HashMap<usize, File> = HashMap::new();
+
+    for segment in segments {
+        let spill = match spill_files.entry(segment.spill_i) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(File::open(&spill_paths[segment.spill_i])?),
+        };
+        spill.seek(SeekFrom::Start(segment.offset))?;
+        let copied = io::copy(&mut spill.take(segment.len), &mut out)?;
+        if copied < segment.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is truncated", spill_paths[segment.spill_i].display()),
+            ));
+        }
+    }
+
+    out.flush()
+}
+
+/// A chunk encoded in memory, then spilled as one segment
+struct ScatterChunk<'a> {
+    sink: &'a ScatterSink,
+    path: PathBuf,
+    encoder: Encoder<PooledBuffer<'static>>,
+}
+
+impl Write for ScatterChunk<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl SinkChunk for ScatterChunk<'_> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let block = self.encoder.finish()?;
+        self.sink.spill(&self.path, &block)
+    }
+}
+
+impl OutputSink for ScatterSink {
+    fn chunk<'a>(
+        &'a self,
+        path: &Path,
+        output_options: &OutputOptions,
+    ) -> io::Result<Box<dyn SinkChunk + 'a>> {
+        Ok(Box::new(ScatterChunk {
+            sink: self,
+            path: path.to_path_buf(),
+            encoder: Encoder::new(
+                buffer_pool().get(output_options.write_buffer),
+                output_options,
+            ),
+        }))
+    }
+
+    fn append_encoded(&self, path: &Path, encoded: &[u8]) -> io::Result<()> {
+        self.spill(path, encoded)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let gathered = self.gather();
+        self.remove_spills()?;
+        gathered
+    }
+
+    fn abort(&self) {
+        if let Err(e) = self.remove_spills() {
+            warn!("Error removing {}: {}", self.dir.display(), e);
+        }
+    }
+
+    fn in_output_folder(&self) -> bool {
+        true
+    }
+
+    fn open_files(&self, _n_files: usize, n_writers: usize) -> usize {
+        // the spill file of each group, then an output file and a spill file for
+        // each gathering thread
+        self.groups.len() + 2 * n_writers
+    }
+}
+
+/// Counts the bytes written to it, and drops them
+struct Discard<'a>(&'a AtomicU64);
+
+impl Write for Discard<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Throws the reads away once they're encoded, to measure how fast a run can be
+/// read, matched and compressed without the output storage. Nothing is written
+/// or removed in the output folder except the stats and reports
+#[derive(Default)]
+pub struct NullSink {
+    bytes: AtomicU64,
+}
+
+impl NullSink {
+    /// The bytes that were thrown away
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl OutputSink for NullSink {
+    fn chunk<'a>(
+        &'a self,
+        _path: &Path,
+        output_options: &OutputOptions,
+    ) -> io::Result<Box<dyn SinkChunk + 'a>> {
+        Ok(Box::new(StreamChunk(Encoder::new(
+            Discard(&self.bytes),
+            output_options,
+        ))))
+    }
+
+    fn append_encoded(&self, _path: &Path, encoded: &[u8]) -> io::Result<()> {
+        self.bytes
+            .fetch_add(encoded.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn put(&self, _path: &Path, _contents: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        info!("discarded {} bytes of fastq files", self.bytes());
+        Ok(())
+    }
+
+    fn clear(&self, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn open_files(&self, _n_files: usize, _n_writers: usize) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn read_gz(path: &Path) -> String {
+        let mut contents = String::new();
+        flate2::read::MultiGzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_chunks() {
+        let output_path = PathBuf::from("test_data/test_output/output_sink_zstd");
+        let _ = fs::remove_dir_all(&output_path);
+        fs::create_dir_all(&output_path).unwrap();
+        let path = output_path.join("reads.fastq.zst");
+
+        let output_options = OutputOptions {
+            zstd: true,
+            ..Default::default()
+        };
+        // a frame for each chunk, and an empty one
+        let reads = "@read\nACGT\n+\nFFFF\n".repeat(5000);
+        for chunk_reads in [&reads[..], "", &reads[..]] {
+            let mut chunk = FileSink.chunk(&path, &output_options).unwrap();
+            chunk.write_all(chunk_reads.as_bytes()).unwrap();
+            chunk.finish().unwrap();
+        }
+
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(&contents[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+        let decoded = zstd::stream::decode_all(&contents[..]).unwrap();
+        assert_eq!(decoded, reads.repeat(2).as_bytes());
+    }
+
+    #[test]
+    fn bgzf() {
+        let output_path = PathBuf::from("test_data/test_output/output_sink");
+        let _ = fs::remove_dir_all(&output_path);
+        fs::create_dir_all(&output_path).unwrap();
+        let path = output_path.join("reads.fastq.gz");
+
+        let output_options = OutputOptions {
+            bgzf: true,
+            ..Default::default()
+        };
+        // more than one block, in two chunks
+        let reads = "@read\nACGT\n+\nFFFF\n".repeat(5000);
+        for _ in 0..2 {
+            let mut chunk = FileSink.chunk(&path, &output_options).unwrap();
+            chunk.write_all(reads.as_bytes()).unwrap();
+            chunk.finish().unwrap();
+        }
+        assert_eq!(read_gz(&path), reads.repeat(2));
+
+        // each block has its size in the header, and the file ends with the empty block
+        let contents = fs::read(&path).unwrap();
+        let block_size = u16::from_le_bytes([contents[16], contents[17]]) as usize + 1;
+        assert_eq!(&contents[block_size..block_size + 4], &BGZF_EOF[..4]);
+        assert!(contents.ends_with(&BGZF_EOF));
+
+        // blocks that don't compress still fit, with the compressor reused for each
+        let mut noise = Vec::new();
+        let mut x = 1u32;
+        for _ in 0..3 * BGZF_BLOCK_SIZE {
+            x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            noise.push((x >> 24) as u8);
+        }
+        let mut writer = BgzfWriter::new(Vec::new(), 6);
+        writer.write_all(&noise).unwrap();
+        let mut decoded = Vec::new();
+        flate2::read::MultiGzDecoder::new(&writer.finish().unwrap()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, noise);
+
+        assert!(FileSink.clear(&path).unwrap());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn null_sink() {
+        let sink = NullSink::default();
+        let output_options = OutputOptions {
+            compression: None,
+            ..Default::default()
+        };
+        let path = Path::new("test_data/test_output/null_sink/reads.fastq");
+
+        let mut chunk = sink.chunk(path, &output_options).unwrap();
+        chunk.write_all(b"@read\nACGT\n+\nFFFF\n").unwrap();
+        chunk.finish().unwrap();
+        assert_eq!(sink.bytes(), 18);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn lru_file_sink() {
+        let output_path = PathBuf::from("test_data/test_output/lru_file_sink");
+        let _ = fs::remove_dir_all(&output_path);
+        fs::create_dir_all(&output_path).unwrap();
+
+        let sink = LruFileSink::new(2);
+        let paths: Vec<_> = (0..3)
+            .map(|i| output_path.join(format!("{}.fastq.gz", i)))
+            .collect();
+        // the first file is closed for the third, then opened again
+        for path in paths.iter().chain(&paths[..1]) {
+            let mut chunk = sink.chunk(path, &Default::default()).unwrap();
+            chunk.write_all(path.to_str().unwrap().as_bytes()).unwrap();
+            chunk.finish().unwrap();
+            assert!(sink.n_open() <= 2);
+        }
+        sink.finish().unwrap();
+        assert_eq!(sink.n_open(), 0);
+
+        let first = paths[0].to_str().unwrap();
+        assert_eq!(read_gz(&paths[0]), first.repeat(2));
+        assert_eq!(read_gz(&paths[2]), paths[2].to_str().unwrap());
+    }
+
+    #[test]
+    fn scatter_sink() {
+        let output_path = PathBuf::from("test_data/test_output/scatter_sink");
+        let _ = fs::remove_dir_all(&output_path);
+        fs::create_dir_all(&output_path).unwrap();
+
+        let sink = ScatterSink::new(output_path.join("spill"), 2);
+        let paths = [
+            output_path.join("a.fastq.gz"),
+            output_path.join("b.fastq.gz"),
+        ];
+        // the chunks of the two files are spilled in turn
+        for i in 0..3 {
+            for path in paths.iter() {
+                let mut chunk = sink.chunk(path, &Default::default()).unwrap();
+                writeln!(chunk, "{} {}", path.display(), i).unwrap();
+                chunk.finish().unwrap();
+            }
+        }
+        assert!(!paths[0].exists());
+        assert!(fs::read_dir(output_path.join("spill")).unwrap().count() > 0);
+
+        sink.finish().unwrap();
+        assert_eq!(
+            read_gz(&paths[1]),
+            format!("{0} 0\n{0} 1\n{0} 2\n", paths[1].display())
+        );
+        assert!(!output_path.join("spill").exists());
+
+        // the spill files of a sink that wasn't finished are removed too
+        let sink = ScatterSink::new(output_path.join("spill"), 2);
+        sink.append_encoded(&paths[0], b"spilled").unwrap();
+        assert!(output_path.join("spill").exists());
+        sink.abort();
+        assert!(!output_path.join("spill").exists());
+        sink.append_encoded(&paths[0], b"spilled").unwrap();
+        drop(sink);
+        assert!(!output_path.join("spill").exists());
+    }
+
+    #[test]
+    fn sink_kind() {
+        assert_eq!("fifo".parse(), Ok(SinkKind::Fifo));
+        assert!("file".parse::<SinkKind>().unwrap().sink().is_none());
+        assert!("s3".parse::<SinkKind>().is_err());
+    }
+}
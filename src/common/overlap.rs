@@ -0,0 +1,220 @@
+//! Find where the two reads of a pair overlap, when the insert is shorter than the
+//! reads put together, as in most amplicon libraries. Where the reads disagree in
+//! the overlap, the call with the higher quality score can be taken for both, and
+//! when the insert is shorter than a read, the bases past it (read-through into
+//! the adapter) can be trimmed. The overlap finds read-through where adapter
+//! matching would miss it, e.g. when the adapter is too short or too degraded
+
+/// The largest fraction of the overlap that can disagree
+const MAX_MISMATCH_FRACTION: f64 = 0.1;
+
+/// What a mismatch in the overlap costs, against 1 for a match, when choosing
+/// between overlaps
+const MISMATCH_PENALTY: usize = 3;
+
+/// How the reads of a pair are overlapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverlapOptions {
+    /// the fewest bases that the reads have to overlap by
+    pub min_overlap: usize,
+    /// correct the bases where the reads disagree
+    pub correct: bool,
+    /// trim the bases of each read past the end of the insert
+    pub trim: bool,
+}
+
+/// A base of one read replaced with the call from the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Correction {
+    /// from 0, in the read that is corrected
+    pub position: usize,
+    pub base: u8,
+    pub qscore: u8,
+}
+
+/// The overlap of the reads of one cluster
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PairOverlap {
+    /// the length of the insert, if the reads overlap
+    pub insert_len: Option<usize>,
+    /// the corrections to the first and second read
+    pub corrections: [Vec<Correction>; 2],
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => b'N',
+    }
+}
+
+/// The length of the insert of a pair, from the best overlap of the first read
+/// with the reverse complement of the second, or None if they don't overlap by
+/// `min_overlap` bases. Base `p` of the first read is base `insert_len - 1 - p`
+/// of the second
+pub fn find_insert(r1: &[u8], r2: &[u8], min_overlap: usize) -> Option<usize> {
+    let min_overlap = min_overlap.max(1);
+    // (score, insert length)
+    let mut best: Option<(usize, usize)> = None;
+
+    for insert_len in min_overlap..=(r1.len() + r2.len()).saturating_sub(min_overlap) {
+        let start = insert_len.saturating_sub(r2.len());
+        let end = insert_len.min(r1.len());
+        if end < start + min_overlap {
+            continue;
+        }
+
+        let overlap = end - start;
+        let max_mismatches = (overlap as f64 * MAX_MISMATCH_FRACTION) as usize;
+        let mut mismatches = 0;
+        for p in start..end {
+            if r1[p] == b'N' || r1[p] != complement(r2[insert_len - 1 - p]) {
+                mismatches += 1;
+                if mismatches > max_mismatches {
+                    break;
+                }
+            }
+        }
+        if mismatches > max_mismatches {
+            continue;
+        }
+
+        let score = overlap - (1 + MISMATCH_PENALTY) * mismatches;
+        if best.is_none_or(|(best_score, _)| score > best_score) {
+            best = Some((score, insert_len));
+        }
+    }
+
+    best.map(|(_, insert_len)| insert_len)
+}
+
+/// Find the overlap of a pair of reads, each given as its bases and quality
+/// scores, and the corrections that make them agree. A base is only corrected
+/// from a call with a higher quality score, so where both are as good the reads
+/// are left to disagree
+pub fn overlap_pair(
+    r1: (&[u8], &[u8]),
+    r2: (&[u8], &[u8]),
+    options: &OverlapOptions,
+) -> PairOverlap {
+    let insert_len = match find_insert(r1.0, r2.0, options.min_overlap) {
+        Some(insert_len) => insert_len,
+        None => return PairOverlap::default(),
+    };
+
+    let mut corrections = [Vec::new(), Vec::new()];
+    if !options.correct {
+        return PairOverlap {
+            insert_len: Some(insert_len),
+            corrections,
+        };
+    }
+
+    for p in insert_len.saturating_sub(r2.0.len())..insert_len.min(r1.0.len()) {
+        let q = insert_len - 1 - p;
+        if r1.0[p] == complement(r2.0[q]) && r1.0[p] != b'N' {
+            continue;
+        }
+
+        if r1.1[p] > r2.1[q] {
+            corrections[1].push(Correction {
+                position: q,
+                base: complement(r1.0[p]),
+                qscore: r1.1[p],
+            });
+        } else if r2.1[q] > r1.1[p] {
+            corrections[0].push(Correction {
+                position: p,
+                base: complement(r2.0[q]),
+                qscore: r2.1[q],
+            });
+        }
+    }
+
+    // the second read's were found from its end
+    corrections[1].reverse();
+
+    PairOverlap {
+        insert_len: Some(insert_len),
+        corrections,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+        seq.iter().rev().map(|&b| complement(b)).collect()
+    }
+
+    #[test]
+    fn find_inserts() {
+        let insert = b"ACGTTGCATGCAAGTCCGATAGGCTTACGA";
+        let adapter = b"AGATCGGAAGAGCACA";
+
+        // a 30 base insert read through into the adapter by 10 bases
+        let r1 = [&insert[..], &adapter[..10]].concat();
+        let r2 = [reverse_complement(insert), adapter[..10].to_vec()].concat();
+        assert_eq!(find_insert(&r1, &r2, 10), Some(30));
+
+        // reads of 20 that overlap by 10, with one mismatch
+        let r1 = insert[..20].to_vec();
+        let mut r2 = reverse_complement(&insert[..])[..20].to_vec();
+        r2[15] = b'C';
+        assert_eq!(find_insert(&r1, &r2, 10), Some(30));
+        assert_eq!(find_insert(&r1, &r2, 12), None);
+
+        assert_eq!(find_insert(b"ACGTACGT", b"TTTTTTTT", 4), None);
+    }
+
+    #[test]
+    fn correct_pair() {
+        let insert = b"ACGTTGCATGCAAGTCCGATAGGCTTACGA";
+        let r1 = insert[..20].to_vec();
+        let mut r2 = reverse_complement(&insert[..])[..20].to_vec();
+        // base 15 of R2 is base 14 of R1, which is a T
+        r2[15] = b'C';
+        let q1 = vec![b'F'; 20];
+        let mut q2 = vec![b'F'; 20];
+
+        // as good as each other, so neither is corrected
+        let options = OverlapOptions {
+            min_overlap: 10,
+            correct: true,
+            trim: false,
+        };
+        let overlap = overlap_pair((&r1, &q1), (&r2, &q2), &options);
+        assert_eq!(overlap.insert_len, Some(30));
+        assert_eq!(overlap.corrections, [vec![], vec![]]);
+
+        q2[15] = b'#';
+        let overlap = overlap_pair((&r1, &q1), (&r2, &q2), &options);
+        assert_eq!(
+            overlap.corrections,
+            [
+                vec![],
+                vec![Correction {
+                    position: 15,
+                    base: b'A',
+                    qscore: b'F'
+                }]
+            ]
+        );
+
+        let overlap = overlap_pair((&r1, &q1), (b"TTTTTTTTTT", &q1[..10]), &options);
+        assert_eq!(overlap, PairOverlap::default());
+
+        // only the insert is found without correcting
+        let options = OverlapOptions {
+            correct: false,
+            ..options
+        };
+        let overlap = overlap_pair((&r1, &q1), (&r2, &q2), &options);
+        assert_eq!(overlap.insert_len, Some(30));
+        assert_eq!(overlap.corrections, [vec![], vec![]]);
+    }
+}
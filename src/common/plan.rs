@@ -0,0 +1,653 @@
+//! Plans a demux without reading any basecalls: which tiles will be read together,
+//! roughly how much output will be written, and how much memory the buffers need.
+//! Useful for checking a new run folder before committing cluster time to it
+
+use std::{fmt, fs::File, io::BufWriter, path::Path};
+
+use serde::Serialize;
+
+use crate::compression_workers::BLOCK_SIZE;
+use crate::novaseq_run::NovaSeqRun;
+use crate::sample_data::SampleData;
+use crate::write_fastq::{
+    buffer_dims, read_buffer_cycles, OutputOptions, DEFAULT_QUEUE_DEPTH, DEFAULT_WRITE_BUFFER,
+};
+
+/// A rough compression ratio for gzipped fastq at a low compression level
+const GZIP_RATIO: f64 = 0.25;
+
+/// The length of the `:lane:tile:x:y` part of a read header, for a NovaSeq run
+const LOCATION_HEADER_LEN: usize = 20;
+
+/// The largest write buffer we'll use for an output file
+const MAX_WRITE_BUFFER: usize = 1 << 20;
+
+/// The fraction of the memory limit that can go to write buffers
+const WRITE_BUFFER_FRACTION: u64 = 8;
+
+/// The most blocks we'll queue for each compression worker
+const MAX_QUEUE_DEPTH: usize = 16;
+
+/// The fraction of the available memory that auto-tuning uses, leaving the rest
+/// for the headers, stats and everything else running on the machine
+const AVAILABLE_MEMORY_FRACTION: u64 = 2;
+
+/// A group of tiles that are read and demultiplexed together
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TileChunk {
+    pub lane: usize,
+    pub surface: usize,
+    pub tiles: Vec<u32>,
+    pub clusters_pf: u64,
+}
+
+/// The work that a demux would do, and estimates of its output size and memory use
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DemuxPlan {
+    pub run_id: String,
+    pub lanes: Vec<usize>,
+    pub read_chunks: usize,
+    pub n_samples: usize,
+    pub n_output_files: usize,
+    pub clusters_pf: u64,
+    pub chunks: Vec<TileChunk>,
+    /// assumes every pass-filter cluster is assigned to a sample, so this is an
+    /// upper bound for uncompressed output
+    pub estimated_output_bytes: u64,
+    /// the read, index, and location buffers, plus the locs for the run
+    pub estimated_memory_bytes: u64,
+}
+
+/// Compute the plan for demultiplexing `sample_data` from `novaseq_run`, reading
+/// `n_chunks` tiles at a time
+pub fn plan_demux(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    n_chunks: usize,
+    output_options: &OutputOptions,
+) -> DemuxPlan {
+    let run_info = &novaseq_run.run_info;
+
+    let mut lanes: Vec<_> = sample_data.keys().cloned().collect();
+    lanes.sort_unstable();
+
+    let read_cycles: Vec<_> = novaseq_run
+        .read_structure
+        .templates()
+        .filter(|s| output_options.writes_read(s.kind_number))
+        .map(|s| s.num_cycles())
+        .collect();
+
+    let [_, n_idx_cycles, _] = buffer_dims(novaseq_run);
+
+    let mut chunks = Vec::new();
+    let mut n_samples = 0;
+
+    for &lane_n in lanes.iter() {
+        n_samples += sample_data[&lane_n].sample_names.len();
+
+        let lane_iter = if lane_n == 0 {
+            1..=run_info.flowcell_layout.lane_count
+        } else {
+            lane_n..=lane_n
+        };
+
+        for lane in lane_iter {
+            for surface in run_info.flowcell_layout.surfaces(lane) {
+                let (tile_ids, n_pfs) = match (
+                    novaseq_run.tile_ids.get(&[lane, surface]),
+                    novaseq_run.n_pfs.get(&[lane, surface]),
+                ) {
+                    (Some(tile_ids), Some(n_pfs)) => (tile_ids, n_pfs),
+                    _ => continue,
+                };
+
+                for (tid_chunk, n_pf_chunk) in tile_ids.chunks(n_chunks).zip(n_pfs.chunks(n_chunks))
+                {
+                    chunks.push(TileChunk {
+                        lane,
+                        surface,
+                        tiles: tid_chunk.to_vec(),
+                        clusters_pf: n_pf_chunk.iter().sum::<usize>() as u64,
+                    });
+                }
+            }
+        }
+    }
+
+    let clusters_pf = chunks.iter().map(|c| c.clusters_pf).sum::<u64>();
+
+    let record_bytes: usize = read_cycles
+        .iter()
+        .map(|&cycles| {
+            if output_options.seq_only {
+                cycles + 1
+            } else {
+                // header, sequence, separator, and quality lines
+                novaseq_run.run_id.len()
+                    + LOCATION_HEADER_LEN
+                    + " 1:N:0:".len()
+                    + n_idx_cycles
+                    + output_options
+                        .header_comment
+                        .as_ref()
+                        .map_or(0, |c| c.len() + 1)
+                    + 2 * (cycles + 1)
+                    + 2
+            }
+        })
+        .sum();
+
+    let mut estimated_output_bytes = clusters_pf as f64 * record_bytes as f64;
+    if output_options.compression.is_some() {
+        estimated_output_bytes *= GZIP_RATIO;
+    }
+
+    let estimated_memory_bytes = buffer_memory(
+        novaseq_run,
+        n_chunks,
+        output_options,
+        rayon::current_num_threads(),
+    );
+
+    DemuxPlan {
+        run_id: run_info.id.clone(),
+        lanes,
+        read_chunks: n_chunks,
+        n_samples,
+        n_output_files: n_samples * read_cycles.len(),
+        clusters_pf,
+        chunks,
+        estimated_output_bytes: estimated_output_bytes as u64,
+        estimated_memory_bytes,
+    }
+}
+
+/// The most bytes of a read name after the run id: the lane, tile, position and
+/// read number, and the separators between them
+const READ_NAME_BYTES: usize = 48;
+
+/// The bytes needed for each tile that is read at once: the bases and qscores of
+/// every cycle (of both reads, if they are overlapped), the cycle-major copy of a
+/// read that is decoded before it is transposed into the buffer, and the locations
+/// of the clusters. Without compression workers the tiles of a chunk are encoded in
+/// parallel, and a tile waits in memory until its sample's earlier tiles are
+/// written, so the encoded reads of a tile are counted too, as they are before
+/// compression
+fn tile_buffer_bytes(novaseq_run: &NovaSeqRun, output_options: &OutputOptions) -> u64 {
+    let [max_read_cycles, n_idx_cycles, max_n_pf] = buffer_dims(novaseq_run);
+    let n_cycles = read_buffer_cycles(novaseq_run, output_options);
+
+    let encoded_bytes = if output_options.compression_workers {
+        0
+    } else {
+        // the name and index, then the bases and qscores with their newlines and '+'
+        novaseq_run.run_id.len() + READ_NAME_BYTES + n_idx_cycles + 2 * max_read_cycles + 4
+    };
+
+    // each read is decoded a cycle at a time, then transposed into the buffer
+    let transpose_bytes = 2 * max_read_cycles;
+
+    ((2 * (n_cycles + n_idx_cycles)
+        + transpose_bytes
+        + std::mem::size_of::<[u32; 2]>()
+        + encoded_bytes)
+        * max_n_pf) as u64
+}
+
+/// The bytes needed for the locs of the run, which are shared by every tile, or
+/// the locs for every tile if they have their own
+fn locs_bytes(novaseq_run: &NovaSeqRun) -> u64 {
+    let n_locs = novaseq_run.locs.len()
+        + novaseq_run
+            .tile_locs
+            .values()
+            .flatten()
+            .map(|locs| locs.len())
+            .sum::<usize>();
+
+    (std::mem::size_of::<[u32; 2]>() * n_locs) as u64
+}
+
+/// The memory used by the read and index buffers when reading `n_chunks` tiles at a
+/// time, plus the write buffers for `n_writers` output files written at once
+pub fn buffer_memory(
+    novaseq_run: &NovaSeqRun,
+    n_chunks: usize,
+    output_options: &OutputOptions,
+    n_writers: usize,
+) -> u64 {
+    n_chunks as u64 * tile_buffer_bytes(novaseq_run, output_options)
+        + locs_bytes(novaseq_run)
+        + (output_options.write_buffer * n_writers) as u64
+}
+
+/// How much to read and buffer at once to stay under a memory limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryBudget {
+    /// the number of tiles to read at once
+    pub read_chunks: usize,
+    /// the size of the write buffer for each output file
+    pub write_buffer: usize,
+}
+
+/// Choose the number of tiles to read at once and the size of the write buffers,
+/// so that the buffers fit in `memory_limit` bytes with `n_writers` output files
+/// being written at once. The buffers are most of our memory use, but the headers
+/// and stats for the run need some more on top of this
+pub fn fit_memory(
+    novaseq_run: &NovaSeqRun,
+    memory_limit: u64,
+    n_writers: usize,
+    output_options: &OutputOptions,
+) -> Result<MemoryBudget, String> {
+    let write_buffer = ((memory_limit / WRITE_BUFFER_FRACTION) as usize / n_writers.max(1))
+        .clamp(DEFAULT_WRITE_BUFFER, MAX_WRITE_BUFFER);
+
+    let tile_bytes = tile_buffer_bytes(novaseq_run, output_options);
+    let fixed_bytes = locs_bytes(novaseq_run) + (write_buffer * n_writers) as u64;
+
+    let read_chunks = (memory_limit.saturating_sub(fixed_bytes) / tile_bytes) as usize;
+    if read_chunks == 0 {
+        return Err(format!(
+            "a memory limit of {} bytes is too small for this run, it needs at least {}",
+            memory_limit,
+            fixed_bytes + tile_bytes
+        ));
+    }
+
+    Ok(MemoryBudget {
+        read_chunks,
+        write_buffer,
+    })
+}
+
+/// The buffer and queue sizes for a demux, tuned for its number of output files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tuning {
+    /// the number of tiles to read at once
+    pub read_chunks: usize,
+    /// the size of the write buffer for each output file
+    pub write_buffer: usize,
+    /// the most blocks waiting for each compression worker
+    pub queue_depth: usize,
+}
+
+/// The most output files of `sample_data` that are open at once: the samples of a
+/// lane and its undetermined reads, for each read that is written
+pub fn n_output_files(
+    novaseq_run: &NovaSeqRun,
+    sample_data: &SampleData,
+    output_options: &OutputOptions,
+) -> usize {
+    let n_reads = novaseq_run
+        .read_structure
+        .templates()
+        .filter(|s| output_options.writes_read(s.kind_number))
+        .count();
+    let n_samples = if output_options.single_file {
+        1
+    } else {
+        sample_data
+            .values()
+            .map(|lane| lane.sample_names.len())
+            .max()
+            .unwrap_or(0)
+    };
+
+    (n_samples + 1) * n_reads
+}
+
+/// Tune the buffers to demultiplex `novaseq_run` to `n_files` output files within
+/// `memory` bytes, with `n_writers` files being written at once and the rest of
+/// `output_options`. With compression
+/// workers every file has its own queue of blocks, so for plates with thousands of
+/// samples the queues are made shorter to fit, instead of using most of the memory.
+/// A `write_buffer` or `queue_depth` that is given is kept, and counted as it is.
+/// The tiles read at once get what's left, up to a whole surface
+pub fn auto_tune(
+    novaseq_run: &NovaSeqRun,
+    memory: u64,
+    n_files: usize,
+    n_writers: usize,
+    output_options: &OutputOptions,
+    write_buffer: Option<usize>,
+    queue_depth: Option<usize>,
+) -> Result<Tuning, String> {
+    let writer_memory = (memory / WRITE_BUFFER_FRACTION) as usize;
+
+    let (write_buffer, queue_depth, writer_bytes) = if output_options.compression_workers {
+        // each file also has a block being filled and one being compressed
+        let file_bytes = writer_memory / n_files.max(1);
+        let queue_depth = queue_depth.unwrap_or_else(|| {
+            (file_bytes.saturating_sub(write_buffer.unwrap_or(DEFAULT_WRITE_BUFFER)) / BLOCK_SIZE)
+                .saturating_sub(2)
+                .clamp(1, MAX_QUEUE_DEPTH)
+        });
+        let write_buffer = write_buffer.unwrap_or_else(|| {
+            file_bytes
+                .saturating_sub((queue_depth + 2) * BLOCK_SIZE)
+                .clamp(DEFAULT_WRITE_BUFFER, MAX_WRITE_BUFFER)
+        });
+        let writer_bytes = n_files * ((queue_depth + 2) * BLOCK_SIZE + write_buffer);
+
+        (write_buffer, queue_depth, writer_bytes)
+    } else {
+        let write_buffer = write_buffer.unwrap_or_else(|| {
+            (writer_memory / n_writers.max(1)).clamp(DEFAULT_WRITE_BUFFER, MAX_WRITE_BUFFER)
+        });
+
+        (
+            write_buffer,
+            queue_depth.unwrap_or(DEFAULT_QUEUE_DEPTH),
+            n_writers * write_buffer,
+        )
+    };
+
+    let tile_bytes = tile_buffer_bytes(novaseq_run, output_options);
+    let fixed_bytes = locs_bytes(novaseq_run) + writer_bytes as u64;
+    let max_tiles = novaseq_run
+        .tile_ids
+        .values()
+        .map(|tile_ids| tile_ids.len())
+        .max()
+        .unwrap_or(1);
+
+    let read_chunks = (memory.saturating_sub(fixed_bytes) / tile_bytes) as usize;
+    if read_chunks == 0 {
+        return Err(format!(
+            "{} bytes of memory is too small for this run with {} output files, it needs at least {}",
+            memory,
+            n_files,
+            fixed_bytes + tile_bytes
+        ));
+    }
+
+    Ok(Tuning {
+        read_chunks: read_chunks.min(max_tiles),
+        write_buffer,
+        queue_depth,
+    })
+}
+
+/// The memory that auto-tuning can use: part of what the kernel says is
+/// available, or `None` if it doesn't say, e.g. off Linux
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kilobytes = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some((kilobytes << 10) / AVAILABLE_MEMORY_FRACTION)
+}
+
+/// Parse an amount of memory in bytes, with an optional K, M, G or T suffix for
+/// powers of 1024, e.g. `32G` or `1.5T`
+pub fn parse_memory(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid memory size '{}'", s);
+
+    let upper = s.trim().to_ascii_uppercase();
+    let number = upper.trim_end_matches('B');
+    let (number, scale) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1u64 << 10),
+        Some('M') => (&number[..number.len() - 1], 1 << 20),
+        Some('G') => (&number[..number.len() - 1], 1 << 30),
+        Some('T') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+
+    let value = number.trim().parse::<f64>().map_err(|_| invalid())?;
+    if !value.is_finite() || value <= 0. {
+        return Err(invalid());
+    }
+
+    Ok((value * scale as f64) as u64)
+}
+
+/// format a number of bytes in MB
+fn megabytes(n_bytes: u64) -> String {
+    format!("{:.1} MB", n_bytes as f64 / 1e6)
+}
+
+impl fmt::Display for DemuxPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "run: {}", self.run_id)?;
+        writeln!(
+            f,
+            "lanes: {}",
+            self.lanes
+                .iter()
+                .map(|l| l.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+        writeln!(
+            f,
+            "tiles: {} in {} chunks of up to {}",
+            self.chunks.iter().map(|c| c.tiles.len()).sum::<usize>(),
+            self.chunks.len(),
+            self.read_chunks
+        )?;
+        writeln!(f, "clusters passing filter: {}", self.clusters_pf)?;
+        writeln!(
+            f,
+            "samples: {} ({} output files)",
+            self.n_samples, self.n_output_files
+        )?;
+        writeln!(
+            f,
+            "estimated output: up to {}",
+            megabytes(self.estimated_output_bytes)
+        )?;
+        write!(
+            f,
+            "estimated memory: {}",
+            megabytes(self.estimated_memory_bytes)
+        )
+    }
+}
+
+impl DemuxPlan {
+    /// Write the plan to `demux_plan.json` in the output directory
+    pub fn write(&self, output_path: &Path) -> std::io::Result<()> {
+        let out_file = BufWriter::new(File::create(output_path.join("demux_plan.json"))?);
+        serde_json::to_writer_pretty(out_file, self)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::overlap::OverlapOptions;
+    use crate::sample_data::read_samplesheet;
+
+    #[test]
+    fn plan_demux() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sample_data = read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+
+        let output_options = OutputOptions {
+            compression: None,
+            seq_only: true,
+            shard: None,
+            write_buffer: 1024,
+            trim_poly_g: 0,
+            header_comment: None,
+            upload: None,
+            sink: None,
+            bgzf: false,
+            zstd: false,
+            zstd_dictionary: None,
+            two_phase: false,
+            two_phase_undetermined: false,
+            compression_workers: false,
+            queue_depth: 4,
+            writer_groups: None,
+            gpu: false,
+            undetermined_limit: None,
+            index_cache: None,
+            skip_reads: Default::default(),
+            matcher: None,
+            ascii_offset: 33,
+            read_filter: None,
+            non_pf: None,
+            append: false,
+            phix: None,
+            screen: None,
+            adapter_dimers: None,
+            overlap: None,
+            umi: None,
+            single_file: false,
+            sample_numbers: false,
+            truncate_indices: true,
+        };
+        let plan = super::plan_demux(&novaseq_run, &sample_data, 2, &output_options);
+
+        assert_eq!(plan.lanes, vec![1]);
+        assert_eq!(plan.n_samples, 93);
+        assert_eq!(plan.n_output_files, 186);
+        assert_eq!(plan.clusters_pf, 245);
+        assert_eq!(plan.chunks.len(), 2);
+        assert_eq!(plan.chunks[0].tiles, vec![1101, 1102]);
+        // 245 clusters * two reads of 4 bases and a newline
+        assert_eq!(plan.estimated_output_bytes, 245 * 2 * 5);
+    }
+
+    #[test]
+    fn fit_memory() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+
+        let budget = super::fit_memory(&novaseq_run, 1 << 30, 4, &Default::default()).unwrap();
+        assert_eq!(budget.write_buffer, MAX_WRITE_BUFFER);
+
+        let output_options = OutputOptions {
+            write_buffer: budget.write_buffer,
+            ..Default::default()
+        };
+        assert!(buffer_memory(&novaseq_run, budget.read_chunks, &output_options, 4) <= 1 << 30);
+        assert!(buffer_memory(&novaseq_run, budget.read_chunks + 1, &output_options, 4) > 1 << 30);
+
+        assert!(super::fit_memory(&novaseq_run, 1000, 4, &Default::default()).is_err());
+
+        // overlapped reads are both held in the buffer
+        let overlap_options = OutputOptions {
+            overlap: Some(OverlapOptions {
+                min_overlap: 10,
+                correct: true,
+                trim: false,
+            }),
+            ..Default::default()
+        };
+        // without compression workers, a tile's encoded reads can wait to be written
+        let workers = OutputOptions {
+            compression_workers: true,
+            ..Default::default()
+        };
+        assert!(
+            tile_buffer_bytes(&novaseq_run, &Default::default())
+                > tile_buffer_bytes(&novaseq_run, &workers)
+        );
+
+        let [n_cycles, n_idx_cycles, max_n_pf] = buffer_dims(&novaseq_run);
+        assert_eq!(
+            tile_buffer_bytes(&novaseq_run, &overlap_options)
+                - tile_buffer_bytes(&novaseq_run, &Default::default()),
+            (2 * n_cycles * max_n_pf) as u64
+        );
+        // the bases and qscores of a read, the indices, a read's copy for the
+        // transpose and the locations
+        assert_eq!(
+            tile_buffer_bytes(&novaseq_run, &workers),
+            ((2 * (n_cycles + n_idx_cycles) + 2 * n_cycles + 8) * max_n_pf) as u64
+        );
+    }
+
+    #[test]
+    fn auto_tune() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sample_data = read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+
+        // 93 samples and the undetermined reads, for two reads
+        let n_files = n_output_files(&novaseq_run, &sample_data, &Default::default());
+        assert_eq!(n_files, 188);
+        let plain = OutputOptions::default();
+        let workers = OutputOptions {
+            compression_workers: true,
+            ..Default::default()
+        };
+
+        // without compression workers only the writers' buffers depend on memory
+        let tuning =
+            super::auto_tune(&novaseq_run, 1 << 30, n_files, 4, &plain, None, None).unwrap();
+        assert_eq!(tuning.write_buffer, MAX_WRITE_BUFFER);
+        assert_eq!(tuning.queue_depth, DEFAULT_QUEUE_DEPTH);
+        // a whole surface of tiles fits
+        assert_eq!(tuning.read_chunks, 3);
+
+        // a 1536 sample plate has short queues to fit
+        let tuning =
+            super::auto_tune(&novaseq_run, 8 << 30, 2 * 1537, 4, &workers, None, None).unwrap();
+        assert_eq!(tuning.queue_depth, 1);
+        assert_eq!(tuning.write_buffer, DEFAULT_WRITE_BUFFER);
+        let tuning =
+            super::auto_tune(&novaseq_run, 64 << 30, 2 * 1537, 4, &workers, None, None).unwrap();
+        assert_eq!(tuning.queue_depth, 8);
+
+        // the queues themselves don't fit
+        assert!(
+            super::auto_tune(&novaseq_run, 1 << 30, 2 * 1537, 4, &workers, None, None).is_err()
+        );
+
+        // a queue depth that is given is kept, and takes the memory it needs
+        let tuning = super::auto_tune(
+            &novaseq_run,
+            64 << 30,
+            2 * 1537,
+            4,
+            &workers,
+            None,
+            Some(64),
+        )
+        .unwrap();
+        assert_eq!(tuning.queue_depth, 64);
+        assert_eq!(tuning.write_buffer, DEFAULT_WRITE_BUFFER);
+        assert!(
+            super::auto_tune(&novaseq_run, 8 << 30, 2 * 1537, 4, &workers, None, Some(64)).is_err()
+        );
+
+        // as is a write buffer
+        let tuning = super::auto_tune(
+            &novaseq_run,
+            1 << 30,
+            n_files,
+            4,
+            &plain,
+            Some(DEFAULT_WRITE_BUFFER),
+            None,
+        )
+        .unwrap();
+        assert_eq!(tuning.write_buffer, DEFAULT_WRITE_BUFFER);
+    }
+
+    #[test]
+    fn parse_memory() {
+        assert_eq!(super::parse_memory("32G").unwrap(), 32 << 30);
+        assert_eq!(super::parse_memory("512mb").unwrap(), 512 << 20);
+        assert_eq!(super::parse_memory("1.5K").unwrap(), 1536);
+        assert_eq!(super::parse_memory("1000").unwrap(), 1000);
+        assert!(super::parse_memory("lots").is_err());
+        assert!(super::parse_memory("-1G").is_err());
+    }
+}
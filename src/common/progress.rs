@@ -0,0 +1,235 @@
+//! Reports progress through a run, either as a progress bar on the terminal or as
+//! lines of JSON that a pipeline manager can parse. The same counters are exposed
+//! to monitoring via the `metrics` module
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+
+use crate::stats::SampleStats;
+
+/// How progress should be displayed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// a progress bar on stderr (only drawn if stderr is a terminal)
+    Bar,
+    /// one JSON object per line on stdout
+    Json,
+    /// no progress output
+    Hidden,
+}
+
+/// Tracks the number of tiles processed, reads written and bytes of basecall data
+/// decoded, along with some finer-grained counters for monitoring. Can be shared
+/// between threads
+pub struct Progress {
+    mode: ProgressMode,
+    bar: ProgressBar,
+    total_tiles: u64,
+    tiles: AtomicU64,
+    reads: AtomicU64,
+    bytes: AtomicU64,
+    start: Instant,
+    /// bytes written to the output files
+    pub bytes_written: AtomicU64,
+    /// number of threads currently writing output files
+    pub busy_workers: AtomicU64,
+    /// reads assigned to each sample so far, keyed by lane and sample ID
+    sample_reads: Mutex<BTreeMap<(usize, String), u64>>,
+    /// called with the tiles done and the total whenever progress is reported
+    callback: Option<ProgressCallback>,
+}
+
+/// A function to call with the number of tiles done and the total number of tiles
+pub type ProgressCallback = Box<dyn Fn(u64, u64) + Send + Sync>;
+
+/// estimate the seconds remaining, assuming the remaining tiles go at the same rate
+fn eta_secs(elapsed: f64, tiles: u64, total_tiles: u64) -> f64 {
+    if tiles == 0 {
+        0.
+    } else {
+        elapsed * total_tiles.saturating_sub(tiles) as f64 / tiles as f64
+    }
+}
+
+impl Progress {
+    /// Start tracking progress for a run with `total_tiles` tiles to process
+    pub fn new(mode: ProgressMode, total_tiles: u64) -> Progress {
+        let bar = match mode {
+            ProgressMode::Bar => {
+                let bar = ProgressBar::new(total_tiles);
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "[{elapsed_precise}] {bar:40} {pos}/{len} tiles, {msg} (ETA {eta})",
+                    )
+                    .unwrap(),
+                );
+                bar
+            }
+            _ => ProgressBar::hidden(),
+        };
+
+        Progress {
+            mode,
+            bar,
+            total_tiles,
+            tiles: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            start: Instant::now(),
+            bytes_written: AtomicU64::new(0),
+            busy_workers: AtomicU64::new(0),
+            sample_reads: Mutex::new(BTreeMap::new()),
+            callback: None,
+        }
+    }
+
+    /// Also call `callback` every time progress is reported, e.g. to update a
+    /// progress display in another program
+    pub fn with_callback(mut self, callback: ProgressCallback) -> Progress {
+        self.callback = Some(callback);
+        self
+    }
+
+    /// The number of tiles that have been processed
+    pub fn tiles(&self) -> u64 {
+        self.tiles.load(Ordering::SeqCst)
+    }
+
+    /// The total number of tiles to process
+    pub fn total_tiles(&self) -> u64 {
+        self.total_tiles
+    }
+
+    /// The number of reads written to sample files
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    /// The number of bytes of basecall data decoded
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Update the per-sample read counts for a lane
+    pub fn set_sample_reads(&self, lane: usize, demux_results: &[SampleStats]) {
+        let mut sample_reads = self.sample_reads.lock().unwrap();
+        for sample_stats in demux_results {
+            sample_reads.insert(
+                (lane, sample_stats.sample_id.clone()),
+                sample_stats.number_reads,
+            );
+        }
+    }
+
+    /// A copy of the per-sample read counts, keyed by lane and sample ID
+    pub fn sample_reads(&self) -> BTreeMap<(usize, String), u64> {
+        self.sample_reads.lock().unwrap().clone()
+    }
+
+    /// Record a chunk of finished tiles, with the number of reads written out and
+    /// the number of bytes of basecall data that were decoded
+    pub fn add_tiles(&self, n_tiles: u64, n_reads: u64, n_bytes: u64) {
+        let tiles = self.tiles.fetch_add(n_tiles, Ordering::SeqCst) + n_tiles;
+        let reads = self.reads.fetch_add(n_reads, Ordering::SeqCst) + n_reads;
+        let bytes = self.bytes.fetch_add(n_bytes, Ordering::SeqCst) + n_bytes;
+
+        self.report(tiles, reads, bytes, false);
+    }
+
+    /// Mark the run as complete
+    pub fn finish(&self) {
+        self.report(
+            self.tiles.load(Ordering::SeqCst),
+            self.reads.load(Ordering::SeqCst),
+            self.bytes.load(Ordering::SeqCst),
+            true,
+        );
+    }
+
+    /// update the progress bar or print a line of JSON
+    fn report(&self, tiles: u64, reads: u64, bytes: u64, done: bool) {
+        if let Some(callback) = &self.callback {
+            callback(tiles, self.total_tiles);
+        }
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mb_per_sec = if elapsed > 0. {
+            bytes as f64 / 1e6 / elapsed
+        } else {
+            0.
+        };
+
+        match self.mode {
+            ProgressMode::Bar => {
+                self.bar.set_position(tiles);
+                self.bar
+                    .set_message(format!("{} reads, {:.1} MB/s", reads, mb_per_sec));
+                if done {
+                    self.bar.finish();
+                }
+            }
+            ProgressMode::Json => println!(
+                "{}",
+                json!({
+                    "tiles_done": tiles,
+                    "tiles_total": self.total_tiles,
+                    "reads_written": reads,
+                    "bytes_processed": bytes,
+                    "mb_per_sec": mb_per_sec,
+                    "elapsed_secs": elapsed,
+                    "eta_secs": eta_secs(elapsed, tiles, self.total_tiles),
+                    "done": done,
+                })
+            ),
+            ProgressMode::Hidden => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_secs() {
+        assert_eq!(super::eta_secs(10., 0, 100), 0.);
+        assert_eq!(super::eta_secs(10., 25, 100), 30.);
+        assert_eq!(super::eta_secs(10., 100, 100), 0.);
+    }
+
+    #[test]
+    fn add_tiles() {
+        let progress = Progress::new(ProgressMode::Hidden, 10);
+        progress.add_tiles(2, 100, 1000);
+        progress.add_tiles(3, 50, 500);
+
+        assert_eq!(progress.tiles.load(Ordering::SeqCst), 5);
+        assert_eq!(progress.reads.load(Ordering::SeqCst), 150);
+        assert_eq!(progress.bytes.load(Ordering::SeqCst), 1500);
+    }
+
+    #[test]
+    fn callback() {
+        let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let seen_cb = seen.clone();
+        let progress =
+            Progress::new(ProgressMode::Hidden, 4).with_callback(Box::new(move |tiles, total| {
+                seen_cb.lock().unwrap().push((tiles, total))
+            }));
+
+        progress.add_tiles(1, 10, 100);
+        progress.add_tiles(3, 30, 300);
+        progress.finish();
+
+        assert_eq!(*seen.lock().unwrap(), vec![(1, 4), (4, 4), (4, 4)]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Records how a demux was run in `provenance.json`: the version of bcl2fastr, the
+//! effective configuration, a checksum of the samplesheet, and a summary of the
+//! run. This lets delivered fastq files be traced back to exactly what made them
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read},
+    path::{Path, PathBuf},
+};
+
+use flate2::Crc;
+use ring::digest;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::novaseq_run::NovaSeqRun;
+
+/// The git commit that this binary was built from, if it was built from a checkout
+pub const GIT_HASH: Option<&str> = option_env!("BCL2FASTR_GIT_HASH");
+
+/// The size, CRC32 and SHA-256 of a file, to check that it hasn't changed since.
+/// The CRC32 is quick to check, but only the SHA-256 identifies the file, as it
+/// can't be matched by another one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileChecksum {
+    pub path: String,
+    pub bytes: u64,
+    pub crc32: String,
+    pub sha256: String,
+}
+
+impl FileChecksum {
+    pub fn read_path(path: &Path) -> std::io::Result<FileChecksum> {
+        let mut file = File::open(path)?;
+        let mut crc = Crc::new();
+        let mut sha256 = digest::Context::new(&digest::SHA256);
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buffer[..n]);
+            sha256.update(&buffer[..n]);
+        }
+
+        Ok(FileChecksum {
+            path: path.display().to_string(),
+            bytes: crc.amount() as u64,
+            crc32: format!("{:08x}", crc.sum()),
+            sha256: sha256
+                .finish()
+                .as_ref()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        })
+    }
+}
+
+/// The parts of RunInfo.xml that identify the run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunInfoSummary {
+    pub run_id: String,
+    pub flowcell: String,
+    pub instrument: String,
+    pub date: String,
+    pub read_structure: String,
+    pub lanes: Vec<usize>,
+    pub tiles: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chemistry: Option<String>,
+}
+
+impl RunInfoSummary {
+    pub fn new(novaseq_run: &NovaSeqRun) -> RunInfoSummary {
+        let mut lanes: Vec<_> = novaseq_run.tile_ids.keys().map(|[lane, _]| *lane).collect();
+        lanes.sort_unstable();
+        lanes.dedup();
+
+        RunInfoSummary {
+            run_id: novaseq_run.run_info.id.clone(),
+            flowcell: novaseq_run.run_info.flowcell.clone(),
+            instrument: novaseq_run.run_info.instrument.clone(),
+            date: novaseq_run.run_info.date.clone(),
+            read_structure: novaseq_run.read_structure.to_string(),
+            lanes,
+            tiles: novaseq_run.tile_count(0),
+            chemistry: novaseq_run.run_parameters.as_ref().map(|rp| rp.chemistry()),
+        }
+    }
+}
+
+/// Everything needed to reproduce a demux, written out as `provenance.json`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Provenance {
+    pub version: String,
+    pub git_hash: Option<String>,
+    pub command_line: Vec<String>,
+    /// every option that was used, with the values from the command line, the
+    /// config file or the defaults
+    pub config: Config,
+    pub samplesheet: FileChecksum,
+    /// the other samplesheets, when several were merged for the demux
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub merged_samplesheets: Vec<FileChecksum>,
+    pub run_info: RunInfoSummary,
+    /// the other runs, when several runs of the same libraries were demultiplexed
+    /// together
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub other_runs: Vec<RunInfoSummary>,
+}
+
+impl Provenance {
+    pub fn new(
+        novaseq_run: &NovaSeqRun,
+        samplesheets: &[PathBuf],
+        config: Config,
+    ) -> std::io::Result<Provenance> {
+        Ok(Provenance {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: GIT_HASH.map(String::from),
+            command_line: std::env::args().collect(),
+            config,
+            samplesheet: FileChecksum::read_path(&samplesheets[0])?,
+            merged_samplesheets: samplesheets[1..]
+                .iter()
+                .map(|samplesheet| FileChecksum::read_path(samplesheet))
+                .collect::<std::io::Result<_>>()?,
+            run_info: RunInfoSummary::new(novaseq_run),
+            other_runs: Vec::new(),
+        })
+    }
+
+    /// A tag for fastq headers, e.g. `bcl2fastr=0.1.0+0a1b2c3d4e5f;ss=<SHA-256>`,
+    /// which ties each read to the version and samplesheet that produced it
+    pub fn header_comment(&self) -> String {
+        let version = match &self.git_hash {
+            Some(hash) => format!("{}+{}", self.version, hash),
+            None => self.version.clone(),
+        };
+
+        format!("bcl2fastr={};ss={}", version, self.samplesheet.sha256)
+    }
+
+    /// Write `provenance.json` to the output directory, or a file with `suffix`
+    /// added if this is one part of a bigger run
+    pub fn write(&self, output_path: &Path, suffix: Option<&str>) -> std::io::Result<()> {
+        let file_name = match suffix {
+            Some(suffix) => format!("provenance.{}.json", suffix),
+            None => "provenance.json".to_string(),
+        };
+
+        let out_file = BufWriter::new(File::create(output_path.join(file_name))?);
+        serde_json::to_writer_pretty(out_file, self)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn checksum() {
+        let checksum = FileChecksum::read_path(Path::new("test_data/empty_file")).unwrap();
+        assert_eq!(checksum.bytes, 0);
+        assert_eq!(checksum.crc32, "00000000");
+        assert_eq!(
+            checksum.sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn provenance() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), true).unwrap();
+        let samplesheet = run_path.join("SampleSheet.csv");
+
+        let provenance = Provenance::new(
+            &novaseq_run,
+            std::slice::from_ref(&samplesheet),
+            Config::default(),
+        )
+        .unwrap();
+
+        assert_eq!(provenance.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            provenance.samplesheet.bytes,
+            std::fs::metadata(&samplesheet).unwrap().len()
+        );
+        assert_eq!(provenance.run_info.read_structure, "Y4;I8;I8;Y4");
+        assert_eq!(provenance.run_info.lanes, vec![1]);
+        assert_eq!(provenance.run_info.tiles, 3);
+        assert!(provenance
+            .header_comment()
+            .ends_with(&format!(";ss={}", provenance.samplesheet.sha256)));
+    }
+}
@@ -0,0 +1,374 @@
+//! Python bindings, built as `bcl2fastr._bcl2fastr` and re-exported from the
+//! `bcl2fastr` package. These cover reading a samplesheet, loading a run and
+//! reading its tiles, and running a whole demux with a progress callback, so that
+//! Python pipelines can drive bcl2fastr without going through the command line
+
+// the code generated by #[pyfunction] for functions returning PyResult trips this
+#![allow(clippy::useless_conversion)]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::demux::DemuxBuilder;
+use crate::novaseq_run::NovaSeqRun;
+use crate::progress::{Progress, ProgressMode};
+use crate::provenance::RunInfoSummary;
+use crate::sample_data::{read_oriented_samplesheet, I5Orientation};
+use crate::stats::LaneStats;
+use crate::write_fastq::{dump_tile, OutputOptions};
+
+fn io_error(e: std::io::Error) -> PyErr {
+    PyIOError::new_err(e.to_string())
+}
+
+fn parse_orientation(i5_orientation: &str) -> PyResult<I5Orientation> {
+    i5_orientation.parse().map_err(PyValueError::new_err)
+}
+
+/// Read a samplesheet into a dict from lane (0 if there is no Lane column) to a
+/// list of samples, each a dict with the sample name, project and index
+#[pyfunction]
+#[pyo3(signature = (path, mismatch=1, i5_orientation="forward"))]
+fn read_samplesheet(
+    py: Python,
+    path: PathBuf,
+    mismatch: usize,
+    i5_orientation: &str,
+) -> PyResult<BTreeMap<usize, Vec<PyObject>>> {
+    let i5_orientation = parse_orientation(i5_orientation)?;
+    let sample_data = py
+        .allow_threads(|| read_oriented_samplesheet(path, mismatch, i5_orientation))
+        .map_err(io_error)?;
+
+    let mut lanes = BTreeMap::new();
+    for (&lane, samples) in &sample_data {
+        let mut sample_dicts = Vec::with_capacity(samples.sample_names.len());
+        for (i, sample_name) in samples.sample_names.iter().enumerate() {
+            let sample = PyDict::new_bound(py);
+            sample.set_item("sample", sample_name)?;
+            sample.set_item("project", &samples.project_names[i])?;
+            sample.set_item("index", samples.index_string(i))?;
+            sample_dicts.push(sample.into());
+        }
+        lanes.insert(lane, sample_dicts);
+    }
+
+    Ok(lanes)
+}
+
+/// A run folder, loaded with all of its CBCL headers
+#[pyclass(name = "Run")]
+struct PyRun {
+    novaseq_run: Arc<NovaSeqRun>,
+    summary: RunInfoSummary,
+}
+
+#[pymethods]
+impl PyRun {
+    #[new]
+    fn new(py: Python, run_path: PathBuf) -> PyResult<PyRun> {
+        let novaseq_run = py
+            .allow_threads(|| NovaSeqRun::read_path(run_path, false))
+            .map_err(io_error)?;
+        let summary = RunInfoSummary::new(&novaseq_run);
+
+        Ok(PyRun {
+            novaseq_run: Arc::new(novaseq_run),
+            summary,
+        })
+    }
+
+    #[getter]
+    fn run_id(&self) -> &str {
+        &self.summary.run_id
+    }
+
+    #[getter]
+    fn flowcell(&self) -> &str {
+        &self.summary.flowcell
+    }
+
+    #[getter]
+    fn instrument(&self) -> &str {
+        &self.summary.instrument
+    }
+
+    #[getter]
+    fn date(&self) -> &str {
+        &self.summary.date
+    }
+
+    /// e.g. `Y151;I8;I8;Y151`
+    #[getter]
+    fn read_structure(&self) -> &str {
+        &self.summary.read_structure
+    }
+
+    #[getter]
+    fn chemistry(&self) -> Option<&str> {
+        self.summary.chemistry.as_deref()
+    }
+
+    #[getter]
+    fn lanes(&self) -> Vec<usize> {
+        self.summary.lanes.clone()
+    }
+
+    /// Every tile in the run as (lane, tile) pairs, in order
+    #[getter]
+    fn tiles(&self) -> Vec<(usize, u32)> {
+        self.novaseq_run
+            .tile_ids
+            .iter()
+            .flat_map(|(&[lane, _], tiles)| tiles.iter().map(move |&tile| (lane, tile)))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Iterate over the pass-filter reads in one tile, as (name, sequence, quality)
+    /// tuples, like `dump-tile`: the first read of every cluster, then the second.
+    /// The tile is decoded in the background as the reads are taken
+    fn read_tile(&self, lane: usize, tile: u32) -> TileReads {
+        let (sender, receiver) = sync_channel(TILE_QUEUE_DEPTH);
+        let novaseq_run = self.novaseq_run.clone();
+        let dump = thread::spawn(move || {
+            let mut writer = ChannelWriter {
+                sender,
+                block: Vec::with_capacity(TILE_BLOCK_SIZE),
+            };
+            dump_tile(&novaseq_run, lane, tile, &mut writer)
+        });
+
+        TileReads {
+            lines: BufReader::new(ChannelReader {
+                receiver,
+                block: io::Cursor::new(Vec::new()),
+            })
+            .lines(),
+            dump: Some(dump),
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Run('{}', {})",
+            self.summary.run_id, self.summary.read_structure
+        )
+    }
+}
+
+/// The size of the blocks of fastq text sent from `dump_tile` to the iterator
+const TILE_BLOCK_SIZE: usize = 1 << 16;
+
+/// How many blocks of a tile can be decoded ahead of the iterator
+const TILE_QUEUE_DEPTH: usize = 4;
+
+/// Sends what `dump_tile` writes to the iterator, a block at a time. Fails once the
+/// iterator is gone, which stops the dump
+struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+    block: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send_block(&mut self) -> io::Result<()> {
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(TILE_BLOCK_SIZE));
+        self.sender
+            .send(block)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "tile reads were dropped"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.block.extend_from_slice(buf);
+        if self.block.len() >= TILE_BLOCK_SIZE {
+            self.send_block()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.send_block()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the blocks from a `ChannelWriter`, until it is dropped
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    block: io::Cursor<Vec<u8>>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.block.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.receiver.recv() {
+                Ok(block) => self.block = io::Cursor::new(block),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// An iterator over the reads in a tile, as they are decoded
+#[pyclass]
+struct TileReads {
+    lines: io::Lines<BufReader<ChannelReader>>,
+    /// the thread decoding the tile, until its result has been checked
+    dump: Option<JoinHandle<io::Result<usize>>>,
+}
+
+impl TileReads {
+    /// The next record, or the error from decoding the tile once there are no more
+    fn next_record(&mut self) -> io::Result<Option<(String, String, String)>> {
+        let mut record = Vec::with_capacity(4);
+        for line in self.lines.by_ref().take(4) {
+            record.push(line?);
+        }
+
+        if let [name, seq, _, qual] = &mut record[..] {
+            return Ok(Some((
+                name.trim_start_matches('@').to_string(),
+                std::mem::take(seq),
+                std::mem::take(qual),
+            )));
+        }
+
+        match self.dump.take().map(|dump| dump.join()) {
+            Some(Ok(result)) => result.map(|_| None),
+            Some(Err(_)) => Err(io::Error::other("reading the tile panicked")),
+            None => Ok(None),
+        }
+    }
+}
+
+#[pymethods]
+impl TileReads {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>, py: Python) -> PyResult<Option<(String, String, String)>> {
+        let tile_reads = &mut *slf;
+        py.allow_threads(|| tile_reads.next_record())
+            .map_err(io_error)
+    }
+}
+
+/// The per-lane totals from Stats.json, as a dict
+fn lane_stats_dict<'py>(py: Python<'py>, lane_stats: &LaneStats) -> PyResult<Bound<'py, PyDict>> {
+    let samples = PyDict::new_bound(py);
+    for sample_stats in &lane_stats.demux_results {
+        samples.set_item(&sample_stats.sample_id, sample_stats.number_reads)?;
+    }
+
+    let lane = PyDict::new_bound(py);
+    lane.set_item("lane", lane_stats.lane_number)?;
+    lane.set_item("clusters_raw", lane_stats.total_clusters_raw)?;
+    lane.set_item("clusters_pf", lane_stats.total_clusters_pf)?;
+    lane.set_item("yield", lane_stats.yield_bases)?;
+    lane.set_item("samples", samples)?;
+    lane.set_item("undetermined", lane_stats.undetermined.number_reads)?;
+
+    Ok(lane)
+}
+
+/// Demultiplex a run into `output`, which must already exist. `progress` is
+/// called with (tiles done, total tiles) as the run goes. If it raises, it isn't
+/// called again and its exception is raised once the demux is done. Returns the
+/// totals for each lane, with the reads for each sample
+#[pyfunction]
+#[pyo3(signature = (
+    run_path,
+    samplesheet,
+    output,
+    mismatch=1,
+    lanes=None,
+    i5_orientation=None,
+    compression=Some(1),
+    reports=true,
+    progress=None,
+))]
+#[allow(clippy::too_many_arguments)]
+fn demux<'py>(
+    py: Python<'py>,
+    run_path: PathBuf,
+    samplesheet: PathBuf,
+    output: PathBuf,
+    mismatch: usize,
+    lanes: Option<BTreeSet<usize>>,
+    i5_orientation: Option<&str>,
+    compression: Option<u32>,
+    reports: bool,
+    progress: Option<PyObject>,
+) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let mut builder = DemuxBuilder::new(run_path, samplesheet, output)
+        .mismatch(mismatch)
+        .output_options(OutputOptions {
+            compression,
+            ..Default::default()
+        })
+        .reports(reports);
+    if let Some(lanes) = lanes {
+        builder = builder.lanes(lanes);
+    }
+    if let Some(i5_orientation) = i5_orientation {
+        builder = builder.i5_orientation(parse_orientation(i5_orientation)?);
+    }
+
+    // the first error from the callback, which is raised once the demux is done
+    let callback_error: Arc<Mutex<Option<PyErr>>> = Arc::new(Mutex::new(None));
+    let lane_stats = py.allow_threads(|| {
+        let demux = builder.build()?;
+
+        let mut run_progress = Progress::new(ProgressMode::Hidden, demux.total_tiles() as u64);
+        if let Some(callback) = progress {
+            let callback_error = callback_error.clone();
+            run_progress = run_progress.with_callback(Box::new(move |tiles, total| {
+                let mut callback_error = callback_error.lock().unwrap();
+                if callback_error.is_none() {
+                    *callback_error =
+                        Python::with_gil(|py| callback.call1(py, (tiles, total)).err());
+                }
+            }));
+        }
+
+        demux.run_with_progress(&run_progress)
+    });
+
+    if let Some(e) = callback_error.lock().unwrap().take() {
+        return Err(e);
+    }
+    lane_stats
+        .map_err(io_error)?
+        .iter()
+        .map(|ls| lane_stats_dict(py, ls))
+        .collect()
+}
+
+#[pymodule]
+fn _bcl2fastr(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_function(wrap_pyfunction!(read_samplesheet, m)?)?;
+    m.add_function(wrap_pyfunction!(demux, m)?)?;
+    m.add_class::<PyRun>()?;
+    m.add_class::<TileReads>()?;
+
+    Ok(())
+}
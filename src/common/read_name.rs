@@ -0,0 +1,75 @@
+//! The read names of the fastq records. Everything up to the cluster's position
+//! is the same for every cluster in a tile, so it's formatted once per tile, and
+//! only the numbers that change are written into the name for each cluster. For
+//! billions of reads this keeps formatting and allocation out of the writer path
+
+/// Formats the names of the reads in one tile of a lane, as
+/// `<run id>:<lane>:<tile>:<x>:<y> <read>:<filtered>:0:`. The index sequences
+/// that follow are written by the caller
+pub(crate) struct ReadName {
+    /// the name of the last read, starting with the tile's prefix
+    name: Vec<u8>,
+    /// the length of `<run id>:<lane>:<tile>:`
+    prefix_len: usize,
+    numbers: itoa::Buffer,
+}
+
+impl ReadName {
+    pub(crate) fn new(run_id: &str, lane: usize, tile: u32) -> ReadName {
+        let mut numbers = itoa::Buffer::new();
+        let mut name = Vec::with_capacity(run_id.len() + 64);
+        name.extend_from_slice(run_id.as_bytes());
+        name.push(b':');
+        name.extend_from_slice(numbers.format(lane).as_bytes());
+        name.push(b':');
+        name.extend_from_slice(numbers.format(tile).as_bytes());
+        name.push(b':');
+
+        ReadName {
+            prefix_len: name.len(),
+            name,
+            numbers,
+        }
+    }
+
+    /// The name of the read at `loc` in the tile. Clusters that didn't pass filter
+    /// are flagged with a Y
+    pub(crate) fn format(&mut self, loc: [u32; 2], read_num: usize, passed_filter: bool) -> &[u8] {
+        self.name.truncate(self.prefix_len);
+        self.name
+            .extend_from_slice(self.numbers.format(loc[0]).as_bytes());
+        self.name.push(b':');
+        self.name
+            .extend_from_slice(self.numbers.format(loc[1]).as_bytes());
+        self.name.push(b' ');
+        self.name
+            .extend_from_slice(self.numbers.format(read_num).as_bytes());
+        self.name
+            .extend_from_slice(if passed_filter { b":N:0:" } else { b":Y:0:" });
+
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_read_names() {
+        let mut read_name = ReadName::new("@A00111:296:HJCWWDSXX", 1, 1101);
+        assert_eq!(
+            read_name.format([1090, 1000], 1, true),
+            b"@A00111:296:HJCWWDSXX:1:1101:1090:1000 1:N:0:"
+        );
+        // a shorter name after a longer one leaves nothing behind
+        assert_eq!(
+            read_name.format([9, 0], 2, false),
+            b"@A00111:296:HJCWWDSXX:1:1101:9:0 2:Y:0:"
+        );
+        assert_eq!(
+            read_name.format([u32::MAX, 32000], 12, true),
+            format!("@A00111:296:HJCWWDSXX:1:1101:{}:32000 12:N:0:", u32::MAX).as_bytes()
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Splits the cycles of a run into read segments (R1, I1, I2, R2...) based on the
+//! reads listed in RunInfo.xml, so that single-index runs, single reads, and runs
+//! with extra reads are handled the same way as the usual paired, dual-index run
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+
+use crate::run_info_parser::Read;
+
+/// Whether a read segment is sequence from the insert or an index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Template,
+    Index,
+}
+
+/// One read from RunInfo.xml, with its place among the reads of the same kind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadSegment {
+    pub kind: SegmentKind,
+    /// The read number in RunInfo.xml, counting every read
+    pub number: usize,
+    /// The number among reads of the same kind, e.g. 2 for R2 or I2
+    pub kind_number: usize,
+    /// The cycles in this segment, numbered from 1
+    pub cycles: Range<usize>,
+}
+
+impl ReadSegment {
+    pub fn num_cycles(&self) -> usize {
+        self.cycles.len()
+    }
+
+    pub fn is_index(&self) -> bool {
+        self.kind == SegmentKind::Index
+    }
+
+    /// The usual name for the segment, e.g. `R1` or `I2`
+    pub fn name(&self) -> String {
+        match self.kind {
+            SegmentKind::Template => format!("R{}", self.kind_number),
+            SegmentKind::Index => format!("I{}", self.kind_number),
+        }
+    }
+}
+
+/// The read segments of a run, in cycle order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadStructure {
+    pub segments: Vec<ReadSegment>,
+}
+
+impl ReadStructure {
+    /// Number the template and index reads separately, in the order they are
+    /// sequenced
+    pub fn new(reads: &[Read]) -> ReadStructure {
+        let mut n_templates = 0;
+        let mut n_indices = 0;
+
+        let segments = reads
+            .iter()
+            .map(|r| {
+                let (kind, counter) = if r.is_indexed_read {
+                    (SegmentKind::Index, &mut n_indices)
+                } else {
+                    (SegmentKind::Template, &mut n_templates)
+                };
+                *counter += 1;
+
+                ReadSegment {
+                    kind,
+                    number: r.number,
+                    kind_number: *counter,
+                    cycles: r.start..r.end,
+                }
+            })
+            .collect();
+
+        ReadStructure { segments }
+    }
+
+    /// The reads that are written to fastq files
+    pub fn templates(&self) -> impl Iterator<Item = &ReadSegment> {
+        self.segments
+            .iter()
+            .filter(|s| s.kind == SegmentKind::Template)
+    }
+
+    /// The index reads that are used to assign clusters to samples
+    pub fn indices(&self) -> impl Iterator<Item = &ReadSegment> {
+        self.segments.iter().filter(|s| s.is_index())
+    }
+
+    pub fn n_templates(&self) -> usize {
+        self.templates().count()
+    }
+
+    pub fn n_indices(&self) -> usize {
+        self.indices().count()
+    }
+
+    /// The length of the longest template read, or 0 if there are none
+    pub fn max_template_cycles(&self) -> usize {
+        self.templates().map(|s| s.num_cycles()).max().unwrap_or(0)
+    }
+
+    /// One past the last index cycle, or 1 if there are no index reads
+    pub fn index_end(&self) -> usize {
+        self.indices().map(|s| s.cycles.end).max().unwrap_or(1)
+    }
+
+    /// The segment that a cycle belongs to
+    pub fn segment_at(&self, cycle: usize) -> Option<&ReadSegment> {
+        self.segments.iter().find(|s| s.cycles.contains(&cycle))
+    }
+}
+
+/// Parse a list of template reads, e.g. `R1,R3`, into their numbers. Index reads
+/// are only ever written into the read headers, so they can't be listed
+pub fn parse_read_names(s: &str) -> Result<BTreeSet<usize>, String> {
+    s.split(',')
+        .map(|name| {
+            let name = name.trim();
+            match name.strip_prefix('R').map(str::parse::<usize>) {
+                Some(Ok(n)) if n > 0 => Ok(n),
+                _ if name.starts_with('I') => Err(format!(
+                    "index reads like {} are written in the read headers, not to their own files",
+                    name
+                )),
+                _ => Err(format!("invalid read '{}', expected e.g. R1", name)),
+            }
+        })
+        .collect()
+}
+
+/// Format the structure like `Y151;I8;I8;Y151`
+impl fmt::Display for ReadStructure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let segments: Vec<_> = self
+            .segments
+            .iter()
+            .map(|s| match s.kind {
+                SegmentKind::Template => format!("Y{}", s.num_cycles()),
+                SegmentKind::Index => format!("I{}", s.num_cycles()),
+            })
+            .collect();
+
+        write!(f, "{}", segments.join(";"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::run_info_parser::parse_run_info;
+
+    #[test]
+    fn paired_dual_index() {
+        let run_info = parse_run_info(Path::new(
+            "test_data/190414_A00111_0296_AHJCWWDSXX/RunInfo.xml",
+        ))
+        .unwrap();
+        let read_structure = ReadStructure::new(&run_info.reads);
+
+        assert_eq!(read_structure.to_string(), "Y4;I8;I8;Y4");
+        assert_eq!(read_structure.n_templates(), 2);
+        assert_eq!(read_structure.n_indices(), 2);
+        assert_eq!(read_structure.max_template_cycles(), 4);
+        assert_eq!(read_structure.index_end(), 21);
+
+        let names: Vec<_> = read_structure.segments.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["R1", "I1", "I2", "R2"]);
+
+        assert_eq!(read_structure.segment_at(1).unwrap().name(), "R1");
+        assert_eq!(read_structure.segment_at(13).unwrap().name(), "I2");
+        assert_eq!(read_structure.segment_at(25), None);
+    }
+
+    #[test]
+    fn single_index() {
+        let run_info = parse_run_info(Path::new("test_data/RunInfo_v2_MiSeq.xml")).unwrap();
+        let read_structure = ReadStructure::new(&run_info.reads);
+
+        assert_eq!(read_structure.to_string(), "Y151;I8;Y151");
+        assert_eq!(read_structure.segments[2].name(), "R2");
+        assert_eq!(read_structure.segments[2].number, 3);
+        assert_eq!(read_structure.index_end(), 160);
+    }
+
+    #[test]
+    fn read_names() {
+        assert_eq!(
+            parse_read_names("R1, R3").unwrap(),
+            [1, 3].iter().cloned().collect()
+        );
+        assert!(parse_read_names("R0").is_err());
+        assert!(parse_read_names("X1").is_err());
+        assert!(parse_read_names("R1,I1")
+            .unwrap_err()
+            .contains("written in the read headers"));
+    }
+}
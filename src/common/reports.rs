@@ -0,0 +1,1044 @@
+//! Writes BCL Convert-style summary reports (`Reports/Demultiplex_Stats.csv` and
+//! `Reports/Top_Unknown_Barcodes.csv`) from the statistics collected during demux,
+//! along with per-cycle and per-tile QC metrics, pool balance, index hopping reports
+//! for dual-indexed runs, likely causes for the most common unknown barcodes, read
+//! counts and yield for each project, and a
+//! self-contained HTML summary (`Reports/report.html`). A MultiQC custom content
+//! file (`Reports/bcl2fastr_mqc.json`) is also written, so the results can be shown
+//! in existing QC dashboards alongside `Stats/Stats.json`
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as FmtWrite,
+    fs::{create_dir_all, write},
+    path::Path,
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::barcode_hints::find_hints;
+use crate::novaseq_run::NovaSeqRun;
+use crate::run_info_parser::TileNamingConvention;
+use crate::stats::{
+    project_stats, LaneStats, ReadMetrics, TileStats, CYCLE_BASES, MAX_BARCODE_CV,
+    MAX_INDEX_BASE_FRACTION, TOP_UNKNOWN_BARCODES,
+};
+
+/// The number of unknown barcodes to show per lane in the HTML report
+const HTML_UNKNOWN_BARCODES: usize = 20;
+
+/// The number of unknown barcodes to check for likely causes, per lane
+const HINT_UNKNOWN_BARCODES: usize = 100;
+
+/// The number of mismatches in an index from which reads are counted together in
+/// the HTML report, as 2+
+const HTML_HISTOGRAM_MISMATCHES: usize = 2;
+
+/// Minimal styling so the report is readable without any external files
+const HTML_STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.75em; text-align: right; }
+th { background: #eee; }
+td.name { text-align: left; }";
+
+/// fraction of `n` out of `total`, formatted for the reports
+fn fraction(n: u64, total: u64) -> String {
+    if total == 0 {
+        format!("{:.4}", 0.)
+    } else {
+        format!("{:.4}", n as f64 / total as f64)
+    }
+}
+
+/// Write a table of per-sample read counts, with the number of reads that matched
+/// each index with zero, one, or two mismatches
+fn write_demux_stats(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record([
+        "Lane",
+        "SampleID",
+        "Sample_Project",
+        "Index",
+        "# Reads",
+        "# Perfect Index Reads",
+        "# One Mismatch Index Reads",
+        "# Two Mismatch Index Reads",
+        "% Reads",
+        "% Perfect Index Reads",
+        "% One Mismatch Index Reads",
+        "% Two Mismatch Index Reads",
+    ])?;
+
+    for ls in lane_stats {
+        let lane_reads = ls.number_reads();
+
+        for sample_stats in ls.demux_results.iter() {
+            let index_metrics = &sample_stats.index_metrics[0];
+            let mismatch_count = |k: &str| -> u64 {
+                index_metrics
+                    .mismatch_counts
+                    .get(k)
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            let n_reads = sample_stats.number_reads;
+            let counts = [
+                mismatch_count("0"),
+                mismatch_count("1"),
+                mismatch_count("2"),
+            ];
+
+            wtr.write_record(&[
+                ls.lane_number.to_string(),
+                sample_stats.sample_id.clone(),
+                sample_stats.sample_project.clone().unwrap_or_default(),
+                index_metrics.index_sequence.replace('+', "-"),
+                n_reads.to_string(),
+                counts[0].to_string(),
+                counts[1].to_string(),
+                counts[2].to_string(),
+                fraction(n_reads, lane_reads),
+                fraction(counts[0], n_reads),
+                fraction(counts[1], n_reads),
+                fraction(counts[2], n_reads),
+            ])?;
+        }
+
+        let n_undetermined = ls.undetermined.number_reads;
+
+        wtr.write_record(&[
+            ls.lane_number.to_string(),
+            "Undetermined".to_string(),
+            String::new(),
+            String::new(),
+            n_undetermined.to_string(),
+            "0".to_string(),
+            "0".to_string(),
+            "0".to_string(),
+            fraction(n_undetermined, lane_reads),
+            fraction(0, 0),
+            fraction(0, 0),
+            fraction(0, 0),
+        ])?;
+
+        if ls.phix.number_reads > 0 {
+            wtr.write_record(&[
+                ls.lane_number.to_string(),
+                "PhiX".to_string(),
+                String::new(),
+                String::new(),
+                ls.phix.number_reads.to_string(),
+                "0".to_string(),
+                "0".to_string(),
+                "0".to_string(),
+                fraction(ls.phix.number_reads, lane_reads),
+                fraction(0, 0),
+                fraction(0, 0),
+                fraction(0, 0),
+            ])?;
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write a table of the reads, yield and Q30 of each `Sample_Project`, summed over
+/// its samples in every lane
+fn write_project_stats(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record(["Sample_Project", "# Samples", "# Reads", "Yield", "% Q30"])?;
+    for project_stats in project_stats(lane_stats) {
+        wtr.write_record(&[
+            project_stats.project.clone().unwrap_or_default(),
+            project_stats.samples.to_string(),
+            project_stats.reads.to_string(),
+            project_stats.yield_bases.to_string(),
+            fraction(project_stats.yield_q30, project_stats.yield_bases),
+        ])?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write a table of the most common barcodes among the undetermined reads
+fn write_top_unknown_barcodes(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record([
+        "Lane",
+        "index",
+        "index2",
+        "# Reads",
+        "% of Unknown Barcodes",
+        "% of All Reads",
+    ])?;
+
+    for ls in lane_stats {
+        let lane_reads = ls.number_reads();
+        let n_undetermined = ls.undetermined.number_reads;
+
+        for (barcode, count) in ls.top_unknown_barcodes(TOP_UNKNOWN_BARCODES) {
+            let mut indices = barcode.split('+');
+
+            wtr.write_record(&[
+                ls.lane_number.to_string(),
+                indices.next().unwrap_or_default().to_string(),
+                indices.next().unwrap_or_default().to_string(),
+                count.to_string(),
+                fraction(count, n_undetermined),
+                fraction(count, lane_reads),
+            ])?;
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write a table of the mean quality, fraction of bases at or above Q30, and base
+/// composition for every cycle of every read. A dead cycle or a chemistry problem
+/// shows up here even when the per-sample numbers look fine
+fn write_cycle_metrics(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    let mut header = vec![
+        "Lane".to_string(),
+        "Read".to_string(),
+        "Cycle".to_string(),
+        "Mean Quality".to_string(),
+        "% Q30".to_string(),
+    ];
+    header.extend(CYCLE_BASES.iter().map(|&b| format!("% {}", b as char)));
+    wtr.write_record(&header)?;
+
+    for ls in lane_stats {
+        for (read_i, read_cycles) in ls.cycle_metrics.iter().enumerate() {
+            for (cycle_i, cm) in read_cycles.iter().enumerate() {
+                let n_bases = cm.n_bases();
+                let mean_quality = if n_bases == 0 {
+                    0.
+                } else {
+                    cm.quality_score_sum as f64 / n_bases as f64
+                };
+
+                let mut record = vec![
+                    ls.lane_number.to_string(),
+                    (read_i + 1).to_string(),
+                    (cycle_i + 1).to_string(),
+                    format!("{:.2}", mean_quality),
+                    fraction(cm.q30_count, n_bases),
+                ];
+                record.extend(cm.base_counts.iter().map(|&n| fraction(n, n_bases)));
+                wtr.write_record(&record)?;
+            }
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// format the QC columns shared by the tile and swath reports
+fn tile_record(ts: &TileStats) -> Vec<String> {
+    let mean_quality = if ts.n_bases == 0 {
+        0.
+    } else {
+        ts.quality_score_sum as f64 / ts.n_bases as f64
+    };
+
+    vec![
+        ts.clusters_raw.to_string(),
+        ts.clusters_pf.to_string(),
+        fraction(ts.clusters_pf, ts.clusters_raw),
+        fraction(ts.clusters_assigned, ts.clusters_pf),
+        format!("{:.2}", mean_quality),
+    ]
+}
+
+/// The columns for the QC metrics in `tile_record`
+const TILE_COLUMNS: [&str; 5] = [
+    "# Raw Clusters",
+    "# PF Clusters",
+    "% PF",
+    "% Assigned",
+    "Mean Quality",
+];
+
+/// Write pass-filter rate, assigned fraction and mean quality for every tile, and
+/// the same metrics aggregated by surface and swath, so that spatial problems on
+/// the flowcell (bubbles, edge effects) stand out
+fn write_tile_metrics(
+    tile_path: &Path,
+    swath_path: &Path,
+    lane_stats: &[LaneStats],
+    convention: TileNamingConvention,
+) -> csv::Result<()> {
+    let mut tile_wtr = csv::Writer::from_path(tile_path)?;
+    let mut swath_wtr = csv::Writer::from_path(swath_path)?;
+
+    let mut tile_header = vec!["Lane", "Tile", "Surface", "Swath"];
+    tile_header.extend(TILE_COLUMNS.iter());
+    tile_wtr.write_record(&tile_header)?;
+
+    let mut swath_header = vec!["Lane", "Surface", "Swath"];
+    swath_header.extend(TILE_COLUMNS.iter());
+    swath_wtr.write_record(&swath_header)?;
+
+    for ls in lane_stats {
+        let mut swath_stats: BTreeMap<(usize, usize), TileStats> = BTreeMap::new();
+
+        for (&tile, ts) in ls.tile_stats.iter() {
+            let decoded = convention.decode(ls.lane_number, tile);
+            let (surface, swath) = (decoded.surface, decoded.swath);
+            swath_stats.entry((surface, swath)).or_default().merge(ts);
+
+            let mut record = vec![
+                ls.lane_number.to_string(),
+                tile.to_string(),
+                surface.to_string(),
+                swath.to_string(),
+            ];
+            record.extend(tile_record(ts));
+            tile_wtr.write_record(&record)?;
+        }
+
+        for ((surface, swath), ts) in swath_stats {
+            let mut record = vec![
+                ls.lane_number.to_string(),
+                surface.to_string(),
+                swath.to_string(),
+            ];
+            record.extend(tile_record(&ts));
+            swath_wtr.write_record(&record)?;
+        }
+    }
+
+    tile_wtr.flush()?;
+    swath_wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write the balance of reads across the samples in each lane, and the base
+/// composition of each index cycle, flagging pools that may need to be re-pooled
+fn write_barcode_balance(
+    balance_path: &Path,
+    index_path: &Path,
+    lane_stats: &[LaneStats],
+) -> csv::Result<()> {
+    let mut balance_wtr = csv::Writer::from_path(balance_path)?;
+    let mut index_wtr = csv::Writer::from_path(index_path)?;
+
+    balance_wtr.write_record(["Lane", "# Samples", "Mean Reads per Sample", "CV", "Flag"])?;
+
+    let mut index_header = vec![
+        "Lane".to_string(),
+        "Index Read".to_string(),
+        "Cycle".to_string(),
+    ];
+    index_header.extend(CYCLE_BASES.iter().map(|&b| format!("% {}", b as char)));
+    index_header.push("Flag".to_string());
+    index_wtr.write_record(&index_header)?;
+
+    for ls in lane_stats {
+        let n_samples = ls.demux_results.len();
+        let n_assigned = ls.assigned_reads();
+        let cv = ls.read_count_cv();
+
+        balance_wtr.write_record(&[
+            ls.lane_number.to_string(),
+            n_samples.to_string(),
+            format!("{:.1}", n_assigned as f64 / n_samples.max(1) as f64),
+            format!("{:.4}", cv),
+            if cv > MAX_BARCODE_CV {
+                "poorly balanced".to_string()
+            } else {
+                String::new()
+            },
+        ])?;
+
+        for (idx_i, index_cycles) in ls.index_cycle_metrics.iter().enumerate() {
+            for (cycle_i, cm) in index_cycles.iter().enumerate() {
+                let n_bases = cm.n_bases();
+
+                let mut record = vec![
+                    ls.lane_number.to_string(),
+                    (idx_i + 1).to_string(),
+                    (cycle_i + 1).to_string(),
+                ];
+                record.extend(cm.base_counts.iter().map(|&n| fraction(n, n_bases)));
+                record.push(if cm.max_base_fraction() > MAX_INDEX_BASE_FRACTION {
+                    "low diversity".to_string()
+                } else {
+                    String::new()
+                });
+                index_wtr.write_record(&record)?;
+            }
+        }
+    }
+
+    balance_wtr.flush()?;
+    index_wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write the likely causes (e.g. a reverse-complemented index2) for the most
+/// common unknown barcodes, with a suggestion of what to check
+fn write_unknown_barcode_hints(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record([
+        "Lane",
+        "index",
+        "index2",
+        "# Reads",
+        "% of Unknown Barcodes",
+        "Likely Cause",
+    ])?;
+
+    for ls in lane_stats {
+        let n_undetermined = ls.undetermined.number_reads;
+
+        for (barcode, count) in ls.top_unknown_barcodes(HINT_UNKNOWN_BARCODES) {
+            let mut indices = barcode.split('+');
+            let index = indices.next().unwrap_or_default().to_string();
+            let index2 = indices.next().unwrap_or_default().to_string();
+
+            for hint in find_hints(&barcode, &ls.demux_results) {
+                wtr.write_record(&[
+                    ls.lane_number.to_string(),
+                    index.clone(),
+                    index2.clone(),
+                    count.to_string(),
+                    fraction(count, n_undetermined),
+                    hint.to_string(),
+                ])?;
+            }
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write what the screened undetermined reads of each lane look like
+fn write_undetermined_screen(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record([
+        "Lane",
+        "# Screened Reads",
+        "% PhiX",
+        "% Adapter Dimer",
+        "% Low Complexity",
+        "% Other",
+    ])?;
+
+    for ls in lane_stats {
+        let screen = &ls.screen;
+        wtr.write_record(&[
+            ls.lane_number.to_string(),
+            screen.screened.to_string(),
+            fraction(screen.phix, screen.screened),
+            fraction(screen.adapter_dimer, screen.screened),
+            fraction(screen.low_complexity, screen.screened),
+            fraction(screen.other, screen.screened),
+        ])?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write the quality of the assigned clusters' UMIs in each lane
+fn write_umi_metrics(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record([
+        "Lane",
+        "# UMIs",
+        "% With N",
+        "% Low Quality",
+        "Mean Quality",
+    ])?;
+
+    for ls in lane_stats {
+        let umi = &ls.umi;
+        wtr.write_record(&[
+            ls.lane_number.to_string(),
+            umi.umis.to_string(),
+            fraction(umi.with_n, umi.umis),
+            fraction(umi.low_quality, umi.umis),
+            format!("{:.2}", umi.mean_quality()),
+        ])?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write the adapter dimers of each sample in each lane, and the fraction of the
+/// sample's reads they are
+fn write_adapter_dimers(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record([
+        "Lane",
+        "SampleID",
+        "# Reads",
+        "# Adapter Dimer Reads",
+        "% Adapter Dimer",
+    ])?;
+
+    for ls in lane_stats {
+        for sample_stats in &ls.demux_results {
+            let dimers = sample_stats.number_reads_adapter_dimer.unwrap_or(0);
+            wtr.write_record(&[
+                ls.lane_number.to_string(),
+                sample_stats.sample_id.clone(),
+                sample_stats.number_reads.to_string(),
+                dimers.to_string(),
+                fraction(dimers, sample_stats.number_reads),
+            ])?;
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// True if the adapter dimers were counted in any lane
+fn has_adapter_dimers(lane_stats: &[LaneStats]) -> bool {
+    lane_stats
+        .iter()
+        .flat_map(|ls| ls.demux_results.iter())
+        .any(|s| s.number_reads_adapter_dimer.is_some())
+}
+
+/// Write a per-lane summary of index hopping: the number of reads with valid but
+/// mismatched index pairs, and the estimated hopping rate
+fn write_index_hopping_summary(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record(["Lane", "# Assigned Reads", "# Hopped Reads", "Hopping Rate"])?;
+
+    for ls in lane_stats {
+        wtr.write_record(&[
+            ls.lane_number.to_string(),
+            ls.assigned_reads().to_string(),
+            ls.hopped_reads().to_string(),
+            format!("{:.6}", ls.hopping_rate()),
+        ])?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write the counts of each unexpected index combination, along with the samples
+/// that the two indices came from
+fn write_index_hopping_counts(report_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(report_path)?;
+
+    wtr.write_record([
+        "Lane",
+        "index",
+        "index2",
+        "index SampleID",
+        "index2 SampleID",
+        "# Reads",
+        "% of Hopped Reads",
+    ])?;
+
+    for ls in lane_stats {
+        let n_hopped = ls.hopped_reads();
+
+        for ((sample_i, sample2_i), count) in ls.index_hopping.most_common_ordered() {
+            let sample = &ls.demux_results[sample_i];
+            let sample2 = &ls.demux_results[sample2_i];
+            let index = &sample.index_metrics[0].index_sequence;
+            let index2 = &sample2.index_metrics[0].index_sequence;
+
+            wtr.write_record(&[
+                ls.lane_number.to_string(),
+                index.split('+').next().unwrap_or_default().to_string(),
+                index2.split('+').nth(1).unwrap_or_default().to_string(),
+                sample.sample_id.clone(),
+                sample2.sample_id.clone(),
+                count.to_string(),
+                fraction(count, n_hopped),
+            ])?;
+        }
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// escape the characters that have special meaning in HTML
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// percentage of `n` out of `total`, formatted for the HTML report
+fn percent(n: u64, total: u64) -> String {
+    if total == 0 {
+        "0.00".to_string()
+    } else {
+        format!("{:.2}", 100. * n as f64 / total as f64)
+    }
+}
+
+/// Combine the read metrics for all samples and undetermined reads in a lane
+fn lane_read_metrics(ls: &LaneStats) -> Vec<ReadMetrics> {
+    let mut read_metrics = ls.undetermined.read_metrics.clone();
+
+    for sample_stats in ls.demux_results.iter() {
+        for (lane_rm, sample_rm) in read_metrics.iter_mut().zip(&sample_stats.read_metrics) {
+            lane_rm.merge(sample_rm);
+        }
+    }
+
+    read_metrics
+}
+
+/// Add a table of how many of each sample's reads had 0, 1 or 2+ mismatches in
+/// each index, if any reads were assigned
+fn write_mismatch_histogram(html: &mut String, ls: &LaneStats) {
+    let n_indices = ls
+        .demux_results
+        .iter()
+        .map(|s| s.index_mismatch_histogram.len())
+        .max()
+        .unwrap_or_default();
+    if n_indices == 0 {
+        return;
+    }
+
+    writeln!(html, "<h2>Lane {}: index mismatches</h2>", ls.lane_number).unwrap();
+    write!(html, "<table><tr><th>Sample</th>").unwrap();
+    for index_i in 1..=n_indices {
+        for mismatches in 0..=HTML_HISTOGRAM_MISMATCHES {
+            let plus = if mismatches == HTML_HISTOGRAM_MISMATCHES {
+                "+"
+            } else {
+                ""
+            };
+            write!(html, "<th>Index {}: {}{}</th>", index_i, mismatches, plus).unwrap();
+        }
+    }
+    writeln!(html, "</tr>").unwrap();
+
+    for sample_stats in ls.demux_results.iter() {
+        write!(
+            html,
+            "<tr><td class=\"name\">{}</td>",
+            escape_html(&sample_stats.sample_name)
+        )
+        .unwrap();
+        for index_i in 0..n_indices {
+            let counts = sample_stats
+                .index_mismatch_histogram
+                .get(index_i)
+                .map_or(&[][..], |c| c.as_slice());
+            for mismatches in 0..=HTML_HISTOGRAM_MISMATCHES {
+                let n: u64 = if mismatches == HTML_HISTOGRAM_MISMATCHES {
+                    counts.iter().skip(mismatches).sum()
+                } else {
+                    counts.get(mismatches).cloned().unwrap_or_default()
+                };
+                write!(html, "<td>{}</td>", n).unwrap();
+            }
+        }
+        writeln!(html, "</tr>").unwrap();
+    }
+    writeln!(html, "</table>").unwrap();
+}
+
+/// Render a single-file HTML summary of the run, with per-lane and per-sample
+/// tables and the most common unknown barcodes
+fn render_html(novaseq_run: &NovaSeqRun, lane_stats: &[LaneStats]) -> String {
+    let run_id = escape_html(&novaseq_run.run_info.id);
+    let mut html = String::new();
+
+    writeln!(html, "<!DOCTYPE html>").unwrap();
+    writeln!(html, "<html><head><meta charset=\"utf-8\">").unwrap();
+    writeln!(html, "<title>bcl2fastr report: {}</title>", run_id).unwrap();
+    writeln!(html, "<style>{}</style></head><body>", HTML_STYLE).unwrap();
+    writeln!(html, "<h1>{}</h1>", run_id).unwrap();
+    writeln!(
+        html,
+        "<p>Flowcell {} on instrument {}, run {}</p>",
+        escape_html(&novaseq_run.run_info.flowcell),
+        escape_html(&novaseq_run.run_info.instrument),
+        novaseq_run.run_info.number,
+    )
+    .unwrap();
+    if let Some(run_parameters) = &novaseq_run.run_parameters {
+        writeln!(
+            html,
+            "<p>Chemistry: {}</p>",
+            escape_html(&run_parameters.chemistry())
+        )
+        .unwrap();
+    }
+
+    writeln!(html, "<h2>Lane summary</h2>").unwrap();
+    writeln!(
+        html,
+        "<table><tr><th>Lane</th><th>Clusters (raw)</th><th>Clusters (PF)</th>\
+         <th>Yield</th><th>% Undetermined</th></tr>"
+    )
+    .unwrap();
+    for ls in lane_stats {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            ls.lane_number,
+            ls.total_clusters_raw,
+            ls.total_clusters_pf,
+            ls.yield_bases,
+            percent(ls.undetermined.number_reads, ls.number_reads()),
+        )
+        .unwrap();
+    }
+    writeln!(html, "</table>").unwrap();
+
+    writeln!(html, "<h2>Pool balance</h2>").unwrap();
+    writeln!(
+        html,
+        "<table><tr><th>Lane</th><th>Samples</th><th>CV of reads</th>\
+         <th class=\"name\">Flags</th></tr>"
+    )
+    .unwrap();
+    for ls in lane_stats {
+        let cv = ls.read_count_cv();
+        let mut flags = Vec::new();
+
+        if cv > MAX_BARCODE_CV {
+            flags.push("reads are poorly balanced across samples".to_string());
+        }
+        for (idx_i, index_cycles) in ls.index_cycle_metrics.iter().enumerate() {
+            for (cycle_i, cm) in index_cycles.iter().enumerate() {
+                if cm.max_base_fraction() > MAX_INDEX_BASE_FRACTION {
+                    flags.push(format!(
+                        "low base diversity in index {} cycle {}",
+                        idx_i + 1,
+                        cycle_i + 1
+                    ));
+                }
+            }
+        }
+
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{:.3}</td><td class=\"name\">{}</td></tr>",
+            ls.lane_number,
+            ls.demux_results.len(),
+            cv,
+            flags.join("<br>"),
+        )
+        .unwrap();
+    }
+    writeln!(html, "</table>").unwrap();
+
+    writeln!(html, "<h2>Quality by read</h2>").unwrap();
+    writeln!(
+        html,
+        "<table><tr><th>Lane</th><th>Read</th><th>Yield</th><th>% &ge; Q30</th>\
+         <th>Mean quality</th></tr>"
+    )
+    .unwrap();
+    for ls in lane_stats {
+        for rm in lane_read_metrics(ls) {
+            let mean_quality = if rm.yield_bases == 0 {
+                0.
+            } else {
+                rm.quality_score_sum as f64 / rm.yield_bases as f64
+            };
+
+            writeln!(
+                html,
+                "<tr><td>{}</td><td>R{}</td><td>{}</td><td>{}</td><td>{:.2}</td></tr>",
+                ls.lane_number,
+                rm.read_number,
+                rm.yield_bases,
+                percent(rm.yield_q30, rm.yield_bases),
+                mean_quality,
+            )
+            .unwrap();
+        }
+    }
+    writeln!(html, "</table>").unwrap();
+
+    for ls in lane_stats {
+        let lane_reads = ls.number_reads();
+
+        writeln!(html, "<h2>Lane {}: samples</h2>", ls.lane_number).unwrap();
+        writeln!(
+            html,
+            "<table><tr><th>Sample</th><th>#</th><th>Project</th><th>Index</th><th>Reads</th>\
+             <th>% of lane</th><th>% Perfect index</th></tr>"
+        )
+        .unwrap();
+        for sample_stats in ls.demux_results.iter() {
+            let index_metrics = &sample_stats.index_metrics[0];
+            let perfect = index_metrics
+                .mismatch_counts
+                .get("0")
+                .cloned()
+                .unwrap_or_default();
+
+            writeln!(
+                html,
+                "<tr><td class=\"name\">{}</td><td>S{}</td><td class=\"name\">{}</td>\
+                 <td class=\"name\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&sample_stats.sample_name),
+                sample_stats.sample_number,
+                escape_html(sample_stats.sample_project.as_deref().unwrap_or_default()),
+                escape_html(&index_metrics.index_sequence),
+                sample_stats.number_reads,
+                percent(sample_stats.number_reads, lane_reads),
+                percent(perfect, sample_stats.number_reads),
+            )
+            .unwrap();
+        }
+        writeln!(
+            html,
+            "<tr><td class=\"name\">Undetermined</td><td>S0</td><td></td><td></td>\
+             <td>{}</td><td>{}</td><td></td></tr>",
+            ls.undetermined.number_reads,
+            percent(ls.undetermined.number_reads, lane_reads),
+        )
+        .unwrap();
+        if ls.phix.number_reads > 0 {
+            writeln!(
+                html,
+                "<tr><td class=\"name\">PhiX</td><td></td><td></td><td></td>\
+                 <td>{}</td><td>{}</td><td></td></tr>",
+                ls.phix.number_reads,
+                percent(ls.phix.number_reads, lane_reads),
+            )
+            .unwrap();
+        }
+        writeln!(html, "</table>").unwrap();
+
+        write_mismatch_histogram(&mut html, ls);
+
+        writeln!(
+            html,
+            "<h2>Lane {}: top unknown barcodes</h2>",
+            ls.lane_number
+        )
+        .unwrap();
+        writeln!(
+            html,
+            "<table><tr><th>Barcode</th><th>Reads</th><th>% of lane</th>\
+             <th>Likely cause</th></tr>"
+        )
+        .unwrap();
+        for (barcode, count) in ls.top_unknown_barcodes(HTML_UNKNOWN_BARCODES) {
+            let hints: Vec<_> = find_hints(&barcode, &ls.demux_results)
+                .iter()
+                .map(|hint| escape_html(&hint.to_string()))
+                .collect();
+
+            writeln!(
+                html,
+                "<tr><td class=\"name\">{}</td><td>{}</td><td>{}</td>\
+                 <td class=\"name\">{}</td></tr>",
+                escape_html(&barcode),
+                count,
+                percent(count, lane_reads),
+                hints.join("<br>"),
+            )
+            .unwrap();
+        }
+        writeln!(html, "</table>").unwrap();
+    }
+
+    writeln!(html, "</body></html>").unwrap();
+
+    html
+}
+
+/// Build a MultiQC custom content table with a row per sample and lane, with read
+/// counts, the fraction of the lane, and the breakdown of index mismatches
+fn multiqc_table(lane_stats: &[LaneStats]) -> Value {
+    let mut data = Map::new();
+
+    for ls in lane_stats {
+        let lane_reads = ls.number_reads();
+
+        for sample_stats in ls.demux_results.iter() {
+            let mismatch_counts = &sample_stats.index_metrics[0].mismatch_counts;
+            let n_reads = sample_stats.number_reads;
+            let n_perfect = mismatch_counts.get("0").cloned().unwrap_or_default();
+
+            data.insert(
+                format!("{} (L{})", sample_stats.sample_id, ls.lane_number),
+                json!({
+                    "lane": ls.lane_number,
+                    "reads": n_reads,
+                    "pct_lane": 100. * n_reads as f64 / lane_reads.max(1) as f64,
+                    "pct_perfect_index": 100. * n_perfect as f64 / n_reads.max(1) as f64,
+                    "pct_mismatch_index":
+                        100. * (n_reads - n_perfect) as f64 / n_reads.max(1) as f64,
+                }),
+            );
+        }
+
+        data.insert(
+            format!("Undetermined (L{})", ls.lane_number),
+            json!({
+                "lane": ls.lane_number,
+                "reads": ls.undetermined.number_reads,
+                "pct_lane":
+                    100. * ls.undetermined.number_reads as f64 / lane_reads.max(1) as f64,
+            }),
+        );
+    }
+
+    json!({
+        "id": "bcl2fastr_demux",
+        "section_name": "bcl2fastr",
+        "description": "Reads assigned to each sample by bcl2fastr",
+        "plot_type": "table",
+        "pconfig": {
+            "id": "bcl2fastr_demux_table",
+            "title": "bcl2fastr: demultiplexing",
+        },
+        "headers": {
+            "lane": {"title": "Lane", "format": "{:,.0f}"},
+            "reads": {"title": "Reads", "format": "{:,.0f}"},
+            "pct_lane": {"title": "% of Lane", "suffix": "%", "max": 100, "min": 0},
+            "pct_perfect_index": {
+                "title": "% Perfect Index", "suffix": "%", "max": 100, "min": 0,
+            },
+            "pct_mismatch_index": {
+                "title": "% Mismatched Index", "suffix": "%", "max": 100, "min": 0,
+            },
+        },
+        "data": data,
+    })
+}
+
+/// Write the BCL Convert-style reports and the HTML summary into `Reports/`
+/// in the output directory
+pub fn write_reports(
+    output_path: &Path,
+    novaseq_run: &NovaSeqRun,
+    lane_stats: &[LaneStats],
+) -> csv::Result<()> {
+    let reports_path = output_path.join("Reports");
+    create_dir_all(&reports_path)?;
+
+    write_demux_stats(&reports_path.join("Demultiplex_Stats.csv"), lane_stats)?;
+    write_project_stats(&reports_path.join("Project_Stats.csv"), lane_stats)?;
+    write_top_unknown_barcodes(&reports_path.join("Top_Unknown_Barcodes.csv"), lane_stats)?;
+    write_cycle_metrics(&reports_path.join("Cycle_Metrics.csv"), lane_stats)?;
+    write_tile_metrics(
+        &reports_path.join("Tile_Metrics.csv"),
+        &reports_path.join("Swath_Metrics.csv"),
+        lane_stats,
+        novaseq_run
+            .run_info
+            .flowcell_layout
+            .tile_naming_convention
+            .unwrap_or(TileNamingConvention::FourDigit),
+    )?;
+    write_barcode_balance(
+        &reports_path.join("Barcode_Balance.csv"),
+        &reports_path.join("Index_Base_Balance.csv"),
+        lane_stats,
+    )?;
+    write_unknown_barcode_hints(&reports_path.join("Unknown_Barcode_Hints.csv"), lane_stats)?;
+    if lane_stats.iter().any(|ls| ls.screen.screened > 0) {
+        write_undetermined_screen(&reports_path.join("Undetermined_Screen.csv"), lane_stats)?;
+    }
+    if lane_stats.iter().any(|ls| ls.umi.umis > 0) {
+        write_umi_metrics(&reports_path.join("UMI_Metrics.csv"), lane_stats)?;
+    }
+    if has_adapter_dimers(lane_stats) {
+        write_adapter_dimers(&reports_path.join("Adapter_Dimers.csv"), lane_stats)?;
+    }
+
+    // index hopping can only be detected with two indices
+    if novaseq_run.read_structure.n_indices() == 2 {
+        write_index_hopping_summary(&reports_path.join("Index_Hopping_Summary.csv"), lane_stats)?;
+        write_index_hopping_counts(&reports_path.join("Index_Hopping_Counts.csv"), lane_stats)?;
+    }
+    write(
+        reports_path.join("bcl2fastr_mqc.json"),
+        serde_json::to_string_pretty(&multiqc_table(lane_stats)).unwrap(),
+    )?;
+    write(
+        reports_path.join("report.html"),
+        render_html(novaseq_run, lane_stats),
+    )?;
+
+    Ok(())
+}
+
+/// Write the reports that can be made from merged Stats.json files, when each
+/// shard of a run was demultiplexed separately. The per-cycle and per-tile
+/// metrics and the index hopping counts aren't kept in Stats.json, so those
+/// reports are left out
+pub fn write_merged_reports(output_path: &Path, lane_stats: &[LaneStats]) -> csv::Result<()> {
+    let reports_path = output_path.join("Reports");
+    create_dir_all(&reports_path)?;
+
+    write_demux_stats(&reports_path.join("Demultiplex_Stats.csv"), lane_stats)?;
+    write_top_unknown_barcodes(&reports_path.join("Top_Unknown_Barcodes.csv"), lane_stats)?;
+    write_unknown_barcode_hints(&reports_path.join("Unknown_Barcode_Hints.csv"), lane_stats)?;
+    if lane_stats.iter().any(|ls| ls.screen.screened > 0) {
+        write_undetermined_screen(&reports_path.join("Undetermined_Screen.csv"), lane_stats)?;
+    }
+    if lane_stats.iter().any(|ls| ls.umi.umis > 0) {
+        write_umi_metrics(&reports_path.join("UMI_Metrics.csv"), lane_stats)?;
+    }
+    if has_adapter_dimers(lane_stats) {
+        write_adapter_dimers(&reports_path.join("Adapter_Dimers.csv"), lane_stats)?;
+    }
+    write(
+        reports_path.join("bcl2fastr_mqc.json"),
+        serde_json::to_string_pretty(&multiqc_table(lane_stats)).unwrap(),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fraction() {
+        assert_eq!(super::fraction(1, 4), "0.2500");
+        assert_eq!(super::fraction(0, 0), "0.0000");
+    }
+
+    #[test]
+    fn escape_html() {
+        assert_eq!(
+            super::escape_html("<a & \"b\">"),
+            "&lt;a &amp; &quot;b&quot;&gt;"
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! Compares the contents of a run folder with RunInfo.xml before anything is
+//! loaded, so that every missing CBCL, filter or locs file is listed up front
+//! rather than the first one causing a panic partway through loading the run
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::novaseq_run::tile_locs_path;
+use crate::run_info_parser::{parse_run_info, read_run_info, RunInfo};
+use crate::storage::tar_storage::{is_archive, tar_index, TarStorage};
+use crate::storage::{gz_path, LocalStorage, RunStorage};
+
+const BASECALLS: &str = "Data/Intensities/BaseCalls";
+
+/// The files that RunInfo.xml says should be in the run folder but aren't, and
+/// the files that are there but shouldn't be. Paths are relative to the run folder
+#[derive(Debug, Default, PartialEq)]
+pub struct FolderCheck {
+    pub missing: Vec<PathBuf>,
+    pub unexpected: Vec<PathBuf>,
+}
+
+impl FolderCheck {
+    /// True if nothing is missing. Unexpected files don't stop us from
+    /// demultiplexing, but might mean RunInfo.xml is from a different run
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// The missing files of each lane, or None if a file that every lane needs is
+    /// missing, like `s.locs`
+    pub fn missing_by_lane(&self) -> Option<BTreeMap<usize, Vec<PathBuf>>> {
+        let mut by_lane: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for path in self.missing.iter() {
+            let lane = path
+                .components()
+                .find_map(|c| dir_number(c.as_os_str().to_str()?, "L", ""))?;
+            by_lane.entry(lane).or_default().push(path.clone());
+        }
+
+        Some(by_lane)
+    }
+
+    /// One line for each missing or unexpected file
+    pub fn problems(&self) -> Vec<String> {
+        self.missing
+            .iter()
+            .map(|p| format!("missing file {}", p.display()))
+            .chain(
+                self.unexpected
+                    .iter()
+                    .map(|p| format!("unexpected file {}", p.display())),
+            )
+            .collect()
+    }
+}
+
+fn lane_dir(lane: usize) -> PathBuf {
+    Path::new(BASECALLS).join(format!("L{:03}", lane))
+}
+
+fn cbcl_path(lane: usize, cycle: usize, surface: usize) -> PathBuf {
+    lane_dir(lane).join(format!("C{}.1/L{:03}_{}.cbcl", cycle, lane, surface))
+}
+
+fn filter_path(lane: usize, tile: u32) -> PathBuf {
+    lane_dir(lane).join(format!("s_{}_{}.filter", lane, tile))
+}
+
+/// The number in a name like `L001` or `C12.1`
+fn dir_number(name: &str, prefix: &str, suffix: &str) -> Option<usize> {
+    name.strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+/// The files of a run: a run folder, or a run archived as tar files, with the
+/// entries of each folder in the archive's index
+enum RunFiles<'a> {
+    Folder(&'a Path),
+    Archive {
+        run_path: &'a Path,
+        storage: Arc<TarStorage>,
+        folders: BTreeMap<PathBuf, BTreeSet<(String, bool)>>,
+    },
+}
+
+impl<'a> RunFiles<'a> {
+    /// The folders of the archive at `run_path`, from the files in its index
+    fn archive(run_path: &'a Path) -> std::io::Result<RunFiles<'a>> {
+        let storage = tar_index(run_path)?;
+        let mut folders: BTreeMap<PathBuf, BTreeSet<_>> = BTreeMap::new();
+        for path in storage.files() {
+            let mut is_dir = false;
+            for entry in path.ancestors() {
+                if let (Some(parent), Some(name)) = (entry.parent(), entry.file_name()) {
+                    folders
+                        .entry(parent.to_path_buf())
+                        .or_default()
+                        .insert((name.to_string_lossy().into_owned(), is_dir));
+                }
+                is_dir = true;
+            }
+        }
+
+        Ok(RunFiles::Archive {
+            run_path,
+            storage,
+            folders,
+        })
+    }
+
+    fn run_info(&self) -> std::io::Result<RunInfo> {
+        match self {
+            RunFiles::Folder(run_path) => Ok(parse_run_info(&run_path.join("RunInfo.xml"))?),
+            RunFiles::Archive {
+                run_path, storage, ..
+            } => Ok(read_run_info(storage.open(&run_path.join("RunInfo.xml"))?)?),
+        }
+    }
+
+    /// Whether there is a file at `path`, relative to the run folder
+    fn is_file(&self, path: &Path) -> bool {
+        match self {
+            RunFiles::Folder(run_path) => run_path.join(path).is_file(),
+            RunFiles::Archive {
+                run_path, storage, ..
+            } => storage.is_file(&run_path.join(path)),
+        }
+    }
+
+    /// The names of the entries in a folder of the run, sorted, and whether each is
+    /// a folder. Nothing if the folder can't be read
+    fn entries(&self, path: &Path) -> Vec<(String, bool)> {
+        match self {
+            RunFiles::Folder(run_path) => {
+                let mut entries: Vec<_> = match fs::read_dir(run_path.join(path)) {
+                    Ok(entries) => entries
+                        .filter_map(|e| e.ok())
+                        .map(|e| {
+                            (
+                                e.file_name().to_string_lossy().into_owned(),
+                                e.path().is_dir(),
+                            )
+                        })
+                        .collect(),
+                    Err(_) => Vec::new(),
+                };
+                entries.sort();
+
+                entries
+            }
+            RunFiles::Archive { folders, .. } => folders
+                .get(path)
+                .map_or_else(Vec::new, |entries| entries.iter().cloned().collect()),
+        }
+    }
+
+    /// Whether each tile has its own locs file, as the run's storage sees it
+    fn has_tile_locs(&self) -> bool {
+        match self {
+            RunFiles::Folder(run_path) => LocalStorage.has_tile_locs(run_path),
+            RunFiles::Archive {
+                run_path, storage, ..
+            } => storage.has_tile_locs(run_path),
+        }
+    }
+}
+
+/// Read RunInfo.xml and check that the run folder has a CBCL for every lane,
+/// surface and cycle, a filter file for every tile, and the locs file (or a locs
+/// file for every tile, on an iSeq), and look
+/// for CBCL and filter files that RunInfo.xml doesn't know about. If `lanes` is
+/// given, other lanes are ignored. A run archived as tar files is checked against
+/// the archive's index
+pub fn check_run_folder(
+    run_path: &Path,
+    lanes: Option<&BTreeSet<usize>>,
+) -> std::io::Result<FolderCheck> {
+    let run_files = if is_archive(run_path) {
+        RunFiles::archive(run_path)?
+    } else {
+        RunFiles::Folder(run_path)
+    };
+    let run_info = run_files.run_info()?;
+
+    Ok(compare_run_folder(&run_files, &run_info, lanes))
+}
+
+/// Compare the files in the run folder with `run_info`
+fn compare_run_folder(
+    run_files: &RunFiles,
+    run_info: &RunInfo,
+    lanes: Option<&BTreeSet<usize>>,
+) -> FolderCheck {
+    let layout = &run_info.flowcell_layout;
+    let n_cycles = run_info.total_cycles();
+    let check_lane = |lane: &usize| lanes.is_none_or(|lanes| lanes.contains(lane));
+
+    let tile_locs = run_files.has_tile_locs();
+
+    let mut expected = Vec::new();
+    if !tile_locs {
+        expected.push(PathBuf::from("Data/Intensities/s.locs"));
+    }
+    for [lane, surface] in layout.lane_surfaces() {
+        if check_lane(&lane) {
+            expected.extend((1..=n_cycles).map(|cycle| cbcl_path(lane, cycle, surface)));
+        }
+    }
+    expected.extend(
+        layout
+            .tiles
+            .iter()
+            .filter(|t| check_lane(&t.lane))
+            .map(|t| filter_path(t.lane, t.number)),
+    );
+    if tile_locs {
+        expected.extend(
+            layout
+                .tiles
+                .iter()
+                .filter(|t| check_lane(&t.lane))
+                .map(|t| tile_locs_path(t.lane, t.number)),
+        );
+    }
+
+    let missing = expected
+        .iter()
+        .filter(|p| !run_files.is_file(p) && !run_files.is_file(&gz_path(p)))
+        .cloned()
+        .collect();
+
+    let expected: HashSet<_> = expected.into_iter().collect();
+    let mut unexpected = Vec::new();
+
+    for (name, is_dir) in run_files.entries(Path::new(BASECALLS)) {
+        let lane = match dir_number(&name, "L", "") {
+            Some(lane) if is_dir => lane,
+            _ => continue,
+        };
+
+        if lane == 0 || lane > layout.lane_count {
+            unexpected.push(lane_dir(lane));
+            continue;
+        }
+        if !check_lane(&lane) {
+            continue;
+        }
+
+        for (name, _) in run_files.entries(&lane_dir(lane)) {
+            let relative = lane_dir(lane).join(&name);
+
+            if let Some(cycle) = dir_number(&name, "C", ".1") {
+                if cycle == 0 || cycle > n_cycles {
+                    unexpected.push(relative);
+                    continue;
+                }
+
+                unexpected.extend(
+                    run_files
+                        .entries(&relative)
+                        .into_iter()
+                        .map(|(name, _)| relative.join(name))
+                        .filter(|p| {
+                            p.extension().is_some_and(|e| e == "cbcl") && !expected.contains(p)
+                        }),
+                );
+            } else if name.trim_end_matches(".gz").ends_with(".filter")
+                && !layout.tiles.is_empty()
+                // a gzipped filter stands in for the one that is expected
+                && !expected.contains(&lane_dir(lane).join(name.trim_end_matches(".gz")))
+            {
+                unexpected.push(relative);
+            }
+        }
+    }
+
+    FolderCheck {
+        missing,
+        unexpected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_run() {
+        let run_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let folder_check = check_run_folder(run_path, None).unwrap();
+        assert_eq!(folder_check, FolderCheck::default());
+        assert!(folder_check.is_complete());
+    }
+
+    #[test]
+    fn iseq_run() {
+        let run_path = Path::new("test_data/210618_FS10000171_0042_BPA73113-1417");
+        let mut run_info = parse_run_info(&run_path.join("RunInfo.xml")).unwrap();
+        assert_eq!(
+            compare_run_folder(&RunFiles::Folder(run_path), &run_info, None),
+            FolderCheck::default()
+        );
+
+        // a tile that isn't in the run is missing its filter and locs files
+        let mut extra_tile = run_info.flowcell_layout.tiles[1];
+        extra_tile.number = 1103;
+        run_info.flowcell_layout.tiles.push(extra_tile);
+        assert_eq!(
+            compare_run_folder(&RunFiles::Folder(run_path), &run_info, None).missing,
+            vec![
+                filter_path(1, 1103),
+                PathBuf::from("Data/Intensities/L001/s_1_1103.locs")
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_and_unexpected() {
+        let run_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let mut run_info = parse_run_info(&run_path.join("RunInfo.xml")).unwrap();
+
+        // pretend the run has two more cycles and one fewer tile than it does
+        run_info.reads.last_mut().unwrap().end += 2;
+        run_info.reads.last_mut().unwrap().num_cycles += 2;
+        run_info.flowcell_layout.tiles.pop();
+
+        let folder_check = compare_run_folder(&RunFiles::Folder(run_path), &run_info, None);
+        assert_eq!(
+            folder_check.missing,
+            vec![cbcl_path(1, 25, 1), cbcl_path(1, 26, 1)]
+        );
+        assert_eq!(folder_check.unexpected, vec![filter_path(1, 1103)]);
+        assert_eq!(
+            folder_check.missing_by_lane(),
+            Some(
+                vec![(1, vec![cbcl_path(1, 25, 1), cbcl_path(1, 26, 1)])]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        assert_eq!(
+            folder_check.problems()[0],
+            "missing file Data/Intensities/BaseCalls/L001/C25.1/L001_1.cbcl"
+        );
+
+        // lane 1 isn't checked, so only the locs file is expected
+        let lanes: BTreeSet<usize> = vec![2].into_iter().collect();
+        assert_eq!(
+            compare_run_folder(&RunFiles::Folder(run_path), &run_info, Some(&lanes)),
+            FolderCheck::default()
+        );
+    }
+
+    #[test]
+    fn tar_archive() {
+        let archive_path = Path::new("test_data/test_output/run_folder_archive");
+        let _ = fs::remove_dir_all(archive_path);
+        fs::create_dir_all(archive_path).unwrap();
+
+        // the whole run, and the run without one of its CBCLs
+        for (name, exclude) in [("complete", "nothing"), ("incomplete", "C3.1")] {
+            let tar_path = archive_path.join(format!("{}.tar", name));
+            let status = std::process::Command::new("tar")
+                .arg(format!("--exclude={}", exclude))
+                .arg("-cf")
+                .arg(&tar_path)
+                .args(["-C", "test_data", "190414_A00111_0296_AHJCWWDSXX"])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        let folder_check = check_run_folder(&archive_path.join("complete.tar"), None).unwrap();
+        assert_eq!(folder_check, FolderCheck::default());
+
+        let folder_check = check_run_folder(&archive_path.join("incomplete.tar"), None).unwrap();
+        assert_eq!(folder_check.missing, vec![cbcl_path(1, 3, 1)]);
+        assert!(folder_check.unexpected.is_empty());
+    }
+}
@@ -1,12 +1,72 @@
 //! Deserializes the `RunInfo.xml` file from a NovaSeq run into a useful struct
-//! of information about the sequencing run.
+//! of information about the sequencing run. Versions 2 to 6 of the file are
+//! supported, which covers the MiSeq, HiSeq, NextSeq, NovaSeq and NovaSeq X
 
 use serde::{de, Deserialize};
 use serde_xml_rs::from_reader;
-use std::{fs::File, ops::RangeInclusive, path::Path};
+use std::{error, fmt, fs::File, io, ops::RangeInclusive, path::Path, str::FromStr};
+
+/// The versions of RunInfo.xml that we know how to read
+const SUPPORTED_VERSIONS: RangeInclusive<u32> = 2..=6;
+
+/// An error reading or validating RunInfo.xml
+#[derive(Debug)]
+pub enum RunInfoError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The file isn't valid XML, or is missing a required field
+    Parse(String),
+    /// The file is from a version we don't support
+    UnsupportedVersion(u32),
+    /// The file was parsed but its contents don't make sense
+    Invalid(String),
+}
+
+impl fmt::Display for RunInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunInfoError::Io(e) => write!(f, "{}", e),
+            RunInfoError::Parse(e) => write!(f, "Error parsing RunInfo: {}", e),
+            RunInfoError::UnsupportedVersion(v) => write!(
+                f,
+                "RunInfo version {} is not supported (expected {} to {})",
+                v,
+                SUPPORTED_VERSIONS.start(),
+                SUPPORTED_VERSIONS.end()
+            ),
+            RunInfoError::Invalid(e) => write!(f, "Invalid RunInfo: {}", e),
+        }
+    }
+}
+
+impl error::Error for RunInfoError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RunInfoError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RunInfoError {
+    fn from(e: io::Error) -> Self {
+        RunInfoError::Io(e)
+    }
+}
+
+/// Most of the callers read the rest of the run folder too, so it's convenient to
+/// turn these into IO errors
+impl From<RunInfoError> for io::Error {
+    fn from(e: RunInfoError) -> Self {
+        match e {
+            RunInfoError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
 
 /// The top-level struct for the contents of RunInfo.xml
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RunInfo {
     /// Version number of this file (depends on the sequencer)
     pub version: u32,
@@ -102,8 +162,82 @@ impl<'de> Deserialize<'de> for RunInfo {
     }
 }
 
+impl RunInfo {
+    /// Check that the fields we rely on have sensible values
+    fn validate(&self) -> Result<(), RunInfoError> {
+        if !SUPPORTED_VERSIONS.contains(&self.version) {
+            return Err(RunInfoError::UnsupportedVersion(self.version));
+        }
+
+        for (name, value) in [
+            ("Id", &self.id),
+            ("Flowcell", &self.flowcell),
+            ("Instrument", &self.instrument),
+        ] {
+            if value.is_empty() {
+                return Err(RunInfoError::Invalid(format!("{} is empty", name)));
+            }
+        }
+
+        if self.reads.is_empty() {
+            return Err(RunInfoError::Invalid("no reads".to_string()));
+        }
+        if let Some(read) = self.reads.iter().find(|r| r.num_cycles == 0) {
+            return Err(RunInfoError::Invalid(format!(
+                "read {} has no cycles",
+                read.number
+            )));
+        }
+
+        let layout = &self.flowcell_layout;
+        if layout.lane_count == 0 || layout.surface_range.is_empty() {
+            return Err(RunInfoError::Invalid(
+                "flowcell has no lanes or surfaces".to_string(),
+            ));
+        }
+
+        for tile in layout.tiles.iter() {
+            if !(1..=layout.lane_count).contains(&tile.lane) {
+                return Err(RunInfoError::Invalid(format!(
+                    "tile {} is in lane {} but the flowcell has {} lanes",
+                    tile, tile.lane, layout.lane_count
+                )));
+            }
+            if !layout.surface_range.contains(&tile.surface) {
+                return Err(RunInfoError::Invalid(format!(
+                    "tile {} is on surface {} but the flowcell has {} surfaces",
+                    tile,
+                    tile.surface,
+                    layout.surface_range.end()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Only use the first `n_cycles` cycles of the run, e.g. because it is still
+    /// sequencing. Reads that extend past the last cycle are shortened, and reads
+    /// that start after it are removed
+    pub fn truncate_cycles(&mut self, n_cycles: usize) {
+        // cycles are numbered from 1, so `end` is one past the last cycle
+        let end = n_cycles + 1;
+
+        self.reads.retain(|r| r.start < end);
+        for read in self.reads.iter_mut() {
+            read.end = read.end.min(end);
+            read.num_cycles = read.end - read.start;
+        }
+    }
+
+    /// The total number of cycles in the run
+    pub fn total_cycles(&self) -> usize {
+        self.reads.iter().map(|r| r.num_cycles).sum()
+    }
+}
+
 /// Information about one of the reads in a run
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct Read {
     /// Which read this is
     #[serde(rename = "Number")]
@@ -140,7 +274,7 @@ where
 }
 
 /// Information about the flowcell used in the run
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FlowcellLayout {
     /// Number of lanes
     pub lane_count: usize,
@@ -150,16 +284,160 @@ pub struct FlowcellLayout {
     pub swath_count: u64,
     /// Number of tiles per swath
     pub tile_count: u64,
-    /// Sides of the flowcell
-    pub flowcell_side: u32,
-    /// Format for naming tiles
-    pub tile_naming_convention: String,
-    /// A Vec of tile names
-    pub tiles: Vec<String>,
+    /// Sides of the flowcell. Only written by the NovaSeq
+    pub flowcell_side: Option<u32>,
+    /// Format for naming tiles, if the file lists them
+    pub tile_naming_convention: Option<TileNamingConvention>,
+    /// The tiles on the flowcell, which is empty for older versions that don't
+    /// list them
+    pub tiles: Vec<Tile>,
+}
+
+impl FlowcellLayout {
+    /// Every [lane, surface] pair with tiles on it, in order. If the tiles aren't
+    /// listed, assume every lane has every surface
+    pub fn lane_surfaces(&self) -> Vec<[usize; 2]> {
+        if self.tiles.is_empty() {
+            return (1..=self.lane_count)
+                .flat_map(|lane| self.surface_range.clone().map(move |s| [lane, s]))
+                .collect();
+        }
+
+        let mut lane_surfaces: Vec<_> = self.tiles.iter().map(|t| [t.lane, t.surface]).collect();
+        lane_surfaces.sort_unstable();
+        lane_surfaces.dedup();
+
+        lane_surfaces
+    }
+
+    /// The surfaces with tiles in `lane`
+    pub fn surfaces(&self, lane: usize) -> Vec<usize> {
+        self.lane_surfaces()
+            .into_iter()
+            .filter(|[l, _]| *l == lane)
+            .map(|[_, surface]| surface)
+            .collect()
+    }
+}
+
+/// How the tiles are numbered. The digits of the tile number are the surface, the
+/// swath, (for FiveDigit) the camera section, and then two digits for the tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileNamingConvention {
+    FourDigit,
+    FiveDigit,
+}
+
+impl TileNamingConvention {
+    /// The number of digits in a tile number
+    pub fn digits(self) -> usize {
+        match self {
+            TileNamingConvention::FourDigit => 4,
+            TileNamingConvention::FiveDigit => 5,
+        }
+    }
+
+    /// Guess the convention from the length of a tile number, for files that
+    /// don't say which one they use
+    fn from_digits(digits: usize) -> Option<TileNamingConvention> {
+        match digits {
+            4 => Some(TileNamingConvention::FourDigit),
+            5 => Some(TileNamingConvention::FiveDigit),
+            _ => None,
+        }
+    }
+
+    /// Split a tile number (e.g. 2345 or 23456) into its parts
+    pub fn decode(self, lane: usize, number: u32) -> Tile {
+        let (surface, swath, section) = match self {
+            TileNamingConvention::FourDigit => (number / 1000, (number / 100) % 10, None),
+            TileNamingConvention::FiveDigit => (
+                number / 10000,
+                (number / 1000) % 10,
+                Some(((number / 100) % 10) as usize),
+            ),
+        };
+
+        Tile {
+            lane,
+            surface: surface as usize,
+            swath: swath as usize,
+            section,
+            tile: (number % 100) as usize,
+            number,
+        }
+    }
+}
+
+impl FromStr for TileNamingConvention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "FourDigit" => Ok(TileNamingConvention::FourDigit),
+            "FiveDigit" => Ok(TileNamingConvention::FiveDigit),
+            _ => Err(format!("unsupported tile naming convention {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for TileNamingConvention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TileNamingConvention::FourDigit => write!(f, "FourDigit"),
+            TileNamingConvention::FiveDigit => write!(f, "FiveDigit"),
+        }
+    }
+}
+
+/// A tile on the flowcell, decoded from a name like `1_2104`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tile {
+    pub lane: usize,
+    pub surface: usize,
+    pub swath: usize,
+    /// The camera section within the swath, only in FiveDigit names
+    pub section: Option<usize>,
+    /// The position of the tile within the swath (or section)
+    pub tile: usize,
+    /// The full tile number, as used in the CBCL headers and read names
+    pub number: u32,
+}
+
+impl Tile {
+    /// Parse a tile name from RunInfo.xml. If `convention` is `None` it is guessed
+    /// from the number of digits
+    pub fn parse(name: &str, convention: Option<TileNamingConvention>) -> Result<Tile, String> {
+        let (lane, number) = name
+            .split_once('_')
+            .filter(|(_, n)| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+            .ok_or_else(|| format!("tile name {} is not lane_tile", name))?;
+        let lane = lane
+            .parse()
+            .map_err(|_| format!("tile name {} has an invalid lane", name))?;
+
+        let convention = convention
+            .or_else(|| TileNamingConvention::from_digits(number.len()))
+            .ok_or_else(|| format!("can't tell the naming convention of tile {}", name))?;
+        if number.len() != convention.digits() {
+            return Err(format!(
+                "tile name {} doesn't match the {} naming convention",
+                name, convention
+            ));
+        }
+
+        Ok(convention.decode(lane, number.parse().unwrap()))
+    }
+}
+
+impl fmt::Display for Tile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}_{}", self.lane, self.number)
+    }
 }
 
 /// Deserialize the FlowcellLayout struct including flattening the interior
-/// TileNamingConvention struct into the top level
+/// TileNamingConvention struct into the top level. Older versions have no TileSet
 impl<'de> Deserialize<'de> for FlowcellLayout {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -176,9 +454,9 @@ impl<'de> Deserialize<'de> for FlowcellLayout {
             #[serde(rename = "TileCount")]
             tile_count: u64,
             #[serde(rename = "FlowcellSide")]
-            flowcell_side: u32,
+            flowcell_side: Option<u32>,
             #[serde(rename = "TileSet")]
-            tile_set: Inner,
+            tile_set: Option<Inner>,
         }
 
         #[derive(Deserialize)]
@@ -206,34 +484,50 @@ impl<'de> Deserialize<'de> for FlowcellLayout {
 
         let helper = Outer::deserialize(deserializer)?;
 
-        let surface_start = helper
-            .tile_set
-            .tiles
+        let (tile_naming_convention, tile_names) = match helper.tile_set {
+            Some(tile_set) => (
+                Some(
+                    tile_set
+                        .tile_naming_convention
+                        .parse()
+                        .map_err(de::Error::custom)?,
+                ),
+                tile_set.tiles,
+            ),
+            None => (None, Vec::new()),
+        };
+
+        let tiles = tile_names
             .iter()
-            .filter_map(|t| t.chars().nth(2).unwrap().to_digit(10))
-            .min()
-            .unwrap();
+            .map(|name| Tile::parse(name, tile_naming_convention))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(de::Error::custom)?;
+
+        // some flowcells only use the second surface. If no tiles are listed,
+        // assume every surface is used
+        let surface_start = tiles.iter().map(|t| t.surface).min().unwrap_or(1);
 
         Ok(FlowcellLayout {
             lane_count: helper.lane_count,
-            surface_range: (surface_start as usize)..=helper.surface_count,
+            surface_range: surface_start..=helper.surface_count,
             swath_count: helper.swath_count,
             tile_count: helper.tile_count,
             flowcell_side: helper.flowcell_side,
-            tile_naming_convention: helper.tile_set.tile_naming_convention,
-            tiles: helper.tile_set.tiles,
+            tile_naming_convention,
+            tiles,
         })
     }
 }
 
-/// Parse a `RunInfo.xml` file into a `RunInfo` struct or panic
-pub fn parse_run_info(run_info_path: &Path) -> std::io::Result<RunInfo> {
-    let run_xml = File::open(run_info_path)?;
+/// Parse a `RunInfo.xml` file into a `RunInfo` struct and check that it's valid
+pub fn parse_run_info(run_info_path: &Path) -> Result<RunInfo, RunInfoError> {
+    read_run_info(File::open(run_info_path)?)
+}
 
-    let runinfo: RunInfo = match from_reader(run_xml) {
-        Err(e) => panic!("Error parsing RunInfo: {}", e),
-        Ok(r) => r,
-    };
+/// Parse and check RunInfo.xml from any reader, e.g. a file in object storage
+pub fn read_run_info<R: io::Read>(run_xml: R) -> Result<RunInfo, RunInfoError> {
+    let runinfo: RunInfo = from_reader(run_xml).map_err(|e| RunInfoError::Parse(e.to_string()))?;
+    runinfo.validate()?;
 
     Ok(runinfo)
 }
@@ -265,12 +559,12 @@ mod tests {
                 surface_range: 1..=1,
                 swath_count: 6,
                 tile_count: 3,
-                flowcell_side: 1,
-                tile_naming_convention: "FourDigit".to_owned(),
+                flowcell_side: Some(1),
+                tile_naming_convention: Some(TileNamingConvention::FourDigit),
                 tiles: vec![
-                    "1_1101".to_owned(),
-                    "1_1102".to_owned(),
-                    "1_1103".to_owned(),
+                    Tile { lane: 1, surface: 1, swath: 1, section: None, tile: 1, number: 1101 },
+                    Tile { lane: 1, surface: 1, swath: 1, section: None, tile: 2, number: 1102 },
+                    Tile { lane: 1, surface: 1, swath: 1, section: None, tile: 3, number: 1103 },
                 ],
             },
         };
@@ -278,37 +572,154 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = r#"No such file or directory"#)]
+    fn truncate_cycles() {
+        let filename_info = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX/RunInfo.xml");
+        let mut run_info = parse_run_info(filename_info).unwrap();
+        assert_eq!(run_info.total_cycles(), 24);
+
+        run_info.truncate_cycles(22);
+        assert_eq!(run_info.total_cycles(), 22);
+        assert_eq!(run_info.reads.len(), 4);
+        assert_eq!(
+            run_info.reads[3],
+            Read {
+                number: 4,
+                start: 21,
+                end: 23,
+                num_cycles: 2,
+                is_indexed_read: false
+            }
+        );
+
+        run_info.truncate_cycles(20);
+        assert_eq!(run_info.reads.len(), 3);
+        assert_eq!(run_info.total_cycles(), 20);
+    }
+
+    #[test]
+    fn versions() {
+        let run_info = parse_run_info(Path::new("test_data/RunInfo_v2_MiSeq.xml")).unwrap();
+        assert_eq!(run_info.version, 2);
+        assert_eq!(run_info.total_cycles(), 310);
+        assert_eq!(run_info.flowcell_layout.surface_range, 1..=2);
+        assert_eq!(run_info.flowcell_layout.flowcell_side, None);
+        assert_eq!(run_info.flowcell_layout.tile_naming_convention, None);
+        assert!(run_info.flowcell_layout.tiles.is_empty());
+        assert_eq!(
+            run_info.flowcell_layout.lane_surfaces(),
+            vec![[1, 1], [1, 2]]
+        );
+
+        let run_info = parse_run_info(Path::new("test_data/RunInfo_v4_NextSeq.xml")).unwrap();
+        assert_eq!(run_info.flowcell_layout.lane_count, 4);
+        assert_eq!(run_info.flowcell_layout.surface_range, 1..=2);
+        assert_eq!(
+            run_info.flowcell_layout.tile_naming_convention,
+            Some(TileNamingConvention::FiveDigit)
+        );
+        assert_eq!(
+            run_info.flowcell_layout.tiles[1],
+            Tile {
+                lane: 1,
+                surface: 2,
+                swath: 1,
+                section: Some(1),
+                tile: 1,
+                number: 21101
+            }
+        );
+        assert_eq!(
+            run_info.flowcell_layout.lane_surfaces(),
+            vec![[1, 1], [1, 2], [4, 2]]
+        );
+        assert_eq!(run_info.flowcell_layout.surfaces(4), vec![2]);
+
+        let run_info = parse_run_info(Path::new("test_data/RunInfo_v6_NovaSeqX.xml")).unwrap();
+        assert_eq!(run_info.version, 6);
+        assert_eq!(run_info.date, "2023-03-17T22:03:31Z");
+        assert_eq!(run_info.reads.len(), 4);
+        assert_eq!(run_info.reads[3].start, 172);
+    }
+
+    #[test]
+    fn tile_names() {
+        let four = TileNamingConvention::FourDigit;
+        assert_eq!(four.decode(1, 1101).surface, 1);
+        assert_eq!(four.decode(1, 2478).swath, 4);
+        assert_eq!(Tile::parse("3_2478", None).unwrap(), four.decode(3, 2478));
+        assert_eq!(Tile::parse("3_2478", None).unwrap().to_string(), "3_2478");
+
+        let five = Tile::parse("2_13612", Some(TileNamingConvention::FiveDigit)).unwrap();
+        assert_eq!(
+            [five.surface, five.swath, five.section.unwrap(), five.tile],
+            [1, 3, 6, 12]
+        );
+
+        assert_eq!(
+            Tile::parse("1_1101", Some(TileNamingConvention::FiveDigit)),
+            Err("tile name 1_1101 doesn't match the FiveDigit naming convention".to_string())
+        );
+        assert!(Tile::parse("1101", None).is_err());
+        assert!(Tile::parse("1_101", None).is_err());
+        assert!("SixDigit".parse::<TileNamingConvention>().is_err());
+    }
+
+    /// the error message from parsing a file that should fail
+    fn parse_error(path: &str) -> String {
+        parse_run_info(Path::new(path)).unwrap_err().to_string()
+    }
+
+    #[test]
     fn no_file() {
-        let filename_info = Path::new("test_data/no_RunInfo.xml");
-        parse_run_info(filename_info).unwrap();
+        let err = parse_run_info(Path::new("test_data/no_RunInfo.xml")).unwrap_err();
+        assert!(matches!(err, RunInfoError::Io(_)));
+        assert!(err.to_string().contains("No such file or directory"));
     }
 
     #[test]
-    #[should_panic(expected = r#"invalid value: string "Q", expected Y or N"#)]
     fn weird_file() {
-        let filename_info = Path::new("test_data/weird_RunInfo.xml");
-        parse_run_info(filename_info).unwrap();
+        assert!(parse_error("test_data/weird_RunInfo.xml")
+            .contains(r#"invalid value: string "Q", expected Y or N"#));
     }
 
     #[test]
-    #[should_panic(expected = r#"Error parsing RunInfo: custom: 'missing field `Read`'"#)]
     fn no_reads() {
-        let filename_info = Path::new("test_data/bad_RunInfo_no_reads.xml");
-        parse_run_info(filename_info).unwrap();
+        assert_eq!(
+            parse_error("test_data/bad_RunInfo_no_reads.xml"),
+            "Error parsing RunInfo: custom: 'missing field `Read`'"
+        );
     }
 
     #[test]
-    #[should_panic(expected = r#"Error parsing RunInfo: custom: 'missing field `Tile`'"#)]
     fn no_tiles() {
-        let filename_info = Path::new("test_data/bad_RunInfo_no_tiles.xml");
-        parse_run_info(filename_info).unwrap();
+        assert_eq!(
+            parse_error("test_data/bad_RunInfo_no_tiles.xml"),
+            "Error parsing RunInfo: custom: 'missing field `Tile`'"
+        );
     }
 
     #[test]
-    #[should_panic(expected = r#"1:1 Unexpected end of stream: no root element found"#)]
     fn empty_file() {
-        let filename_info = Path::new("test_data/empty_file");
-        parse_run_info(filename_info).unwrap();
+        assert!(parse_error("test_data/empty_file")
+            .contains("1:1 Unexpected end of stream: no root element found"));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        assert_eq!(
+            parse_error("test_data/bad_RunInfo_version.xml"),
+            "RunInfo version 7 is not supported (expected 2 to 6)"
+        );
+    }
+
+    #[test]
+    fn tile_lane() {
+        assert_eq!(
+            parse_error("test_data/bad_RunInfo_tile_lane.xml"),
+            "Invalid RunInfo: tile 2_1103 is in lane 2 but the flowcell has 1 lanes"
+        );
+
+        let io_err: io::Error = RunInfoError::Invalid("test".to_string()).into();
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidData);
     }
 }
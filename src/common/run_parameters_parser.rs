@@ -0,0 +1,302 @@
+//! Deserializes the `RunParameters.xml` file from a run folder, which describes the
+//! instrument software, reagents and flowcell that were used. The chemistry decides
+//! some defaults for the demux, like the orientation of i5 and poly-G trimming
+
+use serde::{de, Deserialize};
+use serde_xml_rs::from_reader;
+use std::{fs::File, io::Read, path::Path};
+
+use crate::sample_data::I5Orientation;
+use crate::storage::{run_storage, RunStorage};
+
+/// Instruments that use two-color chemistry, where a G is called when there is no
+/// signal at all
+const TWO_COLOR_INSTRUMENTS: [&str; 4] = ["NovaSeq", "NextSeq", "MiniSeq", "iSeq"];
+
+/// Instruments that read i5 as the reverse complement with every reagent kit
+const REVERSE_COMPLEMENT_INSTRUMENTS: [&str; 3] = ["NextSeq", "MiniSeq", "iSeq"];
+
+/// The SBS consumable version for NovaSeq v1.5 reagents, which read i5 as the
+/// reverse complement
+const NOVASEQ_V15_SBS: u32 = 3;
+
+/// The parts of RunParameters.xml that we use. Different instruments write
+/// different fields, so they are all optional
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunParameters {
+    /// Name of the control software, which tells us the instrument type
+    pub application: Option<String>,
+    /// Version of the control software
+    pub application_version: Option<String>,
+    /// Version of the real-time analysis software that wrote the CBCLs
+    pub rta_version: Option<String>,
+    /// e.g. NovaSeqStandard or NovaSeqXp
+    pub workflow_type: Option<String>,
+    /// Which side of the instrument (A or B) the flowcell was on
+    pub side: Option<String>,
+    /// Flowcell type, e.g. SP or S4
+    pub flowcell_mode: Option<String>,
+    /// Version of the SBS reagent kit
+    pub sbs_consumable_version: Option<u32>,
+    /// Planned cycles for read 1, index 1, index 2 and read 2
+    pub read_lengths: [Option<usize>; 4],
+}
+
+/// Deserialize RunParameters, flattening the RfidsInfo struct into the top level.
+/// Bench-top instruments name some fields differently: iSeq writes `ApplicationName`
+/// and `RTAVersion`, and MiniSeq and NextSeq put the application in a `Setup` struct
+impl<'de> Deserialize<'de> for RunParameters {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Outer {
+            #[serde(rename = "Application", alias = "ApplicationName")]
+            application: Option<String>,
+            #[serde(rename = "ApplicationVersion")]
+            application_version: Option<String>,
+            #[serde(rename = "RtaVersion", alias = "RTAVersion")]
+            rta_version: Option<String>,
+            #[serde(rename = "Setup")]
+            setup: Option<Setup>,
+            #[serde(rename = "WorkflowType")]
+            workflow_type: Option<String>,
+            #[serde(rename = "Side")]
+            side: Option<String>,
+            #[serde(rename = "RfidsInfo")]
+            rfids_info: Option<Inner>,
+            #[serde(rename = "Read1NumberOfCycles")]
+            read1_cycles: Option<usize>,
+            #[serde(rename = "IndexRead1NumberOfCycles")]
+            index1_cycles: Option<usize>,
+            #[serde(rename = "IndexRead2NumberOfCycles")]
+            index2_cycles: Option<usize>,
+            #[serde(rename = "Read2NumberOfCycles")]
+            read2_cycles: Option<usize>,
+        }
+
+        #[derive(Deserialize)]
+        struct Inner {
+            #[serde(rename = "FlowCellMode")]
+            flowcell_mode: Option<String>,
+            #[serde(rename = "SbsConsumableVersion")]
+            sbs_consumable_version: Option<u32>,
+        }
+
+        #[derive(Deserialize)]
+        struct Setup {
+            #[serde(rename = "ApplicationName")]
+            application: Option<String>,
+            #[serde(rename = "ApplicationVersion")]
+            application_version: Option<String>,
+        }
+
+        let helper = Outer::deserialize(deserializer)?;
+        let (flowcell_mode, sbs_consumable_version) = match helper.rfids_info {
+            Some(rfids) => (rfids.flowcell_mode, rfids.sbs_consumable_version),
+            None => (None, None),
+        };
+
+        let (setup_application, setup_version) = match helper.setup {
+            Some(setup) => (setup.application, setup.application_version),
+            None => (None, None),
+        };
+
+        Ok(RunParameters {
+            application: helper.application.or(setup_application),
+            application_version: helper.application_version.or(setup_version),
+            rta_version: helper.rta_version,
+            workflow_type: helper.workflow_type,
+            side: helper.side,
+            flowcell_mode,
+            sbs_consumable_version,
+            read_lengths: [
+                helper.read1_cycles,
+                helper.index1_cycles,
+                helper.index2_cycles,
+                helper.read2_cycles,
+            ],
+        })
+    }
+}
+
+impl RunParameters {
+    /// true if the application name mentions one of `instruments`
+    fn is_instrument(&self, instruments: &[&str]) -> bool {
+        self.application
+            .as_ref()
+            .is_some_and(|app| instruments.iter().any(|i| app.contains(i)))
+    }
+
+    /// true if the instrument uses two-color chemistry, so reads that run off the
+    /// end of the insert (or have no signal) end in a run of G
+    pub fn is_two_color(&self) -> bool {
+        self.is_instrument(&TWO_COLOR_INSTRUMENTS)
+    }
+
+    /// The orientation that the instrument reads i5 in, compared to the forward
+    /// strand sequences that are usually in a samplesheet
+    pub fn i5_orientation(&self) -> I5Orientation {
+        let novaseq_v15 = self.is_instrument(&["NovaSeq"])
+            && self
+                .sbs_consumable_version
+                .is_some_and(|v| v >= NOVASEQ_V15_SBS);
+
+        if novaseq_v15 || self.is_instrument(&REVERSE_COMPLEMENT_INSTRUMENTS) {
+            I5Orientation::ReverseComplement
+        } else {
+            I5Orientation::Forward
+        }
+    }
+
+    /// The name of the reagent kit, if the SBS consumable version is known
+    pub fn reagent_kit(&self) -> Option<String> {
+        self.sbs_consumable_version.map(|v| match v {
+            1 => "v1.0".to_string(),
+            NOVASEQ_V15_SBS => "v1.5".to_string(),
+            v => format!("version {}", v),
+        })
+    }
+
+    /// A one-line description of the instrument and chemistry for reports
+    pub fn chemistry(&self) -> String {
+        let mut parts = Vec::new();
+
+        match (&self.application, &self.application_version) {
+            (Some(app), Some(version)) => parts.push(format!("{} {}", app, version)),
+            (Some(app), None) => parts.push(app.clone()),
+            _ => (),
+        }
+        if let Some(kit) = self.reagent_kit() {
+            parts.push(format!("{} reagents", kit));
+        }
+        if let Some(mode) = &self.flowcell_mode {
+            parts.push(format!("{} flowcell", mode));
+        }
+        if let Some(workflow) = &self.workflow_type {
+            parts.push(format!("{} workflow", workflow));
+        }
+        if let Some(side) = &self.side {
+            parts.push(format!("side {}", side));
+        }
+
+        if parts.is_empty() {
+            "unknown".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+/// Parse a `RunParameters.xml` file into a `RunParameters` struct
+pub fn parse_run_parameters(run_parameters_path: &Path) -> std::io::Result<RunParameters> {
+    read_run_parameters_xml(File::open(run_parameters_path)?)
+}
+
+/// Parse `RunParameters.xml` from any reader, e.g. a file in object storage
+fn read_run_parameters_xml<R: Read>(run_xml: R) -> std::io::Result<RunParameters> {
+    from_reader(run_xml).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Error parsing RunParameters: {}", e),
+        )
+    })
+}
+
+/// Parse `RunParameters.xml` in a run folder, if there is one. Older runs and
+/// some instruments don't have it
+pub fn read_run_parameters(run_path: &Path) -> std::io::Result<Option<RunParameters>> {
+    read_run_parameters_from(run_storage(run_path)?.as_ref(), run_path)
+}
+
+/// Like `read_run_parameters`, for a run folder in `storage`
+pub fn read_run_parameters_from(
+    storage: &dyn RunStorage,
+    run_path: &Path,
+) -> std::io::Result<Option<RunParameters>> {
+    let run_parameters_path = run_path.join("RunParameters.xml");
+
+    if storage.is_file(&run_parameters_path) {
+        read_run_parameters_xml(storage.open(&run_parameters_path)?).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let run_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let run_parameters = read_run_parameters(run_path).unwrap().unwrap();
+
+        assert_eq!(
+            run_parameters,
+            RunParameters {
+                application: Some("NovaSeq Control Software".to_owned()),
+                application_version: Some("1.6.0".to_owned()),
+                rta_version: Some("v3.4.4".to_owned()),
+                workflow_type: Some("NovaSeqStandard".to_owned()),
+                side: Some("A".to_owned()),
+                flowcell_mode: Some("S4".to_owned()),
+                sbs_consumable_version: Some(1),
+                read_lengths: [Some(4), Some(8), Some(8), Some(4)],
+            }
+        );
+        assert!(run_parameters.is_two_color());
+        assert_eq!(run_parameters.i5_orientation(), I5Orientation::Forward);
+        assert_eq!(
+            run_parameters.chemistry(),
+            "NovaSeq Control Software 1.6.0, v1.0 reagents, S4 flowcell, \
+             NovaSeqStandard workflow, side A"
+        );
+    }
+
+    #[test]
+    fn novaseq_v15() {
+        let run_parameters =
+            parse_run_parameters(Path::new("test_data/RunParameters_v1.5.xml")).unwrap();
+
+        assert_eq!(run_parameters.reagent_kit(), Some("v1.5".to_owned()));
+        assert_eq!(
+            run_parameters.i5_orientation(),
+            I5Orientation::ReverseComplement
+        );
+    }
+
+    #[test]
+    fn iseq() {
+        let run_parameters =
+            read_run_parameters(Path::new("test_data/210618_FS10000171_0042_BPA73113-1417"))
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(
+            run_parameters.application,
+            Some("iSeq Control Software".to_owned())
+        );
+        assert_eq!(run_parameters.rta_version, Some("2.9.2".to_owned()));
+        assert!(run_parameters.is_two_color());
+        assert_eq!(
+            run_parameters.i5_orientation(),
+            I5Orientation::ReverseComplement
+        );
+        assert_eq!(run_parameters.chemistry(), "iSeq Control Software 2.0.0.10");
+    }
+
+    #[test]
+    fn no_file() {
+        assert_eq!(read_run_parameters(Path::new("test_data")).unwrap(), None);
+        assert_eq!(RunParameters::default().chemistry(), "unknown");
+        assert!(!RunParameters::default().is_two_color());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"1:1 Unexpected end of stream: no root element found"#)]
+    fn empty_file() {
+        parse_run_parameters(Path::new("test_data/empty_file")).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Records the outcome of a run in `run_summary.json`, and maps it to an exit code
+//! so that workflow engines can tell different kinds of failure apart
+
+use std::{collections::BTreeMap, fs::File, io::BufWriter, path::Path, time::Instant};
+
+use chrono::{Local, SecondsFormat};
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::loading::LaneLoading;
+use crate::novaseq_run::Shard;
+
+/// The overall result of a run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    /// all tiles were demultiplexed
+    Success,
+    /// the samplesheet could not be found or parsed
+    SamplesheetError,
+    /// the run folder could not be found, or its metadata could not be read
+    BasecallError,
+    /// the output directory is missing, or the output could not be written
+    OutputError,
+    /// demux finished, but some tiles could not be read and were skipped
+    PartialSuccess,
+    /// a signal stopped the demux before every tile was done
+    Interrupted,
+    /// `compare` found differences between the two sets of fastq files
+    Discordant,
+}
+
+impl RunStatus {
+    /// The process exit code for this status. Usage errors from the argument
+    /// parser exit with 1, so we start from 2
+    pub fn exit_code(self) -> i32 {
+        match self {
+            RunStatus::Success => 0,
+            RunStatus::SamplesheetError => 2,
+            RunStatus::BasecallError => 3,
+            RunStatus::OutputError => 4,
+            RunStatus::PartialSuccess => 5,
+            RunStatus::Interrupted => 6,
+            RunStatus::Discordant => 7,
+        }
+    }
+}
+
+/// Whether a lane was demultiplexed. A lane that fails doesn't stop the others
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LaneResult {
+    Success,
+    Failed { error: String },
+}
+
+/// Status, warnings and stage timings for a run, written out as `run_summary.json`
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    status: RunStatus,
+    exit_code: i32,
+    run_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shard: Option<String>,
+    started_at: String,
+    finished_at: Option<String>,
+    warnings: Vec<String>,
+    errors: Vec<String>,
+    /// seconds spent in each stage of the run
+    timings: BTreeMap<String, f64>,
+    /// the pass-filter rate and occupancy of each lane
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    lanes: Vec<LaneLoading>,
+    /// whether each lane was demultiplexed, or the error that stopped it
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    lane_results: BTreeMap<usize, LaneResult>,
+    /// the S number of each sample, and of Undetermined
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    sample_numbers: BTreeMap<String, usize>,
+    #[serde(skip)]
+    stage_start: Instant,
+    #[serde(skip)]
+    file_name: String,
+}
+
+impl Default for RunSummary {
+    fn default() -> Self {
+        RunSummary {
+            status: RunStatus::Success,
+            exit_code: 0,
+            run_id: None,
+            shard: None,
+            started_at: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            finished_at: None,
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            timings: BTreeMap::new(),
+            lanes: Vec::new(),
+            lane_results: BTreeMap::new(),
+            sample_numbers: BTreeMap::new(),
+            stage_start: Instant::now(),
+            file_name: "run_summary.json".to_string(),
+        }
+    }
+}
+
+impl RunSummary {
+    /// Start a summary, with the clock running for the first stage
+    pub fn new() -> RunSummary {
+        RunSummary::default()
+    }
+
+    pub fn set_run_id(&mut self, run_id: &str) {
+        self.run_id = Some(run_id.to_string());
+    }
+
+    /// Record that this is one shard of a run, and write the summary to a file of
+    /// its own so that the shards can share an output directory
+    pub fn set_shard(&mut self, shard: &Shard) {
+        self.shard = Some(shard.to_string());
+        self.file_name = format!("run_summary.{}.json", shard.suffix());
+    }
+
+    /// Record how well each lane was loaded
+    pub fn set_loading(&mut self, lanes: Vec<LaneLoading>) {
+        self.lanes = lanes;
+    }
+
+    /// Record that `lane` was demultiplexed
+    pub fn lane_succeeded(&mut self, lane: usize) {
+        self.lane_results.insert(lane, LaneResult::Success);
+    }
+
+    /// Record that `lane` couldn't be demultiplexed, and log the error
+    pub fn lane_failed(&mut self, lane: usize, error: String) {
+        self.error(format!("lane {} failed: {}", lane, error));
+        self.lane_results.insert(lane, LaneResult::Failed { error });
+    }
+
+    /// True if any lane failed
+    pub fn has_failed_lanes(&self) -> bool {
+        self.lane_results
+            .values()
+            .any(|result| *result != LaneResult::Success)
+    }
+
+    /// Record the number of each sample
+    pub fn set_sample_numbers(&mut self, sample_numbers: BTreeMap<String, usize>) {
+        self.sample_numbers = sample_numbers;
+    }
+
+    /// Record the time since the previous stage ended as the time for `stage`
+    pub fn end_stage(&mut self, stage: &str) {
+        self.timings
+            .insert(stage.to_string(), self.stage_start.elapsed().as_secs_f64());
+        self.stage_start = Instant::now();
+    }
+
+    /// Log a warning and include it in the summary
+    pub fn warn(&mut self, warning: String) {
+        warn!("{}", warning);
+        self.warnings.push(warning);
+    }
+
+    /// Log an error and include it in the summary
+    pub fn error(&mut self, error: String) {
+        error!("{}", error);
+        self.errors.push(error);
+    }
+
+    /// Set the final status and write `run_summary.json` (or the file for this
+    /// shard) into the output directory
+    pub fn finish(&mut self, status: RunStatus, output_path: &Path) -> std::io::Result<()> {
+        self.status = status;
+        self.exit_code = status.exit_code();
+        self.finished_at = Some(Local::now().to_rfc3339_opts(SecondsFormat::Secs, false));
+
+        let out_file = BufWriter::new(File::create(output_path.join(&self.file_name))?);
+        serde_json::to_writer_pretty(out_file, self)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(RunStatus::Success.exit_code(), 0);
+        assert_eq!(RunStatus::PartialSuccess.exit_code(), 5);
+        assert_eq!(RunStatus::Interrupted.exit_code(), 6);
+    }
+
+    #[test]
+    fn end_stage() {
+        let mut summary = RunSummary::new();
+        summary.end_stage("load");
+        summary.end_stage("demux");
+
+        assert_eq!(
+            summary.timings.keys().collect::<Vec<_>>(),
+            vec!["demux", "load"]
+        );
+    }
+
+    #[test]
+    fn lane_results() {
+        let mut summary = RunSummary::new();
+        summary.lane_succeeded(1);
+        assert!(!summary.has_failed_lanes());
+
+        summary.lane_failed(2, "missing filter".to_string());
+        assert!(summary.has_failed_lanes());
+        assert_eq!(summary.errors, vec!["lane 2 failed: missing filter"]);
+        assert_eq!(
+            serde_json::to_value(&summary.lane_results).unwrap(),
+            serde_json::json!({
+                "1": {"status": "success"},
+                "2": {"status": "failed", "error": "missing filter"},
+            })
+        );
+    }
+}
@@ -2,20 +2,160 @@
 //! that have index to sample mappings with all indices included within distance 1
 //! of original index or distance 0 if overlapping indices are present within distance 1
 
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-
-use log::warn;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use flate2::read::MultiGzDecoder;
 use ndarray::ArrayView1;
 use rayon::prelude::*;
 
-use crate::hamming_set::{check_conflict, hamming_set, singleton_set};
+use crate::barcode_hints::reverse_complement;
+use crate::hamming_set::{conflicting_samples, hamming_set, singleton_set, Alphabet};
 
 /// SampleData maps from lane number to the index maps for the lane. The maps are
 /// chunked into different pieces, each corresponding to a set of samples that will be
 /// processed together
 pub type SampleData = HashMap<usize, Samples>;
 
+/// How the index2 (i5) sequences in the samplesheet compare to the way the
+/// instrument reads them. Some chemistries (e.g. NovaSeq v1.5 reagents) read i5
+/// as the reverse complement of the sequence on the forward strand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I5Orientation {
+    Forward,
+    ReverseComplement,
+}
+
+impl FromStr for I5Orientation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => Ok(I5Orientation::Forward),
+            "reverse-complement" => Ok(I5Orientation::ReverseComplement),
+            _ => Err(format!(
+                "expected forward or reverse-complement, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for I5Orientation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            I5Orientation::Forward => write!(f, "forward"),
+            I5Orientation::ReverseComplement => write!(f, "reverse-complement"),
+        }
+    }
+}
+
+/// How a read's two indices must match a sample. In combinatorial mode any pairing of
+/// an i7 and an i5 that is in the samplesheet is a sample, so indices can be shared
+/// between samples. Unique dual indices (UDI) give every sample its own i7 and i5, so
+/// we can also require the read to be close to the sample across both indices
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DualIndexMode {
+    #[default]
+    Combinatorial,
+    StrictUdi {
+        max_mismatches: usize,
+    },
+}
+
+impl fmt::Display for DualIndexMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DualIndexMode::Combinatorial => write!(f, "combinatorial"),
+            DualIndexMode::StrictUdi { max_mismatches } => write!(
+                f,
+                "strict UDI with at most {} mismatches in total",
+                max_mismatches
+            ),
+        }
+    }
+}
+
+/// What to do when two samples in a lane have indices within the requested number
+/// of mismatches of each other
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// fail, naming the samples
+    Error,
+    /// allow fewer mismatches in the lane, down to exact matches
+    #[default]
+    Reduce,
+    /// keep the mismatches and drop one sample of each conflicting pair, so that its
+    /// reads go to Undetermined
+    DropSample,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(ConflictPolicy::Error),
+            "reduce" => Ok(ConflictPolicy::Reduce),
+            "drop-sample" => Ok(ConflictPolicy::DropSample),
+            _ => Err(format!(
+                "expected error, reduce or drop-sample, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// What was changed in one lane because of conflicting samples
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictReport {
+    pub requested_distance: usize,
+    pub distance: usize,
+    /// the samples whose indices overlapped, with the number of mismatches where
+    /// they first overlapped
+    pub conflicts: Vec<(usize, String, String)>,
+    pub dropped_samples: Vec<String>,
+}
+
+impl ConflictReport {
+    /// One line for each change, e.g. to print or add to the run summary
+    pub fn messages(&self, lane: usize) -> Vec<String> {
+        let lane = match lane {
+            0 => "all lanes".to_string(),
+            lane => format!("lane {}", lane),
+        };
+
+        let mut messages: Vec<_> = self
+            .conflicts
+            .iter()
+            .map(|(distance, a, b)| {
+                format!(
+                    "{}: {} and {} conflict at distance {}",
+                    lane, a, b, distance
+                )
+            })
+            .collect();
+        if self.distance < self.requested_distance {
+            messages.push(format!(
+                "{}: using distance {} instead of {}",
+                lane, self.distance, self.requested_distance
+            ));
+        }
+        messages.extend(self.dropped_samples.iter().map(|sample| {
+            format!(
+                "{}: dropped {}, so its reads are undetermined",
+                lane, sample
+            )
+        }));
+
+        messages
+    }
+}
+
 /// The Samples struct has one or two maps that go from potential indices to sample
 /// and corrected index strings. To save space and for speed, we save the original
 /// data as a vector and use integers to index into them.
@@ -23,14 +163,21 @@ pub type SampleData = HashMap<usize, Samples>;
 /// If there is only one index, index2 will contain a single empty string. If there are
 /// two indices index2 will contain the original index with a '+' prepended. This makes
 /// it very easy to print out the correct header later.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Samples {
     pub sample_names: Vec<String>,
     pub project_names: Vec<Option<String>>,
+    /// from the samplesheet's `Genome` or `ReferenceGenome` column, if it has one
+    pub genomes: Vec<Option<String>>,
+    /// bcl2fastq's S1..Sn, by the order the samples first appear in the
+    /// samplesheets. The undetermined reads are S0
+    pub sample_numbers: Vec<usize>,
     index_vec: Vec<Vec<u8>>,
     index_map: Vec<HashSet<Vec<u8>>>,
     index2_vec: Vec<Vec<u8>>,
     index2_map: Vec<HashSet<Vec<u8>>>,
+    distance: usize,
+    dual_index_mode: DualIndexMode,
 }
 
 impl Samples {
@@ -55,6 +202,148 @@ impl Samples {
         }
     }
 
+    /// Count the total number of mismatches between the indices and a sample's
+    /// original index sequences
+    pub fn mismatches(&self, i: usize, indices: &[ArrayView1<u8>]) -> usize {
+        self.index_mismatches(i, indices).iter().sum()
+    }
+
+    /// Count the mismatches between each of the indices and the sample's original
+    /// index sequence
+    pub fn index_mismatches(&self, i: usize, indices: &[ArrayView1<u8>]) -> Vec<usize> {
+        let mut sample_indices = vec![&self.index_vec[i]];
+        if let Some(index2) = self.index2_vec.get(i) {
+            sample_indices.push(index2);
+        }
+
+        sample_indices
+            .iter()
+            .zip(indices)
+            .map(|(sample_index, index)| {
+                sample_index
+                    .iter()
+                    .zip(index.iter())
+                    .filter(|(a, b)| a != b)
+                    .count()
+            })
+            .collect()
+    }
+
+    /// Find the sample (if any) that matches a vector of indices
+    pub fn find_sample(&self, indices: &[ArrayView1<u8>]) -> Option<usize> {
+        let sample_i = (0..self.sample_names.len()).find(|&i| self.get_sample(i, indices))?;
+        match self.max_total_mismatches() {
+            Some(max_mismatches) if self.mismatches(sample_i, indices) > max_mismatches => None,
+            _ => Some(sample_i),
+        }
+    }
+
+    /// Switch to strict UDI matching, or back to combinatorial. Strict UDI needs two
+    /// indices and fails if any i7 or i5 is used by more than one sample
+    pub fn set_dual_index_mode(&mut self, dual_index_mode: DualIndexMode) -> Result<(), String> {
+        if let DualIndexMode::StrictUdi { .. } = dual_index_mode {
+            if self.index2_vec.is_empty() {
+                return Err("strict UDI needs two indices, but the samples have one".to_string());
+            }
+
+            for (name, index_vec) in [("Index", &self.index_vec), ("Index2", &self.index2_vec)] {
+                let mut seen = HashMap::new();
+                for (sample_i, index) in index_vec.iter().enumerate() {
+                    if let Some(other_i) = seen.insert(index, sample_i) {
+                        return Err(format!(
+                            "{} and {} have the same {} {}, so the indices aren't unique",
+                            self.sample_names[other_i],
+                            self.sample_names[sample_i],
+                            name,
+                            String::from_utf8_lossy(index)
+                        ));
+                    }
+                }
+            }
+        }
+
+        self.dual_index_mode = dual_index_mode;
+        Ok(())
+    }
+
+    /// The limit on mismatches summed over both indices, in strict UDI mode
+    pub fn max_total_mismatches(&self) -> Option<usize> {
+        match self.dual_index_mode {
+            DualIndexMode::Combinatorial => None,
+            DualIndexMode::StrictUdi { max_mismatches } => Some(max_mismatches),
+        }
+    }
+
+    /// For dual-indexed samples, find two different samples whose first and second
+    /// indices (respectively) match the read. This is an unexpected combination of
+    /// indices, most likely caused by index hopping
+    pub fn find_hopped(&self, indices: &[ArrayView1<u8>]) -> Option<(usize, usize)> {
+        if indices.len() != 2 || self.index2_map.is_empty() {
+            return None;
+        }
+
+        let idx = indices[0].as_slice().unwrap();
+        let idx2 = indices[1].as_slice().unwrap();
+
+        let sample_i = self.index_map.iter().position(|m| m.contains(idx))?;
+        let sample2_i = self.index2_map.iter().position(|m| m.contains(idx2))?;
+
+        if sample_i == sample2_i {
+            None
+        } else {
+            Some((sample_i, sample2_i))
+        }
+    }
+
+    /// The original index sequence(s) for a sample, joined with '+' if there are two
+    pub fn index_string(&self, i: usize) -> String {
+        let index = String::from_utf8_lossy(&self.index_vec[i]);
+        match self.index2_vec.get(i) {
+            Some(index2) => format!("{}+{}", index, String::from_utf8_lossy(index2)),
+            None => index.to_string(),
+        }
+    }
+
+    /// A sample's name with its number, as bcl2fastq names its files, e.g.
+    /// `sample_1_S1`
+    pub fn numbered_name(&self, i: usize) -> String {
+        numbered_sample_name(&self.sample_names[i], self.sample_numbers[i])
+    }
+
+    /// The original sequence of index `index_i` (0 or 1) for a sample
+    pub fn sample_index(&self, index_i: usize, sample_i: usize) -> &[u8] {
+        match index_i {
+            0 => &self.index_vec[sample_i],
+            1 => &self.index2_vec[sample_i],
+            x => panic!("Got index {}?!", x),
+        }
+    }
+
+    /// The length of each index in the samplesheet, the longest if the samples'
+    /// differ
+    pub fn index_lengths(&self) -> Vec<usize> {
+        [&self.index_vec, &self.index2_vec]
+            .iter()
+            .take(self.n_indices())
+            .map(|indices| indices.iter().map(Vec::len).max().unwrap_or(0))
+            .collect()
+    }
+
+    /// The number of indices for each sample, 1 or 2
+    pub fn n_indices(&self) -> usize {
+        if self.index2_vec.is_empty() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// The number of mismatches allowed in each index, which can be less than asked
+    /// for if the samples would conflict
+    pub fn distance(&self) -> usize {
+        self.distance
+    }
+
     /// Checks if the indices match any of the samples
     pub fn is_any_sample(&self, indices: &[Vec<u8>]) -> bool {
         match indices.len() {
@@ -70,167 +359,564 @@ impl Samples {
 
     /// helper function for when there is one index
     fn get_1index_sample(&self, i: usize, idx: ArrayView1<u8>) -> bool {
-        return self.index_map[i].contains(idx.as_slice().unwrap());
+        self.index_map[i].contains(idx.as_slice().unwrap())
     }
 
     /// helper function for when there are two indices
     fn get_2index_sample(&self, i: usize, idx: ArrayView1<u8>, idx2: ArrayView1<u8>) -> bool {
-        return self.index_map[i].contains(idx.as_slice().unwrap())
-            && self.index2_map[i].contains(idx2.as_slice().unwrap());
+        self.index_map[i].contains(idx.as_slice().unwrap())
+            && self.index2_map[i].contains(idx2.as_slice().unwrap())
+    }
+}
+
+/// Keep the items of `v` where `keep` is true, unless `v` is empty
+fn retain_kept<T>(v: &mut Vec<T>, keep: &[bool]) {
+    if v.len() == keep.len() {
+        let mut kept = keep.iter();
+        v.retain(|_| *kept.next().unwrap());
     }
 }
 
 /// Function to go from a lane worth of sample and index vectors to a Lane struct
-/// which will include the necessary error-correction, up to some limit `max_distance`
+/// which will include the necessary error-correction, up to some limit `max_distance`.
+/// Conflicts between samples are handled with `on_conflict`, and the report says
+/// what was changed if there were any
 fn make_sample_maps(
     sample_names: &[String],
     project_names: &[Option<String>],
+    genomes: &[Option<String>],
     index_vec: &[Vec<u8>],
     index2_vec: &[Vec<u8>],
     max_distance: usize,
-) -> Samples {
+    on_conflict: ConflictPolicy,
+) -> Result<(Samples, Option<ConflictReport>), String> {
     // index_vec should be full
-    assert_eq!(
-        sample_names.len(),
-        index_vec.len(),
-        "Missing indexes for some samples"
-    );
+    if sample_names.len() != index_vec.len() {
+        return Err("Missing indexes for some samples".to_string());
+    }
 
     // index2_vec should either be full or empty, nothing in between
-    assert!(
-        index2_vec.len() == index_vec.len() || index2_vec.len() == 0,
-        "Samplesheet is missing index2 for some samples"
-    );
+    if index2_vec.len() != index_vec.len() && !index2_vec.is_empty() {
+        return Err("Samplesheet is missing index2 for some samples".to_string());
+    }
 
     // sample for sample_project: full or empty, nothing in between
-    let n_project_names = project_names.iter().cloned().filter_map(|n| n).count();
-    assert!(
-        n_project_names == sample_names.len() || n_project_names == 0,
-        "Samplesheet is missing project names for some samples"
-    );
+    let n_project_names = project_names.iter().filter(|n| n.is_some()).count();
+    if n_project_names != sample_names.len() && n_project_names != 0 {
+        return Err("Samplesheet is missing project names for some samples".to_string());
+    }
 
     // (for now) sample names should be unique, or we'll have conflicts
-    assert_eq!(
-        sample_names.len(),
-        sample_names.iter().cloned().collect::<HashSet<_>>().len(),
-        "Sample names must be unique"
-    );
+    if sample_names.len() != sample_names.iter().collect::<HashSet<_>>().len() {
+        return Err("Sample names must be unique".to_string());
+    }
+
+    let mut sample_names = sample_names.to_vec();
+    let mut project_names = project_names.to_vec();
+    let mut genomes = genomes.to_vec();
+    genomes.resize(sample_names.len(), None);
+    let mut index_vec = index_vec.to_vec();
+    let mut index2_vec = index2_vec.to_vec();
 
     // start at distance 0: just map samples to indices
     let mut index_hash_sets: Vec<_> = index_vec.iter().map(singleton_set).collect();
     let mut index2_hash_sets: Vec<_> = index2_vec.iter().map(singleton_set).collect();
 
-    if check_conflict(&sample_names, &index_hash_sets, &index2_hash_sets) {
-        panic!("Can't demux two different samples using the same indices");
-    }
-
-    for i in 1..=max_distance {
-        let new_index_hash_sets: Vec<_> = index_hash_sets.par_iter().map(hamming_set).collect();
-        let new_index2_hash_sets: Vec<_> = index2_hash_sets.par_iter().map(hamming_set).collect();
+    let mut conflicts = Vec::new();
+    let mut dropped_samples = Vec::new();
+    let mut distance = 0;
+    for i in 0..=max_distance {
+        let (mut new_index_hash_sets, mut new_index2_hash_sets): (Vec<_>, Vec<_>) = if i == 0 {
+            (index_hash_sets.clone(), index2_hash_sets.clone())
+        } else {
+            (
+                index_hash_sets
+                    .par_iter()
+                    .map(|set| hamming_set(set, 1, Alphabet::Acgtn))
+                    .collect(),
+                index2_hash_sets
+                    .par_iter()
+                    .map(|set| hamming_set(set, 1, Alphabet::Acgtn))
+                    .collect(),
+            )
+        };
 
-        if check_conflict(&sample_names, &new_index_hash_sets, &new_index2_hash_sets) {
-            warn!(
-                "Warning: conflict at distance {}, using {} instead",
-                i,
-                i - 1
-            );
-            break;
+        let clashes: Vec<_> =
+            conflicting_samples(&sample_names, &new_index_hash_sets, &new_index2_hash_sets)
+                .into_iter()
+                .map(|(a, b)| (i, a.clone(), b.clone()))
+                .collect();
+        if !clashes.is_empty() {
+            match on_conflict {
+                ConflictPolicy::DropSample => {
+                    // drop the later sample of each pair, unless one is gone already
+                    let position = |name: &String| sample_names.iter().position(|s| s == name);
+                    let mut keep = vec![true; sample_names.len()];
+                    for (_, a, b) in &clashes {
+                        let (a, b) = (position(a).unwrap(), position(b).unwrap());
+                        if keep[a] && keep[b] {
+                            keep[a.max(b)] = false;
+                        }
+                    }
+
+                    dropped_samples.extend(
+                        sample_names
+                            .iter()
+                            .zip(&keep)
+                            .filter(|(_, &k)| !k)
+                            .map(|(name, _)| name.clone()),
+                    );
+                    retain_kept(&mut sample_names, &keep);
+                    retain_kept(&mut project_names, &keep);
+                    retain_kept(&mut genomes, &keep);
+                    retain_kept(&mut index_vec, &keep);
+                    retain_kept(&mut index2_vec, &keep);
+                    retain_kept(&mut new_index_hash_sets, &keep);
+                    retain_kept(&mut new_index2_hash_sets, &keep);
+                    conflicts.extend(clashes);
+                }
+                _ if i == 0 => {
+                    return Err(
+                        "Can't demux two different samples using the same indices".to_string()
+                    )
+                }
+                ConflictPolicy::Error => {
+                    let (_, a, b) = &clashes[0];
+                    return Err(format!("{} and {} conflict at distance {}", a, b, i));
+                }
+                ConflictPolicy::Reduce => {
+                    conflicts.extend(clashes);
+                    break;
+                }
+            }
         }
 
         index_hash_sets = new_index_hash_sets;
         index2_hash_sets = new_index2_hash_sets;
+        distance = i;
     }
 
-    Samples {
-        sample_names: sample_names.to_vec(),
-        project_names: project_names.to_vec(),
-        index_vec: index_vec.to_vec(),
+    let report = if conflicts.is_empty() {
+        None
+    } else {
+        Some(ConflictReport {
+            requested_distance: max_distance,
+            distance,
+            conflicts,
+            dropped_samples,
+        })
+    };
+
+    let samples = Samples {
+        sample_numbers: (1..=sample_names.len()).collect(),
+        sample_names,
+        project_names,
+        genomes,
+        index_vec,
         index_map: index_hash_sets,
-        index2_vec: index2_vec.to_vec(),
+        index2_vec,
         index2_map: index2_hash_sets,
+        distance,
+        dual_index_mode: DualIndexMode::Combinatorial,
+    };
+
+    Ok((samples, report))
+}
+
+/// Read all of the rows of a samplesheet. It can be a CSV file, gzipped if the name
+/// ends in .gz, or an Excel workbook ending in .xlsx if bcl2fastr was built with the
+/// `xlsx` feature, in which case the first sheet is read
+pub fn read_samplesheet_rows(samplesheet: &Path) -> std::io::Result<Vec<csv::StringRecord>> {
+    let extension = samplesheet.extension().and_then(|e| e.to_str());
+    if extension == Some("xlsx") {
+        return read_xlsx_rows(samplesheet);
     }
+
+    let file = File::open(samplesheet)?;
+    let reader: Box<dyn Read> = if extension == Some("gz") {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(reader);
+
+    rdr.records()
+        .map(|r| {
+            r.map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {}", samplesheet.display(), e),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Read the first sheet of an Excel workbook as text, the way it would be saved
+/// as CSV
+#[cfg(feature = "xlsx")]
+fn read_xlsx_rows(samplesheet: &Path) -> std::io::Result<Vec<csv::StringRecord>> {
+    use calamine::{open_workbook, Reader, Xlsx, XlsxError};
+
+    let invalid = |e: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {}", samplesheet.display(), e),
+        )
+    };
+
+    let mut workbook: Xlsx<_> =
+        open_workbook(samplesheet).map_err(|e: XlsxError| invalid(e.to_string()))?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| invalid("the workbook has no sheets".to_string()))?
+        .map_err(|e| invalid(e.to_string()))?;
+
+    Ok(range
+        .rows()
+        .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+        .collect())
+}
+
+#[cfg(not(feature = "xlsx"))]
+fn read_xlsx_rows(samplesheet: &Path) -> std::io::Result<Vec<csv::StringRecord>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "can't read {}: bcl2fastr was built without the xlsx feature",
+            samplesheet.display()
+        ),
+    ))
 }
 
 /// loads a sample sheet and converts it into a SampleData struct. Our version
 /// automatically determines the mismatch rate that prevents conflicts, up to
 /// a specified maximum
 pub fn read_samplesheet(samplesheet: PathBuf, max_distance: usize) -> std::io::Result<SampleData> {
-    let mut rdr = csv::ReaderBuilder::new()
-        .flexible(true)
-        .has_headers(false)
-        .from_path(samplesheet)?;
-
-    // ignore any rows before the Data section
-    let rows: Vec<_> = rdr
-        .records()
-        .filter_map(|r| match r {
-            Ok(r) => Some(r),
-            Err(e) => panic!("{}", e),
-        })
-        .skip_while(|r| &r[0] != "[Data]")
-        .collect();
+    read_oriented_samplesheet(samplesheet, max_distance, I5Orientation::Forward)
+}
 
-    assert!(rows.len() > 2, "No samples found in samplesheet");
+/// Like `read_samplesheet`, but reverse complements the index2 sequences if the
+/// instrument reads i5 the other way round from the samplesheet
+pub fn read_oriented_samplesheet(
+    samplesheet: PathBuf,
+    max_distance: usize,
+    i5_orientation: I5Orientation,
+) -> std::io::Result<SampleData> {
+    read_oriented_samplesheets(&[samplesheet], max_distance, i5_orientation)
+}
 
-    // check for required columns before we start processing
-    {
-        let row_set: Vec<_> = rows[1].iter().collect();
-        if !row_set.contains(&"Sample_Name") {
-            panic!("Samplesheet does not have a Sample_Name column")
-        }
+/// The project for the samples of a merged samplesheet that don't have a
+/// Sample_Project, e.g. `lab_a` for `lab_a.csv` or `lab_a.csv.gz`
+fn sheet_project_name(samplesheet: &Path) -> String {
+    let samplesheet = match samplesheet.extension() {
+        Some(extension) if extension == "gz" => samplesheet.with_extension(""),
+        _ => samplesheet.to_path_buf(),
+    };
+
+    samplesheet
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+}
 
-        if !row_set.contains(&"Index") {
-            panic!("Samplesheet does not have an Index column")
-        }
-    }
+/// The samples of one lane as they are read from the samplesheets, with the
+/// samplesheet that each came from
+#[derive(Default)]
+struct LaneRecords {
+    sample_names: Vec<String>,
+    project_names: Vec<Option<String>>,
+    genomes: Vec<Option<String>>,
+    sample_idx: Vec<Vec<u8>>,
+    sample_idx2: Vec<Vec<u8>>,
+    sheets: Vec<usize>,
+}
+
+/// Read several samplesheets for the same flowcell (e.g. one from each group that
+/// shares it) into one SampleData. A sample can't be in two samplesheets, and two
+/// samplesheets can't use the same indices in a lane. When there is more than one
+/// samplesheet, the samples without a Sample_Project are put in a project named
+/// after their samplesheet, so each group's reads and stats stay together
+pub fn read_oriented_samplesheets(
+    samplesheets: &[PathBuf],
+    max_distance: usize,
+    i5_orientation: I5Orientation,
+) -> std::io::Result<SampleData> {
+    read_samplesheets_on_conflict(
+        samplesheets,
+        max_distance,
+        i5_orientation,
+        ConflictPolicy::Reduce,
+    )
+    .map(|(sample_data, _)| sample_data)
+}
+
+/// Like `read_oriented_samplesheets`, handling samples that conflict at
+/// `max_distance` with `on_conflict`. Also returns what was changed in each lane
+/// with conflicts
+pub fn read_samplesheets_on_conflict(
+    samplesheets: &[PathBuf],
+    max_distance: usize,
+    i5_orientation: I5Orientation,
+    on_conflict: ConflictPolicy,
+) -> std::io::Result<(SampleData, BTreeMap<usize, ConflictReport>)> {
+    let conflict = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
 
     // collect samples per-lane (or in one big lane if there is no lane column)
-    let mut lanes = HashMap::new();
+    let mut lanes: HashMap<usize, LaneRecords> = HashMap::new();
+    // numbered across every lane and samplesheet, so a sample has one number
+    let mut sample_numbers: HashMap<String, usize> = HashMap::new();
+
+    for (sheet_i, samplesheet) in samplesheets.iter().enumerate() {
+        // ignore any rows before the Data section
+        let rows: Vec<_> = read_samplesheet_rows(samplesheet)?
+            .into_iter()
+            .skip_while(|r| &r[0] != "[Data]")
+            .collect();
+
+        if rows.len() <= 2 {
+            return Err(conflict(format!(
+                "no samples found in {}",
+                samplesheet.display()
+            )));
+        }
 
-    for record in rows[2..]
-        .iter()
-        .map(|r| rows[1].iter().zip(r.iter()).collect::<HashMap<_, _>>())
-    {
-        let lane: usize = match record.get(&"Lane") {
-            Some(lane) => lane.parse().unwrap(),
-            None => 0,
+        // check for required columns before we start processing
+        {
+            let row_set: Vec<_> = rows[1].iter().collect();
+            for (column, name) in [("Sample_Name", "a Sample_Name"), ("Index", "an Index")] {
+                if !row_set.contains(&column) {
+                    return Err(conflict(format!(
+                        "{} does not have {} column",
+                        samplesheet.display(),
+                        name
+                    )));
+                }
+            }
+        }
+
+        let sheet_project = if samplesheets.len() > 1 {
+            Some(sheet_project_name(samplesheet))
+        } else {
+            None
         };
 
-        let (sample_names, project_names, sample_idx, sample_idx2) = lanes
-            .entry(lane)
-            .or_insert_with(|| (Vec::new(), Vec::new(), Vec::new(), Vec::new()));
+        for record in rows[2..]
+            .iter()
+            .map(|r| rows[1].iter().zip(r.iter()).collect::<HashMap<_, _>>())
+        {
+            let lane: usize = match record.get(&"Lane") {
+                Some(lane) => lane.parse().map_err(|_| {
+                    conflict(format!(
+                        "{} has an invalid Lane {:?}",
+                        samplesheet.display(),
+                        lane
+                    ))
+                })?,
+                None => 0,
+            };
+
+            let records = lanes.entry(lane).or_default();
+
+            let sample_name = match record.get(&"Sample_Name") {
+                Some(sample_name) => sample_name.to_string(),
+                None => {
+                    return Err(conflict(format!(
+                        "{} has a row without a Sample_Name",
+                        samplesheet.display()
+                    )))
+                }
+            };
+            if let Some(other_i) = records
+                .sample_names
+                .iter()
+                .position(|name| name == &sample_name)
+                .map(|i| records.sheets[i])
+                .filter(|&other_sheet| other_sheet != sheet_i)
+            {
+                return Err(conflict(format!(
+                    "sample {} is in both {} and {}",
+                    sample_name,
+                    samplesheets[other_i].display(),
+                    samplesheet.display()
+                )));
+            }
+
+            let n_numbered = sample_numbers.len();
+            sample_numbers
+                .entry(sample_name.clone())
+                .or_insert(n_numbered + 1);
+            records.sample_names.push(sample_name);
+            records.sheets.push(sheet_i);
+            match record.get(&"Sample_Project") {
+                Some(&project_name) if !project_name.is_empty() => {
+                    records.project_names.push(Some(project_name.to_string()))
+                }
+                Some(_) | None => records.project_names.push(sheet_project.clone()),
+            }
+            records.genomes.push(
+                ["Genome", "ReferenceGenome"]
+                    .iter()
+                    .filter_map(|column| record.get(column))
+                    .find(|genome| !genome.is_empty())
+                    .map(|genome| genome.to_string()),
+            );
+            match record.get(&"Index") {
+                Some(&idx) if !idx.is_empty() => records.sample_idx.push(idx.as_bytes().to_vec()),
+                Some(_) | None => (),
+            }
+            match record.get(&"Index2") {
+                Some(&idx2) if !idx2.is_empty() => match i5_orientation {
+                    I5Orientation::Forward => records.sample_idx2.push(idx2.as_bytes().to_vec()),
+                    I5Orientation::ReverseComplement => records
+                        .sample_idx2
+                        .push(reverse_complement(idx2).into_bytes()),
+                },
+                Some(_) | None => (),
+            }
+        }
+    }
+
+    // clashes within a samplesheet are caught when the hamming sets are built, but
+    // name both samplesheets if they have a sample with the same indices
+    for records in lanes.values() {
+        if records.sample_idx.len() != records.sample_names.len() {
+            continue;
+        }
 
-        sample_names.push(record.get(&"Sample_Name").unwrap().to_string());
-        match record.get(&"Sample_Project") {
-            Some(&project_name) if project_name.len() > 0 => {
-                project_names.push(Some(project_name.to_string()))
+        let mut seen: HashMap<_, usize> = HashMap::new();
+        for (i, idx) in records.sample_idx.iter().enumerate() {
+            let key = (idx, records.sample_idx2.get(i));
+            match seen.get(&key) {
+                Some(&other) if records.sheets[other] != records.sheets[i] => {
+                    return Err(conflict(format!(
+                        "{} in {} and {} in {} have the same indices {}",
+                        records.sample_names[other],
+                        samplesheets[records.sheets[other]].display(),
+                        records.sample_names[i],
+                        samplesheets[records.sheets[i]].display(),
+                        match key.1 {
+                            Some(idx2) => format!(
+                                "{}+{}",
+                                String::from_utf8_lossy(idx),
+                                String::from_utf8_lossy(idx2)
+                            ),
+                            None => String::from_utf8_lossy(idx).into_owned(),
+                        }
+                    )));
+                }
+                Some(_) => (),
+                None => {
+                    seen.insert(key, i);
+                }
             }
-            Some(_) | None => project_names.push(None),
         }
-        match record.get(&"Index") {
-            Some(&idx) if idx.len() > 0 => sample_idx.push(idx.as_bytes().to_vec()),
-            Some(_) | None => (),
+    }
+
+    let mut sample_data = HashMap::new();
+    let mut reports = BTreeMap::new();
+    for (lane, records) in lanes {
+        let (mut samples, report) = make_sample_maps(
+            &records.sample_names,
+            &records.project_names,
+            &records.genomes,
+            &records.sample_idx,
+            &records.sample_idx2,
+            max_distance,
+            on_conflict,
+        )
+        .map_err(|e| match lane {
+            0 => conflict(e),
+            lane => conflict(format!("lane {}: {}", lane, e)),
+        })?;
+        samples.sample_numbers = samples
+            .sample_names
+            .iter()
+            .map(|sample_name| sample_numbers[sample_name])
+            .collect();
+
+        sample_data.insert(lane, samples);
+        if let Some(report) = report {
+            reports.insert(lane, report);
         }
-        match record.get(&"Index2") {
-            Some(&idx2) if idx2.len() > 0 => sample_idx2.push(idx2.as_bytes().to_vec()),
-            Some(_) | None => (),
+    }
+
+    Ok((sample_data, reports))
+}
+
+/// Give each of `lanes` its own copy of the samples in lane 0, where a samplesheet
+/// without a Lane column puts them, so that every lane gets its own fastq files
+pub fn split_lane_zero(sample_data: &mut SampleData, lanes: &[usize]) {
+    if let Some(samples) = sample_data.remove(&0) {
+        for &lane in lanes {
+            sample_data.insert(lane, samples.clone());
         }
     }
+}
+
+/// Put the samples of every lane in lane 0, so that the reads of each sample from
+/// all of the lanes go in one set of fastq files. Every lane has to have the same
+/// samples with the same indices. Returns the lanes that had samples, which are the
+/// only ones to demultiplex, or `None` if the samplesheet had no lanes
+pub fn merge_lanes(sample_data: &mut SampleData) -> Result<Option<BTreeSet<usize>>, String> {
+    if sample_data.contains_key(&0) {
+        return Ok(None);
+    }
 
-    let sample_data: HashMap<_, _> = lanes
+    let lanes: BTreeSet<_> = sample_data.keys().cloned().collect();
+    let first_lane = match lanes.iter().next() {
+        Some(&lane) => lane,
+        None => return Ok(None),
+    };
+    if let Some(lane) = lanes
         .iter()
-        .map(|(&i, (sample_names, project_names, idx_vec, idx2_vec))| {
-            (
-                i,
-                make_sample_maps(sample_names, project_names, idx_vec, idx2_vec, max_distance),
-            )
+        .find(|lane| sample_data[lane] != sample_data[&first_lane])
+    {
+        return Err(format!(
+            "lane {} has different samples than lane {}, so they can't be merged",
+            lane, first_lane
+        ));
+    }
+
+    let samples = sample_data.remove(&first_lane).unwrap();
+    sample_data.clear();
+    sample_data.insert(0, samples);
+
+    Ok(Some(lanes))
+}
+
+/// A sample's name followed by `_S` and its number
+pub fn numbered_sample_name(sample_name: &str, sample_number: usize) -> String {
+    format!("{}_S{}", sample_name, sample_number)
+}
+
+/// The number of every sample in the samplesheets, and S0 for the undetermined
+/// reads
+pub fn sample_numbers(sample_data: &SampleData) -> BTreeMap<String, usize> {
+    let mut sample_numbers: BTreeMap<_, _> = sample_data
+        .values()
+        .flat_map(|samples| {
+            samples
+                .sample_names
+                .iter()
+                .cloned()
+                .zip(samples.sample_numbers.iter().cloned())
         })
         .collect();
+    sample_numbers.insert("Undetermined".to_string(), 0);
 
-    Ok(sample_data)
+    sample_numbers
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::vec_init_then_push,
+    clippy::useless_vec,
+    clippy::cloned_ref_to_slice_refs
+)]
 mod tests {
     use super::*;
     use std::fs;
@@ -254,10 +940,14 @@ mod tests {
         let expected_lane1 = Samples {
             sample_names: vec!["sample_1".to_string()],
             project_names: vec![None],
+            genomes: vec![None],
+            sample_numbers: vec![1],
             index_vec: vec![vec![65, 67, 84, 71, 67, 71, 65, 65]],
             index_map: expected_lane1_index,
             index2_vec: Vec::new(),
             index2_map: Vec::new(),
+            distance: 1,
+            dual_index_mode: DualIndexMode::Combinatorial,
         };
 
         let test_contents = fs::read_to_string("test_data/hamming_distance_1_test2.txt").unwrap();
@@ -270,10 +960,14 @@ mod tests {
         let expected_lane2 = Samples {
             sample_names: vec!["sample_2".to_string()],
             project_names: vec![None],
+            genomes: vec![None],
+            sample_numbers: vec![2],
             index_vec: vec![vec![65, 67, 84, 67, 65, 84, 67, 67]],
             index_map: expected_lane2_index,
             index2_vec: Vec::new(),
             index2_map: Vec::new(),
+            distance: 1,
+            dual_index_mode: DualIndexMode::Combinatorial,
         };
 
         let mut expected_sampledata = HashMap::new();
@@ -397,10 +1091,14 @@ mod tests {
             Samples {
                 sample_names,
                 project_names,
+                genomes: vec![None, None],
+                sample_numbers: vec![1, 2],
                 index_map: expected_index,
                 index_vec: vec![vec![71, 71, 71, 71, 71], vec![84, 84, 84, 84, 84]],
                 index2_map: expected_index2,
                 index2_vec: vec![vec![65, 65, 65, 65, 65], vec![67, 67, 67, 67, 67]],
+                distance: 1,
+                dual_index_mode: DualIndexMode::Combinatorial,
             },
         );
 
@@ -463,6 +1161,114 @@ mod tests {
         assert!(!lane.is_exact(0, &[idx1a.view(), idx2g.view()]));
     }
 
+    #[test]
+    fn find_sample() {
+        let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        let lane = &sampledata.get(&0).unwrap();
+
+        let idx1 = array![71, 84, 71, 71, 71];
+        let idx2 = array![65, 65, 65, 65, 65];
+        let idx3 = array![84, 84, 84, 84, 84];
+        let idx4 = array![67, 67, 67, 67, 71];
+
+        assert_eq!(lane.find_sample(&[idx1.view(), idx2.view()]), Some(0));
+        assert_eq!(lane.find_sample(&[idx3.view(), idx4.view()]), Some(1));
+        assert_eq!(lane.find_sample(&[idx1.view(), idx4.view()]), None);
+
+        assert_eq!(lane.mismatches(0, &[idx1.view(), idx2.view()]), 1);
+        assert_eq!(lane.mismatches(1, &[idx3.view(), idx4.view()]), 1);
+        assert_eq!(lane.mismatches(1, &[idx1.view(), idx4.view()]), 5);
+        assert_eq!(
+            lane.index_mismatches(1, &[idx1.view(), idx4.view()]),
+            [4, 1]
+        );
+
+        assert_eq!(lane.index_string(0), "GGGGG+AAAAA");
+        assert_eq!(lane.index_string(1), "TTTTT+CCCCC");
+    }
+
+    #[test]
+    fn find_hopped() {
+        let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        let lane = &sampledata.get(&0).unwrap();
+
+        let idx1 = array![71, 84, 71, 71, 71];
+        let idx2 = array![65, 65, 65, 65, 65];
+        let idx3 = array![84, 84, 84, 84, 84];
+        let idx4 = array![67, 67, 67, 67, 71];
+
+        assert_eq!(lane.find_hopped(&[idx1.view(), idx4.view()]), Some((0, 1)));
+        assert_eq!(lane.find_hopped(&[idx3.view(), idx2.view()]), Some((1, 0)));
+        assert_eq!(lane.find_hopped(&[idx1.view(), idx2.view()]), None);
+        assert_eq!(lane.find_hopped(&[idx1.view(), idx1.view()]), None);
+        assert_eq!(lane.find_hopped(&[idx1.view()]), None);
+    }
+
+    #[test]
+    fn strict_udi() {
+        let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
+        let mut sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        let lane = sampledata.get_mut(&0).unwrap();
+
+        let idx1 = array![71, 84, 71, 71, 71];
+        let idx2 = array![65, 65, 65, 65, 65];
+        let idx2t = array![65, 65, 65, 65, 84];
+
+        // one mismatch in each index is fine until we limit the total
+        assert_eq!(lane.find_sample(&[idx1.view(), idx2t.view()]), Some(0));
+
+        lane.set_dual_index_mode(DualIndexMode::StrictUdi { max_mismatches: 1 })
+            .unwrap();
+        assert_eq!(lane.max_total_mismatches(), Some(1));
+        assert_eq!(lane.find_sample(&[idx1.view(), idx2.view()]), Some(0));
+        assert_eq!(lane.find_sample(&[idx1.view(), idx2t.view()]), None);
+
+        lane.set_dual_index_mode(DualIndexMode::Combinatorial)
+            .unwrap();
+        assert_eq!(lane.find_sample(&[idx1.view(), idx2t.view()]), Some(0));
+
+        // a single index can't be a dual index
+        let samplesheet = PathBuf::from(ROOT).join("w_conflict_no_index2_w_lanes.csv");
+        let mut sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        let lane = sampledata.get_mut(&1).unwrap();
+        assert!(lane
+            .set_dual_index_mode(DualIndexMode::StrictUdi { max_mismatches: 1 })
+            .is_err());
+    }
+
+    #[test]
+    fn combinatorial() {
+        let samplesheet = PathBuf::from(ROOT).join("combinatorial_w_index2.csv");
+        let mut sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        let lane = sampledata.get_mut(&0).unwrap();
+
+        let idx_g = array![71, 71, 71, 71, 71];
+        let idx_t = array![84, 84, 84, 84, 84];
+        let idx_a = array![65, 65, 65, 65, 65];
+        let idx_c = array![67, 67, 67, 67, 67];
+
+        assert_eq!(lane.find_sample(&[idx_g.view(), idx_a.view()]), Some(0));
+        assert_eq!(lane.find_sample(&[idx_g.view(), idx_c.view()]), Some(1));
+        assert_eq!(lane.find_sample(&[idx_t.view(), idx_a.view()]), Some(2));
+
+        // TTTTT+CCCCC isn't a sample, so it's reported as hopping
+        assert_eq!(lane.find_sample(&[idx_t.view(), idx_c.view()]), None);
+        assert_eq!(
+            lane.find_hopped(&[idx_t.view(), idx_c.view()]),
+            Some((2, 1))
+        );
+
+        assert_eq!(
+            lane.set_dual_index_mode(DualIndexMode::StrictUdi { max_mismatches: 1 }),
+            Err(
+                "sample_1 and sample_2 have the same Index GGGGG, so the indices aren't unique"
+                    .to_string()
+            )
+        );
+    }
+
     #[test]
     fn any_sample_check() {
         let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
@@ -526,8 +1332,17 @@ mod tests {
 
         let index_vec = vec![b"GGGGG".to_vec(), b"TTTTT".to_vec()];
 
-        let actual_mapping =
-            super::make_sample_maps(&sample_names, &project_names, &index_vec, &[], 1);
+        let actual_mapping = super::make_sample_maps(
+            &sample_names,
+            &project_names,
+            &[],
+            &index_vec,
+            &[],
+            1,
+            ConflictPolicy::Reduce,
+        )
+        .unwrap()
+        .0;
 
         assert_eq!(actual_mapping.index_map, expected_index);
     }
@@ -540,12 +1355,85 @@ mod tests {
 
         let expected_index: Vec<_> = index_vec.iter().map(singleton_set).collect();
 
-        let actual_mapping =
-            super::make_sample_maps(&sample_names, &project_names, &index_vec, &[], 1);
+        let actual_mapping = super::make_sample_maps(
+            &sample_names,
+            &project_names,
+            &[],
+            &index_vec,
+            &[],
+            1,
+            ConflictPolicy::Reduce,
+        )
+        .unwrap()
+        .0;
 
         assert_eq!(actual_mapping.index_map, expected_index);
     }
 
+    #[test]
+    fn conflict_policies() {
+        let samplesheet = [PathBuf::from(ROOT).join("w_conflict_w_index2_w_lanes.csv")];
+        let read = |on_conflict| {
+            read_samplesheets_on_conflict(&samplesheet, 1, I5Orientation::Forward, on_conflict)
+        };
+
+        let (sampledata, reports) = read(ConflictPolicy::Reduce).unwrap();
+        assert_eq!(sampledata[&1].distance(), 0);
+        assert_eq!(
+            reports[&1],
+            ConflictReport {
+                requested_distance: 1,
+                distance: 0,
+                conflicts: vec![(1, "sample_1".to_string(), "sample_2".to_string())],
+                dropped_samples: Vec::new(),
+            }
+        );
+        assert_eq!(
+            reports[&1].messages(1),
+            [
+                "lane 1: sample_1 and sample_2 conflict at distance 1",
+                "lane 1: using distance 0 instead of 1"
+            ]
+        );
+
+        let e = read(ConflictPolicy::Error).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "lane 1: sample_1 and sample_2 conflict at distance 1"
+        );
+
+        let (sampledata, reports) = read(ConflictPolicy::DropSample).unwrap();
+        assert_eq!(sampledata[&1].sample_names, ["sample_1"]);
+        assert_eq!(sampledata[&1].distance(), 1);
+        assert_eq!(reports[&1].dropped_samples, ["sample_2"]);
+        assert_eq!(
+            reports[&1].messages(1)[1],
+            "lane 1: dropped sample_2, so its reads are undetermined"
+        );
+
+        assert_eq!(
+            "drop-sample".parse::<ConflictPolicy>(),
+            Ok(ConflictPolicy::DropSample)
+        );
+        assert!("ignore".parse::<ConflictPolicy>().is_err());
+    }
+
+    #[test]
+    fn reverse_complement_index2() {
+        let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
+        let sampledata =
+            read_oriented_samplesheet(samplesheet, 1, I5Orientation::ReverseComplement).unwrap();
+        let lane = &sampledata[&0];
+
+        assert_eq!(lane.index_string(0), "GGGGG+TTTTT");
+        assert_eq!(lane.index_string(1), "TTTTT+GGGGG");
+        assert_eq!(
+            "reverse-complement".parse::<I5Orientation>(),
+            Ok(I5Orientation::ReverseComplement)
+        );
+        assert!("backwards".parse::<I5Orientation>().is_err());
+    }
+
     #[test]
     #[should_panic(expected = r#"No such file or directory"#)]
     fn no_file() {
@@ -554,52 +1442,228 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = r#"invalid UTF-8 in field 0 near byte index 0"#)]
+    fn gzipped_file() {
+        let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
+        let gzipped = PathBuf::from(ROOT).join("no_conflict_w_index2.csv.gz");
+
+        assert_eq!(
+            read_samplesheet(gzipped, 1).unwrap(),
+            read_samplesheet(samplesheet, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn merged_samplesheets() {
+        let samplesheets = [
+            PathBuf::from(ROOT).join("no_conflict_w_index2.csv"),
+            PathBuf::from(ROOT).join("lab_b.csv"),
+        ];
+        let sampledata =
+            read_oriented_samplesheets(&samplesheets, 1, I5Orientation::Forward).unwrap();
+
+        let lane = &sampledata[&0];
+        assert_eq!(
+            lane.sample_names,
+            ["sample_1", "sample_2", "sample_3", "sample_4"]
+        );
+        // lab_b.csv has no projects, so its samples are put in a project named after it
+        assert_eq!(
+            lane.project_names,
+            [
+                Some("project_1".to_string()),
+                Some("project_2".to_string()),
+                Some("lab_b".to_string()),
+                Some("lab_b".to_string())
+            ]
+        );
+        assert_eq!(lane.index_string(2), "ACGTA+TGCAT");
+
+        // on its own, lab_b.csv's samples have no project
+        let sampledata = read_samplesheet(samplesheets[1].clone(), 1).unwrap();
+        assert_eq!(sampledata[&0].project_names, [None, None]);
+    }
+
+    #[test]
+    fn genomes() {
+        let samplesheet = PathBuf::from(ROOT).join("w_genomes.csv");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        assert_eq!(
+            sampledata[&0].genomes,
+            [Some("GRCh38".to_string()), Some("GRCm39".to_string()), None]
+        );
+
+        let samplesheet = PathBuf::from(ROOT).join("lab_b.csv");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        assert_eq!(sampledata[&0].genomes, [None, None]);
+    }
+
+    #[test]
+    fn sample_numbers() {
+        // numbered across the lanes, in the order of the samplesheet
+        let samplesheet = PathBuf::from(ROOT).join("w_conflict_in_separate_lanes_w_index2.csv");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        assert_eq!(sampledata[&1].sample_numbers, [1]);
+        assert_eq!(sampledata[&2].sample_numbers, [2]);
+        assert_eq!(sampledata[&2].numbered_name(0), "sample_2_S2");
+
+        assert_eq!(
+            super::sample_numbers(&sampledata),
+            [("Undetermined", 0), ("sample_1", 1), ("sample_2", 2)]
+                .iter()
+                .map(|&(name, n)| (name.to_string(), n))
+                .collect()
+        );
+    }
+
+    #[test]
+    fn merged_samplesheet_conflicts() {
+        let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
+        let gzipped = PathBuf::from(ROOT).join("no_conflict_w_index2.csv.gz");
+        let e =
+            read_oriented_samplesheets(&[samplesheet.clone(), gzipped], 1, I5Orientation::Forward)
+                .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "sample sample_1 is in both test_data/sample_data/no_conflict_w_index2.csv \
+             and test_data/sample_data/no_conflict_w_index2.csv.gz"
+        );
+
+        let clash = PathBuf::from(ROOT).join("lab_b_index_clash.csv");
+        let e = read_oriented_samplesheets(&[samplesheet, clash], 1, I5Orientation::Forward)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "sample_1 in test_data/sample_data/no_conflict_w_index2.csv and sample_9 in \
+             test_data/sample_data/lab_b_index_clash.csv have the same indices GGGGG+AAAAA"
+        );
+    }
+
+    #[test]
+    fn split_and_merge_lanes() {
+        let samplesheet = PathBuf::from(ROOT).join("no_conflict_w_index2.csv");
+        let mut sampledata = read_samplesheet(samplesheet.clone(), 1).unwrap();
+        let samples = sampledata[&0].clone();
+
+        split_lane_zero(&mut sampledata, &[1, 2]);
+        assert_eq!(sampledata.len(), 2);
+        assert_eq!(sampledata[&1], samples);
+        assert_eq!(sampledata[&2], samples);
+
+        // the same samples in both lanes can be merged back
+        assert_eq!(
+            merge_lanes(&mut sampledata).unwrap(),
+            Some([1, 2].iter().cloned().collect())
+        );
+        assert_eq!(sampledata.len(), 1);
+        assert_eq!(sampledata[&0], samples);
+        assert_eq!(merge_lanes(&mut sampledata).unwrap(), None);
+
+        let samplesheet = PathBuf::from(ROOT).join("w_conflict_in_separate_lanes_w_index2.csv");
+        let mut sampledata = read_samplesheet(samplesheet, 1).unwrap();
+        assert!(merge_lanes(&mut sampledata)
+            .unwrap_err()
+            .contains("has different samples than lane 1"));
+    }
+
+    #[test]
+    fn sheet_project_name() {
+        assert_eq!(super::sheet_project_name(Path::new("a/lab_a.csv")), "lab_a");
+        assert_eq!(
+            super::sheet_project_name(Path::new("lab_a.csv.gz")),
+            "lab_a"
+        );
+        assert_eq!(super::sheet_project_name(Path::new("lab_a.xlsx")), "lab_a");
+    }
+
+    #[test]
+    #[cfg(feature = "xlsx")]
+    fn xlsx_file() {
+        let samplesheet = PathBuf::from(ROOT).join("w_index2_w_lanes.xlsx");
+        let sampledata = read_samplesheet(samplesheet, 1).unwrap();
+
+        // the lane is a number in the workbook
+        let lane = &sampledata[&1];
+        assert_eq!(lane.sample_names, ["sample_1", "sample_2"]);
+        assert_eq!(
+            lane.project_names,
+            [Some("project_1".to_string()), Some("project_2".to_string())]
+        );
+        assert_eq!(lane.index_string(0), "GGGGG+AAAAA");
+        assert_eq!(lane.index_string(1), "TTTTT+CCCCC");
+    }
+
+    #[test]
+    #[cfg(not(feature = "xlsx"))]
+    fn xlsx_without_feature() {
+        let samplesheet = PathBuf::from(ROOT).join("w_index2_w_lanes.xlsx");
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
     fn bad_file() {
         let samplesheet = PathBuf::from(ROOT).join("bad_file.csv");
-        read_samplesheet(samplesheet, 1).unwrap();
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e
+            .to_string()
+            .contains("invalid UTF-8 in field 0 near byte index 0"));
     }
 
     #[test]
-    #[should_panic(expected = r#"No samples found in samplesheet"#)]
     fn empty_file() {
         let samplesheet = PathBuf::from("test_data/empty_file");
-        read_samplesheet(samplesheet, 1).unwrap();
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e
+            .to_string()
+            .contains("no samples found in test_data/empty_file"));
     }
 
     #[test]
-    #[should_panic(expected = r#"Can't demux two different samples using the same indices"#)]
     fn sample_collision() {
         let samplesheet = PathBuf::from(ROOT).join("sample_collision.csv");
-        read_samplesheet(samplesheet, 1).unwrap();
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e
+            .to_string()
+            .contains("Can't demux two different samples using the same indices"));
     }
 
     #[test]
-    #[should_panic(expected = r#"Samplesheet does not have a Sample_Name column"#)]
     fn no_sample() {
         let samplesheet = PathBuf::from(ROOT).join("no_sample_name.csv");
-        read_samplesheet(samplesheet, 1).unwrap();
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("does not have a Sample_Name column"));
     }
 
     #[test]
-    #[should_panic(expected = r#"Samplesheet does not have an Index column"#)]
     fn no_index() {
         let samplesheet = PathBuf::from(ROOT).join("no_index.csv");
-        read_samplesheet(samplesheet, 1).unwrap();
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("does not have an Index column"));
     }
 
     #[test]
-    #[should_panic(expected = r#"Samplesheet is missing index2 for some samples"#)]
     fn missing_index() {
         let samplesheet = PathBuf::from(ROOT).join("missing_index.csv");
-        read_samplesheet(samplesheet, 1).unwrap();
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e
+            .to_string()
+            .contains("Samplesheet is missing index2 for some samples"));
     }
 
     #[test]
-    #[should_panic(expected = r#"Samplesheet is missing project names for some samples"#)]
     fn missing_project() {
         let samplesheet = PathBuf::from(ROOT).join("missing_project.csv");
-        read_samplesheet(samplesheet, 1).unwrap();
+        let e = read_samplesheet(samplesheet, 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e
+            .to_string()
+            .contains("Samplesheet is missing project names for some samples"));
     }
 
     #[test]
@@ -0,0 +1,277 @@
+//! A quick screen of some of the undetermined reads in each lane, to see what they
+//! are when a run has too many: PhiX, adapter dimers, low-complexity sequence, or
+//! something else, which is usually a sample missing from the samplesheet. Reads
+//! are compared by k-mers and prefixes, without aligning them
+
+use std::{collections::HashSet, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// The length of the k-mers compared with the reference
+pub const SCREEN_K: usize = 16;
+
+/// The adapters that an adapter dimer's read starts with
+pub const ADAPTERS: [(&str, &[u8]); 4] = [
+    ("TruSeq read 1", b"AGATCGGAAGAGCACACGTCTGAACTCCAGTCA"),
+    ("TruSeq read 2", b"AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGT"),
+    ("Nextera read 1", b"CTGTCTCTTATACACATCTCCGAGCCCACGAGAC"),
+    ("Nextera read 2", b"CTGTCTCTTATACACATCTGACGCTGCCGACGA"),
+];
+
+/// The bases at the start of a read that are compared with each adapter, and the
+/// mismatches allowed in them
+const ADAPTER_PREFIX: usize = 12;
+const ADAPTER_MISMATCHES: usize = 1;
+
+/// A read is low-complexity if this much of it is one base (or N)
+const LOW_COMPLEXITY_FRACTION: f64 = 0.8;
+
+/// What an undetermined read looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenClass {
+    /// no signal in the first index, as unindexed PhiX has, or k-mers from the
+    /// reference
+    Phix,
+    AdapterDimer,
+    LowComplexity,
+    Other,
+}
+
+/// The number of screened reads of each class in a lane
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ScreenCounts {
+    pub screened: u64,
+    pub phix: u64,
+    pub adapter_dimer: u64,
+    pub low_complexity: u64,
+    pub other: u64,
+}
+
+impl ScreenCounts {
+    pub fn add(&mut self, class: ScreenClass) {
+        self.screened += 1;
+        match class {
+            ScreenClass::Phix => self.phix += 1,
+            ScreenClass::AdapterDimer => self.adapter_dimer += 1,
+            ScreenClass::LowComplexity => self.low_complexity += 1,
+            ScreenClass::Other => self.other += 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.screened == 0
+    }
+
+    pub fn merge(&mut self, other: &ScreenCounts) {
+        self.screened += other.screened;
+        self.phix += other.phix;
+        self.adapter_dimer += other.adapter_dimer;
+        self.low_complexity += other.low_complexity;
+        self.other += other.other;
+    }
+}
+
+/// Screens the first `max_reads` undetermined reads of each lane
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Screen {
+    pub max_reads: u64,
+    /// the k-mers of the PhiX genome, on both strands, if it was given
+    reference_kmers: HashSet<u32>,
+}
+
+/// The 2-bit code of a base, or None for N
+fn base_code(base: u8) -> Option<u32> {
+    match base {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// The k-mers of a sequence as 2-bit codes, skipping any with an N
+fn kmers(seq: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    seq.windows(SCREEN_K).filter_map(|window| {
+        window
+            .iter()
+            .try_fold(0, |kmer, &base| Some(kmer << 2 | base_code(base)?))
+    })
+}
+
+fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|&base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Whether a read starts with one of the adapters, so that the insert between them
+/// is (nearly) empty. Reads shorter than the prefix that is compared never are
+pub fn is_adapter_dimer(seq: &[u8]) -> bool {
+    seq.len() >= ADAPTER_PREFIX
+        && ADAPTERS.iter().any(|(_, adapter)| {
+            seq[..ADAPTER_PREFIX]
+                .iter()
+                .zip(adapter.iter())
+                .filter(|(a, b)| a != b)
+                .count()
+                <= ADAPTER_MISMATCHES
+        })
+}
+
+impl Screen {
+    pub fn new(max_reads: u64) -> Screen {
+        Screen {
+            max_reads,
+            reference_kmers: HashSet::new(),
+        }
+    }
+
+    /// Add the k-mers of the sequences in a FASTA file, e.g. the PhiX genome, to
+    /// count the reads that have any of them as PhiX
+    pub fn add_reference(&mut self, fasta_path: &Path) -> io::Result<()> {
+        let fasta = fs::read_to_string(fasta_path)?;
+        self.add_fasta(&fasta_path.display().to_string(), &fasta)
+    }
+
+    /// Add the k-mers of the sequences in `fasta`, the text of a FASTA file, so that
+    /// a reference that is built in can be added like one that is read. `name` is
+    /// for the errors
+    pub fn add_fasta(&mut self, name: &str, fasta: &str) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if !fasta.starts_with('>') {
+            return Err(invalid(format!("{} is not a FASTA file", name)));
+        }
+
+        let mut sequences = vec![Vec::new()];
+        for line in fasta.lines() {
+            if line.starts_with('>') {
+                sequences.push(Vec::new());
+            } else {
+                let sequence = sequences.last_mut().unwrap();
+                sequence.extend(line.trim().bytes().map(|b| b.to_ascii_uppercase()));
+            }
+        }
+
+        if sequences.iter().all(|seq| seq.len() < SCREEN_K) {
+            return Err(invalid(format!(
+                "{} has no sequence of at least {} bases",
+                name, SCREEN_K
+            )));
+        }
+
+        for seq in sequences {
+            self.reference_kmers.extend(kmers(&seq));
+            self.reference_kmers
+                .extend(kmers(&reverse_complement(&seq)));
+        }
+
+        Ok(())
+    }
+
+    /// What a read with this sequence and first index looks like
+    pub fn classify(&self, seq: &[u8], index: &[u8]) -> ScreenClass {
+        if (!index.is_empty() && index.iter().all(|&b| b == b'G'))
+            || kmers(seq).any(|kmer| self.reference_kmers.contains(&kmer))
+        {
+            return ScreenClass::Phix;
+        }
+
+        if is_adapter_dimer(seq) {
+            return ScreenClass::AdapterDimer;
+        }
+
+        let mut counts = [0; 5];
+        for &base in seq {
+            counts[base_code(base).unwrap_or(4) as usize] += 1;
+        }
+        if !seq.is_empty()
+            && *counts.iter().max().unwrap() as f64 >= LOW_COMPLEXITY_FRACTION * seq.len() as f64
+        {
+            return ScreenClass::LowComplexity;
+        }
+
+        ScreenClass::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let reference = "ACGTTGCATGCAAGTCCGATAGGCTTACGATCGGATCATGCAATGC";
+        let fasta_path = Path::new("test_data/test_output/screen_reference.fa");
+        fs::create_dir_all(fasta_path.parent().unwrap()).unwrap();
+        fs::write(
+            fasta_path,
+            format!(">phix\n{}\n{}\n", &reference[..20], &reference[20..]),
+        )
+        .unwrap();
+
+        let mut screen = Screen::new(10);
+        screen.add_reference(fasta_path).unwrap();
+
+        // a read from either strand of the reference, or with no first index
+        assert_eq!(
+            screen.classify(&reference.as_bytes()[10..40], b"ACGTACGT"),
+            ScreenClass::Phix
+        );
+        assert_eq!(
+            screen.classify(
+                &reverse_complement(&reference.as_bytes()[5..30]),
+                b"ACGTACGT"
+            ),
+            ScreenClass::Phix
+        );
+        assert_eq!(
+            screen.classify(b"TTTTCCCCAAAAGGGGTTTT", b"GGGGGGGG"),
+            ScreenClass::Phix
+        );
+
+        assert_eq!(
+            screen.classify(b"AGATCGGAAGTGCACACGTCTGGGGGGGGG", b"ACGTACGT"),
+            ScreenClass::AdapterDimer
+        );
+        assert!(is_adapter_dimer(b"CTGTCTCTTATACACATCT"));
+        assert!(!is_adapter_dimer(b"AGATCGGAAG"));
+        assert_eq!(
+            screen.classify(b"GGGGGGGGGGGGGGGGGGGGAGGG", b"ACGTACGT"),
+            ScreenClass::LowComplexity
+        );
+        assert_eq!(
+            screen.classify(b"TTGACCATGACGTTAGCAGTACCA", b"ACGTACGT"),
+            ScreenClass::Other
+        );
+
+        let mut counts = ScreenCounts::default();
+        counts.add(ScreenClass::Phix);
+        counts.add(ScreenClass::Other);
+        counts.merge(&counts.clone());
+        assert_eq!((counts.screened, counts.phix, counts.other), (4, 2, 2));
+
+        fs::write(fasta_path, ">phix\nACGT\n").unwrap();
+        assert!(Screen::new(10).add_reference(fasta_path).is_err());
+
+        // FASTA text is read the same way as a file
+        let mut screen = Screen::new(10);
+        screen
+            .add_fasta("built-in", &format!(">phix\n{}\n", reference))
+            .unwrap();
+        assert_eq!(
+            screen.classify(&reference.as_bytes()[10..40], b"ACGTACGT"),
+            ScreenClass::Phix
+        );
+        let e = Screen::new(10).add_fasta("built-in", "ACGT").unwrap_err();
+        assert_eq!(e.to_string(), "built-in is not a FASTA file");
+    }
+}
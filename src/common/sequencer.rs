@@ -0,0 +1,109 @@
+//! Puts work that finishes out of order back in order. Each piece of work has a
+//! ticket, its place in the sequence, and hands its result in when it's done. The
+//! results are released strictly in ticket order by whichever thread hands in the
+//! next one, so only the results that finished ahead of an earlier ticket are held.
+//! The tiles of a chunk are encoded for each sample in parallel this way, and
+//! still written to the sample's files in tile order
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+struct State<T> {
+    /// the next ticket to release
+    next: usize,
+    /// the results that are waiting for an earlier ticket
+    pending: BTreeMap<usize, T>,
+    /// a thread is releasing results
+    releasing: bool,
+}
+
+/// Releases results in ticket order, starting from ticket 0
+pub(crate) struct Sequencer<T> {
+    state: Mutex<State<T>>,
+}
+
+impl<T> Sequencer<T> {
+    pub(crate) fn new() -> Sequencer<T> {
+        Sequencer {
+            state: Mutex::new(State {
+                next: 0,
+                pending: BTreeMap::new(),
+                releasing: false,
+            }),
+        }
+    }
+
+    /// Hand in the result for `ticket`. If it's next, it's passed to `release`
+    /// along with any later results that were waiting for it, in order. Otherwise
+    /// it waits for the thread that hands in the ticket before it, which releases
+    /// it with its own `release`, so every call should release them the same way.
+    /// `release` isn't called while the sequencer is locked, so other threads can
+    /// keep handing in results, and it's never called by two threads at once
+    pub(crate) fn submit(&self, ticket: usize, result: T, mut release: impl FnMut(T)) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(ticket, result);
+        if state.releasing {
+            // the releasing thread will get to it
+            return;
+        }
+
+        state.releasing = true;
+        loop {
+            let next = state.next;
+            match state.pending.remove(&next) {
+                Some(result) => {
+                    state.next += 1;
+                    drop(state);
+                    release(result);
+                    state = self.state.lock().unwrap();
+                }
+                None => {
+                    state.releasing = false;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The number of results waiting for an earlier ticket
+    pub(crate) fn n_pending(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+
+    #[test]
+    fn release_in_order() {
+        let sequencer = Sequencer::new();
+        let mut released = Vec::new();
+
+        sequencer.submit(1, "b", |r| released.push(r));
+        sequencer.submit(2, "c", |r| released.push(r));
+        assert!(released.is_empty());
+        assert_eq!(sequencer.n_pending(), 2);
+
+        sequencer.submit(0, "a", |r| released.push(r));
+        assert_eq!(released, ["a", "b", "c"]);
+        assert_eq!(sequencer.n_pending(), 0);
+    }
+
+    #[test]
+    fn release_from_threads() {
+        let sequencer = Sequencer::new();
+        let released = Mutex::new(Vec::new());
+
+        // handed in from every thread, in whatever order they finish
+        (0..1000usize).into_par_iter().rev().for_each(|ticket| {
+            sequencer.submit(ticket, ticket, |r| released.lock().unwrap().push(r));
+        });
+
+        assert_eq!(
+            released.into_inner().unwrap(),
+            (0..1000).collect::<Vec<_>>()
+        );
+    }
+}
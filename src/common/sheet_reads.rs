@@ -0,0 +1,257 @@
+//! Checks the `[Reads]` section and `OverrideCycles` setting of a samplesheet against
+//! the reads in RunInfo.xml, to catch a samplesheet that was written for a run with
+//! different read lengths before any reads go to the wrong place
+
+use std::path::Path;
+
+use crate::read_structure::ReadStructure;
+use crate::sample_data::read_samplesheet_rows;
+
+/// The reads that a samplesheet was written for
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SheetReads {
+    /// the cycles of each read in the `[Reads]` section, by name (e.g. `R1` or `I1`).
+    /// Older samplesheets only list the template reads, as bare numbers
+    pub reads: Vec<(String, usize)>,
+    /// the `OverrideCycles` setting, e.g. `Y151;I8N2;I8N2;Y151`
+    pub override_cycles: Option<String>,
+}
+
+/// Read the `[Reads]` section and `OverrideCycles` setting of a samplesheet, if it
+/// has them
+pub fn read_sheet_reads(samplesheet: &Path) -> std::io::Result<SheetReads> {
+    let mut sheet_reads = SheetReads::default();
+    let mut section = String::new();
+
+    for row in read_samplesheet_rows(samplesheet)? {
+        let first = row.get(0).unwrap_or_default().trim();
+        if first.starts_with('[') {
+            section = first.to_string();
+            continue;
+        }
+
+        if first == "OverrideCycles" {
+            sheet_reads.override_cycles = row
+                .get(1)
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty());
+        } else if section == "[Reads]" {
+            let invalid = || {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("can't read [Reads] row '{}'", first),
+                )
+            };
+
+            // v1: one number per read. v2: e.g. Read1Cycles,151 or Index2Cycles,10
+            let (name, cycles) = match first.parse::<usize>() {
+                Ok(cycles) => {
+                    let n_templates = sheet_reads.reads.len();
+                    (format!("R{}", n_templates + 1), cycles)
+                }
+                Err(_) if first.is_empty() => continue,
+                Err(_) => {
+                    let name = first.strip_suffix("Cycles").ok_or_else(invalid)?;
+                    let name = if let Some(n) = name.strip_prefix("Read") {
+                        format!("R{}", n)
+                    } else if let Some(n) = name.strip_prefix("Index") {
+                        format!("I{}", n)
+                    } else {
+                        return Err(invalid());
+                    };
+                    let cycles = row
+                        .get(1)
+                        .and_then(|c| c.trim().parse().ok())
+                        .ok_or_else(invalid)?;
+                    (name, cycles)
+                }
+            };
+            sheet_reads.reads.push((name, cycles));
+        }
+    }
+
+    Ok(sheet_reads)
+}
+
+/// The number of cycles in one read of an `OverrideCycles` string, e.g. 10 for
+/// `I8N2` or 151 for `U8Y143`
+fn override_read_cycles(read: &str) -> Result<usize, String> {
+    let mut cycles = 0;
+    let mut rest = read;
+
+    while !rest.is_empty() {
+        if !rest.starts_with(['Y', 'I', 'U', 'N']) {
+            return Err(format!("can't read '{}' in OverrideCycles", read));
+        }
+
+        let digits = rest[1..].len()
+            - rest[1..]
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        cycles += rest[1..1 + digits]
+            .parse::<usize>()
+            .map_err(|_| format!("can't read '{}' in OverrideCycles", read))?;
+        rest = &rest[1 + digits..];
+    }
+
+    Ok(cycles)
+}
+
+impl SheetReads {
+    /// Describe every way that the samplesheet's reads don't match the run
+    pub fn problems(&self, read_structure: &ReadStructure) -> Vec<String> {
+        let run_reads: Vec<_> = read_structure
+            .segments
+            .iter()
+            .map(|s| (s.name(), s.num_cycles()))
+            .collect();
+        let run_names: Vec<_> = run_reads.iter().map(|(name, _)| name.as_str()).collect();
+
+        let mut problems = Vec::new();
+
+        for (name, cycles) in &self.reads {
+            match run_reads.iter().find(|(run_name, _)| run_name == name) {
+                Some((_, run_cycles)) if run_cycles != cycles => problems.push(format!(
+                    "the samplesheet's [Reads] section has {} cycles for {}, but RunInfo.xml has {}",
+                    cycles, name, run_cycles
+                )),
+                Some(_) => (),
+                None => problems.push(format!(
+                    "the samplesheet's [Reads] section has {}, but RunInfo.xml only has {}",
+                    name,
+                    run_names.join(", ")
+                )),
+            }
+        }
+
+        // a [Reads] section lists every template read, and every index read if it
+        // lists any
+        for (run_name, _) in &run_reads {
+            let kind = &run_name[..1];
+            if self.reads.iter().any(|(name, _)| name.starts_with(kind))
+                && !self.reads.iter().any(|(name, _)| name == run_name)
+            {
+                problems.push(format!(
+                    "RunInfo.xml has {}, but the samplesheet's [Reads] section doesn't",
+                    run_name
+                ));
+            }
+        }
+
+        if let Some(override_cycles) = &self.override_cycles {
+            let override_reads: Vec<_> = override_cycles.split(';').collect();
+            if override_reads.len() != run_reads.len() {
+                problems.push(format!(
+                    "OverrideCycles {} has {} reads, but RunInfo.xml has {} ({})",
+                    override_cycles,
+                    override_reads.len(),
+                    run_reads.len(),
+                    run_names.join(", ")
+                ));
+            } else {
+                for (read, (run_name, run_cycles)) in override_reads.iter().zip(&run_reads) {
+                    match override_read_cycles(read) {
+                        Ok(cycles) if cycles != *run_cycles => problems.push(format!(
+                            "OverrideCycles has {} cycles ({}) for {}, but RunInfo.xml has {}",
+                            cycles, read, run_name, run_cycles
+                        )),
+                        Ok(_) => (),
+                        Err(e) => problems.push(e),
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_info_parser::parse_run_info;
+
+    /// Y4;I8;I8;Y4
+    fn read_structure() -> ReadStructure {
+        let run_info = parse_run_info(Path::new(
+            "test_data/190414_A00111_0296_AHJCWWDSXX/RunInfo.xml",
+        ))
+        .unwrap();
+        ReadStructure::new(&run_info.reads)
+    }
+
+    #[test]
+    fn override_read_cycles() {
+        assert_eq!(super::override_read_cycles("Y151"), Ok(151));
+        assert_eq!(super::override_read_cycles("I8N2"), Ok(10));
+        assert_eq!(super::override_read_cycles("N1U8Y142"), Ok(151));
+        assert!(super::override_read_cycles("X8").is_err());
+        assert!(super::override_read_cycles("Y").is_err());
+    }
+
+    #[test]
+    fn read_sections() {
+        let path = Path::new("test_data/test_output/sheet_reads");
+        std::fs::create_dir_all(path).unwrap();
+
+        let v1 = path.join("v1.csv");
+        std::fs::write(
+            &v1,
+            "[Header]\nDate,2020\n[Reads]\n4\n4\n\n[Data]\nSample_Name,Index\n",
+        )
+        .unwrap();
+        let sheet_reads = read_sheet_reads(&v1).unwrap();
+        assert_eq!(
+            sheet_reads.reads,
+            [("R1".to_string(), 4), ("R2".to_string(), 4)]
+        );
+        assert!(sheet_reads.problems(&read_structure()).is_empty());
+
+        let v2 = path.join("v2.csv");
+        std::fs::write(
+            &v2,
+            "[Reads]\nRead1Cycles,4\nRead2Cycles,4\nIndex1Cycles,8\nIndex2Cycles,8\n\
+             [BCLConvert_Settings]\nOverrideCycles,Y4;I8;I6N2;Y4\n",
+        )
+        .unwrap();
+        let sheet_reads = read_sheet_reads(&v2).unwrap();
+        assert_eq!(sheet_reads.reads.len(), 4);
+        assert_eq!(
+            sheet_reads.override_cycles.as_deref(),
+            Some("Y4;I8;I6N2;Y4")
+        );
+        assert!(sheet_reads.problems(&read_structure()).is_empty());
+    }
+
+    #[test]
+    fn problems() {
+        let sheet_reads = SheetReads {
+            reads: vec![("R1".to_string(), 151), ("I1".to_string(), 8)],
+            override_cycles: Some("Y4;I10;Y4".to_string()),
+        };
+
+        assert_eq!(
+            sheet_reads.problems(&read_structure()),
+            [
+                "the samplesheet's [Reads] section has 151 cycles for R1, but RunInfo.xml has 4",
+                "RunInfo.xml has I2, but the samplesheet's [Reads] section doesn't",
+                "RunInfo.xml has R2, but the samplesheet's [Reads] section doesn't",
+                "OverrideCycles Y4;I10;Y4 has 3 reads, but RunInfo.xml has 4 (R1, I1, I2, R2)",
+            ]
+        );
+
+        let sheet_reads = SheetReads {
+            reads: vec![("R3".to_string(), 4)],
+            override_cycles: Some("Y4;I10;I8;Y4".to_string()),
+        };
+        assert_eq!(
+            sheet_reads.problems(&read_structure()),
+            [
+                "the samplesheet's [Reads] section has R3, but RunInfo.xml only has R1, I1, I2, R2",
+                "RunInfo.xml has R1, but the samplesheet's [Reads] section doesn't",
+                "RunInfo.xml has R2, but the samplesheet's [Reads] section doesn't",
+                "OverrideCycles has 10 cycles (I10) for I1, but RunInfo.xml has 8",
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Stops a demux cleanly when the process gets SIGINT or SIGTERM (e.g. when a
+//! scheduler preempts the job). The current chunk of tiles is finished so that no
+//! output file is left with a truncated gzip member, and a manifest of the tiles
+//! that were and weren't demultiplexed is written so the run can be picked up again
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::novaseq_run::NovaSeqRun;
+use crate::run_summary::RunStatus;
+use crate::stats::LaneStats;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Handle SIGINT and SIGTERM by asking the demux to stop after the current chunk
+/// of tiles. A second signal exits immediately
+pub fn install_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if SHUTDOWN.swap(true, Ordering::SeqCst) {
+            process::exit(RunStatus::Interrupted.exit_code());
+        }
+        warn!("got a signal to stop, finishing the current tiles");
+    })
+}
+
+/// True if a signal has asked us to stop
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Tiles in one lane and surface
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LaneTiles {
+    pub lane: usize,
+    pub surface: usize,
+    pub tiles: Vec<u32>,
+}
+
+/// Which tiles of a run were demultiplexed before it was stopped, and which are
+/// left to do
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResumeManifest {
+    pub run_id: String,
+    pub completed: Vec<LaneTiles>,
+    pub remaining: Vec<LaneTiles>,
+}
+
+impl ResumeManifest {
+    /// Compare the tiles in the run with the tiles that have stats
+    pub fn new(novaseq_run: &NovaSeqRun, lane_stats: &[LaneStats]) -> ResumeManifest {
+        let done: BTreeMap<_, _> = lane_stats
+            .iter()
+            .map(|ls| (ls.lane_number, &ls.tile_stats))
+            .collect();
+
+        let mut keys: Vec<_> = novaseq_run.tile_ids.keys().cloned().collect();
+        keys.sort_unstable();
+
+        let mut completed = Vec::new();
+        let mut remaining = Vec::new();
+
+        for [lane, surface] in keys {
+            let (done_tiles, todo_tiles): (Vec<u32>, Vec<u32>) = novaseq_run.tile_ids
+                [&[lane, surface]]
+                .iter()
+                .partition(|tile| done.get(&lane).is_some_and(|ts| ts.contains_key(tile)));
+
+            for (tiles, lane_tiles) in [(done_tiles, &mut completed), (todo_tiles, &mut remaining)]
+            {
+                if !tiles.is_empty() {
+                    lane_tiles.push(LaneTiles {
+                        lane,
+                        surface,
+                        tiles,
+                    });
+                }
+            }
+        }
+
+        ResumeManifest {
+            run_id: novaseq_run.run_info.id.clone(),
+            completed,
+            remaining,
+        }
+    }
+
+    /// Write the manifest to `resume.json` in the output directory
+    pub fn write(&self, output_path: &Path) -> std::io::Result<()> {
+        let out_file = BufWriter::new(File::create(output_path.join("resume.json"))?);
+        serde_json::to_writer_pretty(out_file, self)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::sample_data::read_samplesheet;
+
+    #[test]
+    fn resume_manifest() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), true).unwrap();
+        let sample_data = read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+
+        let mut lane_stats = LaneStats::new(1, &sample_data[&1], 2);
+        lane_stats.tile_stats.insert(1101, Default::default());
+
+        let manifest = ResumeManifest::new(&novaseq_run, &[lane_stats]);
+
+        assert_eq!(
+            manifest.completed,
+            vec![LaneTiles {
+                lane: 1,
+                surface: 1,
+                tiles: vec![1101]
+            }]
+        );
+        assert_eq!(manifest.remaining[0].tiles, vec![1102, 1103]);
+        assert!(!shutdown_requested());
+    }
+}
@@ -0,0 +1,1133 @@
+//! Collects demultiplexing statistics while writing fastqs, and writes them out
+//! in the same layout as the `Stats/Stats.json` file from bcl2fastq, so that
+//! downstream QC tools can parse it.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs::{create_dir_all, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use counter::Counter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::novaseq_run::{NovaSeqRun, Shard};
+use crate::overlap::PairOverlap;
+use crate::sample_data::Samples;
+use crate::screen::ScreenCounts;
+use crate::umi::UmiCounts;
+
+/// The number of unknown barcodes to list in the reports, per lane
+pub const TOP_UNKNOWN_BARCODES: usize = 1000;
+
+/// Pools with a coefficient of variation of reads per sample above this are flagged
+pub const MAX_BARCODE_CV: f64 = 0.3;
+
+/// Index cycles where one base is more than this fraction of calls are flagged
+pub const MAX_INDEX_BASE_FRACTION: f64 = 0.75;
+
+/// Yield and quality metrics for one read (e.g. R1 or R2) of a sample
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ReadMetrics {
+    /// which (non-index) read these metrics are for, starting at 1
+    pub read_number: usize,
+    /// number of bases
+    #[serde(rename = "Yield")]
+    pub yield_bases: u64,
+    /// number of bases with a quality score of at least 30
+    #[serde(rename = "YieldQ30")]
+    pub yield_q30: u64,
+    /// the sum of all quality scores
+    pub quality_score_sum: u64,
+    /// bases removed by trimming
+    pub trimmed_bases: u64,
+}
+
+impl ReadMetrics {
+    /// Create empty metrics for the given read number
+    pub fn new(read_number: usize) -> ReadMetrics {
+        ReadMetrics {
+            read_number,
+            ..Default::default()
+        }
+    }
+
+    /// Add the quality scores (as Phred+33 ASCII) of a single read
+    pub fn add_read(&mut self, qscores: &[u8]) {
+        self.yield_bases += qscores.len() as u64;
+        for &q in qscores {
+            let q = q.saturating_sub(33) as u64;
+            self.quality_score_sum += q;
+            if q >= 30 {
+                self.yield_q30 += 1;
+            }
+        }
+    }
+
+    /// Combine metrics for the same read
+    pub fn merge(&mut self, other: &ReadMetrics) {
+        self.yield_bases += other.yield_bases;
+        self.yield_q30 += other.yield_q30;
+        self.quality_score_sum += other.quality_score_sum;
+        self.trimmed_bases += other.trimmed_bases;
+    }
+}
+
+/// The bases that are counted separately in the per-cycle metrics
+pub const CYCLE_BASES: [u8; 5] = *b"ACGTN";
+
+/// Base composition and quality metrics for a single cycle of a read, across
+/// all of the pass-filter clusters in a lane
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CycleMetrics {
+    /// number of each base, in the order of `CYCLE_BASES`
+    pub base_counts: [u64; 5],
+    /// the sum of all quality scores
+    pub quality_score_sum: u64,
+    /// number of bases with a quality score of at least 30
+    pub q30_count: u64,
+}
+
+impl CycleMetrics {
+    /// Add one base call and its quality score (as Phred+33 ASCII)
+    pub fn add_base(&mut self, base: u8, qscore: u8) {
+        let base_i = CYCLE_BASES.iter().position(|&b| b == base).unwrap_or(4);
+        self.base_counts[base_i] += 1;
+
+        let q = qscore.saturating_sub(33) as u64;
+        self.quality_score_sum += q;
+        if q >= 30 {
+            self.q30_count += 1;
+        }
+    }
+
+    /// The total number of bases counted for this cycle
+    pub fn n_bases(&self) -> u64 {
+        self.base_counts.iter().sum()
+    }
+
+    /// The fraction of calls made up by the most common base, or 0 if empty
+    pub fn max_base_fraction(&self) -> f64 {
+        let n_bases = self.n_bases();
+        if n_bases == 0 {
+            0.
+        } else {
+            *self.base_counts.iter().max().unwrap() as f64 / n_bases as f64
+        }
+    }
+
+    /// Combine metrics for the same cycle
+    pub fn merge(&mut self, other: &CycleMetrics) {
+        for (n, m) in self.base_counts.iter_mut().zip(&other.base_counts) {
+            *n += m;
+        }
+        self.quality_score_sum += other.quality_score_sum;
+        self.q30_count += other.q30_count;
+    }
+}
+
+/// Pass-filter, assignment and quality metrics for a single tile
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TileStats {
+    /// total number of clusters on the tile
+    pub clusters_raw: u64,
+    /// number of clusters passing filter
+    pub clusters_pf: u64,
+    /// number of pass-filter clusters assigned to a sample
+    pub clusters_assigned: u64,
+    /// the sum of all quality scores
+    pub quality_score_sum: u64,
+    /// number of bases
+    pub n_bases: u64,
+}
+
+impl TileStats {
+    /// Combine metrics for multiple tiles
+    pub fn merge(&mut self, other: &TileStats) {
+        self.clusters_raw += other.clusters_raw;
+        self.clusters_pf += other.clusters_pf;
+        self.clusters_assigned += other.clusters_assigned;
+        self.quality_score_sum += other.quality_score_sum;
+        self.n_bases += other.n_bases;
+    }
+}
+
+/// Counts of reads assigned to a sample index with a given number of mismatches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct IndexMetrics {
+    /// the index sequence(s) for this sample, joined by '+'
+    pub index_sequence: String,
+    /// map from number of mismatches to number of reads
+    pub mismatch_counts: BTreeMap<String, u64>,
+}
+
+/// All the statistics for one sample in one lane
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SampleStats {
+    pub sample_id: String,
+    pub sample_name: String,
+    /// the sample's S number, as in its file names with `--sample-numbers`. Not in
+    /// bcl2fastq's Stats.json
+    #[serde(default)]
+    pub sample_number: usize,
+    #[serde(skip)]
+    pub sample_project: Option<String>,
+    /// the reference genome from the samplesheet, for pipelines that route samples
+    /// by it. Not in bcl2fastq's Stats.json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genome: Option<String>,
+    pub index_metrics: Vec<IndexMetrics>,
+    /// for each index, the number of reads with 0, 1, 2... mismatches in that index
+    /// alone. Not in bcl2fastq's Stats.json
+    #[serde(default)]
+    pub index_mismatch_histogram: Vec<Vec<u64>>,
+    pub number_reads: u64,
+    /// the reads that failed the read filter, out of `number_reads`. Their yield
+    /// isn't counted. Not in bcl2fastq's Stats.json
+    #[serde(default)]
+    pub number_reads_filtered: u64,
+    /// the reads whose first written read starts with an adapter, out of
+    /// `number_reads`, if adapter dimers were looked for. Not in bcl2fastq's
+    /// Stats.json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number_reads_adapter_dimer: Option<u64>,
+    #[serde(rename = "Yield")]
+    pub yield_bases: u64,
+    pub read_metrics: Vec<ReadMetrics>,
+}
+
+/// Statistics for the reads that did not match any sample
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UndeterminedStats {
+    pub number_reads: u64,
+    #[serde(rename = "Yield")]
+    pub yield_bases: u64,
+    pub read_metrics: Vec<ReadMetrics>,
+}
+
+impl UndeterminedStats {
+    fn is_empty(&self) -> bool {
+        self.number_reads == 0
+    }
+}
+
+/// The demultiplexing results for a single lane of the flowcell
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LaneStats {
+    pub lane_number: usize,
+    pub total_clusters_raw: u64,
+    #[serde(rename = "TotalClustersPF")]
+    pub total_clusters_pf: u64,
+    #[serde(rename = "Yield")]
+    pub yield_bases: u64,
+    pub demux_results: Vec<SampleStats>,
+    pub undetermined: UndeterminedStats,
+    /// the reads that matched no sample but look like the PhiX spike-in, if they
+    /// are told apart from Undetermined. Not in bcl2fastq's Stats.json
+    #[serde(
+        rename = "PhiX",
+        default,
+        skip_serializing_if = "UndeterminedStats::is_empty"
+    )]
+    pub phix: UndeterminedStats,
+    /// counts of the index sequences of undetermined reads
+    #[serde(skip)]
+    pub unknown_barcodes: Counter<Vec<u8>, u64>,
+    /// per-cycle metrics for each (non-index) read
+    #[serde(skip)]
+    pub cycle_metrics: Vec<Vec<CycleMetrics>>,
+    /// counts of undetermined reads whose first index matches one sample and whose
+    /// second index matches another, keyed by the pair of samples
+    #[serde(skip)]
+    pub index_hopping: Counter<(usize, usize), u64>,
+    /// QC metrics for each tile in the lane
+    #[serde(skip)]
+    pub tile_stats: BTreeMap<u32, TileStats>,
+    /// number of (tile, cycle) blocks that couldn't be read and were filled with N
+    #[serde(skip)]
+    pub failed_tile_cycles: u64,
+    /// per-cycle metrics for each index read
+    #[serde(skip)]
+    pub index_cycle_metrics: Vec<Vec<CycleMetrics>>,
+    /// what the screened undetermined reads look like, if they were screened.
+    /// This and the counts below aren't in bcl2fastq's Stats.json, but are kept in
+    /// it so that merge-stats can add them up
+    #[serde(default, skip_serializing_if = "ScreenCounts::is_empty")]
+    pub screen: ScreenCounts,
+    /// the assigned clusters whose reads overlap, if they were overlapped
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub overlapping_pairs: u64,
+    /// the bases of either read that were corrected from the other
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub corrected_bases: u64,
+    /// the overlapping pairs whose insert is shorter than one of the reads
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub read_through_pairs: u64,
+    /// the UMIs of the assigned clusters, if the run has them
+    #[serde(default, rename = "UMI", skip_serializing_if = "UmiCounts::is_empty")]
+    pub umi: UmiCounts,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl LaneStats {
+    /// Create empty stats for a lane, with an entry for every sample
+    pub fn new(lane_number: usize, samples: &Samples, num_reads: usize) -> LaneStats {
+        let read_metrics: Vec<_> = (1..=num_reads).map(ReadMetrics::new).collect();
+
+        let demux_results = samples
+            .sample_names
+            .iter()
+            .enumerate()
+            .map(|(i, sample_name)| SampleStats {
+                sample_id: sample_name.clone(),
+                sample_name: sample_name.clone(),
+                sample_number: samples.sample_numbers[i],
+                sample_project: samples.project_names[i].clone(),
+                genome: samples.genomes[i].clone(),
+                index_metrics: vec![IndexMetrics {
+                    index_sequence: samples.index_string(i),
+                    mismatch_counts: [("0".to_string(), 0), ("1".to_string(), 0)]
+                        .iter()
+                        .cloned()
+                        .collect(),
+                }],
+                index_mismatch_histogram: vec![vec![0; 2]; samples.n_indices()],
+                number_reads: 0,
+                number_reads_filtered: 0,
+                number_reads_adapter_dimer: None,
+                yield_bases: 0,
+                read_metrics: read_metrics.clone(),
+            })
+            .collect();
+
+        LaneStats {
+            lane_number,
+            total_clusters_raw: 0,
+            total_clusters_pf: 0,
+            yield_bases: 0,
+            demux_results,
+            undetermined: UndeterminedStats {
+                number_reads: 0,
+                yield_bases: 0,
+                read_metrics: read_metrics.clone(),
+            },
+            phix: UndeterminedStats {
+                number_reads: 0,
+                yield_bases: 0,
+                read_metrics,
+            },
+            unknown_barcodes: Counter::new(),
+            cycle_metrics: vec![Vec::new(); num_reads],
+            index_hopping: Counter::new(),
+            tile_stats: BTreeMap::new(),
+            failed_tile_cycles: 0,
+            index_cycle_metrics: Vec::new(),
+            screen: ScreenCounts::default(),
+            overlapping_pairs: 0,
+            corrected_bases: 0,
+            read_through_pairs: 0,
+            umi: UmiCounts::default(),
+        }
+    }
+
+    /// Count a read for a sample, with the number of mismatches in its index
+    pub fn add_read(&mut self, sample_i: usize, mismatches: usize) {
+        let sample_stats = &mut self.demux_results[sample_i];
+        sample_stats.number_reads += 1;
+        *sample_stats.index_metrics[0]
+            .mismatch_counts
+            .entry(mismatches.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Count the mismatches in each of the indices of a read assigned to a sample
+    pub fn add_index_mismatches(&mut self, sample_i: usize, index_mismatches: &[usize]) {
+        let histogram = &mut self.demux_results[sample_i].index_mismatch_histogram;
+        if histogram.len() < index_mismatches.len() {
+            histogram.resize(index_mismatches.len(), Vec::new());
+        }
+
+        for (counts, &mismatches) in histogram.iter_mut().zip(index_mismatches) {
+            if counts.len() <= mismatches {
+                counts.resize(mismatches + 1, 0);
+            }
+            counts[mismatches] += 1;
+        }
+    }
+
+    /// Count a read for a sample that failed the read filter
+    pub fn add_filtered_read(&mut self, sample_i: usize) {
+        self.demux_results[sample_i].number_reads_filtered += 1;
+    }
+
+    /// Start counting the adapter dimers of every sample, if they aren't already
+    pub fn count_adapter_dimers(&mut self) {
+        for sample_stats in self.demux_results.iter_mut() {
+            sample_stats.number_reads_adapter_dimer.get_or_insert(0);
+        }
+    }
+
+    /// Count a read for a sample that is an adapter dimer
+    pub fn add_adapter_dimer(&mut self, sample_i: usize) {
+        *self.demux_results[sample_i]
+            .number_reads_adapter_dimer
+            .get_or_insert(0) += 1;
+    }
+
+    /// Count the overlap of a cluster's reads, if they overlap, and whether they
+    /// read through an insert shorter than `read_len`, the longer of the two
+    pub fn add_pair_overlap(&mut self, overlap: &PairOverlap, read_len: usize) {
+        if let Some(insert_len) = overlap.insert_len {
+            self.overlapping_pairs += 1;
+            self.read_through_pairs += (insert_len < read_len) as u64;
+            self.corrected_bases += overlap
+                .corrections
+                .iter()
+                .map(|corrections| corrections.len() as u64)
+                .sum::<u64>();
+        }
+    }
+
+    /// The number of reads in the lane that failed the read filter
+    pub fn filtered_reads(&self) -> u64 {
+        self.demux_results
+            .iter()
+            .map(|s| s.number_reads_filtered)
+            .sum()
+    }
+
+    /// Count a read that didn't match any sample, along with its index sequence
+    pub fn add_undetermined_read(&mut self, barcode: &[u8]) {
+        self.undetermined.number_reads += 1;
+        *self.unknown_barcodes.entry(barcode.to_vec()).or_insert(0) += 1;
+    }
+
+    /// Count a read that didn't match any sample but looks like PhiX
+    pub fn add_phix_read(&mut self) {
+        self.phix.number_reads += 1;
+    }
+
+    /// Count an undetermined read that has the first index of one sample and the
+    /// second index of another
+    pub fn add_hopped_read(&mut self, sample_i: usize, sample2_i: usize) {
+        *self.index_hopping.entry((sample_i, sample2_i)).or_insert(0) += 1;
+    }
+
+    /// The number of reads with an unexpected combination of sample indices
+    pub fn hopped_reads(&self) -> u64 {
+        self.index_hopping.values().sum()
+    }
+
+    /// Estimate the index hopping rate as the fraction of reads with valid indices
+    /// that have an unexpected combination of them
+    pub fn hopping_rate(&self) -> f64 {
+        let hopped = self.hopped_reads();
+        let total =
+            hopped + self.number_reads() - self.undetermined.number_reads - self.phix.number_reads;
+
+        if total == 0 {
+            0.
+        } else {
+            hopped as f64 / total as f64
+        }
+    }
+
+    /// The total number of reads in the lane, including undetermined and PhiX
+    pub fn number_reads(&self) -> u64 {
+        self.undetermined.number_reads
+            + self.phix.number_reads
+            + self
+                .demux_results
+                .iter()
+                .map(|s| s.number_reads)
+                .sum::<u64>()
+    }
+
+    /// The number of reads in the lane that were assigned to a sample
+    pub fn assigned_reads(&self) -> u64 {
+        self.demux_results.iter().map(|s| s.number_reads).sum()
+    }
+
+    /// The most common unknown barcodes with their counts, in descending order
+    pub fn top_unknown_barcodes(&self, top_n: usize) -> Vec<(String, u64)> {
+        self.unknown_barcodes
+            .most_common_ordered()
+            .into_iter()
+            .take(top_n)
+            .map(|(barcode, count)| (String::from_utf8_lossy(&barcode).to_string(), count))
+            .collect()
+    }
+
+    /// Add yield and quality metrics for a sample (or Undetermined)
+    pub fn add_read_metrics(&mut self, sample_i: Option<usize>, metrics: &ReadMetrics) {
+        let (yield_bases, read_metrics) = match sample_i {
+            Some(i) => {
+                let sample_stats = &mut self.demux_results[i];
+                (
+                    &mut sample_stats.yield_bases,
+                    &mut sample_stats.read_metrics,
+                )
+            }
+            None => (
+                &mut self.undetermined.yield_bases,
+                &mut self.undetermined.read_metrics,
+            ),
+        };
+
+        *yield_bases += metrics.yield_bases;
+        read_metrics[metrics.read_number - 1].merge(metrics);
+        self.yield_bases += metrics.yield_bases;
+    }
+
+    /// Add yield and quality metrics for the PhiX reads
+    pub fn add_phix_read_metrics(&mut self, metrics: &ReadMetrics) {
+        self.phix.yield_bases += metrics.yield_bases;
+        self.phix.read_metrics[metrics.read_number - 1].merge(metrics);
+        self.yield_bases += metrics.yield_bases;
+    }
+
+    /// Add per-cycle metrics for one of the reads in this lane
+    pub fn add_cycle_metrics(&mut self, read_number: usize, metrics: &[CycleMetrics]) {
+        merge_cycles(&mut self.cycle_metrics[read_number - 1], metrics);
+    }
+
+    /// Add per-cycle metrics for one of the index reads in this lane
+    pub fn add_index_cycle_metrics(&mut self, index_number: usize, metrics: &[CycleMetrics]) {
+        if self.index_cycle_metrics.len() < index_number {
+            self.index_cycle_metrics.resize(index_number, Vec::new());
+        }
+        merge_cycles(&mut self.index_cycle_metrics[index_number - 1], metrics);
+    }
+
+    /// The coefficient of variation (standard deviation / mean) of the number of
+    /// reads per sample, which measures how well balanced the pool was
+    pub fn read_count_cv(&self) -> f64 {
+        let counts: Vec<_> = self
+            .demux_results
+            .iter()
+            .map(|s| s.number_reads as f64)
+            .collect();
+
+        if counts.is_empty() {
+            return 0.;
+        }
+
+        let mean = counts.iter().sum::<f64>() / counts.len() as f64;
+        if mean == 0. {
+            return 0.;
+        }
+
+        let variance = counts.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / counts.len() as f64;
+
+        variance.sqrt() / mean
+    }
+
+    /// Combine the stats for the same lane from another part of the run. Both must
+    /// have the same samples in the same order
+    pub fn merge(&mut self, other: &LaneStats) -> Result<(), String> {
+        if self.lane_number != other.lane_number
+            || self.demux_results.len() != other.demux_results.len()
+            || self
+                .demux_results
+                .iter()
+                .zip(&other.demux_results)
+                .any(|(s, o)| s.sample_id != o.sample_id)
+        {
+            return Err(format!(
+                "can't merge stats with different samples for lane {}",
+                self.lane_number
+            ));
+        }
+
+        self.total_clusters_raw += other.total_clusters_raw;
+        self.total_clusters_pf += other.total_clusters_pf;
+        self.yield_bases += other.yield_bases;
+
+        for (sample_stats, other_stats) in self.demux_results.iter_mut().zip(&other.demux_results) {
+            sample_stats.number_reads += other_stats.number_reads;
+            sample_stats.number_reads_filtered += other_stats.number_reads_filtered;
+            if let Some(dimers) = other_stats.number_reads_adapter_dimer {
+                *sample_stats.number_reads_adapter_dimer.get_or_insert(0) += dimers;
+            }
+            sample_stats.yield_bases += other_stats.yield_bases;
+            merge_read_metrics(&mut sample_stats.read_metrics, &other_stats.read_metrics);
+            merge_histogram(
+                &mut sample_stats.index_mismatch_histogram,
+                &other_stats.index_mismatch_histogram,
+            );
+
+            for (index_metrics, other_metrics) in sample_stats
+                .index_metrics
+                .iter_mut()
+                .zip(&other_stats.index_metrics)
+            {
+                for (mismatches, n) in other_metrics.mismatch_counts.iter() {
+                    *index_metrics
+                        .mismatch_counts
+                        .entry(mismatches.clone())
+                        .or_insert(0) += n;
+                }
+            }
+        }
+
+        self.undetermined.number_reads += other.undetermined.number_reads;
+        self.undetermined.yield_bases += other.undetermined.yield_bases;
+        merge_read_metrics(
+            &mut self.undetermined.read_metrics,
+            &other.undetermined.read_metrics,
+        );
+
+        // a Stats.json without PhiX reads has no metrics for them
+        if self.phix.read_metrics.len() < other.phix.read_metrics.len() {
+            self.phix.read_metrics = (1..=other.phix.read_metrics.len())
+                .map(ReadMetrics::new)
+                .collect();
+        }
+        self.phix.number_reads += other.phix.number_reads;
+        self.phix.yield_bases += other.phix.yield_bases;
+        merge_read_metrics(&mut self.phix.read_metrics, &other.phix.read_metrics);
+
+        for (barcode, n) in other.unknown_barcodes.iter() {
+            *self.unknown_barcodes.entry(barcode.clone()).or_insert(0) += n;
+        }
+        for (pair, n) in other.index_hopping.iter() {
+            *self.index_hopping.entry(*pair).or_insert(0) += n;
+        }
+
+        if self.cycle_metrics.len() < other.cycle_metrics.len() {
+            self.cycle_metrics
+                .resize(other.cycle_metrics.len(), Vec::new());
+        }
+        for (read_i, metrics) in other.cycle_metrics.iter().enumerate() {
+            self.add_cycle_metrics(read_i + 1, metrics);
+        }
+        for (index_i, metrics) in other.index_cycle_metrics.iter().enumerate() {
+            self.add_index_cycle_metrics(index_i + 1, metrics);
+        }
+        for (tile, ts) in other.tile_stats.iter() {
+            self.tile_stats.entry(*tile).or_default().merge(ts);
+        }
+        self.failed_tile_cycles += other.failed_tile_cycles;
+        self.screen.merge(&other.screen);
+        self.overlapping_pairs += other.overlapping_pairs;
+        self.corrected_bases += other.corrected_bases;
+        self.read_through_pairs += other.read_through_pairs;
+        self.umi.merge(&other.umi);
+
+        Ok(())
+    }
+}
+
+/// Read counts and yield for the samples of one `Sample_Project`, across every lane
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProjectStats {
+    pub project: Option<String>,
+    pub samples: usize,
+    /// the reads that were written, so not the ones that failed the read filter
+    pub reads: u64,
+    pub yield_bases: u64,
+    pub yield_q30: u64,
+}
+
+impl ProjectStats {
+    /// The fraction of the yield with a quality score of at least 30
+    pub fn q30_fraction(&self) -> f64 {
+        self.yield_q30 as f64 / self.yield_bases.max(1) as f64
+    }
+}
+
+/// The stats of each project, in name order, with the samples that have no
+/// project first
+pub fn project_stats(lane_stats: &[LaneStats]) -> Vec<ProjectStats> {
+    let mut projects: BTreeMap<Option<String>, (ProjectStats, BTreeSet<&str>)> = BTreeMap::new();
+
+    for sample_stats in lane_stats.iter().flat_map(|ls| ls.demux_results.iter()) {
+        let (project_stats, sample_ids) = projects
+            .entry(sample_stats.sample_project.clone())
+            .or_insert_with(|| {
+                (
+                    ProjectStats {
+                        project: sample_stats.sample_project.clone(),
+                        ..Default::default()
+                    },
+                    BTreeSet::new(),
+                )
+            });
+
+        sample_ids.insert(&sample_stats.sample_id);
+        project_stats.reads += sample_stats.number_reads - sample_stats.number_reads_filtered;
+        project_stats.yield_bases += sample_stats.yield_bases;
+        project_stats.yield_q30 += sample_stats
+            .read_metrics
+            .iter()
+            .map(|rm| rm.yield_q30)
+            .sum::<u64>();
+    }
+
+    projects
+        .into_values()
+        .map(|(mut project_stats, sample_ids)| {
+            project_stats.samples = sample_ids.len();
+            project_stats
+        })
+        .collect()
+}
+
+/// add the metrics for each read to a running total
+fn merge_read_metrics(read_metrics: &mut [ReadMetrics], other: &[ReadMetrics]) {
+    for (metrics, other_metrics) in read_metrics.iter_mut().zip(other) {
+        metrics.merge(other_metrics);
+    }
+}
+
+/// add the mismatch counts for each index to a running total, extending it if needed
+fn merge_histogram(histogram: &mut Vec<Vec<u64>>, other: &[Vec<u64>]) {
+    if histogram.len() < other.len() {
+        histogram.resize(other.len(), Vec::new());
+    }
+
+    for (counts, other_counts) in histogram.iter_mut().zip(other) {
+        if counts.len() < other_counts.len() {
+            counts.resize(other_counts.len(), 0);
+        }
+        for (n, m) in counts.iter_mut().zip(other_counts) {
+            *n += m;
+        }
+    }
+}
+
+/// add per-cycle metrics to a running total, extending it if needed
+fn merge_cycles(read_cycles: &mut Vec<CycleMetrics>, metrics: &[CycleMetrics]) {
+    if read_cycles.len() < metrics.len() {
+        read_cycles.resize(metrics.len(), CycleMetrics::default());
+    }
+
+    for (cycle, m) in read_cycles.iter_mut().zip(metrics) {
+        cycle.merge(m);
+    }
+}
+
+/// Description of one of the reads in the run, as listed in Stats.json
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ReadInfo {
+    number: usize,
+    num_cycles: usize,
+    is_indexed_read: bool,
+}
+
+/// The read structure for a lane
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ReadInfosForLane {
+    lane_number: usize,
+    read_infos: Vec<ReadInfo>,
+}
+
+/// The most common unknown barcodes for a lane
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UnknownBarcodes {
+    lane: usize,
+    #[serde(serialize_with = "ordered_map", deserialize_with = "map_pairs")]
+    barcodes: Vec<(String, u64)>,
+}
+
+/// Serialize a vector of pairs as a map, keeping the order of the vector
+fn ordered_map<S>(pairs: &[(String, u64)], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(pairs.iter().map(|(k, v)| (k, v)))
+}
+
+/// Read a map back into a vector of pairs. The original order is lost, but the
+/// counts are sorted again before they are written
+fn map_pairs<'de, D>(deserializer: D) -> Result<Vec<(String, u64)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map = BTreeMap::<String, u64>::deserialize(deserializer)?;
+    Ok(map.into_iter().collect())
+}
+
+/// The top level of Stats.json
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Stats<'a> {
+    flowcell: Cow<'a, str>,
+    run_number: u64,
+    run_id: Cow<'a, str>,
+    /// the instrument and reagents from RunParameters.xml, if the run has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chemistry: Option<Cow<'a, str>>,
+    read_infos_for_lanes: Vec<ReadInfosForLane>,
+    conversion_results: Cow<'a, [LaneStats]>,
+    unknown_barcodes: Vec<UnknownBarcodes>,
+}
+
+impl<'a> Stats<'a> {
+    /// Write the stats as `file_name` in the `Stats` directory of the output
+    fn write(&self, output_path: &Path, file_name: &str) -> std::io::Result<()> {
+        let stats_path = output_path.join("Stats");
+        create_dir_all(&stats_path)?;
+
+        let out_file = BufWriter::new(File::create(stats_path.join(file_name))?);
+        serde_json::to_writer_pretty(out_file, self)?;
+
+        Ok(())
+    }
+}
+
+/// Write `Stats/Stats.json` into the output directory
+pub fn write_stats_json(
+    output_path: &Path,
+    novaseq_run: &NovaSeqRun,
+    lane_stats: &[LaneStats],
+) -> std::io::Result<()> {
+    run_stats(novaseq_run, lane_stats).write(output_path, "Stats.json")
+}
+
+/// Write the partial stats for one shard of a run, e.g. `Stats/Stats.shard1of4.json`,
+/// which can be combined with the others using `merge_stats_json`
+pub fn write_shard_stats_json(
+    output_path: &Path,
+    shard: &Shard,
+    novaseq_run: &NovaSeqRun,
+    lane_stats: &[LaneStats],
+) -> std::io::Result<()> {
+    run_stats(novaseq_run, lane_stats).write(output_path, &format!("Stats.{}.json", shard.suffix()))
+}
+
+/// Write the stats for one of several runs of the same libraries that were
+/// demultiplexed together, e.g. `Stats/Stats.190414_A00111_0296_AHJCWWDSXX.json`
+pub fn write_run_stats_json(
+    output_path: &Path,
+    novaseq_run: &NovaSeqRun,
+    lane_stats: &[LaneStats],
+) -> std::io::Result<()> {
+    run_stats(novaseq_run, lane_stats).write(
+        output_path,
+        &format!("Stats.{}.json", novaseq_run.run_info.id),
+    )
+}
+
+/// Write `Stats/Stats.json` for several runs of the same libraries, with the stats
+/// of each lane combined across the runs by `merge_lane_stats`. The run ID and
+/// flowcell are those of every run, joined with `+`
+pub fn write_combined_stats_json(
+    output_path: &Path,
+    novaseq_runs: &[NovaSeqRun],
+    lane_stats: &[LaneStats],
+) -> std::io::Result<()> {
+    let join = |field: fn(&NovaSeqRun) -> &str| {
+        novaseq_runs.iter().map(field).collect::<Vec<_>>().join("+")
+    };
+
+    let mut stats = run_stats(&novaseq_runs[0], lane_stats);
+    stats.run_id = Cow::Owned(join(|r| &r.run_info.id));
+    stats.flowcell = Cow::Owned(join(|r| &r.run_info.flowcell));
+    stats.write(output_path, "Stats.json")
+}
+
+/// Combine the stats of each lane across several runs (or parts of runs) that
+/// were demultiplexed with the same samples, in lane order
+pub fn merge_lane_stats(run_lane_stats: Vec<Vec<LaneStats>>) -> Result<Vec<LaneStats>, String> {
+    let mut lanes: BTreeMap<usize, LaneStats> = BTreeMap::new();
+    for ls in run_lane_stats.into_iter().flatten() {
+        match lanes.get_mut(&ls.lane_number) {
+            Some(lane_stats) => lane_stats.merge(&ls)?,
+            None => {
+                lanes.insert(ls.lane_number, ls);
+            }
+        }
+    }
+
+    Ok(lanes.into_values().collect())
+}
+
+/// Collect the stats for a run in the layout of Stats.json
+fn run_stats<'a>(novaseq_run: &'a NovaSeqRun, lane_stats: &'a [LaneStats]) -> Stats<'a> {
+    let run_info = &novaseq_run.run_info;
+
+    let read_infos: Vec<_> = novaseq_run
+        .read_structure
+        .segments
+        .iter()
+        .map(|s| ReadInfo {
+            number: s.number,
+            num_cycles: s.num_cycles(),
+            is_indexed_read: s.is_index(),
+        })
+        .collect();
+
+    Stats {
+        flowcell: Cow::Borrowed(&run_info.flowcell),
+        run_number: run_info.number,
+        run_id: Cow::Borrowed(&run_info.id),
+        chemistry: novaseq_run
+            .run_parameters
+            .as_ref()
+            .map(|rp| Cow::Owned(rp.chemistry())),
+        read_infos_for_lanes: lane_stats
+            .iter()
+            .map(|ls| ReadInfosForLane {
+                lane_number: ls.lane_number,
+                read_infos: read_infos.clone(),
+            })
+            .collect(),
+        conversion_results: Cow::Borrowed(lane_stats),
+        unknown_barcodes: lane_stats
+            .iter()
+            .map(|ls| UnknownBarcodes {
+                lane: ls.lane_number,
+                barcodes: ls.top_unknown_barcodes(TOP_UNKNOWN_BARCODES),
+            })
+            .collect(),
+    }
+}
+
+/// Read the stats for each lane from a Stats.json file
+pub fn read_stats_json(stats_path: &Path) -> std::io::Result<Vec<LaneStats>> {
+    let stats: Stats =
+        serde_json::from_reader(BufReader::new(File::open(stats_path)?)).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", stats_path.display(), e),
+            )
+        })?;
+
+    Ok(stats.conversion_results.into_owned())
+}
+
+/// Combine the Stats.json files written by each shard of a run into
+/// `Stats/Stats.json`, and return the combined stats for each lane. Only the
+/// top unknown barcodes from each shard are kept, so the merged counts for
+/// rarer barcodes may be too low
+pub fn merge_stats_json(
+    stats_paths: &[PathBuf],
+    output_path: &Path,
+) -> std::io::Result<Vec<LaneStats>> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    let mut merged: Option<Stats> = None;
+    let mut shard_lane_stats = Vec::new();
+
+    for stats_path in stats_paths {
+        let stats: Stats = serde_json::from_reader(BufReader::new(File::open(stats_path)?))
+            .map_err(|e| invalid(format!("{}: {}", stats_path.display(), e)))?;
+
+        let mut conversion_results = stats.conversion_results.to_vec();
+        for unknown in stats.unknown_barcodes.iter() {
+            if let Some(ls) = conversion_results
+                .iter_mut()
+                .find(|ls| ls.lane_number == unknown.lane)
+            {
+                for (barcode, count) in unknown.barcodes.iter() {
+                    *ls.unknown_barcodes
+                        .entry(barcode.as_bytes().to_vec())
+                        .or_insert(0) += count;
+                }
+            }
+        }
+
+        shard_lane_stats.push(conversion_results);
+
+        match &mut merged {
+            Some(merged) => {
+                if merged.run_id != stats.run_id {
+                    return Err(invalid(format!(
+                        "{} is from run {}, not {}",
+                        stats_path.display(),
+                        stats.run_id,
+                        merged.run_id
+                    )));
+                }
+                for read_infos in stats.read_infos_for_lanes {
+                    if !merged
+                        .read_infos_for_lanes
+                        .iter()
+                        .any(|ri| ri.lane_number == read_infos.lane_number)
+                    {
+                        merged.read_infos_for_lanes.push(read_infos);
+                    }
+                }
+            }
+            None => merged = Some(stats),
+        }
+    }
+
+    let mut merged = merged.ok_or_else(|| invalid("no stats to merge".to_string()))?;
+    let lane_stats = merge_lane_stats(shard_lane_stats).map_err(invalid)?;
+
+    merged.read_infos_for_lanes.sort_by_key(|ri| ri.lane_number);
+    merged.unknown_barcodes = lane_stats
+        .iter()
+        .map(|ls| UnknownBarcodes {
+            lane: ls.lane_number,
+            barcodes: ls.top_unknown_barcodes(TOP_UNKNOWN_BARCODES),
+        })
+        .collect();
+    merged.conversion_results = Cow::Owned(lane_stats);
+    merged.write(output_path, "Stats.json")?;
+
+    Ok(merged.conversion_results.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_metrics() {
+        let mut metrics = ReadMetrics::new(1);
+        metrics.add_read(b"F:,#");
+
+        assert_eq!(metrics.yield_bases, 4);
+        assert_eq!(metrics.yield_q30, 1);
+        assert_eq!(metrics.quality_score_sum, 37 + 25 + 11 + 2);
+
+        let mut other = ReadMetrics::new(1);
+        other.add_read(b"FF");
+        metrics.merge(&other);
+
+        assert_eq!(metrics.yield_bases, 6);
+        assert_eq!(metrics.yield_q30, 3);
+    }
+
+    #[test]
+    fn merge_lane_stats() {
+        let samples = crate::sample_data::read_samplesheet(
+            PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv"),
+            1,
+        )
+        .unwrap()
+        .remove(&1)
+        .unwrap();
+
+        let mut lane_stats = LaneStats::new(1, &samples, 2);
+        lane_stats.total_clusters_pf = 10;
+        lane_stats.add_read(0, 1);
+        lane_stats.add_index_mismatches(0, &[1, 0]);
+        lane_stats.add_undetermined_read(b"ACGT");
+
+        let other = lane_stats.clone();
+        lane_stats.merge(&other).unwrap();
+
+        assert_eq!(lane_stats.total_clusters_pf, 20);
+        assert_eq!(lane_stats.demux_results[0].number_reads, 2);
+        assert_eq!(
+            lane_stats.demux_results[0].index_metrics[0].mismatch_counts["1"],
+            2
+        );
+        assert_eq!(
+            lane_stats.demux_results[0].index_mismatch_histogram,
+            vec![vec![0, 2], vec![2, 0]]
+        );
+        assert_eq!(
+            lane_stats.demux_results[1].index_mismatch_histogram,
+            vec![vec![0, 0], vec![0, 0]]
+        );
+        assert_eq!(
+            lane_stats.top_unknown_barcodes(1),
+            vec![("ACGT".to_string(), 2)]
+        );
+
+        let mut other_lane = LaneStats::new(2, &samples, 2);
+        assert!(other_lane.merge(&lane_stats).is_err());
+    }
+
+    #[test]
+    fn counts_in_stats_json() {
+        let samples = crate::sample_data::read_samplesheet(
+            PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv"),
+            1,
+        )
+        .unwrap()
+        .remove(&1)
+        .unwrap();
+
+        // counts that weren't taken are left out, as bcl2fastq does
+        let mut lane_stats = LaneStats::new(1, &samples, 2);
+        let json = serde_json::to_string(&lane_stats).unwrap();
+        assert!(!json.contains("Screen") && !json.contains("UMI") && !json.contains("Overlapping"));
+
+        lane_stats.screen.screened = 5;
+        lane_stats.screen.phix = 2;
+        lane_stats.overlapping_pairs = 3;
+        lane_stats.umi.umis = 4;
+        let json = serde_json::to_string(&lane_stats).unwrap();
+        let read: LaneStats = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.screen, lane_stats.screen);
+        assert_eq!(read.overlapping_pairs, 3);
+        assert_eq!(read.umi, lane_stats.umi);
+    }
+
+    #[test]
+    fn project_stats() {
+        let samples = crate::sample_data::read_samplesheet(
+            PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv"),
+            1,
+        )
+        .unwrap()
+        .remove(&1)
+        .unwrap();
+
+        let mut lane_stats = LaneStats::new(1, &samples, 1);
+        lane_stats.demux_results[1].sample_project = None;
+        for sample_i in [0, 0, 1] {
+            lane_stats.add_read(sample_i, 0);
+            let mut metrics = ReadMetrics::new(1);
+            metrics.add_read(b"FF:#");
+            lane_stats.add_read_metrics(Some(sample_i), &metrics);
+        }
+        lane_stats.add_filtered_read(0);
+        let other_lane = LaneStats {
+            lane_number: 2,
+            ..lane_stats.clone()
+        };
+
+        let projects = super::project_stats(&[lane_stats, other_lane]);
+        assert_eq!(projects.len(), 2);
+        assert_eq!(projects[0].project, None);
+        assert_eq!(projects[0].samples, 1);
+        assert_eq!(projects[0].reads, 2);
+
+        assert_eq!(projects[1].project.as_deref(), Some("project_1"));
+        assert_eq!(projects[1].samples, samples.sample_names.len() - 1);
+        // two reads in each lane, one of which was filtered
+        assert_eq!(projects[1].reads, 2);
+        assert_eq!(projects[1].yield_bases, 16);
+        assert_eq!(projects[1].q30_fraction(), 0.5);
+    }
+
+    #[test]
+    fn cycle_metrics() {
+        let mut metrics = CycleMetrics::default();
+        metrics.add_base(b'A', b'F');
+        metrics.add_base(b'N', b'#');
+        metrics.add_base(b'T', b':');
+
+        assert_eq!(metrics.base_counts, [1, 0, 0, 1, 1]);
+        assert_eq!(metrics.n_bases(), 3);
+        assert_eq!(metrics.q30_count, 1);
+        assert_eq!(metrics.quality_score_sum, 37 + 2 + 25);
+
+        let other = metrics.clone();
+        metrics.merge(&other);
+
+        assert_eq!(metrics.base_counts, [2, 0, 0, 2, 2]);
+        assert_eq!(metrics.q30_count, 2);
+        assert!((metrics.max_base_fraction() - 1. / 3.).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,1583 @@
+//! Where the files in a run folder are read from. Normally this is the local
+//! filesystem, but with the `object-store` feature a run can be read straight
+//! from S3 or GCS: the small files are fetched whole, and each tile is fetched
+//! as a byte range of its CBCL file, using the offsets in the header, so the run
+//! never has to be copied locally.
+//!
+//! A run folder archived as a tar file, or as several in one directory, is read
+//! in place in the same way, as byte ranges of the archive.
+//!
+//! The same feature lets the fastq files be uploaded as they are written: each
+//! file is a multipart upload, with the gzip members for each chunk of tiles
+//! joined into parts, so the fastq files never touch the local disk
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::UNIX_EPOCH,
+};
+
+/// Reads files from a run folder. Paths are the run path joined with the path of
+/// the file inside the run folder, the same as for a local run
+pub trait RunStorage: Send + Sync {
+    /// Open a whole file
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Open `len` bytes of a file, starting from `start`
+    fn open_range(&self, path: &Path, start: u64, len: u64) -> io::Result<Box<dyn Read + Send>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// The size of a file in bytes
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// When a file last changed, as seconds and nanoseconds since the epoch. The
+    /// files in an archive change with the archive
+    fn modified(&self, path: &Path) -> io::Result<(u64, u32)>;
+
+    /// The size of a file and when it last changed, as `file_len` and `modified`
+    /// give them. Object storage gets both with one request
+    fn len_modified(&self, path: &Path) -> io::Result<(u64, (u64, u32))> {
+        Ok((self.file_len(path)?, self.modified(path)?))
+    }
+
+    /// Whether each tile has its own locs file, instead of one `s.locs` for the run
+    fn has_tile_locs(&self, run_path: &Path) -> bool {
+        let locs_path = run_path.join("Data/Intensities/s.locs");
+        !self.is_file(&locs_path) && !self.is_file(&gz_path(&locs_path))
+    }
+
+    /// Open a file, or its gzipped copy `path.gz` if there is only that, as in
+    /// some archived runs. The contents aren't decompressed
+    fn open_or_gz(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        match self.open(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.open(&gz_path(path)).map_err(|_| e)
+            }
+            result => result,
+        }
+    }
+}
+
+/// The path of the gzipped copy of a file, e.g. `s_1_1101.filter.gz`
+pub(crate) fn gz_path(path: &Path) -> PathBuf {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    PathBuf::from(gz_path)
+}
+
+/// When a local file last changed, as seconds and nanoseconds since the epoch
+pub(crate) fn file_modified(path: &Path) -> io::Result<(u64, u32)> {
+    let modified = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok((modified.as_secs(), modified.subsec_nanos()))
+}
+
+/// The first two bytes of every gzip file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// `rdr`, decompressed if it is gzipped. This goes by the first bytes rather than
+/// the name, so a compressed file is read the same whatever it is called
+pub(crate) fn gunzip_if_compressed<'a, R: Read + 'a>(rdr: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut rdr = BufReader::new(rdr);
+
+    if rdr.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(rdr)))
+    } else {
+        Ok(Box::new(rdr))
+    }
+}
+
+/// The storage that a file was read from, kept with its header so the file can be
+/// read again later. Handles are equal if they are the same storage
+#[derive(Clone)]
+pub struct StorageHandle(pub Arc<dyn RunStorage>);
+
+impl Deref for StorageHandle {
+    type Target = dyn RunStorage;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl PartialEq for StorageHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for StorageHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StorageHandle")
+    }
+}
+
+/// Run folders on the local filesystem
+pub struct LocalStorage;
+
+/// The local filesystem, shared by every local run
+pub fn local_storage() -> Arc<dyn RunStorage> {
+    static LOCAL: OnceLock<Arc<dyn RunStorage>> = OnceLock::new();
+    LOCAL.get_or_init(|| Arc::new(LocalStorage)).clone()
+}
+
+impl RunStorage for LocalStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_range(&self, path: &Path, start: u64, len: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start))?;
+
+        Ok(Box::new(file.take(len)))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<(u64, u32)> {
+        file_modified(path)
+    }
+
+    fn has_tile_locs(&self, run_path: &Path) -> bool {
+        let intensities = run_path.join("Data/Intensities");
+
+        !intensities.join("s.locs").is_file()
+            && !intensities.join("s.locs.gz").is_file()
+            && fs::read_dir(&intensities).is_ok_and(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .any(|e| e.file_name().to_string_lossy().starts_with('L') && e.path().is_dir())
+            })
+    }
+}
+
+/// Where the fastq files are written when they go straight to object storage
+/// instead of the output folder. Paths are the same as for local output: the
+/// output path joined with the path of the file inside it
+pub trait OutputStorage: Send + Sync {
+    /// Add a block to the end of a file, starting the file if this is its first
+    /// block. Blocks are whole gzip members (or plain text), so they can be joined
+    fn append(&self, path: &Path, block: &[u8]) -> io::Result<()>;
+
+    /// Write a whole file
+    fn put(&self, path: &Path, contents: Vec<u8>) -> io::Result<()>;
+
+    /// Finish every file that was appended to. Nothing appended is visible until then
+    fn finish(&self) -> io::Result<()>;
+
+    /// Drop a file that was appended to, so that it isn't finished. Returns true if
+    /// there was one
+    fn discard(&self, path: &Path) -> io::Result<bool>;
+
+    /// Write every file in `output_path` (e.g. the stats and reports) to the same
+    /// place in the output storage
+    fn put_folder(&self, output_path: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(output_path)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.put_folder(&path)?;
+            } else {
+                self.put(&path, fs::read(&path)?)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The storage that the fastq files are uploaded to, kept in the output options.
+/// Handles are equal if they are the same storage
+#[derive(Clone)]
+pub struct OutputHandle(pub Arc<dyn OutputStorage>);
+
+impl Deref for OutputHandle {
+    type Target = dyn OutputStorage;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl PartialEq for OutputHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for OutputHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OutputHandle")
+    }
+}
+
+/// The URL schemes for run folders in object storage
+const REMOTE_SCHEMES: [&str; 2] = ["s3://", "gs://"];
+
+/// Whether a run path is a URL for object storage rather than a local path
+pub fn is_remote(run_path: &Path) -> bool {
+    let run_path = run_path.to_string_lossy();
+    REMOTE_SCHEMES.iter().any(|s| run_path.starts_with(s))
+}
+
+/// The storage for a run path: object storage for `s3://` and `gs://` URLs, the
+/// archive for a tar file or a directory of them, or the local filesystem
+pub fn run_storage(run_path: &Path) -> io::Result<Arc<dyn RunStorage>> {
+    if tar_storage::is_archive(run_path) {
+        return tar_storage::tar_storage(run_path);
+    }
+
+    if !is_remote(run_path) {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(storage) = uring_storage::uring_storage() {
+            return Ok(storage);
+        }
+
+        return Ok(local_storage());
+    }
+
+    #[cfg(feature = "object-store")]
+    {
+        Ok(Arc::new(object_storage::ObjectStorage::new(run_path)?))
+    }
+
+    #[cfg(not(feature = "object-store"))]
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Can't read {}: bcl2fastr was built without the object-store feature",
+                run_path.display()
+            ),
+        ))
+    }
+}
+
+/// Storage for uploading the files in `output_path` to an `s3://` or `gs://` URL
+pub fn output_storage(url: &str, output_path: &Path) -> io::Result<Arc<dyn OutputStorage>> {
+    if !is_remote(Path::new(url)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't upload to {}: not an s3:// or gs:// URL", url),
+        ));
+    }
+
+    #[cfg(feature = "object-store")]
+    {
+        Ok(Arc::new(object_storage::ObjectUpload::new(
+            url,
+            output_path,
+        )?))
+    }
+
+    #[cfg(not(feature = "object-store"))]
+    {
+        let _ = output_path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Can't upload to {}: bcl2fastr was built without the object-store feature",
+                url
+            ),
+        ))
+    }
+}
+
+pub mod tar_storage {
+    //! Run folders archived as uncompressed tar files, read in place. The archive
+    //! is indexed once, by reading the header of each member and skipping its
+    //! contents, and then every file is a byte range of the archive, so the
+    //! hundreds of thousands of small files in a run are never unpacked. A big
+    //! run can be split into several tar files in one directory, as shards
+
+    use std::{
+        collections::HashMap,
+        fs::{self, File},
+        io::{self, BufReader, Read, Seek, SeekFrom},
+        path::{Component, Path, PathBuf},
+        sync::{Arc, Mutex, OnceLock},
+    };
+
+    use log::info;
+    use rayon::prelude::*;
+
+    use super::{file_modified, RunStorage};
+
+    /// The size of a tar header, and of the blocks that the contents are padded to
+    const BLOCK_SIZE: u64 = 512;
+
+    /// Where a file's contents are in the archives
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Member {
+        archive: usize,
+        offset: u64,
+        len: u64,
+    }
+
+    /// A run folder in one or more tar archives
+    pub struct TarStorage {
+        run_path: PathBuf,
+        archives: Vec<PathBuf>,
+        /// the members by their path inside the run folder
+        members: HashMap<PathBuf, Member>,
+    }
+
+    fn is_tar(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == "tar")
+    }
+
+    /// The tar files in a directory of shards, in order
+    fn tar_shards(dir: &Path) -> Vec<PathBuf> {
+        let mut shards: Vec<_> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_tar(path) && path.is_file())
+            .collect();
+        shards.sort();
+        shards
+    }
+
+    /// Whether a run path is a tar archive of a run folder, or a directory of tar
+    /// files that are shards of one
+    pub fn is_archive(run_path: &Path) -> bool {
+        if run_path.is_file() {
+            is_tar(run_path)
+        } else {
+            run_path.is_dir()
+                && !run_path.join("RunInfo.xml").is_file()
+                && !tar_shards(run_path).is_empty()
+        }
+    }
+
+    fn invalid_data(message: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+
+    /// A number in a header field: octal digits, or big-endian base 256 if the
+    /// first bit is set, as GNU tar writes sizes of 8 GiB and up
+    fn header_number(field: &[u8]) -> io::Result<u64> {
+        if field[0] & 0x80 != 0 {
+            return Ok(field[1..]
+                .iter()
+                .fold((field[0] & 0x7f) as u64, |n, &b| (n << 8) | b as u64));
+        }
+
+        let digits = String::from_utf8_lossy(field);
+        let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+        if digits.is_empty() {
+            return Ok(0);
+        }
+        u64::from_str_radix(digits, 8)
+            .map_err(|_| invalid_data(format!("bad number in tar header: '{}'", digits)))
+    }
+
+    /// A string in a header field, up to the first NUL
+    fn header_str(field: &[u8]) -> String {
+        let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+        String::from_utf8_lossy(&field[..end]).into_owned()
+    }
+
+    /// The path of a member as a relative path, without any `./`
+    fn member_path(name: &str) -> PathBuf {
+        Path::new(name)
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect()
+    }
+
+    /// The path and size of each file in an archive, with the offset of its
+    /// contents. GNU long names and pax paths and sizes are followed; links,
+    /// directories and anything else are left out
+    fn index_archive(path: &Path) -> io::Result<Vec<(PathBuf, u64, u64)>> {
+        let mut rdr = BufReader::new(File::open(path)?);
+        let mut files = Vec::new();
+        let mut offset = 0;
+        // the path and size for the next member, from an extended header
+        let mut next_name = None;
+        let mut next_len = None;
+
+        let mut header = [0u8; BLOCK_SIZE as usize];
+        loop {
+            match rdr.read_exact(&mut header) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                result => result?,
+            }
+            // the archive ends with blocks of zeros
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+
+            // the checksum is of the header with the checksum field as spaces
+            let checksum = header_number(&header[148..156])?;
+            let sum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+                .sum();
+            if sum != checksum {
+                return Err(invalid_data(format!(
+                    "{} is not a tar archive: bad header at byte {}",
+                    path.display(),
+                    offset
+                )));
+            }
+
+            let header_len = header_number(&header[124..136])?;
+            let contents = offset + BLOCK_SIZE;
+            let len = match header[156] {
+                b'0' | b'\0' | b'7' => next_len.take().unwrap_or(header_len),
+                _ => header_len,
+            };
+
+            match header[156] {
+                b'0' | b'\0' | b'7' => {
+                    let name = match next_name.take() {
+                        Some(name) => name,
+                        None if &header[257..262] == b"ustar" && header[345] != 0 => format!(
+                            "{}/{}",
+                            header_str(&header[345..500]),
+                            header_str(&header[..100])
+                        ),
+                        None => header_str(&header[..100]),
+                    };
+                    files.push((member_path(&name), contents, len));
+                }
+                // a GNU long name for the next member
+                b'L' => {
+                    let mut name = vec![0; len as usize];
+                    rdr.read_exact(&mut name)?;
+                    rdr.seek_relative(-(len as i64))?;
+                    next_name = Some(header_str(&name));
+                }
+                // pax records for the next member, each "<length> <key>=<value>\n"
+                b'x' => {
+                    let mut records = vec![0; len as usize];
+                    rdr.read_exact(&mut records)?;
+                    rdr.seek_relative(-(len as i64))?;
+                    for record in String::from_utf8_lossy(&records).lines() {
+                        let (key, value) = match record
+                            .split_once(' ')
+                            .and_then(|(_, kv)| kv.split_once('='))
+                        {
+                            Some(kv) => kv,
+                            None => continue,
+                        };
+                        match key {
+                            "path" => next_name = Some(value.to_string()),
+                            "size" => next_len = value.parse().ok(),
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+
+            // skip the contents, padded to a whole block
+            let padded = len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            rdr.seek_relative(padded as i64)?;
+            offset = contents + padded;
+        }
+
+        Ok(files)
+    }
+
+    impl TarStorage {
+        /// Index the archive at `run_path`, or every tar file in it if it's a
+        /// directory. The run folder is wherever `RunInfo.xml` is in the archives,
+        /// so a run archived with its folder name in the paths is found too
+        pub fn new(run_path: &Path) -> io::Result<TarStorage> {
+            let archives = if run_path.is_file() {
+                vec![run_path.to_path_buf()]
+            } else {
+                tar_shards(run_path)
+            };
+
+            let indexed = archives
+                .par_iter()
+                .map(|archive| index_archive(archive))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            let root = indexed
+                .iter()
+                .flatten()
+                .map(|(path, _, _)| path)
+                .filter(|path| path.file_name().is_some_and(|name| name == "RunInfo.xml"))
+                .min_by_key(|path| path.components().count())
+                .and_then(|path| path.parent())
+                .map(Path::to_path_buf)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no RunInfo.xml in {}", run_path.display()),
+                    )
+                })?;
+
+            let mut members = HashMap::new();
+            for (archive, files) in indexed.into_iter().enumerate() {
+                for (path, offset, len) in files {
+                    if let Ok(path) = path.strip_prefix(&root) {
+                        let member = Member {
+                            archive,
+                            offset,
+                            len,
+                        };
+                        members.insert(path.to_path_buf(), member);
+                    }
+                }
+            }
+            info!(
+                "indexed {} files in {} tar archives",
+                members.len(),
+                archives.len()
+            );
+
+            Ok(TarStorage {
+                run_path: run_path.to_path_buf(),
+                archives,
+                members,
+            })
+        }
+
+        /// The paths of the files in the archive, relative to the run folder
+        pub fn files(&self) -> impl Iterator<Item = &Path> {
+            self.members.keys().map(PathBuf::as_path)
+        }
+
+        fn member(&self, path: &Path) -> io::Result<Member> {
+            path.strip_prefix(&self.run_path)
+                .ok()
+                .and_then(|path| self.members.get(path))
+                .copied()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} is not in the archive", path.display()),
+                    )
+                })
+        }
+    }
+
+    /// The index of the archive at `run_path`. An archive is only indexed once,
+    /// however many times the run is opened
+    pub fn tar_index(run_path: &Path) -> io::Result<Arc<TarStorage>> {
+        static INDEXED: OnceLock<Mutex<HashMap<PathBuf, Arc<TarStorage>>>> = OnceLock::new();
+        let mut indexed = INDEXED.get_or_init(Default::default).lock().unwrap();
+
+        match indexed.get(run_path) {
+            Some(storage) => Ok(storage.clone()),
+            None => {
+                let storage = Arc::new(TarStorage::new(run_path)?);
+                indexed.insert(run_path.to_path_buf(), storage.clone());
+                Ok(storage)
+            }
+        }
+    }
+
+    /// The storage for the archive at `run_path`
+    pub fn tar_storage(run_path: &Path) -> io::Result<Arc<dyn RunStorage>> {
+        Ok(tar_index(run_path)?)
+    }
+
+    impl RunStorage for TarStorage {
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+            let member = self.member(path)?;
+            self.open_range(path, 0, member.len)
+        }
+
+        fn open_range(
+            &self,
+            path: &Path,
+            start: u64,
+            len: u64,
+        ) -> io::Result<Box<dyn Read + Send>> {
+            let member = self.member(path)?;
+            let mut file = File::open(&self.archives[member.archive])?;
+            file.seek(SeekFrom::Start(member.offset + start.min(member.len)))?;
+
+            Ok(Box::new(
+                file.take(len.min(member.len.saturating_sub(start))),
+            ))
+        }
+
+        fn is_file(&self, path: &Path) -> bool {
+            self.member(path).is_ok()
+        }
+
+        fn file_len(&self, path: &Path) -> io::Result<u64> {
+            Ok(self.member(path)?.len)
+        }
+
+        fn modified(&self, path: &Path) -> io::Result<(u64, u32)> {
+            file_modified(&self.archives[self.member(path)?.archive])
+        }
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring_storage {
+    //! Local run folders read through io_uring. Each block is split into chunks
+    //! that are all read at once, so a fast NVMe array has many reads in flight
+    //! per thread instead of one blocking read at a time. Each thread has its own
+    //! ring, made the first time it reads
+
+    use std::{
+        cell::RefCell,
+        fs::File,
+        io::{self, Cursor, Read},
+        os::unix::io::AsRawFd,
+        path::Path,
+        sync::{Arc, OnceLock},
+    };
+
+    use io_uring::{opcode, types, IoUring};
+    use log::info;
+
+    use super::{LocalStorage, RunStorage};
+
+    /// The most reads in flight on each thread
+    const QUEUE_DEPTH: usize = 32;
+
+    /// The size of each read, so a 4 MiB tile block is 16 reads
+    const CHUNK_SIZE: usize = 256 * 1024;
+
+    thread_local! {
+        static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+    }
+
+    /// Read `len` bytes of `file` from `start`, as chunks that are read at once
+    fn read_at(file: &File, start: u64, len: usize) -> io::Result<Vec<u8>> {
+        RING.with(|ring| {
+            let mut ring_slot = ring.borrow_mut();
+            if ring_slot.is_none() {
+                *ring_slot = Some(IoUring::new(QUEUE_DEPTH as u32)?);
+            }
+            let ring = ring_slot.as_mut().unwrap();
+
+            let mut buffer = vec![0u8; len];
+            let fd = types::Fd(file.as_raw_fd());
+
+            // the (offset, length) of each part of the buffer left to read. Short
+            // reads put the rest of their chunk back in here
+            let mut pending: Vec<_> = (0..len)
+                .step_by(CHUNK_SIZE)
+                .map(|offset| (offset, CHUNK_SIZE.min(len - offset)))
+                .rev()
+                .collect();
+            let mut in_flight = vec![None; QUEUE_DEPTH];
+            let mut n_in_flight = 0;
+            let mut error = None;
+            let mut wait_failed = false;
+
+            while n_in_flight > 0 || (error.is_none() && !pending.is_empty()) {
+                while error.is_none() && n_in_flight < QUEUE_DEPTH {
+                    let Some((offset, chunk_len)) = pending.pop() else {
+                        break;
+                    };
+                    let slot = in_flight.iter().position(Option::is_none).unwrap();
+                    let read =
+                        opcode::Read::new(fd, buffer[offset..].as_mut_ptr(), chunk_len as u32)
+                            .offset(start + offset as u64)
+                            .build()
+                            .user_data(slot as u64);
+
+                    // the queue has room, since there are fewer reads in flight than entries
+                    unsafe { ring.submission().push(&read) }.map_err(io::Error::other)?;
+                    in_flight[slot] = Some((offset, chunk_len));
+                    n_in_flight += 1;
+                }
+
+                // an interrupted wait is tried again. Any other error stops new reads,
+                // and the reads in flight are waited for before the buffer is freed
+                match ring.submit_and_wait(1) {
+                    Ok(_) => wait_failed = false,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if wait_failed => {
+                        // the kernel could still write into the buffer, so it is
+                        // leaked and the ring dropped rather than reused
+                        std::mem::forget(buffer);
+                        *ring_slot = None;
+                        return Err(error.unwrap_or(e));
+                    }
+                    Err(e) => {
+                        wait_failed = true;
+                        error.get_or_insert(e);
+                        continue;
+                    }
+                }
+
+                let completed: Vec<_> = ring
+                    .completion()
+                    .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+                    .collect();
+                for (slot, result) in completed {
+                    let (offset, chunk_len) = in_flight[slot].take().unwrap();
+                    n_in_flight -= 1;
+
+                    // don't return until nothing is reading into the buffer
+                    if result < 0 {
+                        error.get_or_insert(io::Error::from_raw_os_error(-result));
+                    } else if result == 0 {
+                        error.get_or_insert(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    } else if (result as usize) < chunk_len {
+                        let n = result as usize;
+                        pending.push((offset + n, chunk_len - n));
+                    }
+                }
+            }
+
+            match error {
+                Some(e) => Err(e),
+                None => Ok(buffer),
+            }
+        })
+    }
+
+    /// Run folders on the local filesystem, read with io_uring
+    pub struct UringStorage;
+
+    /// io_uring storage, if the kernel allows it. Some containers block io_uring,
+    /// so this checks once and falls back to the usual reads if it can't make a ring
+    pub fn uring_storage() -> Option<Arc<dyn RunStorage>> {
+        static URING: OnceLock<Option<Arc<dyn RunStorage>>> = OnceLock::new();
+        URING
+            .get_or_init(|| match IoUring::new(QUEUE_DEPTH as u32) {
+                Ok(_) => Some(Arc::new(UringStorage)),
+                Err(e) => {
+                    info!("io_uring is not available, using blocking reads: {}", e);
+                    None
+                }
+            })
+            .clone()
+    }
+
+    impl RunStorage for UringStorage {
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+            let file = File::open(path)?;
+            let len = file.metadata()?.len();
+
+            Ok(Box::new(Cursor::new(read_at(&file, 0, len as usize)?)))
+        }
+
+        fn open_range(
+            &self,
+            path: &Path,
+            start: u64,
+            len: u64,
+        ) -> io::Result<Box<dyn Read + Send>> {
+            let file = File::open(path)?;
+
+            Ok(Box::new(Cursor::new(read_at(&file, start, len as usize)?)))
+        }
+
+        fn is_file(&self, path: &Path) -> bool {
+            path.is_file()
+        }
+
+        fn file_len(&self, path: &Path) -> io::Result<u64> {
+            LocalStorage.file_len(path)
+        }
+
+        fn modified(&self, path: &Path) -> io::Result<(u64, u32)> {
+            LocalStorage.modified(path)
+        }
+
+        fn has_tile_locs(&self, run_path: &Path) -> bool {
+            LocalStorage.has_tile_locs(run_path)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn read_range() {
+            if uring_storage().is_none() {
+                return;
+            }
+
+            let path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX")
+                .join("Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl");
+
+            let mut whole = Vec::new();
+            UringStorage
+                .open(&path)
+                .unwrap()
+                .read_to_end(&mut whole)
+                .unwrap();
+            assert_eq!(whole, std::fs::read(&path).unwrap());
+
+            let mut range = Vec::new();
+            UringStorage
+                .open_range(&path, 97, 73)
+                .unwrap()
+                .read_to_end(&mut range)
+                .unwrap();
+            assert_eq!(range, &whole[97..170]);
+
+            let file = File::open(&path).unwrap();
+            assert_eq!(
+                read_at(&file, 0, CHUNK_SIZE * 2).unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+
+        #[test]
+        fn read_many_chunks() {
+            if uring_storage().is_none() {
+                return;
+            }
+
+            // more chunks than the queue has entries, and a partial chunk at the end
+            let path = Path::new("test_data/test_output/uring_chunks.bin");
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let contents: Vec<u8> = (0..CHUNK_SIZE * (QUEUE_DEPTH + 3) + 123)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            std::fs::write(path, &contents).unwrap();
+
+            let mut range = Vec::new();
+            UringStorage
+                .open_range(path, 1000, contents.len() as u64 - 1000)
+                .unwrap()
+                .read_to_end(&mut range)
+                .unwrap();
+            assert_eq!(range, &contents[1000..]);
+        }
+    }
+}
+
+#[cfg(feature = "object-store")]
+pub mod object_storage {
+    //! Run folders in S3 or GCS, read through the `object_store` crate. The
+    //! credentials come from the usual environment variables, e.g.
+    //! `AWS_ACCESS_KEY_ID` or `GOOGLE_APPLICATION_CREDENTIALS`
+
+    use std::{
+        collections::HashMap,
+        io::{self, Cursor, Read},
+        mem,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    use log::warn;
+    use object_store::{
+        aws::AmazonS3Builder,
+        gcp::GoogleCloudStorageBuilder,
+        path::{Path as ObjectPath, PathPart},
+        MultipartUpload, ObjectStore,
+    };
+    use tokio::{runtime::Runtime, task::JoinSet};
+
+    use super::{OutputStorage, RunStorage};
+
+    /// The smallest part of a multipart upload, except for the last, in S3
+    const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+    /// The parts of a file that are uploaded before the part size doubles. S3 takes
+    /// at most 10,000 parts, so ten sizes from 5 MiB reach about 5 TiB, the largest
+    /// object it allows, with the last parts under its 5 GiB limit
+    const PARTS_PER_SIZE: usize = 1000;
+
+    /// The most parts of one file that are uploaded at once
+    const MAX_PART_UPLOADS: usize = 4;
+
+    /// The multipart upload of one file. The parts start at the smallest size, so a
+    /// small file is only buffered a little at a time, and grow with the file
+    struct PartUpload {
+        upload: Box<dyn MultipartUpload>,
+        buffer: Vec<u8>,
+        n_parts: usize,
+        tasks: JoinSet<object_store::Result<()>>,
+    }
+
+    impl PartUpload {
+        fn new(upload: Box<dyn MultipartUpload>) -> PartUpload {
+            PartUpload {
+                upload,
+                buffer: Vec::new(),
+                n_parts: 0,
+                tasks: JoinSet::new(),
+            }
+        }
+
+        /// The size of the next part
+        fn part_size(&self) -> usize {
+            MIN_PART_SIZE << (self.n_parts / PARTS_PER_SIZE).min(9)
+        }
+
+        fn put_part(&mut self) {
+            let part = mem::take(&mut self.buffer);
+            self.tasks.spawn(self.upload.put_part(part.into()));
+            self.n_parts += 1;
+        }
+
+        /// Add to the file, starting the upload of each part that is full. Must be
+        /// called in the runtime
+        fn write(&mut self, mut block: &[u8]) {
+            while !block.is_empty() {
+                let n = block.len().min(self.part_size() - self.buffer.len());
+                self.buffer.extend_from_slice(&block[..n]);
+                block = &block[n..];
+                if self.buffer.len() == self.part_size() {
+                    self.put_part();
+                }
+            }
+        }
+
+        /// Wait until fewer than `max_uploads` parts are being uploaded
+        async fn wait_for_capacity(&mut self, max_uploads: usize) -> io::Result<()> {
+            while !self.tasks.is_empty() && self.tasks.len() >= max_uploads {
+                if let Some(result) = self.tasks.join_next().await {
+                    result.map_err(io::Error::other)?.map_err(store_error)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Upload the last part and put the file together
+        async fn finish(&mut self) -> io::Result<()> {
+            if !self.buffer.is_empty() || self.n_parts == 0 {
+                self.put_part();
+            }
+            self.wait_for_capacity(1).await?;
+            self.upload.complete().await.map_err(store_error)?;
+
+            Ok(())
+        }
+
+        /// Stop the upload, so that the parts that were uploaded aren't kept
+        async fn abort(&mut self) -> io::Result<()> {
+            self.tasks.shutdown().await;
+            self.upload.abort().await.map_err(store_error)
+        }
+    }
+
+    fn store_error(e: object_store::Error) -> io::Error {
+        match e {
+            object_store::Error::NotFound { .. } => {
+                io::Error::new(io::ErrorKind::NotFound, e.to_string())
+            }
+            e => io::Error::other(e),
+        }
+    }
+
+    /// Connect to the bucket in an `s3://` or `gs://` URL
+    fn bucket_store(url: &str) -> io::Result<Box<dyn ObjectStore>> {
+        Ok(if url.starts_with("s3://") {
+            Box::new(
+                AmazonS3Builder::from_env()
+                    .with_url(url)
+                    .build()
+                    .map_err(store_error)?,
+            )
+        } else {
+            Box::new(
+                GoogleCloudStorageBuilder::from_env()
+                    .with_url(url)
+                    .build()
+                    .map_err(store_error)?,
+            )
+        })
+    }
+
+    /// The path inside the bucket in a URL
+    fn url_prefix(url: &str) -> ObjectPath {
+        // skip the scheme and the bucket
+        url.split('/')
+            .skip(3)
+            .filter(|p| !p.is_empty())
+            .map(PathPart::from)
+            .collect()
+    }
+
+    /// The location in the bucket of `path`, which is relative to `root`
+    fn location(prefix: &ObjectPath, root: &Path, path: &Path) -> io::Result<ObjectPath> {
+        let relative = path.strip_prefix(root).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not in {}", path.display(), root.display()),
+            )
+        })?;
+
+        Ok(prefix
+            .parts()
+            .chain(
+                relative
+                    .iter()
+                    .map(|p| p.to_string_lossy().into_owned().into()),
+            )
+            .collect())
+    }
+
+    fn runtime() -> io::Result<Runtime> {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+    }
+
+    /// A run folder under an `s3://` or `gs://` URL
+    pub struct ObjectStorage {
+        store: Box<dyn ObjectStore>,
+        /// the run URL as a path, which file paths are relative to
+        run_path: PathBuf,
+        /// the run folder inside the bucket
+        prefix: ObjectPath,
+        runtime: Runtime,
+    }
+
+    impl ObjectStorage {
+        /// Connect to the bucket in the run URL, with credentials from the environment
+        pub fn new(run_path: &Path) -> io::Result<ObjectStorage> {
+            let url = run_path.to_string_lossy().trim_end_matches('/').to_string();
+
+            ObjectStorage::with_store(bucket_store(&url)?, run_path)
+        }
+
+        /// Read the run at `run_path` from a store that is already set up. The bucket
+        /// in the URL is ignored: `store` is the bucket
+        pub fn with_store(
+            store: Box<dyn ObjectStore>,
+            run_path: &Path,
+        ) -> io::Result<ObjectStorage> {
+            let url = run_path.to_string_lossy().trim_end_matches('/').to_string();
+
+            Ok(ObjectStorage {
+                store,
+                prefix: url_prefix(&url),
+                run_path: PathBuf::from(url),
+                runtime: runtime()?,
+            })
+        }
+
+        /// The location of a file in the bucket
+        fn location(&self, path: &Path) -> io::Result<ObjectPath> {
+            location(&self.prefix, &self.run_path, path)
+        }
+    }
+
+    impl RunStorage for ObjectStorage {
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+            let location = self.location(path)?;
+            let bytes = self
+                .runtime
+                .block_on(async { self.store.get(&location).await?.bytes().await })
+                .map_err(store_error)?;
+
+            Ok(Box::new(Cursor::new(bytes)))
+        }
+
+        fn open_range(
+            &self,
+            path: &Path,
+            start: u64,
+            len: u64,
+        ) -> io::Result<Box<dyn Read + Send>> {
+            let location = self.location(path)?;
+            let range = start as usize..(start + len) as usize;
+            let bytes = self
+                .runtime
+                .block_on(self.store.get_range(&location, range))
+                .map_err(store_error)?;
+
+            Ok(Box::new(Cursor::new(bytes)))
+        }
+
+        fn is_file(&self, path: &Path) -> bool {
+            match self.location(path) {
+                Ok(location) => self.runtime.block_on(self.store.head(&location)).is_ok(),
+                Err(_) => false,
+            }
+        }
+
+        fn file_len(&self, path: &Path) -> io::Result<u64> {
+            Ok(self.len_modified(path)?.0)
+        }
+
+        fn modified(&self, path: &Path) -> io::Result<(u64, u32)> {
+            Ok(self.len_modified(path)?.1)
+        }
+
+        fn len_modified(&self, path: &Path) -> io::Result<(u64, (u64, u32))> {
+            let location = self.location(path)?;
+            let meta = self
+                .runtime
+                .block_on(self.store.head(&location))
+                .map_err(store_error)?;
+
+            Ok((
+                meta.size as u64,
+                (
+                    meta.last_modified.timestamp() as u64,
+                    meta.last_modified.timestamp_subsec_nanos(),
+                ),
+            ))
+        }
+    }
+
+    /// Uploads the output to an `s3://` or `gs://` URL, in place of the output
+    /// folder
+    pub struct ObjectUpload {
+        store: Box<dyn ObjectStore>,
+        /// the local output path, which file paths are relative to
+        output_path: PathBuf,
+        /// the folder inside the bucket to upload to
+        prefix: ObjectPath,
+        runtime: Runtime,
+        /// the files that are being appended to, each with its own lock so that
+        /// different files are uploaded at the same time
+        uploads: Mutex<HashMap<ObjectPath, Arc<Mutex<PartUpload>>>>,
+        /// the first upload that failed. Every upload is aborted then, as the output
+        /// is incomplete, and later appends fail with the same error
+        failed: Mutex<Option<String>>,
+    }
+
+    impl ObjectUpload {
+        /// Connect to the bucket in `url`, with credentials from the environment
+        pub fn new(url: &str, output_path: &Path) -> io::Result<ObjectUpload> {
+            let url = url.trim_end_matches('/');
+
+            ObjectUpload::with_store(bucket_store(url)?, url, output_path)
+        }
+
+        /// Upload to `url` in a store that is already set up. The bucket in the
+        /// URL is ignored: `store` is the bucket
+        pub fn with_store(
+            store: Box<dyn ObjectStore>,
+            url: &str,
+            output_path: &Path,
+        ) -> io::Result<ObjectUpload> {
+            Ok(ObjectUpload {
+                store,
+                output_path: output_path.to_path_buf(),
+                prefix: url_prefix(url),
+                runtime: runtime()?,
+                uploads: Mutex::new(HashMap::new()),
+                failed: Mutex::new(None),
+            })
+        }
+
+        /// The error of the upload that failed, if one has
+        fn check_failed(&self) -> io::Result<()> {
+            match &*self.failed.lock().unwrap() {
+                Some(message) => Err(io::Error::other(format!(
+                    "the upload was aborted: {}",
+                    message
+                ))),
+                None => Ok(()),
+            }
+        }
+
+        /// Note that an upload failed, and abort every upload that is under way
+        fn fail(&self, location: &ObjectPath, e: io::Error) -> io::Error {
+            let e = io::Error::new(e.kind(), format!("uploading {}: {}", location, e));
+            self.failed
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| e.to_string());
+
+            let uploads = mem::take(&mut *self.uploads.lock().unwrap());
+            for (location, upload) in uploads {
+                let mut upload = upload.lock().unwrap();
+                if let Err(e) = self.runtime.block_on(upload.abort()) {
+                    warn!("Error aborting the upload of {}: {}", location, e);
+                }
+            }
+
+            e
+        }
+
+        /// The upload for a file, started if this is the first time it is used
+        fn upload(&self, location: &ObjectPath) -> io::Result<Arc<Mutex<PartUpload>>> {
+            let mut uploads = self.uploads.lock().unwrap();
+            if let Some(upload) = uploads.get(location) {
+                return Ok(Arc::clone(upload));
+            }
+
+            let multipart = self
+                .runtime
+                .block_on(self.store.put_multipart(location))
+                .map_err(store_error)?;
+            let upload = Arc::new(Mutex::new(PartUpload::new(multipart)));
+            uploads.insert(location.clone(), Arc::clone(&upload));
+
+            Ok(upload)
+        }
+    }
+
+    impl OutputStorage for ObjectUpload {
+        fn append(&self, path: &Path, block: &[u8]) -> io::Result<()> {
+            self.check_failed()?;
+            let location = location(&self.prefix, &self.output_path, path)?;
+
+            let result = self.upload(&location).and_then(|upload| {
+                let mut upload = upload.lock().unwrap();
+
+                // full parts are uploaded in the background, on the runtime
+                let _guard = self.runtime.enter();
+                upload.write(block);
+                self.runtime
+                    .block_on(upload.wait_for_capacity(MAX_PART_UPLOADS))
+            });
+            result.map_err(|e| self.fail(&location, e))
+        }
+
+        fn put(&self, path: &Path, contents: Vec<u8>) -> io::Result<()> {
+            let location = location(&self.prefix, &self.output_path, path)?;
+            self.runtime
+                .block_on(self.store.put(&location, contents.into()))
+                .map_err(store_error)?;
+
+            Ok(())
+        }
+
+        fn finish(&self) -> io::Result<()> {
+            self.check_failed()?;
+            let uploads: Vec<_> = self
+                .uploads
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(location, upload)| (location.clone(), Arc::clone(upload)))
+                .collect();
+
+            for (location, upload) in uploads {
+                let result = self.runtime.block_on(upload.lock().unwrap().finish());
+                result.map_err(|e| self.fail(&location, e))?;
+                self.uploads.lock().unwrap().remove(&location);
+            }
+
+            Ok(())
+        }
+
+        fn discard(&self, path: &Path) -> io::Result<bool> {
+            let location = location(&self.prefix, &self.output_path, path)?;
+            let upload = self.uploads.lock().unwrap().remove(&location);
+
+            match upload {
+                Some(upload) => {
+                    let result = self.runtime.block_on(upload.lock().unwrap().abort());
+                    result.map(|_| true)
+                }
+                None => Ok(false),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::demux::DemuxBuilder;
+        use crate::novaseq_run::NovaSeqRun;
+        use crate::storage::OutputHandle;
+        use crate::write_fastq::dump_tile;
+        use crate::write_fastq::OutputOptions;
+        use std::sync::Arc;
+
+        #[test]
+        fn location() {
+            // `ObjectStorage::new` would need AWS_REGION, and the environment is shared
+            // with the other tests, so the paths are checked over a store in memory
+            let store = object_store::memory::InMemory::new();
+            let storage =
+                ObjectStorage::with_store(Box::new(store), Path::new("s3://bucket/runs/my_run/"))
+                    .unwrap();
+
+            let path = Path::new("s3://bucket/runs/my_run").join("Data/Intensities/s.locs");
+            assert_eq!(
+                storage.location(&path).unwrap().as_ref(),
+                "runs/my_run/Data/Intensities/s.locs"
+            );
+            assert!(storage.location(Path::new("s3://other/run")).is_err());
+        }
+
+        #[test]
+        fn read_run() {
+            let run_id = "210618_FS10000171_0042_BPA73113-1417";
+            let store = object_store::local::LocalFileSystem::new_with_prefix("test_data").unwrap();
+            let run_path = Path::new("s3://bucket").join(run_id);
+            let storage = ObjectStorage::with_store(Box::new(store), &run_path).unwrap();
+
+            let novaseq_run = NovaSeqRun::read_storage(Arc::new(storage), run_path, false).unwrap();
+            assert_eq!(novaseq_run.tile_count(0), 2);
+            assert!(novaseq_run.locs.is_empty());
+
+            let local_run =
+                NovaSeqRun::read_path(Path::new("test_data").join(run_id), false).unwrap();
+            let mut remote_reads = Vec::new();
+            dump_tile(&novaseq_run, 1, 1102, &mut remote_reads).unwrap();
+            let mut local_reads = Vec::new();
+            dump_tile(&local_run, 1, 1102, &mut local_reads).unwrap();
+
+            assert_eq!(remote_reads, local_reads);
+        }
+
+        #[test]
+        fn len_modified() {
+            let run_id = "210618_FS10000171_0042_BPA73113-1417";
+            let store = object_store::local::LocalFileSystem::new_with_prefix("test_data").unwrap();
+            let run_path = Path::new("s3://bucket").join(run_id);
+            let storage = ObjectStorage::with_store(Box::new(store), &run_path).unwrap();
+
+            let path = run_path.join("RunInfo.xml");
+            let local_path = Path::new("test_data").join(run_id).join("RunInfo.xml");
+            let (file_len, modified) = storage.len_modified(&path).unwrap();
+            assert_eq!(file_len, std::fs::metadata(&local_path).unwrap().len());
+            assert_eq!(
+                modified.0,
+                crate::storage::LocalStorage
+                    .modified(&local_path)
+                    .unwrap()
+                    .0
+            );
+            assert_eq!(storage.file_len(&path).unwrap(), file_len);
+            assert_eq!(
+                storage
+                    .len_modified(&run_path.join("missing.xml"))
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::NotFound
+            );
+        }
+
+        #[test]
+        fn upload_demux() {
+            let bucket_path = PathBuf::from("test_data/test_output/upload_bucket");
+            let output_path = PathBuf::from("test_data/test_output/upload_demux");
+            let local_path = PathBuf::from("test_data/test_output/upload_local");
+            for path in [&bucket_path, &output_path, &local_path] {
+                let _ = std::fs::remove_dir_all(path);
+                std::fs::create_dir_all(path).unwrap();
+            }
+
+            let store =
+                object_store::local::LocalFileSystem::new_with_prefix(&bucket_path).unwrap();
+            let upload =
+                ObjectUpload::with_store(Box::new(store), "s3://bucket/demux", &output_path)
+                    .unwrap();
+
+            let run_path = PathBuf::from("test_data/210618_FS10000171_0042_BPA73113-1417");
+            let demux = |output_path: &Path, upload: Option<OutputHandle>| {
+                DemuxBuilder::new(&run_path, run_path.join("SampleSheet.csv"), output_path)
+                    .output_options(OutputOptions {
+                        upload,
+                        ..Default::default()
+                    })
+                    .reports(false)
+                    .build()
+                    .unwrap()
+                    .run()
+                    .unwrap()
+            };
+            demux(&output_path, Some(OutputHandle(Arc::new(upload))));
+            demux(&local_path, None);
+
+            // the local files have a sync flush in each member, so compare the reads
+            let read_fastq = |path: PathBuf| {
+                let mut text = String::new();
+                flate2::read::MultiGzDecoder::new(std::fs::File::open(path).unwrap())
+                    .read_to_string(&mut text)
+                    .unwrap();
+                text
+            };
+            let fastq = "iseq_project/iseq_1_L001_R1.fastq.gz";
+            assert!(!output_path.join(fastq).exists());
+            assert_eq!(
+                read_fastq(bucket_path.join("demux").join(fastq)),
+                read_fastq(local_path.join(fastq))
+            );
+            assert!(bucket_path.join("demux/Stats/Stats.json").is_file());
+        }
+
+        #[test]
+        fn part_sizes() {
+            let store = object_store::memory::InMemory::new();
+            let runtime = runtime().unwrap();
+            let multipart = runtime
+                .block_on(store.put_multipart(&ObjectPath::from("reads.fastq.gz")))
+                .unwrap();
+            let mut upload = PartUpload::new(multipart);
+
+            assert_eq!(upload.part_size(), MIN_PART_SIZE);
+            upload.n_parts = PARTS_PER_SIZE;
+            assert_eq!(upload.part_size(), 2 * MIN_PART_SIZE);
+            upload.n_parts = 9999;
+            assert_eq!(upload.part_size(), 512 * MIN_PART_SIZE);
+        }
+
+        #[test]
+        fn failed_upload() {
+            // the bucket is a file, so no object can be written to it
+            let bucket_path = PathBuf::from("test_data/test_output/failed_upload_bucket");
+            let output_path = PathBuf::from("test_data/test_output/failed_upload");
+            let _ = std::fs::remove_dir_all(&output_path);
+            std::fs::create_dir_all(&output_path).unwrap();
+            std::fs::write(&bucket_path, "").unwrap();
+
+            let store =
+                object_store::local::LocalFileSystem::new_with_prefix(&bucket_path).unwrap();
+            let upload =
+                ObjectUpload::with_store(Box::new(store), "s3://bucket/demux", &output_path)
+                    .unwrap();
+
+            let block = vec![b'A'; MIN_PART_SIZE + 1];
+            assert!(upload
+                .append(&output_path.join("a.fastq.gz"), &block)
+                .is_err());
+            let e = upload
+                .append(&output_path.join("b.fastq.gz"), b"ACGT")
+                .unwrap_err();
+            assert!(e.to_string().contains("aborted"), "{}", e);
+            assert!(upload.finish().is_err());
+        }
+
+        #[test]
+        fn discard_upload() {
+            let bucket_path = PathBuf::from("test_data/test_output/discard_upload_bucket");
+            let output_path = PathBuf::from("test_data/test_output/discard_upload");
+            for path in [&bucket_path, &output_path] {
+                let _ = std::fs::remove_dir_all(path);
+                std::fs::create_dir_all(path).unwrap();
+            }
+
+            let store =
+                object_store::local::LocalFileSystem::new_with_prefix(&bucket_path).unwrap();
+            let upload =
+                ObjectUpload::with_store(Box::new(store), "s3://bucket/demux", &output_path)
+                    .unwrap();
+
+            upload.append(&output_path.join("a.fastq"), b"a\n").unwrap();
+            upload.append(&output_path.join("b.fastq"), b"b\n").unwrap();
+            assert!(upload.discard(&output_path.join("a.fastq")).unwrap());
+            assert!(!upload.discard(&output_path.join("c.fastq")).unwrap());
+            upload.finish().unwrap();
+
+            assert!(!bucket_path.join("demux/a.fastq").exists());
+            assert!(bucket_path.join("demux/b.fastq").is_file());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote() {
+        assert!(is_remote(Path::new("s3://bucket/run")));
+        assert!(is_remote(Path::new("gs://bucket/run")));
+        assert!(!is_remote(Path::new(
+            "test_data/190414_A00111_0296_AHJCWWDSXX"
+        )));
+    }
+
+    #[test]
+    fn local_range() {
+        let path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX")
+            .join("Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl");
+
+        let mut whole = Vec::new();
+        LocalStorage
+            .open(&path)
+            .unwrap()
+            .read_to_end(&mut whole)
+            .unwrap();
+
+        let mut range = Vec::new();
+        LocalStorage
+            .open_range(&path, 97, 73)
+            .unwrap()
+            .read_to_end(&mut range)
+            .unwrap();
+
+        assert_eq!(range, &whole[97..170]);
+    }
+
+    #[test]
+    fn tar_archive() {
+        let archive_path = Path::new("test_data/test_output/tar_storage");
+        let _ = fs::remove_dir_all(archive_path);
+        fs::create_dir_all(archive_path).unwrap();
+
+        let run_path = Path::new("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let cbcl_path = Path::new("Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl");
+        let mut whole = Vec::new();
+        LocalStorage
+            .open(&run_path.join(cbcl_path))
+            .unwrap()
+            .read_to_end(&mut whole)
+            .unwrap();
+
+        // GNU tar's own format and pax, with the run folder's name in the paths,
+        // under a long enough directory that the paths need extended headers
+        for format in ["gnu", "pax"] {
+            let tar_path = archive_path.join(format!("{}.tar", format));
+            let status = std::process::Command::new("tar")
+                .arg(format!("--format={}", format))
+                .arg(format!("--transform=s,^,{}/,", "archived_runs".repeat(8)))
+                .arg("-cf")
+                .arg(&tar_path)
+                .arg("-C")
+                .arg("test_data")
+                .arg("190414_A00111_0296_AHJCWWDSXX")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert!(tar_storage::is_archive(&tar_path));
+
+            let storage = tar_storage::TarStorage::new(&tar_path).unwrap();
+            assert!(storage.is_file(&tar_path.join("RunInfo.xml")));
+            assert!(!storage.is_file(&tar_path.join("Data")));
+            assert_eq!(
+                storage.file_len(&tar_path.join(cbcl_path)).unwrap(),
+                whole.len() as u64
+            );
+
+            let mut range = Vec::new();
+            storage
+                .open_range(&tar_path.join(cbcl_path), 97, 73)
+                .unwrap()
+                .read_to_end(&mut range)
+                .unwrap();
+            assert_eq!(range, &whole[97..170]);
+        }
+
+        assert!(!tar_storage::is_archive(run_path));
+        let err = tar_storage::TarStorage::new(&run_path.join("RunInfo.xml"))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn upload_local_path() {
+        let err = output_storage("test_data/test_output", Path::new("test_data"))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(not(feature = "object-store"))]
+    #[test]
+    fn no_object_store() {
+        let err = run_storage(Path::new("s3://bucket/run")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
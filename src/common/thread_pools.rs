@@ -0,0 +1,174 @@
+//! Optional thread pools for each stage of the demux. Reading CBCLs is mostly
+//! waiting on the disk, while assigning reads and compressing output are mostly
+//! CPU, so the best split between them depends on where the run folder lives. If
+//! the pools aren't built, every stage uses the global rayon pool, as does any
+//! stage that isn't given its own number of threads. The stages run one after
+//! another, so the global pool is idle while a stage's own pool is busy
+
+use std::sync::OnceLock;
+
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+/// The stages of the demux that can have their own thread pool
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// reading and decompressing CBCL files
+    Io,
+    /// matching index reads to samples
+    Demux,
+    /// formatting, compressing and writing the output files
+    Compress,
+}
+
+/// A pool for each stage that has its own number of threads
+pub struct StagePools {
+    io: Option<ThreadPool>,
+    demux: Option<ThreadPool>,
+    compress: Option<ThreadPool>,
+}
+
+static STAGE_POOLS: OnceLock<StagePools> = OnceLock::new();
+
+fn build_pool(
+    stage: &str,
+    n_threads: Option<usize>,
+) -> Result<Option<ThreadPool>, ThreadPoolBuildError> {
+    n_threads
+        .map(|n_threads| {
+            let stage = stage.to_string();
+            ThreadPoolBuilder::new()
+                .num_threads(n_threads)
+                .thread_name(move |i| format!("{}-{}", stage, i))
+                .build()
+        })
+        .transpose()
+}
+
+impl StagePools {
+    /// Build a pool for each stage that is given a number of threads
+    pub fn new(
+        io: Option<usize>,
+        demux: Option<usize>,
+        compress: Option<usize>,
+    ) -> Result<StagePools, ThreadPoolBuildError> {
+        Ok(StagePools {
+            io: build_pool("io", io)?,
+            demux: build_pool("demux", demux)?,
+            compress: build_pool("compress", compress)?,
+        })
+    }
+
+    /// Run `op` in the pool for `stage`, or in the current pool if it has none
+    fn in_stage<OP, R>(&self, stage: Stage, op: OP) -> R
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        let pool = match stage {
+            Stage::Io => &self.io,
+            Stage::Demux => &self.demux,
+            Stage::Compress => &self.compress,
+        };
+        match pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+}
+
+/// Build a pool for each stage that is given a number of threads, for the rest of
+/// the process. Can only be called once, later calls are ignored
+pub fn build_stage_pools(
+    io: Option<usize>,
+    demux: Option<usize>,
+    compress: Option<usize>,
+) -> Result<(), ThreadPoolBuildError> {
+    let _ = STAGE_POOLS.set(StagePools::new(io, demux, compress)?);
+
+    Ok(())
+}
+
+/// Run `op` in the pool for `stage`, or in the current pool if the stage pools
+/// weren't built
+pub(crate) fn in_stage<OP, R>(stage: Stage, op: OP) -> R
+where
+    OP: FnOnce() -> R + Send,
+    R: Send,
+{
+    match STAGE_POOLS.get() {
+        Some(pools) => pools.in_stage(stage, op),
+        None => op(),
+    }
+}
+
+/// Run `op` on each of `jobs` in the current pool, starting with the biggest by
+/// `size`, and return the results in the order of `jobs`. Each job is taken by a
+/// thread on its own, so a thread that finishes early steals the next biggest one,
+/// rather than the end of the work waiting on one thread with the biggest tiles
+pub(crate) fn largest_first<T, R, S, OP>(jobs: Vec<T>, size: S, op: OP) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    S: Fn(&T) -> u64,
+    OP: Fn(T) -> R + Sync + Send,
+{
+    let mut jobs: Vec<_> = jobs.into_iter().enumerate().collect();
+    jobs.sort_by_key(|(_, job)| std::cmp::Reverse(size(job)));
+
+    let mut results: Vec<_> = jobs
+        .into_par_iter()
+        .with_max_len(1)
+        .map(|(i, job)| (i, op(job)))
+        .collect();
+    results.sort_unstable_by_key(|&(i, _)| i);
+
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_stage() {
+        // built here rather than for the process, which would change the other tests
+        let pools = StagePools::new(Some(1), None, Some(3)).unwrap();
+
+        assert_eq!(pools.in_stage(Stage::Io, rayon::current_num_threads), 1);
+        assert_eq!(
+            pools.in_stage(Stage::Compress, rayon::current_num_threads),
+            3
+        );
+        let name = pools.in_stage(Stage::Io, || {
+            std::thread::current().name().map(String::from)
+        });
+        assert!(name.unwrap().starts_with("io-"));
+
+        // the demux stage has no pool of its own, so it stays in the current one
+        let outer = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        assert_eq!(
+            outer.install(|| pools.in_stage(Stage::Demux, rayon::current_num_threads)),
+            2
+        );
+    }
+
+    #[test]
+    fn largest_first() {
+        let order = std::sync::Mutex::new(Vec::new());
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+
+        let results = pool.install(|| {
+            super::largest_first(
+                vec![3u64, 10, 1, 7],
+                |&size| size,
+                |size| {
+                    order.lock().unwrap().push(size);
+                    size * 2
+                },
+            )
+        });
+
+        assert_eq!(results, vec![6, 20, 2, 14]);
+        assert_eq!(order.into_inner().unwrap(), vec![10, 7, 3, 1]);
+    }
+}
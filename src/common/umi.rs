@@ -0,0 +1,120 @@
+//! UMIs sequenced in an index read, as in layouts that read the UMI as I1 or I2
+//! instead of a second sample index. The UMI read isn't used to assign clusters
+//! to samples; it stays in the read names, and can be written to its own fastq
+//! files next to the sample's reads
+
+use serde::{Deserialize, Serialize};
+
+use crate::write_fastq::PHRED_33;
+
+/// Where the UMIs are, and what is done with them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UmiOptions {
+    /// the index read with the UMIs, numbered from 1 as in I1
+    pub index_read: usize,
+    /// write the UMIs to `<sample>_UMI.fastq.gz` etc
+    pub write: bool,
+    /// a UMI with a base below this quality score, or an N, is invalid
+    pub min_quality: u8,
+    /// replace the bases of invalid UMIs with N, so that they aren't counted as
+    /// molecules downstream
+    pub mask: bool,
+}
+
+/// Parse the index read of the UMIs, e.g. `I2`
+pub fn parse_umi_read(s: &str) -> Result<usize, String> {
+    match s.strip_prefix('I').map(str::parse::<usize>) {
+        Some(Ok(n)) if n > 0 => Ok(n),
+        _ => Err(format!("expected an index read like I1 or I2, got '{}'", s)),
+    }
+}
+
+/// True if a UMI has no Ns and no base below `min_quality`
+pub fn is_valid_umi(seq: &[u8], qscores: &[u8], min_quality: u8) -> bool {
+    !seq.contains(&b'N')
+        && qscores
+            .iter()
+            .all(|&q| q.saturating_sub(PHRED_33) >= min_quality)
+}
+
+/// The UMIs of the assigned clusters in a lane
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UmiCounts {
+    pub umis: u64,
+    /// the UMIs with an N
+    pub with_n: u64,
+    /// the UMIs without an N but with a base below the quality threshold
+    pub low_quality: u64,
+    pub quality_score_sum: u64,
+    pub n_bases: u64,
+}
+
+impl UmiCounts {
+    /// Count a UMI, and return whether it is valid
+    pub fn add(&mut self, seq: &[u8], qscores: &[u8], min_quality: u8) -> bool {
+        self.umis += 1;
+        self.n_bases += qscores.len() as u64;
+        self.quality_score_sum += qscores
+            .iter()
+            .map(|&q| q.saturating_sub(PHRED_33) as u64)
+            .sum::<u64>();
+
+        let valid = is_valid_umi(seq, qscores, min_quality);
+        if seq.contains(&b'N') {
+            self.with_n += 1;
+        } else if !valid {
+            self.low_quality += 1;
+        }
+        valid
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.umis == 0
+    }
+
+    pub fn merge(&mut self, other: &UmiCounts) {
+        self.umis += other.umis;
+        self.with_n += other.with_n;
+        self.low_quality += other.low_quality;
+        self.quality_score_sum += other.quality_score_sum;
+        self.n_bases += other.n_bases;
+    }
+
+    /// The mean quality score of the UMI bases
+    pub fn mean_quality(&self) -> f64 {
+        if self.n_bases == 0 {
+            0.
+        } else {
+            self.quality_score_sum as f64 / self.n_bases as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn umi_read() {
+        assert_eq!(parse_umi_read("I2"), Ok(2));
+        assert!(parse_umi_read("I0").is_err());
+        assert!(parse_umi_read("R1").is_err());
+    }
+
+    #[test]
+    fn count_umis() {
+        let mut counts = UmiCounts::default();
+        assert!(counts.add(b"ACGT", b"FFFF", 10));
+        assert!(!counts.add(b"ACNT", b"FF#F", 10));
+        assert!(!counts.add(b"ACGT", b"FF+F", 11));
+
+        assert_eq!((counts.umis, counts.with_n, counts.low_quality), (3, 1, 1));
+        assert_eq!(counts.n_bases, 12);
+        // ten Q37s, a Q2 and a Q10
+        assert_eq!(counts.quality_score_sum, 10 * 37 + 2 + 10);
+
+        counts.merge(&counts.clone());
+        assert_eq!(counts.umis, 6);
+    }
+}
@@ -0,0 +1,352 @@
+//! Check the fastq files of a demux against its Stats.json before they are
+//! delivered. The reads in every file are counted again and compared with the
+//! reads Stats.json has for the file's sample and lane, and the totals for each
+//! lane with the clusters passing filter in the run's filter files
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+
+use crate::merge::{output_files, split_lane, split_shard};
+use crate::sample_data::numbered_sample_name;
+use crate::stats::{read_stats_json, LaneStats};
+use crate::write_fastq::{
+    ALL_SAMPLES_NAME, DIMER_SUFFIX, FILTERED_NAME, NON_PF_SUFFIX, PHIX_NAME, UMI_READ,
+};
+use crate::zstd_dictionary::{open_fastq, DICTIONARY_NAME};
+
+/// The size of the blocks the files are read (and decompressed) in
+const COUNT_BUFFER: usize = 1 << 20;
+
+/// The reads in the files of one sample in one lane, and the reads Stats.json has
+/// for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleCount {
+    pub sample: String,
+    /// 0 if the files hold every lane
+    pub lane: usize,
+    /// the reads in the files of each read number, summed over any shards
+    pub file_reads: BTreeMap<usize, u64>,
+    /// the reads in Stats.json that passed the read filter, or None if the sample
+    /// isn't in it
+    pub stats_reads: Option<u64>,
+}
+
+/// The counts for every sample, and whatever didn't agree
+#[derive(Debug)]
+pub struct Verification {
+    pub n_files: usize,
+    pub samples: Vec<SampleCount>,
+    pub discrepancies: Vec<String>,
+}
+
+impl Verification {
+    /// True if every count agreed
+    pub fn is_concordant(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+/// The read of a file, with the UMIs as read 0
+fn read_name(read: usize) -> String {
+    if read == 0 {
+        UMI_READ[1..].to_string()
+    } else {
+        format!("R{}", read)
+    }
+}
+
+fn lane_name(lane: usize) -> String {
+    if lane == 0 {
+        "all".to_string()
+    } else {
+        lane.to_string()
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:>6} {:>6} {:>12} {:>12}",
+            "sample", "lane", "read", "fastq_reads", "stats_reads"
+        )?;
+        for sample_count in &self.samples {
+            let stats_reads = sample_count
+                .stats_reads
+                .map_or_else(|| "-".to_string(), |n| n.to_string());
+            for (read, reads) in &sample_count.file_reads {
+                writeln!(
+                    f,
+                    "{:<40} {:>6} {:>6} {:>12} {:>12}",
+                    sample_count.sample,
+                    lane_name(sample_count.lane),
+                    read_name(*read),
+                    reads,
+                    stats_reads
+                )?;
+            }
+        }
+
+        if self.is_concordant() {
+            write!(f, "all {} files agree with Stats.json", self.n_files)
+        } else {
+            write!(f, "{} discrepancies", self.discrepancies.len())
+        }
+    }
+}
+
+/// The sample, lane (or 0) and read number of a fastq file that a demux writes,
+/// and the number of lines for each read. Files of reads that failed filter are
+/// left out, as Stats.json doesn't count them, and a sample's adapter dimers are
+/// counted with the sample. The UMI files are read 0
+fn parse_file_name(file_name: &str) -> Option<(String, usize, usize, u64)> {
+    let (stem, _, extension) = split_shard(file_name)?;
+    if stem.ends_with(NON_PF_SUFFIX) {
+        return None;
+    }
+    let stem = stem.strip_suffix(DIMER_SUFFIX).unwrap_or(stem);
+
+    let (stem, lane) = split_lane(stem).unwrap_or_else(|| (stem.to_string(), 0));
+    let lines_per_read = if extension.starts_with("fastq") { 4 } else { 1 };
+    if let Some(sample) = stem.strip_suffix(UMI_READ) {
+        return Some((sample.to_string(), lane, 0, lines_per_read));
+    }
+    let (sample, read) = stem.rsplit_once("_R")?;
+
+    Some((sample.to_string(), lane, read.parse().ok()?, lines_per_read))
+}
+
+/// Count the lines of a file, decompressing it if it is gzipped or zstd
+fn count_lines(path: &Path, dictionary: Option<&[u8]>) -> io::Result<u64> {
+    let mut reader = open_fastq(path, dictionary)?;
+
+    let mut buffer = vec![0; COUNT_BUFFER];
+    let mut lines = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(lines);
+        }
+        lines += buffer[..n].iter().filter(|&&b| b == b'\n').count() as u64;
+    }
+}
+
+/// The reads Stats.json has for a sample in a lane (or every lane, for 0), less
+/// those that failed the read filter. The sample can have its number, as in the
+/// file names with `--sample-numbers`. None if the sample isn't in those lanes
+fn stats_reads(lane_stats: &[LaneStats], sample: &str, lane: usize) -> Option<u64> {
+    let lanes = lane_stats
+        .iter()
+        .filter(|ls| lane == 0 || ls.lane_number == lane);
+
+    if sample == FILTERED_NAME {
+        return Some(lanes.map(|ls| ls.filtered_reads()).sum());
+    }
+    if sample == PHIX_NAME {
+        return Some(lanes.map(|ls| ls.phix.number_reads).sum());
+    }
+    if sample == ALL_SAMPLES_NAME {
+        return Some(
+            lanes
+                .flat_map(|ls| ls.demux_results.iter())
+                .map(|s| s.number_reads - s.number_reads_filtered)
+                .sum(),
+        );
+    }
+
+    lanes
+        .flat_map(|ls| ls.demux_results.iter())
+        .filter(|s| {
+            s.sample_name == sample
+                || numbered_sample_name(&s.sample_name, s.sample_number) == sample
+        })
+        .map(|s| s.number_reads - s.number_reads_filtered)
+        .reduce(|a, b| a + b)
+}
+
+/// Count the reads in the fastq files in `output_path` and compare them with its
+/// `Stats/Stats.json`. With `run_clusters`, the raw and passing filter clusters of
+/// each lane from the run's filter files, the lane totals are checked as well
+pub fn verify_output(
+    output_path: &Path,
+    run_clusters: Option<&BTreeMap<usize, (u64, u64)>>,
+) -> io::Result<Verification> {
+    let lane_stats = read_stats_json(&output_path.join("Stats").join("Stats.json"))?;
+    let mut discrepancies = Vec::new();
+
+    for ls in &lane_stats {
+        if ls.number_reads() != ls.total_clusters_pf {
+            discrepancies.push(format!(
+                "lane {}: Stats.json has {} reads for the samples and undetermined, but {} clusters passing filter",
+                ls.lane_number,
+                ls.number_reads(),
+                ls.total_clusters_pf
+            ));
+        }
+
+        match run_clusters.map(|run_clusters| run_clusters.get(&ls.lane_number)) {
+            Some(Some(&(raw, pf))) if (raw, pf) != (ls.total_clusters_raw, ls.total_clusters_pf) => {
+                discrepancies.push(format!(
+                    "lane {}: Stats.json has {} clusters with {} passing filter, the filter files have {} with {}",
+                    ls.lane_number, ls.total_clusters_raw, ls.total_clusters_pf, raw, pf
+                ))
+            }
+            Some(None) => discrepancies.push(format!(
+                "lane {}: in Stats.json but not in the run",
+                ls.lane_number
+            )),
+            _ => (),
+        }
+    }
+
+    let files: Vec<(PathBuf, (String, usize, usize, u64))> = output_files(output_path)?
+        .into_iter()
+        .filter_map(|path| {
+            let parsed = parse_file_name(path.file_name()?.to_str()?)?;
+            Some((path, parsed))
+        })
+        .collect();
+    // zstd files written with a dictionary have a copy of it in the output folder
+    let dictionary_path = output_path.join(DICTIONARY_NAME);
+    let dictionary = if dictionary_path.exists() {
+        Some(fs::read(dictionary_path)?)
+    } else {
+        None
+    };
+    let line_counts = files
+        .par_iter()
+        .map(|(path, _)| count_lines(path, dictionary.as_deref()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut file_reads: BTreeMap<(String, usize), BTreeMap<usize, u64>> = BTreeMap::new();
+    for ((path, (sample, lane, read, lines_per_read)), lines) in files.iter().zip(line_counts) {
+        if lines % lines_per_read != 0 {
+            discrepancies.push(format!(
+                "{}: {} lines is not a whole number of reads",
+                path.display(),
+                lines
+            ));
+        }
+        *file_reads
+            .entry((sample.clone(), *lane))
+            .or_default()
+            .entry(*read)
+            .or_insert(0) += lines / lines_per_read;
+    }
+
+    let mut samples = Vec::new();
+    for ((sample, lane), reads) in file_reads.iter() {
+        let expected = stats_reads(&lane_stats, sample, *lane);
+        match expected {
+            Some(expected) => {
+                for (read, &n) in reads.iter().filter(|(_, &n)| n != expected) {
+                    discrepancies.push(format!(
+                        "{} lane {} {}: {} reads in the fastq files, {} in Stats.json",
+                        sample,
+                        lane_name(*lane),
+                        read_name(*read),
+                        n,
+                        expected
+                    ));
+                }
+            }
+            None => discrepancies.push(format!(
+                "{} lane {}: has fastq files but isn't in Stats.json",
+                sample,
+                lane_name(*lane)
+            )),
+        }
+
+        samples.push(SampleCount {
+            sample: sample.clone(),
+            lane: *lane,
+            file_reads: reads.clone(),
+            stats_reads: expected,
+        });
+    }
+
+    // every sample with reads needs files, for its lane or for all of them, unless
+    // the samples share them
+    let with_files: BTreeSet<_> = file_reads.keys().cloned().collect();
+    for ls in &lane_stats {
+        for s in &ls.demux_results {
+            let reads = s.number_reads - s.number_reads_filtered;
+            let has_files = |sample: &str| {
+                with_files.contains(&(sample.to_string(), ls.lane_number))
+                    || with_files.contains(&(sample.to_string(), 0))
+            };
+            if reads > 0
+                && !has_files(&s.sample_name)
+                && !has_files(&numbered_sample_name(&s.sample_name, s.sample_number))
+                && !has_files(ALL_SAMPLES_NAME)
+            {
+                discrepancies.push(format!(
+                    "{} lane {}: {} reads in Stats.json, but no fastq files",
+                    s.sample_name, ls.lane_number, reads
+                ));
+            }
+        }
+    }
+
+    Ok(Verification {
+        n_files: files.len(),
+        samples,
+        discrepancies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::{fs::File, io::Write};
+
+    #[test]
+    fn parse_file_names() {
+        assert_eq!(
+            parse_file_name("s1_L001_R2.shard1of2.fastq.gz"),
+            Some(("s1".to_string(), 1, 2, 4))
+        );
+        assert_eq!(
+            parse_file_name("s_R1_R1.seq"),
+            Some(("s_R1".to_string(), 0, 1, 1))
+        );
+        assert_eq!(parse_file_name("s1_L001_R1_nonPF.fastq.gz"), None);
+        assert_eq!(
+            parse_file_name("s1_L001_R1_dimer.fastq.gz"),
+            Some(("s1".to_string(), 1, 1, 4))
+        );
+        assert_eq!(
+            parse_file_name("s1_L001_UMI_dimer.fastq.gz"),
+            Some(("s1".to_string(), 1, 0, 4))
+        );
+        assert_eq!(parse_file_name("barcode_L001_report.txt"), None);
+    }
+
+    #[test]
+    fn count_gzipped_lines() {
+        let output_path = Path::new("test_data/test_output/count_lines");
+        fs::create_dir_all(output_path).unwrap();
+
+        // two gzip members, as the compression workers write them
+        let path = output_path.join("s1_R1.fastq.gz");
+        let mut file = File::create(&path).unwrap();
+        for _ in 0..2 {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(b"@r\nACGT\n+\nFFFF\n").unwrap();
+            file.write_all(&gz.finish().unwrap()).unwrap();
+        }
+        drop(file);
+        assert_eq!(count_lines(&path, None).unwrap(), 8);
+
+        let path = output_path.join("s1_R1.seq");
+        fs::write(&path, "ACGT\nACGA\nTT\n").unwrap();
+        assert_eq!(count_lines(&path, None).unwrap(), 3);
+    }
+}
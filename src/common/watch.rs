@@ -0,0 +1,198 @@
+//! Watches a run folder while it is still sequencing, so that we can check the
+//! index reads as soon as they are written, decode them into the index cache while
+//! the template cycles are sequenced, and demultiplex as soon as the run is finished
+
+use std::{
+    io,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use ndarray::{s, Axis};
+use rayon::prelude::*;
+
+use crate::extract_reads::{extract_cbcl, TileCompaction};
+use crate::index_cache;
+use crate::novaseq_run::{completed_cycles, NovaSeqRun};
+use crate::read_structure::ReadStructure;
+use crate::run_info_parser::parse_run_info;
+use crate::write_fastq::{index_buffer, index_slices};
+
+/// Files that the instrument writes when a run is finished. `CopyComplete.txt` is
+/// written after the data is copied to the output folder, so it is the safer signal
+/// but isn't written by every instrument
+pub const COMPLETION_FILES: [&str; 2] = ["CopyComplete.txt", "RTAComplete.txt"];
+
+/// True if any of the completion files exist
+pub fn is_complete(run_path: &Path) -> bool {
+    COMPLETION_FILES
+        .iter()
+        .any(|name| run_path.join(name).is_file())
+}
+
+/// How often to check a run folder, and how long it can go without a new cycle
+/// before the run is taken to have stalled or been stopped
+#[derive(Debug, Clone, Copy)]
+pub struct WatchOptions {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// The time since the run last made progress
+struct Stall {
+    progress: usize,
+    since: Instant,
+    timeout: Duration,
+}
+
+impl Stall {
+    fn new(timeout: Duration) -> Stall {
+        Stall {
+            progress: 0,
+            since: Instant::now(),
+            timeout,
+        }
+    }
+
+    /// Note the run's progress, failing if it hasn't changed for the timeout
+    fn check(&mut self, progress: usize, waiting_for: &str) -> io::Result<()> {
+        if progress != self.progress {
+            self.progress = progress;
+            self.since = Instant::now();
+        } else if self.since.elapsed() >= self.timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "gave up waiting for {} after {}s without a new cycle",
+                    waiting_for,
+                    self.timeout.as_secs()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Check until RunInfo.xml exists and all of the index cycles have been written
+pub fn wait_for_index_cycles(run_path: &Path, watch: WatchOptions) -> io::Result<()> {
+    let mut stall = Stall::new(watch.timeout);
+    let run_info_path = run_path.join("RunInfo.xml");
+    while !run_info_path.is_file() {
+        debug!("waiting for {}", run_info_path.display());
+        stall.check(0, "RunInfo.xml")?;
+        thread::sleep(watch.interval);
+    }
+
+    let run_info = parse_run_info(&run_info_path)?;
+    let index_end = ReadStructure::new(&run_info.reads).index_end() - 1;
+
+    loop {
+        let n_cycles = completed_cycles(run_path, &run_info);
+        if n_cycles >= index_end {
+            info!("index cycles are complete ({} cycles written)", n_cycles);
+            return Ok(());
+        }
+
+        debug!("{} of {} index cycles written", n_cycles, index_end);
+        stall.check(n_cycles, "the index cycles")?;
+        thread::sleep(watch.interval);
+    }
+}
+
+/// Check until the run is finished
+pub fn wait_for_completion(run_path: &Path, watch: WatchOptions) -> io::Result<()> {
+    let mut stall = Stall::new(watch.timeout);
+    let run_info = parse_run_info(&run_path.join("RunInfo.xml"))?;
+    while !is_complete(run_path) {
+        debug!("waiting for run to complete");
+        stall.check(completed_cycles(run_path, &run_info), "the run to complete")?;
+        thread::sleep(watch.interval);
+    }
+
+    info!("run is complete");
+    Ok(())
+}
+
+/// Decode the index cycles of every tile of a run loaded with only its indices into
+/// the index cache, so that the demux only decodes the template cycles once the run
+/// is finished. Tiles that are already cached are skipped. Returns the number of
+/// tiles cached. `truncate_indices` is the demux's, which the tiles are keyed by
+pub fn cache_index_cycles(
+    index_run: &NovaSeqRun,
+    cache_path: &Path,
+    truncate_indices: bool,
+) -> io::Result<usize> {
+    let idx_slices = index_slices(index_run);
+    let cache_key = index_cache::cache_key(index_run, truncate_indices);
+    let mut n_cached = 0;
+
+    for (&[lane, surface], tile_ids) in &index_run.tile_ids {
+        let filters = &index_run.filters[&[lane, surface]];
+        let pf_filters = &index_run.pf_filters[&[lane, surface]];
+        let n_pfs = &index_run.n_pfs[&[lane, surface]];
+        let idx_headers = &index_run.index_headers[&[lane, surface]];
+
+        n_cached += tile_ids
+            .par_iter()
+            .enumerate()
+            .map(|(tile_i, &tile)| {
+                let path = index_cache::tile_path(cache_path, &cache_key, lane, surface, tile);
+                if path.is_file() {
+                    return Ok(0);
+                }
+
+                let mut index_array = index_buffer(index_run, n_pfs[tile_i]);
+                let compaction = TileCompaction::new(&filters[tile_i], &pf_filters[tile_i]);
+                for (idx_vec, &[idx_0, idx_1]) in idx_headers.iter().zip(&idx_slices) {
+                    let mut idx_array = index_array.slice_mut(s![idx_0..idx_1, .., ..]);
+                    for (mut byte_array, idx_h) in idx_array.axis_iter_mut(Axis(0)).zip(idx_vec) {
+                        // a tile that can't be read is left for the demux to decode
+                        if let Err(e) = extract_cbcl(
+                            idx_h,
+                            compaction.for_header(idx_h),
+                            &mut byte_array,
+                            tile_i,
+                        ) {
+                            warn!(
+                                "error reading tile {} from {}: {}",
+                                tile,
+                                idx_h.cbcl_path.display(),
+                                e
+                            );
+                            return Ok(0);
+                        }
+                    }
+                }
+
+                index_cache::write_tile(&path, &index_array.view())?;
+                Ok(1)
+            })
+            .sum::<io::Result<usize>>()?;
+    }
+
+    info!("cached the index cycles of {} tiles", n_cached);
+    Ok(n_cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn wait_for_index_cycles() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let watch = WatchOptions {
+            interval: Duration::from_millis(1),
+            timeout: Duration::from_millis(50),
+        };
+        super::wait_for_index_cycles(&run_path, watch).unwrap();
+
+        assert!(!is_complete(&run_path));
+        let e = wait_for_completion(&run_path, watch).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,107 @@
+//! Notifies other systems (e.g. a LIMS) when a run finishes, by POSTing the run
+//! summary as JSON to a URL
+
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::run_summary::{RunStatus, RunSummary};
+
+/// The longest to wait to connect to a webhook
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest to wait on each read or write of the request, so that a webhook that
+/// never answers doesn't keep a finished run from exiting
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An HTTP agent that gives up after the timeouts
+fn agent(connect_timeout: Duration, io_timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(connect_timeout)
+        .timeout_read(io_timeout)
+        .timeout_write(io_timeout)
+        .build()
+}
+
+/// URLs to notify when a run completes or fails
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Webhooks {
+    /// notified when a run finishes, and also on failure if `on_failure` is unset
+    pub on_complete: Option<String>,
+    /// notified when a run fails
+    pub on_failure: Option<String>,
+}
+
+impl Webhooks {
+    /// the URL to notify for a run that ended with `status`, if any
+    fn url_for(&self, status: RunStatus) -> Option<&str> {
+        match status {
+            RunStatus::Success | RunStatus::PartialSuccess => self.on_complete.as_deref(),
+            _ => self.on_failure.as_deref().or(self.on_complete.as_deref()),
+        }
+    }
+
+    /// POST the run summary to the appropriate URL. A failed notification is only a
+    /// warning, since the run itself is already finished
+    pub fn notify(&self, summary: &RunSummary, status: RunStatus) {
+        let url = match self.url_for(status) {
+            Some(url) => url,
+            None => return,
+        };
+
+        let body = serde_json::to_string(summary).unwrap();
+
+        match agent(CONNECT_TIMEOUT, IO_TIMEOUT)
+            .post(url)
+            .set("Content-Type", "application/json")
+            .send_string(&body)
+        {
+            Ok(_) => info!("notified {}", url),
+            Err(e) => warn!("error notifying {}: {}", url, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_for() {
+        let webhooks = Webhooks {
+            on_complete: Some("http://done".to_string()),
+            on_failure: None,
+        };
+        assert_eq!(webhooks.url_for(RunStatus::Success), Some("http://done"));
+        assert_eq!(
+            webhooks.url_for(RunStatus::BasecallError),
+            Some("http://done")
+        );
+
+        let webhooks = Webhooks {
+            on_complete: None,
+            on_failure: Some("http://failed".to_string()),
+        };
+        assert_eq!(webhooks.url_for(RunStatus::PartialSuccess), None);
+        assert_eq!(
+            webhooks.url_for(RunStatus::SamplesheetError),
+            Some("http://failed")
+        );
+    }
+
+    #[test]
+    fn timeout() {
+        // a webhook that takes the connection but never answers
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!("http://{}/done", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || listener.accept().unwrap());
+
+        let start = std::time::Instant::now();
+        let result = agent(Duration::from_secs(1), Duration::from_millis(200))
+            .post(&url)
+            .send_string("{}");
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(handle.join().unwrap());
+    }
+}
@@ -1,28 +1,570 @@
 //! Extract the reads from a run and write them out to fastq.gz files
 
 use std::{
-    collections::HashMap,
-    fs::{create_dir, File, OpenOptions},
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+    fs::{create_dir, File},
     io::prelude::*,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
-use flate2::write::GzEncoder;
-use log::{debug, info};
-use ndarray::{Array3, ArrayView3, Axis, ShapeBuilder};
+use log::{debug, info, warn};
+use ndarray::{Array3, ArrayView1, ArrayView2, ArrayView3, ArrayViewMut3, Axis, ShapeBuilder};
 use rayon::prelude::*;
 
-use crate::extract_reads::extract_cbcl;
-use crate::novaseq_run::NovaSeqRun;
-use crate::sample_data::Samples;
+use crate::barcode_matcher::{BarcodeMatcher, HammingMatcher, MatcherHandle};
+use crate::buffer_pool::{buffer_pool, PooledBuffer, PooledWriter};
+use crate::cbcl_header_decoder::CBCLHeader;
+use crate::compression_workers::CompressionWorkers;
+use crate::extract_reads::{extract_cbcl, extract_cbcls, TileCompaction};
+use crate::index_cache;
+use crate::logging::{clear_context, set_context};
+use crate::novaseq_run::{NovaSeqRun, Shard};
+use crate::output_sink::{Encoder, FileSink, LruFileSink, OutputSink, SinkHandle};
+use crate::overlap::{overlap_pair, OverlapOptions, PairOverlap};
+use crate::progress::Progress;
+use crate::read_name::ReadName;
+use crate::sample_data::{SampleData, Samples};
+use crate::screen::{is_adapter_dimer, Screen};
+use crate::sequencer::Sequencer;
+use crate::shutdown::shutdown_requested;
+use crate::stats::{CycleMetrics, LaneStats, ReadMetrics};
+use crate::storage::OutputHandle;
+use crate::thread_pools::{in_stage, largest_first, Stage};
+use crate::umi::{is_valid_umi, UmiOptions};
+use crate::zstd_dictionary::{ZstdDictionary, DICTIONARY_NAME};
+
+/// The sample that a read was assigned to and the number of mismatches in its
+/// indices, or `None` if it didn't match any sample
+pub(crate) type Assignment = Option<(usize, usize)>;
+
+/// Controls the format of the output files: whether they are gzipped, and whether
+/// they contain full fastq records or just the sequence of each read
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputOptions {
+    /// gzip compression level, or `None` to write uncompressed text
+    pub compression: Option<u32>,
+    /// write one sequence per line, with no read headers or quality scores
+    pub seq_only: bool,
+    /// the part of the run that this invocation processes, if it is one of several
+    pub shard: Option<Shard>,
+    /// size in bytes of the buffer for writing each output file
+    pub write_buffer: usize,
+    /// trim a run of at least this many Gs from the end of each read, or 0 to
+    /// write reads untrimmed
+    pub trim_poly_g: usize,
+    /// a comment to add to the end of every read header, e.g. provenance
+    pub header_comment: Option<String>,
+    /// upload the output files here as they are written, instead of writing them
+    /// to the output path
+    pub upload: Option<OutputHandle>,
+    /// write the output files to this sink instead of the output path, e.g. to
+    /// named pipes. An upload is its own sink
+    pub sink: Option<SinkHandle>,
+    /// compress as BGZF blocks instead of one gzip member for each chunk of reads
+    pub bgzf: bool,
+    /// compress with zstd at the compression level instead of gzip, as a zstd frame
+    /// for each chunk of reads. Needs the `zstd` feature
+    pub zstd: bool,
+    /// prime every zstd frame with this dictionary, which is needed to decompress
+    /// them again
+    pub zstd_dictionary: Option<Arc<ZstdDictionary>>,
+    /// assign every cluster on a surface from its index cycles first, then read the
+    /// template cycles of the assigned clusters only. Undetermined reads aren't
+    /// decoded, so their yield and quality aren't in the stats, unless
+    /// `two_phase_undetermined` is set
+    pub two_phase: bool,
+    /// with `two_phase`, read the undetermined clusters in the second pass as well,
+    /// for their stats, the screen and the PhiX reads
+    pub two_phase_undetermined: bool,
+    /// compress the output files on threads of their own, fed through a bounded
+    /// queue for each file, instead of on the threads that format the reads
+    pub compression_workers: bool,
+    /// the most blocks of formatted reads waiting for each compression worker,
+    /// before the threads writing to its file wait
+    pub queue_depth: usize,
+    /// share the output files between this many compression workers, each taking
+    /// a group of them, instead of one for each thread of the rayon pool
+    pub writer_groups: Option<usize>,
+    /// unpack the bases and match the barcodes on the GPU, if bcl2fastr was built
+    /// with the `gpu` feature and there is one
+    pub gpu: bool,
+    /// stop with an error if too many of a lane's reads are undetermined, which
+    /// usually means the samplesheet is wrong
+    pub undetermined_limit: Option<UndeterminedLimit>,
+    /// keep the index reads of each tile in this directory, and use the ones that
+    /// are already there instead of decoding the index cycles again
+    pub index_cache: Option<PathBuf>,
+    /// template reads (numbered from 1, as in R1) that are not written out or
+    /// even decoded
+    pub skip_reads: BTreeSet<usize>,
+    /// match the indices to samples with this instead of the hamming sets from the
+    /// samplesheet. The GPU is only used for the hamming sets
+    pub matcher: Option<MatcherHandle>,
+    /// the ASCII code for Q0 in the quality lines: 33 for phred+33, or 64 for tools
+    /// that still expect phred+64
+    pub ascii_offset: u8,
+    /// drop the clusters with a low quality or too many Ns in one of the reads that
+    /// are written, or write them to their own files
+    pub read_filter: Option<ReadFilter>,
+    /// also write the clusters that failed filter, for runs whose CBCL files still
+    /// have them. The run has to be set up with `NovaSeqRun::include_non_pf`
+    pub non_pf: Option<NonPfOutput>,
+    /// add to the output files that are already there instead of replacing them,
+    /// for the second and later run folders of the same libraries
+    pub append: bool,
+    /// count the undetermined reads that look like the PhiX spike-in on their own,
+    /// and write them to their own files if asked to
+    pub phix: Option<PhixControl>,
+    /// screen some of the undetermined reads in each lane, to see what they are
+    pub screen: Option<Screen>,
+    /// count the reads of each sample whose first written read starts with an
+    /// adapter, and write them to their own files if asked to
+    pub adapter_dimers: Option<DimerOutput>,
+    /// with two written reads, find where they overlap, to correct the bases where
+    /// they disagree with the better call or to trim read-through into the adapter
+    pub overlap: Option<OverlapOptions>,
+    /// the index read with the UMIs, which isn't matched to the samples, and
+    /// whether the UMIs are written to their own files or masked
+    pub umi: Option<UmiOptions>,
+    /// write the reads of every sample to one file for each read, with the sample
+    /// in each read name as `SM:Z:<sample>`, for tools that split them themselves
+    pub single_file: bool,
+    /// put each sample's number in its file names as bcl2fastq does, e.g.
+    /// `sample_1_S1_L001_R1.fastq.gz`
+    pub sample_numbers: bool,
+    /// match only the first bases of index reads that are longer than the
+    /// samplesheet's indices
+    pub truncate_indices: bool,
+}
+
+/// Where the clusters that failed filter are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonPfOutput {
+    /// with their sample's reads, with a Y in the filter field of the read name
+    Inline,
+    /// to `<sample>_R1_nonPF.fastq.gz` etc, next to their sample's files
+    Separate,
+}
+
+impl FromStr for NonPfOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inline" => Ok(NonPfOutput::Inline),
+            "separate" => Ok(NonPfOutput::Separate),
+            _ => Err(format!("expected inline or separate, got '{}'", s)),
+        }
+    }
+}
+
+/// What is done with the adapter dimers of each sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DimerOutput {
+    /// counted in the stats, and written with their sample's reads
+    Count,
+    /// counted, and written to `<sample>_R1_dimer.fastq.gz` etc instead
+    Separate,
+}
+
+impl FromStr for DimerOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(DimerOutput::Count),
+            "separate" => Ok(DimerOutput::Separate),
+            _ => Err(format!("expected count or separate, got '{}'", s)),
+        }
+    }
+}
+
+/// The most reads in a lane that can be undetermined, checked once enough tiles
+/// have been assigned to tell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UndeterminedLimit {
+    /// the largest fraction of reads passing filter that can be undetermined
+    pub max_fraction: f64,
+    /// the number of tiles of a lane to assign before checking
+    pub after_tiles: usize,
+}
+
+/// The error when a lane goes over the undetermined limit. It comes back inside an
+/// `std::io::Error`, so use `is_undetermined_limit` to tell it from the others
+#[derive(Debug)]
+pub struct UndeterminedLimitError(String);
+
+impl fmt::Display for UndeterminedLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UndeterminedLimitError {}
+
+/// Whether `e` is a lane going over the undetermined limit, which most likely
+/// means the samplesheet is wrong for the run
+pub fn is_undetermined_limit(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<UndeterminedLimitError>())
+}
+
+/// The reads to filter out of the output. A cluster is filtered if any of its
+/// written reads fails, so that paired reads stay in step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadFilter {
+    /// the lowest mean quality score that a read can have
+    pub min_mean_quality: Option<f64>,
+    /// the largest fraction of a read's bases that can be N
+    pub max_n_fraction: Option<f64>,
+    /// write the filtered clusters to `Filtered` files instead of dropping them
+    pub keep_filtered: bool,
+}
+
+/// How the PhiX spike-in is told apart from the other undetermined reads. PhiX
+/// usually has no index, so its first index has no signal, which reads as all G
+/// on two-color instruments. An indexed PhiX is found by its index instead
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PhixControl {
+    /// the indices of an indexed PhiX, each with the second index if it has one
+    pub indices: Vec<Vec<Vec<u8>>>,
+    /// write the PhiX reads to `PhiX_R1.fastq.gz` etc, instead of dropping them
+    pub write: bool,
+}
+
+impl PhixControl {
+    /// Whether the indices of an undetermined read are those of PhiX
+    pub(crate) fn matches(&self, indices: &[ArrayView1<u8>]) -> bool {
+        if indices
+            .first()
+            .is_some_and(|index| !index.is_empty() && index.iter().all(|&b| b == b'G'))
+        {
+            return true;
+        }
+
+        self.indices.iter().any(|phix_indices| {
+            phix_indices.iter().zip(indices).all(|(phix_index, index)| {
+                phix_index.len() <= index.len()
+                    && phix_index
+                        .iter()
+                        .zip(index.iter())
+                        .filter(|(a, b)| a != b)
+                        .count()
+                        <= PHIX_MISMATCHES
+            })
+        })
+    }
+}
+
+/// Parse a PhiX index such as `ACGTACGT` or `ACGTACGT+TTGGCCAA`
+pub fn parse_phix_index(s: &str) -> Result<Vec<Vec<u8>>, String> {
+    s.split('+')
+        .map(|index| {
+            if index.is_empty() || !index.bytes().all(|b| b"ACGTN".contains(&b)) {
+                Err(format!(
+                    "expected an index like ACGTACGT or ACGTACGT+TTGGCCAA, got '{}'",
+                    s
+                ))
+            } else {
+                Ok(index.as_bytes().to_vec())
+            }
+        })
+        .collect()
+}
+
+impl ReadFilter {
+    /// Whether a read with these bases and quality scores (phred+33) passes
+    pub(crate) fn passes(&self, seq: &[u8], qscores: &[u8]) -> bool {
+        if seq.is_empty() {
+            return true;
+        }
+
+        if let Some(min_mean_quality) = self.min_mean_quality {
+            let qscore_sum: u64 = qscores
+                .iter()
+                .map(|&q| q.saturating_sub(PHRED_33) as u64)
+                .sum();
+            if (qscore_sum as f64) < min_mean_quality * qscores.len() as f64 {
+                return false;
+            }
+        }
+
+        if let Some(max_n_fraction) = self.max_n_fraction {
+            let n_count = seq.iter().filter(|&&b| b == b'N').count();
+            if n_count as f64 > max_n_fraction * seq.len() as f64 {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The name of the files that filtered clusters are written to
+pub(crate) const FILTERED_NAME: &str = "Filtered";
+
+/// The name of the files that every sample's reads are written to with `single_file`
+pub(crate) const ALL_SAMPLES_NAME: &str = "All";
+
+/// The name of the files that PhiX reads are written to
+pub(crate) const PHIX_NAME: &str = "PhiX";
+
+/// The most mismatches in each index for a read to match a PhiX index
+const PHIX_MISMATCHES: usize = 1;
+
+/// Added to the name of a sample's files for the clusters that failed filter
+pub(crate) const NON_PF_SUFFIX: &str = "_nonPF";
+
+/// Added to the name of a sample's files for its adapter dimers
+pub(crate) const DIMER_SUFFIX: &str = "_dimer";
+
+/// Takes the place of the read number in the name of the files with the UMIs
+pub(crate) const UMI_READ: &str = "_UMI";
+
+/// The quality score of the bases of a masked UMI, Q2 as with bcl2fastq's masked
+/// bases
+const MASKED_QSCORE: u8 = PHRED_33 + 2;
+
+/// The number of unknown barcodes to list when a lane is over the undetermined limit
+const LIMIT_UNKNOWN_BARCODES: usize = 5;
+
+impl UndeterminedLimit {
+    /// An error if too many of the reads in `stats` are undetermined, with the
+    /// unknown barcodes that were seen most often
+    fn check(&self, stats: &LaneStats) -> std::io::Result<()> {
+        let n_reads = stats.number_reads();
+        if n_reads == 0 {
+            return Ok(());
+        }
+
+        let fraction = stats.undetermined.number_reads as f64 / n_reads as f64;
+        info!(
+            "{:.1}% of reads in the first {} tiles of lane {} are undetermined",
+            fraction * 100.,
+            stats.tile_stats.len(),
+            stats.lane_number
+        );
+        if fraction <= self.max_fraction {
+            return Ok(());
+        }
+
+        let barcodes: Vec<_> = stats
+            .top_unknown_barcodes(LIMIT_UNKNOWN_BARCODES)
+            .into_iter()
+            .map(|(barcode, count)| format!("{} ({})", barcode, count))
+            .collect();
+
+        Err(std::io::Error::other(UndeterminedLimitError(format!(
+            "{:.1}% of reads in the first {} tiles of lane {} are undetermined, more than the limit of {:.1}%. Check the samplesheet and index orientation. Most common unknown barcodes: {}",
+            fraction * 100.,
+            stats.tile_stats.len(),
+            stats.lane_number,
+            self.max_fraction * 100.,
+            barcodes.join(", ")
+        ))))
+    }
+}
+
+/// The default size of the write buffer for each output file, the same as `BufWriter`
+pub const DEFAULT_WRITE_BUFFER: usize = 8 * 1024;
+
+/// The default number of blocks waiting for each compression worker
+pub const DEFAULT_QUEUE_DEPTH: usize = 4;
+
+/// The size of the buffer that reads are formatted into before they are compressed,
+/// so that the compressor is given large blocks instead of one line at a time
+const FORMAT_BUFFER: usize = 64 * 1024;
+
+/// The ASCII offset of the quality scores as they are decoded
+pub const PHRED_33: u8 = 33;
+
+/// The last printable ASCII character, and so the highest quality score character
+const MAX_QSCORE_CHAR: u32 = b'~' as u32;
+
+/// Check that every quality score in the run's CBCL bins can be written as a
+/// printable character with `ascii_offset`, naming a file whose bins can't
+pub fn check_ascii_offset(novaseq_run: &NovaSeqRun, ascii_offset: u8) -> Result<(), String> {
+    if ascii_offset < PHRED_33 {
+        return Err(format!(
+            "an ASCII offset of {} would write unprintable characters",
+            ascii_offset
+        ));
+    }
+
+    let headers = novaseq_run
+        .read_headers
+        .values()
+        .chain(novaseq_run.index_headers.values())
+        .flatten()
+        .flatten();
+
+    for header in headers {
+        if let Some(&bin) = header.bins.iter().max() {
+            let phred = (bin - PHRED_33) as u32;
+            if phred + ascii_offset as u32 > MAX_QSCORE_CHAR {
+                return Err(format!(
+                    "the quality bins of {} go up to Q{}, which is past '~' with an ASCII offset of {}",
+                    header.cbcl_path.display(),
+                    phred,
+                    ascii_offset
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The shortest run of Gs that is suggested for trimming on two-color instruments,
+/// where G means there was no signal
+pub const DEFAULT_POLY_G_LENGTH: usize = 10;
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            compression: Some(1),
+            seq_only: false,
+            shard: None,
+            write_buffer: DEFAULT_WRITE_BUFFER,
+            trim_poly_g: 0,
+            header_comment: None,
+            upload: None,
+            sink: None,
+            bgzf: false,
+            zstd: false,
+            zstd_dictionary: None,
+            two_phase: false,
+            two_phase_undetermined: false,
+            compression_workers: false,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            writer_groups: None,
+            gpu: false,
+            undetermined_limit: None,
+            index_cache: None,
+            skip_reads: BTreeSet::new(),
+            matcher: None,
+            ascii_offset: PHRED_33,
+            read_filter: None,
+            non_pf: None,
+            append: false,
+            phix: None,
+            screen: None,
+            adapter_dimers: None,
+            overlap: None,
+            umi: None,
+            single_file: false,
+            sample_numbers: false,
+            truncate_indices: true,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// true if template read `read_num` (from 1) is written out
+    pub fn writes_read(&self, read_num: usize) -> bool {
+        !self.skip_reads.contains(&read_num)
+    }
+
+    /// the file extension for output files written with these options
+    pub(crate) fn extension(&self) -> String {
+        let extension = match (self.seq_only, self.compression, self.zstd) {
+            (false, Some(_), false) => "fastq.gz",
+            (false, Some(_), true) => "fastq.zst",
+            (false, None, _) => "fastq",
+            (true, Some(_), false) => "seq.gz",
+            (true, Some(_), true) => "seq.zst",
+            (true, None, _) => "seq",
+        };
+
+        match &self.shard {
+            Some(shard) => format!("{}.{}", shard.suffix(), extension),
+            None => extension.to_string(),
+        }
+    }
+
+    /// where the output files are written: the sink if there is one, then the
+    /// upload, or else the files in the output path
+    pub fn sink(&self) -> &dyn OutputSink {
+        match (&self.sink, &self.upload) {
+            (Some(sink), _) => &**sink,
+            (None, Some(upload)) => upload,
+            (None, None) => &FileSink,
+        }
+    }
+
+    /// a sink for writing the tiles of a chunk to each file one after another, which
+    /// keeps up to `max_open` files open between their tiles, if the files would
+    /// otherwise be opened again for every tile
+    pub(crate) fn tile_sink(&self, max_open: usize) -> Option<LruFileSink> {
+        match (&self.sink, &self.upload) {
+            (None, None) => Some(LruFileSink::new(max_open)),
+            _ => None,
+        }
+    }
+
+    /// true if the fastq files end up in the output path, where they can be
+    /// checksummed for the manifests
+    pub fn in_output_folder(&self) -> bool {
+        match (&self.sink, &self.upload) {
+            (Some(sink), _) => sink.in_output_folder(),
+            (None, upload) => upload.is_none(),
+        }
+    }
+
+    /// the name of sample `i`'s files, numbered if asked for
+    pub(crate) fn sample_file_name(&self, samples: &Samples, i: usize) -> String {
+        if self.sample_numbers {
+            samples.numbered_name(i)
+        } else {
+            samples.sample_names[i].clone()
+        }
+    }
+}
+
+/// Write a copy of the zstd dictionary, if there is one, in the output folder and
+/// in each project's folder, so that a project's files can be decompressed once
+/// they are handed over on their own. The project copies go through the sink, like
+/// the fastq files, and the one in the output folder is uploaded with the reports
+pub fn write_zstd_dictionary(
+    output_path: &Path,
+    sample_data: &SampleData,
+    output_options: &OutputOptions,
+) -> std::io::Result<()> {
+    let dictionary = match &output_options.zstd_dictionary {
+        Some(dictionary) => dictionary,
+        None => return Ok(()),
+    };
+    std::fs::write(output_path.join(DICTIONARY_NAME), dictionary.bytes())?;
+
+    let projects: BTreeSet<_> = sample_data
+        .values()
+        .flat_map(|samples| samples.project_names.iter().flatten())
+        .collect();
+    for project in projects {
+        output_options.sink().put(
+            &output_path.join(project).join(DICTIONARY_NAME),
+            dictionary.bytes(),
+        )?;
+    }
+
+    Ok(())
+}
 
 /// produce the correct filename format, depending on whether we are splitting lanes
-fn make_filename(
-    output_path: &PathBuf,
-    sample_name: &String,
+pub(crate) fn make_filename(
+    output_path: &Path,
+    sample_name: &str,
     sample_project: &Option<String>,
     lane: usize,
     read_num: usize,
+    extension: &str,
 ) -> std::io::Result<PathBuf> {
     let sample_path = match sample_project {
         Some(project_name) => output_path.join(project_name),
@@ -34,212 +576,1137 @@ fn make_filename(
     }
 
     if lane == 0 {
-        Ok(sample_path.join(format!("{}_R{}.fastq.gz", sample_name, read_num)))
+        Ok(sample_path.join(format!("{}_R{}.{}", sample_name, read_num, extension)))
     } else {
         Ok(sample_path.join(format!(
-            "{}_L{:03}_R{}.fastq.gz",
-            sample_name, lane, read_num
+            "{}_L{:03}_R{}.{}",
+            sample_name, lane, read_num, extension
         )))
     }
 }
 
 /// helper function to construct the report filename, depending on lane splitting
-fn make_report_filename(output_path: &PathBuf, lane: usize) -> PathBuf {
+/// and on whether this is one shard of the run
+fn make_report_filename(output_path: &Path, lane: usize, shard: &Option<Shard>) -> PathBuf {
+    let shard_suffix = match shard {
+        Some(shard) => format!(".{}", shard.suffix()),
+        None => String::new(),
+    };
+
     if lane == 0 {
-        output_path.join(format!("barcode_report.txt"))
+        output_path.join(format!("barcode_report{}.txt", shard_suffix))
     } else {
-        output_path.join(format!("barcode_L{:03}_report.txt", lane))
+        output_path.join(format!("barcode_L{:03}_report{}.txt", lane, shard_suffix))
     }
 }
 
-/// remove any existing output files
-fn get_sample_filepaths(
+/// the file for a sample's clusters with `suffix`, e.g. those that failed filter,
+/// next to `file_path`
+fn suffixed_filename(file_path: &Path, suffix: &str, extension: &str) -> PathBuf {
+    let file_name = file_path.file_name().unwrap().to_string_lossy();
+    let stem = file_name.strip_suffix(&format!(".{}", extension)).unwrap();
+
+    file_path.with_file_name(format!("{}{}.{}", stem, suffix, extension))
+}
+
+/// the file for the UMIs of the reads in `file_path`, which are read `read_num`
+pub(crate) fn umi_filename(file_path: &Path, read_num: usize) -> PathBuf {
+    let file_name = file_path.file_name().unwrap().to_string_lossy();
+    let read = format!("_R{}", read_num);
+    let i = file_name.rfind(&read).unwrap();
+
+    file_path.with_file_name(format!(
+        "{}{}{}",
+        &file_name[..i],
+        UMI_READ,
+        &file_name[i + read.len()..]
+    ))
+}
+
+/// The output files for a lane (or every lane, for lane 0), by read and then sample
+/// as the reads are written: the samples' files first, then the other files they
+/// have, and the UMIs last
+pub(crate) fn lane_filepaths(
     novaseq_run: &NovaSeqRun,
     samples: &Samples,
     lane_n: usize,
-    output_path: &PathBuf,
+    output_path: &Path,
+    output_options: &OutputOptions,
 ) -> std::io::Result<Vec<Vec<PathBuf>>> {
-    let num_reads = novaseq_run
-        .run_info
-        .reads
-        .iter()
-        .map(|r| if r.is_indexed_read { 0 } else { 1 })
-        .sum();
+    let num_reads = novaseq_run.read_structure.n_templates();
+    let extension = output_options.extension();
 
     let mut sample_filepaths = Vec::new();
-    let mut removed_files = 0;
 
     for read_num in 1..=num_reads {
         let mut read_filepaths = Vec::new();
+        // skipped reads have no files
+        if !output_options.writes_read(read_num) {
+            sample_filepaths.push(read_filepaths);
+            continue;
+        }
 
-        for (sample_name, sample_project) in samples
-            .sample_names
-            .iter()
-            .zip(samples.project_names.iter())
-        {
-            let file_path =
-                make_filename(output_path, sample_name, sample_project, lane_n, read_num)?;
+        // the samples share one file, or each has its own
+        let sample_names: Vec<_> = if output_options.single_file {
+            vec![(ALL_SAMPLES_NAME.to_string(), None)]
+        } else {
+            (0..samples.sample_names.len())
+                .map(|i| {
+                    (
+                        output_options.sample_file_name(samples, i),
+                        samples.project_names[i].clone(),
+                    )
+                })
+                .collect()
+        };
+        for (sample_name, sample_project) in sample_names.iter() {
+            let file_path = make_filename(
+                output_path,
+                sample_name,
+                sample_project,
+                lane_n,
+                read_num,
+                &extension,
+            )?;
+            read_filepaths.push(file_path);
+        }
 
-            if file_path.exists() {
-                std::fs::remove_file(&file_path)?;
-                removed_files += 1;
+        // the filtered clusters go after the samples
+        if output_options.read_filter.is_some_and(|f| f.keep_filtered) {
+            let file_path = make_filename(
+                output_path,
+                FILTERED_NAME,
+                &None,
+                lane_n,
+                read_num,
+                &extension,
+            )?;
+            read_filepaths.push(file_path);
+        }
+
+        // then the clusters that failed filter, if they have their own files
+        if output_options.non_pf == Some(NonPfOutput::Separate) {
+            for (i, sample_project) in samples.project_names.iter().enumerate() {
+                let file_path = make_filename(
+                    output_path,
+                    &output_options.sample_file_name(samples, i),
+                    sample_project,
+                    lane_n,
+                    read_num,
+                    &extension,
+                )?;
+                let file_path = suffixed_filename(&file_path, NON_PF_SUFFIX, &extension);
+                read_filepaths.push(file_path);
             }
+        }
+
+        // then the PhiX reads
+        if output_options.phix.as_ref().is_some_and(|phix| phix.write) {
+            let file_path =
+                make_filename(output_path, PHIX_NAME, &None, lane_n, read_num, &extension)?;
             read_filepaths.push(file_path);
         }
+
+        // and last the adapter dimers, if they have their own files
+        if output_options.adapter_dimers == Some(DimerOutput::Separate) {
+            for (i, sample_project) in samples.project_names.iter().enumerate() {
+                let file_path = make_filename(
+                    output_path,
+                    &output_options.sample_file_name(samples, i),
+                    sample_project,
+                    lane_n,
+                    read_num,
+                    &extension,
+                )?;
+                let file_path = suffixed_filename(&file_path, DIMER_SUFFIX, &extension);
+                read_filepaths.push(file_path);
+            }
+        }
         sample_filepaths.push(read_filepaths);
     }
 
-    debug!("removed {} files", removed_files);
+    // the UMIs go after the reads, in a file for each of the first written read's
+    if output_options.umi.is_some_and(|umi| umi.write) {
+        let (k, read_filepaths) = sample_filepaths
+            .iter()
+            .enumerate()
+            .find(|(_, read_filepaths)| !read_filepaths.is_empty())
+            .unwrap();
+        let umi_filepaths: Vec<_> = read_filepaths
+            .iter()
+            .map(|file_path| umi_filename(file_path, k + 1))
+            .collect();
+        sample_filepaths.push(umi_filepaths);
+    }
 
     Ok(sample_filepaths)
 }
 
-/// iterate over the index arrays and count up all the reads that match sample data,
-/// keeping track of whether they are an exact match to the index or not
-fn count_reads(
+/// Where the clusters that don't go to their sample's own file are written, as
+/// the assignment of each read's files in the order of `lane_filepaths`. With a
+/// single file, `single_file_assignments` moves them up after the shared file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileSlots {
+    /// the samples' files come first, one each
+    n_samples: usize,
+    /// the file of the filtered clusters
+    filtered: Option<usize>,
+    /// the first of the files of the clusters that failed filter, one per sample
+    non_pf: Option<usize>,
+    /// the file of the PhiX clusters
+    phix: Option<usize>,
+    /// the first of the files of the adapter dimers, one per sample
+    dimers: Option<usize>,
+}
+
+impl FileSlots {
+    fn new(n_samples: usize, output_options: &OutputOptions) -> FileSlots {
+        let mut next = n_samples;
+        let mut slot = |used: bool, n_files: usize| {
+            used.then(|| {
+                next += n_files;
+                next - n_files
+            })
+        };
+
+        FileSlots {
+            n_samples,
+            filtered: slot(
+                output_options.read_filter.is_some_and(|f| f.keep_filtered),
+                1,
+            ),
+            non_pf: slot(
+                output_options.non_pf == Some(NonPfOutput::Separate),
+                n_samples,
+            ),
+            phix: slot(output_options.phix.as_ref().is_some_and(|p| p.write), 1),
+            dimers: slot(
+                output_options.adapter_dimers == Some(DimerOutput::Separate),
+                n_samples,
+            ),
+        }
+    }
+}
+
+/// The output files of a lane by read, and where each kind of cluster goes in them
+struct LaneFiles {
+    paths: Vec<Vec<PathBuf>>,
+    slots: FileSlots,
+}
+
+/// The output files for a lane, after removing any that are left from an earlier
+/// demux
+fn get_sample_filepaths(
+    novaseq_run: &NovaSeqRun,
     samples: &Samples,
-    sample_i: usize,
+    lane_n: usize,
+    output_path: &Path,
+    output_options: &OutputOptions,
+) -> std::io::Result<LaneFiles> {
+    let sample_filepaths =
+        lane_filepaths(novaseq_run, samples, lane_n, output_path, output_options)?;
+
+    if !output_options.append {
+        let sink = output_options.sink();
+        let mut removed_files = 0;
+        for file_path in sample_filepaths.iter().flatten() {
+            if sink.clear(file_path)? {
+                removed_files += 1;
+            }
+        }
+        debug!("removed {} files", removed_files);
+    }
+
+    Ok(LaneFiles {
+        paths: sample_filepaths,
+        slots: FileSlots::new(samples.sample_names.len(), output_options),
+    })
+}
+
+/// iterate over the index array and find the sample that matches each read, if any,
+/// keeping track of how many mismatches there were with the sample index
+pub(crate) fn assign_reads(
+    samples: &Samples,
+    matcher: &dyn BarcodeMatcher,
     n_pf: usize,
     index_array: &ArrayView3<u8>,
     index_slices: &[[usize; 2]],
-) -> [u64; 2] {
-    let read_count = index_array
+) -> Vec<Assignment> {
+    index_array
+        .slice(ndarray::s![.., ..n_pf, ..])
         .axis_iter(Axis(1))
-        .take(n_pf)
-        .par_bridge()
-        .filter_map(|ix_row| {
+        .into_par_iter()
+        .map(|ix_row| {
             let indices: Vec<_> = index_slices
                 .iter()
                 .cloned()
                 .map(|[i0, i1]| ix_row.slice(ndarray::s![i0..i1, 0]))
                 .collect();
+            let qscores: Vec<_> = index_slices
+                .iter()
+                .cloned()
+                .map(|[i0, i1]| ix_row.slice(ndarray::s![i0..i1, 1]))
+                .collect();
 
-            if samples.get_sample(sample_i, &indices) {
-                if !samples.is_exact(sample_i, &indices) {
-                    return Some([0, 1]);
-                } else {
-                    return Some([1, 0]);
-                }
-            }
-
-            return None;
+            matcher.find_sample(samples, &indices, &qscores)
         })
-        .reduce(|| [0, 0], |a, b| [a[0] + b[0], a[1] + b[1]]);
+        .collect()
+}
+
+/// wrap `out` in an encoder if the output is compressed
+pub(crate) fn output_writer<'a, W: Write + 'a>(
+    out: W,
+    output_options: &OutputOptions,
+) -> Box<dyn Write + 'a> {
+    Box::new(Encoder::new(out, output_options))
+}
 
-    read_count
+/// format the reads for a given sample in one tile and encode them according to
+/// `output_options`, as a chunk to add to the end of the sample's file. Returns the
+/// chunk, and the yield and quality metrics for the reads in it
+#[allow(clippy::too_many_arguments)]
+fn encode_reads(
+    novaseq_run: &NovaSeqRun,
+    sample_i: usize,
+    buffer_array: &ArrayView3<u8>,
+    index_array: &ArrayView3<u8>,
+    assignments: &[Assignment],
+    locs_vec: &[[u32; 2]],
+    pf_flags: Option<&[bool]>,
+    overlaps: Option<(&[PairOverlap], usize)>,
+    sample_tags: Option<&[Option<&str>]>,
+    tile: u32,
+    lane: usize,
+    read_num: usize,
+    output_options: &OutputOptions,
+) -> std::io::Result<(PooledBuffer<'static>, ReadMetrics)> {
+    let mut encoder = Encoder::new(
+        buffer_pool().get(output_options.write_buffer),
+        output_options,
+    );
+    // format the reads into a pooled buffer, and encode them a block at a time
+    let mut records = PooledWriter::new(&mut encoder, FORMAT_BUFFER);
+
+    let read_metrics = write_records(
+        &mut records,
+        novaseq_run,
+        sample_i,
+        buffer_array,
+        index_array,
+        assignments,
+        locs_vec,
+        pf_flags,
+        overlaps,
+        sample_tags,
+        tile,
+        lane,
+        read_num,
+        output_options,
+    )?;
+
+    records.flush()?;
+    drop(records);
+    let encoded = encoder.finish()?;
+
+    Ok((encoded, read_metrics))
 }
 
-/// write the reads for a given sample to a fastq.gz file
+/// write the reads for a given sample in one tile to the end of its output file,
+/// formatted and compressed according to `output_options`. Returns the yield and
+/// quality metrics for the reads that were written
+#[allow(clippy::too_many_arguments)]
 fn write_reads(
     novaseq_run: &NovaSeqRun,
-    samples: &Samples,
     sample_i: usize,
-    sample_filepath: &PathBuf,
+    sample_filepath: &Path,
     buffer_array: &ArrayView3<u8>,
     index_array: &ArrayView3<u8>,
-    index_slices: &[[usize; 2]],
+    assignments: &[Assignment],
     locs_vec: &[[u32; 2]],
+    pf_flags: Option<&[bool]>,
+    overlaps: Option<(&[PairOverlap], usize)>,
+    sample_tags: Option<&[Option<&str>]>,
     tile: u32,
     lane: usize,
     read_num: usize,
-    compression: u32,
-) {
-    // create gz writer for this sample, or open for appending
-    let out_file = match OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(sample_filepath)
-    {
-        Ok(out_file) => out_file,
-        Err(e) => panic!("Error creating file: {}", e),
-    };
+    output_options: &OutputOptions,
+) -> std::io::Result<ReadMetrics> {
+    let (encoded, read_metrics) = encode_reads(
+        novaseq_run,
+        sample_i,
+        buffer_array,
+        index_array,
+        assignments,
+        locs_vec,
+        pf_flags,
+        overlaps,
+        sample_tags,
+        tile,
+        lane,
+        read_num,
+        output_options,
+    )?;
+    output_options
+        .sink()
+        .append_encoded(sample_filepath, &encoded)?;
+
+    Ok(read_metrics)
+}
+
+/// An error writing to an output file, with the file's path
+fn write_error(sample_filepath: &Path, e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(
+        e.kind(),
+        format!("Error writing {}: {}", sample_filepath.display(), e),
+    )
+}
+
+/// The first error writing the output files in the parallel stages of a chunk,
+/// which carry on with the other files and return it once they are done
+#[derive(Default)]
+struct WriteErrors(Mutex<Option<std::io::Error>>);
+
+impl WriteErrors {
+    /// Keep `result`'s error, if it is the first
+    fn record<T>(&self, result: std::io::Result<T>) -> Option<T> {
+        result
+            .map_err(|e| {
+                self.0.lock().unwrap().get_or_insert(e);
+            })
+            .ok()
+    }
+
+    /// The first error, if there was one
+    fn check(self) -> std::io::Result<()> {
+        match self.0.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The length of a read after trimming a run of at least `min_length` Gs from the
+/// end. A `min_length` of 0 means no trimming. A read of only Gs keeps one, so that
+/// no record is empty
+pub(crate) fn poly_g_trimmed_len(seq: &[u8], min_length: usize) -> usize {
+    let n_g = seq.iter().rev().take_while(|&&b| b == b'G').count();
+
+    if min_length > 0 && n_g >= min_length {
+        (seq.len() - n_g).max(1.min(seq.len()))
+    } else {
+        seq.len()
+    }
+}
 
-    let mut gz_writer = GzEncoder::new(out_file, flate2::Compression::new(compression));
+/// format the reads assigned to `sample_i` as fastq records (or just sequences,
+/// depending on `output_options`) and write them to `writer`. With `pf_flags`,
+/// the clusters that failed filter are flagged in their read names and left out
+/// of the metrics. With `overlaps`, the overlap of each cluster's pair and which
+/// read of the pair this is, the corrected positions are added to the read names
+/// and the reads are trimmed to the insert if `output_options` asks for that. With
+/// `sample_tags`, each cluster's sample is added to its read name
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_records(
+    writer: &mut dyn Write,
+    novaseq_run: &NovaSeqRun,
+    sample_i: usize,
+    buffer_array: &ArrayView3<u8>,
+    index_array: &ArrayView3<u8>,
+    assignments: &[Assignment],
+    locs_vec: &[[u32; 2]],
+    pf_flags: Option<&[bool]>,
+    overlaps: Option<(&[PairOverlap], usize)>,
+    sample_tags: Option<&[Option<&str>]>,
+    tile: u32,
+    lane: usize,
+    read_num: usize,
+    output_options: &OutputOptions,
+) -> std::io::Result<ReadMetrics> {
+    let mut read_metrics = ReadMetrics::new(read_num);
+    // the quality scores of a read, if they are written with another offset
+    let mut shifted = Vec::new();
+    let mut read_name = ReadName::new(&novaseq_run.run_id, lane, tile);
 
     buffer_array
         .axis_iter(Axis(1))
         .zip(index_array.axis_iter(Axis(1)))
         .zip(locs_vec)
-        .for_each(|((bq_row, ix_row), loc)| {
-            let indices: Vec<_> = index_slices
-                .iter()
-                .cloned()
-                .map(|[i0, i1]| ix_row.slice(ndarray::s![i0..i1, 0]))
-                .collect();
+        .zip(assignments)
+        .enumerate()
+        .try_for_each(|(j, (((bq_row, ix_row), loc), assignment))| {
+            match assignment {
+                Some((i, _)) if *i == sample_i => (),
+                _ => return Ok(()),
+            }
 
-            if samples.get_sample(sample_i, &indices) {
-                write!(
-                    gz_writer,
-                    "{}:{}:{}:{}:{} {}:N:0:",
-                    novaseq_run.run_id, lane, tile, loc[0], loc[1], read_num,
-                )
-                .unwrap();
-                gz_writer
-                    .write_all(ix_row.slice(ndarray::s![.., 0]).as_slice().unwrap())
-                    .unwrap();
-                gz_writer
-                    .write_all(bq_row.slice(ndarray::s![.., 0]).as_slice().unwrap())
-                    .unwrap();
-                gz_writer.write_all(b"\n+\n").unwrap();
-                gz_writer
-                    .write_all(bq_row.slice(ndarray::s![.., 1]).as_slice().unwrap())
-                    .unwrap();
-                gz_writer.write_all(b"\n").unwrap();
+            let passed_filter = pf_flags.is_none_or(|pf| pf[j]);
+            let qscores = bq_row.slice(ndarray::s![.., 1]);
+            if passed_filter {
+                read_metrics.add_read(qscores.as_slice().unwrap());
             }
-        });
-}
 
-/// write the read count (# total, exact, and mismatch reads) to a text file
-fn write_report(
-    report_filepath: &PathBuf,
-    samples: &Samples,
-    sample_counts: &HashMap<usize, [u64; 2]>,
-) {
-    let mut report_out_file = match File::create(report_filepath) {
-        Ok(out_file) => out_file,
-        Err(e) => panic!("Error creating file: {}", e),
-    };
+            let seq = bq_row.slice(ndarray::s![.., 0]);
+            let seq = seq.as_slice().unwrap();
+            let mut read_len = poly_g_trimmed_len(seq, output_options.trim_poly_g);
+            if output_options.overlap.is_some_and(|o| o.trim) {
+                if let Some((overlaps, _)) = overlaps {
+                    read_len = read_len.min(overlaps[j].insert_len.unwrap_or(read_len));
+                }
+            }
+            // by poly-G and read-through trimming both
+            if passed_filter {
+                read_metrics.trimmed_bases += (seq.len() - read_len) as u64;
+            }
 
-    report_out_file
-        .write_all(b"sample_name\ttotal_reads\texact_index\tindex_with_error\n")
-        .unwrap();
+            if output_options.seq_only {
+                writer.write_all(&seq[..read_len])?;
+                writer.write_all(b"\n")?;
+                return Ok(());
+            }
 
-    // sort the counts so that the output is stable
-    let mut count_vec: Vec<_> = sample_counts.iter().collect();
-    count_vec.sort_by_key(|(&k, _)| k);
+            writer.write_all(read_name.format(*loc, read_num, passed_filter))?;
+            let index = ix_row.slice(ndarray::s![.., 0]);
+            let index = index.as_slice().unwrap();
+            let corrections = overlaps.map_or(&[][..], |(overlaps, pair_i)| {
+                &overlaps[j].corrections[pair_i][..]
+            });
+            let sample_tag = sample_tags.and_then(|tags| tags[j]);
+            if output_options.header_comment.is_none()
+                && corrections.is_empty()
+                && sample_tag.is_none()
+            {
+                writer.write_all(index)?;
+            } else {
+                // the index ends with the newline, so the comments go in front of it
+                writer.write_all(&index[..index.len() - 1])?;
+                if let Some(comment) = &output_options.header_comment {
+                    write!(writer, " {}", comment)?;
+                }
+                if let Some(sample) = sample_tag {
+                    write!(writer, " SM:Z:{}", sample)?;
+                }
+                // the corrected positions, from 1, as a SAM tag
+                for (i, correction) in corrections.iter().enumerate() {
+                    let separator = if i == 0 { " XC:Z:" } else { "," };
+                    write!(writer, "{}{}", separator, correction.position + 1)?;
+                }
+                writer.write_all(b"\n")?;
+            }
+            writer.write_all(&seq[..read_len])?;
+            writer.write_all(b"\n+\n")?;
+            let qscores = &qscores.as_slice().unwrap()[..read_len];
+            if output_options.ascii_offset == PHRED_33 {
+                writer.write_all(qscores)?;
+            } else {
+                // checked against the run's bins by check_ascii_offset
+                let shift = output_options.ascii_offset - PHRED_33;
+                shifted.clear();
+                shifted.extend(qscores.iter().map(|q| q + shift));
+                writer.write_all(&shifted)?;
+            }
+            writer.write_all(b"\n")?;
 
-    for (sample_i, [n_reads, m_reads]) in count_vec {
-        writeln!(
-            report_out_file,
-            "{}\t{}\t{}\t{}",
-            samples.sample_names[sample_i.clone()],
-            n_reads + m_reads,
-            n_reads,
-            m_reads
-        )
-        .unwrap();
-    }
+            Ok::<_, std::io::Error>(())
+        })?;
+
+    Ok(read_metrics)
+}
+
+/// The assignments to write a chunk's reads with, once the clusters that failed the
+/// read filter are taken out of their samples and counted. They are written to
+/// `filtered_sample` if there is one, otherwise they aren't written at all
+fn filter_assignments(
+    assignments: &[Vec<Assignment>],
+    passing: &[Vec<bool>],
+    filtered_sample: Option<usize>,
+    lane_stats: &mut LaneStats,
+) -> Vec<Vec<Assignment>> {
+    assignments
+        .iter()
+        .zip(passing)
+        .map(|(tile_assignments, tile_passing)| {
+            tile_assignments
+                .iter()
+                .zip(tile_passing)
+                .map(|(assignment, &passes)| match assignment {
+                    Some((sample_i, _)) if !passes => {
+                        lane_stats.add_filtered_read(*sample_i);
+                        filtered_sample.map(|i| (i, 0))
+                    }
+                    _ => *assignment,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The assignments to write a chunk's reads with, when the clusters that failed
+/// filter have their own files: the ones for sample `i` are at `first_file + i`
+fn non_pf_assignments(
+    assignments: &[Vec<Assignment>],
+    pf_flags: &[Vec<bool>],
+    first_file: usize,
+) -> Vec<Vec<Assignment>> {
+    assignments
+        .iter()
+        .zip(pf_flags)
+        .map(|(tile_assignments, tile_pf_flags)| {
+            tile_assignments
+                .iter()
+                .zip(tile_pf_flags)
+                .map(|(assignment, &pf)| match assignment {
+                    Some((sample_i, mismatches)) if !pf => {
+                        Some((first_file + sample_i, *mismatches))
+                    }
+                    _ => *assignment,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The assignments to write a chunk's reads with when the samples share the first
+/// file: the files after the samples' move up to follow it
+fn single_file_assignments(
+    assignments: &[Vec<Assignment>],
+    n_samples: usize,
+) -> Vec<Vec<Assignment>> {
+    assignments
+        .iter()
+        .map(|tile_assignments| {
+            tile_assignments
+                .iter()
+                .map(|assignment| {
+                    assignment.map(|(file_i, mismatches)| {
+                        if file_i < n_samples {
+                            (0, mismatches)
+                        } else {
+                            (file_i + 1 - n_samples, mismatches)
+                        }
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The bases (0) or quality scores (1) of one cluster's read
+fn read_slice(bq_row: ArrayView2<'_, u8>, c: usize) -> &[u8] {
+    bq_row.slice_move(ndarray::s![.., c]).to_slice().unwrap()
+}
+
+/// Correct read `pair_i` of each cluster's pair, once it is decoded into
+/// `buffer_array`, with the calls from the other read where they overlap
+fn apply_corrections(
+    buffer_array: &mut Array3<u8>,
+    overlaps: &[Vec<PairOverlap>],
+    pair_i: usize,
+    max_n_pf: usize,
+) {
+    for (mut b_array, tile_overlaps) in buffer_array
+        .axis_chunks_iter_mut(Axis(1), max_n_pf)
+        .zip(overlaps)
+    {
+        for (j, overlap) in tile_overlaps.iter().enumerate() {
+            for correction in overlap.corrections[pair_i].iter() {
+                b_array[[correction.position, j, 0]] = correction.base;
+                b_array[[correction.position, j, 1]] = correction.qscore;
+            }
+        }
+    }
+}
+
+/// The assignments to write a chunk's reads with, once the adapter dimers of each
+/// sample are counted. With `dimer_file`, the ones for sample `i` are written to
+/// `dimer_file + i`. Only the clusters that are still written to a sample's own
+/// files are counted, not those that were filtered or failed filter
+fn dimer_assignments(
+    assignments: &[Vec<Assignment>],
+    dimers: &[Vec<bool>],
+    n_samples: usize,
+    dimer_file: Option<usize>,
+    lane_stats: &mut LaneStats,
+) -> Vec<Vec<Assignment>> {
+    lane_stats.count_adapter_dimers();
+
+    assignments
+        .iter()
+        .zip(dimers)
+        .map(|(tile_assignments, tile_dimers)| {
+            tile_assignments
+                .iter()
+                .zip(tile_dimers)
+                .map(|(assignment, &dimer)| match assignment {
+                    Some((sample_i, mismatches)) if dimer && *sample_i < n_samples => {
+                        lane_stats.add_adapter_dimer(*sample_i);
+                        dimer_file.map_or(*assignment, |i| Some((i + sample_i, *mismatches)))
+                    }
+                    _ => *assignment,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// compute the yield and quality metrics for the reads that were not assigned
+/// to any sample, leaving out the clusters that failed filter if there are any.
+/// The ones that look like PhiX, if they are told apart, have their own metrics
+fn undetermined_metrics(
+    buffer_array: &ArrayView3<u8>,
+    assignments: &[Assignment],
+    pf_flags: Option<&[bool]>,
+    phix_flags: Option<&[bool]>,
+    read_num: usize,
+) -> [ReadMetrics; 2] {
+    let mut read_metrics = [ReadMetrics::new(read_num), ReadMetrics::new(read_num)];
+
+    for (j, (bq_row, _)) in buffer_array
+        .axis_iter(Axis(1))
+        .zip(assignments)
+        .enumerate()
+        .filter(|(j, (_, assignment))| assignment.is_none() && pf_flags.is_none_or(|pf| pf[*j]))
+    {
+        let phix = phix_flags.is_some_and(|phix| phix[j]) as usize;
+        read_metrics[phix].add_read(bq_row.slice(ndarray::s![.., 1]).as_slice().unwrap());
+    }
+
+    read_metrics
+}
+
+/// compute the yield and quality metrics of each of the `n_samples` samples, for
+/// the clusters assigned to them that passed filter
+fn sample_metrics(
+    buffer_array: &ArrayView3<u8>,
+    assignments: &[Assignment],
+    pf_flags: Option<&[bool]>,
+    n_samples: usize,
+    read_num: usize,
+) -> Vec<ReadMetrics> {
+    let mut read_metrics = vec![ReadMetrics::new(read_num); n_samples];
+
+    for (j, (bq_row, assignment)) in buffer_array.axis_iter(Axis(1)).zip(assignments).enumerate() {
+        match assignment {
+            Some((sample_i, _)) if *sample_i < n_samples && pf_flags.is_none_or(|pf| pf[j]) => {
+                read_metrics[*sample_i]
+                    .add_read(bq_row.slice(ndarray::s![.., 1]).as_slice().unwrap());
+            }
+            _ => (),
+        }
+    }
+
+    read_metrics
+}
+
+/// compute the base composition and quality metrics for each cycle of a read,
+/// across all of the clusters in the array
+fn cycle_metrics(buffer_array: &ArrayView3<u8>) -> Vec<CycleMetrics> {
+    buffer_array
+        .axis_iter(Axis(0))
+        .into_par_iter()
+        .map(|bq_cycle| {
+            let mut metrics = CycleMetrics::default();
+            for bq in bq_cycle.axis_iter(Axis(0)) {
+                metrics.add_base(bq[0], bq[1]);
+            }
+            metrics
+        })
+        .collect()
+}
+
+/// fill `locs_vec` with the locations of the clusters that pass `filter`. Each
+/// byte of the filter covers two clusters
+pub(crate) fn pf_locs(locs: &[[u32; 2]], filter: &[u8], locs_vec: &mut Vec<[u32; 2]>) {
+    locs_vec.clear();
+
+    for (loc_chunk, filt) in locs.chunks(2).zip(filter.iter().cloned()) {
+        match filt {
+            0b01 => locs_vec.push(loc_chunk[1]),
+            0b10 => locs_vec.push(loc_chunk[0]),
+            0b11 => locs_vec.extend(loc_chunk),
+            _ => (),
+        };
+    }
+}
+
+/// whether each of the first `n_clusters` clusters of a tile passes `filter`, for
+/// a tile whose clusters were all decoded
+pub(crate) fn filter_flags(filter: &[u8], n_clusters: usize) -> Vec<bool> {
+    filter
+        .iter()
+        .flat_map(|&f| [f & 0b10 != 0, f & 0b01 != 0])
+        .take(n_clusters)
+        .collect()
+}
+
+/// extract all the cycles in `headers` for a single tile
+pub(crate) fn extract_tile(
+    headers: &[CBCLHeader],
+    filter: &[u8],
+    pf_filter: &[u8],
+    n_pf: usize,
+    tile_i: usize,
+) -> std::io::Result<Array3<u8>> {
+    let mut tile_array = Array3::zeros((headers.len(), n_pf, 2).f());
+    let compaction = TileCompaction::new(filter, pf_filter);
+
+    tile_array
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .zip(headers)
+        .map(|(mut byte_array, header)| {
+            extract_cbcl(
+                header,
+                compaction.for_header(header),
+                &mut byte_array,
+                tile_i,
+            )
+        })
+        .collect::<std::io::Result<()>>()?;
+
+    Ok(tile_array)
+}
+
+/// Write every pass-filter read in a single tile to `writer`, without assigning
+/// them to samples. The reads are written one read at a time (all of R1, then all
+/// of R2, etc), with the index sequences in the header. Returns the number of
+/// clusters in the tile that passed filter
+pub fn dump_tile(
+    novaseq_run: &NovaSeqRun,
+    lane: usize,
+    tile: u32,
+    writer: &mut dyn Write,
+) -> std::io::Result<usize> {
+    let (surface, tile_i) = novaseq_run
+        .tile_ids
+        .iter()
+        .filter(|([l, _], _)| *l == lane)
+        .find_map(|(&[_, surface], tiles)| {
+            tiles.iter().position(|&t| t == tile).map(|i| (surface, i))
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no tile {} in lane {}", tile, lane),
+            )
+        })?;
+
+    let filter = &novaseq_run.filters[&[lane, surface]][tile_i];
+    let pf_filter = &novaseq_run.pf_filters[&[lane, surface]][tile_i];
+    let n_pf = novaseq_run.n_pfs[&[lane, surface]][tile_i];
+
+    let mut locs_vec = Vec::with_capacity(n_pf);
+    pf_locs(
+        novaseq_run.locs_for([lane, surface], tile_i),
+        filter,
+        &mut locs_vec,
+    );
+
+    let index_arrays = novaseq_run.index_headers[&[lane, surface]]
+        .iter()
+        .map(|idx_h| extract_tile(idx_h, filter, pf_filter, n_pf, tile_i))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let index_strings: Vec<_> = (0..n_pf)
+        .map(|j| {
+            index_arrays
+                .iter()
+                .map(|ix_array| {
+                    String::from_utf8_lossy(&ix_array.slice(ndarray::s![.., j, 0]).to_vec())
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("+")
+        })
+        .collect();
+
+    for (k, read_h) in novaseq_run.read_headers[&[lane, surface]]
+        .iter()
+        .enumerate()
+    {
+        let read_array = extract_tile(read_h, filter, pf_filter, n_pf, tile_i)?;
+
+        for ((bq_row, loc), index_string) in read_array
+            .axis_iter(Axis(1))
+            .zip(&locs_vec)
+            .zip(&index_strings)
+        {
+            writeln!(
+                writer,
+                "{}:{}:{}:{}:{} {}:N:0:{}",
+                novaseq_run.run_id,
+                lane,
+                tile,
+                loc[0],
+                loc[1],
+                k + 1,
+                index_string,
+            )?;
+            writer.write_all(&bq_row.slice(ndarray::s![.., 0]).to_vec())?;
+            writer.write_all(b"\n+\n")?;
+            writer.write_all(&bq_row.slice(ndarray::s![.., 1]).to_vec())?;
+            writer.write_all(b"\n")?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(n_pf)
+}
+
+/// write the read count (# total, exact, and mismatch reads) to a text file
+fn write_report(
+    report_filepath: &Path,
+    samples: &Samples,
+    sample_counts: &HashMap<usize, [u64; 2]>,
+) {
+    let mut report_out_file = match File::create(report_filepath) {
+        Ok(out_file) => out_file,
+        Err(e) => panic!("Error creating file: {}", e),
+    };
+
+    report_out_file
+        .write_all(b"sample_name\ttotal_reads\texact_index\tindex_with_error\n")
+        .unwrap();
+
+    // sort the counts so that the output is stable
+    let mut count_vec: Vec<_> = sample_counts.iter().collect();
+    count_vec.sort_by_key(|(&k, _)| k);
+
+    for (sample_i, [n_reads, m_reads]) in count_vec {
+        writeln!(
+            report_out_file,
+            "{}\t{}\t{}\t{}",
+            samples.sample_names[*sample_i],
+            n_reads + m_reads,
+            n_reads,
+            m_reads
+        )
+        .unwrap();
+    }
+}
+
+/// Add up the barcode reports for `lane` in each of `part_paths`, where the parts of
+/// the lane were demultiplexed, into the lane's report in `output_path`
+pub(crate) fn merge_reports(
+    part_paths: &[PathBuf],
+    output_path: &Path,
+    lane: usize,
+    output_options: &OutputOptions,
+) -> std::io::Result<()> {
+    let mut header = String::new();
+    let mut rows: Vec<(String, [u64; 3])> = Vec::new();
+
+    for part_path in part_paths {
+        let report =
+            std::fs::read_to_string(make_report_filename(part_path, lane, &output_options.shard))?;
+        let mut lines = report.lines();
+        header = lines.next().unwrap_or_default().to_string();
+
+        for (i, line) in lines.enumerate() {
+            let mut fields = line.split('\t');
+            let sample_name = fields.next().unwrap_or_default();
+            let mut counts = [0; 3];
+            for (count, field) in counts.iter_mut().zip(fields) {
+                *count = field.parse().map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid barcode report line '{}'", line),
+                    )
+                })?;
+            }
+
+            match rows.get_mut(i) {
+                Some((_, row_counts)) => {
+                    for (n, m) in row_counts.iter_mut().zip(counts) {
+                        *n += m;
+                    }
+                }
+                None => rows.push((sample_name.to_string(), counts)),
+            }
+        }
+    }
+
+    let mut report = header + "\n";
+    for (sample_name, [total, exact, with_error]) in rows {
+        report += &format!("{}\t{}\t{}\t{}\n", sample_name, total, exact, with_error);
+    }
+    std::fs::write(
+        make_report_filename(output_path, lane, &output_options.shard),
+        report,
+    )
 }
 
-/// Iterate through all lanes and surfaces of a run and extract tiles in chunks
+/// The dimensions needed for the buffers that hold a single tile: the number of
+/// cycles in the longest read, the number of index cycles (plus a separator after
+/// each index), and the highest number of pass-filter clusters in any tile
+pub(crate) fn buffer_dims(novaseq_run: &NovaSeqRun) -> [usize; 3] {
+    // compute max array depth needed for data. There is a chance that the total index
+    // length is longer than the longest read (for instance, in test data) so we account
+    // for that here
+    let n_cycles = novaseq_run.read_structure.max_template_cycles();
+
+    // leave space between index cycles for '+' and at the end for '\n'
+    let n_idx_cycles = novaseq_run
+        .read_structure
+        .indices()
+        .map(|s| s.num_cycles() + 1)
+        .sum::<usize>();
+
+    // find the highest numbers of reads among the chunks of tiles
+    let max_n_pf = novaseq_run.n_pfs.values().flatten().cloned().max().unwrap();
+
+    [n_cycles, n_idx_cycles, max_n_pf]
+}
+
+/// The two reads that are overlapped, if they are: the only two that are written
+pub(crate) fn pair_reads(
+    novaseq_run: &NovaSeqRun,
+    output_options: &OutputOptions,
+) -> Option<[usize; 2]> {
+    output_options.overlap.and_then(|_| {
+        let written_reads: Vec<_> = (0..novaseq_run.read_structure.n_templates())
+            .filter(|k| output_options.writes_read(k + 1))
+            .collect();
+        match written_reads[..] {
+            [k1, k2] => Some([k1, k2]),
+            _ => None,
+        }
+    })
+}
+
+/// The rows of the read buffer: the longest read, or both reads of the pair, which
+/// are decoded one after the other to overlap them
+pub(crate) fn read_buffer_cycles(
+    novaseq_run: &NovaSeqRun,
+    output_options: &OutputOptions,
+) -> usize {
+    let read_cycles: Vec<_> = novaseq_run
+        .read_structure
+        .templates()
+        .map(|s| s.num_cycles())
+        .collect();
+    let n_cycles = buffer_dims(novaseq_run)[0];
+
+    match pair_reads(novaseq_run, output_options) {
+        Some([k1, k2]) => n_cycles.max(read_cycles[k1] + read_cycles[k2]),
+        None => n_cycles,
+    }
+}
+
+/// The range of rows for each index read in the index buffer
+pub(crate) fn index_slices(novaseq_run: &NovaSeqRun) -> Vec<[usize; 2]> {
+    novaseq_run
+        .read_structure
+        .indices()
+        .scan(0, |k, s| {
+            let t = [*k, *k + s.num_cycles()];
+            *k += s.num_cycles() + 1;
+            Some(t)
+        })
+        .collect()
+}
+
+/// Shorten the index slices that are longer than the samplesheet's indices, e.g.
+/// 10 index cycles for 8 base indices, whose extra bases would never match.
+/// Returns each index (from 0) that was shortened, with its cycles and length
+pub(crate) fn truncate_index_slices(
+    idx_slices: &mut [[usize; 2]],
+    samples: &Samples,
+) -> Vec<(usize, usize, usize)> {
+    let mut truncated = Vec::new();
+    for (k, (slice, length)) in idx_slices
+        .iter_mut()
+        .zip(samples.index_lengths())
+        .enumerate()
+    {
+        let cycles = slice[1] - slice[0];
+        if length > 0 && length < cycles {
+            slice[1] = slice[0] + length;
+            truncated.push((k, cycles, length));
+        }
+    }
+
+    truncated
+}
+
+/// Allocate a buffer for the index reads of `n_clusters` clusters, with a '+'
+/// between two indices and a newline after the last one, so that each column can
+/// be written straight into a read header
+pub(crate) fn index_buffer(novaseq_run: &NovaSeqRun, n_clusters: usize) -> Array3<u8> {
+    let [_, n_idx_cycles, _] = buffer_dims(novaseq_run);
+    let idx_slices = index_slices(novaseq_run);
+
+    let mut index_array = Array3::zeros((n_idx_cycles, n_clusters, 2).f());
+
+    // every index is followed by a '+', except the last which gets a newline
+    for &[_, end] in idx_slices.iter() {
+        index_array.index_axis_mut(Axis(0), end).fill(b'+');
+    }
+    index_array
+        .index_axis_mut(Axis(0), n_idx_cycles - 1)
+        .fill(b'\n');
+
+    index_array
+}
+
+/// A pass over the tiles of a surface
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pass {
+    /// read the indices and the reads of each chunk of tiles together
+    Full,
+    /// read the indices only, to assign every cluster to a sample
+    Assign,
+    /// read the assigned clusters, with the assignments from the first pass
+    Extract,
+}
+
+/// Stands for an undetermined cluster in the assignments kept between passes
+const UNASSIGNED: u32 = u32::MAX;
+
+/// The filter for the clusters that pass `filter` and were assigned to a sample.
+/// `assigned` has a flag for each cluster that passes `filter`, in order
+fn assigned_filter(filter: &[u8], assigned: impl IntoIterator<Item = bool>) -> Vec<u8> {
+    let mut assigned = assigned.into_iter();
+
+    filter
+        .iter()
+        .map(|&f| {
+            let mut out = 0;
+            // the first cluster of each pair is the high bit
+            if f & 0b10 != 0 && assigned.next().unwrap() {
+                out |= 0b10;
+            }
+            if f & 0b01 != 0 && assigned.next().unwrap() {
+                out |= 0b01;
+            }
+            out
+        })
+        .collect()
+}
+
+/// Iterate through all lanes and surfaces of a run and extract tiles in chunks. Fails
+/// if a lane goes over `output_options.undetermined_limit`
 pub fn demux_fastqs(
     novaseq_run: &NovaSeqRun,
     lane_n: usize,
     samples: &Samples,
-    output_path: &PathBuf,
+    output_path: &Path,
     n_chunks: usize,
-    compression: u32,
-) -> Result<(), &'static str> {
+    output_options: &OutputOptions,
+    progress: &Progress,
+) -> std::io::Result<Vec<LaneStats>> {
     // 0. check for existing files and get shared file -> path map
-    let sample_files = match get_sample_filepaths(novaseq_run, samples, lane_n, output_path) {
-        Ok(sample_fs) => sample_fs,
-        Err(e) => panic!("Couldn't clear existing files: {}", e),
-    };
+    let LaneFiles {
+        paths: sample_files,
+        slots,
+    } = get_sample_filepaths(novaseq_run, samples, lane_n, output_path, output_options).map_err(
+        |e| std::io::Error::new(e.kind(), format!("Couldn't clear existing files: {}", e)),
+    )?;
     // keep track of per-sample counts and output to a report text file
     let mut sample_counts: HashMap<usize, [u64; 2]> = (0..samples.sample_names.len())
         .map(|sample_i| (sample_i, [0u64; 2]))
         .collect();
-    let report_filepath = make_report_filename(output_path, lane_n);
+    let report_filepath = make_report_filename(output_path, lane_n, &output_options.shard);
 
     info!(
         "sample files: {}",
@@ -254,77 +1721,92 @@ pub fn demux_fastqs(
         lane_n..=lane_n
     };
 
-    // compute max array depth needed for data. There is a chance that the total index
-    // length is longer than the longest read (for instance, in test data) so we account
-    // for that here
-    let n_cycles = novaseq_run
-        .run_info
-        .reads
-        .iter()
-        .filter_map(|r| {
-            if r.is_indexed_read {
-                None
-            } else {
-                Some(r.num_cycles)
-            }
-        })
-        .max()
-        .unwrap();
-
-    let idx_reads: Vec<_> = novaseq_run
-        .run_info
-        .reads
-        .iter()
-        .filter(|r| r.is_indexed_read)
-        .collect();
+    let [n_cycles, n_idx_cycles, max_n_pf] = buffer_dims(novaseq_run);
 
-    let idx_slices: Vec<_> = idx_reads
+    // the UMIs are in one of the index reads, which isn't matched to the samples
+    let all_idx_slices = index_slices(novaseq_run);
+    let umi_slice = output_options
+        .umi
+        .map(|umi| all_idx_slices[umi.index_read - 1]);
+    let mut idx_slices: Vec<_> = all_idx_slices
         .iter()
-        .scan(0, |k, r| {
-            let t = [*k, *k + r.num_cycles];
-            *k += r.num_cycles + 1;
-            Some(t)
-        })
+        .copied()
+        .filter(|&slice| Some(slice) != umi_slice)
         .collect();
-
-    // leave space between index cycles for '+' and at the end for '\n'
-    let n_idx_cycles = idx_reads.len() + idx_reads.iter().map(|r| r.num_cycles).sum::<usize>();
+    if output_options.truncate_indices {
+        for (k, cycles, length) in truncate_index_slices(&mut idx_slices, samples) {
+            warn!(
+                "lane {}: index {} has {} cycles but the samplesheet's indices are {} bases, so only the first {} are matched",
+                lane_n, k + 1, cycles, length, length
+            );
+        }
+    }
+    let n_templates = novaseq_run.read_structure.n_templates();
+    let cache_key = index_cache::cache_key(novaseq_run, output_options.truncate_indices);
+    let matcher = output_options
+        .matcher
+        .as_deref()
+        .unwrap_or(&HammingMatcher as &dyn BarcodeMatcher);
 
     debug!("max_cycles: {}", n_cycles);
-
-    // find the highest numbers of reads among the chunks of tiles
-    let max_n_pf = novaseq_run.n_pfs.values().flatten().cloned().max().unwrap();
-
     debug!("max_n_pf: {}", max_n_pf);
 
     // this array is big enough to hold all of the reads/qscores for a chunk of tiles,
     // which is basically all of the data we ever load. So as long as it fits in memory,
     // everything should be okay...
-    let mut buffer_array = Array3::zeros((n_cycles, n_chunks * max_n_pf, 2).f());
-    let mut index_array = Array3::zeros((n_idx_cycles, n_chunks * max_n_pf, 2).f());
-
-    index_array
-        .index_axis_mut(Axis(0), n_idx_cycles - 1)
-        .fill(b'\n');
-
-    if idx_reads.len() == 2 {
-        index_array
-            .index_axis_mut(Axis(0), idx_reads[0].num_cycles)
-            .fill(b'+');
-    }
+    let pair_reads = pair_reads(novaseq_run, output_options);
+    let mut buffer_array = Array3::zeros(
+        (
+            read_buffer_cycles(novaseq_run, output_options),
+            n_chunks * max_n_pf,
+            2,
+        )
+            .f(),
+    );
+    let mut index_array = index_buffer(novaseq_run, n_chunks * max_n_pf);
 
     // preallocate vectors for loc tuples
     let mut locs_vecs = vec![Vec::with_capacity(max_n_pf); n_chunks];
 
     debug!("buffer size: {:?}", buffer_array.raw_dim());
 
+    let workers = if output_options.compression_workers {
+        match CompressionWorkers::start(&sample_files, output_options) {
+            Ok(workers) => Some(workers),
+            Err(e) => panic!("Error starting compression workers: {}", e),
+        }
+    } else {
+        None
+    };
+
+    let mut lane_stats = Vec::new();
+    // the output files are shared between lanes if we aren't splitting by lane
+    let mut prev_bytes_written = 0;
+
     for lane in lane_iter {
-        for surface in novaseq_run.run_info.flowcell_layout.surface_range.clone() {
+        if shutdown_requested() {
+            break;
+        }
+
+        // skip lanes that aren't being processed
+        if novaseq_run.tile_count(lane) == 0 {
+            continue;
+        }
+
+        let mut this_lane_stats = LaneStats::new(lane, samples, n_templates);
+        // lanes with fewer tiles are checked at the end
+        let mut limit_tiles = output_options
+            .undetermined_limit
+            .map(|limit| limit.after_tiles.min(novaseq_run.tile_count(lane)));
+
+        for surface in novaseq_run.run_info.flowcell_layout.surfaces(lane) {
             // check to make sure the data is here. Only relevant for testing
             if !novaseq_run.read_headers.contains_key(&[lane, surface]) {
                 continue;
             }
 
+            set_context("lane", lane);
+            set_context("surface", surface);
             info!("Extracting lane {} surface {}", lane, surface);
 
             let read_headers = novaseq_run.read_headers.get(&[lane, surface]).unwrap();
@@ -333,16 +1815,44 @@ pub fn demux_fastqs(
             let pf_filters = novaseq_run.pf_filters.get(&[lane, surface]).unwrap();
             let tile_ids = novaseq_run.tile_ids.get(&[lane, surface]).unwrap();
             let n_pfs = novaseq_run.n_pfs.get(&[lane, surface]).unwrap();
+            // the real filters, if the clusters that failed them are decoded too
+            let surface_pf_flags = novaseq_run.pf_flags.get(&[lane, surface]);
+
+            let passes: &[Pass] = if output_options.two_phase {
+                &[Pass::Assign, Pass::Extract]
+            } else {
+                &[Pass::Full]
+            };
+            // the sample of every cluster on the surface, from the first of two passes,
+            // and the stats for each chunk. The stats are only added to the lane's once
+            // the chunk's reads are written, so a run that stops early has stats for the
+            // tiles that are done
+            let mut surface_assignments: Vec<Vec<u32>> = Vec::new();
+            let mut pending_stats = VecDeque::new();
 
             // n_chunks defines how many tiles we extract at a time. We read all the tiles
             // in parallel within the chunk and across cycles, to maximize CPU and IO usage
-            for (i, (((f_chunk, pff_chunk), tid_chunk), n_pf_chunk)) in filters
-                .chunks(n_chunks)
-                .zip(pf_filters.chunks(n_chunks))
-                .zip(tile_ids.chunks(n_chunks))
-                .zip(n_pfs.chunks(n_chunks))
-                .enumerate()
+            let chunks = || {
+                filters
+                    .chunks(n_chunks)
+                    .zip(pf_filters.chunks(n_chunks))
+                    .zip(tile_ids.chunks(n_chunks))
+                    .zip(n_pfs.chunks(n_chunks))
+                    .enumerate()
+            };
+            for (pass, (i, (((f_chunk, pff_chunk), tid_chunk), n_pf_chunk))) in passes
+                .iter()
+                .flat_map(|&pass| chunks().map(move |chunk| (pass, chunk)))
             {
+                // finish the chunk we're on, but don't start another one
+                if shutdown_requested() {
+                    break;
+                }
+
+                set_context(
+                    "tiles",
+                    format!("{}-{}", tid_chunk[0], tid_chunk[tid_chunk.len() - 1]),
+                );
                 debug!("Read chunk {}", i);
 
                 // 1. par_iter over rows/index cycles
@@ -352,149 +1862,1207 @@ pub fn demux_fastqs(
 
                 // beginning of this chunk
                 let chunk_i = i * n_chunks;
-
-                f_chunk
-                    .par_iter()
-                    .zip(&mut locs_vecs)
-                    .for_each(|(filter, locs_vec)| {
-                        locs_vec.clear();
-
-                        for (loc_chunk, filt) in
-                            novaseq_run.locs.chunks(2).zip(filter.iter().cloned())
+                // count the cycles of each tile that couldn't be read
+                let failed_tile_cycles = AtomicU64::new(0);
+                // the indices are read again in the second pass, so only count them once
+                let failed_index_cycles = AtomicU64::new(0);
+
+                in_stage(Stage::Io, || {
+                    f_chunk.par_iter().zip(&mut locs_vecs).enumerate().for_each(
+                        |(j, (filter, locs_vec))| {
+                            pf_locs(
+                                novaseq_run.locs_for([lane, surface], chunk_i + j),
+                                filter,
+                                locs_vec,
+                            )
+                        },
+                    )
+                });
+
+                // which of the decoded clusters passed filter, if they didn't all
+                let mut chunk_pf_flags: Option<Vec<Vec<bool>>> = surface_pf_flags.map(|filters| {
+                    filters[chunk_i..chunk_i + tid_chunk.len()]
+                        .iter()
+                        .zip(n_pf_chunk)
+                        .map(|(filter, &n_clusters)| filter_flags(filter, n_clusters))
+                        .collect()
+                });
+
+                // in the second pass, read only the clusters that were assigned, as if
+                // the rest had failed the filter, unless the undetermined are read too
+                let kept = |s: u32| s != UNASSIGNED || output_options.two_phase_undetermined;
+                let mut pass_assignments = None;
+                let mut assigned_filters = Vec::new();
+                let mut assigned_pf_filters = Vec::new();
+                let mut n_assigned_chunk = Vec::new();
+                if pass == Pass::Extract {
+                    let tile_assignments = &surface_assignments[chunk_i..chunk_i + tid_chunk.len()];
+
+                    for (((filter, pf_filter), samples), locs_vec) in f_chunk
+                        .iter()
+                        .zip(pff_chunk)
+                        .zip(tile_assignments)
+                        .zip(&mut locs_vecs)
+                    {
+                        let assigned = || samples.iter().map(|&s| kept(s));
+                        assigned_filters.push(assigned_filter(filter, assigned()));
+                        assigned_pf_filters.push(assigned_filter(pf_filter, assigned()));
+                        n_assigned_chunk.push(assigned().filter(|&a| a).count());
+
+                        let mut assigned = assigned();
+                        locs_vec.retain(|_| assigned.next().unwrap());
+                    }
+                    if let Some(chunk_pf_flags) = &mut chunk_pf_flags {
+                        for (tile_pf_flags, samples) in
+                            chunk_pf_flags.iter_mut().zip(tile_assignments)
                         {
-                            match filt {
-                                0b01 => locs_vec.push(loc_chunk[1]),
-                                0b10 => locs_vec.push(loc_chunk[0]),
-                                0b11 => locs_vec.extend(loc_chunk),
-                                _ => (),
-                            };
+                            let mut assigned = samples.iter().map(|&s| kept(s));
+                            tile_pf_flags.retain(|_| assigned.next().unwrap());
                         }
-                    });
+                    }
+
+                    // the mismatches were counted in the first pass
+                    pass_assignments = Some(
+                        tile_assignments
+                            .iter()
+                            .map(|samples| {
+                                samples
+                                    .iter()
+                                    .filter(|&&s| kept(s))
+                                    .map(|&s| (s != UNASSIGNED).then_some((s as usize, 0)))
+                                    .collect::<Vec<Assignment>>()
+                            })
+                            .collect::<Vec<_>>(),
+                    );
+                }
+                let tile_pf_flags: Vec<Option<&[bool]>> = (0..tid_chunk.len())
+                    .map(|j| chunk_pf_flags.as_ref().map(|flags| &flags[j][..]))
+                    .collect();
+                let (f_chunk, pff_chunk, n_pf_chunk) = if pass == Pass::Extract {
+                    (
+                        &assigned_filters[..],
+                        &assigned_pf_filters[..],
+                        &n_assigned_chunk[..],
+                    )
+                } else {
+                    (f_chunk, pff_chunk, n_pf_chunk)
+                };
+
+                // 0a. tiles in the index cache don't need their index cycles decoded
+                let cache_paths: Vec<_> = tid_chunk
+                    .iter()
+                    .map(|&tid| {
+                        output_options.index_cache.as_ref().map(|cache_path| {
+                            index_cache::tile_path(cache_path, &cache_key, lane, surface, tid)
+                        })
+                    })
+                    .collect();
+                let cached: Vec<bool> = in_stage(Stage::Io, || {
+                    let tile_arrays: Vec<_> = index_array
+                        .axis_chunks_iter_mut(Axis(1), max_n_pf)
+                        .zip(n_pf_chunk)
+                        .zip(&cache_paths)
+                        .enumerate()
+                        .collect();
+
+                    tile_arrays
+                        .into_par_iter()
+                        .map(|(k, ((mut ix_array, &n_pf), cache_path))| {
+                            let path = match cache_path {
+                                Some(path) if path.is_file() => path,
+                                _ => return false,
+                            };
+                            // the second pass only has the assigned clusters
+                            let keep: Option<Vec<_>> = (pass == Pass::Extract).then(|| {
+                                surface_assignments[chunk_i + k]
+                                    .iter()
+                                    .map(|&s| kept(s))
+                                    .collect()
+                            });
+
+                            index_cache::read_tile(
+                                path,
+                                &mut ix_array.slice_mut(ndarray::s![.., ..n_pf, ..]),
+                                keep.as_deref(),
+                            )
+                            .map_err(|e| {
+                                warn!("error reading cached indices, decoding them instead: {}", e)
+                            })
+                            .is_ok()
+                        })
+                        .collect()
+                });
 
                 debug!("Reading indices");
                 // 1. chunk_mut the array and par_iter the indexes into it by cycle
-                for (idx_vec, [idx_0, idx_1]) in idx_headers.iter().zip(idx_slices.iter().cloned())
-                {
-                    let mut idx_array = index_array.slice_mut(ndarray::s![idx_0..idx_1, .., ..]);
-
-                    idx_array
-                        .axis_chunks_iter_mut(Axis(1), max_n_pf)
-                        .into_par_iter()
-                        .zip(f_chunk)
+                in_stage(Stage::Io, || {
+                    // the same for every index cycle of a tile
+                    let compactions: Vec<_> = f_chunk
+                        .iter()
                         .zip(pff_chunk)
-                        .zip(n_pf_chunk)
-                        .enumerate()
-                        .for_each(|(k, (((mut ix_array, filter), pf_filter), &n_pf))| {
-                            ix_array
-                                .axis_iter_mut(Axis(0))
-                                .into_par_iter()
-                                .zip(idx_vec)
-                                .for_each(|(mut byte_array, idx_h)| {
-                                    extract_cbcl(
-                                        idx_h,
-                                        if idx_h.non_pf_clusters_excluded {
-                                            &pf_filter
-                                        } else {
-                                            &filter
-                                        },
-                                        &mut byte_array.slice_mut(ndarray::s![..n_pf, ..]),
-                                        chunk_i + k,
+                        .map(|(filter, pf_filter)| TileCompaction::new(filter, pf_filter))
+                        .collect();
+                    for (idx_vec, [idx_0, idx_1]) in
+                        idx_headers.iter().zip(all_idx_slices.iter().cloned())
+                    {
+                        let mut idx_array =
+                            index_array.slice_mut(ndarray::s![idx_0..idx_1, .., ..]);
+                        let mut tile_arrays: Vec<_> =
+                            idx_array.axis_chunks_iter_mut(Axis(1), max_n_pf).collect();
+
+                        // every cycle of every tile, the biggest first
+                        let jobs: Vec<_> = tile_arrays
+                            .iter_mut()
+                            .zip(&compactions)
+                            .zip(n_pf_chunk)
+                            .enumerate()
+                            .filter(|(k, _)| !cached[*k])
+                            .flat_map(|(k, ((ix_array, compaction), &n_pf))| {
+                                ix_array.axis_iter_mut(Axis(0)).zip(idx_vec).map(
+                                    move |(byte_array, idx_h)| {
+                                        (k, compaction, n_pf, byte_array, idx_h)
+                                    },
+                                )
+                            })
+                            .collect();
+
+                        largest_first(
+                            jobs,
+                            |(k, .., idx_h)| idx_h.compressed_size[chunk_i + k],
+                            |(k, compaction, n_pf, mut byte_array, idx_h)| {
+                                extract_cbcl(
+                                    idx_h,
+                                    compaction.for_header(idx_h),
+                                    &mut byte_array.slice_mut(ndarray::s![..n_pf, ..]),
+                                    chunk_i + k,
+                                )
+                                .unwrap_or_else(|e| {
+                                    warn!(
+                                        "error reading tile {} from {}: {}",
+                                        tid_chunk[k],
+                                        idx_h.cbcl_path.display(),
+                                        e
                                     );
+                                    failed_index_cycles.fetch_add(1, Ordering::SeqCst);
                                 });
-                        });
+                            },
+                        );
+                    }
+                });
+
+                // 0b. cache the indices that were decoded, unless some couldn't be read
+                if pass != Pass::Extract && failed_index_cycles.load(Ordering::SeqCst) == 0 {
+                    in_stage(Stage::Io, || {
+                        index_array
+                            .axis_chunks_iter(Axis(1), max_n_pf)
+                            .zip(n_pf_chunk)
+                            .zip(&cache_paths)
+                            .zip(&cached)
+                            .filter_map(|(((ix_array, &n_pf), path), &cached)| {
+                                Some((ix_array, n_pf, path.as_ref().filter(|_| !cached)?))
+                            })
+                            .collect::<Vec<_>>()
+                            .into_par_iter()
+                            .for_each(|(ix_array, n_pf, path)| {
+                                index_cache::write_tile(
+                                    path,
+                                    &ix_array.slice(ndarray::s![.., ..n_pf, ..]),
+                                )
+                                .unwrap_or_else(|e| {
+                                    warn!("error caching indices in {}: {}", path.display(), e)
+                                });
+                            })
+                    });
                 }
 
-                // 1a. count the reads for each sample
-                debug!("Counting reads");
-                index_array
-                    .axis_chunks_iter(Axis(1), max_n_pf)
-                    .zip(n_pf_chunk)
-                    .for_each(|(ix_array, &n_pf)| {
-                        sample_counts
-                            .par_iter_mut()
-                            .for_each(|(sample_i, [n_reads, m_reads])| {
-                                let [lane_n, lane_m] = count_reads(
-                                    samples,
-                                    sample_i.clone(),
-                                    n_pf,
-                                    &ix_array,
-                                    &idx_slices,
-                                );
-                                *n_reads += lane_n;
-                                *m_reads += lane_m;
-                            });
-                    });
+                // 1a. assign each read to a sample and count the reads for each sample
+                debug!("Assigning reads");
+                let assignments: Vec<_> = match pass_assignments {
+                    Some(assignments) => assignments,
+                    None => in_stage(Stage::Demux, || {
+                        index_array
+                            .axis_chunks_iter(Axis(1), max_n_pf)
+                            .zip(n_pf_chunk)
+                            .map(|(ix_array, &n_pf)| {
+                                #[cfg(feature = "gpu")]
+                                if output_options.gpu && output_options.matcher.is_none() {
+                                    if let Some(assignments) = crate::gpu::gpu().and_then(|gpu| {
+                                        gpu.assign_reads(samples, n_pf, &ix_array, &idx_slices)
+                                    }) {
+                                        return assignments;
+                                    }
+                                }
+
+                                assign_reads(samples, matcher, n_pf, &ix_array, &idx_slices)
+                            })
+                            .collect()
+                    }),
+                };
+                let n_assigned =
+                    assignments.iter().flatten().filter(|a| a.is_some()).count() as u64;
+
+                // 1a'. the undetermined clusters that passed filter and look like PhiX
+                let phix_flags: Option<Vec<Vec<bool>>> = output_options.phix.as_ref().map(|phix| {
+                    index_array
+                        .axis_chunks_iter(Axis(1), max_n_pf)
+                        .zip(&assignments)
+                        .zip(&tile_pf_flags)
+                        .map(|((ix_array, tile_assignments), pf_flags)| {
+                            ix_array
+                                .index_axis(Axis(2), 0)
+                                .axis_iter(Axis(1))
+                                .zip(tile_assignments)
+                                .enumerate()
+                                .map(|(i, (ix_row, assignment))| {
+                                    assignment.is_none()
+                                        && pf_flags.is_none_or(|pf| pf[i])
+                                        && phix.matches(
+                                            &idx_slices
+                                                .iter()
+                                                .map(|&[i0, i1]| ix_row.slice(ndarray::s![i0..i1]))
+                                                .collect::<Vec<_>>(),
+                                        )
+                                })
+                                .collect()
+                        })
+                        .collect()
+                });
+
+                let mut chunk_stats = LaneStats::new(lane, samples, n_templates);
+                let mut chunk_counts = HashMap::new();
+                if pass == Pass::Extract {
+                    // the index stats were counted in the first pass
+                    let (stats, counts): (LaneStats, HashMap<usize, [u64; 2]>) =
+                        pending_stats.pop_front().unwrap();
+                    this_lane_stats.merge(&stats).unwrap();
+                    for (sample_i, [n_reads, m_reads]) in counts {
+                        let sample_counts = sample_counts.get_mut(&sample_i).unwrap();
+                        sample_counts[0] += n_reads;
+                        sample_counts[1] += m_reads;
+                    }
+                } else {
+                    let (index_stats, index_counts) = if pass == Pass::Assign {
+                        (&mut chunk_stats, &mut chunk_counts)
+                    } else {
+                        (&mut this_lane_stats, &mut sample_counts)
+                    };
+                    index_stats.failed_tile_cycles += failed_index_cycles.load(Ordering::SeqCst);
+
+                    // 1b. base composition of the index cycles, to check pool balance
+                    for (ix_array, &n_pf) in index_array
+                        .axis_chunks_iter(Axis(1), max_n_pf)
+                        .zip(n_pf_chunk)
+                    {
+                        for (idx_i, &[i0, i1]) in all_idx_slices.iter().enumerate() {
+                            index_stats.add_index_cycle_metrics(
+                                idx_i + 1,
+                                &cycle_metrics(&ix_array.slice(ndarray::s![i0..i1, ..n_pf, ..])),
+                            );
+                        }
+                    }
 
-                // 2. per read:
-                for (k, (read_h, read_files)) in read_headers.iter().zip(&sample_files).enumerate()
+                    for (j, (((ix_array, tile_assignments), tid), pf_flags)) in index_array
+                        .axis_chunks_iter(Axis(1), max_n_pf)
+                        .zip(assignments.iter())
+                        .zip(tid_chunk)
+                        .zip(&tile_pf_flags)
+                        .enumerate()
+                    {
+                        // the clusters that failed filter aren't counted, even if they
+                        // are written
+                        let passed_filter = |i: usize| pf_flags.is_none_or(|pf| pf[i]);
+                        let clusters_pf = (0..tile_assignments.len())
+                            .filter(|&i| passed_filter(i))
+                            .count() as u64;
+                        let clusters_raw =
+                            novaseq_run.locs_for([lane, surface], chunk_i + j).len() as u64;
+                        index_stats.total_clusters_raw += clusters_raw;
+                        index_stats.total_clusters_pf += clusters_pf;
+
+                        let tile_stats = index_stats.tile_stats.entry(*tid).or_default();
+                        tile_stats.clusters_raw += clusters_raw;
+                        tile_stats.clusters_pf += clusters_pf;
+                        tile_stats.clusters_assigned += tile_assignments
+                            .iter()
+                            .enumerate()
+                            .filter(|(i, a)| a.is_some() && passed_filter(*i))
+                            .count() as u64;
+
+                        for (i, (ix_row, assignment)) in ix_array
+                            .index_axis(Axis(2), 0)
+                            .axis_iter(Axis(1))
+                            .zip(tile_assignments)
+                            .enumerate()
+                        {
+                            if !passed_filter(i) {
+                                continue;
+                            }
+
+                            match assignment {
+                                Some((sample_i, mismatches)) => {
+                                    let counts = index_counts.entry(*sample_i).or_insert([0; 2]);
+                                    counts[if *mismatches == 0 { 0 } else { 1 }] += 1;
+                                    index_stats.add_read(*sample_i, *mismatches);
+
+                                    let indices: Vec<_> = idx_slices
+                                        .iter()
+                                        .map(|&[i0, i1]| ix_row.slice(ndarray::s![i0..i1]))
+                                        .collect();
+                                    index_stats.add_index_mismatches(
+                                        *sample_i,
+                                        &samples.index_mismatches(*sample_i, &indices),
+                                    );
+
+                                    if let (Some(umi), Some([u0, u1])) =
+                                        (&output_options.umi, umi_slice)
+                                    {
+                                        index_stats.umi.add(
+                                            ix_array
+                                                .slice(ndarray::s![u0..u1, i, 0])
+                                                .as_slice()
+                                                .unwrap(),
+                                            ix_array
+                                                .slice(ndarray::s![u0..u1, i, 1])
+                                                .as_slice()
+                                                .unwrap(),
+                                            umi.min_quality,
+                                        );
+                                    }
+                                }
+                                None if phix_flags.as_ref().is_some_and(|flags| flags[j][i]) => {
+                                    index_stats.add_phix_read();
+                                }
+                                None => {
+                                    let indices: Vec<_> = idx_slices
+                                        .iter()
+                                        .map(|&[i0, i1]| ix_row.slice(ndarray::s![i0..i1]))
+                                        .collect();
+
+                                    // the barcodes are listed as they are matched,
+                                    // without the UMIs or truncated bases
+                                    if idx_slices != all_idx_slices {
+                                        index_stats.add_undetermined_read(
+                                            &indices
+                                                .iter()
+                                                .map(|index| index.to_vec())
+                                                .collect::<Vec<_>>()
+                                                .join(&b'+'),
+                                        );
+                                    } else {
+                                        index_stats.add_undetermined_read(
+                                            ix_row
+                                                .slice(ndarray::s![..n_idx_cycles - 1])
+                                                .as_slice()
+                                                .unwrap(),
+                                        );
+                                    }
+
+                                    if let Some((sample_i, sample2_i)) =
+                                        samples.find_hopped(&indices)
+                                    {
+                                        index_stats.add_hopped_read(sample_i, sample2_i);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if let Some(after_tiles) = limit_tiles.filter(|_| pass != Pass::Extract) {
+                    // the first pass's stats aren't in the lane's yet
+                    let lane_so_far = if pass == Pass::Assign {
+                        let mut stats = this_lane_stats.clone();
+                        for (pending, _) in pending_stats.iter() {
+                            stats.merge(pending).unwrap();
+                        }
+                        stats.merge(&chunk_stats).unwrap();
+                        Cow::Owned(stats)
+                    } else {
+                        Cow::Borrowed(&this_lane_stats)
+                    };
+
+                    if lane_so_far.tile_stats.len() >= after_tiles {
+                        output_options
+                            .undetermined_limit
+                            .unwrap()
+                            .check(&lane_so_far)?;
+                        limit_tiles = None;
+                    }
+                }
+
+                if pass == Pass::Assign {
+                    surface_assignments.extend(assignments.iter().map(|tile_assignments| {
+                        tile_assignments
+                            .iter()
+                            .map(|a| a.map_or(UNASSIGNED, |(sample_i, _)| sample_i as u32))
+                            .collect::<Vec<_>>()
+                    }));
+                    pending_stats.push_back((chunk_stats, chunk_counts));
+                    continue;
+                }
+
+                // mask the invalid UMIs with Ns before they are written in the read
+                // names, now that they have been counted
+                if let (Some(umi), Some([u0, u1])) =
+                    (output_options.umi.filter(|umi| umi.mask), umi_slice)
                 {
-                    debug!("reading data for read {}", k + 1);
-                    // 3. par_iter over cycles and read the data in
-                    buffer_array
+                    for (mut ix_array, &n_pf) in index_array
+                        .slice_mut(ndarray::s![u0..u1, .., ..])
                         .axis_chunks_iter_mut(Axis(1), max_n_pf)
-                        .into_par_iter()
-                        .zip(f_chunk)
-                        .zip(pff_chunk)
                         .zip(n_pf_chunk)
-                        .enumerate()
-                        .for_each(|(j, (((mut b_array, filter), pf_filter), &n_pf))| {
-                            b_array
-                                .axis_iter_mut(Axis(0))
-                                .into_par_iter()
-                                .zip(read_h)
-                                .for_each(|(mut byte_array, header)| {
-                                    extract_cbcl(
-                                        header,
-                                        if header.non_pf_clusters_excluded {
-                                            &pf_filter
-                                        } else {
-                                            &filter
-                                        },
-                                        &mut byte_array.slice_mut(ndarray::s![..n_pf, ..]),
+                    {
+                        for mut umi_row in ix_array
+                            .slice_mut(ndarray::s![.., ..n_pf, ..])
+                            .axis_iter_mut(Axis(1))
+                        {
+                            let valid = is_valid_umi(
+                                read_slice(umi_row.view(), 0),
+                                read_slice(umi_row.view(), 1),
+                                umi.min_quality,
+                            );
+                            if !valid {
+                                umi_row.slice_mut(ndarray::s![.., 0]).fill(b'N');
+                                umi_row.slice_mut(ndarray::s![.., 1]).fill(MASKED_QSCORE);
+                            }
+                        }
+                    }
+                }
+
+                // decode a read into the buffer: par_iter over tiles and cycles, starting
+                // with the biggest tiles so that the chunk doesn't wait on one at the end
+                let decode_read =
+                    |buffer_array: &mut ArrayViewMut3<u8>,
+                     read_h: &[CBCLHeader],
+                     failed_tile_cycles: &AtomicU64| {
+                        in_stage(Stage::Io, || {
+                            let jobs: Vec<_> = buffer_array
+                                .axis_chunks_iter_mut(Axis(1), max_n_pf)
+                                .zip(f_chunk)
+                                .zip(pff_chunk)
+                                .zip(n_pf_chunk)
+                                .enumerate()
+                                .collect();
+
+                            largest_first(
+                                jobs,
+                                |(j, _)| {
+                                    read_h.iter().map(|h| h.compressed_size[chunk_i + j]).sum()
+                                },
+                                |(j, (((mut b_array, filter), pf_filter), &n_pf))| {
+                                    let results = extract_cbcls(
+                                        read_h,
+                                        filter,
+                                        pf_filter,
+                                        &mut b_array.slice_mut(ndarray::s![
+                                            ..read_h.len(),
+                                            ..n_pf,
+                                            ..
+                                        ]),
                                         chunk_i + j,
-                                    )
-                                });
+                                        output_options.gpu,
+                                    );
+
+                                    for (result, header) in results.into_iter().zip(read_h) {
+                                        if let Err(e) = result {
+                                            warn!(
+                                                "error reading tile {} from {}: {}",
+                                                tid_chunk[j],
+                                                header.cbcl_path.display(),
+                                                e
+                                            );
+                                            failed_tile_cycles.fetch_add(1, Ordering::SeqCst);
+                                        }
+                                    }
+                                },
+                            );
+                        });
+                    };
+
+                // 1b. find where the reads of each assigned cluster overlap, the bases to
+                // correct whenever either read is decoded, and where to trim them
+                let overlaps: Option<Vec<Vec<PairOverlap>>> = pair_reads.map(|[k1, k2]| {
+                    debug!("overlapping reads {} and {}", k1 + 1, k2 + 1);
+                    // the reads go one after the other in the buffer, so both are views of it
+                    let (r1_len, r2_len) = (read_headers[k1].len(), read_headers[k2].len());
+                    let r2_rows = r1_len..r1_len + r2_len;
+                    decode_read(
+                        &mut buffer_array.slice_mut(ndarray::s![..r1_len, .., ..]),
+                        &read_headers[k1],
+                        &AtomicU64::new(0),
+                    );
+                    decode_read(
+                        &mut buffer_array.slice_mut(ndarray::s![r2_rows.clone(), .., ..]),
+                        &read_headers[k2],
+                        &AtomicU64::new(0),
+                    );
+                    let r1_array = buffer_array.slice(ndarray::s![..r1_len, .., ..]);
+                    let r2_array = buffer_array.slice(ndarray::s![r2_rows, .., ..]);
+
+                    let overlap_options = output_options.overlap.as_ref().unwrap();
+                    in_stage(Stage::Demux, || {
+                        r1_array
+                            .axis_chunks_iter(Axis(1), max_n_pf)
+                            .zip(r2_array.axis_chunks_iter(Axis(1), max_n_pf))
+                            .zip(&assignments)
+                            .zip(n_pf_chunk)
+                            .map(|(((r1, r2), tile_assignments), &n_pf)| {
+                                let r1 =
+                                    r1.slice(ndarray::s![..read_headers[k1].len(), ..n_pf, ..]);
+                                let r2 =
+                                    r2.slice(ndarray::s![..read_headers[k2].len(), ..n_pf, ..]);
+                                r1.axis_iter(Axis(1))
+                                    .into_par_iter()
+                                    .zip(r2.axis_iter(Axis(1)).into_par_iter())
+                                    .zip(tile_assignments.par_iter())
+                                    .map(|((bq1, bq2), assignment)| {
+                                        if assignment.is_none() {
+                                            return PairOverlap::default();
+                                        }
+                                        overlap_pair(
+                                            (read_slice(bq1, 0), read_slice(bq1, 1)),
+                                            (read_slice(bq2, 0), read_slice(bq2, 1)),
+                                            overlap_options,
+                                        )
+                                    })
+                                    .collect()
+                            })
+                            .collect()
+                    })
+                });
+                if let (Some(overlaps), Some([k1, k2])) = (&overlaps, pair_reads) {
+                    let read_len = read_headers[k1].len().max(read_headers[k2].len());
+                    for overlap in overlaps.iter().flatten() {
+                        this_lane_stats.add_pair_overlap(overlap, read_len);
+                    }
+                }
+
+                // decode a read, with the corrections from the overlap if it is one of
+                // the pair
+                let load_read =
+                    |buffer_array: &mut Array3<u8>, k: usize, failed_tile_cycles: &AtomicU64| {
+                        decode_read(
+                            &mut buffer_array.view_mut(),
+                            &read_headers[k],
+                            failed_tile_cycles,
+                        );
+                        if let (Some(overlaps), Some(pair_reads)) = (&overlaps, pair_reads) {
+                            if let Some(pair_i) = pair_reads.iter().position(|&r| r == k) {
+                                apply_corrections(buffer_array, overlaps, pair_i, max_n_pf);
+                            }
+                        }
+                    };
+
+                // 1c. decode the written reads once before writing any of them, to find
+                // the clusters that fail the read filter in any read
+                let sample_assignments = match &output_options.read_filter {
+                    Some(read_filter) => {
+                        let mut passing: Vec<Vec<bool>> =
+                            n_pf_chunk.iter().map(|&n_pf| vec![true; n_pf]).collect();
+                        for (k, read_h) in read_headers.iter().enumerate() {
+                            if !output_options.writes_read(k + 1) {
+                                continue;
+                            }
+
+                            debug!("filtering read {}", k + 1);
+                            // failures are counted when the read is decoded again
+                            load_read(&mut buffer_array, k, &AtomicU64::new(0));
+                            in_stage(Stage::Demux, || {
+                                for ((b_array, &n_pf), passing) in buffer_array
+                                    .axis_chunks_iter(Axis(1), max_n_pf)
+                                    .zip(n_pf_chunk)
+                                    .zip(&mut passing)
+                                {
+                                    let b_array =
+                                        b_array.slice(ndarray::s![..read_h.len(), ..n_pf, ..]);
+                                    for (bq_row, passes) in
+                                        b_array.axis_iter(Axis(1)).zip(passing.iter_mut())
+                                    {
+                                        *passes = *passes
+                                            && read_filter.passes(
+                                                bq_row
+                                                    .slice(ndarray::s![.., 0])
+                                                    .as_slice()
+                                                    .unwrap(),
+                                                bq_row
+                                                    .slice(ndarray::s![.., 1])
+                                                    .as_slice()
+                                                    .unwrap(),
+                                            );
+                                    }
+                                }
+                            });
+                        }
+                        // only the clusters that passed filter are filtered again
+                        for (passing, pf_flags) in passing.iter_mut().zip(&tile_pf_flags) {
+                            if let Some(pf_flags) = pf_flags {
+                                for (passes, &pf) in passing.iter_mut().zip(pf_flags.iter()) {
+                                    *passes = *passes || !pf;
+                                }
+                            }
+                        }
+
+                        Cow::Owned(filter_assignments(
+                            &assignments,
+                            &passing,
+                            slots.filtered,
+                            &mut this_lane_stats,
+                        ))
+                    }
+                    None => Cow::Borrowed(&assignments[..]),
+                };
+                // the clusters that failed filter go to the files after the samples' and
+                // the filtered clusters'
+                let write_assignments =
+                    match (&chunk_pf_flags, slots.non_pf) {
+                        (Some(chunk_pf_flags), Some(non_pf_file)) => Cow::Owned(
+                            non_pf_assignments(&sample_assignments, chunk_pf_flags, non_pf_file),
+                        ),
+                        _ => Cow::Borrowed(&sample_assignments[..]),
+                    };
+                // then the PhiX clusters go to their files, if they are written
+                let write_assignments = match (&phix_flags, slots.phix) {
+                    (Some(phix_flags), Some(phix_file)) => Cow::Owned(
+                        write_assignments
+                            .iter()
+                            .zip(phix_flags)
+                            .map(|(tile_assignments, tile_phix)| {
+                                tile_assignments
+                                    .iter()
+                                    .zip(tile_phix)
+                                    .map(|(assignment, &phix)| {
+                                        if phix {
+                                            Some((phix_file, 0))
+                                        } else {
+                                            *assignment
+                                        }
+                                    })
+                                    .collect()
+                            })
+                            .collect(),
+                    ),
+                    _ => write_assignments,
+                };
+                // and the adapter dimers of each sample are counted, and go to the last
+                // files if they are written separately. The first written read is
+                // decoded once more to find them
+                let write_assignments = match output_options.adapter_dimers {
+                    Some(_) => {
+                        let (k, read_h) = read_headers
+                            .iter()
+                            .enumerate()
+                            .find(|(k, _)| output_options.writes_read(k + 1))
+                            .unwrap();
+
+                        debug!("finding adapter dimers in read {}", k + 1);
+                        load_read(&mut buffer_array, k, &AtomicU64::new(0));
+                        let dimers: Vec<Vec<bool>> = in_stage(Stage::Demux, || {
+                            buffer_array
+                                .axis_chunks_iter(Axis(1), max_n_pf)
+                                .zip(n_pf_chunk)
+                                .map(|(b_array, &n_pf)| {
+                                    b_array
+                                        .slice(ndarray::s![..read_h.len(), ..n_pf, 0])
+                                        .axis_iter(Axis(1))
+                                        .map(|seq| is_adapter_dimer(seq.as_slice().unwrap()))
+                                        .collect()
+                                })
+                                .collect()
                         });
 
+                        Cow::Owned(dimer_assignments(
+                            &write_assignments,
+                            &dimers,
+                            slots.n_samples,
+                            slots.dimers,
+                            &mut this_lane_stats,
+                        ))
+                    }
+                    None => write_assignments,
+                };
+                // with a single file, every sample's clusters go to the first one, tagged
+                // with their sample, and the other files move up
+                let write_assignments = if output_options.single_file {
+                    Cow::Owned(single_file_assignments(&write_assignments, slots.n_samples))
+                } else {
+                    write_assignments
+                };
+                let sample_tags: Vec<Option<Vec<Option<&str>>>> = assignments
+                    .iter()
+                    .map(|tile_assignments| {
+                        output_options.single_file.then(|| {
+                            tile_assignments
+                                .iter()
+                                .map(|a| a.map(|(i, _)| samples.sample_names[i].as_str()))
+                                .collect()
+                        })
+                    })
+                    .collect();
+
+                // 2. per read:
+                for (k, (read_h, read_files)) in read_headers.iter().zip(&sample_files).enumerate()
+                {
+                    if !output_options.writes_read(k + 1) {
+                        continue;
+                    }
+
+                    debug!("reading data for read {}", k + 1);
+                    // 3. read the data in
+                    load_read(&mut buffer_array, k, &failed_tile_cycles);
+
+                    for ((b_array, &n_pf), tid) in buffer_array
+                        .axis_chunks_iter(Axis(1), max_n_pf)
+                        .zip(n_pf_chunk)
+                        .zip(tid_chunk)
+                    {
+                        let tile_cycles =
+                            cycle_metrics(&b_array.slice(ndarray::s![..read_h.len(), ..n_pf, ..]));
+
+                        let tile_stats = this_lane_stats.tile_stats.entry(*tid).or_default();
+                        for cm in tile_cycles.iter() {
+                            tile_stats.quality_score_sum += cm.quality_score_sum;
+                            tile_stats.n_bases += cm.n_bases();
+                        }
+
+                        this_lane_stats.add_cycle_metrics(k + 1, &tile_cycles);
+                    }
+
+                    // the overlaps of each tile's clusters, if this read is one of the pair
+                    let pair_i = pair_reads.and_then(|pair| pair.iter().position(|&r| r == k));
+                    let tile_overlaps: Vec<Option<(&[PairOverlap], usize)>> =
+                        match (&overlaps, pair_i) {
+                            (Some(overlaps), Some(pair_i)) => overlaps
+                                .iter()
+                                .map(|tile_overlaps| Some((&tile_overlaps[..], pair_i)))
+                                .collect(),
+                            _ => vec![None; tid_chunk.len()],
+                        };
+
                     debug!("writing out read {}", k + 1);
                     // 4. par_iter the reads into files.
                     // It's possible/likely that n_samples >> n_threads, but they will block
                     // on i/o and so this should maximize CPU usage (maybe)
-                    read_files
-                        .par_iter()
-                        .enumerate()
-                        .for_each(|(sample_i, sample_filepath)| {
-                            buffer_array
-                                .axis_chunks_iter(Axis(1), max_n_pf)
-                                .zip(index_array.axis_chunks_iter(Axis(1), max_n_pf))
-                                .zip(&locs_vecs)
-                                .zip(tid_chunk)
-                                .zip(n_pf_chunk)
-                                .for_each(|((((b_array, ix_array), locs_vec), &tid), &n_pf)| {
-                                    write_reads(
+                    let write_errors = WriteErrors::default();
+                    let read_metrics: Vec<_> = in_stage(Stage::Compress, || match &workers {
+                        Some(workers) => read_files
+                            .par_iter()
+                            .enumerate()
+                            .map(|(sample_i, sample_filepath)| {
+                                progress.busy_workers.fetch_add(1, Ordering::SeqCst);
+                                let mut read_metrics = ReadMetrics::new(k + 1);
+                                let mut queue_writer = workers.writer(k, sample_i);
+
+                                buffer_array
+                                    .axis_chunks_iter(Axis(1), max_n_pf)
+                                    .zip(index_array.axis_chunks_iter(Axis(1), max_n_pf))
+                                    .zip(&locs_vecs)
+                                    .zip(write_assignments.iter())
+                                    .zip(&tile_pf_flags)
+                                    .zip(tile_overlaps.iter().zip(&sample_tags))
+                                    .zip(tid_chunk)
+                                    .zip(n_pf_chunk)
+                                    .for_each(
+                                        |(
+                                            (
+                                                (
+                                                    (
+                                                        (
+                                                            ((b_array, ix_array), locs_vec),
+                                                            assignment,
+                                                        ),
+                                                        &pf_flags,
+                                                    ),
+                                                    (&overlaps, sample_tags),
+                                                ),
+                                                &tid,
+                                            ),
+                                            &n_pf,
+                                        )| {
+                                            let b_array = b_array.slice(ndarray::s![
+                                                ..read_h.len(),
+                                                ..n_pf,
+                                                ..
+                                            ]);
+                                            let ix_array =
+                                                ix_array.slice(ndarray::s![.., ..n_pf, ..]);
+
+                                            let tile_metrics = write_records(
+                                                &mut queue_writer,
+                                                novaseq_run,
+                                                sample_i,
+                                                &b_array,
+                                                &ix_array,
+                                                assignment,
+                                                locs_vec,
+                                                pf_flags,
+                                                overlaps,
+                                                sample_tags.as_deref(),
+                                                tid,
+                                                lane,
+                                                k + 1,
+                                                output_options,
+                                            )
+                                            .map_err(|e| write_error(sample_filepath, e));
+                                            if let Some(tile_metrics) =
+                                                write_errors.record(tile_metrics)
+                                            {
+                                                read_metrics.merge(&tile_metrics);
+                                            }
+                                        },
+                                    );
+
+                                write_errors.record(
+                                    queue_writer
+                                        .flush()
+                                        .map_err(|e| write_error(sample_filepath, e)),
+                                );
+
+                                progress.busy_workers.fetch_sub(1, Ordering::SeqCst);
+                                read_metrics
+                            })
+                            .collect(),
+                        None => {
+                            // every sample's tiles are encoded in parallel, with the tile's
+                            // place in the chunk as its ticket, and each sample's sequencer
+                            // writes them to its file in tile order as they are done
+                            let n_tiles = tid_chunk.len();
+                            let sequencers: Vec<Sequencer<Option<PooledBuffer>>> =
+                                read_files.iter().map(|_| Sequencer::new()).collect();
+                            // the files being written are kept open while their tiles
+                            // are, instead of being opened again for each one
+                            let tile_sink = output_options.tile_sink(rayon::current_num_threads());
+                            let sink = tile_sink
+                                .as_ref()
+                                .map_or(output_options.sink(), |sink| sink as &dyn OutputSink);
+
+                            let tile_metrics: Vec<ReadMetrics> = (0..read_files.len() * n_tiles)
+                                .into_par_iter()
+                                .map(|ticket| {
+                                    progress.busy_workers.fetch_add(1, Ordering::SeqCst);
+                                    let (sample_i, j) = (ticket / n_tiles, ticket % n_tiles);
+                                    let clusters = j * max_n_pf..j * max_n_pf + n_pf_chunk[j];
+                                    let b_array = buffer_array.slice(ndarray::s![
+                                        ..read_h.len(),
+                                        clusters.clone(),
+                                        ..
+                                    ]);
+                                    let ix_array = index_array.slice(ndarray::s![.., clusters, ..]);
+
+                                    let encoded = encode_reads(
                                         novaseq_run,
-                                        samples,
                                         sample_i,
-                                        sample_filepath,
-                                        &b_array.slice(ndarray::s![..read_h.len(), ..n_pf, ..]),
-                                        &ix_array.slice(ndarray::s![.., ..n_pf, ..]),
-                                        &idx_slices,
-                                        locs_vec,
-                                        tid,
+                                        &b_array,
+                                        &ix_array,
+                                        &write_assignments[j],
+                                        &locs_vecs[j],
+                                        tile_pf_flags[j],
+                                        tile_overlaps[j],
+                                        sample_tags[j].as_deref(),
+                                        tid_chunk[j],
                                         lane,
                                         k + 1,
-                                        compression,
-                                    )
-                                });
-                        });
+                                        output_options,
+                                    );
+                                    let sample_filepath = &read_files[sample_i];
+                                    // a tile that couldn't be encoded still hands in its
+                                    // ticket, so that the tiles after it are written
+                                    let (encoded, read_metrics) = match write_errors.record(
+                                        encoded.map_err(|e| write_error(sample_filepath, e)),
+                                    ) {
+                                        Some((encoded, read_metrics)) => {
+                                            (Some(encoded), read_metrics)
+                                        }
+                                        None => (None, ReadMetrics::new(k + 1)),
+                                    };
+                                    sequencers[sample_i].submit(j, encoded, |encoded| {
+                                        if let Some(encoded) = encoded {
+                                            write_errors.record(
+                                                sink.append_encoded(sample_filepath, &encoded)
+                                                    .map_err(|e| write_error(sample_filepath, e)),
+                                            );
+                                        }
+                                    });
+
+                                    progress.busy_workers.fetch_sub(1, Ordering::SeqCst);
+                                    read_metrics
+                                })
+                                .collect();
+                            // every ticket was handed in, so they've all been written
+                            debug_assert!(sequencers.iter().all(|s| s.n_pending() == 0));
+
+                            tile_metrics
+                                .chunks(n_tiles)
+                                .map(|sample_tiles| {
+                                    let mut read_metrics = ReadMetrics::new(k + 1);
+                                    for tile_metrics in sample_tiles {
+                                        read_metrics.merge(tile_metrics);
+                                    }
+                                    read_metrics
+                                })
+                                .collect()
+                        }
+                    });
+                    write_errors.check()?;
+
+                    if output_options.single_file {
+                        // the samples share a file, so each one's reads are counted here
+                        for (((b_array, tile_assignments), &pf_flags), &n_pf) in buffer_array
+                            .axis_chunks_iter(Axis(1), max_n_pf)
+                            .zip(sample_assignments.iter())
+                            .zip(&tile_pf_flags)
+                            .zip(n_pf_chunk)
+                        {
+                            for (sample_i, metrics) in sample_metrics(
+                                &b_array.slice(ndarray::s![..read_h.len(), ..n_pf, ..]),
+                                tile_assignments,
+                                pf_flags,
+                                samples.sample_names.len(),
+                                k + 1,
+                            )
+                            .iter()
+                            .enumerate()
+                            {
+                                this_lane_stats.add_read_metrics(Some(sample_i), metrics);
+                            }
+                        }
+                    } else {
+                        // the filtered clusters, if they are written, are only counted
+                        for (sample_i, metrics) in read_metrics
+                            .iter()
+                            .enumerate()
+                            .take(samples.sample_names.len())
+                        {
+                            this_lane_stats.add_read_metrics(Some(sample_i), metrics);
+                        }
+                        // while the adapter dimers written on their own are still their
+                        // sample's reads
+                        if let Some(dimer_file) = slots.dimers {
+                            for (sample_i, metrics) in read_metrics[dimer_file..].iter().enumerate()
+                            {
+                                this_lane_stats.add_read_metrics(Some(sample_i), metrics);
+                            }
+                        }
+                    }
+
+                    for (j, (((b_array, assignment), &pf_flags), &n_pf)) in buffer_array
+                        .axis_chunks_iter(Axis(1), max_n_pf)
+                        .zip(&assignments)
+                        .zip(&tile_pf_flags)
+                        .zip(n_pf_chunk)
+                        .enumerate()
+                    {
+                        let [undetermined, phix] = undetermined_metrics(
+                            &b_array.slice(ndarray::s![..read_h.len(), ..n_pf, ..]),
+                            assignment,
+                            pf_flags,
+                            phix_flags.as_ref().map(|flags| &flags[j][..]),
+                            k + 1,
+                        );
+                        this_lane_stats.add_read_metrics(None, &undetermined);
+                        this_lane_stats.add_phix_read_metrics(&phix);
+                    }
+
+                    // screen the undetermined reads, on the first read that is written
+                    let screen = output_options
+                        .screen
+                        .as_ref()
+                        .filter(|_| (0..k).all(|earlier| !output_options.writes_read(earlier + 1)));
+                    if let Some(screen) = screen {
+                        for (j, (((b_array, ix_array), assignment), &pf_flags)) in buffer_array
+                            .axis_chunks_iter(Axis(1), max_n_pf)
+                            .zip(index_array.axis_chunks_iter(Axis(1), max_n_pf))
+                            .zip(&assignments)
+                            .zip(&tile_pf_flags)
+                            .enumerate()
+                        {
+                            for (i, ((bq_row, ix_row), assignment)) in b_array
+                                .slice(ndarray::s![..read_h.len(), .., ..])
+                                .axis_iter(Axis(1))
+                                .zip(ix_array.index_axis(Axis(2), 0).axis_iter(Axis(1)))
+                                .zip(assignment)
+                                .enumerate()
+                            {
+                                if this_lane_stats.screen.screened >= screen.max_reads {
+                                    break;
+                                }
+                                if assignment.is_some()
+                                    || !pf_flags.is_none_or(|pf| pf[i])
+                                    || phix_flags.as_ref().is_some_and(|flags| flags[j][i])
+                                {
+                                    continue;
+                                }
+
+                                let seq = bq_row.slice(ndarray::s![.., 0]);
+                                let [i0, i1] = idx_slices.first().copied().unwrap_or([0, 0]);
+                                let index = ix_row.slice(ndarray::s![i0..i1]);
+                                this_lane_stats.screen.add(screen.classify(
+                                    &seq.iter().copied().collect::<Vec<_>>(),
+                                    &index.iter().copied().collect::<Vec<_>>(),
+                                ));
+                            }
+                        }
+                    }
+                }
+
+                // 2b. the UMIs of the written clusters go to their own files, with the
+                // same read names as the first written read
+                if let (Some(umi_files), Some([u0, u1])) = (
+                    sample_files
+                        .get(n_templates)
+                        .filter(|_| output_options.umi.is_some()),
+                    umi_slice,
+                ) {
+                    let read_num = (1..=n_templates)
+                        .find(|&read_num| output_options.writes_read(read_num))
+                        .unwrap();
+                    // the UMIs are written as they were read
+                    let umi_options = OutputOptions {
+                        trim_poly_g: 0,
+                        overlap: None,
+                        ..output_options.clone()
+                    };
+
+                    debug!("writing out the UMIs");
+                    let write_errors = WriteErrors::default();
+                    in_stage(Stage::Compress, || {
+                        umi_files
+                            .par_iter()
+                            .enumerate()
+                            .for_each(|(sample_i, sample_filepath)| {
+                                let mut queue_writer = workers
+                                    .as_ref()
+                                    .map(|workers| workers.writer(n_templates, sample_i));
+
+                                for (
+                                    (((((ix_array, locs_vec), assignment), &pf_flags), tags), &tid),
+                                    &n_pf,
+                                ) in index_array
+                                    .axis_chunks_iter(Axis(1), max_n_pf)
+                                    .zip(&locs_vecs)
+                                    .zip(write_assignments.iter())
+                                    .zip(&tile_pf_flags)
+                                    .zip(&sample_tags)
+                                    .zip(tid_chunk)
+                                    .zip(n_pf_chunk)
+                                {
+                                    let ix_array = ix_array.slice(ndarray::s![.., ..n_pf, ..]);
+                                    let umi_array = ix_array.slice(ndarray::s![u0..u1, .., ..]);
+
+                                    let written = match &mut queue_writer {
+                                        Some(queue_writer) => write_records(
+                                            queue_writer,
+                                            novaseq_run,
+                                            sample_i,
+                                            &umi_array,
+                                            &ix_array,
+                                            assignment,
+                                            locs_vec,
+                                            pf_flags,
+                                            None,
+                                            tags.as_deref(),
+                                            tid,
+                                            lane,
+                                            read_num,
+                                            &umi_options,
+                                        ),
+                                        None => write_reads(
+                                            novaseq_run,
+                                            sample_i,
+                                            sample_filepath,
+                                            &umi_array,
+                                            &ix_array,
+                                            assignment,
+                                            locs_vec,
+                                            pf_flags,
+                                            None,
+                                            tags.as_deref(),
+                                            tid,
+                                            lane,
+                                            read_num,
+                                            &umi_options,
+                                        ),
+                                    };
+                                    write_errors.record(
+                                        written.map_err(|e| write_error(sample_filepath, e)),
+                                    );
+                                }
+
+                                if let Some(queue_writer) = &mut queue_writer {
+                                    write_errors.record(
+                                        queue_writer
+                                            .flush()
+                                            .map_err(|e| write_error(sample_filepath, e)),
+                                    );
+                                }
+                            })
+                    });
+                    write_errors.check()?;
                 }
+
+                let n_bytes: u64 = read_headers
+                    .iter()
+                    .chain(idx_headers.iter())
+                    .flatten()
+                    .map(|h| {
+                        h.uncompressed_size[chunk_i..chunk_i + tid_chunk.len()]
+                            .iter()
+                            .sum::<u64>()
+                    })
+                    .sum();
+
+                // the output files are only appended to, so the change in their total
+                // size is the number of bytes written for this chunk
+                let lane_bytes_written: u64 = sample_files
+                    .iter()
+                    .flatten()
+                    .filter_map(|path| std::fs::metadata(path).ok())
+                    .map(|m| m.len())
+                    .sum();
+                progress
+                    .bytes_written
+                    .fetch_add(lane_bytes_written - prev_bytes_written, Ordering::SeqCst);
+                prev_bytes_written = lane_bytes_written;
+
+                this_lane_stats.failed_tile_cycles += failed_tile_cycles.load(Ordering::SeqCst);
+
+                progress.set_sample_reads(lane, &this_lane_stats.demux_results);
+                progress.add_tiles(
+                    tid_chunk.len() as u64,
+                    n_assigned * sample_files.iter().filter(|f| !f.is_empty()).count() as u64,
+                    n_bytes,
+                );
             }
         }
+
+        if output_options.read_filter.is_some() {
+            info!(
+                "{} reads in lane {} failed the read filter",
+                this_lane_stats.filtered_reads(),
+                lane
+            );
+        }
+        if pair_reads.is_some() {
+            info!(
+                "{} pairs in lane {} overlap, with {} bases corrected and {} read through",
+                this_lane_stats.overlapping_pairs,
+                lane,
+                this_lane_stats.corrected_bases,
+                this_lane_stats.read_through_pairs
+            );
+        }
+        lane_stats.push(this_lane_stats);
+    }
+
+    for key in ["lane", "surface", "tiles"].iter() {
+        clear_context(key);
+    }
+
+    if let Some(workers) = workers {
+        workers.finish()?;
     }
 
     write_report(&report_filepath, samples, &sample_counts);
 
-    Ok(())
+    Ok(lane_stats)
 }
 
 #[cfg(test)]
@@ -502,6 +3070,7 @@ mod tests {
     use super::*;
     use std::path::PathBuf;
 
+    use crate::progress::ProgressMode;
     use crate::sample_data;
 
     #[test]
@@ -511,25 +3080,406 @@ mod tests {
         let project_name = Some("project_1".to_string());
 
         let file_name1 =
-            super::make_filename(&output_path, &sample_name, &project_name, 0, 1).unwrap();
+            super::make_filename(&output_path, &sample_name, &project_name, 0, 1, "fastq.gz")
+                .unwrap();
         assert_eq!(
             file_name1,
             output_path.join("project_1").join("sample_1_R1.fastq.gz")
         );
 
-        let file_name2 = super::make_filename(&output_path, &sample_name, &None, 1, 2).unwrap();
+        let file_name2 =
+            super::make_filename(&output_path, &sample_name, &None, 1, 2, "fastq.gz").unwrap();
         assert_eq!(file_name2, output_path.join("sample_1_L001_R2.fastq.gz"));
     }
 
+    #[test]
+    fn truncate_index_slices() {
+        let sample_data = sample_data::read_samplesheet(
+            PathBuf::from("test_data/sample_data/no_conflict_w_index2.csv"),
+            1,
+        )
+        .unwrap();
+
+        // the indices are 5 bases
+        let mut idx_slices = vec![[0, 8], [9, 17]];
+        assert_eq!(
+            super::truncate_index_slices(&mut idx_slices, &sample_data[&0]),
+            [(0, 8, 5), (1, 8, 5)]
+        );
+        assert_eq!(idx_slices, [[0, 5], [9, 14]]);
+
+        let mut idx_slices = vec![[0, 5], [6, 10]];
+        assert!(super::truncate_index_slices(&mut idx_slices, &sample_data[&0]).is_empty());
+        assert_eq!(idx_slices, [[0, 5], [6, 10]]);
+    }
+
+    #[test]
+    fn umi_filename() {
+        assert_eq!(
+            super::umi_filename(Path::new("p/s_R1_L001_R1_nonPF.fastq.gz"), 1),
+            Path::new("p/s_R1_L001_UMI_nonPF.fastq.gz")
+        );
+        assert_eq!(
+            super::umi_filename(Path::new("s_R2.shard1of2.seq"), 2),
+            Path::new("s_UMI.shard1of2.seq")
+        );
+    }
+
+    #[test]
+    fn output_extension() {
+        let mut output_options = OutputOptions::default();
+        assert_eq!(output_options.extension(), "fastq.gz");
+
+        output_options.compression = None;
+        assert_eq!(output_options.extension(), "fastq");
+
+        output_options.seq_only = true;
+        assert_eq!(output_options.extension(), "seq");
+
+        output_options.compression = Some(6);
+        assert_eq!(output_options.extension(), "seq.gz");
+
+        output_options.shard = Some(Shard { index: 2, count: 4 });
+        assert_eq!(output_options.extension(), "shard2of4.seq.gz");
+
+        output_options.zstd = true;
+        assert_eq!(output_options.extension(), "shard2of4.seq.zst");
+        output_options.seq_only = false;
+        output_options.shard = None;
+        assert_eq!(output_options.extension(), "fastq.zst");
+    }
+
+    #[test]
+    fn poly_g_trimmed_len() {
+        assert_eq!(super::poly_g_trimmed_len(b"ACGTGGGG", 4), 4);
+        assert_eq!(super::poly_g_trimmed_len(b"ACGTGGGG", 5), 8);
+        assert_eq!(super::poly_g_trimmed_len(b"GGGG", 2), 1);
+        assert_eq!(super::poly_g_trimmed_len(b"", 2), 0);
+        assert_eq!(super::poly_g_trimmed_len(b"ACGTGGGG", 0), 8);
+    }
+
+    #[test]
+    fn read_filter() {
+        let read_filter = ReadFilter {
+            min_mean_quality: Some(20.),
+            max_n_fraction: Some(0.25),
+            keep_filtered: false,
+        };
+        // Q37 and Q2 average to Q19.5
+        assert!(read_filter.passes(b"ACGT", b"FFFF"));
+        assert!(!read_filter.passes(b"ACGT", b"FF##"));
+        assert!(read_filter.passes(b"NCGT", b"#FFF"));
+        assert!(!read_filter.passes(b"NNGT", b"FFFF"));
+        assert!(read_filter.passes(b"", b""));
+
+        let samplesheet_path =
+            PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv");
+        let sampledata = sample_data::read_samplesheet(samplesheet_path, 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+        let n_samples = samples.sample_names.len();
+
+        let assignments = vec![vec![Some((0, 0)), None, Some((1, 1)), Some((0, 1))]];
+        let passing = vec![vec![false, false, true, false]];
+
+        let mut lane_stats = LaneStats::new(1, samples, 2);
+        assert_eq!(
+            filter_assignments(&assignments, &passing, None, &mut lane_stats),
+            [[None, None, Some((1, 1)), None]]
+        );
+        assert_eq!(lane_stats.demux_results[0].number_reads_filtered, 2);
+        assert_eq!(lane_stats.filtered_reads(), 2);
+
+        let mut lane_stats = LaneStats::new(1, samples, 2);
+        assert_eq!(
+            filter_assignments(&assignments, &passing, Some(n_samples), &mut lane_stats),
+            [[
+                Some((n_samples, 0)),
+                None,
+                Some((1, 1)),
+                Some((n_samples, 0))
+            ]]
+        );
+    }
+
+    #[test]
+    fn adapter_dimers() {
+        let samplesheet_path =
+            PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv");
+        let sampledata = sample_data::read_samplesheet(samplesheet_path, 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+        let n_samples = samples.sample_names.len();
+
+        // the last cluster was filtered into the file after the samples'
+        let assignments = vec![vec![
+            Some((0, 0)),
+            None,
+            Some((1, 1)),
+            Some((0, 1)),
+            Some((n_samples, 0)),
+        ]];
+        let dimers = vec![vec![true, true, false, true, true]];
+
+        let mut lane_stats = LaneStats::new(1, samples, 2);
+        assert_eq!(
+            dimer_assignments(&assignments, &dimers, n_samples, None, &mut lane_stats),
+            assignments
+        );
+        assert_eq!(
+            lane_stats.demux_results[0].number_reads_adapter_dimer,
+            Some(2)
+        );
+        assert_eq!(
+            lane_stats.demux_results[1].number_reads_adapter_dimer,
+            Some(0)
+        );
+
+        let mut lane_stats = LaneStats::new(1, samples, 2);
+        assert_eq!(
+            dimer_assignments(
+                &assignments,
+                &dimers,
+                n_samples,
+                Some(n_samples + 1),
+                &mut lane_stats
+            ),
+            [[
+                Some((n_samples + 1, 0)),
+                None,
+                Some((1, 1)),
+                Some((n_samples + 1, 1)),
+                Some((n_samples, 0))
+            ]]
+        );
+    }
+
+    #[test]
+    fn apply_corrections() {
+        use crate::overlap::Correction;
+
+        // two tiles of up to two clusters, with 3 cycles
+        let mut buffer_array = Array3::from_elem((3, 4, 2).f(), b'A');
+        let overlaps = vec![
+            vec![PairOverlap::default(), PairOverlap::default()],
+            vec![PairOverlap {
+                insert_len: Some(4),
+                corrections: [
+                    vec![],
+                    vec![Correction {
+                        position: 2,
+                        base: b'C',
+                        qscore: b'F',
+                    }],
+                ],
+            }],
+        ];
+
+        super::apply_corrections(&mut buffer_array, &overlaps, 0, 2);
+        assert!(buffer_array.iter().all(|&b| b == b'A'));
+
+        super::apply_corrections(&mut buffer_array, &overlaps, 1, 2);
+        assert_eq!(buffer_array[[2, 2, 0]], b'C');
+        assert_eq!(buffer_array[[2, 2, 1]], b'F');
+        assert_eq!(buffer_array.iter().filter(|&&b| b != b'A').count(), 2);
+    }
+
+    #[test]
+    fn overlap_annotation_and_trim() {
+        use crate::overlap::Correction;
+
+        let novaseq_run =
+            NovaSeqRun::read_path("test_data/190414_A00111_0296_AHJCWWDSXX".into(), false).unwrap();
+        let mut buffer_array = Array3::from_elem((4, 1, 2).f(), b'F');
+        for (c, &base) in b"ACGT".iter().enumerate() {
+            buffer_array[[c, 0, 0]] = base;
+        }
+        let index_array = index_buffer(&novaseq_run, 1);
+        let overlaps = [PairOverlap {
+            insert_len: Some(3),
+            corrections: [
+                vec![Correction {
+                    position: 1,
+                    base: b'C',
+                    qscore: b'F',
+                }],
+                vec![],
+            ],
+        }];
+
+        let write = |output_options: &OutputOptions| {
+            let mut out = Vec::new();
+            let read_metrics = write_records(
+                &mut out,
+                &novaseq_run,
+                0,
+                &buffer_array.view(),
+                &index_array.view(),
+                &[Some((0, 0))],
+                &[[1, 2]],
+                None,
+                Some((&overlaps, 0)),
+                None,
+                1101,
+                1,
+                1,
+                output_options,
+            )
+            .unwrap();
+            (String::from_utf8(out).unwrap(), read_metrics.trimmed_bases)
+        };
+
+        let mut output_options = OutputOptions::default();
+        let (record, trimmed_bases) = write(&output_options);
+        assert!(record.lines().next().unwrap().ends_with(" XC:Z:2"));
+        assert_eq!(record.lines().nth(1), Some("ACGT"));
+        assert_eq!(trimmed_bases, 0);
+
+        output_options.overlap = Some(OverlapOptions {
+            min_overlap: 3,
+            correct: true,
+            trim: true,
+        });
+        let (record, trimmed_bases) = write(&output_options);
+        assert_eq!(record.lines().nth(1), Some("ACG"));
+        assert_eq!(record.lines().nth(3), Some("FFF"));
+        // the base read through past the insert
+        assert_eq!(trimmed_bases, 1);
+    }
+
+    #[test]
+    fn file_slots() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let output_path = PathBuf::from("test_data/test_output/file_slots");
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sampledata =
+            sample_data::read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+        let n_samples = samples.sample_names.len();
+        let output_options = OutputOptions {
+            compression: None,
+            read_filter: Some(ReadFilter {
+                min_mean_quality: Some(30.),
+                max_n_fraction: None,
+                keep_filtered: true,
+            }),
+            non_pf: Some(NonPfOutput::Separate),
+            phix: Some(PhixControl {
+                indices: Vec::new(),
+                write: true,
+            }),
+            adapter_dimers: Some(DimerOutput::Separate),
+            ..Default::default()
+        };
+
+        let slots = FileSlots::new(n_samples, &output_options);
+        assert_eq!(
+            slots,
+            FileSlots {
+                n_samples,
+                filtered: Some(n_samples),
+                non_pf: Some(n_samples + 1),
+                phix: Some(2 * n_samples + 1),
+                dimers: Some(2 * n_samples + 2),
+            }
+        );
+
+        // the slots are where lane_filepaths puts the files
+        let paths =
+            lane_filepaths(&novaseq_run, samples, 1, &output_path, &output_options).unwrap();
+        let name = |i: usize| {
+            paths[0][i]
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        assert_eq!(paths[0].len(), 3 * n_samples + 2);
+        assert!(name(slots.filtered.unwrap()).starts_with(FILTERED_NAME));
+        assert!(name(slots.non_pf.unwrap()).contains(NON_PF_SUFFIX));
+        assert!(name(slots.phix.unwrap()).starts_with(PHIX_NAME));
+        assert!(name(slots.dimers.unwrap()).contains(DIMER_SUFFIX));
+        assert!(name(slots.dimers.unwrap() + n_samples - 1).contains(DIMER_SUFFIX));
+
+        assert_eq!(
+            FileSlots::new(n_samples, &OutputOptions::default()),
+            FileSlots {
+                n_samples,
+                filtered: None,
+                non_pf: None,
+                phix: None,
+                dimers: None,
+            }
+        );
+    }
+
+    #[test]
+    fn single_file() {
+        // two samples, then the filtered clusters
+        let assignments = vec![vec![Some((1, 1)), None, Some((2, 0)), Some((0, 0))]];
+        assert_eq!(
+            single_file_assignments(&assignments, 2),
+            [[Some((0, 1)), None, Some((1, 0)), Some((0, 0))]]
+        );
+
+        let mut buffer_array = Array3::from_elem((2, 4, 2).f(), b'F');
+        buffer_array[[0, 3, 1]] = b'#';
+        let read_metrics = sample_metrics(
+            &buffer_array.view(),
+            &assignments[0],
+            Some(&[true, true, true, false]),
+            2,
+            1,
+        );
+        assert_eq!(read_metrics[0].yield_bases, 0);
+        assert_eq!(read_metrics[1].yield_bases, 2);
+
+        let novaseq_run =
+            NovaSeqRun::read_path("test_data/190414_A00111_0296_AHJCWWDSXX".into(), false).unwrap();
+        let index_array = index_buffer(&novaseq_run, 4);
+        let mut out = Vec::new();
+        write_records(
+            &mut out,
+            &novaseq_run,
+            0,
+            &buffer_array.view(),
+            &index_array.view(),
+            &single_file_assignments(&assignments, 2)[0],
+            &[[1, 2]; 4],
+            None,
+            None,
+            Some(&[Some("s2"), None, Some("s1"), Some("s1")]),
+            1101,
+            1,
+            1,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        let names: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .step_by(4)
+            .map(|name| name.rsplit(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["SM:Z:s2", "SM:Z:s1"]);
+    }
+
     #[test]
     fn make_report_filename() {
         let output_path = PathBuf::from("test_data/test_output");
 
-        let file_name1 = super::make_report_filename(&output_path, 0);
+        let file_name1 = super::make_report_filename(&output_path, 0, &None);
         assert_eq!(file_name1, output_path.join("barcode_report.txt"));
 
-        let file_name2 = super::make_report_filename(&output_path, 1);
+        let file_name2 = super::make_report_filename(&output_path, 1, &None);
         assert_eq!(file_name2, output_path.join("barcode_L001_report.txt"));
+
+        let shard = Some(Shard { index: 1, count: 2 });
+        let file_name3 = super::make_report_filename(&output_path, 1, &shard);
+        assert_eq!(
+            file_name3,
+            output_path.join("barcode_L001_report.shard1of2.txt")
+        );
     }
 
     #[test]
@@ -543,6 +3493,520 @@ mod tests {
         let sampledata = sample_data::read_samplesheet(samplesheet_path, 1).unwrap();
         let samples = sampledata.get(&1).unwrap();
 
-        super::demux_fastqs(&novaseq_run, 1, samples, &output_path, 2, 1).unwrap();
+        let lane_stats = super::demux_fastqs(
+            &novaseq_run,
+            1,
+            samples,
+            &output_path,
+            2,
+            &OutputOptions::default(),
+            &Progress::new(ProgressMode::Hidden, 3),
+        )
+        .unwrap();
+
+        assert_eq!(lane_stats.len(), 1);
+        assert_eq!(lane_stats[0].total_clusters_raw, 300);
+        assert_eq!(lane_stats[0].total_clusters_pf, 245);
+        assert_eq!(lane_stats[0].undetermined.number_reads, 23);
+        assert_eq!(
+            lane_stats[0]
+                .demux_results
+                .iter()
+                .map(|s| s.number_reads)
+                .sum::<u64>(),
+            222
+        );
+
+        // every assigned read is counted once for each index, and two reads had a
+        // mismatch in one of them
+        let histograms: Vec<_> = (0..2)
+            .map(|index_i| {
+                lane_stats[0]
+                    .demux_results
+                    .iter()
+                    .fold(vec![0; 2], |mut total, s| {
+                        for (t, n) in total.iter_mut().zip(&s.index_mismatch_histogram[index_i]) {
+                            *t += n;
+                        }
+                        total
+                    })
+            })
+            .collect();
+        assert!(histograms.iter().all(|h| h.iter().sum::<u64>() == 222));
+        assert_eq!(histograms[0][1] + histograms[1][1], 2);
+
+        let cycle_metrics = &lane_stats[0].cycle_metrics;
+        assert_eq!(cycle_metrics.len(), 2);
+        assert_eq!(cycle_metrics[0].len(), 4);
+        assert!(cycle_metrics[0].iter().all(|c| c.n_bases() == 245));
+
+        let index_cycle_metrics = &lane_stats[0].index_cycle_metrics;
+        assert_eq!(index_cycle_metrics.len(), 2);
+        assert_eq!(index_cycle_metrics[0].len(), 8);
+        assert!(index_cycle_metrics[1].iter().all(|c| c.n_bases() == 245));
+
+        let tile_stats = &lane_stats[0].tile_stats;
+        assert_eq!(tile_stats.values().map(|t| t.clusters_pf).sum::<u64>(), 245);
+        assert_eq!(
+            tile_stats
+                .values()
+                .map(|t| t.clusters_assigned)
+                .sum::<u64>(),
+            222
+        );
+    }
+
+    #[test]
+    fn undetermined_limit() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let samplesheet_path = run_path.join("SampleSheet.csv");
+        let output_path = PathBuf::from("test_data/test_output/undetermined_limit");
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        let sampledata = sample_data::read_samplesheet(samplesheet_path, 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+
+        let demux = |max_fraction, two_phase| {
+            super::demux_fastqs(
+                &novaseq_run,
+                1,
+                samples,
+                &output_path,
+                1,
+                &OutputOptions {
+                    compression: None,
+                    two_phase,
+                    undetermined_limit: Some(UndeterminedLimit {
+                        max_fraction,
+                        after_tiles: 2,
+                    }),
+                    ..Default::default()
+                },
+                &Progress::new(ProgressMode::Hidden, 3),
+            )
+        };
+
+        for two_phase in [false, true] {
+            let e = demux(0.01, two_phase).unwrap_err();
+            assert!(is_undetermined_limit(&e));
+            assert!(e.to_string().contains("in the first 2 tiles of lane 1"));
+            assert!(e.to_string().contains("Most common unknown barcodes: "));
+
+            let lane_stats = demux(0.5, two_phase).unwrap();
+            assert_eq!(lane_stats[0].undetermined.number_reads, 23);
+        }
+    }
+
+    #[test]
+    fn index_cache() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let samplesheet_path = run_path.join("SampleSheet.csv");
+        let output_path = PathBuf::from("test_data/test_output/index_cache_demux");
+        let cache_path = output_path.join("cache");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&cache_path).unwrap();
+
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        let sampledata = sample_data::read_samplesheet(samplesheet_path, 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+
+        let demux = |two_phase| {
+            super::demux_fastqs(
+                &novaseq_run,
+                1,
+                samples,
+                &output_path,
+                2,
+                &OutputOptions {
+                    compression: None,
+                    two_phase,
+                    index_cache: Some(cache_path.clone()),
+                    ..Default::default()
+                },
+                &Progress::new(ProgressMode::Hidden, 3),
+            )
+            .unwrap()
+        };
+
+        // the first run fills the cache, and the others read from it
+        for two_phase in [false, false, true] {
+            let lane_stats = demux(two_phase);
+            assert_eq!(lane_stats[0].total_clusters_pf, 245);
+            assert_eq!(lane_stats[0].undetermined.number_reads, 23);
+            assert_eq!(std::fs::read_dir(&cache_path).unwrap().count(), 3);
+        }
+
+        // so if a tile's indices are changed in the cache, its reads are undetermined
+        let n_pf = novaseq_run.n_pfs[&[1, 1]][0];
+        let mut no_calls = index_buffer(&novaseq_run, n_pf);
+        for &[i0, i1] in &index_slices(&novaseq_run) {
+            no_calls.slice_mut(ndarray::s![i0..i1, .., 0]).fill(b'N');
+        }
+        index_cache::write_tile(
+            &index_cache::tile_path(
+                &cache_path,
+                &index_cache::cache_key(&novaseq_run, true),
+                1,
+                1,
+                1101,
+            ),
+            &no_calls.view(),
+        )
+        .unwrap();
+
+        let lane_stats = demux(false);
+        assert_eq!(lane_stats[0].tile_stats[&1101].clusters_assigned, 0);
+        assert!(lane_stats[0].tile_stats[&1102].clusters_assigned > 0);
+    }
+
+    #[test]
+    fn skip_reads() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let samplesheet_path = run_path.join("SampleSheet.csv");
+        let output_path = PathBuf::from("test_data/test_output/skip_reads");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        let sampledata = sample_data::read_samplesheet(samplesheet_path, 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+
+        let lane_stats = super::demux_fastqs(
+            &novaseq_run,
+            1,
+            samples,
+            &output_path,
+            2,
+            &OutputOptions {
+                compression: None,
+                skip_reads: [2].iter().cloned().collect(),
+                ..Default::default()
+            },
+            &Progress::new(ProgressMode::Hidden, 3),
+        )
+        .unwrap();
+
+        let project_path = output_path.join("project_1");
+        assert!(project_path.join("8034211010_L001_R1.fastq").is_file());
+        assert!(!project_path.join("8034211010_L001_R2.fastq").exists());
+
+        // the reads are still counted, but R2 was never decoded
+        assert_eq!(lane_stats[0].undetermined.number_reads, 23);
+        let demux_results = &lane_stats[0].demux_results;
+        assert!(demux_results
+            .iter()
+            .any(|r| r.read_metrics[0].yield_bases > 0));
+        assert!(demux_results
+            .iter()
+            .all(|r| r.read_metrics[1].yield_bases == 0));
+    }
+
+    #[test]
+    fn phix() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let samplesheet_path = run_path.join("SampleSheet.csv");
+        let output_path = PathBuf::from("test_data/test_output/phix");
+        let _ = std::fs::remove_dir_all(&output_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        let sampledata = sample_data::read_samplesheet(samplesheet_path, 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+
+        let phix = PhixControl {
+            indices: vec![parse_phix_index("AAACCAGA+AATCCAGC").unwrap()],
+            write: true,
+        };
+        let lane_stats = super::demux_fastqs(
+            &novaseq_run,
+            1,
+            samples,
+            &output_path,
+            2,
+            &OutputOptions {
+                compression: None,
+                phix: Some(phix),
+                ..Default::default()
+            },
+            &Progress::new(ProgressMode::Hidden, 3),
+        )
+        .unwrap();
+
+        // five reads with no first index, and one with the PhiX index
+        assert_eq!(lane_stats[0].phix.number_reads, 6);
+        assert_eq!(lane_stats[0].undetermined.number_reads, 17);
+        assert_eq!(lane_stats[0].number_reads(), 245);
+        assert_eq!(lane_stats[0].phix.read_metrics[0].yield_bases, 24);
+
+        let phix_reads = std::fs::read_to_string(output_path.join("PhiX_L001_R1.fastq")).unwrap();
+        assert_eq!(phix_reads.lines().count(), 24);
+        assert!(phix_reads.contains(":0:GGGGGGGG+"));
+        assert!(phix_reads.contains(":0:AAACCAGA+AATCCAGC"));
+
+        assert!(parse_phix_index("ACGT+").is_err());
+        assert!(parse_phix_index("acgt").is_err());
+    }
+
+    #[test]
+    fn assigned_filter() {
+        // clusters 0, 1, 3 and 4 pass the filter, and 1 and 4 were assigned
+        let filter = [0b11, 0b01, 0b10];
+        let assigned = [false, true, false, true];
+
+        assert_eq!(
+            super::assigned_filter(&filter, assigned.iter().cloned()),
+            vec![0b01, 0b00, 0b10]
+        );
+    }
+
+    #[test]
+    fn demux_two_phase() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sampledata =
+            sample_data::read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+
+        let demux = |output_path: &Path, two_phase: bool, two_phase_undetermined: bool| {
+            let _ = std::fs::remove_dir_all(output_path);
+            std::fs::create_dir_all(output_path).unwrap();
+
+            super::demux_fastqs(
+                &novaseq_run,
+                1,
+                samples,
+                output_path,
+                2,
+                &OutputOptions {
+                    compression: None,
+                    two_phase,
+                    two_phase_undetermined,
+                    ..Default::default()
+                },
+                &Progress::new(ProgressMode::Hidden, 3),
+            )
+            .unwrap()
+            .remove(0)
+        };
+
+        let one_pass_path = PathBuf::from("test_data/test_output/one_pass");
+        let two_phase_path = PathBuf::from("test_data/test_output/two_phase");
+        let one_pass = demux(&one_pass_path, false, false);
+        let two_phase = demux(&two_phase_path, true, false);
+
+        assert_eq!(two_phase.total_clusters_pf, one_pass.total_clusters_pf);
+        assert_eq!(
+            two_phase.undetermined.number_reads,
+            one_pass.undetermined.number_reads
+        );
+        assert_eq!(two_phase.unknown_barcodes, one_pass.unknown_barcodes);
+        assert_eq!(two_phase.tile_stats.len(), 3);
+        for (s2, s1) in two_phase.demux_results.iter().zip(&one_pass.demux_results) {
+            assert_eq!(s2.number_reads, s1.number_reads);
+            assert_eq!(s2.index_metrics, s1.index_metrics);
+            assert_eq!(s2.read_metrics, s1.read_metrics);
+        }
+        // the undetermined reads are never read
+        assert_eq!(two_phase.undetermined.read_metrics[0].yield_bases, 0);
+        // unless they are asked for
+        let with_undetermined = demux(&two_phase_path, true, true);
+        assert_eq!(with_undetermined.undetermined, one_pass.undetermined);
+        assert_eq!(with_undetermined.demux_results, one_pass.demux_results);
+
+        for entry in std::fs::read_dir(one_pass_path.join("project_1")).unwrap() {
+            let path = entry.unwrap().path();
+            let two_phase_file = two_phase_path
+                .join("project_1")
+                .join(path.file_name().unwrap());
+            assert_eq!(
+                std::fs::read_to_string(two_phase_file).unwrap(),
+                std::fs::read_to_string(&path).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn demux_compression_workers() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path.clone(), false).unwrap();
+        let sampledata =
+            sample_data::read_samplesheet(run_path.join("SampleSheet.csv"), 1).unwrap();
+        let samples = sampledata.get(&1).unwrap();
+
+        let demux = |output_path: &Path, compression_workers: bool| {
+            let _ = std::fs::remove_dir_all(output_path);
+            std::fs::create_dir_all(output_path).unwrap();
+
+            super::demux_fastqs(
+                &novaseq_run,
+                1,
+                samples,
+                output_path,
+                2,
+                &OutputOptions {
+                    compression_workers,
+                    ..Default::default()
+                },
+                &Progress::new(ProgressMode::Hidden, 3),
+            )
+            .unwrap()
+            .remove(0)
+        };
+
+        let inline_path = PathBuf::from("test_data/test_output/inline_compression");
+        let workers_path = PathBuf::from("test_data/test_output/worker_compression");
+        let inline = demux(&inline_path, false);
+        let workers = demux(&workers_path, true);
+        assert_eq!(workers.demux_results, inline.demux_results);
+
+        let read_fastq = |path: &Path| {
+            let mut text = String::new();
+            flate2::read::MultiGzDecoder::new(File::open(path).unwrap())
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        };
+        for entry in std::fs::read_dir(inline_path.join("project_1")).unwrap() {
+            let path = entry.unwrap().path();
+            let workers_file = workers_path
+                .join("project_1")
+                .join(path.file_name().unwrap());
+            assert_eq!(read_fastq(&workers_file), read_fastq(&path));
+        }
+    }
+
+    #[test]
+    fn non_pf() {
+        assert_eq!(
+            super::filter_flags(&[0b10, 0b01, 0b11], 5),
+            [true, false, false, true, true]
+        );
+
+        // the test run's CBCLs only have the clusters that passed filter
+        let mut novaseq_run =
+            NovaSeqRun::read_path("test_data/190414_A00111_0296_AHJCWWDSXX".into(), false).unwrap();
+        let e = novaseq_run.include_non_pf().unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("only has the clusters that passed filter"));
+
+        let test_path = PathBuf::from("test_data/test_output/non_pf");
+        let run_path = test_path.join("run");
+        let _ = std::fs::remove_dir_all(&test_path);
+        let sampledata = sample_data::read_samplesheet(
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv".into(),
+            1,
+        )
+        .unwrap();
+        crate::genrun::generate_run(
+            Path::new("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv"),
+            &sampledata,
+            &run_path,
+            &Default::default(),
+        )
+        .unwrap();
+        let samples = sampledata.get(&1).unwrap();
+
+        let demux = |novaseq_run: &NovaSeqRun, name: &str, non_pf: Option<NonPfOutput>| {
+            let output_path = test_path.join(name);
+            std::fs::create_dir_all(&output_path).unwrap();
+
+            super::demux_fastqs(
+                novaseq_run,
+                1,
+                samples,
+                &output_path,
+                2,
+                &OutputOptions {
+                    compression: None,
+                    non_pf,
+                    ..Default::default()
+                },
+                &Progress::new(ProgressMode::Hidden, 3),
+            )
+            .unwrap()
+            .remove(0)
+        };
+        let read_file = |name: &str, file: &str| {
+            std::fs::read_to_string(test_path.join(name).join("project_1").join(file)).unwrap()
+        };
+
+        let mut novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+        let pf_only = demux(&novaseq_run, "pf_only", None);
+        novaseq_run.include_non_pf().unwrap();
+        assert!(novaseq_run.n_pfs[&[1, 1]].iter().all(|&n| n == 100));
+        let separate = demux(&novaseq_run, "separate", Some(NonPfOutput::Separate));
+        let inline = demux(&novaseq_run, "inline", Some(NonPfOutput::Inline));
+
+        // the stats only count the clusters that passed filter
+        for stats in [&separate, &inline].iter() {
+            assert_eq!(stats.total_clusters_pf, pf_only.total_clusters_pf);
+            assert_eq!(stats.undetermined, pf_only.undetermined);
+            for (s, pf) in stats.demux_results.iter().zip(&pf_only.demux_results) {
+                assert_eq!(s.number_reads, pf.number_reads);
+                assert_eq!(s.read_metrics, pf.read_metrics);
+            }
+        }
+
+        let mut n_non_pf = 0;
+        for sample_name in samples.sample_names.iter() {
+            let pf_file = format!("{}_L001_R1.fastq", sample_name);
+            let pf_reads = read_file("pf_only", &pf_file);
+            assert_eq!(read_file("separate", &pf_file), pf_reads);
+
+            let non_pf_reads =
+                read_file("separate", &format!("{}_L001_R1_nonPF.fastq", sample_name));
+            assert!(non_pf_reads
+                .lines()
+                .step_by(4)
+                .all(|l| l.contains(" 1:Y:0:")));
+            n_non_pf += non_pf_reads.lines().count() / 4;
+
+            // inline, the reads that failed are mixed in with the rest
+            let inline_reads = read_file("inline", &pf_file);
+            assert_eq!(
+                inline_reads.lines().count(),
+                pf_reads.lines().count() + non_pf_reads.lines().count()
+            );
+        }
+        assert!(n_non_pf > 0);
+    }
+
+    #[test]
+    fn check_ascii_offset() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+
+        // the highest bin is Q37
+        assert!(super::check_ascii_offset(&novaseq_run, 33).is_ok());
+        assert!(super::check_ascii_offset(&novaseq_run, 64).is_ok());
+        assert!(super::check_ascii_offset(&novaseq_run, 89).is_ok());
+        let e = super::check_ascii_offset(&novaseq_run, 90).unwrap_err();
+        assert!(e.contains(".cbcl go up to Q37"), "{}", e);
+        assert!(super::check_ascii_offset(&novaseq_run, 20).is_err());
+    }
+
+    #[test]
+    fn dump_tile() {
+        let run_path = PathBuf::from("test_data/190414_A00111_0296_AHJCWWDSXX");
+        let novaseq_run = NovaSeqRun::read_path(run_path, false).unwrap();
+
+        let mut output = Vec::new();
+        let n_pf = super::dump_tile(&novaseq_run, 1, 1101, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+
+        assert_eq!(lines.len(), n_pf * 2 * 4);
+        assert!(lines[0].starts_with("@A00111:296:HJCWWDSXX:1:1101:"));
+        assert_eq!(
+            lines[0].split(' ').nth(1).unwrap().len(),
+            "1:N:0:".len() + 17
+        );
+        assert_eq!(lines[1].len(), 4);
+        assert_eq!(lines[2], "+");
+        assert!(lines[n_pf * 4].contains(" 2:N:0:"));
+
+        assert!(super::dump_tile(&novaseq_run, 1, 9999, &mut Vec::new()).is_err());
     }
 }
@@ -0,0 +1,245 @@
+//! Dictionaries for zstd output. Every chunk of reads is its own zstd frame, and a
+//! small frame compresses worse than one long stream, as it starts with nothing to
+//! refer back to. A dictionary trained on fastq records gives each frame the read
+//! headers and quality strings to start from. The frames can only be decompressed
+//! with the same dictionary, so a demux keeps a copy of it next to the fastq files.
+//! Gzip and BGZF output can't be primed this way: gzip has no field for a preset
+//! dictionary, so standard readers couldn't inflate them
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+/// The copy of the dictionary in the output folder, for `zstd -d -D`
+pub const DICTIONARY_NAME: &str = "fastq.zstd_dict";
+
+/// The largest dictionary trained by default, the zstd command line's default
+pub const DEFAULT_DICTIONARY_SIZE: usize = 112_640;
+
+/// The lines of fastq in each training sample, a few reads like a small frame
+const SAMPLE_LINES: usize = 64;
+
+/// The most fastq read for training, which is already far more than zstd needs
+const MAX_TRAINING_BYTES: usize = 64 << 20;
+
+/// A zstd dictionary, prepared for the compression level it is used with
+pub struct ZstdDictionary {
+    bytes: Vec<u8>,
+    #[cfg(feature = "zstd")]
+    prepared: zstd::dict::EncoderDictionary<'static>,
+}
+
+impl ZstdDictionary {
+    /// Prepare a dictionary for compressing at `level`
+    #[cfg(feature = "zstd")]
+    pub fn new(bytes: Vec<u8>, level: u32) -> io::Result<ZstdDictionary> {
+        if bytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the zstd dictionary is empty",
+            ));
+        }
+        let prepared = zstd::dict::EncoderDictionary::copy(&bytes, level as i32);
+        Ok(ZstdDictionary { bytes, prepared })
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    pub fn new(_bytes: Vec<u8>, _level: u32) -> io::Result<ZstdDictionary> {
+        Err(unsupported())
+    }
+
+    /// Read a dictionary file, e.g. from `zstd --train` or `train_dictionary`
+    pub fn read(path: &Path, level: u32) -> io::Result<ZstdDictionary> {
+        ZstdDictionary::new(std::fs::read(path)?, level)
+    }
+
+    /// The dictionary as it is stored
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    #[cfg(feature = "zstd")]
+    pub(crate) fn prepared(&self) -> &zstd::dict::EncoderDictionary<'static> {
+        &self.prepared
+    }
+}
+
+impl PartialEq for ZstdDictionary {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ZstdDictionary({} bytes)", self.bytes.len())
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "bcl2fastr was built without the zstd feature, so it can't use zstd dictionaries",
+    )
+}
+
+/// Open a fastq file to read, decompressing it if it is gzipped or zstd. A zstd
+/// file written with a dictionary needs the same one
+pub(crate) fn open_fastq(path: &Path, dictionary: Option<&[u8]>) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::with_capacity(1 << 20, File::open(path)?);
+    Ok(match path.extension() {
+        Some(ext) if ext == "gz" => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+        Some(ext) if ext == "zst" => zstd_decoder(file, dictionary)?,
+        _ => Box::new(file),
+    })
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decoder(file: BufReader<File>, dictionary: Option<&[u8]>) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(zstd::stream::read::Decoder::with_dictionary(
+        file,
+        dictionary.unwrap_or_default(),
+    )?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decoder(_file: BufReader<File>, _dictionary: Option<&[u8]>) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "bcl2fastr was built without the zstd feature, so it can't read zstd",
+    ))
+}
+
+/// Split fastq into samples of a few reads each, up to the training limit
+fn training_samples<R: BufRead>(readers: impl IntoIterator<Item = R>) -> io::Result<Vec<Vec<u8>>> {
+    let mut samples = Vec::new();
+    let mut total = 0;
+    for mut reader in readers {
+        let mut sample = Vec::new();
+        let mut lines = 0;
+        while total < MAX_TRAINING_BYTES {
+            let n = reader.read_until(b'\n', &mut sample)?;
+            total += n;
+            if n > 0 {
+                lines += 1;
+            }
+            if n == 0 || lines == SAMPLE_LINES {
+                if !sample.is_empty() {
+                    samples.push(std::mem::take(&mut sample));
+                }
+                lines = 0;
+            }
+            if n == 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+/// Train a dictionary of at most `max_size` bytes on the reads of some fastq files,
+/// in samples of a few reads, like the frames of small chunks
+pub fn train_dictionary<R: BufRead>(
+    readers: impl IntoIterator<Item = R>,
+    max_size: usize,
+) -> io::Result<Vec<u8>> {
+    from_samples(&training_samples(readers)?, max_size)
+}
+
+#[cfg(feature = "zstd")]
+fn from_samples(samples: &[Vec<u8>], max_size: usize) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn from_samples(_samples: &[Vec<u8>], _max_size: usize) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+/// Train a dictionary on fastq files, which can be compressed
+pub fn train_from_files(paths: &[&Path], max_size: usize) -> io::Result<Vec<u8>> {
+    let readers = paths
+        .iter()
+        .map(|path| Ok(BufReader::new(open_fastq(path, None)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    train_dictionary(readers, max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads whose headers, qualities and sequences repeat a lot
+    fn fastq(n_reads: usize) -> String {
+        (0..n_reads)
+            .map(|i| {
+                let seq: String = (0..100)
+                    .map(|j| b"ACGT"[(i * 7 + j * j) % 4] as char)
+                    .collect();
+                format!(
+                    "@A00111:296:HJCWWDSXX:1:1101:{}:{} 1:N:0:ACGTACGT+TTGGCCAA\n{}\n+\n{}\n",
+                    i % 32000,
+                    (i * 13) % 37000,
+                    seq,
+                    &"FF:F,FFFF".repeat(12)[..100]
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn samples() {
+        let reads = fastq(100);
+        let samples = training_samples([reads.as_bytes(), b"@r\nA\n+\nF" as &[u8]]).unwrap();
+        // 400 lines in groups of 64, then the second file
+        assert_eq!(samples.len(), 8);
+        assert_eq!(
+            samples[0].iter().filter(|&&b| b == b'\n').count(),
+            SAMPLE_LINES
+        );
+        assert_eq!(samples[..7].concat(), reads.as_bytes());
+        assert_eq!(samples[7], b"@r\nA\n+\nF");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn smaller_frames() {
+        let reads = fastq(20000);
+        let bytes = train_dictionary([reads.as_bytes()], 16 << 10).unwrap();
+        assert!(bytes.len() <= 16 << 10);
+        let dictionary = ZstdDictionary::new(bytes, 3).unwrap();
+
+        // one small frame, like a chunk of a few reads
+        let frame_reads = fastq(20)[..].to_string();
+        let plain = zstd::stream::encode_all(frame_reads.as_bytes(), 3).unwrap();
+        let mut encoder = zstd::stream::write::Encoder::with_prepared_dictionary(
+            Vec::new(),
+            dictionary.prepared(),
+        )
+        .unwrap();
+        io::Write::write_all(&mut encoder, frame_reads.as_bytes()).unwrap();
+        let primed = encoder.finish().unwrap();
+        assert!(primed.len() < plain.len());
+
+        let mut decoded = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(&primed[..], dictionary.bytes())
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, frame_reads.as_bytes());
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn without_zstd() {
+        assert_eq!(
+            ZstdDictionary::new(vec![1, 2, 3], 3).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+}
@@ -10,7 +10,8 @@ mod integration {
     #[test]
     fn run() {
         let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
-        cmd.args(&[
+        cmd.args([
+            "demux",
             "--run-path",
             "test_data/190414_A00111_0296_AHJCWWDSXX",
             "--samplesheet",
@@ -20,22 +21,3128 @@ mod integration {
         ]);
 
         cmd.assert().success();
+
+        let output_path = std::path::Path::new("test_data/test_output");
+        assert!(output_path.join("run_summary.json").is_file());
+        assert!(output_path.join("Stats/Stats.json").is_file());
+        assert!(output_path.join("Reports/Demultiplex_Stats.csv").is_file());
+        assert!(output_path
+            .join("Reports/Top_Unknown_Barcodes.csv")
+            .is_file());
+        assert!(output_path.join("Reports/Cycle_Metrics.csv").is_file());
+        assert!(output_path
+            .join("Reports/Index_Hopping_Summary.csv")
+            .is_file());
+        assert!(output_path
+            .join("Reports/Unknown_Barcode_Hints.csv")
+            .is_file());
+        assert!(output_path.join("Reports/Tile_Metrics.csv").is_file());
+        assert!(output_path.join("Reports/Swath_Metrics.csv").is_file());
+        assert!(output_path.join("Reports/bcl2fastr_mqc.json").is_file());
+        assert!(output_path.join("Reports/Barcode_Balance.csv").is_file());
+        assert!(output_path.join("Reports/Index_Base_Balance.csv").is_file());
+        assert!(output_path.join("Reports/report.html").is_file());
+
+        // 245 of the 300 clusters passed filter
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(summary.contains("\"clusters_pf\": 245,"));
+        assert!(summary.contains("\"pf_fraction\": 0.8166"));
+    }
+
+    #[test]
+    fn run_uncompressed_seq_only() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output",
+            "--no-compression",
+            "--seq-only",
+        ]);
+
+        cmd.assert().success();
+
+        let seqs =
+            std::fs::read_to_string("test_data/test_output/project_1/8034211776_L001_R1.seq")
+                .unwrap();
+        assert_eq!(seqs.lines().count(), 10);
+        assert!(seqs.lines().all(|l| l.len() == 4));
+    }
+
+    #[test]
+    fn ascii_offset() {
+        let output_path = std::path::Path::new("test_data/test_output/ascii_offset");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let demux = |ascii_offset: &str| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/ascii_offset",
+                "--no-compression",
+                "--ascii-offset",
+                ascii_offset,
+            ]);
+            cmd.assert()
+        };
+
+        demux("64").success();
+        let fastq = std::fs::read_to_string(output_path.join("project_1/8034211776_L001_R1.fastq"))
+            .unwrap();
+        // Q2, Q11, Q25 and Q37 as phred+64
+        let qscores: Vec<_> = fastq.lines().skip(3).step_by(4).collect();
+        assert_eq!(qscores.len(), 10);
+        assert!(qscores
+            .iter()
+            .all(|q| q.bytes().all(|b| [66, 75, 89, 101].contains(&b))));
+
+        demux("90").failure().code(1).stderr(
+            predicate::str::contains("invalid value for 'ascii-offset'")
+                .and(predicate::str::contains("go up to Q37"))
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn read_filter() {
+        let output_path = std::path::Path::new("test_data/test_output/read_filter");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let demux = |extra_args: &[&str]| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/read_filter",
+                "--no-compression",
+                "--min-mean-quality",
+                "28",
+            ])
+            .args(extra_args);
+            cmd.assert()
+        };
+        let n_reads = |path: &str| {
+            std::fs::read_to_string(output_path.join(path))
+                .unwrap()
+                .lines()
+                .count()
+                / 4
+        };
+
+        // R2 starts with an N at Q2, so one more low-quality base fails the cluster
+        demux(&["--keep-filtered"]).success();
+        assert_eq!(n_reads("project_1/8034211776_L001_R1.fastq"), 9);
+        assert_eq!(n_reads("project_1/8034211776_L001_R2.fastq"), 9);
+        assert_eq!(n_reads("Filtered_L001_R1.fastq"), 45);
+        assert_eq!(n_reads("Filtered_L001_R2.fastq"), 45);
+
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReadsFiltered\": 1,"));
+
+        std::fs::remove_dir_all(output_path).unwrap();
+        std::fs::create_dir_all(output_path).unwrap();
+        demux(&[]).success();
+        assert_eq!(n_reads("project_1/8034211776_L001_R1.fastq"), 9);
+        assert!(!output_path.join("Filtered_L001_R1.fastq").exists());
+
+        demux(&["--max-n-fraction", "2"])
+            .failure()
+            .code(1)
+            .stderr(predicate::str::contains("invalid value for 'max-n-fraction'").from_utf8());
+    }
+
+    #[test]
+    fn include_non_pf() {
+        let run_path = "test_data/test_output/include_non_pf_run";
+        let output_path = std::path::Path::new("test_data/test_output/include_non_pf");
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "genrun",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            run_path,
+        ]);
+        cmd.assert().success();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            run_path,
+            "--samplesheet",
+            "test_data/test_output/include_non_pf_run/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/include_non_pf",
+            "--no-compression",
+            "--include-non-pf",
+            "separate",
+        ]);
+        cmd.assert().success();
+        let project_path = output_path.join("iseq_project");
+        assert!(project_path.join("iseq_1_L001_R1.fastq").is_file());
+        let non_pf_reads =
+            std::fs::read_to_string(project_path.join("iseq_1_L001_R2_nonPF.fastq")).unwrap();
+        assert!(non_pf_reads
+            .lines()
+            .step_by(4)
+            .all(|l| l.contains(" 2:Y:0:")));
+
+        // the NovaSeq left out the clusters that failed filter
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/include_non_pf",
+            "--include-non-pf",
+            "inline",
+        ]);
+        cmd.assert()
+            .failure()
+            .code(3)
+            .stderr(predicate::str::contains("Can't use --include-non-pf").from_utf8());
+    }
+
+    #[test]
+    fn delivery_manifests() {
+        let output_path = std::path::Path::new("test_data/test_output/delivery_manifests");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/delivery_manifests",
+            "--delivery-manifests",
+        ]);
+        cmd.assert().success();
+
+        let project_stats =
+            std::fs::read_to_string(output_path.join("Reports/Project_Stats.csv")).unwrap();
+        assert!(project_stats.contains("project_1,93,222,1776,"));
+
+        let manifest =
+            std::fs::read_to_string(output_path.join("project_1/delivery_manifest.json")).unwrap();
+        assert!(manifest.contains("\"path\": \"8034211776_L001_R1.fastq.gz\""));
+        assert!(manifest.contains("\"reads\": 222"));
+    }
+
+    #[test]
+    fn run_json_progress() {
+        let output_path = "test_data/test_output/json_progress";
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            output_path,
+            "--json-progress",
+        ]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains(r#""tiles_done":3"#)
+                .and(predicate::str::contains(r#""done":true"#))
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn run_webhook() {
+        let output_path = "test_data/test_output/webhook";
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", server.server_addr());
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            output_path,
+            "--on-complete-webhook",
+            &url,
+        ]);
+
+        let handle = std::thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            request.respond(tiny_http::Response::empty(200)).unwrap();
+            body
+        });
+
+        cmd.assert().success();
+
+        let body = handle.join().unwrap();
+        assert!(body.contains(r#""status":"success""#));
+    }
+
+    #[test]
+    fn run_config() {
+        let output_path = std::path::Path::new("test_data/test_output/config");
+        std::fs::create_dir_all(output_path).unwrap();
+        std::fs::write(
+            output_path.join("run.toml"),
+            "seq_only = true\nno-compression = true\nread-chunks = 2\nthreads = 2\n",
+        )
+        .unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/config",
+            "--config",
+            "test_data/test_output/config/run.toml",
+            "--read-chunks",
+            "3",
+        ]);
+
+        cmd.assert().success();
+
+        assert!(output_path
+            .join("project_1/8034211776_L001_R1.seq")
+            .is_file());
+
+        let config = std::fs::read_to_string(output_path.join("config.toml")).unwrap();
+        assert!(config.contains("seq-only = true"));
+        assert!(config.contains("read-chunks = 3"));
+        assert!(config.contains("threads = 2"));
+    }
+
+    #[test]
+    fn config_unknown_option() {
+        let output_path = std::path::Path::new("test_data/test_output/config_unknown");
+        std::fs::create_dir_all(output_path).unwrap();
+        std::fs::write(
+            output_path.join("run.toml"),
+            "read_chunk = 2\nthreads = 2\n",
+        )
+        .unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--config",
+            "test_data/test_output/config_unknown/run.toml",
+        ]);
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("unknown option(s) read-chunk"));
+    }
+
+    #[test]
+    fn bench() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "bench",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--tiles",
+            "2",
+        ]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains("benchmarked 2 of 3 tiles")
+                .and(predicate::str::contains("compress: "))
+                .and(predicate::str::contains("projected time for the full run"))
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn dry_run() {
+        let output_path = std::path::Path::new("test_data/test_output/dry_run");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/dry_run",
+            "--dry-run",
+        ]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains("clusters passing filter: 245")
+                .and(predicate::str::contains("samples: 93 (186 output files)"))
+                .from_utf8(),
+        );
+
+        assert!(output_path.join("demux_plan.json").is_file());
+        assert!(!output_path.join("project_1").exists());
+        assert!(!output_path.join("run_summary.json").exists());
+    }
+
+    #[test]
+    fn run_shards() {
+        let output_path = std::path::Path::new("test_data/test_output/shards");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        for shard in ["1/2", "2/2"] {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/shards",
+                "--shard",
+                shard,
+                "--screen-undetermined",
+                "1000",
+            ]);
+
+            cmd.assert().success();
+        }
+
+        assert!(output_path.join("Stats/Stats.shard1of2.json").is_file());
+        assert!(output_path.join("Stats/Stats.shard2of2.json").is_file());
+        assert!(output_path.join("run_summary.shard2of2.json").is_file());
+        assert!(output_path
+            .join("barcode_L001_report.shard1of2.txt")
+            .is_file());
+        assert!(!output_path.join("Stats/Stats.json").exists());
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args(["merge-stats", "--output", "test_data/test_output/shards"]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains("lane 1: 245 clusters passing filter, 23 undetermined")
+                .from_utf8(),
+        );
+
+        assert!(output_path.join("Stats/Stats.json").is_file());
+        assert!(output_path.join("Reports/Demultiplex_Stats.csv").is_file());
+        // the screen isn't in bcl2fastq's Stats.json, but is kept for merging
+        let screen =
+            std::fs::read_to_string(output_path.join("Reports/Undetermined_Screen.csv")).unwrap();
+        assert!(screen.lines().nth(1).unwrap().starts_with("1,23,"));
+    }
+
+    #[test]
+    fn merge_shards() {
+        let output_path = std::path::Path::new("test_data/test_output/merge_shards");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        for shard in ["1/2", "2/2"] {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/merge_shards",
+                "--shard",
+                shard,
+            ]);
+            cmd.assert().success();
+        }
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "merge",
+            "--output",
+            "test_data/test_output/merge_shards",
+            "--lanes",
+            "--remove-chunks",
+        ]);
+        cmd.assert().success().stdout(
+            predicate::str::contains("project_1/8034211776_R1.fastq.gz: 2 files").from_utf8(),
+        );
+
+        let project_path = output_path.join("project_1");
+        assert!(!project_path
+            .join("8034211776_L001_R1.shard1of2.fastq.gz")
+            .exists());
+        let mut reads = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(
+                std::fs::File::open(project_path.join("8034211776_R1.fastq.gz")).unwrap(),
+            ),
+            &mut reads,
+        )
+        .unwrap();
+        assert_eq!(reads.lines().count(), 4 * 10);
+
+        let checksums = std::fs::read_to_string(output_path.join("fastq_checksums.json")).unwrap();
+        assert!(checksums.contains("\"path\": \"project_1/8034211776_R1.fastq.gz\""));
+        assert!(output_path.join("Stats/Stats.json").is_file());
+    }
+
+    #[test]
+    fn first_tile_only() {
+        let output_path = std::path::Path::new("test_data/test_output/first_tile_only");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/first_tile_only",
+            "--first-tile-only",
+        ]);
+        cmd.assert().success();
+
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"TotalClustersRaw\": 100"));
+    }
+
+    #[test]
+    fn abort_if_undetermined_above() {
+        let output_path = std::path::Path::new("test_data/test_output/undetermined_abort");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/undetermined_abort",
+            "--abort-if-undetermined-above",
+            "0.01",
+            "--check-after-tiles",
+            "2",
+        ]);
+        cmd.assert().code(2).stderr(
+            predicate::str::contains("of reads in the first 3 tiles of lane 1 are undetermined")
+                .from_utf8(),
+        );
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/undetermined_abort",
+            "--abort-if-undetermined-above",
+            "1.5",
+        ]);
+        cmd.assert().failure().code(1);
+    }
+
+    #[test]
+    fn index_cache() {
+        let output_path = std::path::Path::new("test_data/test_output/index_cache_cli");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        for _ in 0..2 {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/index_cache_cli",
+                "--index-cache",
+                "test_data/test_output/index_cache_cli/cache",
+            ]);
+            cmd.assert().success();
+        }
+
+        assert!(cached_tile(&output_path.join("cache"), "L001_1_1103").is_some());
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"TotalClustersPF\": 245"));
+    }
+
+    /// the cache file of a tile, named with the key of the run
+    fn cached_tile(cache_path: &std::path::Path, tile: &str) -> Option<std::path::PathBuf> {
+        std::fs::read_dir(cache_path)
+            .ok()?
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .ends_with(&format!("_{}.idx.gz", tile))
+            })
+    }
+
+    #[test]
+    fn metadata_cache() {
+        let output_path = std::path::Path::new("test_data/test_output/metadata_cache_cli");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // the second demux uses the headers cached by the first
+        for _ in 0..2 {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/metadata_cache_cli",
+                "--metadata-cache",
+                "test_data/test_output/metadata_cache_cli/cache",
+            ]);
+            cmd.assert().success();
+        }
+
+        assert!(output_path.join("cache/L001_1.headers.json.gz").is_file());
+        assert!(output_path.join("cache/L001_1.filters.json.gz").is_file());
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"TotalClustersPF\": 245"));
+    }
+
+    #[test]
+    fn exclude_tiles() {
+        let output_path = std::path::Path::new("test_data/test_output/exclude_tiles");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let exclude_path = output_path.join("excluded_tiles.txt");
+        std::fs::write(&exclude_path, "# out of focus\ns_1_1102\n").unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "stats",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--exclude-tiles",
+            exclude_path.to_str().unwrap(),
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("1\t2\t200\t162").from_utf8());
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/exclude_tiles",
+            "--exclude-tiles",
+            exclude_path.to_str().unwrap(),
+        ]);
+        cmd.assert().success();
+
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"TotalClustersPF\": 162"));
+    }
+
+    #[test]
+    fn lane_splitting() {
+        let output_path = std::path::Path::new("test_data/test_output/lane_splitting");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let samplesheet =
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap()
+                .replace(",Lane\n", "\n")
+                .replace(",1\n", "\n");
+        let samplesheet_path = output_path.join("SampleSheet.csv");
+        std::fs::write(&samplesheet_path, samplesheet).unwrap();
+
+        let demux = |no_lane_splitting: bool| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                samplesheet_path.to_str().unwrap(),
+                "--output",
+                "test_data/test_output/lane_splitting",
+            ]);
+            if no_lane_splitting {
+                cmd.arg("--no-lane-splitting");
+            }
+            cmd.assert().success();
+        };
+
+        // the samples apply to every lane, which are named in the fastq files
+        demux(false);
+        assert!(output_path
+            .join("project_1/8034211010_L001_R1.fastq.gz")
+            .is_file());
+        assert!(!output_path
+            .join("project_1/8034211010_R1.fastq.gz")
+            .exists());
+
+        demux(true);
+        assert!(output_path
+            .join("project_1/8034211010_R1.fastq.gz")
+            .is_file());
+
+        // a samplesheet with lanes is merged if every lane has the same samples
+        std::fs::remove_dir_all(output_path.join("project_1")).unwrap();
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/lane_splitting",
+            "--no-lane-splitting",
+        ]);
+        cmd.assert().success();
+        assert!(output_path
+            .join("project_1/8034211010_R1.fastq.gz")
+            .is_file());
+    }
+
+    #[test]
+    fn only_reads() {
+        let output_path = std::path::Path::new("test_data/test_output/only_reads");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/210618_FS10000171_0042_BPA73113-1417",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/only_reads",
+            "--only-reads",
+            "R1",
+        ]);
+        cmd.assert().success();
+
+        assert!(output_path
+            .join("iseq_project/iseq_1_L001_R1.fastq.gz")
+            .is_file());
+        assert!(!output_path
+            .join("iseq_project/iseq_1_L001_R2.fastq.gz")
+            .exists());
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/210618_FS10000171_0042_BPA73113-1417",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/only_reads",
+            "--skip-reads",
+            "R3",
+        ]);
+        cmd.assert().failure().code(1).stderr(
+            predicate::str::contains("invalid value for 'skip-reads': the run has no R3")
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn run_stage_threads() {
+        let output_path = std::path::Path::new("test_data/test_output/stage_threads");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/stage_threads",
+            "--io-threads",
+            "2",
+            "--compress-threads",
+            "1",
+        ]);
+
+        cmd.assert().success();
+
+        let config = std::fs::read_to_string(output_path.join("config.toml")).unwrap();
+        assert!(config.contains("io-threads = 2"));
+        assert!(!config.contains("demux-threads"));
+    }
+
+    #[test]
+    fn run_reverse_complement_i5() {
+        let output_path = std::path::Path::new("test_data/test_output/rc_i5");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/rc_i5",
+            "--i5-orientation",
+            "reverse-complement",
+            "--trim-poly-g",
+            "2",
+        ]);
+
+        cmd.assert().success();
+
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"Chemistry\": \"NovaSeq Control Software 1.6.0, v1.0 reagents"));
+
+        let config = std::fs::read_to_string(output_path.join("config.toml")).unwrap();
+        assert!(config.contains("i5-orientation = \"reverse-complement\""));
+        assert!(config.contains("trim-poly-g = 2"));
+    }
+
+    #[test]
+    fn run_provenance() {
+        let output_path = std::path::Path::new("test_data/test_output/provenance");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/provenance",
+            "--no-compression",
+            "--provenance-headers",
+        ]);
+
+        cmd.assert().success();
+
+        let provenance = std::fs::read_to_string(output_path.join("provenance.json")).unwrap();
+        assert!(provenance.contains("\"read_structure\": \"Y4;I8;I8;Y4\""));
+        assert!(provenance.contains("\"provenance-headers\": true"));
+        assert!(provenance.contains("\"sha256\""));
+
+        let fastq = std::fs::read_to_string(output_path.join("project_1/8025874336_L001_R1.fastq"))
+            .unwrap();
+        let header = fastq.lines().next().unwrap();
+        assert!(header.contains(":N:0:CTGTATGC+AGCCGTAA bcl2fastr="));
+        // the samplesheet is identified by its SHA-256
+        let samplesheet_hash = header.rsplit(";ss=").next().unwrap();
+        assert_eq!(samplesheet_hash.len(), 64);
+        assert!(provenance.contains(&format!("\"sha256\": \"{}\"", samplesheet_hash)));
+    }
+
+    #[test]
+    fn run_iseq() {
+        let output_path = std::path::Path::new("test_data/test_output/iseq");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/210618_FS10000171_0042_BPA73113-1417",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/iseq",
+            "--no-compression",
+        ]);
+
+        cmd.assert().success();
+
+        // i5 is reverse complemented on the iSeq, so the forward samplesheet matches
+        let fastq =
+            std::fs::read_to_string(output_path.join("iseq_project/iseq_1_L001_R1.fastq")).unwrap();
+        let header = fastq.lines().next().unwrap();
+        assert!(header.starts_with("@FS10000171:42:BPA73113-1417:1:110"));
+        assert!(header.ends_with(":N:0:ACTGCGAA+GGACAATC"));
+
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"TotalClustersRaw\": 48"));
+    }
+
+    #[test]
+    fn run_bad_shard() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output",
+            "--shard",
+            "3/2",
+        ]);
+
+        cmd.assert().failure().code(1);
+    }
+
+    #[test]
+    fn dry_run_memory_limit() {
+        let output_path = std::path::Path::new("test_data/test_output/dry_run");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/dry_run",
+            "--dry-run",
+            "--memory-limit",
+            "48K",
+        ]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("tiles: 3 in 3 chunks of up to 1").from_utf8());
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/dry_run",
+            "--dry-run",
+            "--memory-limit",
+            "1K",
+        ]);
+
+        cmd.assert().failure().code(1);
+    }
+
+    #[test]
+    fn dry_run_auto_tune() {
+        let output_path = std::path::Path::new("test_data/test_output/dry_run_auto_tune");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let dry_run = |memory_limit: &str, extra_args: &[&str]| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/dry_run_auto_tune",
+                "--dry-run",
+                "--auto-tune",
+                "--memory-limit",
+                memory_limit,
+            ])
+            .args(extra_args);
+            cmd
+        };
+
+        // the whole surface is read at once
+        dry_run("1G", &[])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("tiles: 3 in 1 chunks of up to 3").from_utf8());
+
+        // unless the number of tiles is given
+        dry_run("1G", &["--read-chunks", "1"])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("tiles: 3 in 3 chunks of up to 1").from_utf8());
+
+        // the queues of 188 files don't fit in 40M
+        dry_run("40M", &["--compression-workers"])
+            .assert()
+            .failure()
+            .code(1);
+
+        dry_run("1G", &["--compression-workers", "--queue-depth", "0"])
+            .assert()
+            .failure()
+            .code(1);
+    }
+
+    #[test]
+    fn run_allow_incomplete() {
+        let output_path = std::path::Path::new("test_data/test_output/allow_incomplete");
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/allow_incomplete",
+            "--allow-incomplete",
+        ]);
+
+        cmd.assert().success();
+
+        // every cycle is there, so there's nothing to warn about
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(!summary.contains("run is incomplete"));
+
+        // a copy of the run without its last two cycles
+        let run_path = std::path::Path::new("test_data/test_output/allow_incomplete_run");
+        let _ = std::fs::remove_dir_all(run_path);
+        copy_dir(
+            std::path::Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            run_path,
+        );
+        for cycle in [23, 24] {
+            std::fs::remove_dir_all(
+                run_path.join(format!("Data/Intensities/BaseCalls/L001/C{}.1", cycle)),
+            )
+            .unwrap();
+        }
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/test_output/allow_incomplete_run",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/allow_incomplete",
+            "--allow-incomplete",
+        ]);
+
+        cmd.assert().success();
+
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(summary.contains("run is incomplete: demultiplexed 22 of 24 cycles"));
+    }
+
+    #[test]
+    fn single_index_run() {
+        // a copy of the run read as R1, I1 and a 12 cycle R2
+        let run_path = std::path::Path::new("test_data/test_output/single_index_run");
+        let output_path = std::path::Path::new("test_data/test_output/single_index");
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        copy_dir(
+            std::path::Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            run_path,
+        );
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let run_info = std::fs::read_to_string(run_path.join("RunInfo.xml")).unwrap();
+        let run_info = run_info.replace(
+            concat!(
+                "<Read IsIndexedRead=\"Y\" NumCycles=\"8\" Number=\"3\" />\n",
+                "\t\t\t<Read IsIndexedRead=\"N\" NumCycles=\"4\" Number=\"4\" />",
+            ),
+            "<Read IsIndexedRead=\"N\" NumCycles=\"12\" Number=\"3\" />",
+        );
+        assert!(!run_info.contains("Number=\"4\""));
+        std::fs::write(run_path.join("RunInfo.xml"), run_info).unwrap();
+
+        // the samplesheet without its Index2 column
+        let samplesheet = std::fs::read_to_string(run_path.join("SampleSheet.csv")).unwrap();
+        let samplesheet: String = samplesheet
+            .lines()
+            .map(|line| {
+                let mut fields: Vec<_> = line.split(',').collect();
+                fields.remove(4);
+                fields.join(",") + "\n"
+            })
+            .collect();
+        std::fs::write(run_path.join("SampleSheet.csv"), samplesheet).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/test_output/single_index_run",
+            "--samplesheet",
+            "test_data/test_output/single_index_run/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/single_index",
+        ]);
+
+        cmd.assert().success();
+
+        let mut reads = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(
+                std::fs::File::open(output_path.join("project_1/8034210952_L001_R2.fastq.gz"))
+                    .unwrap(),
+            ),
+            &mut reads,
+        )
+        .unwrap();
+        let read: Vec<_> = reads.lines().take(4).collect();
+        assert!(read[0].ends_with(" 2:N:0:TAGTCTCG"));
+        assert_eq!(read[1].len(), 12);
+        assert_eq!(read[3].len(), 12);
+    }
+
+    #[test]
+    fn two_phase_undetermined() {
+        let output_path = std::path::Path::new("test_data/test_output/two_phase_undetermined");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            output_path.to_str().unwrap(),
+            "--two-phase",
+        ]);
+        cmd.assert().success();
+
+        // no empty files stand in for the undetermined reads
+        let file_names: Vec<_> = std::fs::read_dir(output_path.join("project_1"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(!file_names.is_empty());
+        assert!(file_names
+            .iter()
+            .all(|name| !name.starts_with("Undetermined")));
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(summary.contains("--two-phase doesn't decode the undetermined reads"));
+
+        // the PhiX reads are undetermined, so can't be written without them
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            output_path.to_str().unwrap(),
+            "--two-phase",
+            "--phix-output",
+        ]);
+        cmd.assert()
+            .code(1)
+            .stderr(predicate::str::contains("unless with --two-phase-undetermined").from_utf8());
+
+        // which are read in the second pass when asked for
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            output_path.to_str().unwrap(),
+            "--two-phase",
+            "--two-phase-undetermined",
+            "--screen-undetermined",
+            "100",
+        ]);
+        cmd.assert().success();
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(!summary.contains("--two-phase doesn't decode the undetermined reads"));
+        assert!(output_path
+            .join("Reports/Undetermined_Screen.csv")
+            .is_file());
+    }
+
+    /// copy a directory tree, e.g. to make a copy of a run we can modify
+    fn copy_dir(from: &std::path::Path, to: &std::path::Path) {
+        std::fs::create_dir_all(to).unwrap();
+
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &to.join(entry.file_name()));
+            } else {
+                std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn gzipped_filters_and_locs() {
+        let run_path = std::path::Path::new("test_data/test_output/gzipped_run");
+        let output_path = std::path::Path::new("test_data/test_output/gzipped_run_output");
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        copy_dir(
+            std::path::Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            run_path,
+        );
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // an archived copy of the run, with only the gzipped filters and locs
+        let lane_path = run_path.join("Data/Intensities/BaseCalls/L001");
+        let mut paths = vec![run_path.join("Data/Intensities/s.locs")];
+        paths.extend(
+            ["1101", "1102", "1103"]
+                .iter()
+                .map(|tile| lane_path.join(format!("s_1_{}.filter", tile))),
+        );
+        for path in paths {
+            let mut gz = flate2::write::GzEncoder::new(
+                std::fs::File::create(format!("{}.gz", path.display())).unwrap(),
+                flate2::Compression::default(),
+            );
+            std::io::copy(&mut std::fs::File::open(&path).unwrap(), &mut gz).unwrap();
+            gz.finish().unwrap();
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/test_output/gzipped_run",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/gzipped_run_output",
+            "--no-compression",
+        ]);
+        cmd.assert().success();
+
+        // the gzipped files stand in for the ones RunInfo.xml expects
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(!summary.contains("Unexpected file"));
+        let reads = std::fs::read_to_string(output_path.join("project_1/8034211776_L001_R1.fastq"))
+            .unwrap();
+        assert_eq!(reads.lines().count(), 4 * 10);
+    }
+
+    #[test]
+    fn inspect_cbcl() {
+        let cbcl_path = "test_data/190414_A00111_0296_AHJCWWDSXX/Data/Intensities/BaseCalls/L001/C1.1/L001_1.cbcl";
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args(["inspect-cbcl", cbcl_path]);
+        cmd.assert().success().stdout(
+            predicate::str::contains("bins: Q2 Q11 Q25 Q37")
+                .and(predicate::str::contains("1102\t100\t170\t73\t50"))
+                .from_utf8(),
+        );
+
+        // the last tile's block is cut short
+        let truncated_path = std::path::Path::new("test_data/test_output/truncated.cbcl");
+        std::fs::create_dir_all("test_data/test_output").unwrap();
+        let bytes = std::fs::read(cbcl_path).unwrap();
+        std::fs::write(truncated_path, &bytes[..bytes.len() - 10]).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args(["inspect-cbcl", "test_data/test_output/truncated.cbcl"]);
+        cmd.assert().code(3).stderr(
+            predicate::str::contains("tile record 2 (tile 1103) ends at byte 316").from_utf8(),
+        );
+    }
+
+    #[test]
+    fn multiple_runfolders() {
+        let run_path = std::path::Path::new("test_data/test_output/topup_run");
+        let output_path = std::path::Path::new("test_data/test_output/topup_output");
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        copy_dir(
+            std::path::Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            run_path,
+        );
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // a second run of the same libraries, on another flowcell
+        let run_info = std::fs::read_to_string(run_path.join("RunInfo.xml"))
+            .unwrap()
+            .replace(
+                "Id=\"190414_A00111_0296_AHJCWWDSXX\" Number=\"296\"",
+                "Id=\"190421_A00111_0301_BHJCWWDSXX\" Number=\"301\"",
+            )
+            .replace(">HJCWWDSXX<", ">BHJCWWDSXX<");
+        std::fs::write(run_path.join("RunInfo.xml"), run_info).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--runfolder",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--runfolder",
+            "test_data/test_output/topup_run",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/topup_output",
+            "--no-compression",
+        ]);
+        cmd.assert().success();
+
+        // the reads of both runs are in the same files, told apart by their names
+        let reads = std::fs::read_to_string(output_path.join("project_1/8034211776_L001_R1.fastq"))
+            .unwrap();
+        assert_eq!(reads.lines().count(), 4 * 20);
+        assert_eq!(
+            reads
+                .lines()
+                .filter(|line| line.starts_with("@A00111:301:BHJCWWDSXX:"))
+                .count(),
+            10
+        );
+
+        let stats_path = output_path.join("Stats");
+        assert!(stats_path
+            .join("Stats.190414_A00111_0296_AHJCWWDSXX.json")
+            .is_file());
+        assert!(stats_path
+            .join("Stats.190421_A00111_0301_BHJCWWDSXX.json")
+            .is_file());
+        let stats = std::fs::read_to_string(stats_path.join("Stats.json")).unwrap();
+        assert!(stats.contains("190414_A00111_0296_AHJCWWDSXX+190421_A00111_0301_BHJCWWDSXX"));
+        assert!(stats.contains("\"TotalClustersPF\": 490"));
+
+        // the same run twice would write every read name twice
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--runfolder",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--runfolder",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/topup_output",
+        ]);
+        cmd.assert()
+            .code(3)
+            .stderr(predicate::str::contains("would have the same read names").from_utf8());
+    }
+
+    #[test]
+    fn run_watch() {
+        let run_path = std::path::Path::new("test_data/test_output/watch_run");
+        let output_path = std::path::Path::new("test_data/test_output/watch");
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        copy_dir(
+            std::path::Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            run_path,
+        );
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/test_output/watch_run",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/watch",
+            "--watch",
+            "--watch-interval",
+            "1",
+        ]);
+
+        let mut child = cmd.spawn().unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(1500));
+        // the run shouldn't be demultiplexed until it's complete
+        assert!(!output_path.join("run_summary.json").exists());
+
+        std::fs::write(run_path.join("CopyComplete.txt"), "").unwrap();
+
+        assert!(child.wait().unwrap().success());
+        assert!(output_path.join("run_summary.json").is_file());
+        assert!(!output_path.join(".watch_index_cache").exists());
+    }
+
+    #[test]
+    fn watch_index_cache() {
+        let run_path = std::path::Path::new("test_data/test_output/watch_cache_run");
+        let output_path = std::path::Path::new("test_data/test_output/watch_cache");
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        copy_dir(
+            std::path::Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            run_path,
+        );
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/test_output/watch_cache_run",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/watch_cache",
+            "--index-cache",
+            "test_data/test_output/watch_cache/cache",
+            "--watch",
+            "--watch-interval",
+            "1",
+        ]);
+        let mut child = cmd.spawn().unwrap();
+
+        // the indices are decoded while the run is still sequencing
+        let cache_path = output_path.join("cache");
+        for _ in 0..600 {
+            if cached_tile(&cache_path, "L001_1_1103").is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert!(cached_tile(&cache_path, "L001_1_1103").is_some());
+        assert!(!output_path.join("run_summary.json").exists());
+
+        std::fs::write(run_path.join("CopyComplete.txt"), "").unwrap();
+
+        assert!(child.wait().unwrap().success());
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"TotalClustersPF\": 245"));
+        // the demux found the tiles under the same key, so didn't cache them again
+        assert_eq!(std::fs::read_dir(&cache_path).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn watch_timeout() {
+        let output_path = std::path::Path::new("test_data/test_output/watch_timeout");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // every cycle is written, but the run never finishes
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/watch_timeout",
+            "--watch",
+            "--watch-interval",
+            "1",
+            "--watch-timeout",
+            "1",
+        ]);
+        cmd.assert()
+            .code(3)
+            .stderr(predicate::str::contains("without a new cycle").from_utf8());
+    }
+
+    #[test]
+    fn samplesheet_without_index() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/sample_data/no_index.csv",
+            "--output",
+            "test_data/test_output",
+        ]);
+
+        cmd.assert().code(2).stderr(
+            predicate::str::contains("no_index.csv does not have an Index column").from_utf8(),
+        );
+    }
+
+    #[test]
+    fn run_other_lanes() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output",
+            "--lanes",
+            "2-4",
+        ]);
+
+        cmd.assert()
+            .code(2)
+            .stderr(predicate::str::contains("No samples in the selected lanes").from_utf8());
+    }
+
+    #[test]
+    fn run_missing_lane() {
+        let output_path = std::path::Path::new("test_data/test_output/missing_lane");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // without a Lane column the samples are in every lane, so lane 2 is loaded
+        let samplesheet =
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap()
+                .replace(",Lane\n", "\n")
+                .replace(",1\n", "\n");
+        let samplesheet_path = output_path.join("SampleSheet.csv");
+        std::fs::write(&samplesheet_path, samplesheet).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+            "--output",
+            "test_data/test_output/missing_lane",
+            "--lanes",
+            "2",
+        ]);
+
+        cmd.assert()
+            .code(3)
+            .stderr(predicate::str::contains("the run has no lane 2").from_utf8());
+    }
+
+    #[test]
+    fn call_without_args() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("SUBCOMMANDS:").from_utf8());
+    }
+
+    #[test]
+    fn demux_without_args() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.arg("demux");
+
+        cmd.assert().failure().stderr(
+            predicate::str::contains("The following required arguments were not provided:")
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+        ]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains("samplesheet is valid for run 190414_A00111_0296_AHJCWWDSXX")
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn index_truncation() {
+        let output_path = std::path::Path::new("test_data/test_output/index_truncation");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // the samplesheet's indices are 7 bases, for 8 index cycles
+        let samplesheet: Vec<_> =
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap()
+                .lines()
+                .enumerate()
+                .map(|(i, line)| {
+                    let mut fields: Vec<_> = line.split(',').map(str::to_string).collect();
+                    if i > 1 {
+                        fields[3].truncate(7);
+                        fields[4].truncate(7);
+                    }
+                    fields.join(",")
+                })
+                .collect();
+        let samplesheet_path = output_path.join("SampleSheet.csv");
+        std::fs::write(&samplesheet_path, samplesheet.join("\n")).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+        ]);
+        cmd.assert().success();
+
+        let demux = |extra_args: &[&str]| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                samplesheet_path.to_str().unwrap(),
+                "--output",
+                "test_data/test_output/index_truncation",
+            ])
+            .args(extra_args);
+            cmd.assert().success();
+            std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap()
+        };
+
+        // the same reads are undetermined as with the whole indices
+        let stats = demux(&[]);
+        assert!(stats.contains("\"NumberReads\": 23"));
+        assert!(stats.contains("\"GGGGGGG+TCTTTCC\": 4"));
+
+        // and all of them without truncation
+        let stats = demux(&["--no-index-truncation"]);
+        assert!(stats.contains("\"NumberReads\": 245"));
+    }
+
+    #[test]
+    fn strict_udi() {
+        let output_path = std::path::Path::new("test_data/test_output/strict_udi");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/strict_udi",
+            "--strict-udi",
+            "--max-total-mismatches",
+            "0",
+        ]);
+        cmd.assert().success();
+
+        // two reads have a mismatch in one index, so they are undetermined now
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReads\": 25"));
+
+        // give the second sample the first sample's Index2
+        let samplesheet =
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap()
+                .replace("TAGTCTCG,AGTGGCAA", "TAGTCTCG,GATTGTCC");
+        let samplesheet_path = output_path.join("SampleSheet.csv");
+        std::fs::write(&samplesheet_path, samplesheet).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+            "--strict-udi",
+        ]);
+        cmd.assert().failure().code(2).stderr(
+            predicate::str::contains(
+                "8034211010 and 8034210952 have the same Index2 GATTGTCC, so the indices aren't unique",
+            )
+            .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn exact_matcher() {
+        let output_path = std::path::Path::new("test_data/test_output/exact_matcher");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/exact_matcher",
+            "--matcher",
+            "exact",
+        ]);
+        cmd.assert().success();
+
+        // the two reads with a mismatch aren't corrected
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReads\": 25"));
+    }
+
+    #[test]
+    fn quality_matcher() {
+        let output_path = std::path::Path::new("test_data/test_output/quality_matcher");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/quality_matcher",
+            "--matcher",
+            "quality",
+        ]);
+        cmd.assert().success();
+
+        // one of the two reads with a mismatch has it at a high quality base
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReads\": 24"));
+    }
+
+    #[test]
+    fn validate_gzipped_samplesheet() {
+        use std::io::Write;
+
+        let output_path = std::path::Path::new("test_data/test_output/gzipped_samplesheet");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let samplesheet_path = output_path.join("SampleSheet.csv.gz");
+        let mut gz = flate2::write::GzEncoder::new(
+            std::fs::File::create(&samplesheet_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(
+            &std::fs::read("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv").unwrap(),
+        )
+        .unwrap();
+        gz.finish().unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+        ]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains("samplesheet is valid for run 190414_A00111_0296_AHJCWWDSXX")
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn samplesheet_reads() {
+        let output_path = std::path::Path::new("test_data/test_output/samplesheet_reads");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // a samplesheet for a 2x151 run, while the test run is Y4;I8;I8;Y4
+        let samplesheet = format!(
+            "[Reads]\n151\n151\n\n{}",
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap()
+        );
+        let samplesheet_path = output_path.join("SampleSheet.csv");
+        std::fs::write(&samplesheet_path, samplesheet).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+        ]);
+        cmd.assert().failure().code(2).stdout(
+            predicate::str::contains(
+                "the samplesheet's [Reads] section has 151 cycles for R2, but RunInfo.xml has 4",
+            )
+            .from_utf8(),
+        );
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+            "--output",
+            "test_data/test_output/samplesheet_reads",
+        ]);
+        cmd.assert().failure().code(2).stderr(
+            predicate::str::contains("The samplesheet was written for a different run").from_utf8(),
+        );
+    }
+
+    #[test]
+    fn merged_samplesheets() {
+        let output_path = std::path::Path::new("test_data/test_output/merged_samplesheets");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // split the samplesheet in two, and take the projects out of the second one
+        let samplesheet =
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap();
+        let lines: Vec<_> = samplesheet.lines().collect();
+        let lab_a_path = output_path.join("lab_a.csv");
+        let lab_b_path = output_path.join("lab_b.csv");
+        std::fs::write(&lab_a_path, lines[..50].join("\n")).unwrap();
+        std::fs::write(
+            &lab_b_path,
+            lines[..2]
+                .iter()
+                .chain(&lines[50..])
+                .map(|line| line.replace(",project_1,", ",,"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+        .unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            lab_a_path.to_str().unwrap(),
+            "--samplesheet",
+            lab_b_path.to_str().unwrap(),
+            "--output",
+            "test_data/test_output/merged_samplesheets",
+        ]);
+        cmd.assert().success();
+
+        // the same reads are assigned as with one samplesheet
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReads\": 23"));
+        let demux_stats =
+            std::fs::read_to_string(output_path.join("Reports/Demultiplex_Stats.csv")).unwrap();
+        assert!(demux_stats.contains("1,Neg,lab_b,CTAACCTG-AACAACCG,"));
+        assert!(output_path.join("lab_b/Neg_L001_R1.fastq.gz").exists());
+
+        let provenance = std::fs::read_to_string(output_path.join("provenance.json")).unwrap();
+        assert!(provenance.contains("\"merged_samplesheets\""));
+
+        // a sample can't be in both
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            lab_a_path.to_str().unwrap(),
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+        ]);
+        cmd.assert()
+            .failure()
+            .code(2)
+            .stderr(predicate::str::contains("sample 8034211010 is in both").from_utf8());
+    }
+
+    #[test]
+    fn on_conflict() {
+        let output_path = std::path::Path::new("test_data/test_output/on_conflict");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // move the second sample to one mismatch from the first in each index
+        let samplesheet =
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap()
+                .replace("TAGTCTCG,AGTGGCAA", "ACTGCGAT,GATTGTCA");
+        let samplesheet_path = output_path.join("SampleSheet.csv");
+        std::fs::write(&samplesheet_path, samplesheet).unwrap();
+
+        let validate = |on_conflict: &str| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "validate",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                samplesheet_path.to_str().unwrap(),
+                "--on-conflict",
+                on_conflict,
+            ]);
+            cmd.assert()
+        };
+
+        validate("reduce")
+            .success()
+            .stdout(predicate::str::contains("lane 1: using distance 0 instead of 1").from_utf8());
+        validate("error").failure().code(2).stderr(
+            predicate::str::contains("lane 1: 8034210952 and 8034211010 conflict at distance 1")
+                .from_utf8(),
+        );
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+            "--output",
+            "test_data/test_output/on_conflict",
+            "--on-conflict",
+            "drop-sample",
+        ]);
+        cmd.assert().success();
+
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        // the second sample in the samplesheet is dropped
+        assert!(summary.contains("lane 1: dropped 8034210952, so its reads are undetermined"));
+        assert!(!output_path
+            .join("project_1/8034210952_L001_R1.fastq.gz")
+            .exists());
+        assert!(output_path
+            .join("project_1/8034211010_L001_R1.fastq.gz")
+            .exists());
+    }
+
+    #[test]
+    fn validate_missing_cbcl() {
+        let run_path = std::path::Path::new("test_data/test_output/missing_cbcl_run");
+        let _ = std::fs::remove_dir_all(run_path);
+        copy_dir(
+            std::path::Path::new("test_data/190414_A00111_0296_AHJCWWDSXX"),
+            run_path,
+        );
+        std::fs::remove_file(run_path.join("Data/Intensities/BaseCalls/L001/C3.1/L001_1.cbcl"))
+            .unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "validate",
+            "--run-path",
+            "test_data/test_output/missing_cbcl_run",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+        ]);
+
+        cmd.assert().failure().stdout(
+            predicate::str::contains(
+                "missing file Data/Intensities/BaseCalls/L001/C3.1/L001_1.cbcl",
+            )
+            .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "stats",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+        ]);
+
+        cmd.assert().success().stdout(
+            predicate::str::contains("read_structure\tY4;I8;I8;Y4")
+                .and(predicate::str::contains("1\t3\t300\t245\t81.67"))
+                .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn compare() {
+        let output_path = std::path::Path::new("test_data/test_output/compare_ours");
+        let empty_path = std::path::Path::new("test_data/test_output/compare_empty");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+        std::fs::create_dir_all(empty_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/210618_FS10000171_0042_BPA73113-1417",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/compare_ours",
+        ]);
+        cmd.assert().success();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "compare",
+            "--ours",
+            "test_data/test_output/compare_ours",
+            "--theirs",
+            "test_data/test_output/compare_ours",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("all 6 files are identical").from_utf8());
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "compare",
+            "--ours",
+            "test_data/test_output/compare_ours",
+            "--theirs",
+            "test_data/test_output/compare_empty",
+        ]);
+        cmd.assert()
+            .code(7)
+            .stdout(predicate::str::contains("6 of 6 files differ").from_utf8());
+    }
+
+    #[test]
+    fn verify_output() {
+        let output_path = std::path::Path::new("test_data/test_output/verify_output");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/verify_output",
+        ]);
+        cmd.assert().success();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "verify-output",
+            "--output",
+            "test_data/test_output/verify_output",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+        ]);
+        cmd.assert().success().stdout(
+            predicate::str::contains("all 186 files agree with Stats.json")
+                .and(predicate::str::contains("8034211776"))
+                .from_utf8(),
+        );
+
+        // the reads of one file twice, as two gzip members
+        let fastq_path = output_path.join("project_1/8034211776_L001_R1.fastq.gz");
+        let fastq = std::fs::read(&fastq_path).unwrap();
+        std::fs::write(&fastq_path, [fastq.clone(), fastq].concat()).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "verify-output",
+            "--output",
+            "test_data/test_output/verify_output",
+        ]);
+        cmd.assert().code(7).stderr(
+            predicate::str::contains(
+                "8034211776 lane 1 R1: 20 reads in the fastq files, 10 in Stats.json",
+            )
+            .from_utf8(),
+        );
+    }
+
+    #[test]
+    fn adapter_dimers() {
+        let output_path = std::path::Path::new("test_data/test_output/adapter_dimers");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/adapter_dimers",
+            "--adapter-dimers",
+            "separate",
+        ]);
+        cmd.assert().success();
+
+        // the reads are too short to find any, but every sample is counted
+        let dimers =
+            std::fs::read_to_string(output_path.join("Reports/Adapter_Dimers.csv")).unwrap();
+        assert!(dimers.contains("1,8034211776,10,0,0.0000"));
+        assert!(output_path
+            .join("project_1/8034211776_L001_R1_dimer.fastq.gz")
+            .is_file());
+
+        // the dimer files count toward their sample
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "verify-output",
+            "--output",
+            "test_data/test_output/adapter_dimers",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("all 372 files agree with Stats.json").from_utf8());
+    }
+
+    #[test]
+    fn umi_read() {
+        let output_path = std::path::Path::new("test_data/test_output/umi_read");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // the same samples with only their i7, so that I2 can be read as a UMI
+        let samplesheet =
+            std::fs::read_to_string("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+                .unwrap();
+        let samplesheet: String = samplesheet
+            .lines()
+            .map(|line| {
+                let mut fields: Vec<_> = line.split(',').collect();
+                fields.remove(4);
+                fields.join(",") + "\n"
+            })
+            .collect();
+        std::fs::write(output_path.join("SampleSheet.csv"), samplesheet).unwrap();
+
+        let demux = |samplesheet: &str| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                samplesheet,
+                "--output",
+                "test_data/test_output/umi_read",
+                "--umi-read",
+                "I2",
+                "--umi-fastq",
+                "--mask-invalid-umis",
+                "--umi-min-quality",
+                "20",
+            ]);
+            cmd.assert()
+        };
+        demux("test_data/test_output/umi_read/SampleSheet.csv").success();
+
+        let metrics = std::fs::read_to_string(output_path.join("Reports/UMI_Metrics.csv")).unwrap();
+        assert!(metrics.contains("1,232,0.0000,0.1810,35.24"));
+
+        // the second UMI has a base below Q20
+        let mut umis = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(
+                std::fs::File::open(output_path.join("project_1/8034211776_L001_UMI.fastq.gz"))
+                    .unwrap(),
+            ),
+            &mut umis,
+        )
+        .unwrap();
+        let umis: Vec<_> = umis.lines().skip(1).step_by(4).collect();
+        assert_eq!(umis[..2], ["ATTAGCCG", "NNNNNNNN"]);
+
+        // the UMI files have a read for each of the sample's
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "verify-output",
+            "--output",
+            "test_data/test_output/umi_read",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("all 279 files agree with Stats.json").from_utf8());
+
+        demux("test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv")
+            .failure()
+            .code(2)
+            .stderr(predicate::str::contains("The samples have 2 indices").from_utf8());
+    }
+
+    #[test]
+    fn single_file() {
+        let output_path = std::path::Path::new("test_data/test_output/single_file");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let demux = |extra_args: &[&str]| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/single_file",
+                "--single-file",
+            ])
+            .args(extra_args);
+            cmd.assert()
+        };
+        demux(&[]).success();
+
+        // every sample's reads are in one file, tagged with the sample
+        assert!(!output_path.join("project_1").exists());
+        let mut reads = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(
+                std::fs::File::open(output_path.join("All_L001_R1.fastq.gz")).unwrap(),
+            ),
+            &mut reads,
+        )
+        .unwrap();
+        assert_eq!(reads.matches(" SM:Z:8034211776\n").count(), 10);
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "verify-output",
+            "--output",
+            "test_data/test_output/single_file",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("all 2 files agree with Stats.json").from_utf8());
+
+        demux(&["--adapter-dimers", "separate"])
+            .failure()
+            .code(1)
+            .stderr(
+                predicate::str::contains("invalid value for 'adapter-dimers': separate files")
+                    .from_utf8(),
+            );
     }
 
     #[test]
-    fn call_without_args() {
+    fn sample_numbers() {
+        let output_path = std::path::Path::new("test_data/test_output/sample_numbers");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/sample_numbers",
+            "--sample-numbers",
+        ]);
+        cmd.assert().success();
+
+        // the twelfth sample in the samplesheet
+        assert!(output_path
+            .join("project_1/8034211776_S12_L001_R2.fastq.gz")
+            .is_file());
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"SampleNumber\": 12"));
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(summary.contains("\"8034211776\": 12"));
+        assert!(summary.contains("\"Undetermined\": 0"));
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "verify-output",
+            "--output",
+            "test_data/test_output/sample_numbers",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("files agree with Stats.json").from_utf8());
+    }
+
+    #[test]
+    fn correct_overlap() {
+        let output_path = std::path::Path::new("test_data/test_output/correct_overlap");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let demux = |extra_args: &[&str]| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/correct_overlap",
+                "--correct-overlap",
+                "--log-level",
+                "common::write_fastq=info",
+            ])
+            .args(extra_args);
+            cmd.assert()
+        };
+
+        // the reads are only 4 bases, so they can only overlap that much. R2 starts
+        // with an N, so no insert is shorter than the reads
+        demux(&["--min-overlap", "3", "--trim-overlap"])
+            .success()
+            .stderr(
+                predicate::str::contains(
+                    "14 pairs in lane 1 overlap, with 0 bases corrected and 0 read through",
+                )
+                .from_utf8(),
+            );
+        demux(&["--min-overlap", "5"])
+            .success()
+            .stderr(predicate::str::contains("0 pairs in lane 1 overlap").from_utf8());
+
+        demux(&["--only-reads", "R1"])
+            .failure()
+            .code(3)
+            .stderr(predicate::str::contains("Can't overlap the reads").from_utf8());
+    }
+
+    #[test]
+    fn screen_undetermined() {
+        let output_path = std::path::Path::new("test_data/test_output/screen_undetermined");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/screen_undetermined",
+            "--screen-undetermined",
+            "10",
+        ]);
+        cmd.assert().success();
+
+        let screen =
+            std::fs::read_to_string(output_path.join("Reports/Undetermined_Screen.csv")).unwrap();
+        assert!(screen.contains("1,10,0.2000,0.0000,0.1000,0.7000"));
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/screen_undetermined",
+            "--screen-undetermined",
+            "10",
+            "--screen-reference",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/RunInfo.xml",
+        ]);
+        cmd.assert()
+            .code(1)
+            .stderr(predicate::str::contains("invalid value for 'screen-reference'").from_utf8());
+    }
+
+    #[test]
+    fn genrun() {
+        let run_path = "test_data/test_output/genrun_run";
+        let output_path = "test_data/test_output/genrun_output";
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "genrun",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            run_path,
+            "--seed",
+            "3",
+            "--tiles",
+            "2",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("lane 1\tiseq_1\t").from_utf8());
+
         let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            run_path,
+            "--samplesheet",
+            "test_data/test_output/genrun_run/SampleSheet.csv",
+            "--output",
+            output_path,
+        ]);
+        cmd.assert().success();
+        assert!(std::path::Path::new(output_path)
+            .join("iseq_project/iseq_1_L001_R1.fastq.gz")
+            .is_file());
 
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "genrun",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            run_path,
+            "--bins",
+            "0",
+        ]);
         cmd.assert().failure().stderr(
-            predicate::str::contains("The following required arguments were not provided:")
+            predicate::str::contains("invalid value for 'bins': expected 2 to 4 quality bins")
                 .from_utf8(),
         );
     }
 
+    #[test]
+    fn poly_g_trimming() {
+        use std::io::Read;
+
+        let run_path = std::path::Path::new("test_data/test_output/poly_g_run");
+        let output_path = std::path::Path::new("test_data/test_output/poly_g");
+        let _ = std::fs::remove_dir_all(run_path);
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "genrun",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            run_path.to_str().unwrap(),
+            "--read-length",
+            "24",
+            "--poly-g-fraction",
+            "0.5",
+        ]);
+        cmd.assert().success();
+        // a NovaSeq, but the reads are only trimmed when asked
+        std::fs::copy(
+            "test_data/190414_A00111_0296_AHJCWWDSXX/RunParameters.xml",
+            run_path.join("RunParameters.xml"),
+        )
+        .unwrap();
+
+        let demux = |extra_args: &[&str]| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                run_path.to_str().unwrap(),
+                "--samplesheet",
+                run_path.join("SampleSheet.csv").to_str().unwrap(),
+                "--output",
+                output_path.to_str().unwrap(),
+            ])
+            .args(extra_args);
+            cmd.assert().success();
+
+            let mut fastq = String::new();
+            flate2::read::MultiGzDecoder::new(
+                std::fs::File::open(output_path.join("iseq_project/iseq_1_L001_R1.fastq.gz"))
+                    .unwrap(),
+            )
+            .read_to_string(&mut fastq)
+            .unwrap();
+            fastq
+        };
+
+        let fastq = demux(&[]);
+        assert!(fastq.lines().skip(1).step_by(4).all(|s| s.len() == 24));
+
+        let fastq = demux(&["--trim-poly-g", "10"]);
+        let seqs: Vec<_> = fastq.lines().skip(1).step_by(4).collect();
+        assert!(seqs.iter().any(|s| s.len() <= 12));
+        assert!(seqs.iter().all(|s| !s.is_empty()));
+        assert!(seqs.iter().all(|s| !s.ends_with("GGGGGGGGGG")));
+        let trimmed: u64 = seqs.iter().map(|s| 24 - s.len() as u64).sum();
+
+        // the trimmed bases are counted in Stats.json
+        let stats: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap(),
+        )
+        .unwrap();
+        let sample = stats["ConversionResults"][0]["DemuxResults"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|r| r["SampleName"] == "iseq_1")
+            .unwrap();
+        let r1 = sample["ReadMetrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|m| m["ReadNumber"] == 1)
+            .unwrap();
+        assert!(trimmed > 0);
+        assert_eq!(r1["TrimmedBases"], trimmed);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_output() {
+        let output_path = std::path::Path::new("test_data/test_output/zstd_output");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // a frame for each block with the compression workers
+        for extra_args in [&[][..], &["--compression-workers"][..]] {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                output_path.to_str().unwrap(),
+                "--zstd",
+                "--compression",
+                "3",
+            ])
+            .args(extra_args);
+            cmd.assert().success();
+
+            let contents =
+                std::fs::read(output_path.join("project_1/8034211776_L001_R1.fastq.zst")).unwrap();
+            let reads = zstd::stream::decode_all(&contents[..]).unwrap();
+            assert_eq!(reads.iter().filter(|&&b| b == b'\n').count(), 40);
+            assert!(!output_path
+                .join("project_1/8034211776_L001_R1.fastq.gz")
+                .exists());
+        }
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args(["verify-output", "--output", output_path.to_str().unwrap()]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("all 186 files agree with Stats.json").from_utf8());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_dictionary() {
+        use std::io::Read;
+
+        let output_path = std::path::Path::new("test_data/test_output/zstd_dictionary");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path.join("demux")).unwrap();
+
+        // reads like the ones of a run, to train on
+        let training_path = output_path.join("training.fastq");
+        let training: String = (0..20000)
+            .map(|i| {
+                let seq: String = (0..50)
+                    .map(|j| b"ACGT"[(i * 7 + j * j) % 4] as char)
+                    .collect();
+                format!(
+                    "@A00111:296:HJCWWDSXX:1:1101:{}:{} 1:N:0:ACGTACGT+TTGGCCAA\n{}\n+\n{}\n",
+                    i % 32000,
+                    (i * 13) % 37000,
+                    seq,
+                    &"FF:F,FFFF".repeat(6)[..50]
+                )
+            })
+            .collect();
+        std::fs::write(&training_path, training).unwrap();
+
+        let dictionary_path = output_path.join("fastq.dict");
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "train-zstd-dictionary",
+            "--output",
+            dictionary_path.to_str().unwrap(),
+            "--max-size",
+            "16384",
+            training_path.to_str().unwrap(),
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("byte dictionary to").from_utf8());
+        let dictionary = std::fs::read(&dictionary_path).unwrap();
+        assert!(dictionary.len() <= 16384);
+
+        let demux_path = output_path.join("demux");
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            demux_path.to_str().unwrap(),
+            "--zstd",
+            "--zstd-dictionary",
+            dictionary_path.to_str().unwrap(),
+        ]);
+        cmd.assert().success();
+
+        // the files can only be decompressed with the copy of the dictionary
+        let copy = std::fs::read(demux_path.join("fastq.zstd_dict")).unwrap();
+        assert_eq!(copy, dictionary);
+        // and each project has its own copy, to hand over with its files
+        let project_copy = std::fs::read(demux_path.join("project_1/fastq.zstd_dict")).unwrap();
+        assert_eq!(project_copy, dictionary);
+        let contents =
+            std::fs::read(demux_path.join("project_1/8034211776_L001_R1.fastq.zst")).unwrap();
+        assert!(zstd::stream::decode_all(&contents[..]).is_err());
+        let mut reads = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(&contents[..], &copy)
+            .unwrap()
+            .read_to_end(&mut reads)
+            .unwrap();
+        assert_eq!(reads.iter().filter(|&&b| b == b'\n').count(), 40);
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args(["verify-output", "--output", demux_path.to_str().unwrap()]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("all 186 files agree with Stats.json").from_utf8());
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn zstd_output() {
+        std::fs::create_dir_all("test_data/test_output/zstd_output").unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/zstd_output",
+            "--zstd",
+        ]);
+        cmd.assert()
+            .code(1)
+            .stderr(predicate::str::contains("built without the zstd feature").from_utf8());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn output_sinks() {
+        use std::io::Read;
+        use std::os::unix::fs::FileTypeExt;
+
+        let output_path = std::path::Path::new("test_data/test_output/output_sinks");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path.join("iseq_project")).unwrap();
+        let fastq_path = output_path.join("iseq_project/iseq_2_L001_R1.fastq.gz");
+
+        let demux = |extra_args: &[&str]| {
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/210618_FS10000171_0042_BPA73113-1417",
+                "--samplesheet",
+                "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+                "--output",
+                "test_data/test_output/output_sinks",
+            ])
+            .args(extra_args);
+            cmd.assert().success();
+        };
+
+        // BGZF blocks have their size in the header, and end with the empty block
+        demux(&["--bgzf"]);
+        let contents = std::fs::read(&fastq_path).unwrap();
+        assert_eq!(&contents[12..14], b"BC");
+        assert!(contents.ends_with(&[0x1b, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        let mut reads = String::new();
+        flate2::read::MultiGzDecoder::new(&contents[..])
+            .read_to_string(&mut reads)
+            .unwrap();
+        assert_eq!(reads.lines().count(), 52);
+
+        // with named pipes, the reads go to whatever is reading them
+        let mut pipes = Vec::new();
+        for entry in std::fs::read_dir(output_path.join("iseq_project")).unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::remove_file(&path).unwrap();
+            let status = std::process::Command::new("mkfifo")
+                .arg(&path)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            pipes.push(std::thread::spawn(move || {
+                let mut contents = Vec::new();
+                std::fs::File::open(&path)
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .unwrap();
+                (path, contents)
+            }));
+        }
+        demux(&["--sink", "fifo"]);
+        for pipe in pipes {
+            let (path, contents) = pipe.join().unwrap();
+            if path == fastq_path {
+                let mut piped = String::new();
+                flate2::read::MultiGzDecoder::new(&contents[..])
+                    .read_to_string(&mut piped)
+                    .unwrap();
+                assert_eq!(piped, reads);
+            }
+        }
+        assert!(std::fs::metadata(&fastq_path)
+            .unwrap()
+            .file_type()
+            .is_fifo());
+    }
+
+    #[test]
+    fn null_sink() {
+        let output_path = std::path::Path::new("test_data/test_output/null_sink");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/null_sink",
+            "--sink",
+            "null",
+            "-vv",
+        ]);
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("discarded").from_utf8());
+
+        // the stats are the same, without any fastq files
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReads\": 23"));
+        assert!(!output_path
+            .join("project_1/8034211776_L001_R1.fastq.gz")
+            .exists());
+    }
+
+    #[test]
+    fn many_samples() {
+        use std::io::Read;
+
+        let output_path = std::path::Path::new("test_data/test_output/many_samples");
+        let _ = std::fs::remove_dir_all(output_path);
+
+        let demux = |name: &str, extra_args: &[&str]| {
+            let demux_path = output_path.join(name);
+            std::fs::create_dir_all(&demux_path).unwrap();
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/210618_FS10000171_0042_BPA73113-1417",
+                "--samplesheet",
+                "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+                "--output",
+                demux_path.to_str().unwrap(),
+                "--delivery-manifests",
+            ])
+            .args(extra_args);
+            cmd.assert().success();
+            demux_path
+        };
+        let read_fastqs = |demux_path: &std::path::Path| {
+            let mut fastqs = std::collections::BTreeMap::new();
+            for entry in std::fs::read_dir(demux_path.join("iseq_project")).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().is_some_and(|e| e == "gz") {
+                    let mut reads = String::new();
+                    flate2::read::MultiGzDecoder::new(std::fs::File::open(&path).unwrap())
+                        .read_to_string(&mut reads)
+                        .unwrap();
+                    fastqs.insert(path.file_name().unwrap().to_owned(), reads);
+                }
+            }
+            fastqs
+        };
+
+        let expected = read_fastqs(&demux("files", &[]));
+        assert!(!expected.is_empty());
+
+        // fewer handles than files, so they're closed and opened again
+        let lru_path = demux(
+            "lru",
+            &["--many-samples", "--max-open-files", "2", "--threads", "2"],
+        );
+        assert_eq!(read_fastqs(&lru_path), expected);
+
+        let scatter_path = demux("scatter", &["--many-samples", "--scatter"]);
+        assert_eq!(read_fastqs(&scatter_path), expected);
+        // the spill files are gone, and the gathered files are in the manifests
+        assert!(std::fs::read_dir(&scatter_path).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(".spill")));
+        assert!(scatter_path
+            .join("iseq_project/delivery_manifest.json")
+            .is_file());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn open_file_limit() {
+        let output_path = std::path::Path::new("test_data/test_output/open_file_limit");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        // keeping all of the 188 files open needs more than the limit
+        let demux = |ulimit: &str, extra_args: &str| {
+            let mut cmd = Command::new("bash");
+            cmd.arg("-c").arg(format!(
+                "ulimit {} && exec {} demux --run-path {} --samplesheet {}/SampleSheet.csv --output {} --many-samples --max-open-files 200 --threads 2 -v {}",
+                ulimit,
+                assert_cmd::cargo::cargo_bin(crate_name!()).display(),
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                output_path.display(),
+                extra_args
+            ));
+            cmd
+        };
+
+        // the soft limit is raised
+        demux("-Sn 100", "-v")
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("raised the open file limit from 100").from_utf8());
+
+        // the hard limit can't be, so the files are pooled
+        demux("-n 100", "")
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("only 34 output files are kept open").from_utf8());
+        let stats = std::fs::read_to_string(output_path.join("Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReads\": 23"));
+
+        // named pipes can't be pooled
+        demux("-n 100", "--sink fifo")
+            .assert()
+            .failure()
+            .code(4)
+            .stderr(predicate::str::contains("Raise it with `ulimit -n`").from_utf8());
+    }
+
+    #[test]
+    fn tile_order() {
+        let output_path = std::path::Path::new("test_data/test_output/tile_order");
+        let _ = std::fs::remove_dir_all(output_path);
+
+        // the tiles of each sample are encoded in parallel, but written in order
+        let demux = |threads: &str| {
+            let demux_path = output_path.join(threads);
+            std::fs::create_dir_all(&demux_path).unwrap();
+            let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+            cmd.args([
+                "demux",
+                "--run-path",
+                "test_data/190414_A00111_0296_AHJCWWDSXX",
+                "--samplesheet",
+                "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+                "--output",
+                demux_path.to_str().unwrap(),
+                "--threads",
+                threads,
+                "--read-chunks",
+                "3",
+            ]);
+            cmd.assert().success();
+            std::fs::read(demux_path.join("project_1/8034211776_L001_R1.fastq.gz")).unwrap()
+        };
+
+        let fastq = demux("1");
+        assert_eq!(demux("8"), fastq);
+    }
+
+    #[test]
+    fn tar_archive() {
+        let output_path = std::path::Path::new("test_data/test_output/tar_archive");
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path.join("output")).unwrap();
+
+        let tar_path = output_path.join("run.tar");
+        let status = std::process::Command::new("tar")
+            .arg("-cf")
+            .arg(&tar_path)
+            .args(["-C", "test_data", "190414_A00111_0296_AHJCWWDSXX"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            tar_path.to_str().unwrap(),
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output/tar_archive/output",
+        ]);
+        cmd.assert().success();
+
+        let stats = std::fs::read_to_string(output_path.join("output/Stats/Stats.json")).unwrap();
+        assert!(stats.contains("\"NumberReads\": 23"));
+        assert!(output_path
+            .join("output/project_1/8034211776_L001_R1.fastq.gz")
+            .is_file());
+    }
+
+    #[test]
+    fn lane_failure() {
+        let test_path = std::path::Path::new("test_data/test_output/lane_failure");
+        let run_path = test_path.join("run");
+        let output_path = test_path.join("output");
+        let _ = std::fs::remove_dir_all(test_path);
+        std::fs::create_dir_all(&output_path).unwrap();
+
+        // the iSeq samples without their lane, in both lanes of a generated run
+        let samplesheet: Vec<_> = std::fs::read_to_string(
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+        )
+        .unwrap()
+        .lines()
+        .map(|line| line.rsplit_once(',').unwrap().0.to_string())
+        .collect();
+        let samplesheet_path = test_path.join("SampleSheet.csv");
+        std::fs::write(&samplesheet_path, samplesheet.join("\n")).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "genrun",
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+            "--output",
+            run_path.to_str().unwrap(),
+            "--lanes",
+            "2",
+            "--tiles",
+            "2",
+        ]);
+        cmd.assert().success();
+
+        // lane 2 is missing a filter file, but lane 1 is still demultiplexed
+        std::fs::remove_file(run_path.join("Data/Intensities/BaseCalls/L002/s_2_1101.filter"))
+            .unwrap();
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            run_path.to_str().unwrap(),
+            "--samplesheet",
+            samplesheet_path.to_str().unwrap(),
+            "--output",
+            output_path.to_str().unwrap(),
+        ]);
+        cmd.assert().code(5).stderr(
+            predicate::str::contains("lane 2 failed: run folder is missing 1 files").from_utf8(),
+        );
+
+        assert!(output_path
+            .join("iseq_project/iseq_1_L001_R1.fastq.gz")
+            .is_file());
+        assert!(!output_path
+            .join("iseq_project/iseq_1_L002_R1.fastq.gz")
+            .exists());
+        let summary = std::fs::read_to_string(output_path.join("run_summary.json")).unwrap();
+        assert!(summary.contains("\"status\": \"partial_success\""));
+        assert!(summary.contains("\"status\": \"failed\""));
+    }
+
+    #[test]
+    fn dump_tile() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "dump-tile",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--lane",
+            "1",
+            "--tile",
+            "1101",
+        ]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::starts_with("@A00111:296:HJCWWDSXX:1:1101:").from_utf8());
+    }
+
+    #[cfg(not(feature = "object-store"))]
+    #[test]
+    fn dump_tile_object_store() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "dump-tile",
+            "--run-path",
+            "s3://bucket/190414_A00111_0296_AHJCWWDSXX",
+            "--lane",
+            "1",
+            "--tile",
+            "1101",
+        ]);
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("built without the object-store feature").from_utf8());
+    }
+
+    #[cfg(not(feature = "object-store"))]
+    #[test]
+    fn demux_upload() {
+        let output_path = "test_data/test_output/demux_upload";
+        let _ = std::fs::remove_dir_all(output_path);
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/210618_FS10000171_0042_BPA73113-1417",
+            "--samplesheet",
+            "test_data/210618_FS10000171_0042_BPA73113-1417/SampleSheet.csv",
+            "--output",
+            output_path,
+            "--upload",
+            "s3://bucket/demux",
+        ]);
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("built without the object-store feature").from_utf8());
+    }
+
+    #[test]
+    fn barcode_count() {
+        let output_path = "test_data/test_output/barcode_count.txt";
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "barcode-count",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--output",
+            output_path,
+        ]);
+
+        cmd.assert().success();
+        assert!(std::path::Path::new(output_path).is_file());
+    }
+
+    #[test]
+    fn barcode_count_unwritable() {
+        let output_path = "test_data/test_output/barcode_count_dir";
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "barcode-count",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--output",
+            output_path,
+        ]);
+
+        cmd.assert()
+            .code(4)
+            .stderr(predicate::str::contains("Error writing index counts").from_utf8());
+    }
+
     #[test]
     fn no_run() {
         let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
-        cmd.args(&[
+        cmd.args([
+            "demux",
             "--run-path",
             "test_data/190414_A00111_0296_AHJCWWDSXXX",
             "--samplesheet",
@@ -44,7 +3151,7 @@ mod integration {
             "test_data/test_output",
         ]);
 
-        cmd.assert().failure().stderr(
+        cmd.assert().code(3).stderr(
             predicate::str::contains(
                 "Could not find run path test_data/190414_A00111_0296_AHJCWWDSXXX",
             )
@@ -55,7 +3162,8 @@ mod integration {
     #[test]
     fn no_samplesheet() {
         let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
-        cmd.args(&[
+        cmd.args([
+            "demux",
             "--run-path",
             "test_data/190414_A00111_0296_AHJCWWDSXX",
             "--samplesheet",
@@ -64,7 +3172,7 @@ mod integration {
             "test_data/test_output",
         ]);
 
-        cmd.assert().failure().stderr(
+        cmd.assert().code(2).stderr(
             predicate::str::contains("Could not find samplesheet test_data/no_file.csv")
                 .from_utf8(),
         );
@@ -73,7 +3181,8 @@ mod integration {
     #[test]
     fn no_output() {
         let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
-        cmd.args(&[
+        cmd.args([
+            "demux",
             "--run-path",
             "test_data/190414_A00111_0296_AHJCWWDSXX",
             "--samplesheet",
@@ -82,7 +3191,7 @@ mod integration {
             "test_data/not_a_dir",
         ]);
 
-        cmd.assert().failure().stderr(
+        cmd.assert().code(4).stderr(
             predicate::str::contains("Could not find output path test_data/not_a_dir").from_utf8(),
         );
     }
@@ -90,7 +3199,8 @@ mod integration {
     #[test]
     fn bad_read_chunk() {
         let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
-        cmd.args(&[
+        cmd.args([
+            "demux",
             "--run-path",
             "test_data/190414_A00111_0296_AHJCWWDSXX",
             "--samplesheet",
@@ -108,4 +3218,25 @@ mod integration {
             .from_utf8(),
         );
     }
+
+    #[test]
+    fn bad_log_level() {
+        let mut cmd = Command::cargo_bin(crate_name!()).unwrap();
+        cmd.args([
+            "demux",
+            "--run-path",
+            "test_data/190414_A00111_0296_AHJCWWDSXX",
+            "--samplesheet",
+            "test_data/190414_A00111_0296_AHJCWWDSXX/SampleSheet.csv",
+            "--output",
+            "test_data/test_output",
+            "--log-level",
+            "common=loud",
+        ]);
+
+        cmd.assert().failure().stderr(
+            predicate::str::contains("invalid value for 'log-level': invalid log level 'loud'")
+                .from_utf8(),
+        );
+    }
 }